@@ -20,6 +20,7 @@
 
 #include <log/log.h>
 
+#include <algorithm>
 #include <memory>
 #include <string>
 #include <vector>
@@ -228,18 +229,27 @@ build_attestation_application_id(const KeyAttestationApplicationId& key_attestat
     }
 
     /** Apps can only share a uid iff they were signed with the same certificate(s). Because the
-     *  signature field actually holds the signing certificate, rather than a signature, we can
-     *  simply use the set of signature digests of the first package info.
+     *  signature field actually holds the signing certificate, rather than a signature, the
+     *  signature digests of all package infos should be the same set. Packages signed by multiple
+     *  signers carry one signature per signer, and package manager may report the same signing
+     *  certificate more than once. To be robust against package infos that disagree, we use the
+     *  union of the signature digests of all package infos, with each digest included only once.
      */
-    const auto& pinfo = *key_attestation_id.pinfos_begin();
     std::vector<std::vector<uint8_t>> signature_digests;
 
-    for (auto sig = pinfo.sigs_begin(); sig != pinfo.sigs_end(); ++sig) {
-        signature_digests.push_back(signature2SHA256(*sig));
+    for (auto pinfo = key_attestation_id.pinfos_begin(); pinfo != key_attestation_id.pinfos_end();
+         ++pinfo) {
+        for (auto sig = pinfo->sigs_begin(); sig != pinfo->sigs_end(); ++sig) {
+            auto digest = signature2SHA256(*sig);
+            if (std::find(signature_digests.begin(), signature_digests.end(), digest) ==
+                signature_digests.end()) {
+                signature_digests.push_back(std::move(digest));
+            }
+        }
     }
 
     auto signature_digest_stack = reinterpret_cast<_STACK*>(attestation_id->signature_digests);
-    for (auto si : signature_digests) {
+    for (const auto& si : signature_digests) {
         estimated_encoded_size += AAID_SIGNATURE_SIZE;
         if (estimated_encoded_size > KEY_ATTESTATION_APPLICATION_ID_MAX_SIZE) {
             break;
@@ -0,0 +1,186 @@
+/*
+ * Copyright (C) 2023 The Android Open Source Project
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+
+#include <gtest/gtest.h>
+
+#include <algorithm>
+#include <memory>
+#include <optional>
+#include <string>
+#include <utility>
+#include <vector>
+
+#include <openssl/bytestring.h>
+#include <openssl/sha.h>
+#include <utils/String16.h>
+
+#include <keystore/keystore_attestation_id.h>
+
+#include <keystore/KeyAttestationApplicationId.h>
+#include <keystore/KeyAttestationPackageInfo.h>
+#include <keystore/Signature.h>
+
+using ::android::String16;
+using ::android::content::pm::Signature;
+using ::android::security::build_attestation_application_id;
+using ::android::security::keymaster::KeyAttestationApplicationId;
+using ::android::security::keymaster::KeyAttestationPackageInfo;
+using std::vector;
+
+namespace keystore {
+
+namespace test {
+
+namespace {
+
+constexpr const char* kFirstPackageName = "com.example.first";
+constexpr const char* kSecondPackageName = "com.example.second";
+
+const vector<uint8_t> kFirstSignerCert = {0x30, 0x03, 0x02, 0x01, 0x01};
+const vector<uint8_t> kSecondSignerCert = {0x30, 0x03, 0x02, 0x01, 0x02};
+
+// The decoded form of the AttestationApplicationId, i.e.,
+// AttestationApplicationId ::= SEQUENCE {
+//     package_infos  SET OF AttestationPackageInfo,
+//     signature_digests  SET OF OCTET_STRING,
+// }
+// AttestationPackageInfo ::= SEQUENCE {
+//     package_name  OCTET_STRING,
+//     version  INTEGER,
+// }
+struct DecodedApplicationId {
+    vector<std::pair<std::string, uint64_t>> package_infos;
+    vector<vector<uint8_t>> signature_digests;
+};
+
+vector<uint8_t> sha256(const vector<uint8_t>& data) {
+    vector<uint8_t> digest(SHA256_DIGEST_LENGTH);
+    SHA256(data.data(), data.size(), digest.data());
+    return digest;
+}
+
+bool decode_application_id(const vector<uint8_t>& encoded, DecodedApplicationId* decoded) {
+    CBS cbs, app_id, package_infos, signature_digests;
+    CBS_init(&cbs, encoded.data(), encoded.size());
+    if (!CBS_get_asn1(&cbs, &app_id, CBS_ASN1_SEQUENCE) || CBS_len(&cbs) != 0 ||
+        !CBS_get_asn1(&app_id, &package_infos, CBS_ASN1_SET) ||
+        !CBS_get_asn1(&app_id, &signature_digests, CBS_ASN1_SET) || CBS_len(&app_id) != 0) {
+        return false;
+    }
+    while (CBS_len(&package_infos) != 0) {
+        CBS package_info, package_name;
+        uint64_t version;
+        if (!CBS_get_asn1(&package_infos, &package_info, CBS_ASN1_SEQUENCE) ||
+            !CBS_get_asn1(&package_info, &package_name, CBS_ASN1_OCTETSTRING) ||
+            !CBS_get_asn1_uint64(&package_info, &version) || CBS_len(&package_info) != 0) {
+            return false;
+        }
+        decoded->package_infos.emplace_back(
+            std::string(reinterpret_cast<const char*>(CBS_data(&package_name)),
+                        CBS_len(&package_name)),
+            version);
+    }
+    while (CBS_len(&signature_digests) != 0) {
+        CBS digest;
+        if (!CBS_get_asn1(&signature_digests, &digest, CBS_ASN1_OCTETSTRING)) return false;
+        decoded->signature_digests.emplace_back(CBS_data(&digest),
+                                                CBS_data(&digest) + CBS_len(&digest));
+    }
+    return true;
+}
+
+std::optional<KeyAttestationPackageInfo>
+make_package_info(const char* package_name, int64_t version_code,
+                  const vector<vector<uint8_t>>& signer_certs) {
+    KeyAttestationPackageInfo::SignaturesVector signatures;
+    for (const auto& cert : signer_certs) {
+        signatures.push_back(std::make_optional<Signature>(cert));
+    }
+    return std::make_optional<KeyAttestationPackageInfo>(
+        String16(package_name), version_code,
+        std::make_shared<KeyAttestationPackageInfo::SignaturesVector>(std::move(signatures)));
+}
+
+// The digests are encoded as a DER SET OF, which does not preserve their order.
+vector<vector<uint8_t>> sorted(vector<vector<uint8_t>> digests) {
+    std::sort(digests.begin(), digests.end());
+    return digests;
+}
+
+}  // namespace
+
+TEST(AaidSignatureDigestsTest, singleSignerTest) {
+    KeyAttestationApplicationId app_id(make_package_info(kFirstPackageName, 7, {kFirstSignerCert}));
+
+    auto result = build_attestation_application_id(app_id);
+    ASSERT_TRUE(result.isOk());
+    DecodedApplicationId decoded;
+    ASSERT_TRUE(decode_application_id(result.value(), &decoded));
+
+    ASSERT_EQ(decoded.package_infos.size(), 1u);
+    EXPECT_EQ(decoded.package_infos[0].first, kFirstPackageName);
+    EXPECT_EQ(decoded.package_infos[0].second, 7u);
+    EXPECT_EQ(decoded.signature_digests, vector<vector<uint8_t>>{sha256(kFirstSignerCert)});
+}
+
+TEST(AaidSignatureDigestsTest, multiSignerTest) {
+    KeyAttestationApplicationId app_id(
+        make_package_info(kFirstPackageName, 1, {kFirstSignerCert, kSecondSignerCert}));
+
+    auto result = build_attestation_application_id(app_id);
+    ASSERT_TRUE(result.isOk());
+    DecodedApplicationId decoded;
+    ASSERT_TRUE(decode_application_id(result.value(), &decoded));
+
+    ASSERT_EQ(decoded.package_infos.size(), 1u);
+    EXPECT_EQ(decoded.package_infos[0].first, kFirstPackageName);
+    EXPECT_EQ(sorted(decoded.signature_digests),
+              sorted({sha256(kFirstSignerCert), sha256(kSecondSignerCert)}));
+}
+
+TEST(AaidSignatureDigestsTest, sharedUidSignersAreIncludedOnceTest) {
+    KeyAttestationApplicationId::PackageInfoVector packages;
+    packages.push_back(
+        make_package_info(kFirstPackageName, 1, {kFirstSignerCert, kSecondSignerCert}));
+    packages.push_back(
+        make_package_info(kSecondPackageName, 2, {kSecondSignerCert, kFirstSignerCert}));
+    KeyAttestationApplicationId app_id(std::move(packages));
+
+    auto result = build_attestation_application_id(app_id);
+    ASSERT_TRUE(result.isOk());
+    DecodedApplicationId decoded;
+    ASSERT_TRUE(decode_application_id(result.value(), &decoded));
+
+    EXPECT_EQ(decoded.package_infos.size(), 2u);
+    EXPECT_EQ(sorted(decoded.signature_digests),
+              sorted({sha256(kFirstSignerCert), sha256(kSecondSignerCert)}));
+}
+
+TEST(AaidSignatureDigestsTest, unsignedPackageTest) {
+    KeyAttestationApplicationId app_id(make_package_info(kFirstPackageName, 1, {}));
+
+    auto result = build_attestation_application_id(app_id);
+    ASSERT_TRUE(result.isOk());
+    DecodedApplicationId decoded;
+    ASSERT_TRUE(decode_application_id(result.value(), &decoded));
+
+    EXPECT_EQ(decoded.package_infos.size(), 1u);
+    EXPECT_TRUE(decoded.signature_digests.empty());
+}
+
+}  // namespace test
+}  // namespace keystore
@@ -75,6 +75,13 @@ constexpr const size_t kTooManySignatures = 35;
 using ::android::content::pm::Signature;
 using ::android::security::build_attestation_application_id;
 
+// Signatures are deduplicated, so each generated signature differs in its first byte.
+std::vector<uint8_t> make_dummy_signature(size_t index) {
+    std::vector<uint8_t> sig_data(kDummySignature, kDummySignature + 32);
+    sig_data[0] = static_cast<uint8_t>(index);
+    return sig_data;
+}
+
 std::optional<KeyAttestationPackageInfo>
 make_package_info_with_signatures(const char* package_name,
                                   KeyAttestationPackageInfo::SignaturesVector signatures) {
@@ -107,11 +114,10 @@ TEST(AaidTruncationTest, tooLongPackageNameTest) {
 }
 
 TEST(AaidTruncationTest, tooManySignaturesTest) {
-    std::vector<uint8_t> dummy_sig_data(kDummySignature, kDummySignature + 32);
     KeyAttestationPackageInfo::SignaturesVector signatures;
-    // Add 35 signatures which will surely exceed the 1K limit.
+    // Add 35 distinct signatures which will surely exceed the 1K limit.
     for (size_t i = 0; i < kTooManySignatures; ++i) {
-        signatures.push_back(std::make_optional<Signature>(dummy_sig_data));
+        signatures.push_back(std::make_optional<Signature>(make_dummy_signature(i)));
     }
 
     KeyAttestationApplicationId app_id(
@@ -124,14 +130,13 @@ TEST(AaidTruncationTest, tooManySignaturesTest) {
 }
 
 TEST(AaidTruncationTest, combinedPackagesAndSignaturesTest) {
-    std::vector<uint8_t> dummy_sig_data(kDummySignature, kDummySignature + 32);
     KeyAttestationApplicationId::PackageInfoVector packages;
 
     for (size_t i = 0; i < kTooManyPackages; ++i) {
         KeyAttestationPackageInfo::SignaturesVector signatures;
         // Add a few signatures for each package
-        for (int j = 0; j < 3; ++j) {
-            signatures.push_back(std::make_optional<Signature>(dummy_sig_data));
+        for (size_t j = 0; j < 3; ++j) {
+            signatures.push_back(std::make_optional<Signature>(make_dummy_signature(i * 3 + j)));
         }
         packages.push_back(
             make_package_info_with_signatures(kReasonablePackageName, std::move(signatures)));
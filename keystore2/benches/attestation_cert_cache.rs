@@ -0,0 +1,50 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares loading the certificate of an attestation key from the database with serving it
+//! from the attestation certificate cache.
+
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+use criterion::{criterion_group, criterion_main, Criterion};
+use keystore2::attestation_cert_cache::AttestationCertCache;
+use keystore2::database::{KeyType, KeystoreDB, SubComponentType, KEYSTORE_UUID};
+use keystore2_test_utils::TempDir;
+
+/// The size of a typical attestation certificate.
+const CERT: &[u8] = &[0x30; 1024];
+/// The size of a typical attestation certificate chain.
+const CERT_CHAIN: &[u8] = &[0x30; 4096];
+
+fn bench_attestation_cert_cache(c: &mut Criterion) {
+    let temp_dir = TempDir::new("attestation_cert_cache_bench").unwrap();
+    let mut db = KeystoreDB::new(temp_dir.path(), None).unwrap();
+    let key_id =
+        db.create_key_entry(&Domain::APP, &10001, KeyType::Client, &KEYSTORE_UUID).unwrap();
+    db.set_blob(&key_id, SubComponentType::CERT, Some(CERT), None).unwrap();
+    db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(CERT_CHAIN), None).unwrap();
+
+    c.bench_function("load_certificates", |b| b.iter(|| db.load_certificates(&key_id).unwrap()));
+
+    let cache = AttestationCertCache::new(64);
+    c.bench_function("attestation_cert_cache_hit", |b| {
+        b.iter(|| {
+            cache
+                .get_or_load(key_id.id(), || Ok(db.load_certificates(&key_id)?.0.unwrap()))
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, bench_attestation_cert_cache);
+criterion_main!(benches);
@@ -0,0 +1,380 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the generation of the nonces with which blobs are encrypted under a
+//! super key.
+//!
+//! By default, nonces are random. Alternatively, nonces can be taken from a monotonic counter,
+//! which is persisted in the database. The counter is reserved in ranges, and each range is
+//! committed to the database before any of its values is used, so a value is never used twice,
+//! even if keystore is restarted or the device reboots in the middle of a range. A counter that
+//! cannot be persisted would risk nonce reuse after a restart, so such a configuration is
+//! rejected.
+//!
+//! Reserving a range takes a write transaction on a separate database connection. It would
+//! conflict with a write transaction that the current thread already holds on its own
+//! connection, so code that encrypts blobs inside a transaction must reserve the nonces it needs
+//! beforehand with `NonceGenerator::reserve_for_transaction`.
+
+use crate::database::KeystoreDB;
+use crate::error::Error;
+use crate::globals::DB_PATH;
+use crate::ks_err;
+use crate::sysprop::read_prop_parsed;
+use anyhow::{Context, Result};
+use keystore2_crypto::generate_random_data;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::thread::ThreadId;
+
+/// Selects how nonces are generated, "random" or "counter".
+const NONCE_STRATEGY_PROPERTY: &str = "keystore.blob_nonce_strategy";
+
+/// The number of counter values that are reserved in the database at once.
+const COUNTER_RESERVATION: u64 = 1024;
+
+/// The number of counter values that `NonceGenerator::reserve_for_transaction` sets aside. A
+/// transaction that encrypts one blob needs one nonce per attempt, and rarely more than a few
+/// attempts.
+const TRANSACTION_RESERVATION: u64 = 8;
+
+/// The ways in which nonces can be generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonceStrategy {
+    /// Each nonce is random.
+    Random,
+    /// Each nonce is the next value of a persistent counter, encoded big endian.
+    Counter,
+}
+
+impl NonceStrategy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "random" => Some(Self::Random),
+            "counter" => Some(Self::Counter),
+            _ => None,
+        }
+    }
+}
+
+/// The persistent storage of the nonce counter.
+pub trait CounterStore: Send {
+    /// Reserves `count` consecutive counter values, such that they are never reserved again,
+    /// and returns the first of them.
+    fn reserve(&mut self, count: u64) -> Result<u64>;
+}
+
+impl CounterStore for KeystoreDB {
+    fn reserve(&mut self, count: u64) -> Result<u64> {
+        self.reserve_nonce_counter_range(count)
+    }
+}
+
+struct CounterState {
+    store: Box<dyn CounterStore>,
+    /// The next value to be used.
+    next: u64,
+    /// The end of the reserved range.
+    end: u64,
+    /// The values set aside for individual threads by `NonceGenerator::reserve_for_transaction`.
+    thread_reservations: HashMap<ThreadId, Range<u64>>,
+}
+
+impl CounterState {
+    /// Takes `count` consecutive values from the reserved range, and reserves a new range first
+    /// if the current one is too short. The rest of a range that is too short is never used.
+    fn take(&mut self, count: u64) -> Result<u64> {
+        if self.end - self.next < count {
+            let reservation = std::cmp::max(count, COUNTER_RESERVATION);
+            let start = self
+                .store
+                .reserve(reservation)
+                .context(ks_err!("Failed to reserve nonce counter values."))?;
+            self.next = start;
+            self.end = start + reservation;
+        }
+        let value = self.next;
+        self.next += count;
+        Ok(value)
+    }
+}
+
+/// Generates the nonces for blob encryption according to a `NonceStrategy`.
+pub struct NonceGenerator {
+    /// The counter, if the strategy is `NonceStrategy::Counter`.
+    counter: Option<Mutex<CounterState>>,
+}
+
+impl NonceGenerator {
+    /// Creates a generator with `strategy`. The counter strategy requires a `store`, otherwise
+    /// the configuration is rejected with `ResponseCode::SYSTEM_ERROR`.
+    pub fn new(strategy: NonceStrategy, store: Option<Box<dyn CounterStore>>) -> Result<Self> {
+        match (strategy, store) {
+            (NonceStrategy::Random, _) => Ok(Self { counter: None }),
+            (NonceStrategy::Counter, Some(store)) => Ok(Self {
+                counter: Some(Mutex::new(CounterState {
+                    store,
+                    next: 0,
+                    end: 0,
+                    thread_reservations: HashMap::new(),
+                })),
+            }),
+            (NonceStrategy::Counter, None) => {
+                Err(Error::sys()).context(ks_err!("Counter nonces require a persistent counter."))
+            }
+        }
+    }
+
+    /// Creates a generator with the strategy of the `keystore.blob_nonce_strategy` system
+    /// property. The counter is stored in the keystore database. If the database cannot be
+    /// opened, the counter strategy is rejected, and random nonces are used instead.
+    pub fn from_property() -> Self {
+        let strategy =
+            read_prop_parsed(NONCE_STRATEGY_PROPERTY, NonceStrategy::Random, NonceStrategy::parse);
+        let store = match strategy {
+            NonceStrategy::Random => None,
+            NonceStrategy::Counter => {
+                let db_path = DB_PATH.read().expect("Could not get the database directory.");
+                match KeystoreDB::new(&db_path, None) {
+                    Ok(db) => Some(Box::new(db) as Box<dyn CounterStore>),
+                    Err(e) => {
+                        log::error!("Failed to open the nonce counter database: {:?}", e);
+                        None
+                    }
+                }
+            }
+        };
+        Self::new(strategy, store).unwrap_or_else(|e| {
+            log::error!("Rejected nonce strategy {:?}, using random nonces: {:?}", strategy, e);
+            Self { counter: None }
+        })
+    }
+
+    /// Returns the strategy of this generator.
+    pub fn strategy(&self) -> NonceStrategy {
+        match self.counter {
+            Some(_) => NonceStrategy::Counter,
+            None => NonceStrategy::Random,
+        }
+    }
+
+    /// Sets counter values aside for the current thread, which its next nonces use before they
+    /// take values from the shared range. This must be called before the current thread starts a
+    /// database transaction in which it encrypts blobs, because reserving values from the
+    /// database inside of that transaction would fail. Replaces the values that were set aside
+    /// earlier, which are never used then. Does nothing for random nonces.
+    pub fn reserve_for_transaction(&self) -> Result<()> {
+        let counter = match &self.counter {
+            Some(counter) => counter,
+            None => return Ok(()),
+        };
+        let mut state = counter.lock().unwrap();
+        let start = state.take(TRANSACTION_RESERVATION).context(ks_err!())?;
+        state
+            .thread_reservations
+            .insert(std::thread::current().id(), start..start + TRANSACTION_RESERVATION);
+        Ok(())
+    }
+
+    /// Returns a new nonce of `len` bytes. Counter nonces are at least 8 bytes long.
+    pub fn next_nonce(&self, len: usize) -> Result<Vec<u8>> {
+        let counter = match &self.counter {
+            Some(counter) => counter,
+            None => return generate_random_data(len).context(ks_err!("Failed to generate nonce.")),
+        };
+        if len < std::mem::size_of::<u64>() {
+            return Err(Error::sys()).context(ks_err!("Nonce of {} bytes is too short.", len));
+        }
+        let mut state = counter.lock().unwrap();
+        let thread = std::thread::current().id();
+        let value = match state.thread_reservations.get_mut(&thread).and_then(|r| r.next()) {
+            Some(value) => value,
+            None => {
+                state.thread_reservations.remove(&thread);
+                state.take(1).context(ks_err!())?
+            }
+        };
+
+        let mut nonce = vec![0; len];
+        nonce[len - std::mem::size_of::<u64>()..].copy_from_slice(&value.to_be_bytes());
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        BlobMetaData, BlobMetaEntry, CertificateInfo, ExistingAlias, KeyEntryLoadBits, KeyMetaData,
+        KeyType, KEYSTORE_UUID,
+    };
+    use android_system_keystore2::aidl::android::system::keystore2::{
+        Domain::Domain, KeyDescriptor::KeyDescriptor,
+    };
+    use keystore2_crypto::{
+        aes_gcm_decrypt, aes_gcm_encrypt_with_iv, generate_aes256_key, GCM_IV_LENGTH,
+    };
+    use keystore2_test_utils::TempDir;
+    use std::collections::HashSet;
+
+    fn assert_unique(generator: &NonceGenerator, count: usize, seen: &mut HashSet<Vec<u8>>) {
+        for _ in 0..count {
+            let nonce = generator.next_nonce(GCM_IV_LENGTH).unwrap();
+            assert_eq!(nonce.len(), GCM_IV_LENGTH);
+            assert!(seen.insert(nonce));
+        }
+    }
+
+    #[test]
+    fn test_random_nonces_are_unique() {
+        let generator = NonceGenerator::new(NonceStrategy::Random, None).unwrap();
+        assert_eq!(generator.strategy(), NonceStrategy::Random);
+        assert_unique(&generator, 1000, &mut HashSet::new());
+    }
+
+    #[test]
+    fn test_counter_nonces_are_unique_across_restarts() -> Result<()> {
+        let temp_dir = TempDir::new("nonce_counter_test")?;
+        let mut seen = HashSet::new();
+
+        let generator = NonceGenerator::new(
+            NonceStrategy::Counter,
+            Some(Box::new(KeystoreDB::new(temp_dir.path(), None)?)),
+        )?;
+        assert_eq!(generator.strategy(), NonceStrategy::Counter);
+        // Cross a reservation boundary.
+        assert_unique(&generator, COUNTER_RESERVATION as usize + 10, &mut seen);
+        assert_eq!(
+            generator.next_nonce(GCM_IV_LENGTH)?,
+            [&[0; 4][..], &(COUNTER_RESERVATION + 10).to_be_bytes()].concat()
+        );
+        drop(generator);
+
+        // A restarted generator continues after the last reserved range, even though most of
+        // that range was not used.
+        let generator = NonceGenerator::new(
+            NonceStrategy::Counter,
+            Some(Box::new(KeystoreDB::new(temp_dir.path(), None)?)),
+        )?;
+        assert_eq!(
+            generator.next_nonce(GCM_IV_LENGTH)?,
+            [&[0; 4][..], &(2 * COUNTER_RESERVATION).to_be_bytes()].concat()
+        );
+        assert_unique(&generator, 100, &mut seen);
+        Ok(())
+    }
+
+    #[test]
+    fn test_counter_nonces_inside_of_a_store_transaction() -> Result<()> {
+        let temp_dir = TempDir::new("nonce_counter_store_test")?;
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        let generator = NonceGenerator::new(
+            NonceStrategy::Counter,
+            Some(Box::new(KeystoreDB::new(temp_dir.path(), None)?)),
+        )?;
+        let super_key = generate_aes256_key()?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 10001,
+            alias: Some("counter_nonce_key".to_string()),
+            blob: None,
+        };
+
+        // Each stored key takes its nonce from the values reserved before its transaction,
+        // including the first one, for which no range was reserved yet, and the one after the
+        // reserved range was used up.
+        for i in 0..COUNTER_RESERVATION / TRANSACTION_RESERVATION + 2 {
+            generator.reserve_for_transaction()?;
+            db.store_new_key_with_blob(
+                &key,
+                KeyType::Client,
+                &[],
+                |_| {
+                    let nonce = generator.next_nonce(GCM_IV_LENGTH)?;
+                    let (blob, iv, tag) =
+                        aes_gcm_encrypt_with_iv(b"key blob", &super_key, &[], nonce)?;
+                    let mut metadata = BlobMetaData::new();
+                    metadata.add(BlobMetaEntry::Iv(iv));
+                    metadata.add(BlobMetaEntry::AeadTag(tag));
+                    Ok((blob, metadata))
+                },
+                &CertificateInfo::new(None, None),
+                None,
+                &KeyMetaData::new(),
+                &KEYSTORE_UUID,
+                ExistingAlias::Replace,
+            )?;
+            let (_, mut key_entry) =
+                db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 10001, |_, _| {
+                    Ok(())
+                })?;
+            let (blob, metadata) = key_entry.take_key_blob_info().unwrap();
+            let iv = metadata.iv().unwrap();
+            assert_eq!(
+                iv[..],
+                [&[0; 4][..], &(i * TRANSACTION_RESERVATION).to_be_bytes()].concat()
+            );
+            let plaintext = aes_gcm_decrypt(&blob, iv, metadata.aead_tag().unwrap(), &super_key)?;
+            assert_eq!(&plaintext[..], b"key blob");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_thread_reservations_are_not_shared() -> Result<()> {
+        let generator = NonceGenerator::new(
+            NonceStrategy::Counter,
+            Some(Box::new(KeystoreDB::new_in_memory()?)),
+        )?;
+        generator.reserve_for_transaction()?;
+        let reserved = generator.next_nonce(GCM_IV_LENGTH)?;
+
+        // Another thread takes the value after the reservation of this thread.
+        let other = std::thread::scope(|s| s.spawn(|| generator.next_nonce(GCM_IV_LENGTH)).join())
+            .unwrap()?;
+        assert_eq!(other, [&[0; 4][..], &TRANSACTION_RESERVATION.to_be_bytes()].concat());
+
+        // This thread uses up its reservation before it takes values from the shared range.
+        let mut seen = HashSet::from([reserved, other]);
+        assert_unique(&generator, TRANSACTION_RESERVATION as usize - 1, &mut seen);
+        assert_eq!(
+            generator.next_nonce(GCM_IV_LENGTH)?,
+            [&[0; 4][..], &(TRANSACTION_RESERVATION + 1).to_be_bytes()].concat()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsafe_configurations_are_rejected() {
+        assert!(NonceGenerator::new(NonceStrategy::Counter, None).is_err());
+
+        let generator = NonceGenerator::new(
+            NonceStrategy::Counter,
+            Some(Box::new(KeystoreDB::new_in_memory().unwrap())),
+        )
+        .unwrap();
+        assert!(generator.next_nonce(4).is_err());
+    }
+
+    #[test]
+    fn test_exhausted_counter_is_not_reused() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        assert_eq!(db.reserve_nonce_counter_range(i64::MAX as u64 - 1)?, 0);
+        assert!(db.reserve_nonce_counter_range(2).is_err());
+        assert_eq!(db.reserve_nonce_counter_range(1)?, i64::MAX as u64 - 1);
+        assert!(db.reserve_nonce_counter_range(1).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module derives app scoped symmetric keys, e.g., for file encryption.
+//!
+//! Apps that want to encrypt their files without managing a key get an AES-256 key derived with
+//! HKDF from a device bound secret, the caller's UID, and a caller supplied context. The secret
+//! is computed by an internal KeyMint HMAC key that never leaves the TEE, see
+//! `device_id::get_internal_secret`. Thus the keys are stable for the lifetime of the key
+//! database, and no app can derive the key of another app. They do not survive a factory reset.
+
+use crate::database::KeystoreDB;
+use crate::device_id::get_internal_secret;
+use crate::ks_err;
+use anyhow::{Context, Result};
+use keystore2_crypto::{hkdf_expand, hkdf_extract, ZVec, AES_256_KEY_LENGTH};
+
+const APP_KEY_SECRET_INPUT: &[u8] = b"Create app key secret";
+const APP_KEY_SALT: &[u8] = b"Keystore app key";
+
+/// Derives the key for the given caller and context from `secret`. The UID has a fixed size, so
+/// the HKDF info is unambiguous.
+fn derive_app_key(secret: &[u8], caller_uid: u32, context: &[u8]) -> Result<ZVec> {
+    let prk = hkdf_extract(secret, APP_KEY_SALT).context(ks_err!("hkdf_extract failed."))?;
+    let mut info = Vec::with_capacity(std::mem::size_of::<u32>() + context.len());
+    info.extend_from_slice(&caller_uid.to_be_bytes());
+    info.extend_from_slice(context);
+    hkdf_expand(AES_256_KEY_LENGTH, &prk, &info).context(ks_err!("hkdf_expand failed."))
+}
+
+/// Returns a stable AES-256 key that is specific to `caller_uid` and `context`.
+pub fn get_app_key(db: &mut KeystoreDB, caller_uid: u32, context: &[u8]) -> Result<ZVec> {
+    let secret = get_internal_secret(db, APP_KEY_SECRET_INPUT).context(ks_err!())?;
+    derive_app_key(&secret, caller_uid, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_app_key_is_stable() {
+        let key1 = derive_app_key(SECRET, 10001, b"files").unwrap();
+        let key2 = derive_app_key(SECRET, 10001, b"files").unwrap();
+        assert_eq!(&key1[..], &key2[..]);
+        assert_eq!(key1.len(), AES_256_KEY_LENGTH);
+    }
+
+    #[test]
+    fn test_app_key_diverges() {
+        let key = derive_app_key(SECRET, 10001, b"files").unwrap();
+        assert_ne!(&key[..], &derive_app_key(SECRET, 10002, b"files").unwrap()[..]);
+        assert_ne!(&key[..], &derive_app_key(SECRET, 10001, b"cache").unwrap()[..]);
+        assert_ne!(&key[..], &derive_app_key(SECRET, 10001, b"").unwrap()[..]);
+        assert_ne!(
+            &key[..],
+            &derive_app_key(b"fedcba9876543210fedcba9876543210", 10001, b"files").unwrap()[..]
+        );
+    }
+}
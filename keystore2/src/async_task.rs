@@ -18,15 +18,44 @@
 //! and resource consumption, the thread will linger for about 30 seconds after it has
 //! processed all tasks before it terminates.
 //! Note that low priority tasks are processed only when the high priority queue is empty.
+//! Long running tasks cannot be preempted, but they can be cancelled cooperatively using a
+//! `CancellationToken` that they check at safe points.
 
 use std::{any::Any, any::TypeId, time::Duration};
 use std::{
     collections::{HashMap, VecDeque},
     sync::Arc,
-    sync::{Condvar, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex, MutexGuard,
+    },
     thread,
 };
 
+/// A CancellationToken allows requesting that a long running task stops early. Clones of a
+/// token share their state, so the requester keeps one clone and hands another one to the task.
+/// Cancellation is cooperative: the task checks `is_cancelled` at points where it can stop
+/// without leaving any state inconsistent, commits the progress it made so far, and returns.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a new token that is not cancelled.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Requests cancellation of all tasks holding a clone of this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if cancellation was requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum State {
     Exiting,
@@ -259,7 +288,7 @@ impl AsyncTask {
 
 #[cfg(test)]
 mod tests {
-    use super::{AsyncTask, Shelf};
+    use super::{AsyncTask, CancellationToken, Shelf};
     use std::sync::{
         mpsc::{channel, sync_channel, RecvTimeoutError},
         Arc,
@@ -531,4 +560,52 @@ mod tests {
         });
         done_receiver.recv().unwrap();
     }
+
+    #[test]
+    fn test_async_task_cancellation() {
+        // The task processes items and commits its progress to the shelf after each item.
+        #[derive(Default)]
+        struct Progress {
+            committed: Vec<usize>,
+        }
+
+        let at = AsyncTask::default();
+        let token = CancellationToken::new();
+        let (started_sender, started_receiver) = channel();
+        let (done_sender, done_receiver) = channel();
+        let task_token = token.clone();
+        at.queue_lo(move |shelf| {
+            for i in 0..1_000_000 {
+                // Safe point: all items before i are committed.
+                if task_token.is_cancelled() {
+                    break;
+                }
+                shelf.get_mut::<Progress>().committed.push(i);
+                if i == 10 {
+                    started_sender.send(()).unwrap();
+                }
+                std::thread::sleep(Duration::from_micros(100));
+            }
+            done_sender.send(()).unwrap();
+        });
+
+        // Cancel once the task has made some progress. It must stop promptly.
+        started_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        token.cancel();
+        done_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The committed progress is consistent, and the shelf is usable by later jobs.
+        let (sender, receiver) = channel();
+        at.queue_hi(move |shelf| {
+            sender.send(shelf.get_mut::<Progress>().committed.clone()).unwrap();
+        });
+        let committed = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(committed.len() > 10 && committed.len() < 1_000_000);
+        assert!(committed.iter().enumerate().all(|(i, item)| i == *item));
+
+        // Cancellation is shared among clones and is sticky.
+        assert!(token.is_cancelled());
+        assert!(token.clone().is_cancelled());
+        assert!(!CancellationToken::new().is_cancelled());
+    }
 }
@@ -0,0 +1,214 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a read-through cache of the parsed certificates of user generated
+//! attestation keys.
+//!
+//! Attesting a new key with a user generated attestation key requires the subject of the
+//! attestation key's certificate. Callers that reuse the same attestation key would otherwise
+//! load and parse its certificate from the database for every new key. The cache is keyed by
+//! the key id of the attestation key and bounded in size. The database invalidates entries
+//! whenever the certificates of a key change, so the cache never serves a subject after the
+//! certificate it was parsed from was replaced.
+
+use crate::sysprop::read_prop_u32;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Maximum number of cached certificates. A size of 0 disables the cache.
+const SIZE_PROPERTY: &str = "keystore.attestation_cert_cache.size";
+const DEFAULT_SIZE: u32 = 64;
+
+lazy_static! {
+    /// The attestation certificate cache used by keystore.
+    pub static ref ATTESTATION_CERT_CACHE: AttestationCertCache =
+        AttestationCertCache::new(read_prop_u32(SIZE_PROPERTY, DEFAULT_SIZE) as usize);
+}
+
+#[derive(Debug)]
+struct Entry {
+    issuer_subject: Vec<u8>,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<i64, Entry>,
+    /// Incremented on every invalidation. Loads that raced with an invalidation are not
+    /// cached, because they may have read the certificate before it was replaced.
+    generation: u64,
+    /// Logical clock for the least recently used eviction.
+    clock: u64,
+}
+
+/// A bounded cache that maps key ids of attestation keys to the subject of their certificate,
+/// i.e., the issuer subject of the keys that they attest.
+#[derive(Debug)]
+pub struct AttestationCertCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl AttestationCertCache {
+    /// Creates a cache that holds at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, state: Default::default() }
+    }
+
+    /// Returns the cached issuer subject for `key_id`. On a miss, `load` is called without
+    /// holding the cache lock, and its result is cached unless the cache was invalidated in
+    /// the meantime.
+    pub fn get_or_load<F>(&self, key_id: i64, load: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        if self.capacity == 0 {
+            return load();
+        }
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            state.clock += 1;
+            let now = state.clock;
+            if let Some(entry) = state.entries.get_mut(&key_id) {
+                entry.last_used = now;
+                return Ok(entry.issuer_subject.clone());
+            }
+            state.generation
+        };
+
+        let issuer_subject = load()?;
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            if state.entries.len() >= self.capacity {
+                if let Some(lru) =
+                    state.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(id, _)| *id)
+                {
+                    state.entries.remove(&lru);
+                }
+            }
+            let last_used = state.clock;
+            state
+                .entries
+                .insert(key_id, Entry { issuer_subject: issuer_subject.clone(), last_used });
+        }
+        Ok(issuer_subject)
+    }
+
+    /// Drops the cached certificate of `key_id`. This must be called after the certificates of
+    /// the key were changed and the change was committed to the database.
+    pub fn invalidate(&self, key_id: i64) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.remove(&key_id);
+        state.generation += 1;
+    }
+
+    /// Returns true if a certificate is cached for `key_id`.
+    pub fn contains(&self, key_id: i64) -> bool {
+        self.state.lock().unwrap().entries.contains_key(&key_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn load_counting<'a>(
+        loads: &'a Cell<u32>,
+        subject: &'a [u8],
+    ) -> impl FnOnce() -> Result<Vec<u8>> + 'a {
+        move || {
+            loads.set(loads.get() + 1);
+            Ok(subject.to_vec())
+        }
+    }
+
+    #[test]
+    fn test_cache_reads_through() -> Result<()> {
+        let cache = AttestationCertCache::new(4);
+        let loads = Cell::new(0);
+
+        assert_eq!(cache.get_or_load(1, load_counting(&loads, b"subject"))?, b"subject");
+        assert_eq!(cache.get_or_load(1, load_counting(&loads, b"other"))?, b"subject");
+        assert_eq!(loads.get(), 1);
+        assert!(cache.contains(1));
+
+        // Failed loads are not cached.
+        assert!(cache.get_or_load(2, || Err(anyhow::anyhow!("load failed"))).is_err());
+        assert!(!cache.contains(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalidation_drops_entry() -> Result<()> {
+        let cache = AttestationCertCache::new(4);
+        let loads = Cell::new(0);
+
+        cache.get_or_load(1, load_counting(&loads, b"old subject"))?;
+        cache.get_or_load(2, load_counting(&loads, b"unrelated"))?;
+        cache.invalidate(1);
+        assert!(!cache.contains(1));
+        assert!(cache.contains(2));
+
+        // The next lookup serves the updated certificate.
+        assert_eq!(cache.get_or_load(1, load_counting(&loads, b"new subject"))?, b"new subject");
+        assert_eq!(cache.get_or_load(1, load_counting(&loads, b"stale"))?, b"new subject");
+        assert_eq!(loads.get(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_racing_with_invalidation_is_not_cached() -> Result<()> {
+        let cache = AttestationCertCache::new(4);
+
+        // The certificate is replaced while the old one is being loaded.
+        let subject = cache.get_or_load(1, || {
+            cache.invalidate(1);
+            Ok(b"old subject".to_vec())
+        })?;
+        assert_eq!(subject, b"old subject");
+        assert!(!cache.contains(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_least_recently_used_entry_is_evicted() -> Result<()> {
+        let cache = AttestationCertCache::new(2);
+        let loads = Cell::new(0);
+
+        cache.get_or_load(1, load_counting(&loads, b"one"))?;
+        cache.get_or_load(2, load_counting(&loads, b"two"))?;
+        cache.get_or_load(1, load_counting(&loads, b"one"))?;
+        cache.get_or_load(3, load_counting(&loads, b"three"))?;
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_zero_size_disables_cache() -> Result<()> {
+        let cache = AttestationCertCache::new(0);
+        let loads = Cell::new(0);
+
+        cache.get_or_load(1, load_counting(&loads, b"subject"))?;
+        cache.get_or_load(1, load_counting(&loads, b"subject"))?;
+        assert_eq!(loads.get(), 2);
+        assert!(!cache.contains(1));
+        Ok(())
+    }
+}
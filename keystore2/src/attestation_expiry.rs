@@ -0,0 +1,456 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the background check of stored attestation chains for expiry.
+//!
+//! The certificates of the attestation chain of a key, in particular the intermediate and root
+//! certificates of the attestation key, may expire long before the key itself. Clients only find
+//! out when a relying party rejects the chain. The garbage collector therefore periodically checks
+//! the stored chains and marks the keys whose chain expired or is about to expire for
+//! re-attestation, logging a metric for each. Each key is marked only once, until its
+//! certificates are replaced. Since the check parses every stored chain, it runs at most once per
+//! check interval.
+//!
+//! Management tools can also ask for the keys of a namespace that expire within a window of
+//! their choosing, either because of the expiry date of the key or because of a certificate of
+//! its attestation chain, so that they can rotate the keys before they stop working.
+
+use crate::clock_rollback::Clock;
+use crate::database::{DateTime, KeystoreDB};
+use crate::ks_err;
+use crate::metrics_store::log_attestation_cert_expiry;
+use crate::sysprop::read_prop_duration;
+use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
+use anyhow::{Context, Result};
+use keystore2_crypto::{parse_not_after_from_certificate, split_certificate_chain};
+use std::time::Duration;
+
+/// Chains with a certificate that expires within this window are considered about to expire.
+const WARNING_WINDOW_PROPERTY: &str = "keystore.attestation_expiry_warning_window";
+const DEFAULT_WARNING_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The minimum time between two checks, in monotonic time.
+const CHECK_INTERVAL_PROPERTY: &str = "keystore.attestation_expiry_check_interval";
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The expiry status of an attestation chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainExpiry {
+    /// No certificate of the chain expires within the warning window.
+    Valid,
+    /// A certificate of the chain expires within the warning window.
+    ExpiringSoon,
+    /// A certificate of the chain has expired.
+    Expired,
+}
+
+/// Returns the earliest notAfter time of the certificates in `chain`, a concatenation of
+/// DER-encoded certificates, in milliseconds since the epoch. Returns None for an empty chain.
+fn chain_not_after(chain: &[u8]) -> Result<Option<i64>> {
+    let certs = split_certificate_chain(chain).context(ks_err!("Malformed chain."))?;
+    let mut earliest: Option<i64> = None;
+    for cert in certs {
+        let not_after = parse_not_after_from_certificate(cert).context(ks_err!())?;
+        earliest = Some(earliest.map_or(not_after, |earliest| earliest.min(not_after)));
+    }
+    Ok(earliest.map(|secs| secs.saturating_mul(1000)))
+}
+
+/// Returns the expiry status at `now` of `chain`, a concatenation of DER-encoded certificates,
+/// given the warning window `window`.
+pub fn chain_expiry(chain: &[u8], now: DateTime, window: Duration) -> Result<ChainExpiry> {
+    let not_after_millis = match chain_not_after(chain)? {
+        Some(not_after_millis) => not_after_millis,
+        None => return Ok(ChainExpiry::Valid),
+    };
+    let now = now.to_millis_epoch();
+    if not_after_millis < now {
+        Ok(ChainExpiry::Expired)
+    } else if not_after_millis - now <= window.as_millis() as i64 {
+        Ok(ChainExpiry::ExpiringSoon)
+    } else {
+        Ok(ChainExpiry::Valid)
+    }
+}
+
+/// What makes a key expire, see `ExpiringKey`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryCause {
+    /// The expiry date of the key itself.
+    KeyExpiry,
+    /// The notAfter time of a certificate of the attestation chain of the key.
+    AttestationChain,
+}
+
+/// A key that expires within a window, see `list_keys_expiring_within`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiringKey {
+    /// The id of the key entry.
+    pub key_id: i64,
+    /// The alias of the key.
+    pub alias: String,
+    /// When the key expires, i.e., the earlier of the expiry date of the key and the expiry of
+    /// its attestation chain.
+    pub expires: DateTime,
+    /// What expires at `expires`.
+    pub cause: ExpiryCause,
+}
+
+/// Returns the keys of the given namespace in `db` that expire no later than `window` after
+/// `now`, sorted by alias. Keys that already expired are included, they are the most urgent to
+/// rotate. Chains that cannot be parsed are skipped, as in `mark_expiring_attestation_chains`,
+/// but the expiry date of their key is still considered.
+pub fn list_keys_expiring_within(
+    db: &mut KeystoreDB,
+    domain: Domain,
+    namespace: i64,
+    now: DateTime,
+    window: Duration,
+) -> Result<Vec<ExpiringKey>> {
+    let window_end =
+        now.to_millis_epoch().saturating_add(window.as_millis().try_into().unwrap_or(i64::MAX));
+    let keys = db.get_key_expiry_data(domain, namespace).context(ks_err!())?;
+    Ok(keys
+        .into_iter()
+        .filter_map(|key| {
+            let key_expiry =
+                key.expiry_date.map(|date| (date.to_millis_epoch(), ExpiryCause::KeyExpiry));
+            let cert_expiry = match key.chain.as_deref().map(chain_not_after) {
+                Some(Ok(Some(millis))) => Some((millis, ExpiryCause::AttestationChain)),
+                Some(Err(e)) => {
+                    log::warn!("Cannot check attestation chain of key {}. {:?}", key.key_id, e);
+                    None
+                }
+                _ => None,
+            };
+            // On a tie, the expiry date of the key is reported.
+            let (expires, cause) = [key_expiry, cert_expiry]
+                .into_iter()
+                .flatten()
+                .min_by_key(|(millis, _)| *millis)?;
+            if expires > window_end {
+                return None;
+            }
+            Some(ExpiringKey {
+                key_id: key.key_id,
+                alias: key.alias,
+                expires: DateTime::from_millis_epoch(expires),
+                cause,
+            })
+        })
+        .collect())
+}
+
+/// Checks the attestation chains of the keys in `db` that are not yet marked for re-attestation
+/// at `now`, and marks those that expired or expire within `window`. Chains that cannot be
+/// parsed, e.g., certificates that were imported in another encoding, are skipped. Returns the
+/// ids of the marked keys with their status.
+pub fn mark_expiring_attestation_chains(
+    db: &mut KeystoreDB,
+    now: DateTime,
+    window: Duration,
+) -> Result<Vec<(i64, ChainExpiry)>> {
+    let chains = db.get_attestation_chains_to_check().context(ks_err!())?;
+    let marked: Vec<(i64, ChainExpiry)> = chains
+        .iter()
+        .filter_map(|(key_id, chain)| match chain_expiry(chain, now, window) {
+            Ok(ChainExpiry::Valid) => None,
+            Ok(expiry) => Some((*key_id, expiry)),
+            Err(e) => {
+                log::warn!("Cannot check attestation chain of key {}. {:?}", key_id, e);
+                None
+            }
+        })
+        .collect();
+    if marked.is_empty() {
+        return Ok(marked);
+    }
+    let key_ids: Vec<i64> = marked.iter().map(|(key_id, _)| *key_id).collect();
+    db.mark_for_reattestation(&key_ids).context(ks_err!())?;
+    for (_, expiry) in &marked {
+        log_attestation_cert_expiry(*expiry == ChainExpiry::Expired);
+    }
+    Ok(marked)
+}
+
+/// Throttles the check of the stored attestation chains to once per check interval.
+#[derive(Debug, Default)]
+pub struct AttestationExpiryCheck {
+    /// The monotonic time of the last check.
+    last_check: Option<i64>,
+}
+
+impl AttestationExpiryCheck {
+    /// Checks the chains in `db` if the check interval elapsed since the last check according to
+    /// `clock`. Returns the marked keys, or None if the check was not due.
+    pub fn run_if_due(
+        &mut self,
+        db: &mut KeystoreDB,
+        clock: &dyn Clock,
+    ) -> Result<Option<Vec<(i64, ChainExpiry)>>> {
+        let monotonic = clock.monotonic_millis();
+        let interval = read_prop_duration(CHECK_INTERVAL_PROPERTY, DEFAULT_CHECK_INTERVAL);
+        if let Some(last_check) = self.last_check {
+            if monotonic - last_check < interval.as_millis() as i64 {
+                return Ok(None);
+            }
+        }
+        self.last_check = Some(monotonic);
+        let now = DateTime::from_millis_epoch(clock.wall_millis());
+        let window = read_prop_duration(WARNING_WINDOW_PROPERTY, DEFAULT_WARNING_WINDOW);
+        mark_expiring_attestation_chains(db, now, window).context(ks_err!()).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+    use crate::database::{
+        BlobInfo, BlobMetaData, CertificateInfo, KeyEntryLoadBits, KeyMetaData, KeyMetaEntry,
+        KeyType, SubComponentType, KEYSTORE_UUID,
+    };
+    use crate::legacy_blob::test_utils::legacy_blob_test_vectors::{
+        LOADED_CACERT_AUTHBOUND, LOADED_CERT_AUTHBOUND,
+    };
+    use crate::metrics_store::METRICS_STORE;
+    use android_security_metrics::aidl::android::security::metrics::{
+        AtomID::AtomID, AttestationCertExpiryStats::AttestationCertExpiryStats,
+        KeystoreAtomPayload::KeystoreAtomPayload,
+    };
+    use android_system_keystore2::aidl::android::system::keystore2::KeyDescriptor::KeyDescriptor;
+    use std::sync::Arc;
+
+    /// The notAfter of the root of LOADED_CACERT_AUTHBOUND, the earliest in the chain, in
+    /// milliseconds. The leaf LOADED_CERT_AUTHBOUND does not expire before 2106.
+    const ROOT_NOT_AFTER: i64 = 1_779_640_132_000;
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+    const WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    fn key(alias: &str) -> KeyDescriptor {
+        KeyDescriptor { domain: Domain::APP, nspace: 1, alias: Some(alias.to_string()), blob: None }
+    }
+
+    fn store_key(db: &mut KeystoreDB, alias: &str, cert: Option<&[u8]>, chain: Option<&[u8]>) {
+        store_key_with_expiry(db, alias, cert, chain, None)
+    }
+
+    fn store_key_with_expiry(
+        db: &mut KeystoreDB,
+        alias: &str,
+        cert: Option<&[u8]>,
+        chain: Option<&[u8]>,
+        expiry_date: Option<i64>,
+    ) {
+        let mut metadata = KeyMetaData::new();
+        if let Some(expiry_date) = expiry_date {
+            metadata.add(KeyMetaEntry::ExpiryDate(DateTime::from_millis_epoch(expiry_date)));
+        }
+        db.store_new_key(
+            &key(alias),
+            KeyType::Client,
+            &[],
+            &BlobInfo::new(alias.as_bytes(), &BlobMetaData::new()),
+            &CertificateInfo::new(cert.map(|c| c.to_vec()), chain.map(|c| c.to_vec())),
+            &metadata,
+            &KEYSTORE_UUID,
+        )
+        .unwrap();
+    }
+
+    fn needs_reattestation(db: &mut KeystoreDB, alias: &str) -> bool {
+        let (_, key_entry) = db
+            .load_key_entry(&key(alias), KeyType::Client, KeyEntryLoadBits::NONE, 1, |_, _| Ok(()))
+            .unwrap();
+        key_entry.needs_reattestation()
+    }
+
+    fn expiry_count(expired: bool) -> i32 {
+        let expected =
+            KeystoreAtomPayload::AttestationCertExpiryStats(AttestationCertExpiryStats { expired });
+        METRICS_STORE
+            .get_atoms(AtomID::ATTESTATION_CERT_EXPIRY_STATS)
+            .unwrap()
+            .iter()
+            .find(|atom| atom.payload == expected)
+            .map_or(0, |atom| atom.count)
+    }
+
+    fn new_db() -> KeystoreDB {
+        let mut db = KeystoreDB::new_in_memory().unwrap();
+        store_key(&mut db, "chain", Some(LOADED_CERT_AUTHBOUND), Some(LOADED_CACERT_AUTHBOUND));
+        store_key(&mut db, "leaf", Some(LOADED_CERT_AUTHBOUND), None);
+        store_key(&mut db, "no_cert", None, Some(LOADED_CACERT_AUTHBOUND));
+        db
+    }
+
+    #[test]
+    fn test_chain_expiry() -> Result<()> {
+        let chain = [LOADED_CERT_AUTHBOUND, LOADED_CACERT_AUTHBOUND].concat();
+        let at = DateTime::from_millis_epoch;
+        assert_eq!(
+            chain_expiry(&chain, at(ROOT_NOT_AFTER - 60 * DAY), WINDOW)?,
+            ChainExpiry::Valid
+        );
+        assert_eq!(
+            chain_expiry(&chain, at(ROOT_NOT_AFTER - 10 * DAY), WINDOW)?,
+            ChainExpiry::ExpiringSoon
+        );
+        assert_eq!(chain_expiry(&chain, at(ROOT_NOT_AFTER + DAY), WINDOW)?, ChainExpiry::Expired);
+        assert_eq!(
+            chain_expiry(LOADED_CERT_AUTHBOUND, at(ROOT_NOT_AFTER + DAY), WINDOW)?,
+            ChainExpiry::Valid
+        );
+        assert!(chain_expiry(b"not a certificate", at(ROOT_NOT_AFTER), WINDOW).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expiring_chain_is_marked() -> Result<()> {
+        let mut db = new_db();
+        let before = expiry_count(false);
+        let now = DateTime::from_millis_epoch(ROOT_NOT_AFTER - 60 * DAY);
+        assert!(mark_expiring_attestation_chains(&mut db, now, WINDOW)?.is_empty());
+
+        let now = DateTime::from_millis_epoch(ROOT_NOT_AFTER - 10 * DAY);
+        let marked = mark_expiring_attestation_chains(&mut db, now, WINDOW)?;
+        assert_eq!(marked.len(), 1);
+        assert_eq!(marked[0].1, ChainExpiry::ExpiringSoon);
+        assert!(needs_reattestation(&mut db, "chain"));
+        assert!(!needs_reattestation(&mut db, "leaf"));
+        assert!(!needs_reattestation(&mut db, "no_cert"));
+        assert_eq!(expiry_count(false), before + 1);
+
+        // Marked keys are not checked again.
+        let now = DateTime::from_millis_epoch(ROOT_NOT_AFTER + DAY);
+        assert!(mark_expiring_attestation_chains(&mut db, now, WINDOW)?.is_empty());
+        assert_eq!(expiry_count(false), before + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_chain_is_marked() -> Result<()> {
+        let mut db = new_db();
+        let before = expiry_count(true);
+        let now = DateTime::from_millis_epoch(ROOT_NOT_AFTER + DAY);
+        let marked = mark_expiring_attestation_chains(&mut db, now, WINDOW)?;
+        assert_eq!(marked.len(), 1);
+        assert_eq!(marked[0].1, ChainExpiry::Expired);
+        assert!(needs_reattestation(&mut db, "chain"));
+        assert!(!needs_reattestation(&mut db, "leaf"));
+        assert_eq!(expiry_count(true), before + 1);
+
+        // Replacing the chain of the key clears the mark.
+        let (key_id_guard, _) = db.load_key_entry(
+            &key("chain"),
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            1,
+            |_, _| Ok(()),
+        )?;
+        db.set_blob(
+            &key_id_guard,
+            SubComponentType::CERT_CHAIN,
+            Some(LOADED_CACERT_AUTHBOUND),
+            None,
+        )?;
+        drop(key_id_guard);
+        assert!(!needs_reattestation(&mut db, "chain"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_is_throttled() -> Result<()> {
+        let mut db = new_db();
+        let clock = Arc::new(FakeClock::default());
+        clock.move_wall_by(ROOT_NOT_AFTER + DAY);
+        let mut check = AttestationExpiryCheck::default();
+        assert_eq!(check.run_if_due(&mut db, &clock)?.map(|marked| marked.len()), Some(1));
+        clock.advance(DEFAULT_CHECK_INTERVAL.as_millis() as i64 - 1);
+        assert_eq!(check.run_if_due(&mut db, &clock)?, None);
+        clock.advance(1);
+        assert_eq!(check.run_if_due(&mut db, &clock)?, Some(vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_keys_expiring_within() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let now = ROOT_NOT_AFTER - 20 * DAY;
+        let chain = Some(LOADED_CACERT_AUTHBOUND);
+        let leaf = Some(LOADED_CERT_AUTHBOUND);
+        // The chain of this key expires in 20 days.
+        store_key(&mut db, "chain", leaf, chain);
+        // This key has no chain, but expires in 10 days, or earlier than its chain.
+        store_key_with_expiry(&mut db, "expiring", leaf, None, Some(now + 10 * DAY));
+        store_key_with_expiry(&mut db, "expiring_with_chain", leaf, chain, Some(now + 5 * DAY));
+        // These keys expired already.
+        store_key_with_expiry(&mut db, "expired", None, None, Some(now - DAY));
+        // These keys expire after all windows.
+        store_key_with_expiry(&mut db, "late", leaf, None, Some(now + 60 * DAY));
+        store_key(&mut db, "leaf", leaf, None);
+        store_key(&mut db, "no_cert", None, None);
+        // Malformed chains are skipped, but the expiry date is still considered.
+        store_key_with_expiry(&mut db, "malformed", Some(b"garbage"), None, Some(now + DAY));
+
+        let list = |db: &mut KeystoreDB, window_days: u64| {
+            list_keys_expiring_within(
+                db,
+                Domain::APP,
+                1,
+                DateTime::from_millis_epoch(now),
+                Duration::from_secs(window_days * 24 * 60 * 60),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|key| (key.alias, key.expires.to_millis_epoch(), key.cause))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            list(&mut db, 0),
+            vec![("expired".to_string(), now - DAY, ExpiryCause::KeyExpiry)]
+        );
+        assert_eq!(
+            list(&mut db, 15),
+            vec![
+                ("expired".to_string(), now - DAY, ExpiryCause::KeyExpiry),
+                ("expiring".to_string(), now + 10 * DAY, ExpiryCause::KeyExpiry),
+                ("expiring_with_chain".to_string(), now + 5 * DAY, ExpiryCause::KeyExpiry),
+                ("malformed".to_string(), now + DAY, ExpiryCause::KeyExpiry),
+            ]
+        );
+        assert_eq!(
+            list(&mut db, 30),
+            vec![
+                ("chain".to_string(), ROOT_NOT_AFTER, ExpiryCause::AttestationChain),
+                ("expired".to_string(), now - DAY, ExpiryCause::KeyExpiry),
+                ("expiring".to_string(), now + 10 * DAY, ExpiryCause::KeyExpiry),
+                ("expiring_with_chain".to_string(), now + 5 * DAY, ExpiryCause::KeyExpiry),
+                ("malformed".to_string(), now + DAY, ExpiryCause::KeyExpiry),
+            ]
+        );
+
+        // Keys of other namespaces are not listed.
+        assert!(list_keys_expiring_within(
+            &mut db,
+            Domain::APP,
+            2,
+            DateTime::from_millis_epoch(now),
+            WINDOW
+        )?
+        .is_empty());
+        Ok(())
+    }
+}
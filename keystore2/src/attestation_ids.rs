@@ -0,0 +1,185 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module checks requests for device ID attestation against the IDs provisioned in KeyMint.
+//!
+//! KeyMint fails key generations that request the attestation of IDs that were never provisioned
+//! with `CANNOT_ATTEST_IDS`, without saying which ID was missing. If the device declares which IDs
+//! are provisioned, keystore can reject such requests before calling KeyMint, naming the missing
+//! IDs. Whether such requests are rejected or only logged is configurable.
+
+use crate::error::{Error, ErrorCode};
+use crate::ks_err;
+use crate::sysprop::{read_prop_bool, read_prop_parsed};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, Tag::Tag,
+};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+
+/// Comma separated list of the IDs that are provisioned in KeyMint for ID attestation, named as
+/// in `ID_TAGS`, or "*" for all. If the property is not set, the provisioning status is unknown
+/// and requests are passed to KeyMint unchecked.
+const PROVISIONED_IDS_PROPERTY: &str = "ro.keystore.attestation_ids_provisioned";
+
+/// If true, the default, requests for IDs that are not provisioned fail before KeyMint is
+/// called. If false, they are only logged and passed to KeyMint.
+const STRICT_PROPERTY: &str = "keystore.attestation_ids_unavailable_strict";
+
+/// The ID attestation tags and the names by which `PROVISIONED_IDS_PROPERTY` refers to them.
+const ID_TAGS: [(Tag, &str); 9] = [
+    (Tag::ATTESTATION_ID_BRAND, "brand"),
+    (Tag::ATTESTATION_ID_DEVICE, "device"),
+    (Tag::ATTESTATION_ID_PRODUCT, "product"),
+    (Tag::ATTESTATION_ID_SERIAL, "serial"),
+    (Tag::ATTESTATION_ID_IMEI, "imei"),
+    (Tag::ATTESTATION_ID_SECOND_IMEI, "second_imei"),
+    (Tag::ATTESTATION_ID_MEID, "meid"),
+    (Tag::ATTESTATION_ID_MANUFACTURER, "manufacturer"),
+    (Tag::ATTESTATION_ID_MODEL, "model"),
+];
+
+/// The provisioning status of the attestation IDs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttestationIdStatus {
+    /// The device does not declare which IDs are provisioned.
+    Unknown,
+    /// Only the IDs of the listed tags are provisioned.
+    Provisioned(HashSet<Tag>),
+}
+
+impl AttestationIdStatus {
+    /// Queries the provisioning status from the `ro.keystore.attestation_ids_provisioned`
+    /// system property. A malformed value is logged and makes the status unknown.
+    pub fn query() -> Self {
+        read_prop_parsed(PROVISIONED_IDS_PROPERTY, Self::Unknown, Self::parse)
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        if value == "*" {
+            return Some(Self::Provisioned(ID_TAGS.iter().map(|(tag, _)| *tag).collect()));
+        }
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| ID_TAGS.iter().find(|(_, n)| *n == name).map(|(tag, _)| *tag))
+            .collect::<Option<HashSet<Tag>>>()
+            .map(Self::Provisioned)
+    }
+
+    /// Returns the ID attestation tags in `params` whose IDs are known not to be provisioned.
+    pub fn unavailable_ids(&self, params: &[KeyParameter]) -> Vec<Tag> {
+        match self {
+            Self::Unknown => vec![],
+            Self::Provisioned(tags) => params
+                .iter()
+                .map(|kp| kp.tag)
+                .filter(|tag| ID_TAGS.iter().any(|(t, _)| t == tag) && !tags.contains(tag))
+                .collect(),
+        }
+    }
+}
+
+/// Checks that the IDs whose attestation `params` request are provisioned, according to the
+/// queried provisioning status and the configured strictness.
+pub fn check_attestation_ids_available(params: &[KeyParameter]) -> Result<()> {
+    check_with_status(params, &AttestationIdStatus::query(), read_prop_bool(STRICT_PROPERTY, true))
+}
+
+/// Returns `ErrorCode::CANNOT_ATTEST_IDS` if `params` request IDs that are not provisioned
+/// according to `status` and `strict` is true. If `strict` is false, the missing IDs are only
+/// logged.
+fn check_with_status(
+    params: &[KeyParameter],
+    status: &AttestationIdStatus,
+    strict: bool,
+) -> Result<()> {
+    let unavailable = status.unavailable_ids(params);
+    if unavailable.is_empty() {
+        return Ok(());
+    }
+    if !strict {
+        log::warn!(
+            "Attestation IDs {:?} are not provisioned. Passing the request on.",
+            unavailable
+        );
+        return Ok(());
+    }
+    Err(Error::Km(ErrorCode::CANNOT_ATTEST_IDS))
+        .context(ks_err!("Attestation IDs unavailable: {:?} are not provisioned.", unavailable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::KeyParameterValue::KeyParameterValue;
+
+    fn blob_param(tag: Tag, value: &[u8]) -> KeyParameter {
+        KeyParameter { tag, value: KeyParameterValue::Blob(value.to_vec()) }
+    }
+
+    fn id_params() -> Vec<KeyParameter> {
+        vec![
+            blob_param(Tag::ATTESTATION_CHALLENGE, b"challenge"),
+            blob_param(Tag::ATTESTATION_ID_BRAND, b"brand"),
+            blob_param(Tag::ATTESTATION_ID_SERIAL, b"serial"),
+        ]
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(
+            AttestationIdStatus::parse("brand, serial"),
+            Some(AttestationIdStatus::Provisioned(
+                [Tag::ATTESTATION_ID_BRAND, Tag::ATTESTATION_ID_SERIAL].into_iter().collect()
+            ))
+        );
+        assert_eq!(
+            AttestationIdStatus::parse("*"),
+            Some(AttestationIdStatus::Provisioned(ID_TAGS.iter().map(|(tag, _)| *tag).collect()))
+        );
+        assert_eq!(
+            AttestationIdStatus::parse(""),
+            Some(AttestationIdStatus::Provisioned(HashSet::new()))
+        );
+        assert_eq!(AttestationIdStatus::parse("brand,fingerprint"), None);
+    }
+
+    #[test]
+    fn test_requested_but_unavailable() {
+        let status = AttestationIdStatus::parse("brand,imei").unwrap();
+        assert_eq!(status.unavailable_ids(&id_params()), vec![Tag::ATTESTATION_ID_SERIAL]);
+        assert_eq!(
+            check_with_status(&id_params(), &status, true)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::CANNOT_ATTEST_IDS))
+        );
+        // Lenient checks leave the decision to KeyMint.
+        assert!(check_with_status(&id_params(), &status, false).is_ok());
+    }
+
+    #[test]
+    fn test_requested_and_available() {
+        let status = AttestationIdStatus::parse("brand,serial").unwrap();
+        assert!(status.unavailable_ids(&id_params()).is_empty());
+        assert!(check_with_status(&id_params(), &status, true).is_ok());
+        assert!(check_with_status(&id_params(), &AttestationIdStatus::Unknown, true).is_ok());
+        // Requests without ID attestation tags are never rejected.
+        let status = AttestationIdStatus::Provisioned(HashSet::new());
+        assert!(check_with_status(&id_params()[..1], &status, true).is_ok());
+    }
+}
@@ -15,26 +15,44 @@
 //! Implements get_attestation_key_info which loads remote provisioned or user
 //! generated attestation keys.
 
+use crate::attestation_cert_cache::ATTESTATION_CERT_CACHE;
 use crate::database::{BlobMetaData, KeyEntryLoadBits, KeyType};
-use crate::database::{KeyIdGuard, KeystoreDB};
+use crate::database::{KeyIdGuard, KeystoreDB, Uuid};
 use crate::error::{Error, ErrorCode};
+use crate::key_parameter::KeyParameter as KsKeyParam;
+use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
 use crate::ks_err;
 use crate::permission::KeyPerm;
 use crate::remote_provisioning::RemProvState;
-use crate::utils::check_key_permission;
+use crate::sysprop::read_prop_parsed;
+use crate::utils::{
+    check_device_attestation_permissions, check_key_permission, AID_APP_START, AID_KEYSTORE,
+    AID_USER_OFFSET,
+};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    AttestationKey::AttestationKey, Certificate::Certificate, KeyParameter::KeyParameter, Tag::Tag,
+    AttestationKey::AttestationKey, Certificate::Certificate, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    Tag::Tag,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
 };
 use anyhow::{Context, Result};
-use keystore2_crypto::parse_subject_from_certificate;
+use keystore2_crypto::{
+    certificate_public_keys_match, parse_subject_from_certificate, split_certificate_chain,
+};
+use log::LevelFilter;
+use std::collections::{HashMap, HashSet};
 
 /// KeyMint takes two different kinds of attestation keys. Remote provisioned keys
 /// and those that have been generated by the user. Unfortunately, they need to be
 /// handled quite differently, thus the different representations.
 pub enum AttestationKeyInfo {
+    RemoteProvisioned {
+        key_id_guard: KeyIdGuard,
+        attestation_key: AttestationKey,
+        attestation_certs: Certificate,
+    },
     RkpdProvisioned {
         attestation_key: AttestationKey,
         attestation_certs: Certificate,
@@ -47,23 +65,732 @@ pub enum AttestationKeyInfo {
     },
 }
 
+impl AttestationKeyInfo {
+    /// Returns a summary of the attestation key that is safe to log. It names the kind of the
+    /// attestation key and non-sensitive identifiers, but never key blobs or certificates.
+    pub fn summary(&self) -> String {
+        match self {
+            Self::RemoteProvisioned { key_id_guard, attestation_key, attestation_certs } => {
+                format!(
+                    concat!(
+                        "RemoteProvisioned {{ key_id: {}, issuer_subject_len: {}, ",
+                        "cert_chain_len: {} }}"
+                    ),
+                    key_id_guard.id(),
+                    attestation_key.issuerSubjectName.len(),
+                    cert_chain_len(attestation_certs)
+                )
+            }
+            Self::RkpdProvisioned { attestation_key, attestation_certs } => format!(
+                "RkpdProvisioned {{ issuer_subject_len: {}, cert_chain_len: {} }}",
+                attestation_key.issuerSubjectName.len(),
+                cert_chain_len(attestation_certs)
+            ),
+            Self::UserGenerated { key_id_guard, issuer_subject, .. } => format!(
+                "UserGenerated {{ key_id: {}, issuer_subject_len: {} }}",
+                key_id_guard.id(),
+                issuer_subject.len()
+            ),
+        }
+    }
+}
+
+/// Returns the number of certificates in `certs`, or 0 if they cannot be parsed.
+fn cert_chain_len(certs: &Certificate) -> usize {
+    split_certificate_chain(&certs.encodedCertificate).map_or(0, |chain| chain.len())
+}
+
+/// Level at which `get_attest_key_info` logs a summary of the attestation parameters of every
+/// request, see `summarize_attestation_params`: "off", the default, "error", "warn", "info",
+/// "debug", or "trace".
+const ATTESTATION_LOG_LEVEL_PROPERTY: &str = "keystore.attestation.log_level";
+
+/// Returns a summary of the attestation parameters in `params` that is safe to log. It lists
+/// the tags that are present, the length of the attestation challenge, and whether device
+/// unique attestation is requested, but never the values of the parameters. In particular, the
+/// attestation challenge itself is never included.
+pub fn summarize_attestation_params(params: &[KeyParameter]) -> String {
+    let mut tags: Vec<Tag> = Vec::new();
+    for kp in params {
+        if !tags.contains(&kp.tag) {
+            tags.push(kp.tag);
+        }
+    }
+    let challenge_len = params.iter().find_map(|kp| match kp {
+        KeyParameter { tag: Tag::ATTESTATION_CHALLENGE, value: KeyParameterValue::Blob(c) } => {
+            Some(c.len())
+        }
+        _ => None,
+    });
+    format!(
+        "AttestationParams {{ tags: {:?}, challenge_len: {}, device_unique: {} }}",
+        tags,
+        challenge_len.map_or_else(|| "none".to_string(), |len| len.to_string()),
+        params.iter().any(|kp| kp.tag == Tag::DEVICE_UNIQUE_ATTESTATION)
+    )
+}
+
+/// Logs the summary of `params` at the level configured by the
+/// `keystore.attestation.log_level` system property. The property is read on every call.
+fn log_attestation_params(params: &[KeyParameter]) {
+    let level = read_prop_parsed(ATTESTATION_LOG_LEVEL_PROPERTY, LevelFilter::Off, |value| {
+        value.parse::<LevelFilter>().ok()
+    });
+    if let Some(level) = level.to_level() {
+        log::log!(level, "In get_attest_key_info: {}", summarize_attestation_params(params));
+    }
+}
+
+/// Sources of attestation keys that are consulted if the caller requests attestation without
+/// specifying an attestation key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttestationKeySource {
+    /// A remote provisioned key from keystore's own key pool.
+    RemoteProvisioned,
+    /// A remote provisioned key obtained from RKPD.
+    Rkpd,
+    /// The factory provisioned batch key of the KeyMint instance. This source always succeeds,
+    /// so sources listed after it are never consulted.
+    Factory,
+}
+
+/// Categories of callers that may be subject to different attestation key policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CallerCategory {
+    /// Callers with a UID below AID_APP_START.
+    System,
+    /// Apps that hold the permission to attest device IDs.
+    PrivilegedApp,
+    /// All other apps.
+    App,
+}
+
+impl CallerCategory {
+    /// Determines the category of `caller_uid`. `is_privileged` is only called for apps.
+    fn of_caller<F: FnOnce() -> bool>(caller_uid: u32, is_privileged: F) -> Self {
+        if caller_uid % AID_USER_OFFSET < AID_APP_START {
+            Self::System
+        } else if is_privileged() {
+            Self::PrivilegedApp
+        } else {
+            Self::App
+        }
+    }
+}
+
+/// Maps each caller category to the attestation key sources that are tried, in order, and to
+/// the pool of keystore's own key pools that `AttestationKeySource::RemoteProvisioned` draws
+/// from. Categories without a pool use the default pool. If a fallback key is configured, it is
+/// used when none of the remote provisioned sources yields a key, instead of the factory
+/// provisioned key or of failing the request.
+///
+/// Device unique attestation requests have their own sources and pool regardless of the caller
+/// category. By default, they only use `AttestationKeySource::Factory`, i.e., KeyMint signs them
+/// with its factory provisioned device unique key. A device without such a key can point them at
+/// a pool of remote provisioned device unique keys instead. They never use the fallback key.
+#[derive(Debug, Clone)]
+pub struct AttestationKeyPolicy {
+    sources: HashMap<CallerCategory, Vec<AttestationKeySource>>,
+    pools: HashMap<CallerCategory, String>,
+    fallback_key: Option<KeyDescriptor>,
+    device_unique_sources: Vec<AttestationKeySource>,
+    device_unique_pool: Option<String>,
+}
+
+impl Default for AttestationKeyPolicy {
+    /// System components prefer keys from keystore's own pool, apps prefer RKPD. All categories
+    /// fall back to the factory provisioned key. System components consult RKPD before, so that
+    /// they do not fall back to the factory provisioned key on RKP only devices, where RKPD
+    /// fails instead if it has no key.
+    fn default() -> Self {
+        use AttestationKeySource::*;
+        Self::new([
+            (CallerCategory::System, vec![RemoteProvisioned, Rkpd, Factory]),
+            (CallerCategory::PrivilegedApp, vec![Rkpd, RemoteProvisioned, Factory]),
+            (CallerCategory::App, vec![Rkpd, Factory]),
+        ])
+    }
+}
+
+impl AttestationKeyPolicy {
+    /// Creates a policy from (category, sources) pairs. Categories that are not listed use no
+    /// attestation key source at all.
+    pub fn new<I>(sources: I) -> Self
+    where
+        I: IntoIterator<Item = (CallerCategory, Vec<AttestationKeySource>)>,
+    {
+        Self {
+            sources: sources.into_iter().collect(),
+            pools: Default::default(),
+            fallback_key: None,
+            device_unique_sources: vec![AttestationKeySource::Factory],
+            device_unique_pool: None,
+        }
+    }
+
+    /// Makes the given caller category draw remote provisioned keys from the named pool.
+    pub fn with_pool(mut self, category: CallerCategory, pool: &str) -> Self {
+        self.pools.insert(category, pool.to_string());
+        self
+    }
+
+    /// Makes the user generated attestation key `key` the fallback for all caller categories.
+    pub fn with_fallback_key(mut self, key: KeyDescriptor) -> Self {
+        self.fallback_key = Some(key);
+        self
+    }
+
+    /// Returns the fallback attestation key, if one is configured.
+    pub fn fallback_key(&self) -> Option<&KeyDescriptor> {
+        self.fallback_key.as_ref()
+    }
+
+    /// Makes device unique attestation requests try `sources` in order, drawing remote
+    /// provisioned keys from the named pool, or from the default pool if `pool` is None.
+    pub fn with_device_unique_sources(
+        mut self,
+        sources: Vec<AttestationKeySource>,
+        pool: Option<&str>,
+    ) -> Self {
+        self.device_unique_sources = sources;
+        self.device_unique_pool = pool.map(str::to_string);
+        self
+    }
+
+    /// Returns the attestation key sources for device unique attestation requests in order of
+    /// preference.
+    pub fn device_unique_sources(&self) -> &[AttestationKeySource] {
+        &self.device_unique_sources
+    }
+
+    /// Returns the key pool that device unique attestation requests draw remote provisioned keys
+    /// from, or None for the default pool.
+    pub fn device_unique_pool(&self) -> Option<&str> {
+        self.device_unique_pool.as_deref()
+    }
+
+    /// Returns the attestation key sources for the given caller category in order of preference.
+    pub fn sources_for(&self, category: CallerCategory) -> &[AttestationKeySource] {
+        self.sources.get(&category).map_or(&[], |s| s.as_slice())
+    }
+
+    /// Returns the key pool that the given caller category draws remote provisioned keys from,
+    /// or None for the default pool.
+    pub fn pool_for(&self, category: CallerCategory) -> Option<&str> {
+        self.pools.get(&category).map(String::as_str)
+    }
+
+    /// Returns the policy of the KeyMint backends of `security_level`: the default policy with
+    /// the overrides from the policy property of the security level, if any, and the fallback
+    /// key from the fallback key property of the security level, if any. A malformed property
+    /// is logged and ignored as a whole.
+    pub fn for_security_level(security_level: SecurityLevel) -> Self {
+        let policy = match policy_property(security_level) {
+            Some(property) => read_prop_parsed(property, Self::default(), |value| {
+                Self::default().with_overrides(value)
+            }),
+            None => Self::default(),
+        };
+        let fallback_key = fallback_key_property(security_level).and_then(|property| {
+            read_prop_parsed(property, None, |value| parse_key(value).map(Some))
+        });
+        match fallback_key {
+            Some(key) => policy.with_fallback_key(key),
+            None => policy,
+        }
+    }
+
+    /// Replaces the sources of the categories listed in `value`, see `policy_property`.
+    fn with_overrides(mut self, value: &str) -> Option<Self> {
+        for entry in value.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (category, sources) = entry.split_once('=')?;
+            let category = match category.trim() {
+                "system" => Some(CallerCategory::System),
+                "privileged_app" => Some(CallerCategory::PrivilegedApp),
+                "app" => Some(CallerCategory::App),
+                // Device unique attestation requests are configured like a category.
+                "device_unique" => None,
+                _ => return None,
+            };
+            let mut pool = None;
+            let sources = sources
+                .split(',')
+                .map(|source| match source.trim() {
+                    "pool" => Some(AttestationKeySource::RemoteProvisioned),
+                    "rkpd" => Some(AttestationKeySource::Rkpd),
+                    "factory" => Some(AttestationKeySource::Factory),
+                    source => {
+                        let name = source.strip_prefix("pool:").filter(|name| !name.is_empty())?;
+                        pool = Some(name.to_string());
+                        Some(AttestationKeySource::RemoteProvisioned)
+                    }
+                })
+                .collect::<Option<Vec<_>>>()?;
+            let category = match category {
+                Some(category) => category,
+                None => {
+                    self.device_unique_sources = sources;
+                    self.device_unique_pool = pool;
+                    continue;
+                }
+            };
+            self.sources.insert(category, sources);
+            match pool {
+                Some(pool) => self.pools.insert(category, pool),
+                None => self.pools.remove(&category),
+            };
+        }
+        Some(self)
+    }
+}
+
+/// Returns the property that overrides the attestation key policy of `security_level`, so that
+/// StrongBox and the TEE can order their sources independently. The value is a `;` separated
+/// list of `<category>=<sources>` entries. The category is one of `system`, `privileged_app`,
+/// and `app`, and the sources are a comma separated list of `pool` for keystore's own default key
+/// pool, `pool:<name>` for its named key pool `<name>`, `rkpd`, and `factory`, e.g.,
+/// `app=pool,factory` or `system=pool:vpn,factory`. Categories that are not listed keep the
+/// sources of the default policy. The sources of device unique attestation requests are
+/// configured in the same way with the category `device_unique`, e.g.,
+/// `device_unique=pool:device_unique`.
+fn policy_property(security_level: SecurityLevel) -> Option<&'static str> {
+    match security_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => Some("keystore.tee.attestation_key_policy"),
+        SecurityLevel::STRONGBOX => Some("keystore.strongbox.attestation_key_policy"),
+        _ => None,
+    }
+}
+
+/// Returns the property that configures the fallback attestation key of `security_level`. The
+/// value names a user generated attestation key as `app:<alias>` for a key of the caller's own
+/// namespace, or as `selinux:<namespace>:<alias>`, e.g., `selinux:102:break_glass`. Callers need
+/// the permission to use the key, like for any attestation key they specify themselves.
+fn fallback_key_property(security_level: SecurityLevel) -> Option<&'static str> {
+    match security_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => Some("keystore.tee.fallback_attestation_key"),
+        SecurityLevel::STRONGBOX => Some("keystore.strongbox.fallback_attestation_key"),
+        _ => None,
+    }
+}
+
+/// Parses a key descriptor of the form described at `fallback_key_property`.
+fn parse_key(value: &str) -> Option<KeyDescriptor> {
+    let (domain, rest) = value.trim().split_once(':')?;
+    let (domain, nspace, alias) = match domain {
+        "app" => (Domain::APP, 0, rest),
+        "selinux" => {
+            let (nspace, alias) = rest.split_once(':')?;
+            (Domain::SELINUX, nspace.parse::<i64>().ok()?, alias)
+        }
+        _ => return None,
+    };
+    if alias.is_empty() {
+        return None;
+    }
+    Some(KeyDescriptor { domain, nspace, alias: Some(alias.to_string()), blob: None })
+}
+
+/// Comma separated list of the UIDs that may obtain attestation keys from RKPD. If the property
+/// is not set, empty, or set to "*", all UIDs may use RKPD.
+const RKPD_UID_ALLOWLIST_PROPERTY: &str = "keystore.rkpd_uid_allowlist";
+
+/// Determines which callers may take the RKPD path. Callers that are not permitted use
+/// keystore's own pool of remote provisioned keys instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RkpdUidAllowlist {
+    /// Every caller may use RKPD.
+    All,
+    /// Only the listed UIDs may use RKPD.
+    Uids(HashSet<u32>),
+}
+
+impl RkpdUidAllowlist {
+    /// Reads the allowlist from the `keystore.rkpd_uid_allowlist` system property. The property
+    /// is read on every call, so changes take effect with the next key generation. A malformed
+    /// value is logged and permits everyone.
+    pub fn from_property() -> Self {
+        read_prop_parsed(RKPD_UID_ALLOWLIST_PROPERTY, Self::All, Self::parse)
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        // Clearing the property restores the default, like leaving it unset.
+        if matches!(value.trim(), "" | "*") {
+            return Some(Self::All);
+        }
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|uid| !uid.is_empty())
+            .map(|uid| uid.parse::<u32>().ok())
+            .collect::<Option<HashSet<u32>>>()
+            .map(Self::Uids)
+    }
+
+    /// Returns true if `caller_uid` may obtain attestation keys from RKPD.
+    pub fn permits(&self, caller_uid: u32) -> bool {
+        match self {
+            Self::All => true,
+            Self::Uids(uids) => uids.contains(&caller_uid),
+        }
+    }
+}
+
+/// Returns `sources` with `Rkpd` replaced by `RemoteProvisioned` if the caller may not use RKPD.
+/// Each source is only listed once.
+fn restrict_rkpd(
+    sources: &[AttestationKeySource],
+    rkpd_permitted: bool,
+) -> Vec<AttestationKeySource> {
+    let mut result = Vec::with_capacity(sources.len());
+    for source in sources {
+        let source = match source {
+            AttestationKeySource::Rkpd if !rkpd_permitted => {
+                AttestationKeySource::RemoteProvisioned
+            }
+            s => *s,
+        };
+        if !result.contains(&source) {
+            result.push(source);
+        }
+    }
+    result
+}
+
+/// Tries `sources` in order and returns the first key found. `Factory` yields None without
+/// consulting `try_source`. If no source yields a key, ATTESTATION_KEYS_NOT_PROVISIONED is
+/// returned.
+fn select_attestation_key<T, F>(
+    sources: &[AttestationKeySource],
+    mut try_source: F,
+) -> Result<Option<T>>
+where
+    F: FnMut(AttestationKeySource) -> Result<Option<T>>,
+{
+    for source in sources {
+        if *source == AttestationKeySource::Factory {
+            return Ok(None);
+        }
+        if let Some(key) = try_source(*source)
+            .with_context(|| ks_err!("Trying attestation key source {:?}.", source))?
+        {
+            return Ok(Some(key));
+        }
+    }
+    Err(Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED))
+        .context(ks_err!("No attestation key available from {:?}.", sources))
+}
+
+/// Returns the key of `selected`, the result of `select_attestation_key`, unless it yielded no
+/// key, i.e., selected the factory provisioned key or failed. In that case, and if a fallback
+/// key is configured, `load_fallback` is called with the fallback key and its result returned.
+fn with_fallback<T, F>(
+    selected: Result<Option<T>>,
+    fallback_key: Option<&KeyDescriptor>,
+    load_fallback: F,
+) -> Result<Option<T>>
+where
+    F: FnOnce(&KeyDescriptor) -> Result<T>,
+{
+    let fallback_key = match (selected, fallback_key) {
+        (Ok(Some(key)), _) => return Ok(Some(key)),
+        (selected, None) => return selected,
+        (Ok(None), Some(fallback_key)) => fallback_key,
+        (Err(e), Some(fallback_key)) => {
+            log::warn!("No remote provisioned attestation key, using the fallback key: {:?}", e);
+            fallback_key
+        }
+    };
+    load_fallback(fallback_key).context(ks_err!("Trying the fallback attestation key.")).map(Some)
+}
+
+/// A disagreement between the remote provisioned attestation key source that a caller category
+/// prefers and the sources that actually have keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProvisioningDisagreement {
+    /// RKPD is preferred but has no keys, while keystore's own key pool has keys.
+    RkpdEmptyPoolAvailable,
+    /// Keystore's own key pool is preferred but empty, while RKPD has keys.
+    PoolEmptyRkpdAvailable,
+}
+
+/// Returns the disagreement, if any, between the first of `sources` and the availability of keys
+/// in keystore's own key pool and in RKPD.
+fn find_provisioning_disagreement(
+    sources: &[AttestationKeySource],
+    pool_available: bool,
+    rkpd_available: bool,
+) -> Option<ProvisioningDisagreement> {
+    match sources.first()? {
+        AttestationKeySource::Rkpd if !rkpd_available && pool_available => {
+            Some(ProvisioningDisagreement::RkpdEmptyPoolAvailable)
+        }
+        AttestationKeySource::RemoteProvisioned if !pool_available && rkpd_available => {
+            Some(ProvisioningDisagreement::PoolEmptyRkpdAvailable)
+        }
+        _ => None,
+    }
+}
+
+/// Checks for each caller category whether the remote provisioned attestation key source that
+/// `policy` prefers has keys while the other one has none. Such a disagreement usually means
+/// that the device is misconfigured, and attestations of the category fail or fall back to the
+/// factory provisioned key once the fallback sources are exhausted.
+pub fn check_provisioning_paths(
+    rem_prov_state: &RemProvState,
+    policy: &AttestationKeyPolicy,
+    db: &mut KeystoreDB,
+) -> Result<Vec<(CallerCategory, ProvisioningDisagreement)>> {
+    let rkpd_available = rem_prov_state.is_rkpd_available(AID_KEYSTORE);
+    let mut disagreements = Vec::new();
+    for category in [CallerCategory::System, CallerCategory::PrivilegedApp, CallerCategory::App] {
+        let pool_available = rem_prov_state
+            .get_attestation_pool_size(policy.pool_for(category), db)
+            .context(ks_err!())?
+            > 0;
+        if let Some(disagreement) = find_provisioning_disagreement(
+            policy.sources_for(category),
+            pool_available,
+            rkpd_available,
+        ) {
+            disagreements.push((category, disagreement));
+        }
+    }
+    Ok(disagreements)
+}
+
+/// An estimate of how many more attestations a KeyMint instance can sign before its attestation
+/// keys need to be provisioned again, see `estimate_attestation_capacity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttestationCapacity {
+    /// The remaining uses of the remote provisioned keys in keystore's own key pools that the
+    /// policy draws from, summed over the pools, or None if the uses of the keys are not
+    /// limited.
+    pub remote_provisioned_uses: Option<i64>,
+    /// True if RKPD hands out attestation keys. RKPD does not report how many keys it holds, so
+    /// its keys are not included in the estimate.
+    pub rkpd_available: bool,
+    /// True if the policy falls back to a user generated attestation key. User generated keys
+    /// are not remote provisioned, so they can sign attestations without limit.
+    pub user_generated_unlimited: bool,
+}
+
+impl AttestationCapacity {
+    /// Returns the estimate as a single number, or None if the attestations are effectively
+    /// unlimited, because a user generated attestation key is available or the remote
+    /// provisioned keys have no use limit.
+    pub fn estimate(&self) -> Option<i64> {
+        if self.user_generated_unlimited {
+            None
+        } else {
+            self.remote_provisioned_uses
+        }
+    }
+}
+
+/// Estimates the remaining attestation capacity of the KeyMint instance of `rem_prov_state`
+/// across all attestation key sources of `policy`: the remaining uses of the remote provisioned
+/// keys in each of keystore's own key pools that a caller category draws from, see
+/// `RemProvState::get_remaining_attestation_key_uses`, whether RKPD has keys, and whether a
+/// user generated key backs the policy. The factory provisioned batch key is not counted.
+pub fn estimate_attestation_capacity(
+    rem_prov_state: &RemProvState,
+    policy: &AttestationKeyPolicy,
+    db: &mut KeystoreDB,
+) -> Result<AttestationCapacity> {
+    estimate_attestation_capacity_with(
+        policy,
+        rem_prov_state.is_rkpd_available(AID_KEYSTORE),
+        |pool| rem_prov_state.get_remaining_attestation_key_uses(pool, db),
+    )
+}
+
+fn estimate_attestation_capacity_with<F>(
+    policy: &AttestationKeyPolicy,
+    rkpd_available: bool,
+    mut remaining_uses: F,
+) -> Result<AttestationCapacity>
+where
+    F: FnMut(Option<&str>) -> Result<Option<i64>>,
+{
+    let mut pools: Vec<Option<&str>> = Vec::new();
+    for category in [CallerCategory::System, CallerCategory::PrivilegedApp, CallerCategory::App] {
+        let pool = policy.pool_for(category);
+        if policy.sources_for(category).contains(&AttestationKeySource::RemoteProvisioned)
+            && !pools.contains(&pool)
+        {
+            pools.push(pool);
+        }
+    }
+    let pool = policy.device_unique_pool();
+    if policy.device_unique_sources().contains(&AttestationKeySource::RemoteProvisioned)
+        && !pools.contains(&pool)
+    {
+        pools.push(pool);
+    }
+    let mut remote_provisioned_uses = Some(0);
+    for pool in pools {
+        let uses =
+            remaining_uses(pool).context(ks_err!("Failed to count the uses of {:?}.", pool))?;
+        remote_provisioned_uses = remote_provisioned_uses.zip(uses).map(|(sum, uses)| sum + uses);
+    }
+    Ok(AttestationCapacity {
+        remote_provisioned_uses,
+        rkpd_available,
+        user_generated_unlimited: policy.fallback_key().is_some(),
+    })
+}
+
+/// How an attestation key is selected for a request, before any source is consulted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SelectionPlan {
+    /// The caller specified a user generated attestation key.
+    UserGenerated,
+    /// Attestation was not requested, because no challenge is present.
+    NotRequested,
+    /// Device unique attestation was requested. `sources` are the device unique sources of the
+    /// policy. With the default sources, KeyMint signs it with its factory provisioned device
+    /// unique key, so no remote provisioned key is selected. `use_rkpd` is as for `Sources`.
+    DeviceUnique { use_rkpd: bool, sources: Vec<AttestationKeySource> },
+    /// `sources` are tried in order for a caller of `category`. `use_rkpd` tells whether the
+    /// caller is on the RKPD UID allowlist, and `fallback` whether a fallback key is configured.
+    Sources {
+        category: CallerCategory,
+        use_rkpd: bool,
+        sources: Vec<AttestationKeySource>,
+        fallback: bool,
+    },
+}
+
+/// Plans the selection of the attestation key for a request of `caller_uid`. `is_privileged`
+/// and `rkpd_allowlist` are only called if the sources of `policy` are consulted.
+fn plan_selection<F, G>(
+    caller_uid: u32,
+    attest_key_specified: bool,
+    params: &[KeyParameter],
+    policy: &AttestationKeyPolicy,
+    is_privileged: F,
+    rkpd_allowlist: G,
+) -> SelectionPlan
+where
+    F: FnOnce() -> bool,
+    G: FnOnce() -> RkpdUidAllowlist,
+{
+    if attest_key_specified {
+        SelectionPlan::UserGenerated
+    } else if !params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
+        SelectionPlan::NotRequested
+    } else if params.iter().any(|kp| kp.tag == Tag::DEVICE_UNIQUE_ATTESTATION) {
+        let use_rkpd = rkpd_allowlist().permits(caller_uid);
+        let sources = restrict_rkpd(policy.device_unique_sources(), use_rkpd);
+        SelectionPlan::DeviceUnique { use_rkpd, sources }
+    } else {
+        let category = CallerCategory::of_caller(caller_uid, is_privileged);
+        let use_rkpd = rkpd_allowlist().permits(caller_uid);
+        let sources = restrict_rkpd(policy.sources_for(category), use_rkpd);
+        SelectionPlan::Sources {
+            category,
+            use_rkpd,
+            sources,
+            fallback: policy.fallback_key().is_some(),
+        }
+    }
+}
+
+/// The reason why `get_attest_key_info` selected no attestation key, in which case
+/// KeyMint signs the attestation with its factory provisioned key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoAttestKeyReason {
+    /// The request has no attestation challenge, so attestation was not requested.
+    NoChallenge,
+    /// Device unique attestation was requested, and the device unique sources of the policy
+    /// selected KeyMint's factory provisioned device unique key.
+    DeviceUnique,
+    /// The sources of the policy for the caller's category selected KeyMint's factory
+    /// provisioned batch key, and no fallback key is configured.
+    FactoryKey,
+}
+
+/// The result of `get_attest_key_info`.
+pub enum AttestKeyInfoOutcome {
+    /// The attestation key that was selected.
+    Selected(AttestationKeyInfo),
+    /// No attestation key was selected for the given reason.
+    NotSelected(NoAttestKeyReason),
+}
+
 /// This function loads and, optionally, assigns the caller's remote provisioned
-/// attestation key if a challenge is present. Alternatively, if `attest_key_descriptor` is given,
-/// it loads the user generated attestation key from the database.
+/// attestation key if a challenge is present. The attestation key sources are tried in the
+/// order given by `policy` for the caller's category. Callers that are not on the RKPD UID
+/// allowlist use keystore's own key pool instead of RKPD. App callers that consumed too many remote
+/// provisioned keys recently fail with OUT_OF_KEYS_TRANSIENT_ERROR, see `RkpUidLimit`. If no
+/// source yields a remote provisioned key, the fallback key of `policy` is loaded if one is
+/// configured. Device unique attestation requests try the device unique sources of `policy`
+/// instead and never use the fallback key. Alternatively, if
+/// `attest_key_descriptor` is given, it loads the user generated attestation key from the
+/// database. User generated keys must belong to the KeyMint instance of `rem_prov_state`.
+/// If no attestation key is selected, the outcome tells why.
 pub fn get_attest_key_info(
     key: &KeyDescriptor,
     caller_uid: u32,
     attest_key_descriptor: Option<&KeyDescriptor>,
     params: &[KeyParameter],
     rem_prov_state: &RemProvState,
+    policy: &AttestationKeyPolicy,
     db: &mut KeystoreDB,
-) -> Result<Option<AttestationKeyInfo>> {
-    let challenge_present = params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE);
-    let is_device_unique_attestation =
-        params.iter().any(|kp| kp.tag == Tag::DEVICE_UNIQUE_ATTESTATION);
-    match attest_key_descriptor {
-        // Do not select an RKP key if DEVICE_UNIQUE_ATTESTATION is present.
-        None if challenge_present && !is_device_unique_attestation => rem_prov_state
+) -> Result<AttestKeyInfoOutcome> {
+    log_attestation_params(params);
+    if let Some(attest_key) = attest_key_descriptor {
+        return get_user_generated_attestation_key(
+            attest_key,
+            caller_uid,
+            &rem_prov_state.get_uuid(),
+            db,
+        )
+        .context(ks_err!("Trying to load attest key"))
+        .map(AttestKeyInfoOutcome::Selected);
+    }
+    let (pool, sources, fallback_key, reason) = match plan_selection(
+        caller_uid,
+        false,
+        params,
+        policy,
+        || check_device_attestation_permissions().is_ok(),
+        RkpdUidAllowlist::from_property,
+    ) {
+        SelectionPlan::Sources { category, sources, .. } => (
+            policy.pool_for(category),
+            sources,
+            policy.fallback_key(),
+            NoAttestKeyReason::FactoryKey,
+        ),
+        SelectionPlan::DeviceUnique { sources, .. } => {
+            (policy.device_unique_pool(), sources, None, NoAttestKeyReason::DeviceUnique)
+        }
+        // User generated attestation keys were loaded above.
+        SelectionPlan::NotRequested | SelectionPlan::UserGenerated => {
+            return Ok(AttestKeyInfoOutcome::NotSelected(NoAttestKeyReason::NoChallenge))
+        }
+    };
+    // Throttled callers fail rather than falling back to another attestation key.
+    if sources.first().map_or(false, |source| *source != AttestationKeySource::Factory) {
+        rem_prov_state
+            .check_key_consumption(caller_uid)
+            .context(ks_err!("The caller exceeded its remote provisioned key limit."))?;
+    }
+    let selected = select_attestation_key(&sources, |source| match source {
+        AttestationKeySource::RemoteProvisioned => rem_prov_state
+            .get_remote_provisioned_key_and_certs(key, params, pool, db)
+            .context(ks_err!("Trying to get remote provisioned attestation key."))
+            .map(|result| {
+                result.map(|(key_id_guard, attestation_key, attestation_certs)| {
+                    AttestationKeyInfo::RemoteProvisioned {
+                        key_id_guard,
+                        attestation_key,
+                        attestation_certs,
+                    }
+                })
+            }),
+        AttestationKeySource::Rkpd => rem_prov_state
             .get_rkpd_attestation_key_and_certs(key, caller_uid, params)
             .context(ks_err!("Trying to get attestation key from RKPD."))
             .map(|result| {
@@ -71,56 +798,1102 @@ pub fn get_attest_key_info(
                     AttestationKeyInfo::RkpdProvisioned { attestation_key, attestation_certs }
                 })
             }),
-        None => Ok(None),
-        Some(attest_key) => get_user_generated_attestation_key(attest_key, caller_uid, db)
-            .context(ks_err!("Trying to load attest key"))
-            .map(Some),
+        AttestationKeySource::Factory => Ok(None),
+    });
+    if let Ok(Some(_)) = &selected {
+        rem_prov_state.record_key_consumption(caller_uid);
+    }
+    let selected = with_fallback(selected, fallback_key, |fallback_key| {
+        get_user_generated_attestation_key(fallback_key, caller_uid, &rem_prov_state.get_uuid(), db)
+    })?;
+    Ok(match selected {
+        Some(info) => AttestKeyInfoOutcome::Selected(info),
+        None => AttestKeyInfoOutcome::NotSelected(reason),
+    })
+}
+
+/// The attestation key that `get_attest_key_info` selects for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttestKeySelection {
+    /// The user generated attestation key specified by the caller.
+    UserGenerated,
+    /// No attestation key, because attestation was not requested.
+    NotRequested,
+    /// The factory provisioned device unique key, because device unique attestation was
+    /// requested and its sources did not yield a remote provisioned key.
+    DeviceUnique,
+    /// A key from the given source. `Factory` means that KeyMint uses its batch key.
+    Source(AttestationKeySource),
+    /// The fallback key of the policy, because no source has a remote provisioned key.
+    Fallback,
+    /// None, because no source has a key. The key generation fails.
+    Unavailable,
+}
+
+/// A trace of the decisions that lead to the selection of an attestation key for a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestKeySelectionTrace {
+    /// Whether the request contains an attestation challenge.
+    pub challenge_present: bool,
+    /// Whether the request asks for device unique attestation.
+    pub device_unique_attestation: bool,
+    /// Whether the caller specified a user generated attestation key.
+    pub attest_key_specified: bool,
+    /// The category of the caller, if the sources of the policy apply to the request.
+    pub category: Option<CallerCategory>,
+    /// Whether the caller may use RKPD. Only evaluated if the sources of the policy apply.
+    pub use_rkpd: bool,
+    /// The sources that apply to the caller, in order.
+    pub sources: Vec<AttestationKeySource>,
+    /// The sources that were consulted, in order, and whether each of them has a key. Sources
+    /// that failed, e.g., RKPD on a device that is RKP only, are listed as having no key.
+    pub consulted: Vec<(AttestationKeySource, bool)>,
+    /// The selected attestation key.
+    pub selection: AttestKeySelection,
+}
+
+/// Traces the selection of `plan`. `has_key` tells whether a source has a key and must not
+/// have side effects.
+fn trace_selection<F>(
+    params: &[KeyParameter],
+    attest_key_specified: bool,
+    plan: SelectionPlan,
+    mut has_key: F,
+) -> AttestKeySelectionTrace
+where
+    F: FnMut(AttestationKeySource) -> Result<bool>,
+{
+    let mut trace = AttestKeySelectionTrace {
+        challenge_present: params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE),
+        device_unique_attestation: params.iter().any(|kp| kp.tag == Tag::DEVICE_UNIQUE_ATTESTATION),
+        attest_key_specified,
+        category: None,
+        use_rkpd: false,
+        sources: vec![],
+        consulted: vec![],
+        selection: AttestKeySelection::NotRequested,
+    };
+    trace.selection = match plan {
+        SelectionPlan::UserGenerated => AttestKeySelection::UserGenerated,
+        SelectionPlan::NotRequested => AttestKeySelection::NotRequested,
+        SelectionPlan::DeviceUnique { use_rkpd, sources } => {
+            trace.use_rkpd = use_rkpd;
+            let consulted = &mut trace.consulted;
+            let result = select_attestation_key(&sources, |source| {
+                let found = has_key(source);
+                consulted.push((source, matches!(found, Ok(true))));
+                found.map(|found| found.then_some(source))
+            });
+            trace.sources = sources;
+            match result {
+                Ok(Some(source)) => AttestKeySelection::Source(source),
+                Ok(None) => AttestKeySelection::DeviceUnique,
+                Err(_) => AttestKeySelection::Unavailable,
+            }
+        }
+        SelectionPlan::Sources { category, use_rkpd, sources, fallback } => {
+            trace.category = Some(category);
+            trace.use_rkpd = use_rkpd;
+            let consulted = &mut trace.consulted;
+            let result = select_attestation_key(&sources, |source| {
+                let found = has_key(source);
+                consulted.push((source, matches!(found, Ok(true))));
+                found.map(|found| found.then_some(source))
+            });
+            trace.sources = sources;
+            match result {
+                Ok(Some(source)) => AttestKeySelection::Source(source),
+                _ if fallback => AttestKeySelection::Fallback,
+                Ok(None) => AttestKeySelection::Source(AttestationKeySource::Factory),
+                Err(_) => AttestKeySelection::Unavailable,
+            }
+        }
+    };
+    trace
+}
+
+/// Explains which attestation key `get_attest_key_info` would select for the given request and
+/// why. This has no side effects: no attestation key is assigned, locked, or loaded, and no user
+/// generated attestation key is checked for accessibility.
+pub fn explain_attest_key_selection(
+    key: &KeyDescriptor,
+    caller_uid: u32,
+    attest_key_descriptor: Option<&KeyDescriptor>,
+    params: &[KeyParameter],
+    rem_prov_state: &RemProvState,
+    policy: &AttestationKeyPolicy,
+    db: &mut KeystoreDB,
+) -> AttestKeySelectionTrace {
+    let attest_key_specified = attest_key_descriptor.is_some();
+    let plan = plan_selection(
+        caller_uid,
+        attest_key_specified,
+        params,
+        policy,
+        || check_device_attestation_permissions().is_ok(),
+        RkpdUidAllowlist::from_property,
+    );
+    let pool = match &plan {
+        SelectionPlan::Sources { category, .. } => policy.pool_for(*category),
+        SelectionPlan::DeviceUnique { .. } => policy.device_unique_pool(),
+        _ => None,
+    };
+    trace_selection(params, attest_key_specified, plan, |source| match source {
+        AttestationKeySource::RemoteProvisioned => {
+            rem_prov_state.has_remote_provisioned_key(key, params, pool, db)
+        }
+        AttestationKeySource::Rkpd => rem_prov_state.has_rkpd_key(key, params),
+        AttestationKeySource::Factory => Ok(true),
+    })
+}
+
+/// The DER encoding of an X.509 name without any relative distinguished names.
+const EMPTY_DER_NAME: &[u8] = &[0x30, 0x00];
+
+/// Returns `subject`, the subject of the certificate of a user generated attestation key,
+/// unless it is empty. KeyMint rejects an empty issuer subject only once it signs the new key,
+/// so it is rejected up front with `ErrorCode::INVALID_ISSUER_SUBJECT`.
+fn check_issuer_subject(subject: Vec<u8>) -> Result<Vec<u8>> {
+    if subject.is_empty() || subject == EMPTY_DER_NAME {
+        return Err(Error::Km(ErrorCode::INVALID_ISSUER_SUBJECT))
+            .context(ks_err!("The certificate of the attestation key has an empty subject."));
     }
+    Ok(subject)
 }
 
 fn get_user_generated_attestation_key(
     key: &KeyDescriptor,
     caller_uid: u32,
+    km_uuid: &Uuid,
     db: &mut KeystoreDB,
 ) -> Result<AttestationKeyInfo> {
-    let (key_id_guard, blob, cert, blob_metadata) =
-        load_attest_key_blob_and_cert(key, caller_uid, db)
-            .context(ks_err!("Failed to load blob and cert"))?;
+    let (key_id_guard, blob, blob_metadata) = load_attest_key_blob(key, caller_uid, km_uuid, db)
+        .context(ks_err!("Failed to load blob"))?;
 
-    let issuer_subject: Vec<u8> = parse_subject_from_certificate(&cert)
-        .context(ks_err!("Failed to parse subject from certificate"))?;
+    // Callers tend to reuse their attestation keys, so the subject is cached. The database
+    // stores the subject whenever the certificate is replaced. Keys whose certificate was stored
+    // before that have their certificate parsed instead.
+    let issuer_subject = ATTESTATION_CERT_CACHE
+        .get_or_load(key_id_guard.id(), || {
+            let subject = match db
+                .load_certificate_subject(&key_id_guard)
+                .context(ks_err!("Failed to load certificate subject"))?
+            {
+                Some(subject) => subject,
+                None => {
+                    let cert = db
+                        .load_certificates(&key_id_guard)
+                        .context(ks_err!("Failed to load cert"))?
+                        .0
+                        .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                        .context(ks_err!("Successfully loaded key entry, but cert was missing"))?;
+                    parse_subject_from_certificate(&cert)
+                        .context(ks_err!("Failed to parse subject from certificate"))?
+                }
+            };
+            check_issuer_subject(subject)
+        })
+        .context(ks_err!("Failed to get the issuer subject"))?;
 
     Ok(AttestationKeyInfo::UserGenerated { key_id_guard, blob, issuer_subject, blob_metadata })
 }
 
-fn load_attest_key_blob_and_cert(
+fn load_attest_key_blob(
     key: &KeyDescriptor,
     caller_uid: u32,
+    km_uuid: &Uuid,
     db: &mut KeystoreDB,
-) -> Result<(KeyIdGuard, Vec<u8>, Vec<u8>, BlobMetaData)> {
+) -> Result<(KeyIdGuard, Vec<u8>, BlobMetaData)> {
     match key.domain {
         Domain::BLOB => Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
             .context(ks_err!("Domain::BLOB attestation keys not supported")),
         _ => {
             let (key_id_guard, mut key_entry) = db
-                .load_key_entry(
-                    key,
-                    KeyType::Client,
-                    KeyEntryLoadBits::BOTH,
-                    caller_uid,
-                    |k, av| check_key_permission(KeyPerm::Use, k, &av),
-                )
+                .load_key_entry(key, KeyType::Client, KeyEntryLoadBits::KM, caller_uid, |k, av| {
+                    check_key_permission(KeyPerm::Use, k, &av)
+                })
                 .context(ks_err!("Failed to load key."))?;
 
+            // The attestation key must live on the KeyMint instance that generates the new key.
+            if key_entry.km_uuid() != km_uuid {
+                return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Attestation key belongs to a different KeyMint instance."));
+            }
+
+            if key_entry.is_frozen() {
+                return Err(Error::perm()).context(ks_err!("Attestation key is frozen."));
+            }
+
             let (blob, blob_metadata) = key_entry
                 .take_key_blob_info()
                 .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
                 .context(ks_err!("Successfully loaded key entry, but KM blob was missing"))?;
-            let cert = key_entry
-                .take_cert()
-                .ok_or(Error::Rc(ResponseCode::INVALID_ARGUMENT))
-                .context(ks_err!("Successfully loaded key entry, but cert was missing"))?;
-            Ok((key_id_guard, blob, cert, blob_metadata))
+            Ok((key_id_guard, blob, blob_metadata))
+        }
+    }
+}
+
+/// Checks that `new_cert`, which is about to replace the certificate `stored_cert` of the key
+/// with the parameters `key_params`, certifies the public key of the key blob if the key is an
+/// attestation key. Otherwise, keys attested with it would name an issuer whose key did not
+/// sign them, and their certificate chains would fail to verify. KeyMint does not reveal the
+/// public key of a blob, so it is taken from `stored_cert`: KeyMint issued the first
+/// certificate of the key along with the blob, and every replacement passes this check. Fails
+/// with `ResponseCode::INVALID_ARGUMENT` if the public keys differ or if `new_cert` cannot be
+/// parsed.
+pub fn check_attestation_key_cert(
+    key_params: &[KsKeyParam],
+    stored_cert: Option<&[u8]>,
+    new_cert: &[u8],
+) -> Result<()> {
+    let is_attestation_key = key_params.iter().any(|kp| {
+        matches!(kp.key_parameter_value(), KsKeyParamValue::KeyPurpose(KeyPurpose::ATTEST_KEY))
+    });
+    let stored_cert = match stored_cert {
+        Some(stored_cert) if is_attestation_key => stored_cert,
+        // Without a stored certificate there is nothing to compare with.
+        _ => return Ok(()),
+    };
+    match certificate_public_keys_match(new_cert, stored_cert) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+            "The public key of the new certificate does not match the attestation key."
+        )),
+        Err(e) => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Failed to compare the public keys of the certificates: {:?}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::new_test_db;
+    use crate::database::KEYSTORE_UUID;
+    use crate::legacy_blob::test_utils::legacy_blob_test_vectors::{
+        LOADED_CACERT_AUTHBOUND, LOADED_CERT_AUTHBOUND,
+    };
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::Algorithm::Algorithm;
+    use AttestationKeySource::*;
+
+    const SYSTEM_UID: u32 = 1000;
+    const APP_UID: u32 = 10 * AID_USER_OFFSET + 10123;
+
+    /// A self-signed P-256 certificate whose subject and issuer are empty.
+    const EMPTY_SUBJECT_CERT: &[u8] = &[
+        0x30, 0x81, 0xEF, 0x30, 0x81, 0x95, 0xA0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01, 0x30,
+        0x0A, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02, 0x30, 0x00, 0x30, 0x20,
+        0x17, 0x0D, 0x32, 0x33, 0x30, 0x31, 0x30, 0x31, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x5A,
+        0x18, 0x0F, 0x32, 0x30, 0x35, 0x33, 0x30, 0x31, 0x30, 0x31, 0x30, 0x30, 0x30, 0x30, 0x30,
+        0x30, 0x5A, 0x30, 0x00, 0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2A, 0x86, 0x48, 0xCE, 0x3D,
+        0x02, 0x01, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+        0x04, 0xDC, 0x02, 0xF3, 0x8B, 0x4E, 0xBE, 0xDA, 0x84, 0xA3, 0xDE, 0xD0, 0xA3, 0x4C, 0xFE,
+        0x2D, 0xAA, 0x50, 0x5C, 0x39, 0x90, 0x4F, 0x30, 0xD1, 0x44, 0x1D, 0x9F, 0xBD, 0xD9, 0x94,
+        0xD3, 0xF8, 0x84, 0x72, 0x74, 0xB8, 0xBD, 0x13, 0x68, 0xC7, 0x72, 0x28, 0x9F, 0x9C, 0x87,
+        0xE2, 0xA4, 0xE1, 0xBC, 0x82, 0x57, 0x7C, 0x21, 0x86, 0x96, 0x4C, 0x79, 0x77, 0xD2, 0x88,
+        0xEC, 0x21, 0xB5, 0x22, 0x0D, 0x30, 0x0A, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04,
+        0x03, 0x02, 0x03, 0x49, 0x00, 0x30, 0x46, 0x02, 0x21, 0x00, 0x8A, 0x13, 0x48, 0x5F, 0xF7,
+        0x9B, 0x7F, 0xFD, 0x95, 0xC4, 0x2A, 0x0D, 0x7F, 0x36, 0x15, 0xE3, 0x26, 0x87, 0xCF, 0x2E,
+        0x0D, 0xA0, 0xAA, 0xE1, 0x1C, 0xAD, 0x28, 0x9F, 0x9D, 0xFE, 0xBA, 0x0A, 0x02, 0x21, 0x00,
+        0xE6, 0x45, 0x55, 0x47, 0x8F, 0x28, 0x0B, 0x4D, 0xFA, 0x51, 0x13, 0x5D, 0x17, 0x58, 0x50,
+        0xB2, 0x46, 0x40, 0x39, 0xBB, 0x93, 0xEF, 0xA6, 0xE1, 0xF1, 0xAF, 0xE0, 0x5A, 0x3A, 0xBC,
+        0x6F, 0x43,
+    ];
+
+    /// Selects a source for the given category, pretending that only `available` sources have
+    /// keys. Returns the selected source and the sources that were consulted.
+    fn select(
+        policy: &AttestationKeyPolicy,
+        category: CallerCategory,
+        available: &[AttestationKeySource],
+    ) -> (Result<Option<AttestationKeySource>>, Vec<AttestationKeySource>) {
+        let mut tried = Vec::new();
+        let result = select_attestation_key(policy.sources_for(category), |source| {
+            tried.push(source);
+            Ok(available.contains(&source).then_some(source))
+        });
+        (result, tried)
+    }
+
+    #[test]
+    fn test_caller_category() {
+        assert_eq!(
+            CallerCategory::of_caller(SYSTEM_UID, || panic!("must not be called")),
+            CallerCategory::System
+        );
+        assert_eq!(
+            CallerCategory::of_caller(AID_USER_OFFSET + SYSTEM_UID, || panic!()),
+            CallerCategory::System
+        );
+        assert_eq!(CallerCategory::of_caller(APP_UID, || false), CallerCategory::App);
+        assert_eq!(CallerCategory::of_caller(APP_UID, || true), CallerCategory::PrivilegedApp);
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = AttestationKeyPolicy::default();
+        let all = [RemoteProvisioned, Rkpd];
+
+        let (result, tried) = select(&policy, CallerCategory::System, &all);
+        assert_eq!(result.unwrap(), Some(RemoteProvisioned));
+        assert_eq!(tried, vec![RemoteProvisioned]);
+
+        for category in [CallerCategory::PrivilegedApp, CallerCategory::App] {
+            let (result, tried) = select(&policy, category, &all);
+            assert_eq!(result.unwrap(), Some(Rkpd));
+            assert_eq!(tried, vec![Rkpd]);
+        }
+    }
+
+    #[test]
+    fn test_default_policy_fallback() {
+        let policy = AttestationKeyPolicy::default();
+
+        let (result, tried) = select(&policy, CallerCategory::PrivilegedApp, &[RemoteProvisioned]);
+        assert_eq!(result.unwrap(), Some(RemoteProvisioned));
+        assert_eq!(tried, vec![Rkpd, RemoteProvisioned]);
+
+        // Regular apps never use keystore's own pool and fall back to the factory key.
+        let (result, tried) = select(&policy, CallerCategory::App, &[RemoteProvisioned]);
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(tried, vec![Rkpd]);
+
+        // System components fall back to RKPD before the factory key.
+        let (result, tried) = select(&policy, CallerCategory::System, &[Rkpd]);
+        assert_eq!(result.unwrap(), Some(Rkpd));
+        assert_eq!(tried, vec![RemoteProvisioned, Rkpd]);
+        let (result, tried) = select(&policy, CallerCategory::System, &[]);
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(tried, vec![RemoteProvisioned, Rkpd]);
+    }
+
+    #[test]
+    fn test_custom_policy() {
+        let policy = AttestationKeyPolicy::new([
+            (CallerCategory::System, vec![Rkpd]),
+            (CallerCategory::App, vec![Factory, Rkpd]),
+        ]);
+
+        let (result, _) = select(&policy, CallerCategory::System, &[Rkpd]);
+        assert_eq!(result.unwrap(), Some(Rkpd));
+
+        // Without a factory fallback, running out of sources is an error.
+        let (result, tried) = select(&policy, CallerCategory::System, &[RemoteProvisioned]);
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED))
+        );
+        assert_eq!(tried, vec![Rkpd]);
+
+        // Sources after Factory are not consulted.
+        let (result, tried) = select(&policy, CallerCategory::App, &[Rkpd]);
+        assert_eq!(result.unwrap(), None);
+        assert!(tried.is_empty());
+
+        // Unlisted categories have no sources.
+        let (result, tried) = select(&policy, CallerCategory::PrivilegedApp, &[Rkpd]);
+        assert!(result.is_err());
+        assert!(tried.is_empty());
+    }
+
+    #[test]
+    fn test_policy_per_security_level() {
+        assert_eq!(
+            policy_property(SecurityLevel::TRUSTED_ENVIRONMENT),
+            Some("keystore.tee.attestation_key_policy")
+        );
+        assert_eq!(
+            policy_property(SecurityLevel::STRONGBOX),
+            Some("keystore.strongbox.attestation_key_policy")
+        );
+        assert_eq!(policy_property(SecurityLevel::SOFTWARE), None);
+
+        // Without properties, both security levels keep the default policy.
+        let default = AttestationKeyPolicy::default();
+        for security_level in [SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX] {
+            let policy = AttestationKeyPolicy::for_security_level(security_level);
+            for category in
+                [CallerCategory::System, CallerCategory::PrivilegedApp, CallerCategory::App]
+            {
+                assert_eq!(policy.sources_for(category), default.sources_for(category));
+            }
+        }
+    }
+
+    #[test]
+    fn test_policy_overrides() {
+        // StrongBox never uses RKPD, while the TEE keeps the default policy.
+        let strongbox = AttestationKeyPolicy::default()
+            .with_overrides("privileged_app=pool,factory; app = pool , factory ;")
+            .unwrap();
+        let tee = AttestationKeyPolicy::default();
+        let all = [RemoteProvisioned, Rkpd];
+        for category in [CallerCategory::PrivilegedApp, CallerCategory::App] {
+            let (result, tried) = select(&strongbox, category, &all);
+            assert_eq!(result.unwrap(), Some(RemoteProvisioned));
+            assert_eq!(tried, vec![RemoteProvisioned]);
+            let (result, _) = select(&tee, category, &all);
+            assert_eq!(result.unwrap(), Some(Rkpd));
+        }
+        // Categories that are not listed keep their default sources.
+        assert_eq!(
+            strongbox.sources_for(CallerCategory::System),
+            tee.sources_for(CallerCategory::System)
+        );
+
+        // Categories may draw from a named pool, until they are overridden again.
+        let policy = AttestationKeyPolicy::default()
+            .with_overrides("system=pool:vpn,factory; app=pool,factory")
+            .unwrap();
+        assert_eq!(policy.sources_for(CallerCategory::System), &[RemoteProvisioned, Factory]);
+        assert_eq!(policy.pool_for(CallerCategory::System), Some("vpn"));
+        assert_eq!(policy.pool_for(CallerCategory::App), None);
+        let policy = policy.with_overrides("system=pool,factory").unwrap();
+        assert_eq!(policy.pool_for(CallerCategory::System), None);
+
+        assert!(AttestationKeyPolicy::default().with_overrides("").is_some());
+        for malformed in
+            ["app", "app=", "app=pool,", "app=pool:", "guest=pool", "app=pool;system=better"]
+        {
+            assert!(
+                AttestationKeyPolicy::default().with_overrides(malformed).is_none(),
+                "{:?}",
+                malformed
+            );
+        }
+    }
+
+    #[test]
+    fn test_source_errors_are_propagated() {
+        let policy = AttestationKeyPolicy::default();
+        let result: Result<Option<()>> =
+            select_attestation_key(policy.sources_for(CallerCategory::App), |_| {
+                Err(Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR)).context("test")
+            });
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+        );
+    }
+
+    #[test]
+    fn test_rkpd_uid_allowlist_parse() {
+        assert_eq!(RkpdUidAllowlist::parse("*"), Some(RkpdUidAllowlist::All));
+        assert_eq!(
+            RkpdUidAllowlist::parse("1000, 10123,"),
+            Some(RkpdUidAllowlist::Uids([1000, 10123].into_iter().collect()))
+        );
+        assert_eq!(RkpdUidAllowlist::parse(""), Some(RkpdUidAllowlist::All));
+        assert_eq!(RkpdUidAllowlist::parse(" \t"), Some(RkpdUidAllowlist::All));
+        assert_eq!(RkpdUidAllowlist::parse(" * "), Some(RkpdUidAllowlist::All));
+        // A list without any UIDs permits nobody.
+        assert_eq!(RkpdUidAllowlist::parse(","), Some(RkpdUidAllowlist::Uids(HashSet::new())));
+        assert_eq!(RkpdUidAllowlist::parse("1000,app"), None);
+    }
+
+    #[test]
+    fn test_rkpd_uid_allowlist() {
+        let allowlist = RkpdUidAllowlist::parse(&APP_UID.to_string()).unwrap();
+        assert!(allowlist.permits(APP_UID));
+        assert!(!allowlist.permits(APP_UID + 1));
+        assert!(RkpdUidAllowlist::All.permits(APP_UID + 1));
+
+        let policy = AttestationKeyPolicy::default();
+        let all = [RemoteProvisioned, Rkpd];
+        let sources = policy.sources_for(CallerCategory::App);
+
+        // A UID on the list takes the RKPD path.
+        let allowed = restrict_rkpd(sources, allowlist.permits(APP_UID));
+        assert_eq!(allowed, vec![Rkpd, Factory]);
+        assert_eq!(
+            select_attestation_key(&allowed, |s| Ok(all.contains(&s).then_some(s))).unwrap(),
+            Some(Rkpd)
+        );
+
+        // A UID off the list falls through to keystore's own key pool.
+        let denied = restrict_rkpd(sources, allowlist.permits(APP_UID + 1));
+        assert_eq!(denied, vec![RemoteProvisioned, Factory]);
+        assert_eq!(
+            select_attestation_key(&denied, |s| Ok(all.contains(&s).then_some(s))).unwrap(),
+            Some(RemoteProvisioned)
+        );
+
+        // Sources are not duplicated if the policy already lists the key pool.
+        assert_eq!(
+            restrict_rkpd(policy.sources_for(CallerCategory::PrivilegedApp), false),
+            vec![RemoteProvisioned, Factory]
+        );
+    }
+
+    #[test]
+    fn test_summary_does_not_expose_key_material() -> Result<()> {
+        const BLOB: &[u8] = b"secret attestation key blob";
+        let mut db = new_test_db()?;
+        let attestation_key = || AttestationKey {
+            keyBlob: BLOB.to_vec(),
+            attestKeyParams: vec![],
+            issuerSubjectName: b"issuer".to_vec(),
+        };
+        let attestation_certs =
+            || Certificate { encodedCertificate: LOADED_CACERT_AUTHBOUND.to_vec() };
+
+        let remote_provisioned = AttestationKeyInfo::RemoteProvisioned {
+            key_id_guard: db.create_key_entry(
+                &Domain::APP,
+                &100,
+                KeyType::Attestation,
+                &KEYSTORE_UUID,
+            )?,
+            attestation_key: attestation_key(),
+            attestation_certs: attestation_certs(),
+        };
+        let key_id = match &remote_provisioned {
+            AttestationKeyInfo::RemoteProvisioned { key_id_guard, .. } => key_id_guard.id(),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            remote_provisioned.summary(),
+            format!(
+                "RemoteProvisioned {{ key_id: {}, issuer_subject_len: 6, cert_chain_len: 3 }}",
+                key_id
+            )
+        );
+
+        let rkpd_provisioned = AttestationKeyInfo::RkpdProvisioned {
+            attestation_key: attestation_key(),
+            attestation_certs: attestation_certs(),
+        };
+        assert_eq!(
+            rkpd_provisioned.summary(),
+            "RkpdProvisioned { issuer_subject_len: 6, cert_chain_len: 3 }"
+        );
+
+        let user_generated = AttestationKeyInfo::UserGenerated {
+            key_id_guard: db.create_key_entry(
+                &Domain::APP,
+                &100,
+                KeyType::Client,
+                &KEYSTORE_UUID,
+            )?,
+            blob: BLOB.to_vec(),
+            blob_metadata: BlobMetaData::new(),
+            issuer_subject: b"issuer".to_vec(),
+        };
+        assert!(user_generated.summary().starts_with("UserGenerated { key_id: "));
+
+        for info in [remote_provisioned, rkpd_provisioned, user_generated] {
+            let summary = info.summary();
+            assert!(!summary.contains("secret"));
+            assert!(!summary.contains("issuer"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_attestation_params_summary_does_not_expose_challenge() {
+        const CHALLENGE: &[u8] = b"secret attestation challenge";
+        let params = vec![
+            KeyParameter {
+                tag: Tag::ALGORITHM,
+                value: KeyParameterValue::Algorithm(Algorithm::EC),
+            },
+            KeyParameter {
+                tag: Tag::ATTESTATION_CHALLENGE,
+                value: KeyParameterValue::Blob(CHALLENGE.to_vec()),
+            },
+            KeyParameter {
+                tag: Tag::PURPOSE,
+                value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+            },
+            KeyParameter {
+                tag: Tag::PURPOSE,
+                value: KeyParameterValue::KeyPurpose(KeyPurpose::VERIFY),
+            },
+        ];
+        let summary = summarize_attestation_params(&params);
+        assert_eq!(
+            summary,
+            concat!(
+                "AttestationParams { tags: [ALGORITHM, ATTESTATION_CHALLENGE, PURPOSE], ",
+                "challenge_len: 28, device_unique: false }"
+            )
+        );
+        assert!(!summary.contains("secret"));
+        assert!(!summary.contains(&format!("{:?}", CHALLENGE)));
+
+        let mut device_unique = params.clone();
+        device_unique.push(KeyParameter {
+            tag: Tag::DEVICE_UNIQUE_ATTESTATION,
+            value: KeyParameterValue::BoolValue(true),
+        });
+        assert!(summarize_attestation_params(&device_unique).ends_with("device_unique: true }"));
+        assert_eq!(
+            summarize_attestation_params(&[]),
+            "AttestationParams { tags: [], challenge_len: none, device_unique: false }"
+        );
+    }
+
+    #[test]
+    fn test_provisioning_disagreement() {
+        use ProvisioningDisagreement::*;
+        let policy = AttestationKeyPolicy::default();
+        let system = policy.sources_for(CallerCategory::System);
+        let app = policy.sources_for(CallerCategory::App);
+
+        // RKPD is preferred but empty, while the pool has keys.
+        assert_eq!(find_provisioning_disagreement(app, true, false), Some(RkpdEmptyPoolAvailable));
+        assert_eq!(find_provisioning_disagreement(system, true, false), None);
+
+        // The pool is preferred but empty, while RKPD has keys.
+        assert_eq!(
+            find_provisioning_disagreement(system, false, true),
+            Some(PoolEmptyRkpdAvailable)
+        );
+        assert_eq!(find_provisioning_disagreement(app, false, true), None);
+
+        // Both paths agree.
+        for sources in [system, app] {
+            assert_eq!(find_provisioning_disagreement(sources, true, true), None);
+            assert_eq!(find_provisioning_disagreement(sources, false, false), None);
+        }
+
+        // Categories that prefer the factory key never disagree.
+        assert_eq!(find_provisioning_disagreement(&[Factory, Rkpd], true, false), None);
+        assert_eq!(find_provisioning_disagreement(&[], false, true), None);
+    }
+
+    #[test]
+    fn test_estimate_attestation_capacity() -> Result<()> {
+        // System components and privileged apps draw from different pools of keystore's own
+        // keys, apps use RKPD only.
+        let policy =
+            AttestationKeyPolicy::default().with_pool(CallerCategory::PrivilegedApp, "privileged");
+        let mut counted = Vec::new();
+        let capacity = estimate_attestation_capacity_with(&policy, true, |pool| {
+            counted.push(pool.map(str::to_string));
+            Ok(Some(if pool.is_none() { 27 } else { 5 }))
+        })?;
+        assert_eq!(counted, vec![None, Some("privileged".to_string())]);
+        assert_eq!(
+            capacity,
+            AttestationCapacity {
+                remote_provisioned_uses: Some(32),
+                rkpd_available: true,
+                user_generated_unlimited: false,
+            }
+        );
+        assert_eq!(capacity.estimate(), Some(32));
+
+        // Pools that several categories share are counted once.
+        let capacity =
+            estimate_attestation_capacity_with(&AttestationKeyPolicy::default(), false, |_| {
+                Ok(Some(27))
+            })?;
+        assert_eq!(capacity.estimate(), Some(27));
+
+        // A user generated fallback key makes the attestations effectively unlimited.
+        let fallback_key = KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 100,
+            alias: Some("fallback".to_string()),
+            blob: None,
+        };
+        let policy = policy.with_fallback_key(fallback_key);
+        let capacity = estimate_attestation_capacity_with(&policy, false, |_| Ok(Some(5)))?;
+        assert_eq!(capacity.remote_provisioned_uses, Some(10));
+        assert!(capacity.user_generated_unlimited);
+        assert_eq!(capacity.estimate(), None);
+
+        // So do remote provisioned keys without a use limit.
+        let capacity =
+            estimate_attestation_capacity_with(&AttestationKeyPolicy::default(), false, |_| {
+                Ok(None)
+            })?;
+        assert_eq!(capacity.estimate(), None);
+
+        // RKPD keys are not counted.
+        let policy = AttestationKeyPolicy::new([(CallerCategory::App, vec![Rkpd, Factory])]);
+        let capacity = estimate_attestation_capacity_with(&policy, true, |_| {
+            panic!("No pool must be counted.")
+        })?;
+        assert_eq!(capacity.estimate(), Some(0));
+        assert!(capacity.rkpd_available);
+        Ok(())
+    }
+
+    fn request(challenge: bool, device_unique: bool) -> Vec<KeyParameter> {
+        let mut params = vec![];
+        if challenge {
+            params.push(KeyParameter {
+                tag: Tag::ATTESTATION_CHALLENGE,
+                value: KeyParameterValue::Blob(b"challenge".to_vec()),
+            });
+        }
+        if device_unique {
+            params.push(KeyParameter {
+                tag: Tag::DEVICE_UNIQUE_ATTESTATION,
+                value: KeyParameterValue::BoolValue(true),
+            });
+        }
+        params
+    }
+
+    /// Traces the selection for a request, pretending that only `available` sources have keys,
+    /// and checks that the trace matches the sources that the actual selection consults.
+    fn explain(
+        caller_uid: u32,
+        attest_key_specified: bool,
+        params: &[KeyParameter],
+        policy: &AttestationKeyPolicy,
+        allowlist: &RkpdUidAllowlist,
+        available: &[AttestationKeySource],
+    ) -> AttestKeySelectionTrace {
+        let plan = || {
+            plan_selection(
+                caller_uid,
+                attest_key_specified,
+                params,
+                policy,
+                || false,
+                || allowlist.clone(),
+            )
+        };
+        let trace =
+            trace_selection(params, attest_key_specified, plan(), |s| Ok(available.contains(&s)));
+        if let SelectionPlan::Sources { sources, fallback, .. } = plan() {
+            let mut tried = Vec::new();
+            let actual = select_attestation_key(&sources, |source| {
+                tried.push(source);
+                Ok(available.contains(&source).then_some(source))
+            });
+            let expected = match actual {
+                Ok(Some(source)) => AttestKeySelection::Source(source),
+                _ if fallback => AttestKeySelection::Fallback,
+                Ok(None) => AttestKeySelection::Source(Factory),
+                Err(_) => AttestKeySelection::Unavailable,
+            };
+            assert_eq!(trace.selection, expected);
+            assert_eq!(trace.consulted.iter().map(|(s, _)| *s).collect::<Vec<_>>(), tried);
+        }
+        trace
+    }
+
+    #[test]
+    fn test_explain_without_remote_provisioned_key() {
+        let policy = AttestationKeyPolicy::default();
+        let all = RkpdUidAllowlist::All;
+
+        let trace = explain(APP_UID, false, &request(false, false), &policy, &all, &[Rkpd]);
+        assert_eq!(trace.selection, AttestKeySelection::NotRequested);
+        assert_eq!((trace.challenge_present, trace.category), (false, None));
+        assert!(trace.consulted.is_empty());
+
+        // A user generated attestation key takes precedence, even without a challenge.
+        let trace = explain(APP_UID, true, &request(false, false), &policy, &all, &[Rkpd]);
+        assert_eq!(trace.selection, AttestKeySelection::UserGenerated);
+        assert!(trace.attest_key_specified);
+        assert!(trace.consulted.is_empty());
+
+        let trace = explain(APP_UID, false, &request(true, true), &policy, &all, &[Rkpd]);
+        assert_eq!(trace.selection, AttestKeySelection::DeviceUnique);
+        assert!(trace.challenge_present && trace.device_unique_attestation);
+        assert!(trace.consulted.is_empty());
+    }
+
+    #[test]
+    fn test_device_unique_attestation_sources() {
+        let all = RkpdUidAllowlist::All;
+        let params = request(true, true);
+        let fallback = KeyDescriptor {
+            domain: Domain::APP,
+            alias: Some("fallback".to_string()),
+            ..Default::default()
+        };
+
+        // By default, device unique requests use the factory key even if other keys exist.
+        let policy = AttestationKeyPolicy::default().with_fallback_key(fallback.clone());
+        assert_eq!(policy.device_unique_sources(), &[Factory]);
+        let trace = explain(APP_UID, false, &params, &policy, &all, &[RemoteProvisioned, Rkpd]);
+        assert_eq!(trace.selection, AttestKeySelection::DeviceUnique);
+        assert!(trace.consulted.is_empty());
+
+        // The policy can point them at a pool of remote provisioned device unique keys.
+        let policy = AttestationKeyPolicy::default()
+            .with_fallback_key(fallback)
+            .with_overrides("device_unique=pool:device_unique")
+            .unwrap();
+        assert_eq!(policy.device_unique_sources(), &[RemoteProvisioned]);
+        assert_eq!(policy.device_unique_pool(), Some("device_unique"));
+        // The sources of the caller categories are unaffected.
+        assert_eq!(policy.sources_for(CallerCategory::App), &[Rkpd, Factory]);
+        assert_eq!(policy.pool_for(CallerCategory::App), None);
+
+        let trace = explain(APP_UID, false, &params, &policy, &all, &[RemoteProvisioned, Rkpd]);
+        assert_eq!(trace.selection, AttestKeySelection::Source(RemoteProvisioned));
+        assert_eq!(trace.consulted, vec![(RemoteProvisioned, true)]);
+        assert_eq!(trace.category, None);
+
+        // An empty device unique pool fails the request rather than using the fallback key.
+        let trace = explain(APP_UID, false, &params, &policy, &all, &[Rkpd]);
+        assert_eq!(trace.selection, AttestKeySelection::Unavailable);
+
+        // Requests that are not device unique keep their sources.
+        let trace = explain(APP_UID, false, &request(true, false), &policy, &all, &[Rkpd]);
+        assert_eq!(trace.selection, AttestKeySelection::Source(Rkpd));
+
+        // The factory key remains available as the last resort of an alternate policy.
+        let policy = AttestationKeyPolicy::default()
+            .with_device_unique_sources(vec![RemoteProvisioned, Factory], None);
+        let trace = explain(APP_UID, false, &params, &policy, &all, &[]);
+        assert_eq!(trace.selection, AttestKeySelection::DeviceUnique);
+        assert_eq!(trace.consulted, vec![(RemoteProvisioned, false)]);
+    }
+
+    #[test]
+    fn test_no_attest_key_reason() -> Result<()> {
+        let mut db = new_test_db()?;
+        let rem_prov_state =
+            RemProvState::new(SecurityLevel::TRUSTED_ENVIRONMENT, "default", KEYSTORE_UUID);
+        let key = KeyDescriptor::default();
+        let reason = |params: &[KeyParameter],
+                      policy: &AttestationKeyPolicy,
+                      db: &mut KeystoreDB|
+         -> Result<Option<NoAttestKeyReason>> {
+            let outcome =
+                get_attest_key_info(&key, SYSTEM_UID, None, params, &rem_prov_state, policy, db)?;
+            Ok(match outcome {
+                AttestKeyInfoOutcome::Selected(_) => None,
+                AttestKeyInfoOutcome::NotSelected(reason) => Some(reason),
+            })
+        };
+        let default_policy = AttestationKeyPolicy::default();
+        let factory_policy = AttestationKeyPolicy::new([(CallerCategory::System, vec![Factory])]);
+
+        assert_eq!(
+            reason(&request(false, false), &default_policy, &mut db)?,
+            Some(NoAttestKeyReason::NoChallenge)
+        );
+        // Device unique attestation without a challenge is not requested either.
+        assert_eq!(
+            reason(&request(false, true), &default_policy, &mut db)?,
+            Some(NoAttestKeyReason::NoChallenge)
+        );
+        assert_eq!(
+            reason(&request(true, true), &default_policy, &mut db)?,
+            Some(NoAttestKeyReason::DeviceUnique)
+        );
+        assert_eq!(
+            reason(&request(true, false), &factory_policy, &mut db)?,
+            Some(NoAttestKeyReason::FactoryKey)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_source_selection() {
+        let policy = AttestationKeyPolicy::default();
+        let all = RkpdUidAllowlist::All;
+        let params = request(true, false);
+
+        let trace = explain(APP_UID, false, &params, &policy, &all, &[RemoteProvisioned, Rkpd]);
+        assert_eq!(trace.category, Some(CallerCategory::App));
+        assert!(trace.use_rkpd);
+        assert_eq!(trace.sources, vec![Rkpd, Factory]);
+        assert_eq!(trace.consulted, vec![(Rkpd, true)]);
+        assert_eq!(trace.selection, AttestKeySelection::Source(Rkpd));
+
+        // RKPD has no key, so the app falls back to the factory key.
+        let trace = explain(APP_UID, false, &params, &policy, &all, &[RemoteProvisioned]);
+        assert_eq!(trace.consulted, vec![(Rkpd, false)]);
+        assert_eq!(trace.selection, AttestKeySelection::Source(Factory));
+
+        // Apps off the RKPD UID allowlist use keystore's own key pool.
+        let none = RkpdUidAllowlist::Uids(HashSet::new());
+        let trace = explain(APP_UID, false, &params, &policy, &none, &[RemoteProvisioned, Rkpd]);
+        assert!(!trace.use_rkpd);
+        assert_eq!(trace.sources, vec![RemoteProvisioned, Factory]);
+        assert_eq!(trace.selection, AttestKeySelection::Source(RemoteProvisioned));
+
+        let trace = explain(SYSTEM_UID, false, &params, &policy, &all, &[Rkpd]);
+        assert_eq!(trace.category, Some(CallerCategory::System));
+        assert_eq!(trace.consulted, vec![(RemoteProvisioned, false)]);
+        assert_eq!(trace.selection, AttestKeySelection::Source(Factory));
+    }
+
+    #[test]
+    fn test_explain_unavailable() {
+        let policy = AttestationKeyPolicy::new([(CallerCategory::App, vec![Rkpd])]);
+        let params = request(true, false);
+        let trace = explain(APP_UID, false, &params, &policy, &RkpdUidAllowlist::All, &[]);
+        assert_eq!(trace.consulted, vec![(Rkpd, false)]);
+        assert_eq!(trace.selection, AttestKeySelection::Unavailable);
+
+        // A failing source, e.g., RKPD on a device that is RKP only, makes the selection fail.
+        let plan =
+            plan_selection(APP_UID, false, &params, &policy, || false, || RkpdUidAllowlist::All);
+        let trace = trace_selection(&params, false, plan, |_| {
+            Err(Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED)).context("test")
+        });
+        assert_eq!(trace.consulted, vec![(Rkpd, false)]);
+        assert_eq!(trace.selection, AttestKeySelection::Unavailable);
+    }
+
+    fn break_glass_key() -> KeyDescriptor {
+        KeyDescriptor {
+            domain: Domain::SELINUX,
+            nspace: 102,
+            alias: Some("break_glass".to_string()),
+            blob: None,
+        }
+    }
+
+    #[test]
+    fn test_fallback_key_property() {
+        assert_eq!(
+            fallback_key_property(SecurityLevel::TRUSTED_ENVIRONMENT),
+            Some("keystore.tee.fallback_attestation_key")
+        );
+        assert_eq!(
+            fallback_key_property(SecurityLevel::STRONGBOX),
+            Some("keystore.strongbox.fallback_attestation_key")
+        );
+        assert_eq!(fallback_key_property(SecurityLevel::SOFTWARE), None);
+
+        assert_eq!(parse_key("selinux:102:break_glass"), Some(break_glass_key()));
+        assert_eq!(
+            parse_key(" app:my_key "),
+            Some(KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some("my_key".to_string()),
+                blob: None,
+            })
+        );
+        for malformed in ["", "app", "app:", "selinux:102", "selinux:x:key", "blob:key"] {
+            assert_eq!(parse_key(malformed), None, "{:?}", malformed);
+        }
+
+        // Without properties, no fallback key is configured.
+        for security_level in [SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX] {
+            assert!(AttestationKeyPolicy::for_security_level(security_level)
+                .fallback_key()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_fallback_key_when_rkp_unavailable() {
+        let policy = AttestationKeyPolicy::new([
+            (CallerCategory::System, vec![RemoteProvisioned]),
+            (CallerCategory::App, vec![Rkpd, Factory]),
+        ])
+        .with_fallback_key(break_glass_key());
+        let configured = policy.fallback_key();
+        let select_with_fallback = |category, available: &[AttestationKeySource]| {
+            let (selected, _) = select(&policy, category, available);
+            let mut loaded = None;
+            let result = with_fallback(selected, configured, |key| {
+                loaded = Some(key.clone());
+                Ok(Factory)
+            });
+            (result.unwrap(), loaded)
+        };
+
+        // Remote provisioned keys take precedence.
+        assert_eq!(
+            select_with_fallback(CallerCategory::System, &[RemoteProvisioned]).0,
+            Some(RemoteProvisioned)
+        );
+        assert_eq!(select_with_fallback(CallerCategory::App, &[Rkpd]).0, Some(Rkpd));
+
+        // Without remote provisioned keys, the fallback key is used instead of failing or of
+        // using the factory key.
+        for category in [CallerCategory::System, CallerCategory::App] {
+            let (result, loaded) = select_with_fallback(category, &[]);
+            assert_eq!(result, Some(Factory));
+            assert_eq!(loaded, Some(break_glass_key()));
         }
+
+        // RKPD failing has the same effect.
+        let unavailable: Result<Option<AttestationKeySource>> =
+            Err(Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR)).context("test");
+        assert!(with_fallback(unavailable, configured, |_| Ok(Factory)).is_ok());
+
+        // The fallback key is subject to the same checks as any attestation key, so a caller
+        // that may not use it fails.
+        let result: Result<Option<AttestationKeySource>> =
+            with_fallback(Ok(None), configured, |_| Err(Error::perm()).context("test"));
+        assert_eq!(result.unwrap_err().root_cause().downcast_ref::<Error>(), Some(&Error::perm()));
+
+        // Without a fallback key, the selection is returned unchanged.
+        let result = with_fallback(Ok(None), None, |_| -> Result<AttestationKeySource> {
+            panic!("must not be called")
+        });
+        assert_eq!(result.unwrap(), None);
+
+        let trace =
+            explain(SYSTEM_UID, false, &request(true, false), &policy, &RkpdUidAllowlist::All, &[]);
+        assert_eq!(trace.consulted, vec![(RemoteProvisioned, false)]);
+        assert_eq!(trace.selection, AttestKeySelection::Fallback);
+    }
+
+    #[test]
+    fn test_empty_issuer_subject_is_rejected() -> Result<()> {
+        let subject = parse_subject_from_certificate(LOADED_CERT_AUTHBOUND)?;
+        assert_eq!(check_issuer_subject(subject.clone())?, subject);
+
+        let empty_subject = parse_subject_from_certificate(EMPTY_SUBJECT_CERT)?;
+        assert_eq!(empty_subject, EMPTY_DER_NAME);
+        for subject in [empty_subject, vec![]] {
+            assert_eq!(
+                check_issuer_subject(subject).unwrap_err().root_cause().downcast_ref::<Error>(),
+                Some(&Error::Km(ErrorCode::INVALID_ISSUER_SUBJECT))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_attestation_key_cert_must_match_blob() {
+        let attest_key_params = [KsKeyParam::new(
+            KsKeyParamValue::KeyPurpose(KeyPurpose::ATTEST_KEY),
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        )];
+        let ca_certs = split_certificate_chain(LOADED_CACERT_AUTHBOUND).unwrap();
+
+        // A certificate for the same public key, e.g., a reissued one, may replace the stored one.
+        assert!(check_attestation_key_cert(
+            &attest_key_params,
+            Some(LOADED_CERT_AUTHBOUND),
+            LOADED_CERT_AUTHBOUND
+        )
+        .is_ok());
+
+        // A certificate for another key may not, and neither may a malformed one.
+        for new_cert in [ca_certs[0], &LOADED_CERT_AUTHBOUND[..100]] {
+            assert_eq!(
+                check_attestation_key_cert(
+                    &attest_key_params,
+                    Some(LOADED_CERT_AUTHBOUND),
+                    new_cert
+                )
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+                Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            );
+        }
+
+        // Other keys, and keys without a stored certificate, are not checked.
+        let sign_key_params = [KsKeyParam::new(
+            KsKeyParamValue::KeyPurpose(KeyPurpose::SIGN),
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        )];
+        assert!(check_attestation_key_cert(
+            &sign_key_params,
+            Some(LOADED_CERT_AUTHBOUND),
+            ca_certs[0]
+        )
+        .is_ok());
+        assert!(check_attestation_key_cert(&attest_key_params, None, ca_certs[0]).is_ok());
     }
 }
@@ -0,0 +1,265 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the attestation self-test that keystore can run at startup.
+//!
+//! If the `keystore.attestation_self_test` system property is set, keystore generates a
+//! throwaway attested key on every backend when it starts, with the same pipeline as
+//! `generateKey`, i.e., including the selection of the attestation key. It then verifies the
+//! attestation of the key and deletes it again. The outcome is logged as a metric, so broken
+//! configurations show up before the first caller needs an attestation. If the backend has no
+//! attestation key available, the self-test is skipped instead of failing.
+
+use crate::attestation_verification::verify_attestation;
+use crate::error::{Error, ErrorCode, ResponseCode};
+use crate::ks_err;
+use crate::sysprop::read_prop_bool;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Certificate::Certificate, Digest::Digest, EcCurve::EcCurve,
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor, KeyMetadata::KeyMetadata,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::generate_random_data;
+
+/// Enables the self-test at startup if set to true. The default is false.
+const ATTESTATION_SELF_TEST_PROPERTY: &str = "keystore.attestation_self_test";
+
+/// The su_key namespace as defined in su.te and keystore_key_contexts of the SePolicy, which is
+/// reserved for tests.
+const SELF_TEST_NAMESPACE: i64 = 0;
+
+/// The alias of the throwaway key.
+const SELF_TEST_ALIAS: &str = "keystore2_attestation_self_test";
+
+/// The length of the random attestation challenge of the throwaway key.
+const SELF_TEST_CHALLENGE_LEN: usize = 16;
+
+/// The outcome of a self-test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestOutcome {
+    /// The key was generated, its attestation verified, and it was deleted again.
+    Passed,
+    /// Generating, verifying, or deleting the key failed.
+    Failed,
+    /// No attestation key is available, so there was nothing to test.
+    Skipped,
+}
+
+/// The parts of the key generation pipeline that the self-test exercises.
+pub trait AttestationPipeline {
+    /// Generates a key stored under `key` with `params` like `generateKey` does, but on behalf
+    /// of keystore itself, i.e., without checking the permissions of a caller.
+    fn generate_attested_key(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+    ) -> Result<KeyMetadata>;
+
+    /// Deletes the key stored under `key`.
+    fn delete_attested_key(&self, key: &KeyDescriptor) -> Result<()>;
+}
+
+/// Returns true if the self-test is enabled by the `keystore.attestation_self_test` system
+/// property.
+pub fn self_test_enabled() -> bool {
+    read_prop_bool(ATTESTATION_SELF_TEST_PROPERTY, false)
+}
+
+/// Runs the self-test on `pipeline` with a random attestation challenge.
+pub fn run_self_test(pipeline: &dyn AttestationPipeline) -> SelfTestOutcome {
+    match generate_random_data(SELF_TEST_CHALLENGE_LEN) {
+        Ok(challenge) => run_self_test_with_challenge(pipeline, &challenge),
+        Err(e) => {
+            log::error!("Attestation self-test failed to generate a challenge: {:?}", e);
+            SelfTestOutcome::Failed
+        }
+    }
+}
+
+fn run_self_test_with_challenge(
+    pipeline: &dyn AttestationPipeline,
+    challenge: &[u8],
+) -> SelfTestOutcome {
+    let key = KeyDescriptor {
+        domain: Domain::SELINUX,
+        nspace: SELF_TEST_NAMESPACE,
+        alias: Some(SELF_TEST_ALIAS.to_string()),
+        blob: None,
+    };
+    let metadata = match pipeline.generate_attested_key(&key, &self_test_params(challenge)) {
+        Ok(metadata) => metadata,
+        Err(e) if is_attestation_unavailable(&e) => {
+            log::info!("Attestation self-test skipped, no attestation key available: {:?}", e);
+            return SelfTestOutcome::Skipped;
+        }
+        Err(e) => {
+            log::error!("Attestation self-test failed to generate the key: {:?}", e);
+            return SelfTestOutcome::Failed;
+        }
+    };
+
+    // The key is deleted whether or not its attestation is valid.
+    let verified = verify_key_attestation(&metadata, challenge);
+    let deleted = pipeline.delete_attested_key(&key).context(ks_err!("Failed to delete the key."));
+    match verified.and(deleted) {
+        Ok(()) => SelfTestOutcome::Passed,
+        Err(e) => {
+            log::error!("Attestation self-test failed: {:?}", e);
+            SelfTestOutcome::Failed
+        }
+    }
+}
+
+/// The parameters of the throwaway key, an attested P-256 signing key.
+fn self_test_params(challenge: &[u8]) -> Vec<KeyParameter> {
+    vec![
+        KeyParameter { tag: Tag::ALGORITHM, value: KeyParameterValue::Algorithm(Algorithm::EC) },
+        KeyParameter { tag: Tag::EC_CURVE, value: KeyParameterValue::EcCurve(EcCurve::P_256) },
+        KeyParameter { tag: Tag::PURPOSE, value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN) },
+        KeyParameter { tag: Tag::DIGEST, value: KeyParameterValue::Digest(Digest::SHA_2_256) },
+        KeyParameter { tag: Tag::NO_AUTH_REQUIRED, value: KeyParameterValue::BoolValue(true) },
+        KeyParameter {
+            tag: Tag::ATTESTATION_CHALLENGE,
+            value: KeyParameterValue::Blob(challenge.to_vec()),
+        },
+    ]
+}
+
+/// Returns true if the generation failed because the backend has no attestation key.
+fn is_attestation_unavailable(e: &anyhow::Error) -> bool {
+    matches!(
+        e.root_cause().downcast_ref::<Error>(),
+        Some(Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED))
+            | Some(Error::Rc(ResponseCode::OUT_OF_KEYS))
+            | Some(Error::Rc(ResponseCode::OUT_OF_KEYS_PERMANENT_ERROR))
+            | Some(Error::Rc(ResponseCode::OUT_OF_KEYS_REQUIRES_SYSTEM_UPGRADE))
+    )
+}
+
+/// Verifies the attestation certificate chain in `metadata` against `challenge`.
+fn verify_key_attestation(metadata: &KeyMetadata, challenge: &[u8]) -> Result<()> {
+    let chain: Vec<Certificate> = [&metadata.certificate, &metadata.certificateChain]
+        .into_iter()
+        .flatten()
+        .map(|encoded| Certificate { encodedCertificate: encoded.clone() })
+        .collect();
+    match verify_attestation(&chain, challenge) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error::Km(ErrorCode::VERIFICATION_FAILED))
+            .context(ks_err!("The attestation of the key is invalid: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy_blob::test_utils::legacy_blob_test_vectors::{
+        LOADED_CACERT_AUTHBOUND, LOADED_CERT_AUTHBOUND,
+    };
+    use std::cell::RefCell;
+
+    /// The challenge in the attestation of LOADED_CERT_AUTHBOUND.
+    const CHALLENGE: &[u8] = b"asdfjkl;";
+
+    /// A pipeline that returns the attestation of LOADED_CERT_AUTHBOUND for every key, or fails
+    /// with `generate_error`, and records the keys that it generated and deleted.
+    #[derive(Default)]
+    struct FakePipeline {
+        generate_error: Option<fn() -> Error>,
+        delete_error: Option<fn() -> Error>,
+        generated: RefCell<Vec<(KeyDescriptor, Vec<KeyParameter>)>>,
+        deleted: RefCell<Vec<KeyDescriptor>>,
+    }
+
+    impl AttestationPipeline for FakePipeline {
+        fn generate_attested_key(
+            &self,
+            key: &KeyDescriptor,
+            params: &[KeyParameter],
+        ) -> Result<KeyMetadata> {
+            if let Some(e) = self.generate_error {
+                return Err(e()).context("In generate_attested_key.");
+            }
+            self.generated.borrow_mut().push((key.clone(), params.to_vec()));
+            Ok(KeyMetadata {
+                key: key.clone(),
+                certificate: Some(LOADED_CERT_AUTHBOUND.to_vec()),
+                certificateChain: Some(LOADED_CACERT_AUTHBOUND.to_vec()),
+                ..Default::default()
+            })
+        }
+
+        fn delete_attested_key(&self, key: &KeyDescriptor) -> Result<()> {
+            if let Some(e) = self.delete_error {
+                return Err(e()).context("In delete_attested_key.");
+            }
+            self.deleted.borrow_mut().push(key.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_self_test_passes_on_healthy_pipeline() {
+        let pipeline = FakePipeline::default();
+        assert_eq!(run_self_test_with_challenge(&pipeline, CHALLENGE), SelfTestOutcome::Passed);
+
+        // The throwaway key is attested, lives in the test namespace, and is deleted again.
+        let generated = pipeline.generated.borrow();
+        assert_eq!(generated.len(), 1);
+        let (key, params) = &generated[0];
+        assert_eq!((key.domain, key.nspace), (Domain::SELINUX, SELF_TEST_NAMESPACE));
+        assert!(params.contains(&KeyParameter {
+            tag: Tag::ATTESTATION_CHALLENGE,
+            value: KeyParameterValue::Blob(CHALLENGE.to_vec()),
+        }));
+        assert_eq!(*pipeline.deleted.borrow(), vec![key.clone()]);
+    }
+
+    #[test]
+    fn test_self_test_fails_on_broken_pipeline() {
+        // An attestation that does not carry the challenge fails, but the key is deleted.
+        let pipeline = FakePipeline::default();
+        assert_eq!(run_self_test_with_challenge(&pipeline, b"other"), SelfTestOutcome::Failed);
+        assert_eq!(pipeline.deleted.borrow().len(), 1);
+
+        let pipeline = FakePipeline {
+            generate_error: Some(|| Error::Km(ErrorCode::UNKNOWN_ERROR)),
+            ..Default::default()
+        };
+        assert_eq!(run_self_test_with_challenge(&pipeline, CHALLENGE), SelfTestOutcome::Failed);
+        assert!(pipeline.deleted.borrow().is_empty());
+
+        let pipeline = FakePipeline { delete_error: Some(Error::sys), ..Default::default() };
+        assert_eq!(run_self_test_with_challenge(&pipeline, CHALLENGE), SelfTestOutcome::Failed);
+    }
+
+    #[test]
+    fn test_self_test_is_skipped_without_attestation_key() {
+        let errors: [fn() -> Error; 2] = [
+            || Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED),
+            || Error::Rc(ResponseCode::OUT_OF_KEYS),
+        ];
+        for e in errors {
+            let pipeline = FakePipeline { generate_error: Some(e), ..Default::default() };
+            assert_eq!(
+                run_self_test_with_challenge(&pipeline, CHALLENGE),
+                SelfTestOutcome::Skipped
+            );
+        }
+    }
+}
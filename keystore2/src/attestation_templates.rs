@@ -0,0 +1,210 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements named templates of attestation parameters.
+//!
+//! Callers that attest many keys pass the same attestation parameters with every generation
+//! request. A template holds such a set of parameters under a name. A generation request that
+//! references a template gets the parameters of the template merged with its own, where the
+//! parameters of the request take precedence over template parameters with the same tag.
+//! Templates hold attestation parameters only, and the merged parameters are subject to the
+//! same permission checks as parameters passed by the caller directly. Templates are kept in
+//! memory and have to be registered again after keystore restarted.
+
+use crate::error::Error;
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    ErrorCode::ErrorCode, KeyParameter::KeyParameter, Tag::Tag,
+};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// The attestation templates registered with keystore.
+    pub static ref ATTESTATION_TEMPLATES: AttestationTemplates = Default::default();
+}
+
+/// Returns true if `tag` may be part of an attestation template.
+fn is_attestation_tag(tag: Tag) -> bool {
+    matches!(
+        tag,
+        Tag::ATTESTATION_CHALLENGE
+            | Tag::INCLUDE_UNIQUE_ID
+            | Tag::DEVICE_UNIQUE_ATTESTATION
+            | Tag::ATTESTATION_ID_BRAND
+            | Tag::ATTESTATION_ID_DEVICE
+            | Tag::ATTESTATION_ID_PRODUCT
+            | Tag::ATTESTATION_ID_SERIAL
+            | Tag::ATTESTATION_ID_IMEI
+            | Tag::ATTESTATION_ID_SECOND_IMEI
+            | Tag::ATTESTATION_ID_MEID
+            | Tag::ATTESTATION_ID_MANUFACTURER
+            | Tag::ATTESTATION_ID_MODEL
+            | Tag::CERTIFICATE_SERIAL
+            | Tag::CERTIFICATE_SUBJECT
+            | Tag::CERTIFICATE_NOT_BEFORE
+            | Tag::CERTIFICATE_NOT_AFTER
+    )
+}
+
+/// A registry of named sets of attestation parameters.
+#[derive(Debug, Default)]
+pub struct AttestationTemplates {
+    templates: Mutex<HashMap<String, Vec<KeyParameter>>>,
+}
+
+impl AttestationTemplates {
+    /// Registers `params` as the template `name`, replacing any template of the same name.
+    /// Registering an empty set of parameters removes the template. Each parameter must be an
+    /// attestation parameter, and each tag may occur only once.
+    pub fn register(&self, name: &str, params: &[KeyParameter]) -> Result<()> {
+        if name.is_empty() {
+            return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context(ks_err!("The template name must not be empty."));
+        }
+        for (i, kp) in params.iter().enumerate() {
+            if !is_attestation_tag(kp.tag) {
+                return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                    .context(ks_err!("{:?} is not an attestation parameter.", kp.tag));
+            }
+            if params[..i].iter().any(|other| other.tag == kp.tag) {
+                return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                    .context(ks_err!("{:?} is given more than once.", kp.tag));
+            }
+        }
+
+        let mut templates = self.templates.lock().unwrap();
+        if params.is_empty() {
+            templates.remove(name);
+        } else {
+            templates.insert(name.to_string(), params.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Returns the parameters of the template `name` merged with `params`. Parameters in
+    /// `params` replace the template parameters with the same tag.
+    pub fn apply(&self, name: &str, params: &[KeyParameter]) -> Result<Vec<KeyParameter>> {
+        let templates = self.templates.lock().unwrap();
+        let template = templates
+            .get(name)
+            .ok_or(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("No attestation template named {:?} is registered.", name))?;
+        Ok(template
+            .iter()
+            .filter(|kp| !params.iter().any(|other| other.tag == kp.tag))
+            .chain(params)
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        Algorithm::Algorithm, KeyParameterValue::KeyParameterValue,
+    };
+
+    fn blob_param(tag: Tag, value: &[u8]) -> KeyParameter {
+        KeyParameter { tag, value: KeyParameterValue::Blob(value.to_vec()) }
+    }
+
+    fn algorithm_param() -> KeyParameter {
+        KeyParameter { tag: Tag::ALGORITHM, value: KeyParameterValue::Algorithm(Algorithm::EC) }
+    }
+
+    fn assert_invalid_argument<T: std::fmt::Debug>(result: Result<T>) {
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+        );
+    }
+
+    #[test]
+    fn test_template_is_merged_with_request() -> Result<()> {
+        let templates = AttestationTemplates::default();
+        templates.register(
+            "attest",
+            &[
+                blob_param(Tag::ATTESTATION_CHALLENGE, b"template challenge"),
+                blob_param(Tag::CERTIFICATE_SUBJECT, b"template subject"),
+            ],
+        )?;
+
+        assert_eq!(
+            templates.apply("attest", &[algorithm_param()])?,
+            vec![
+                blob_param(Tag::ATTESTATION_CHALLENGE, b"template challenge"),
+                blob_param(Tag::CERTIFICATE_SUBJECT, b"template subject"),
+                algorithm_param(),
+            ]
+        );
+        assert_invalid_argument(templates.apply("unknown", &[algorithm_param()]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_overrides_template() -> Result<()> {
+        let templates = AttestationTemplates::default();
+        templates.register(
+            "attest",
+            &[
+                blob_param(Tag::ATTESTATION_CHALLENGE, b"template challenge"),
+                blob_param(Tag::CERTIFICATE_SUBJECT, b"template subject"),
+            ],
+        )?;
+
+        assert_eq!(
+            templates.apply(
+                "attest",
+                &[algorithm_param(), blob_param(Tag::ATTESTATION_CHALLENGE, b"request challenge")]
+            )?,
+            vec![
+                blob_param(Tag::CERTIFICATE_SUBJECT, b"template subject"),
+                algorithm_param(),
+                blob_param(Tag::ATTESTATION_CHALLENGE, b"request challenge"),
+            ]
+        );
+
+        // Registering a template again replaces it, and an empty template removes it.
+        templates.register("attest", &[blob_param(Tag::ATTESTATION_ID_BRAND, b"brand")])?;
+        assert_eq!(
+            templates.apply("attest", &[])?,
+            vec![blob_param(Tag::ATTESTATION_ID_BRAND, b"brand")]
+        );
+        templates.register("attest", &[])?;
+        assert_invalid_argument(templates.apply("attest", &[]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_attestation_parameters_can_be_registered() {
+        let templates = AttestationTemplates::default();
+        assert_invalid_argument(templates.register("attest", &[algorithm_param()]));
+        assert_invalid_argument(templates.register(
+            "attest",
+            &[
+                blob_param(Tag::ATTESTATION_CHALLENGE, b"one"),
+                blob_param(Tag::ATTESTATION_CHALLENGE, b"two"),
+            ],
+        ));
+        assert_invalid_argument(
+            templates.register("", &[blob_param(Tag::ATTESTATION_CHALLENGE, b"challenge")]),
+        );
+        assert_invalid_argument(templates.apply("attest", &[]));
+    }
+}
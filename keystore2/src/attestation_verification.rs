@@ -0,0 +1,210 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the verification of the attestation of a newly generated key.
+//!
+//! Callers of `generateKey` can ask keystore to check the attestation that KeyMint produced
+//! before the key is stored. The verification decodes the attestation extension of the leaf
+//! certificate, compares its challenge with the requested one, and checks that each certificate
+//! of the chain was issued by its successor. It does not establish trust in the root of the
+//! chain; that remains the responsibility of the relying party.
+
+use crate::key_flags::KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE;
+use crate::ks_err;
+use crate::security_level::MAX_ATTESTATION_CHALLENGE_LEN;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::Certificate::Certificate;
+use anyhow::{Context, Result};
+use keystore2_crypto::{
+    is_certificate_issued_by, parse_attestation_record_from_certificate, split_certificate_chain,
+};
+
+/// The reasons for which the verification of an attestation can fail.
+#[derive(Debug, Clone, Copy, thiserror::Error, PartialEq, Eq)]
+pub enum VerificationError {
+    /// KeyMint returned no certificate.
+    #[error("No attestation certificate was returned.")]
+    MissingCertificate,
+    /// A certificate of the chain could not be parsed. The index counts from the leaf.
+    #[error("Certificate {0} of the chain is malformed.")]
+    MalformedCertificate(usize),
+    /// The leaf certificate has no or a malformed attestation extension.
+    #[error("The attestation extension is missing or malformed.")]
+    MalformedAttestation,
+    /// The attestation challenge in the attestation extension differs from the requested one.
+    #[error("The attestation challenge does not match the requested challenge.")]
+    ChallengeMismatch,
+    /// The certificate at the given index, counting from the leaf, was not issued by the
+    /// certificate that follows it.
+    #[error("Certificate {0} of the chain was not issued by its successor.")]
+    BrokenChain(usize),
+}
+
+/// Returns the attestation challenge that KeyMint embeds in the attestation extension of a key
+/// generated with `challenge` and the key flags `flags`, i.e., the content of the
+/// `attestationChallenge` OCTET STRING as returned by `parse_attestation_challenge`. This is
+/// `challenge` itself, unless keystore replaces it by its SHA-256 digest because it is oversized
+/// and the caller opted in with `KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE`.
+pub fn expected_attestation_challenge(challenge: &[u8], flags: i32) -> Result<Vec<u8>> {
+    if (flags & KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE) != 0
+        && challenge.len() > MAX_ATTESTATION_CHALLENGE_LEN
+    {
+        keystore2_crypto::sha256(challenge)
+            .context(ks_err!("Failed to hash attestation challenge."))
+    } else {
+        Ok(challenge.to_vec())
+    }
+}
+
+/// Returns the attestation challenge from the attestation extension of the DER encoded
+/// certificate `leaf`.
+pub fn parse_attestation_challenge(leaf: &[u8]) -> Result<Vec<u8>, VerificationError> {
+    parse_attestation_record_from_certificate(leaf)
+        .map(|record| record.attestation_challenge)
+        .map_err(|_| VerificationError::MalformedAttestation)
+}
+
+/// Verifies the attestation `certificate_chain` that KeyMint returned for a new key, with the
+/// leaf first. Entries of `certificate_chain` may hold several concatenated certificates.
+pub fn verify_attestation(
+    certificate_chain: &[Certificate],
+    challenge: &[u8],
+) -> Result<(), VerificationError> {
+    let mut certs = vec![];
+    for cert in certificate_chain {
+        let split = split_certificate_chain(&cert.encodedCertificate)
+            .map_err(|_| VerificationError::MalformedCertificate(certs.len()))?;
+        certs.extend(split);
+    }
+
+    let leaf = certs.first().ok_or(VerificationError::MissingCertificate)?;
+    if parse_attestation_challenge(leaf)? != challenge {
+        return Err(VerificationError::ChallengeMismatch);
+    }
+
+    for (index, pair) in certs.windows(2).enumerate() {
+        match is_certificate_issued_by(pair[0], pair[1]) {
+            Ok(true) => {}
+            Ok(false) => return Err(VerificationError::BrokenChain(index)),
+            Err(_) => return Err(VerificationError::MalformedCertificate(index + 1)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy_blob::test_utils::legacy_blob_test_vectors::{
+        LOADED_CACERT_AUTHBOUND, LOADED_CERT_AUTHBOUND,
+    };
+
+    /// The challenge in the attestation of LOADED_CERT_AUTHBOUND.
+    const CHALLENGE: &[u8] = b"asdfjkl;";
+
+    fn certificate(encoded: &[u8]) -> Certificate {
+        Certificate { encodedCertificate: encoded.to_vec() }
+    }
+
+    /// The leaf followed by the concatenated rest of the chain, as keystore stores them.
+    fn attestation_chain() -> Vec<Certificate> {
+        vec![certificate(LOADED_CERT_AUTHBOUND), certificate(LOADED_CACERT_AUTHBOUND)]
+    }
+
+    #[test]
+    fn test_valid_attestation_passes() {
+        assert_eq!(verify_attestation(&attestation_chain(), CHALLENGE), Ok(()));
+
+        // The chain may also be split into one certificate per entry.
+        let mut chain = vec![certificate(LOADED_CERT_AUTHBOUND)];
+        chain.extend(
+            split_certificate_chain(LOADED_CACERT_AUTHBOUND).unwrap().into_iter().map(certificate),
+        );
+        assert_eq!(chain.len(), 4);
+        assert_eq!(verify_attestation(&chain, CHALLENGE), Ok(()));
+    }
+
+    #[test]
+    fn test_expected_challenge_matches_attestation() {
+        let embedded = parse_attestation_challenge(LOADED_CERT_AUTHBOUND).unwrap();
+        assert_eq!(embedded, expected_attestation_challenge(CHALLENGE, 0).unwrap());
+        // Short challenges are never hashed.
+        assert_eq!(
+            embedded,
+            expected_attestation_challenge(
+                CHALLENGE,
+                KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE
+            )
+            .unwrap()
+        );
+
+        let oversized = [0x5a; MAX_ATTESTATION_CHALLENGE_LEN + 1];
+        assert_eq!(expected_attestation_challenge(&oversized, 0).unwrap(), oversized.to_vec());
+        assert_eq!(
+            expected_attestation_challenge(
+                &oversized,
+                KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE
+            )
+            .unwrap(),
+            keystore2_crypto::sha256(&oversized).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mismatched_challenge_fails() {
+        assert_eq!(
+            verify_attestation(&attestation_chain(), b"asdfjkl"),
+            Err(VerificationError::ChallengeMismatch)
+        );
+        assert_eq!(
+            verify_attestation(&attestation_chain(), b""),
+            Err(VerificationError::ChallengeMismatch)
+        );
+    }
+
+    #[test]
+    fn test_broken_chain_fails() {
+        let cas = split_certificate_chain(LOADED_CACERT_AUTHBOUND).unwrap();
+
+        // The intermediate that issued the leaf is missing.
+        let chain = vec![certificate(LOADED_CERT_AUTHBOUND), certificate(cas[1])];
+        assert_eq!(verify_attestation(&chain, CHALLENGE), Err(VerificationError::BrokenChain(0)));
+
+        // The chain is out of order.
+        let chain = vec![
+            certificate(LOADED_CERT_AUTHBOUND),
+            certificate(cas[0]),
+            certificate(cas[2]),
+            certificate(cas[1]),
+        ];
+        assert_eq!(verify_attestation(&chain, CHALLENGE), Err(VerificationError::BrokenChain(1)));
+    }
+
+    #[test]
+    fn test_missing_or_malformed_certificates_fail() {
+        assert_eq!(verify_attestation(&[], CHALLENGE), Err(VerificationError::MissingCertificate));
+
+        // The CA certificates carry no attestation extension.
+        assert_eq!(
+            verify_attestation(&[certificate(LOADED_CACERT_AUTHBOUND)], CHALLENGE),
+            Err(VerificationError::MalformedAttestation)
+        );
+
+        let truncated = &LOADED_CACERT_AUTHBOUND[..LOADED_CACERT_AUTHBOUND.len() - 1];
+        let chain = vec![certificate(LOADED_CERT_AUTHBOUND), certificate(truncated)];
+        assert_eq!(
+            verify_attestation(&chain, CHALLENGE),
+            Err(VerificationError::MalformedCertificate(1))
+        );
+    }
+}
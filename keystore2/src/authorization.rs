@@ -19,7 +19,7 @@ use crate::error::Error as KeystoreError;
 use crate::error::anyhow_error_to_cstring;
 use crate::globals::{ENFORCEMENTS, SUPER_KEY, DB, LEGACY_IMPORTER};
 use crate::permission::KeystorePerm;
-use crate::super_key::UserState;
+use crate::super_key::{SuperKeyManager, UserState};
 use crate::utils::{check_keystore_permission, watchdog as wd};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     HardwareAuthToken::HardwareAuthToken,
@@ -155,10 +155,8 @@ impl AuthorizationManager {
                     .context(ks_err!("Unlock with password."))?;
                 ENFORCEMENTS.set_device_locked(user_id, false);
 
-                let mut skm = SUPER_KEY.write().unwrap();
-
                 DB.with(|db| {
-                    skm.unlock_screen_lock_bound_key(
+                    SUPER_KEY.write().unwrap().unlock_screen_lock_bound_key(
                         &mut db.borrow_mut(),
                         user_id as u32,
                         &password,
@@ -166,10 +164,11 @@ impl AuthorizationManager {
                 })
                 .context(ks_err!("unlock_screen_lock_bound_key failed"))?;
 
-                // Unlock super key.
+                // Unlock super key. Concurrent unlocks of the same user share one derivation.
                 if let UserState::Uninitialized = DB
                     .with(|db| {
-                        skm.unlock_and_get_user_state(
+                        SuperKeyManager::unlock_user_single_flight(
+                            &SUPER_KEY,
                             &mut db.borrow_mut(),
                             &LEGACY_IMPORTER,
                             user_id as u32,
@@ -15,6 +15,7 @@
 //! Offer keys based on the "boot level" for superencryption.
 
 use crate::ks_err;
+use crate::sysprop::read_prop_parsed;
 use crate::{
     database::{KeyType, KeystoreDB},
     key_parameter::KeyParameterValue,
@@ -26,7 +27,7 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
 };
 use anyhow::{Context, Result};
 use keystore2_crypto::{hkdf_expand, ZVec, AES_256_KEY_LENGTH};
-use std::{collections::VecDeque, convert::TryFrom};
+use std::{collections::VecDeque, convert::TryFrom, sync::RwLock};
 
 /// Strategies used to prevent later boot stages from using the KM key that protects the level 0
 /// key
@@ -47,44 +48,37 @@ enum DenyLaterStrategy {
 /// of KM are present.
 const PROPERTY_NAME: &str = "ro.keystore.boot_level_key.strategy";
 
-fn lookup_level_zero_km_and_strategy() -> Result<Option<(SecurityLevel, DenyLaterStrategy)>> {
-    let property_val = rustutils::system_properties::read(PROPERTY_NAME)
-        .with_context(|| ks_err!("property read failed: {}", PROPERTY_NAME))?;
-    // TODO: use feature(let_else) when that's stabilized.
-    let property_val = if let Some(p) = property_val {
-        p
-    } else {
-        log::info!("{} not set, inferring from installed KM instances", PROPERTY_NAME);
-        return Ok(None);
-    };
-    let (level, strategy) = if let Some(c) = property_val.split_once(':') {
-        c
-    } else {
-        log::error!("Missing colon in {}: {:?}", PROPERTY_NAME, property_val);
-        return Ok(None);
-    };
+/// Parses a value of `PROPERTY_NAME` of the form "LEVEL:STRATEGY".
+fn parse_level_zero_km_and_strategy(value: &str) -> Option<(SecurityLevel, DenyLaterStrategy)> {
+    let (level, strategy) = value.split_once(':')?;
     let level = match level {
         "TRUSTED_ENVIRONMENT" => SecurityLevel::TRUSTED_ENVIRONMENT,
         "STRONGBOX" => SecurityLevel::STRONGBOX,
-        _ => {
-            log::error!("Unknown security level in {}: {:?}", PROPERTY_NAME, level);
-            return Ok(None);
-        }
+        _ => return None,
     };
     let strategy = match strategy {
         "EARLY_BOOT_ONLY" => DenyLaterStrategy::EarlyBootOnly,
         "MAX_USES_PER_BOOT" => DenyLaterStrategy::MaxUsesPerBoot,
-        _ => {
-            log::error!("Unknown DenyLaterStrategy in {}: {:?}", PROPERTY_NAME, strategy);
-            return Ok(None);
-        }
+        _ => return None,
     };
-    log::info!("Set from {}: {}", PROPERTY_NAME, property_val);
-    Ok(Some((level, strategy)))
+    Some((level, strategy))
+}
+
+fn lookup_level_zero_km_and_strategy() -> Option<(SecurityLevel, DenyLaterStrategy)> {
+    // A malformed value is logged by the accessor, and treated like an unset property.
+    let result =
+        read_prop_parsed(PROPERTY_NAME, None, |v| parse_level_zero_km_and_strategy(v).map(Some));
+    match result {
+        Some((level, strategy)) => {
+            log::info!("Set from {}: {:?}:{:?}", PROPERTY_NAME, level, strategy)
+        }
+        None => log::info!("{} not set, inferring from installed KM instances", PROPERTY_NAME),
+    }
+    result
 }
 
 fn get_level_zero_key_km_and_strategy() -> Result<(KeyMintDevice, DenyLaterStrategy)> {
-    if let Some((level, strategy)) = lookup_level_zero_km_and_strategy()? {
+    if let Some((level, strategy)) = lookup_level_zero_km_and_strategy() {
         return Ok((
             KeyMintDevice::get(level).context(ks_err!("Get KM instance failed."))?,
             strategy,
@@ -179,9 +173,8 @@ pub fn get_level_zero_key(db: &mut KeystoreDB) -> Result<ZVec> {
     Ok(level_zero_key)
 }
 
-/// Holds the key for the current boot level, and a cache of future keys generated as required.
-/// When the boot level advances, keys prior to the current boot level are securely dropped.
-pub struct BootLevelKeyCache {
+/// The mutable state of the `BootLevelKeyCache`.
+struct CacheState {
     /// Least boot level currently accessible, if any is.
     current: usize,
     /// Invariant: cache entry *i*, if it exists, holds the HKDF key for boot level
@@ -191,26 +184,22 @@ pub struct BootLevelKeyCache {
     cache: VecDeque<ZVec>,
 }
 
-impl BootLevelKeyCache {
-    const HKDF_ADVANCE: &'static [u8] = b"Advance KDF one step";
-    const HKDF_AES: &'static [u8] = b"Generate AES-256-GCM key";
-    const HKDF_KEY_SIZE: usize = 32;
-
-    /// Initialize the cache with the level zero key.
-    pub fn new(level_zero_key: ZVec) -> Self {
-        let mut cache: VecDeque<ZVec> = VecDeque::new();
-        cache.push_back(level_zero_key);
-        Self { current: 0, cache }
-    }
-
-    /// Report whether the key for the given level can be inferred.
-    pub fn level_accessible(&self, boot_level: usize) -> bool {
+impl CacheState {
+    fn level_accessible(&self, boot_level: usize) -> bool {
         // If the requested boot level is lower than the current boot level
         // or if we have reached the end (`cache.empty()`) we can't retrieve
         // the boot key.
         boot_level >= self.current && !self.cache.is_empty()
     }
 
+    /// Returns the HKDF key for `boot_level` if it is already in the cache.
+    fn get_cached(&self, boot_level: usize) -> Option<&ZVec> {
+        if !self.level_accessible(boot_level) {
+            return None;
+        }
+        self.cache.get(boot_level - self.current)
+    }
+
     /// Get the HKDF key for boot level `boot_level`. The key for level *i*+1
     /// is calculated from the level *i* key using `hkdf_expand`.
     fn get_hkdf_key(&mut self, boot_level: usize) -> Result<Option<&ZVec>> {
@@ -226,56 +215,103 @@ impl BootLevelKeyCache {
             // We check at the start that cache is non-empty and future iterations only push,
             // so this must unwrap.
             let highest_key = self.cache.back().unwrap();
-            let next_key = hkdf_expand(Self::HKDF_KEY_SIZE, highest_key, Self::HKDF_ADVANCE)
-                .context(ks_err!("Advancing key one step"))?;
+            let next_key = hkdf_expand(
+                BootLevelKeyCache::HKDF_KEY_SIZE,
+                highest_key,
+                BootLevelKeyCache::HKDF_ADVANCE,
+            )
+            .context(ks_err!("Advancing key one step"))?;
             self.cache.push_back(next_key);
         }
 
         // If we reach this point, we should have a key at index boot_level - current.
         Ok(Some(self.cache.get(boot_level - self.current).unwrap()))
     }
+}
+
+/// Holds the key for the current boot level, and a cache of future keys generated as required.
+/// When the boot level advances, keys prior to the current boot level are securely dropped.
+///
+/// The cache may be used from multiple threads. Advancing the boot level and growing the cache
+/// happen under the write lock, and every key is derived while the lock protecting the state it
+/// is derived from is held. So a lookup observes the cache either entirely before or entirely
+/// after an advance, and never returns a key for a level that was already dropped.
+pub struct BootLevelKeyCache {
+    state: RwLock<CacheState>,
+}
+
+impl BootLevelKeyCache {
+    const HKDF_ADVANCE: &'static [u8] = b"Advance KDF one step";
+    const HKDF_AES: &'static [u8] = b"Generate AES-256-GCM key";
+    const HKDF_KEY_SIZE: usize = 32;
+
+    /// Initialize the cache with the level zero key.
+    pub fn new(level_zero_key: ZVec) -> Self {
+        let mut cache: VecDeque<ZVec> = VecDeque::new();
+        cache.push_back(level_zero_key);
+        Self { state: RwLock::new(CacheState { current: 0, cache }) }
+    }
+
+    /// Report whether the key for the given level can be inferred.
+    pub fn level_accessible(&self, boot_level: usize) -> bool {
+        self.state.read().unwrap().level_accessible(boot_level)
+    }
 
     /// Drop keys prior to the given boot level, while retaining the ability to generate keys for
     /// that level and later.
-    pub fn advance_boot_level(&mut self, new_boot_level: usize) -> Result<()> {
-        if !self.level_accessible(new_boot_level) {
+    pub fn advance_boot_level(&self, new_boot_level: usize) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if !state.level_accessible(new_boot_level) {
             log::error!(
                 "Failed to advance boot level to {}, current is {}, cache size {}",
                 new_boot_level,
-                self.current,
-                self.cache.len()
+                state.current,
+                state.cache.len()
             );
             return Ok(());
         }
 
         // We `get` the new boot level for the side effect of advancing the cache to a point
         // where the new boot level is present.
-        self.get_hkdf_key(new_boot_level).context(ks_err!("Advancing cache"))?;
+        state.get_hkdf_key(new_boot_level).context(ks_err!("Advancing cache"))?;
 
         // Then we split the queue at the index of the new boot level and discard the front,
         // keeping only the keys with the current boot level or higher.
-        self.cache = self.cache.split_off(new_boot_level - self.current);
+        let current = state.current;
+        state.cache = state.cache.split_off(new_boot_level - current);
 
         // The new cache has the new boot level at index 0, so we set `current` to
         // `new_boot_level`.
-        self.current = new_boot_level;
+        state.current = new_boot_level;
 
         Ok(())
     }
 
     /// Drop all keys, effectively raising the current boot level to infinity; no keys can
     /// be inferred from this point on.
-    pub fn finish(&mut self) {
-        self.cache.clear();
+    pub fn finish(&self) {
+        self.state.write().unwrap().cache.clear();
     }
 
-    fn expand_key(
-        &mut self,
-        boot_level: usize,
-        out_len: usize,
-        info: &[u8],
-    ) -> Result<Option<ZVec>> {
-        self.get_hkdf_key(boot_level)
+    fn expand_key(&self, boot_level: usize, out_len: usize, info: &[u8]) -> Result<Option<ZVec>> {
+        // Most lookups hit the cache, so try with the read lock first. Only if the cache has
+        // to grow, take the write lock and check again, because the boot level may have
+        // advanced in between.
+        {
+            let state = self.state.read().unwrap();
+            match state.get_cached(boot_level) {
+                Some(k) => {
+                    return hkdf_expand(out_len, k, info)
+                        .map(Some)
+                        .context(ks_err!("Calling hkdf_expand"))
+                }
+                None if !state.level_accessible(boot_level) => return Ok(None),
+                None => {}
+            }
+        }
+        let mut state = self.state.write().unwrap();
+        state
+            .get_hkdf_key(boot_level)
             .context(ks_err!("Looking up HKDF key"))?
             .map(|k| hkdf_expand(out_len, k, info))
             .transpose()
@@ -283,7 +319,7 @@ impl BootLevelKeyCache {
     }
 
     /// Return the AES-256-GCM key for the current boot level.
-    pub fn aes_key(&mut self, boot_level: usize) -> Result<Option<ZVec>> {
+    pub fn aes_key(&self, boot_level: usize) -> Result<Option<ZVec>> {
         self.expand_key(boot_level, AES_256_KEY_LENGTH, BootLevelKeyCache::HKDF_AES)
             .context(ks_err!("expand_key failed"))
     }
@@ -296,7 +332,7 @@ mod test {
     #[test]
     fn test_output_is_consistent() -> Result<()> {
         let initial_key = b"initial key";
-        let mut blkc = BootLevelKeyCache::new(ZVec::try_from(initial_key as &[u8])?);
+        let blkc = BootLevelKeyCache::new(ZVec::try_from(initial_key as &[u8])?);
         assert!(blkc.level_accessible(0));
         assert!(blkc.level_accessible(9));
         assert!(blkc.level_accessible(10));
@@ -335,4 +371,51 @@ mod test {
         assert_eq!(None, blkc.aes_key(10)?);
         Ok(())
     }
+
+    #[test]
+    fn test_concurrent_advance_and_lookup() -> Result<()> {
+        use std::sync::Arc;
+        const LEVELS: usize = 40;
+
+        let initial_key = b"initial key";
+        // Compute the expected key of every level single threaded.
+        let reference = BootLevelKeyCache::new(ZVec::try_from(initial_key as &[u8])?);
+        let expected: Arc<Vec<ZVec>> = Arc::new(
+            (0..=LEVELS).map(|level| reference.aes_key(level).unwrap().unwrap()).collect(),
+        );
+
+        let blkc = Arc::new(BootLevelKeyCache::new(ZVec::try_from(initial_key as &[u8])?));
+        let readers: Vec<_> = (0..4)
+            .map(|t| {
+                let blkc = blkc.clone();
+                let expected = expected.clone();
+                std::thread::spawn(move || {
+                    // Once a level became inaccessible, it must stay inaccessible.
+                    let mut lowest_accessible = 0;
+                    for i in 0..2000 {
+                        let level = (i * 7 + t) % (LEVELS + 1);
+                        match blkc.aes_key(level).unwrap() {
+                            Some(key) => {
+                                assert!(level >= lowest_accessible);
+                                assert_eq!(key, expected[level]);
+                            }
+                            None => lowest_accessible = lowest_accessible.max(level + 1),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for level in 1..=LEVELS {
+            blkc.advance_boot_level(level)?;
+            std::thread::yield_now();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        assert!(!blkc.level_accessible(LEVELS - 1));
+        assert_eq!(Some(&expected[LEVELS]), blkc.aes_key(LEVELS)?.as_ref());
+        Ok(())
+    }
 }
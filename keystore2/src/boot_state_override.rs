@@ -0,0 +1,206 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a test only override of the verified boot state in attestations.
+//!
+//! Tests of attestation verifiers need certificates that reflect a controlled verified boot
+//! state, e.g., an unlocked bootloader. The override is configured through system properties and
+//! passed to KeyMint as `Tag::ROOT_OF_TRUST` with the attestation parameters of requests that opt
+//! in with `KEY_FLAG_TEST_VERIFIED_BOOT_STATE`, which only test callers on debuggable builds may
+//! use. Because KeyMint may ignore the override, the attestation of the new key is checked to
+//! carry it, so that a test never silently receives the real boot state.
+
+use crate::error::{Error, ErrorCode};
+use crate::ks_err;
+use crate::sysprop::{read_prop_bool, read_prop_parsed};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, Tag::Tag,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{parse_attestation_record_from_certificate, RootOfTrust};
+
+/// The verified boot state to attest: "verified", "self_signed", "unverified", or "failed".
+const VERIFIED_BOOT_STATE_PROPERTY: &str = "keystore.test.verified_boot_state";
+
+/// Whether the attested bootloader is locked, false by default.
+const DEVICE_LOCKED_PROPERTY: &str = "keystore.test.device_locked";
+
+/// The verified boot states of the `RootOfTrust` schema in IKeyMintDevice.aidl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifiedBootState {
+    /// The full chain of trust extends to the verified boot key.
+    Verified = 0,
+    /// The boot image was signed by a user provided key.
+    SelfSigned = 1,
+    /// The device was unlocked and booted an unverified image.
+    Unverified = 2,
+    /// The verification failed.
+    Failed = 3,
+}
+
+impl VerifiedBootState {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "verified" => Some(Self::Verified),
+            "self_signed" => Some(Self::SelfSigned),
+            "unverified" => Some(Self::Unverified),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// The verified boot fields that are attested instead of the real ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootStateOverride {
+    /// The attested verified boot state.
+    pub verified_boot_state: VerifiedBootState,
+    /// Whether the attested bootloader is locked.
+    pub device_locked: bool,
+}
+
+impl BootStateOverride {
+    /// Reads the override from the `keystore.test.verified_boot_state` and
+    /// `keystore.test.device_locked` system properties. Returns None if no verified boot state
+    /// is configured.
+    pub fn from_property() -> Option<Self> {
+        read_prop_parsed(VERIFIED_BOOT_STATE_PROPERTY, None, |value| {
+            VerifiedBootState::parse(value).map(Some)
+        })
+        .map(|verified_boot_state| Self {
+            verified_boot_state,
+            device_locked: read_prop_bool(DEVICE_LOCKED_PROPERTY, false),
+        })
+    }
+
+    /// Returns the DER encoded `RootOfTrust` with this boot state. The verified boot key and
+    /// the verified boot hash are all zeros, as for devices without verified boot.
+    pub fn encode_root_of_trust(&self) -> Vec<u8> {
+        const EMPTY_DIGEST: [u8; 32] = [0; 32];
+        let mut content = vec![0x04, EMPTY_DIGEST.len() as u8];
+        content.extend_from_slice(&EMPTY_DIGEST);
+        content.extend_from_slice(&[0x01, 0x01, if self.device_locked { 0xff } else { 0x00 }]);
+        content.extend_from_slice(&[0x0a, 0x01, self.verified_boot_state as u8]);
+        content.extend_from_slice(&[0x04, EMPTY_DIGEST.len() as u8]);
+        content.extend_from_slice(&EMPTY_DIGEST);
+        let mut encoded = vec![0x30, content.len() as u8];
+        encoded.extend(content);
+        encoded
+    }
+
+    /// Returns `params` with the root of trust of this boot state, replacing any root of trust
+    /// in `params`.
+    pub fn apply(&self, params: &[KeyParameter]) -> Vec<KeyParameter> {
+        let mut result: Vec<KeyParameter> =
+            params.iter().filter(|kp| kp.tag != Tag::ROOT_OF_TRUST).cloned().collect();
+        result.push(KeyParameter {
+            tag: Tag::ROOT_OF_TRUST,
+            value: KeyParameterValue::Blob(self.encode_root_of_trust()),
+        });
+        result
+    }
+
+    /// Checks that the attestation extension of the DER encoded certificate `leaf` carries
+    /// this boot state. Fails with `ErrorCode::VERIFICATION_FAILED` otherwise.
+    pub fn check_attestation(&self, leaf: &[u8]) -> Result<()> {
+        let record = parse_attestation_record_from_certificate(leaf)
+            .map_err(|_| Error::Km(ErrorCode::VERIFICATION_FAILED))
+            .context(ks_err!("Failed to parse the attestation extension."))?;
+        let expected = RootOfTrust {
+            device_locked: self.device_locked,
+            verified_boot_state: self.verified_boot_state as i32,
+        };
+        if record.root_of_trust != Some(expected) {
+            return Err(Error::Km(ErrorCode::VERIFICATION_FAILED)).context(ks_err!(
+                "Attested root of trust {:?} does not match the override {:?}.",
+                record.root_of_trust,
+                expected
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy_blob::test_utils::legacy_blob_test_vectors::LOADED_CERT_AUTHBOUND;
+
+    #[test]
+    fn test_override_appears_in_attestation() {
+        // LOADED_CERT_AUTHBOUND was attested on an unlocked device with an unverified image.
+        let unlocked = BootStateOverride {
+            verified_boot_state: VerifiedBootState::Unverified,
+            device_locked: false,
+        };
+        assert!(unlocked.check_attestation(LOADED_CERT_AUTHBOUND).is_ok());
+
+        for other in [
+            BootStateOverride {
+                verified_boot_state: VerifiedBootState::Verified,
+                device_locked: true,
+            },
+            BootStateOverride {
+                verified_boot_state: VerifiedBootState::Unverified,
+                device_locked: true,
+            },
+        ] {
+            assert_eq!(
+                other
+                    .check_attestation(LOADED_CERT_AUTHBOUND)
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Km(ErrorCode::VERIFICATION_FAILED))
+            );
+        }
+    }
+
+    #[test]
+    fn test_root_of_trust_is_passed_to_keymint() {
+        let boot_state = BootStateOverride {
+            verified_boot_state: VerifiedBootState::SelfSigned,
+            device_locked: true,
+        };
+        let encoded = boot_state.encode_root_of_trust();
+        assert_eq!(encoded.len(), 76);
+        assert_eq!(&encoded[..4], &[0x30, 74, 0x04, 32]);
+        assert_eq!(&encoded[36..42], &[0x01, 0x01, 0xff, 0x0a, 0x01, 0x01]);
+
+        let params = [
+            KeyParameter {
+                tag: Tag::ATTESTATION_CHALLENGE,
+                value: KeyParameterValue::Blob(vec![1]),
+            },
+            KeyParameter { tag: Tag::ROOT_OF_TRUST, value: KeyParameterValue::Blob(vec![2]) },
+        ];
+        let applied = boot_state.apply(&params);
+        assert_eq!(
+            applied,
+            vec![
+                params[0].clone(),
+                KeyParameter { tag: Tag::ROOT_OF_TRUST, value: KeyParameterValue::Blob(encoded) },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(VerifiedBootState::parse("verified"), Some(VerifiedBootState::Verified));
+        assert_eq!(VerifiedBootState::parse("self_signed"), Some(VerifiedBootState::SelfSigned));
+        assert_eq!(VerifiedBootState::parse("unverified"), Some(VerifiedBootState::Unverified));
+        assert_eq!(VerifiedBootState::parse("failed"), Some(VerifiedBootState::Failed));
+        assert_eq!(VerifiedBootState::parse("green"), None);
+    }
+}
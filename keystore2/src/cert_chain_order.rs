@@ -0,0 +1,204 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the order in which stored attestation certificate chains are returned
+//! to callers.
+//!
+//! Keystore returns the leaf certificate of a key separately from the rest of the chain. Some
+//! verifiers expect the rest of the chain leaf-first, others root-first. By default, the chain is
+//! returned in the order in which it was stored. Otherwise, the chain is normalized by following
+//! the issuer links of its certificates and then returned in the configured order.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use crate::sysprop::read_prop_parsed;
+use anyhow::{Context, Result};
+use keystore2_crypto::{is_certificate_issued_by, split_certificate_chain};
+
+/// The order of returned certificate chains: "stored", "leaf_first", or "root_first".
+const CERT_CHAIN_ORDER_PROPERTY: &str = "keystore.cert_chain_order";
+
+/// The order in which certificate chains are returned to callers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertChainOrder {
+    /// The chain is returned as stored, which is the order in which KeyMint or RKPD provided it.
+    Stored,
+    /// Each certificate is followed by its issuer.
+    LeafFirst,
+    /// Each certificate is followed by the certificate that it issued.
+    RootFirst,
+}
+
+impl CertChainOrder {
+    /// Reads the order from the `keystore.cert_chain_order` system property. The property is
+    /// read on every call, so changes take effect with the next request.
+    pub fn from_property() -> Self {
+        read_prop_parsed(CERT_CHAIN_ORDER_PROPERTY, Self::Stored, Self::parse)
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "stored" => Some(Self::Stored),
+            "leaf_first" => Some(Self::LeafFirst),
+            "root_first" => Some(Self::RootFirst),
+            _ => None,
+        }
+    }
+
+    /// Returns the concatenated certificate chain `chain` in this order. If the chain cannot be
+    /// normalized, e.g., because it is incomplete, it is logged and returned as stored.
+    pub fn apply(self, chain: Vec<u8>) -> Vec<u8> {
+        if self == Self::Stored {
+            return chain;
+        }
+        match self.reorder(&chain) {
+            Ok(reordered) => reordered,
+            Err(e) => {
+                log::warn!("Returning certificate chain as stored: {:?}", e);
+                chain
+            }
+        }
+    }
+
+    fn reorder(self, chain: &[u8]) -> Result<Vec<u8>> {
+        let certs = split_certificate_chain(chain)
+            .context(ks_err!("Failed to split certificate chain."))?;
+        let mut certs = normalize_chain(&certs).context(ks_err!())?;
+        if self == Self::RootFirst {
+            certs.reverse();
+        }
+        Ok(certs.concat())
+    }
+}
+
+/// Orders the DER-encoded certificates `certs` leaf-first by their issuer links, i.e., such that
+/// each certificate is issued by its successor. The leaf is the only certificate that issued none
+/// of the others. Fails with `ResponseCode::VALUE_CORRUPTED` if the certificates do not form a
+/// single chain.
+pub fn normalize_chain<'a>(certs: &[&'a [u8]]) -> Result<Vec<&'a [u8]>> {
+    let issued_by = |cert: &[u8], issuer: &[u8]| {
+        is_certificate_issued_by(cert, issuer).context(ks_err!("Failed to check issuer link."))
+    };
+
+    let mut leaves = vec![];
+    for (i, candidate) in certs.iter().enumerate() {
+        let mut issued_any = false;
+        for (j, cert) in certs.iter().enumerate() {
+            if i != j && issued_by(cert, candidate)? {
+                issued_any = true;
+                break;
+            }
+        }
+        if !issued_any {
+            leaves.push(i);
+        }
+    }
+    if leaves.len() != 1 {
+        return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context(ks_err!("Expected one leaf in the chain, found {}.", leaves.len()));
+    }
+
+    let mut remaining: Vec<usize> = (0..certs.len()).filter(|i| *i != leaves[0]).collect();
+    let mut ordered = vec![certs[leaves[0]]];
+    while !remaining.is_empty() {
+        let current = ordered[ordered.len() - 1];
+        let mut next = None;
+        for (pos, i) in remaining.iter().enumerate() {
+            if issued_by(current, certs[*i])? {
+                next = Some(pos);
+                break;
+            }
+        }
+        match next {
+            Some(pos) => ordered.push(certs[remaining.remove(pos)]),
+            None => {
+                return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                    .context(ks_err!("No issuer for certificate {} of the chain.", ordered.len()))
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy_blob::test_utils::legacy_blob_test_vectors::{
+        LOADED_CACERT_AUTHBOUND, LOADED_CERT_AUTHBOUND,
+    };
+
+    /// The leaf and the three certificates of its chain, leaf-first.
+    fn full_chain() -> Vec<&'static [u8]> {
+        let mut certs = vec![LOADED_CERT_AUTHBOUND];
+        certs.extend(split_certificate_chain(LOADED_CACERT_AUTHBOUND).unwrap());
+        assert_eq!(certs.len(), 4);
+        certs
+    }
+
+    fn assert_leaf_first(certs: &[&[u8]]) {
+        for pair in certs.windows(2) {
+            assert!(is_certificate_issued_by(pair[0], pair[1]).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_normalize_shuffled_chain() {
+        let chain = full_chain();
+        let shuffled = vec![chain[2], chain[0], chain[3], chain[1]];
+        let normalized = normalize_chain(&shuffled).unwrap();
+        assert_eq!(normalized, chain);
+        assert_leaf_first(&normalized);
+    }
+
+    #[test]
+    fn test_reorder_chain_both_directions() {
+        let stored = LOADED_CACERT_AUTHBOUND.to_vec();
+        assert_eq!(CertChainOrder::Stored.apply(stored.clone()), stored);
+
+        let root_first = CertChainOrder::RootFirst.apply(stored.clone());
+        assert_ne!(root_first, stored);
+        let mut certs = split_certificate_chain(&root_first).unwrap();
+        assert_eq!(certs.len(), 3);
+        // Each certificate issued its predecessor.
+        certs.reverse();
+        assert_leaf_first(&certs);
+
+        let leaf_first = CertChainOrder::LeafFirst.apply(root_first);
+        assert_eq!(leaf_first, stored);
+        assert_leaf_first(&split_certificate_chain(&leaf_first).unwrap());
+    }
+
+    #[test]
+    fn test_broken_chain_is_returned_as_stored() {
+        let chain = full_chain();
+        assert_eq!(
+            normalize_chain(&[chain[0], chain[2], chain[3]])
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::VALUE_CORRUPTED))
+        );
+
+        let broken = [chain[3], chain[1]].concat();
+        assert_eq!(CertChainOrder::RootFirst.apply(broken.clone()), broken);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(CertChainOrder::parse("stored"), Some(CertChainOrder::Stored));
+        assert_eq!(CertChainOrder::parse("leaf_first"), Some(CertChainOrder::LeafFirst));
+        assert_eq!(CertChainOrder::parse("root_first"), Some(CertChainOrder::RootFirst));
+        assert_eq!(CertChainOrder::parse("reverse"), None);
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a circuit breaker that guards the calls into a KeyMint backend.
+//!
+//! If a backend is wedged, every request would stall until its binder transaction fails.
+//! After `threshold` consecutive backend failures the breaker opens, and all requests fail
+//! fast with `ResponseCode::BACKEND_BUSY` for the duration of the cooldown. Once the cooldown
+//! expired, a single request is let through as a probe. If the probe succeeds, the breaker
+//! closes again; if it fails, the breaker reopens for another cooldown period.
+//! Only failures that indicate an unhealthy backend are counted. Errors caused by the request
+//! itself, e.g., invalid arguments or permission errors, count as a sign of life.
+
+use crate::error::{Error, ErrorCode, ResponseCode};
+use crate::ks_err;
+use crate::metrics_store::log_circuit_breaker_transition;
+use crate::sysprop::{read_prop_duration, read_prop_u32};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use android_system_keystore2::binder::ExceptionCode;
+use anyhow::{Context, Result};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of consecutive backend failures after which the breaker opens.
+const THRESHOLD_PROPERTY: &str = "keystore.keymint_circuit_breaker.threshold";
+const DEFAULT_THRESHOLD: u32 = 5;
+/// Time for which the breaker stays open before probing the backend.
+const COOLDOWN_PROPERTY: &str = "keystore.keymint_circuit_breaker.cooldown";
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        consecutive_failures: u32,
+    },
+    Open {
+        since: Instant,
+    },
+    /// The cooldown expired and a probe request is in flight.
+    HalfOpen,
+}
+
+/// Circuit breaker for the KeyMint backend of one security level.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    security_level: SecurityLevel,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// Creates a circuit breaker configured by the system properties
+    /// `keystore.keymint_circuit_breaker.threshold` and
+    /// `keystore.keymint_circuit_breaker.cooldown`. A threshold of 0 disables the breaker.
+    pub fn new(security_level: SecurityLevel) -> Self {
+        Self::new_with(
+            security_level,
+            read_prop_u32(THRESHOLD_PROPERTY, DEFAULT_THRESHOLD),
+            read_prop_duration(COOLDOWN_PROPERTY, DEFAULT_COOLDOWN),
+        )
+    }
+
+    fn new_with(security_level: SecurityLevel, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            security_level,
+            threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { consecutive_failures: 0 }),
+        }
+    }
+
+    /// Returns true if the error indicates that the backend itself is unhealthy, as opposed to
+    /// the request being rejected by a healthy backend.
+    fn is_backend_failure(e: &anyhow::Error) -> bool {
+        match e.root_cause().downcast_ref::<Error>() {
+            Some(Error::BinderTransaction(_)) => true,
+            Some(Error::Binder(exception_code, _)) => {
+                *exception_code != ExceptionCode::SERVICE_SPECIFIC
+            }
+            Some(Error::Km(error_code)) => matches!(
+                *error_code,
+                ErrorCode::HARDWARE_TYPE_UNAVAILABLE
+                    | ErrorCode::SECURE_HW_COMMUNICATION_FAILED
+                    | ErrorCode::SECURE_HW_BUSY
+            ),
+            _ => false,
+        }
+    }
+
+    /// Decides whether a request may be sent to the backend. The outcome of an admitted
+    /// request must be recorded through the returned guard.
+    fn admit(&self) -> Result<Admission> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(Admission { breaker: Some(self) }),
+            State::Open { since } if since.elapsed() >= self.cooldown => {
+                *state = State::HalfOpen;
+                Ok(Admission { breaker: Some(self) })
+            }
+            State::Open { .. } | State::HalfOpen => Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(ks_err!(
+                    "KeyMint backend {:?} unavailable: circuit breaker is open.",
+                    self.security_level
+                )),
+        }
+    }
+
+    /// Records the outcome of a request that was admitted.
+    fn record(&self, backend_failure: bool) {
+        let mut state = self.state.lock().unwrap();
+        let new_state = match (*state, backend_failure) {
+            (State::HalfOpen, false) => {
+                log::info!("KeyMint backend {:?} recovered.", self.security_level);
+                log_circuit_breaker_transition(&self.security_level, false);
+                State::Closed { consecutive_failures: 0 }
+            }
+            (State::HalfOpen, true) => State::Open { since: Instant::now() },
+            (State::Closed { .. }, false) => State::Closed { consecutive_failures: 0 },
+            (State::Closed { consecutive_failures }, true) => {
+                let consecutive_failures = consecutive_failures + 1;
+                if consecutive_failures >= self.threshold {
+                    log::error!(
+                        "KeyMint backend {:?} failed {} times in a row. Opening circuit breaker.",
+                        self.security_level,
+                        consecutive_failures
+                    );
+                    log_circuit_breaker_transition(&self.security_level, true);
+                    State::Open { since: Instant::now() }
+                } else {
+                    State::Closed { consecutive_failures }
+                }
+            }
+            // Requests admitted before the breaker opened may still complete.
+            (s @ State::Open { .. }, _) => s,
+        };
+        *state = new_state;
+    }
+
+    /// Runs `f` if the breaker admits the request, and records whether it failed because of
+    /// the backend. If the breaker is open, `f` is not run and `ResponseCode::BACKEND_BUSY` is
+    /// returned.
+    pub fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        if self.threshold == 0 {
+            return f();
+        }
+        let admission = self.admit()?;
+        let result = f();
+        admission.record(result.as_ref().err().map_or(false, Self::is_backend_failure));
+        result
+    }
+}
+
+/// Records the outcome of an admitted request. If the request panics, the guard is dropped
+/// without an outcome and records a backend failure. Otherwise, a panicking probe would leave
+/// the breaker half open, and all later requests would fail fast forever.
+struct Admission<'a> {
+    breaker: Option<&'a CircuitBreaker>,
+}
+
+impl Admission<'_> {
+    fn record(mut self, backend_failure: bool) {
+        if let Some(breaker) = self.breaker.take() {
+            breaker.record(backend_failure);
+        }
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if let Some(breaker) = self.breaker.take() {
+            breaker.record(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    const COOLDOWN: Duration = Duration::from_millis(100);
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new_with(SecurityLevel::TRUSTED_ENVIRONMENT, 3, COOLDOWN)
+    }
+
+    fn backend_failure() -> Result<()> {
+        Err(anyhow!(Error::Km(ErrorCode::SECURE_HW_COMMUNICATION_FAILED)))
+    }
+
+    fn request_failure() -> Result<()> {
+        Err(anyhow!(Error::Km(ErrorCode::INVALID_ARGUMENT)))
+    }
+
+    fn is_fast_fail<T>(result: Result<T>) -> bool {
+        matches!(
+            result.map(|_| ()).unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(Error::Rc(ResponseCode::BACKEND_BUSY))
+        )
+    }
+
+    #[test]
+    fn test_breaker_opens_after_threshold() {
+        let cb = breaker();
+        assert!(cb.call(backend_failure).is_err());
+        assert!(cb.call(backend_failure).is_err());
+        assert_eq!(*cb.state.lock().unwrap(), State::Closed { consecutive_failures: 2 });
+        assert!(cb.call(backend_failure).is_err());
+        assert!(matches!(*cb.state.lock().unwrap(), State::Open { .. }));
+
+        let mut called = false;
+        assert!(is_fast_fail(cb.call(|| {
+            called = true;
+            Ok(())
+        })));
+        assert!(!called);
+    }
+
+    #[test]
+    fn test_request_errors_and_successes_reset_count() {
+        let cb = breaker();
+        assert!(cb.call(backend_failure).is_err());
+        assert!(cb.call(backend_failure).is_err());
+        assert!(cb.call(request_failure).is_err());
+        assert_eq!(*cb.state.lock().unwrap(), State::Closed { consecutive_failures: 0 });
+
+        assert!(cb.call(backend_failure).is_err());
+        assert!(cb.call(backend_failure).is_err());
+        assert!(cb.call(|| Ok(())).is_ok());
+        assert_eq!(*cb.state.lock().unwrap(), State::Closed { consecutive_failures: 0 });
+    }
+
+    #[test]
+    fn test_breaker_recovers_after_cooldown() {
+        let cb = breaker();
+        for _ in 0..3 {
+            assert!(cb.call(backend_failure).is_err());
+        }
+        assert!(is_fast_fail(cb.call(|| Ok(()))));
+
+        std::thread::sleep(COOLDOWN);
+        // The probe fails, so the breaker reopens.
+        assert!(!is_fast_fail(cb.call(backend_failure)));
+        assert!(is_fast_fail(cb.call(|| Ok(()))));
+
+        std::thread::sleep(COOLDOWN);
+        // The probe succeeds, so the breaker closes.
+        assert!(cb.call(|| Ok(())).is_ok());
+        assert_eq!(*cb.state.lock().unwrap(), State::Closed { consecutive_failures: 0 });
+        assert!(cb.call(|| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn test_only_one_probe_while_half_open() {
+        let cb = breaker();
+        for _ in 0..3 {
+            assert!(cb.call(backend_failure).is_err());
+        }
+        std::thread::sleep(COOLDOWN);
+        let result = cb.call(|| {
+            // While the probe is in flight, other requests fail fast.
+            assert!(is_fast_fail(cb.call(|| Ok(()))));
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(*cb.state.lock().unwrap(), State::Closed { consecutive_failures: 0 });
+    }
+
+    #[test]
+    fn test_panicking_probe_reopens_breaker() {
+        let cb = breaker();
+        for _ in 0..3 {
+            assert!(cb.call(backend_failure).is_err());
+        }
+        std::thread::sleep(COOLDOWN);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cb.call(|| -> Result<()> { panic!("probe panicked") })
+        }));
+        assert!(result.is_err());
+        assert!(matches!(*cb.state.lock().unwrap(), State::Open { .. }));
+
+        std::thread::sleep(COOLDOWN);
+        assert!(cb.call(|| Ok(())).is_ok());
+        assert_eq!(*cb.state.lock().unwrap(), State::Closed { consecutive_failures: 0 });
+    }
+
+    #[test]
+    fn test_zero_threshold_disables_breaker() {
+        let cb = CircuitBreaker::new_with(SecurityLevel::TRUSTED_ENVIRONMENT, 0, COOLDOWN);
+        for _ in 0..10 {
+            assert!(!is_fast_fail(cb.call(backend_failure)));
+        }
+    }
+}
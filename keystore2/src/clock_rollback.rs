@@ -0,0 +1,199 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the detection of wall clock rollbacks.
+//!
+//! Features that compare the wall clock with stored timestamps, e.g., the idempotency window of
+//! key generation, can be extended indefinitely by setting the clock back. The detector records
+//! the wall clock time together with the monotonic time whenever it is consulted. Since the
+//! monotonic clock cannot be set, the wall clock must advance at least as much as the monotonic
+//! clock between two observations. If it falls behind by more than a tolerance, the wall clock
+//! was set back, and the clock is considered suspicious for a while. Features may then refuse
+//! to honor time windows that the rollback would extend.
+
+use crate::database::DateTime;
+use crate::sysprop::read_prop_bool;
+use crate::utils::get_current_time_in_milliseconds;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+/// If true, features that would otherwise extend a time window based on the wall clock refuse
+/// to do so while the clock is suspicious.
+const REFUSE_WHILE_SUSPICIOUS_PROPERTY: &str = "keystore.refuse_expiry_extension_on_clock_rollback";
+
+/// The wall clock may fall behind the monotonic clock by this much without being considered
+/// rolled back, to allow for small corrections, e.g., by NTP.
+const ROLLBACK_TOLERANCE_MILLIS: i64 = 60 * 1000;
+
+/// How long the clock is considered suspicious after a rollback, in monotonic time.
+const SUSPICION_PERIOD_MILLIS: i64 = 60 * 60 * 1000;
+
+lazy_static! {
+    /// The detector of wall clock rollbacks of the keystore process.
+    pub static ref CLOCK_ROLLBACK_DETECTOR: ClockRollbackDetector =
+        ClockRollbackDetector::new(Box::new(SystemClock));
+}
+
+/// A source of the current wall clock and monotonic time, both in milliseconds.
+pub trait Clock: Send + Sync {
+    /// Returns the wall clock time in milliseconds since the epoch.
+    fn wall_millis(&self) -> i64;
+    /// Returns the monotonic time in milliseconds.
+    fn monotonic_millis(&self) -> i64;
+}
+
+/// The clocks of the system.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn wall_millis(&self) -> i64 {
+        DateTime::now().map_or(0, |now| now.to_millis_epoch())
+    }
+
+    fn monotonic_millis(&self) -> i64 {
+        get_current_time_in_milliseconds()
+    }
+}
+
+#[derive(Debug, Default)]
+struct DetectorState {
+    /// The wall clock and monotonic time of the last observation.
+    last_seen: Option<(i64, i64)>,
+    /// The monotonic time until which the clock is suspicious.
+    suspicious_until: Option<i64>,
+}
+
+/// Detects rollbacks of the wall clock of `Clock`.
+pub struct ClockRollbackDetector {
+    clock: Box<dyn Clock>,
+    state: Mutex<DetectorState>,
+}
+
+impl ClockRollbackDetector {
+    /// Creates a detector that observes `clock`.
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        Self { clock, state: Default::default() }
+    }
+
+    /// Records the current time and returns true if the wall clock was set back within the
+    /// suspicion period.
+    pub fn is_suspicious(&self) -> bool {
+        let wall = self.clock.wall_millis();
+        let monotonic = self.clock.monotonic_millis();
+        let mut state = self.state.lock().unwrap();
+        if let Some((last_wall, last_monotonic)) = state.last_seen {
+            let expected_wall = last_wall + (monotonic - last_monotonic);
+            if wall + ROLLBACK_TOLERANCE_MILLIS < expected_wall {
+                log::warn!(
+                    "Wall clock was set back by {} ms. Treating it as suspicious.",
+                    expected_wall - wall
+                );
+                state.suspicious_until = Some(monotonic + SUSPICION_PERIOD_MILLIS);
+            }
+        }
+        state.last_seen = Some((wall, monotonic));
+        match state.suspicious_until {
+            Some(until) if monotonic < until => true,
+            Some(_) => {
+                state.suspicious_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Returns true if features should refuse to extend time windows, because the refusal is
+    /// enabled and the clock is suspicious.
+    pub fn refuse_expiry_extension(&self) -> bool {
+        // Consult the clock in any case, so that rollbacks are detected and logged even if the
+        // refusal is disabled.
+        self.is_suspicious() && read_prop_bool(REFUSE_WHILE_SUSPICIOUS_PROPERTY, false)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::Arc;
+
+    /// A clock that only moves when told to.
+    #[derive(Default)]
+    pub struct FakeClock {
+        wall: AtomicI64,
+        monotonic: AtomicI64,
+    }
+
+    impl FakeClock {
+        /// Advances both clocks by `millis`.
+        pub fn advance(&self, millis: i64) {
+            self.wall.fetch_add(millis, Ordering::SeqCst);
+            self.monotonic.fetch_add(millis, Ordering::SeqCst);
+        }
+
+        /// Moves the wall clock by `millis`, which may be negative.
+        pub fn move_wall_by(&self, millis: i64) {
+            self.wall.fetch_add(millis, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for Arc<FakeClock> {
+        fn wall_millis(&self) -> i64 {
+            self.wall.load(Ordering::SeqCst)
+        }
+
+        fn monotonic_millis(&self) -> i64 {
+            self.monotonic.load(Ordering::SeqCst)
+        }
+    }
+
+    fn detector() -> (Arc<FakeClock>, ClockRollbackDetector) {
+        let clock = Arc::new(FakeClock::default());
+        clock.move_wall_by(1_700_000_000_000);
+        (clock.clone(), ClockRollbackDetector::new(Box::new(clock)))
+    }
+
+    #[test]
+    fn test_rollback_is_detected() {
+        let (clock, detector) = detector();
+        assert!(!detector.is_suspicious());
+        clock.advance(10 * 60 * 1000);
+        assert!(!detector.is_suspicious());
+
+        clock.move_wall_by(-24 * 60 * 60 * 1000);
+        assert!(detector.is_suspicious());
+        // The clock stays suspicious for the suspicion period, even though it advances normally
+        // after the rollback.
+        clock.advance(SUSPICION_PERIOD_MILLIS - 1);
+        assert!(detector.is_suspicious());
+        clock.advance(1);
+        assert!(!detector.is_suspicious());
+    }
+
+    #[test]
+    fn test_forward_jumps_and_small_corrections_are_tolerated() {
+        let (clock, detector) = detector();
+        assert!(!detector.is_suspicious());
+        // The wall clock advances during suspend, while the monotonic clock does not.
+        clock.move_wall_by(8 * 60 * 60 * 1000);
+        assert!(!detector.is_suspicious());
+        clock.advance(1000);
+        clock.move_wall_by(-ROLLBACK_TOLERANCE_MILLIS);
+        assert!(!detector.is_suspicious());
+
+        // Each rollback is measured against the last observation.
+        clock.move_wall_by(-ROLLBACK_TOLERANCE_MILLIS - 1);
+        assert!(detector.is_suspicious());
+    }
+}
@@ -20,7 +20,9 @@
 
 #include <assert.h>
 #include <log/log.h>
+#include <openssl/aead.h>
 #include <openssl/aes.h>
+#include <openssl/bytestring.h>
 #include <openssl/ec.h>
 #include <openssl/ec_key.h>
 #include <openssl/ecdh.h>
@@ -28,7 +30,9 @@
 #include <openssl/hkdf.h>
 #include <openssl/hmac.h>
 #include <openssl/rand.h>
+#include <openssl/sha.h>
 #include <openssl/x509.h>
+#include <openssl/x509v3.h>
 
 #include <vector>
 
@@ -76,6 +80,22 @@ bool hmacSha256(const uint8_t* key, size_t key_size, const uint8_t* msg, size_t
     return (p != nullptr);
 }
 
+bool sha256Digest(const uint8_t* msg, size_t msg_size, uint8_t* out, size_t out_size) {
+    if (out_size != SHA256_DIGEST_LENGTH) {
+        return false;
+    }
+    SHA256(msg, msg_size, out);
+    return true;
+}
+
+bool sha512Digest(const uint8_t* msg, size_t msg_size, uint8_t* out, size_t out_size) {
+    if (out_size != SHA512_DIGEST_LENGTH) {
+        return false;
+    }
+    SHA512(msg, msg_size, out);
+    return true;
+}
+
 bool randomBytes(uint8_t* out, size_t len) {
     return RAND_bytes(out, len);
 }
@@ -83,10 +103,12 @@ bool randomBytes(uint8_t* out, size_t len) {
 /*
  * Encrypt 'len' data at 'in' with AES-GCM, using 128-bit or 256-bit key at 'key', 96-bit IV at
  * 'iv' and write output to 'out' (which may be the same location as 'in') and 128-bit tag to
- * 'tag'.
+ * 'tag'. The 'aad_len' bytes at 'aad' are authenticated as additional data. 'aad' may be null
+ * if 'aad_len' is 0.
  */
 bool AES_gcm_encrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t* key,
-                     size_t key_size, const uint8_t* iv, uint8_t* tag) {
+                     size_t key_size, const uint8_t* iv, const uint8_t* aad, size_t aad_len,
+                     uint8_t* tag) {
 
     // There can be 128-bit and 256-bit keys
     const EVP_CIPHER* cipher = getAesCipherForKey(key_size);
@@ -100,6 +122,10 @@ bool AES_gcm_encrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t*
     uint8_t* out_pos = out_tmp.data();
     int out_len;
 
+    if (aad_len > 0 && !EVP_EncryptUpdate(ctx.get(), nullptr, &out_len, aad, aad_len)) {
+        ALOGD("Failed to add additional authenticated data");
+        return false;
+    }
     EVP_EncryptUpdate(ctx.get(), out_pos, &out_len, in, len);
     out_pos += out_len;
     EVP_EncryptFinal_ex(ctx.get(), out_pos, &out_len);
@@ -119,10 +145,12 @@ bool AES_gcm_encrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t*
 /*
  * Decrypt 'len' data at 'in' with AES-GCM, using 128-bit or 256-bit key at 'key', 96-bit IV at
  * 'iv', checking 128-bit tag at 'tag' and writing plaintext to 'out'(which may be the same
- * location as 'in').
+ * location as 'in'). The 'aad_len' bytes at 'aad' must match the additional data that was
+ * authenticated during encryption. 'aad' may be null if 'aad_len' is 0.
  */
 bool AES_gcm_decrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t* key,
-                     size_t key_size, const uint8_t* iv, const uint8_t* tag) {
+                     size_t key_size, const uint8_t* iv, const uint8_t* aad, size_t aad_len,
+                     const uint8_t* tag) {
 
     // There can be 128-bit and 256-bit keys
     const EVP_CIPHER* cipher = getAesCipherForKey(key_size);
@@ -138,6 +166,10 @@ bool AES_gcm_decrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t*
     uint8_t* out_pos = out_tmp.data();
     int out_len;
 
+    if (aad_len > 0 && !EVP_DecryptUpdate(ctx.get(), nullptr, &out_len, aad, aad_len)) {
+        ALOGE("Failed to add additional authenticated data");
+        return false;
+    }
     EVP_DecryptUpdate(ctx.get(), out_pos, &out_len, in, len);
     out_pos += out_len;
     if (!EVP_DecryptFinal_ex(ctx.get(), out_pos, &out_len)) {
@@ -156,6 +188,82 @@ bool AES_gcm_decrypt(const uint8_t* in, uint8_t* out, size_t len, const uint8_t*
     return true;
 }
 
+/**
+ * Returns the EVP_AEAD for one of the AEAD_* constants, or nullptr if the constant is unknown.
+ */
+static const EVP_AEAD* getAead(int aead) {
+    switch (aead) {
+    case AEAD_AES_256_GCM_SIV:
+        return EVP_aead_aes_256_gcm_siv();
+    case AEAD_CHACHA20_POLY1305:
+        return EVP_aead_chacha20_poly1305();
+    default:
+        return nullptr;
+    }
+}
+
+/*
+ * Encrypt 'len' data at 'in' with the AEAD scheme 'aead', one of the AEAD_* constants, using
+ * the key at 'key' and the nonce at 'nonce', and write output to 'out' (which must either be
+ * 'in' or not overlap with it) and the tag of 'tag_size' bytes to 'tag'. The 'aad_len' bytes
+ * at 'aad' are authenticated as additional data. 'aad' may be null if 'aad_len' is 0.
+ */
+bool AEAD_seal(int aead, const uint8_t* in, uint8_t* out, size_t len, const uint8_t* key,
+               size_t key_size, const uint8_t* nonce, size_t nonce_size, const uint8_t* aad,
+               size_t aad_len, uint8_t* tag, size_t tag_size) {
+    const EVP_AEAD* evp_aead = getAead(aead);
+    if (evp_aead == nullptr) {
+        ALOGE("Unknown AEAD %d", aead);
+        return false;
+    }
+
+    EVP_AEAD_CTX ctx;
+    if (!EVP_AEAD_CTX_init(&ctx, evp_aead, key, key_size, tag_size, nullptr /* engine */)) {
+        ALOGE("Failed to initialize AEAD %d", aead);
+        return false;
+    }
+    size_t out_tag_len;
+    bool result = EVP_AEAD_CTX_seal_scatter(&ctx, out, tag, &out_tag_len, tag_size, nonce,
+                                            nonce_size, in, len, nullptr /* extra_in */, 0, aad,
+                                            aad_len) &&
+                  out_tag_len == tag_size;
+    EVP_AEAD_CTX_cleanup(&ctx);
+    if (!result) {
+        ALOGE("Failed to encrypt with AEAD %d", aead);
+    }
+    return result;
+}
+
+/*
+ * Decrypt 'len' data at 'in' with the AEAD scheme 'aead', one of the AEAD_* constants, using
+ * the key at 'key' and the nonce at 'nonce', checking the tag of 'tag_size' bytes at 'tag' and
+ * writing plaintext to 'out' (which must either be 'in' or not overlap with it). The 'aad_len'
+ * bytes at 'aad' must match the additional data that was authenticated during encryption.
+ * 'aad' may be null if 'aad_len' is 0.
+ */
+bool AEAD_open(int aead, const uint8_t* in, uint8_t* out, size_t len, const uint8_t* key,
+               size_t key_size, const uint8_t* nonce, size_t nonce_size, const uint8_t* aad,
+               size_t aad_len, const uint8_t* tag, size_t tag_size) {
+    const EVP_AEAD* evp_aead = getAead(aead);
+    if (evp_aead == nullptr) {
+        ALOGE("Unknown AEAD %d", aead);
+        return false;
+    }
+
+    EVP_AEAD_CTX ctx;
+    if (!EVP_AEAD_CTX_init(&ctx, evp_aead, key, key_size, tag_size, nullptr /* engine */)) {
+        ALOGE("Failed to initialize AEAD %d", aead);
+        return false;
+    }
+    bool result = EVP_AEAD_CTX_open_gather(&ctx, out, nonce, nonce_size, in, len, tag, tag_size,
+                                           aad, aad_len);
+    EVP_AEAD_CTX_cleanup(&ctx);
+    if (!result) {
+        ALOGE("Failed to decrypt blob with AEAD %d; ciphertext or tag is likely corrupted", aead);
+    }
+    return result;
+}
+
 // Copied from system/security/keystore/keymaster_enforcement.cpp.
 
 class EvpMdCtx {
@@ -281,6 +389,50 @@ EC_POINT* ECPOINTOct2Point(const uint8_t* buf, size_t len) {
     return point;
 }
 
+// Upper bound on the size of a certificate handed to the X509 parser. Real certificates,
+// including attestation certificates, are at most a few KiB.
+constexpr size_t kMaxCertificateSize = 64 * 1024;
+// Upper bound on the nesting depth of constructed ASN.1 elements within a certificate.
+// X.509 certificates nest well below this.
+constexpr size_t kMaxAsn1Depth = 32;
+
+// Checks that every element in cbs is well formed DER, that no element exceeds its enclosing
+// element, and that constructed elements nest at most kMaxAsn1Depth deep.
+static bool isBoundedDer(CBS* cbs, size_t depth) {
+    if (depth > kMaxAsn1Depth) {
+        return false;
+    }
+    while (CBS_len(cbs) > 0) {
+        CBS element;
+        CBS_ASN1_TAG tag;
+        size_t header_len;
+        if (!CBS_get_any_asn1_element(cbs, &element, &tag, &header_len)) {
+            return false;
+        }
+        if ((tag & CBS_ASN1_CONSTRUCTED) &&
+            (!CBS_skip(&element, header_len) || !isBoundedDer(&element, depth + 1))) {
+            return false;
+        }
+    }
+    return true;
+}
+
+// Validates the structure of the DER encoded certificate at the start of cert_buf before it is
+// handed to the X509 parser, because certificates may come from less trusted sources.
+// Any data following the certificate, e.g., the rest of a certificate chain, is ignored.
+// On success, stores the length of the certificate in cert_len.
+static bool checkCertificateStructure(const uint8_t* cert_buf, size_t* cert_len) {
+    CBS cbs, cert;
+    CBS_init(&cbs, cert_buf, *cert_len);
+    if (!CBS_get_asn1_element(&cbs, &cert, CBS_ASN1_SEQUENCE) ||
+        CBS_len(&cert) > kMaxCertificateSize) {
+        return false;
+    }
+    *cert_len = CBS_len(&cert);
+    CBS contents = cert;
+    return isBoundedDer(&contents, 0);
+}
+
 int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint8_t* subject_buf,
                                   size_t subject_buf_len) {
     if (!cert_buf || !subject_buf) {
@@ -288,6 +440,11 @@ int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint
         return 0;
     }
 
+    if (!checkCertificateStructure(cert_buf, &cert_len)) {
+        ALOGE("extractSubjectFromCertificate: malformed or oversized certificate");
+        return 0;
+    }
+
     const uint8_t* p = cert_buf;
     bssl::UniquePtr<X509> cert(d2i_X509(nullptr /* Allocate X509 struct */, &p, cert_len));
     if (!cert) {
@@ -319,3 +476,223 @@ int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len, uint
     uint8_t* tmp = subject_buf;
     return i2d_X509_NAME(subject, &tmp);
 }
+
+size_t getCertificateLength(const uint8_t* cert_buf, size_t cert_len) {
+    if (!cert_buf) {
+        ALOGE("getCertificateLength: received null pointer");
+        return 0;
+    }
+    if (!checkCertificateStructure(cert_buf, &cert_len)) {
+        ALOGE("getCertificateLength: malformed or oversized certificate");
+        return 0;
+    }
+    return cert_len;
+}
+
+// Parses the DER encoded certificate at the start of cert_buf after checking its structure.
+static bssl::UniquePtr<X509> parseCertificate(const uint8_t* cert_buf, size_t cert_len) {
+    if (!checkCertificateStructure(cert_buf, &cert_len)) {
+        return nullptr;
+    }
+    const uint8_t* p = cert_buf;
+    return bssl::UniquePtr<X509>(d2i_X509(nullptr /* Allocate X509 struct */, &p, cert_len));
+}
+
+int checkCertificateIssuedBy(const uint8_t* cert_buf, size_t cert_len, const uint8_t* issuer_buf,
+                             size_t issuer_len) {
+    if (!cert_buf || !issuer_buf) {
+        ALOGE("checkCertificateIssuedBy: received null pointer");
+        return -1;
+    }
+
+    bssl::UniquePtr<X509> cert = parseCertificate(cert_buf, cert_len);
+    bssl::UniquePtr<X509> issuer = parseCertificate(issuer_buf, issuer_len);
+    if (!cert || !issuer) {
+        ALOGE("checkCertificateIssuedBy: failed to parse certificate");
+        return -1;
+    }
+
+    if (X509_check_issued(issuer.get(), cert.get()) != X509_V_OK) {
+        return 0;
+    }
+    bssl::UniquePtr<EVP_PKEY> issuer_key(X509_get_pubkey(issuer.get()));
+    if (!issuer_key) {
+        ALOGE("checkCertificateIssuedBy: failed to get public key of issuer");
+        return -1;
+    }
+    return X509_verify(cert.get(), issuer_key.get()) == 1 ? 1 : 0;
+}
+
+int checkCertificatePublicKeysMatch(const uint8_t* cert_buf, size_t cert_len,
+                                    const uint8_t* other_buf, size_t other_len) {
+    if (!cert_buf || !other_buf) {
+        ALOGE("checkCertificatePublicKeysMatch: received null pointer");
+        return -1;
+    }
+
+    bssl::UniquePtr<X509> cert = parseCertificate(cert_buf, cert_len);
+    bssl::UniquePtr<X509> other = parseCertificate(other_buf, other_len);
+    if (!cert || !other) {
+        ALOGE("checkCertificatePublicKeysMatch: failed to parse certificate");
+        return -1;
+    }
+
+    bssl::UniquePtr<EVP_PKEY> cert_key(X509_get_pubkey(cert.get()));
+    bssl::UniquePtr<EVP_PKEY> other_key(X509_get_pubkey(other.get()));
+    if (!cert_key || !other_key) {
+        ALOGE("checkCertificatePublicKeysMatch: failed to get public key");
+        return -1;
+    }
+    // EVP_PKEY_cmp returns negative values for keys of different types.
+    return EVP_PKEY_cmp(cert_key.get(), other_key.get()) == 1 ? 1 : 0;
+}
+
+bool getCertificateNotAfter(const uint8_t* cert_buf, size_t cert_len, int64_t* not_after) {
+    if (!cert_buf || !not_after) {
+        ALOGE("getCertificateNotAfter: received null pointer");
+        return false;
+    }
+
+    bssl::UniquePtr<X509> cert = parseCertificate(cert_buf, cert_len);
+    if (!cert) {
+        ALOGE("getCertificateNotAfter: failed to parse certificate");
+        return false;
+    }
+    if (!ASN1_TIME_to_posix(X509_get0_notAfter(cert.get()), not_after)) {
+        ALOGE("getCertificateNotAfter: malformed notAfter");
+        return false;
+    }
+    return true;
+}
+
+static const char kAttestationExtensionOid[] = "1.3.6.1.4.1.11129.2.1.17";
+static const CBS_ASN1_TAG kRootOfTrustTag = CBS_ASN1_CONTEXT_SPECIFIC | CBS_ASN1_CONSTRUCTED | 704;
+
+// Reads a non-negative DER INTEGER or ENUMERATED element with the given tag that
+// fits into an int64_t.
+static bool getAsn1NonNegative(CBS* cbs, CBS_ASN1_TAG tag, int64_t* out) {
+    CBS child;
+    if (!CBS_get_asn1(cbs, &child, tag) || CBS_len(&child) == 0) {
+        return false;
+    }
+    const uint8_t* data = CBS_data(&child);
+    size_t len = CBS_len(&child);
+    if (data[0] & 0x80) {
+        // Negative.
+        return false;
+    }
+    if (len > 1 && data[0] == 0) {
+        data++;
+        len--;
+    }
+    if (len > sizeof(int64_t) || (len == sizeof(int64_t) && (data[0] & 0x80))) {
+        return false;
+    }
+    uint64_t value = 0;
+    for (size_t i = 0; i < len; ++i) {
+        value = (value << 8) | data[i];
+    }
+    *out = static_cast<int64_t>(value);
+    return true;
+}
+
+// Looks for the RootOfTrust in the given AuthorizationList and stores it in
+// record if found. Returns false if the authorization list is malformed.
+static bool findRootOfTrust(CBS auth_list, AttestationRecord* record) {
+    while (CBS_len(&auth_list) > 0) {
+        CBS element;
+        CBS_ASN1_TAG tag;
+        if (!CBS_get_any_asn1(&auth_list, &element, &tag)) {
+            return false;
+        }
+        if (tag != kRootOfTrustTag) {
+            continue;
+        }
+        CBS root_of_trust, verified_boot_key;
+        int device_locked;
+        int64_t verified_boot_state;
+        if (!CBS_get_asn1(&element, &root_of_trust, CBS_ASN1_SEQUENCE) ||
+            !CBS_get_asn1(&root_of_trust, &verified_boot_key, CBS_ASN1_OCTETSTRING) ||
+            !CBS_get_asn1_bool(&root_of_trust, &device_locked) ||
+            !getAsn1NonNegative(&root_of_trust, CBS_ASN1_ENUMERATED, &verified_boot_state) ||
+            verified_boot_state > INT32_MAX) {
+            return false;
+        }
+        record->has_root_of_trust = true;
+        record->device_locked = device_locked != 0;
+        record->verified_boot_state = static_cast<int32_t>(verified_boot_state);
+        return true;
+    }
+    return true;
+}
+
+bool extractAttestationRecord(const uint8_t* cert_buf, size_t cert_len,
+                              AttestationRecord* record) {
+    if (!cert_buf || !record) {
+        ALOGE("extractAttestationRecord: received null pointer");
+        return false;
+    }
+
+    if (!checkCertificateStructure(cert_buf, &cert_len)) {
+        ALOGE("extractAttestationRecord: malformed or oversized certificate");
+        return false;
+    }
+
+    const uint8_t* p = cert_buf;
+    bssl::UniquePtr<X509> cert(d2i_X509(nullptr /* Allocate X509 struct */, &p, cert_len));
+    if (!cert) {
+        ALOGE("extractAttestationRecord: failed to parse certificate");
+        return false;
+    }
+
+    bssl::UniquePtr<ASN1_OBJECT> oid(
+        OBJ_txt2obj(kAttestationExtensionOid, 1 /* Only accept numerical form */));
+    if (!oid) {
+        ALOGE("extractAttestationRecord: failed to create OID");
+        return false;
+    }
+    int location = X509_get_ext_by_OBJ(cert.get(), oid.get(), -1 /* Search from the start */);
+    if (location < 0) {
+        ALOGE("extractAttestationRecord: certificate has no attestation extension");
+        return false;
+    }
+    ASN1_OCTET_STRING* ext_data = X509_EXTENSION_get_data(X509_get_ext(cert.get(), location));
+
+    CBS ext, key_description, challenge, unique_id, sw_enforced, hw_enforced;
+    CBS_init(&ext, ASN1_STRING_get0_data(ext_data), ASN1_STRING_length(ext_data));
+    int64_t attestation_version, attestation_security_level, keymint_version,
+        keymint_security_level;
+    if (!CBS_get_asn1(&ext, &key_description, CBS_ASN1_SEQUENCE) || CBS_len(&ext) != 0 ||
+        !getAsn1NonNegative(&key_description, CBS_ASN1_INTEGER, &attestation_version) ||
+        !getAsn1NonNegative(&key_description, CBS_ASN1_ENUMERATED, &attestation_security_level) ||
+        !getAsn1NonNegative(&key_description, CBS_ASN1_INTEGER, &keymint_version) ||
+        !getAsn1NonNegative(&key_description, CBS_ASN1_ENUMERATED, &keymint_security_level) ||
+        !CBS_get_asn1(&key_description, &challenge, CBS_ASN1_OCTETSTRING) ||
+        !CBS_get_asn1(&key_description, &unique_id, CBS_ASN1_OCTETSTRING) ||
+        !CBS_get_asn1(&key_description, &sw_enforced, CBS_ASN1_SEQUENCE) ||
+        !CBS_get_asn1(&key_description, &hw_enforced, CBS_ASN1_SEQUENCE) ||
+        attestation_security_level > INT32_MAX || keymint_security_level > INT32_MAX) {
+        ALOGE("extractAttestationRecord: malformed attestation extension");
+        return false;
+    }
+    if (CBS_len(&challenge) > ATTESTATION_CHALLENGE_MAX_SIZE) {
+        ALOGE("extractAttestationRecord: attestation challenge too long (%zu bytes)",
+              CBS_len(&challenge));
+        return false;
+    }
+
+    *record = {};
+    record->attestation_version = attestation_version;
+    record->attestation_security_level = static_cast<int32_t>(attestation_security_level);
+    record->keymint_version = keymint_version;
+    record->keymint_security_level = static_cast<int32_t>(keymint_security_level);
+    memcpy(record->attestation_challenge, CBS_data(&challenge), CBS_len(&challenge));
+    record->attestation_challenge_len = CBS_len(&challenge);
+
+    if (!findRootOfTrust(hw_enforced, record) ||
+        (!record->has_root_of_trust && !findRootOfTrust(sw_enforced, record))) {
+        ALOGE("extractAttestationRecord: malformed authorization list");
+        return false;
+    }
+    return true;
+}
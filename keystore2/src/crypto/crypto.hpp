@@ -24,12 +24,28 @@
 extern "C" {
   bool hmacSha256(const uint8_t* key, size_t key_size, const uint8_t* msg, size_t msg_size,
                   uint8_t* out, size_t out_size);
+  bool sha256Digest(const uint8_t* msg, size_t msg_size, uint8_t* out, size_t out_size);
+  bool sha512Digest(const uint8_t* msg, size_t msg_size, uint8_t* out, size_t out_size);
   bool randomBytes(uint8_t* out, size_t len);
   bool AES_gcm_encrypt(const uint8_t* in, uint8_t* out, size_t len,
-                       const uint8_t* key, size_t key_size, const uint8_t* iv, uint8_t* tag);
+                       const uint8_t* key, size_t key_size, const uint8_t* iv,
+                       const uint8_t* aad, size_t aad_len, uint8_t* tag);
   bool AES_gcm_decrypt(const uint8_t* in, uint8_t* out, size_t len,
                        const uint8_t* key, size_t key_size, const uint8_t* iv,
-                       const uint8_t* tag);
+                       const uint8_t* aad, size_t aad_len, const uint8_t* tag);
+
+  // AEAD schemes supported by AEAD_seal and AEAD_open.
+  static const int AEAD_AES_256_GCM_SIV = 1;
+  static const int AEAD_CHACHA20_POLY1305 = 2;
+
+  bool AEAD_seal(int aead, const uint8_t* in, uint8_t* out, size_t len,
+                 const uint8_t* key, size_t key_size, const uint8_t* nonce,
+                 size_t nonce_size, const uint8_t* aad, size_t aad_len,
+                 uint8_t* tag, size_t tag_size);
+  bool AEAD_open(int aead, const uint8_t* in, uint8_t* out, size_t len,
+                 const uint8_t* key, size_t key_size, const uint8_t* nonce,
+                 size_t nonce_size, const uint8_t* aad, size_t aad_len,
+                 const uint8_t* tag, size_t tag_size);
 
   // Copied from system/security/keystore/keymaster_enforcement.h.
   typedef uint64_t km_id_t;
@@ -81,7 +97,9 @@ extern "C" {
 // subject_buf; the operation was successful.
 //
 // If the return value == 0, certificate parsing failed unrecoverably.  The
-// reason will be logged.
+// reason will be logged.  Certificates larger than 64 KiB or with ASN.1
+// elements nested more than 32 levels deep are rejected this way.  Data
+// following the first certificate in cert_buf is ignored.
 //
 // If the return value < 0, the operation failed because the subject size >
 // subject_buf_len.  The return value is -(subject_size), where subject_size is
@@ -90,4 +108,59 @@ extern "C" {
 int extractSubjectFromCertificate(const uint8_t* cert_buf, size_t cert_len,
                                   uint8_t* subject_buf, size_t subject_buf_len);
 
+// Returns the length of the DER-encoded X.509 certificate at the start of
+// cert_buf, which holds cert_len bytes, e.g., of a certificate chain. Returns 0
+// if the certificate is malformed or subject to the limits described for
+// extractSubjectFromCertificate.
+size_t getCertificateLength(const uint8_t* cert_buf, size_t cert_len);
+
+// Checks whether the DER-encoded X.509 certificate in cert_buf was issued by
+// the certificate in issuer_buf, i.e., that the issuer name of the former
+// matches the subject of the latter and that the former is signed with the key
+// of the latter. Returns 1 if so, 0 if not, and -1 if either certificate cannot
+// be parsed.
+int checkCertificateIssuedBy(const uint8_t* cert_buf, size_t cert_len, const uint8_t* issuer_buf,
+                             size_t issuer_len);
+
+// Checks whether the DER-encoded X.509 certificates in cert_buf and other_buf
+// certify the same public key. Returns 1 if so, 0 if not, and -1 if either
+// certificate cannot be parsed.
+int checkCertificatePublicKeysMatch(const uint8_t* cert_buf, size_t cert_len,
+                                    const uint8_t* other_buf, size_t other_len);
+
+// Stores the notAfter time of the DER-encoded X.509 certificate in cert_buf in
+// not_after, in seconds since the epoch. Returns false if the certificate
+// cannot be parsed.
+bool getCertificateNotAfter(const uint8_t* cert_buf, size_t cert_len, int64_t* not_after);
+
+// The largest attestation challenge accepted by KeyMint.
+static const size_t ATTESTATION_CHALLENGE_MAX_SIZE = 128;
+
+// The fields of the KeyMint attestation extension (OID 1.3.6.1.4.1.11129.2.1.17)
+// decoded by extractAttestationRecord. See the KeyDescription schema in
+// IKeyMintDevice.aidl. Security levels and the verified boot state are the raw
+// ENUMERATED values.
+typedef struct {
+    int64_t attestation_version;
+    int32_t attestation_security_level;
+    int64_t keymint_version;
+    int32_t keymint_security_level;
+    uint8_t attestation_challenge[ATTESTATION_CHALLENGE_MAX_SIZE];
+    size_t attestation_challenge_len;
+    // The following fields are only valid if has_root_of_trust is true. The root
+    // of trust is taken from the hardware enforced authorization list, or from
+    // the software enforced list if the former has none.
+    bool has_root_of_trust;
+    bool device_locked;
+    int32_t verified_boot_state;
+} AttestationRecord;
+
+// Parse a DER-encoded X.509 certificate contained in cert_buf, with length
+// cert_len, and decode its attestation extension into record.
+//
+// Returns false if the certificate cannot be parsed, has no attestation
+// extension, or the extension is malformed. The reason will be logged.
+bool extractAttestationRecord(const uint8_t* cert_buf, size_t cert_len,
+                              AttestationRecord* record);
+
 #endif  //  __CRYPTO_H__
@@ -95,10 +95,30 @@ pub enum Error {
     #[error("Failed to extract certificate subject.")]
     ExtractSubjectFailed,
 
+    /// This is returned if the C implementation of extractAttestationRecord failed.
+    #[error("Failed to extract attestation record.")]
+    ExtractAttestationRecordFailed,
+
+    /// This is returned if a certificate could not be parsed.
+    #[error("Failed to parse certificate.")]
+    ParseCertificateFailed,
+
+    /// This is returned if a PEM encoded certificate chain could not be parsed.
+    #[error("Failed to parse PEM.")]
+    ParsePemFailed,
+
     /// This is returned if the C implementation of hmacSha256 failed.
     #[error("Failed to calculate HMAC-SHA256.")]
     HmacSha256Failed,
 
+    /// This is returned if the C implementation of sha256Digest failed.
+    #[error("Failed to calculate SHA-256.")]
+    Sha256Failed,
+
+    /// This is returned if the C implementation of sha512Digest failed.
+    #[error("Failed to calculate SHA-512.")]
+    Sha512Failed,
+
     /// Zvec error.
     #[error(transparent)]
     ZVec(#[from] zvec::Error),
@@ -19,10 +19,14 @@ mod error;
 pub mod zvec;
 pub use error::Error;
 use keystore2_crypto_bindgen::{
-    extractSubjectFromCertificate, generateKeyFromPassword, hmacSha256, randomBytes,
-    AES_gcm_decrypt, AES_gcm_encrypt, ECDHComputeKey, ECKEYGenerateKey, ECKEYMarshalPrivateKey,
-    ECKEYParsePrivateKey, ECPOINTOct2Point, ECPOINTPoint2Oct, EC_KEY_free, EC_KEY_get0_public_key,
-    EC_POINT_free, HKDFExpand, HKDFExtract, EC_KEY, EC_MAX_BYTES, EC_POINT, EVP_MAX_MD_SIZE,
+    checkCertificateIssuedBy, checkCertificatePublicKeysMatch, extractAttestationRecord,
+    extractSubjectFromCertificate, generateKeyFromPassword, getCertificateLength,
+    getCertificateNotAfter, hmacSha256, randomBytes, sha256Digest, sha512Digest, AEAD_open,
+    AEAD_seal, AES_gcm_decrypt, AES_gcm_encrypt, AttestationRecord as CAttestationRecord,
+    ECDHComputeKey, ECKEYGenerateKey, ECKEYMarshalPrivateKey, ECKEYParsePrivateKey,
+    ECPOINTOct2Point, ECPOINTPoint2Oct, EC_KEY_free, EC_KEY_get0_public_key, EC_POINT_free,
+    HKDFExpand, HKDFExtract, AEAD_AES_256_GCM_SIV, AEAD_CHACHA20_POLY1305, EC_KEY, EC_MAX_BYTES,
+    EC_POINT, EVP_MAX_MD_SIZE,
 };
 use std::convert::TryFrom;
 use std::convert::TryInto;
@@ -41,6 +45,10 @@ pub const AES_128_KEY_LENGTH: usize = 16;
 pub const SALT_LENGTH: usize = 16;
 /// Length of an HMAC-SHA256 tag in bytes.
 pub const HMAC_SHA256_LEN: usize = 32;
+/// Length of a SHA-256 digest in bytes.
+pub const SHA256_LEN: usize = 32;
+/// Length of a SHA-512 digest in bytes.
+pub const SHA512_LEN: usize = 64;
 
 /// Older versions of keystore produced IVs with four extra
 /// ignored zero bytes at the end; recognise and trim those.
@@ -89,6 +97,30 @@ pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> Result<Vec<u8>, Error> {
     }
 }
 
+/// Compute the SHA-256 digest of `msg`.
+pub fn sha256(msg: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut digest = vec![0; SHA256_LEN];
+    // Safety: The first pair of arguments must point to a const buffer with size given by the
+    // second arg of the pair. The final pair of arguments must point to an output buffer with
+    // size given by the second arg of the pair.
+    match unsafe { sha256Digest(msg.as_ptr(), msg.len(), digest.as_mut_ptr(), digest.len()) } {
+        true => Ok(digest),
+        false => Err(Error::Sha256Failed),
+    }
+}
+
+/// Compute the SHA-512 digest of `msg`.
+pub fn sha512(msg: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut digest = vec![0; SHA512_LEN];
+    // Safety: The first pair of arguments must point to a const buffer with size given by the
+    // second arg of the pair. The final pair of arguments must point to an output buffer with
+    // size given by the second arg of the pair.
+    match unsafe { sha512Digest(msg.as_ptr(), msg.len(), digest.as_mut_ptr(), digest.len()) } {
+        true => Ok(digest),
+        false => Err(Error::Sha512Failed),
+    }
+}
+
 /// Uses AES GCM to decipher a message given an initialization vector, aead tag, and key.
 /// This function accepts 128 and 256-bit keys and uses AES128 and AES256 respectively based
 /// on the key length.
@@ -97,6 +129,18 @@ pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> Result<Vec<u8>, Error> {
 /// freed. Input key is taken as a slice for flexibility, but it is recommended that it is held
 /// in a ZVec as well.
 pub fn aes_gcm_decrypt(data: &[u8], iv: &[u8], tag: &[u8], key: &[u8]) -> Result<ZVec, Error> {
+    aes_gcm_decrypt_with_aad(data, iv, tag, key, &[])
+}
+
+/// Like `aes_gcm_decrypt`, but also authenticates the additional data `aad`, which must match
+/// the additional data given to `aes_gcm_encrypt_with_aad`.
+pub fn aes_gcm_decrypt_with_aad(
+    data: &[u8],
+    iv: &[u8],
+    tag: &[u8],
+    key: &[u8],
+    aad: &[u8],
+) -> Result<ZVec, Error> {
     // Old versions of aes_gcm_encrypt produced 16 byte IVs, but the last four bytes were ignored
     // so trim these to the correct size.
     let iv = match iv.len() {
@@ -116,7 +160,8 @@ pub fn aes_gcm_decrypt(data: &[u8], iv: &[u8], tag: &[u8], key: &[u8]) -> Result
     let mut result = ZVec::new(data.len())?;
 
     // Safety: The first two arguments must point to buffers with a size given by the third
-    // argument. We pass the length of the key buffer along with the key.
+    // argument. We pass the length of the key buffer along with the key, and the length of
+    // the `aad` buffer along with `aad`.
     // The `iv` buffer must be 12 bytes and the `tag` buffer 16, which we check above.
     match unsafe {
         AES_gcm_decrypt(
@@ -126,6 +171,8 @@ pub fn aes_gcm_decrypt(data: &[u8], iv: &[u8], tag: &[u8], key: &[u8]) -> Result
             key.as_ptr(),
             key.len(),
             iv.as_ptr(),
+            aad.as_ptr(),
+            aad.len(),
             tag.as_ptr(),
         )
     } {
@@ -139,11 +186,35 @@ pub fn aes_gcm_decrypt(data: &[u8], iv: &[u8], tag: &[u8], key: &[u8]) -> Result
 /// the key length. The function generates an initialization vector. The return value is a tuple
 /// of `(ciphertext, iv, tag)`.
 pub fn aes_gcm_encrypt(plaintext: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Error> {
+    aes_gcm_encrypt_with_aad(plaintext, key, &[])
+}
+
+/// Like `aes_gcm_encrypt`, but also authenticates the additional data `aad`. The ciphertext
+/// can only be decrypted with `aes_gcm_decrypt_with_aad` given the same additional data.
+pub fn aes_gcm_encrypt_with_aad(
+    plaintext: &[u8],
+    key: &[u8],
+    aad: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Error> {
     let mut iv = vec![0; GCM_IV_LENGTH];
     // Safety: iv is GCM_IV_LENGTH bytes long.
     if !unsafe { randomBytes(iv.as_mut_ptr(), GCM_IV_LENGTH) } {
         return Err(Error::RandomNumberGenerationFailed);
     }
+    aes_gcm_encrypt_with_iv(plaintext, key, aad, iv)
+}
+
+/// Like `aes_gcm_encrypt_with_aad`, but uses the given initialization vector instead of
+/// generating one. The caller must ensure that an IV is never used twice with the same key.
+pub fn aes_gcm_encrypt_with_iv(
+    plaintext: &[u8],
+    key: &[u8],
+    aad: &[u8],
+    iv: Vec<u8>,
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Error> {
+    if iv.len() != GCM_IV_LENGTH {
+        return Err(Error::InvalidIvLength);
+    }
 
     match key.len() {
         AES_128_KEY_LENGTH | AES_256_KEY_LENGTH => {}
@@ -153,7 +224,8 @@ pub fn aes_gcm_encrypt(plaintext: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>
     let mut ciphertext: Vec<u8> = vec![0; plaintext.len()];
     let mut tag: Vec<u8> = vec![0; TAG_LENGTH];
     // Safety: The first two arguments must point to buffers with a size given by the third
-    // argument. We pass the length of the key buffer along with the key.
+    // argument. We pass the length of the key buffer along with the key, and the length of
+    // the `aad` buffer along with `aad`.
     // The `iv` buffer must be 12 bytes and the `tag` buffer 16, which we check above.
     if unsafe {
         AES_gcm_encrypt(
@@ -163,6 +235,8 @@ pub fn aes_gcm_encrypt(plaintext: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>
             key.as_ptr(),
             key.len(),
             iv.as_ptr(),
+            aad.as_ptr(),
+            aad.len(),
             tag.as_mut_ptr(),
         )
     } {
@@ -172,6 +246,131 @@ pub fn aes_gcm_encrypt(plaintext: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>
     }
 }
 
+/// Length of the nonce used with the schemes of `Aead`.
+pub const AEAD_NONCE_LENGTH: usize = 12;
+
+/// Authenticated encryption schemes besides AES GCM. All of them take 256-bit keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aead {
+    /// AES-256-GCM-SIV, which is resistant to nonce reuse.
+    Aes256GcmSiv,
+    /// ChaCha20-Poly1305, which is fast on devices without AES hardware acceleration.
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    fn to_c(self) -> i32 {
+        match self {
+            Self::Aes256GcmSiv => AEAD_AES_256_GCM_SIV,
+            Self::ChaCha20Poly1305 => AEAD_CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// Uses `aead` to encrypt a message given a 256-bit key, and authenticates the additional data
+/// `aad`. The function generates a nonce. The return value is a tuple of
+/// `(ciphertext, nonce, tag)`.
+pub fn aead_encrypt_with_aad(
+    aead: Aead,
+    plaintext: &[u8],
+    key: &[u8],
+    aad: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Error> {
+    let mut nonce = vec![0; AEAD_NONCE_LENGTH];
+    // Safety: nonce is AEAD_NONCE_LENGTH bytes long.
+    if !unsafe { randomBytes(nonce.as_mut_ptr(), AEAD_NONCE_LENGTH) } {
+        return Err(Error::RandomNumberGenerationFailed);
+    }
+    aead_encrypt_with_nonce(aead, plaintext, key, aad, nonce)
+}
+
+/// Like `aead_encrypt_with_aad`, but uses the given nonce instead of generating one. The caller
+/// must ensure that a nonce is never used twice with the same key.
+pub fn aead_encrypt_with_nonce(
+    aead: Aead,
+    plaintext: &[u8],
+    key: &[u8],
+    aad: &[u8],
+    nonce: Vec<u8>,
+) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Error> {
+    if key.len() != AES_256_KEY_LENGTH {
+        return Err(Error::InvalidKeyLength);
+    }
+    if nonce.len() != AEAD_NONCE_LENGTH {
+        return Err(Error::InvalidIvLength);
+    }
+
+    let mut ciphertext: Vec<u8> = vec![0; plaintext.len()];
+    let mut tag: Vec<u8> = vec![0; TAG_LENGTH];
+    // Safety: The first two arguments must point to buffers with a size given by the third
+    // argument, and each other buffer is passed along with its length.
+    if unsafe {
+        AEAD_seal(
+            aead.to_c(),
+            plaintext.as_ptr(),
+            ciphertext.as_mut_ptr(),
+            plaintext.len(),
+            key.as_ptr(),
+            key.len(),
+            nonce.as_ptr(),
+            nonce.len(),
+            aad.as_ptr(),
+            aad.len(),
+            tag.as_mut_ptr(),
+            tag.len(),
+        )
+    } {
+        Ok((ciphertext, nonce, tag))
+    } else {
+        Err(Error::EncryptionFailed)
+    }
+}
+
+/// Uses `aead` to decipher a message given a nonce, aead tag, 256-bit key, and the additional
+/// data `aad` that was given to `aead_encrypt_with_aad`. Like `aes_gcm_decrypt`, this function
+/// returns the plaintext in a ZVec.
+pub fn aead_decrypt_with_aad(
+    aead: Aead,
+    data: &[u8],
+    nonce: &[u8],
+    tag: &[u8],
+    key: &[u8],
+    aad: &[u8],
+) -> Result<ZVec, Error> {
+    if nonce.len() != AEAD_NONCE_LENGTH {
+        return Err(Error::InvalidIvLength);
+    }
+    if tag.len() != TAG_LENGTH {
+        return Err(Error::InvalidAeadTagLength);
+    }
+    if key.len() != AES_256_KEY_LENGTH {
+        return Err(Error::InvalidKeyLength);
+    }
+
+    let mut result = ZVec::new(data.len())?;
+    // Safety: The first two arguments must point to buffers with a size given by the third
+    // argument, and each other buffer is passed along with its length.
+    match unsafe {
+        AEAD_open(
+            aead.to_c(),
+            data.as_ptr(),
+            result.as_mut_ptr(),
+            data.len(),
+            key.as_ptr(),
+            key.len(),
+            nonce.as_ptr(),
+            nonce.len(),
+            aad.as_ptr(),
+            aad.len(),
+            tag.as_ptr(),
+            tag.len(),
+        )
+    } {
+        true => Ok(result),
+        false => Err(Error::DecryptionFailed),
+    }
+}
+
 /// Represents a "password" that can be used to key the PBKDF2 algorithm.
 pub enum Password<'a> {
     /// Borrow an existing byte array
@@ -418,6 +617,10 @@ pub fn ec_point_oct_to_point(buf: &[u8]) -> Result<OwnedECPoint, Error> {
 }
 
 /// Uses BoringSSL to extract the DER-encoded subject from a DER-encoded X.509 certificate.
+/// Before parsing, the DER structure of the certificate is validated, and certificates larger
+/// than 64 KiB or nested more than 32 levels deep are rejected, because certificates may come
+/// from less trusted sources. Data following the certificate, e.g., the rest of a certificate
+/// chain, is ignored.
 pub fn parse_subject_from_certificate(cert_buf: &[u8]) -> Result<Vec<u8>, Error> {
     // Try with a 200-byte output buffer, should be enough in all but bizarre cases.
     let mut retval = vec![0; 200];
@@ -465,6 +668,232 @@ pub fn parse_subject_from_certificate(cert_buf: &[u8]) -> Result<Vec<u8>, Error>
     Ok(retval)
 }
 
+/// Splits a certificate chain, i.e., a concatenation of DER-encoded X.509 certificates, into
+/// the individual certificates. Each certificate is subject to the same structural checks as in
+/// `parse_subject_from_certificate`.
+pub fn split_certificate_chain(chain: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let mut certs = vec![];
+    let mut rest = chain;
+    while !rest.is_empty() {
+        // Safety: getCertificateLength reads at most rest.len() bytes from rest.
+        let len = unsafe { getCertificateLength(rest.as_ptr(), rest.len()) };
+        if len == 0 || len > rest.len() {
+            return Err(Error::ParseCertificateFailed);
+        }
+        let (cert, tail) = rest.split_at(len);
+        certs.push(cert);
+        rest = tail;
+    }
+    Ok(certs)
+}
+
+/// Returns true if the DER-encoded X.509 certificate `cert` was issued by `issuer`, i.e., if its
+/// issuer name matches the subject of `issuer` and it is signed with the key of `issuer`.
+pub fn is_certificate_issued_by(cert: &[u8], issuer: &[u8]) -> Result<bool, Error> {
+    // Safety: checkCertificateIssuedBy reads at most cert.len() bytes from cert and at most
+    // issuer.len() bytes from issuer.
+    match unsafe {
+        checkCertificateIssuedBy(cert.as_ptr(), cert.len(), issuer.as_ptr(), issuer.len())
+    } {
+        1 => Ok(true),
+        0 => Ok(false),
+        _ => Err(Error::ParseCertificateFailed),
+    }
+}
+
+/// Returns true if the DER-encoded X.509 certificates `cert` and `other` certify the same public
+/// key. Keys of different types never match.
+pub fn certificate_public_keys_match(cert: &[u8], other: &[u8]) -> Result<bool, Error> {
+    // Safety: checkCertificatePublicKeysMatch reads at most cert.len() bytes from cert and at
+    // most other.len() bytes from other.
+    match unsafe {
+        checkCertificatePublicKeysMatch(cert.as_ptr(), cert.len(), other.as_ptr(), other.len())
+    } {
+        1 => Ok(true),
+        0 => Ok(false),
+        _ => Err(Error::ParseCertificateFailed),
+    }
+}
+
+/// Returns the notAfter time of the DER-encoded X.509 certificate `cert`, in seconds since the
+/// epoch. The certificate is subject to the same structural checks as in
+/// `parse_subject_from_certificate`.
+pub fn parse_not_after_from_certificate(cert: &[u8]) -> Result<i64, Error> {
+    let mut not_after = 0i64;
+    // Safety: getCertificateNotAfter reads at most cert.len() bytes from cert and writes only to
+    // not_after.
+    if !unsafe { getCertificateNotAfter(cert.as_ptr(), cert.len(), &mut not_after) } {
+        return Err(Error::ParseCertificateFailed);
+    }
+    Ok(not_after)
+}
+
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+/// The length of the lines of base64 encoded data in PEM, see RFC 7468.
+const PEM_LINE_LENGTH: usize = 64;
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 4 != 0 {
+        return None;
+    }
+    let last = encoded.len() / 4;
+    let mut decoded = Vec::with_capacity(last * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|c| **c == b'=').count();
+        // Only the last quantum may be padded.
+        if padding > 2 || (padding > 0 && i + 1 != last) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for c in &chunk[..4 - padding] {
+            bits = (bits << 6) | BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+        }
+        bits <<= 6 * padding;
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+/// Converts a certificate chain, i.e., a concatenation of DER-encoded X.509 certificates, into
+/// concatenated PEM blocks with one `CERTIFICATE` block per certificate, in the order of the
+/// chain. All certificates are parsed before any of them is converted, so that malformed chains
+/// fail with `Error::ParseCertificateFailed` instead of producing PEM that other tools reject.
+pub fn certificate_chain_to_pem(chain: &[u8]) -> Result<String, Error> {
+    let certs = split_certificate_chain(chain)?;
+    for cert in &certs {
+        parse_subject_from_certificate(cert).map_err(|_| Error::ParseCertificateFailed)?;
+    }
+    let mut pem = String::new();
+    for cert in certs {
+        pem.push_str(PEM_CERTIFICATE_BEGIN);
+        pem.push('\n');
+        for (i, c) in base64_encode(cert).chars().enumerate() {
+            if i > 0 && i % PEM_LINE_LENGTH == 0 {
+                pem.push('\n');
+            }
+            pem.push(c);
+        }
+        pem.push('\n');
+        pem.push_str(PEM_CERTIFICATE_END);
+        pem.push('\n');
+    }
+    Ok(pem)
+}
+
+/// Converts concatenated PEM `CERTIFICATE` blocks, as produced by `certificate_chain_to_pem`,
+/// back into a certificate chain of concatenated DER-encoded X.509 certificates. Blank lines
+/// between the blocks are ignored. Anything else outside of the blocks, unterminated blocks,
+/// and invalid base64 fail with `Error::ParsePemFailed`, and blocks that do not hold exactly one
+/// certificate fail with `Error::ParseCertificateFailed`.
+pub fn certificate_chain_from_pem(pem: &str) -> Result<Vec<u8>, Error> {
+    let mut chain = vec![];
+    let mut block: Option<String> = None;
+    for line in pem.lines().map(str::trim) {
+        match block.as_mut() {
+            None if line.is_empty() => {}
+            None if line == PEM_CERTIFICATE_BEGIN => block = Some(String::new()),
+            None => return Err(Error::ParsePemFailed),
+            Some(encoded) if line == PEM_CERTIFICATE_END => {
+                let cert = base64_decode(encoded).ok_or(Error::ParsePemFailed)?;
+                if split_certificate_chain(&cert)?.len() != 1 {
+                    return Err(Error::ParseCertificateFailed);
+                }
+                parse_subject_from_certificate(&cert).map_err(|_| Error::ParseCertificateFailed)?;
+                chain.extend_from_slice(&cert);
+                block = None;
+            }
+            Some(encoded) => encoded.push_str(line),
+        }
+    }
+    match block {
+        None => Ok(chain),
+        Some(_) => Err(Error::ParsePemFailed),
+    }
+}
+
+/// The root of trust of an attestation record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootOfTrust {
+    /// Whether the bootloader was locked.
+    pub device_locked: bool,
+    /// The raw `VerifiedBootState` value: 0 = Verified, 1 = SelfSigned, 2 = Unverified,
+    /// 3 = Failed.
+    pub verified_boot_state: i32,
+}
+
+/// The fields of the KeyMint attestation extension (OID 1.3.6.1.4.1.11129.2.1.17) decoded by
+/// `parse_attestation_record_from_certificate`. See the `KeyDescription` schema in
+/// IKeyMintDevice.aidl. Security levels are the raw `SecurityLevel` values: 0 = Software,
+/// 1 = TrustedEnvironment, 2 = StrongBox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestationRecord {
+    /// The version of the attestation schema.
+    pub attestation_version: i64,
+    /// The security level of the attestation.
+    pub attestation_security_level: i32,
+    /// The KeyMint or Keymaster version of the implementation that created the key.
+    pub keymint_version: i64,
+    /// The security level of the KeyMint implementation.
+    pub keymint_security_level: i32,
+    /// The attestation challenge that was passed at key generation.
+    pub attestation_challenge: Vec<u8>,
+    /// The root of trust, if any of the authorization lists has one.
+    pub root_of_trust: Option<RootOfTrust>,
+}
+
+/// Decodes the attestation extension of the DER-encoded X.509 certificate `cert_buf`. The
+/// certificate is subject to the same structural checks as in `parse_subject_from_certificate`.
+pub fn parse_attestation_record_from_certificate(
+    cert_buf: &[u8],
+) -> Result<AttestationRecord, Error> {
+    let mut record = std::mem::MaybeUninit::<CAttestationRecord>::uninit();
+    // Safety: extractAttestationRecord reads at most cert_buf.len() bytes from cert_buf and
+    // writes only to record.
+    if !unsafe { extractAttestationRecord(cert_buf.as_ptr(), cert_buf.len(), record.as_mut_ptr()) }
+    {
+        return Err(Error::ExtractAttestationRecordFailed);
+    }
+    // Safety: extractAttestationRecord initializes record if it returns true.
+    let record = unsafe { record.assume_init() };
+    let challenge = record
+        .attestation_challenge
+        .get(..record.attestation_challenge_len)
+        .ok_or(Error::ExtractAttestationRecordFailed)?;
+    Ok(AttestationRecord {
+        attestation_version: record.attestation_version,
+        attestation_security_level: record.attestation_security_level,
+        keymint_version: record.keymint_version,
+        keymint_security_level: record.keymint_security_level,
+        attestation_challenge: challenge.to_vec(),
+        root_of_trust: record.has_root_of_trust.then_some(RootOfTrust {
+            device_locked: record.device_locked,
+            verified_boot_state: record.verified_boot_state,
+        }),
+    })
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -482,6 +911,60 @@ mod tests {
         assert_eq!(message[..], message2[..])
     }
 
+    #[test]
+    fn test_wrapper_roundtrip_with_aad() {
+        let key = generate_aes256_key().unwrap();
+        let message = b"totally awesome message";
+        let (cipher_text, iv, tag) = aes_gcm_encrypt_with_aad(message, &key, b"context").unwrap();
+        let message2 = aes_gcm_decrypt_with_aad(&cipher_text, &iv, &tag, &key, b"context").unwrap();
+        assert_eq!(message[..], message2[..]);
+
+        assert_eq!(
+            aes_gcm_decrypt_with_aad(&cipher_text, &iv, &tag, &key, b"other context").unwrap_err(),
+            Error::DecryptionFailed
+        );
+        assert_eq!(
+            aes_gcm_decrypt(&cipher_text, &iv, &tag, &key).unwrap_err(),
+            Error::DecryptionFailed
+        );
+    }
+
+    #[test]
+    fn test_aead_roundtrip() {
+        let key = generate_aes256_key().unwrap();
+        let message = b"totally awesome message";
+        for aead in [Aead::Aes256GcmSiv, Aead::ChaCha20Poly1305] {
+            let (cipher_text, nonce, tag) =
+                aead_encrypt_with_aad(aead, message, &key, b"context").unwrap();
+            assert_ne!(cipher_text[..], message[..]);
+            let message2 =
+                aead_decrypt_with_aad(aead, &cipher_text, &nonce, &tag, &key, b"context").unwrap();
+            assert_eq!(message[..], message2[..]);
+
+            assert_eq!(
+                aead_decrypt_with_aad(aead, &cipher_text, &nonce, &tag, &key, b"other context")
+                    .unwrap_err(),
+                Error::DecryptionFailed
+            );
+            let mut corrupted = cipher_text.clone();
+            corrupted[0] ^= 1;
+            assert_eq!(
+                aead_decrypt_with_aad(aead, &corrupted, &nonce, &tag, &key, b"context")
+                    .unwrap_err(),
+                Error::DecryptionFailed
+            );
+            // The schemes are not interchangeable.
+            assert_eq!(
+                aes_gcm_decrypt_with_aad(&cipher_text, &nonce, &tag, &key, b"context").unwrap_err(),
+                Error::DecryptionFailed
+            );
+        }
+        assert_eq!(
+            aead_encrypt_with_aad(Aead::ChaCha20Poly1305, message, &key[..16], b"").unwrap_err(),
+            Error::InvalidKeyLength
+        );
+    }
+
     #[test]
     fn test_encrypt_decrypt() {
         let input = vec![0; 16];
@@ -498,6 +981,8 @@ mod tests {
                 key.as_ptr(),
                 16,
                 iv.as_ptr(),
+                std::ptr::null(),
+                0,
                 tag.as_mut_ptr(),
             );
             assert!(res);
@@ -510,6 +995,8 @@ mod tests {
                 key.as_ptr(),
                 16,
                 iv.as_ptr(),
+                std::ptr::null(),
+                0,
                 tag.as_ptr(),
             );
             assert!(res);
@@ -587,4 +1074,254 @@ mod tests {
         assert_eq!(tag2.len(), HMAC_SHA256_LEN);
         assert_ne!(tag1a, tag2);
     }
+
+    #[test]
+    fn test_sha256() {
+        // Test vectors from FIPS 180-2.
+        assert_eq!(
+            sha256(b"abc").unwrap(),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ]
+        );
+        assert_eq!(
+            sha256(b"").unwrap(),
+            [
+                0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+                0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+                0x78, 0x52, 0xb8, 0x55,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sha512() {
+        // Test vector from FIPS 180-2.
+        assert_eq!(
+            sha512(b"abc").unwrap(),
+            [
+                0xdd, 0xaf, 0x35, 0xa1, 0x93, 0x61, 0x7a, 0xba, 0xcc, 0x41, 0x73, 0x49, 0xae, 0x20,
+                0x41, 0x31, 0x12, 0xe6, 0xfa, 0x4e, 0x89, 0xa9, 0x7e, 0xa2, 0x0a, 0x9e, 0xee, 0xe6,
+                0x4b, 0x55, 0xd3, 0x9a, 0x21, 0x92, 0x99, 0x2a, 0x27, 0x4f, 0xc1, 0xa8, 0x36, 0xba,
+                0x3c, 0x23, 0xa3, 0xfe, 0xeb, 0xbd, 0x45, 0x4d, 0x44, 0x23, 0x64, 0x3c, 0xe8, 0x0e,
+                0x2a, 0x9a, 0xc9, 0x4f, 0xa5, 0x4c, 0xa4, 0x9f,
+            ]
+        );
+        assert_eq!(sha512(b"").unwrap().len(), SHA512_LEN);
+        assert_ne!(sha512(b"abc").unwrap()[..SHA256_LEN], sha256(b"abc").unwrap()[..]);
+    }
+
+    /// An EC key attested by a Keymaster 4 TEE implementation.
+    const ATTESTED_CERT: &[u8] = &[
+        0x30, 0x82, 0x02, 0x93, 0x30, 0x82, 0x02, 0x3A, 0xA0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01,
+        0x01, 0x30, 0x0A, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02, 0x30, 0x29,
+        0x31, 0x19, 0x30, 0x17, 0x06, 0x03, 0x55, 0x04, 0x05, 0x13, 0x10, 0x34, 0x34, 0x61, 0x38,
+        0x31, 0x65, 0x61, 0x65, 0x63, 0x35, 0x31, 0x64, 0x62, 0x30, 0x62, 0x31, 0x31, 0x0C, 0x30,
+        0x0A, 0x06, 0x03, 0x55, 0x04, 0x0C, 0x0C, 0x03, 0x54, 0x45, 0x45, 0x30, 0x20, 0x17, 0x0D,
+        0x37, 0x30, 0x30, 0x31, 0x30, 0x31, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x5A, 0x18, 0x0F,
+        0x32, 0x31, 0x30, 0x36, 0x30, 0x32, 0x30, 0x37, 0x30, 0x36, 0x32, 0x38, 0x31, 0x35, 0x5A,
+        0x30, 0x1F, 0x31, 0x1D, 0x30, 0x1B, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0C, 0x14, 0x41, 0x6E,
+        0x64, 0x72, 0x6F, 0x69, 0x64, 0x20, 0x4B, 0x65, 0x79, 0x73, 0x74, 0x6F, 0x72, 0x65, 0x20,
+        0x4B, 0x65, 0x79, 0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02,
+        0x01, 0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00, 0x04,
+        0x20, 0x16, 0x85, 0xE6, 0x7F, 0xF1, 0x0E, 0x99, 0x1B, 0x3A, 0xC6, 0xC2, 0x83, 0x0A, 0x1D,
+        0xA4, 0xF1, 0x92, 0x76, 0x88, 0x4B, 0x6A, 0xCD, 0xB2, 0x8E, 0xF1, 0x50, 0x58, 0xD2, 0x69,
+        0xDE, 0x57, 0x9C, 0x9C, 0x29, 0x04, 0x03, 0xF2, 0x4D, 0x12, 0x77, 0x9C, 0x62, 0xBC, 0x75,
+        0xB4, 0xAB, 0x7A, 0xBC, 0xA0, 0x8F, 0x60, 0x5E, 0xCD, 0xCE, 0x3A, 0xD8, 0x09, 0xEB, 0x9D,
+        0x40, 0xDB, 0x58, 0x53, 0xA3, 0x82, 0x01, 0x59, 0x30, 0x82, 0x01, 0x55, 0x30, 0x0E, 0x06,
+        0x03, 0x55, 0x1D, 0x0F, 0x01, 0x01, 0xFF, 0x04, 0x04, 0x03, 0x02, 0x07, 0x80, 0x30, 0x82,
+        0x01, 0x41, 0x06, 0x0A, 0x2B, 0x06, 0x01, 0x04, 0x01, 0xD6, 0x79, 0x02, 0x01, 0x11, 0x04,
+        0x82, 0x01, 0x31, 0x30, 0x82, 0x01, 0x2D, 0x02, 0x01, 0x03, 0x0A, 0x01, 0x01, 0x02, 0x01,
+        0x04, 0x0A, 0x01, 0x01, 0x04, 0x08, 0x61, 0x73, 0x64, 0x66, 0x6A, 0x6B, 0x6C, 0x3B, 0x04,
+        0x00, 0x30, 0x6B, 0xBF, 0x85, 0x3D, 0x08, 0x02, 0x06, 0x01, 0x76, 0x31, 0x8B, 0x9D, 0x10,
+        0xBF, 0x85, 0x45, 0x5B, 0x04, 0x59, 0x30, 0x57, 0x31, 0x31, 0x30, 0x2F, 0x04, 0x2A, 0x63,
+        0x6F, 0x6D, 0x2E, 0x67, 0x6F, 0x6F, 0x67, 0x6C, 0x65, 0x2E, 0x65, 0x78, 0x70, 0x65, 0x72,
+        0x69, 0x6D, 0x65, 0x6E, 0x74, 0x73, 0x2E, 0x6A, 0x64, 0x61, 0x6E, 0x69, 0x73, 0x2E, 0x6B,
+        0x65, 0x79, 0x73, 0x74, 0x6F, 0x72, 0x65, 0x74, 0x6F, 0x6F, 0x6C, 0x02, 0x01, 0x01, 0x31,
+        0x22, 0x04, 0x20, 0x30, 0xE0, 0x78, 0x45, 0xAB, 0xD7, 0xC1, 0x74, 0x49, 0x01, 0x0F, 0xA7,
+        0x7F, 0x89, 0xDE, 0x11, 0xA3, 0x8B, 0x3E, 0x31, 0x6B, 0xF1, 0x18, 0xB4, 0x58, 0x1B, 0xD7,
+        0xB3, 0x58, 0xA9, 0xC2, 0x81, 0x30, 0x81, 0xA5, 0xA1, 0x08, 0x31, 0x06, 0x02, 0x01, 0x02,
+        0x02, 0x01, 0x03, 0xA2, 0x03, 0x02, 0x01, 0x03, 0xA3, 0x04, 0x02, 0x02, 0x01, 0x00, 0xA5,
+        0x05, 0x31, 0x03, 0x02, 0x01, 0x04, 0xAA, 0x03, 0x02, 0x01, 0x01, 0xBF, 0x83, 0x78, 0x03,
+        0x02, 0x01, 0x02, 0xBF, 0x85, 0x3E, 0x03, 0x02, 0x01, 0x00, 0xBF, 0x85, 0x40, 0x4C, 0x30,
+        0x4A, 0x04, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x0A, 0x01, 0x02, 0x04, 0x20, 0xE7, 0xAD,
+        0x3C, 0x13, 0xC2, 0x73, 0x41, 0x60, 0xD7, 0x1A, 0x7C, 0x00, 0x5E, 0x14, 0xD8, 0xAE, 0x06,
+        0x5D, 0x22, 0xD0, 0xB5, 0xF5, 0x6A, 0xBA, 0x1F, 0x82, 0xA7, 0x8C, 0x17, 0x2C, 0xFD, 0x0F,
+        0xBF, 0x85, 0x41, 0x05, 0x02, 0x03, 0x01, 0xAD, 0xB0, 0xBF, 0x85, 0x42, 0x05, 0x02, 0x03,
+        0x03, 0x15, 0x75, 0xBF, 0x85, 0x4E, 0x06, 0x02, 0x04, 0x01, 0x34, 0x61, 0xB9, 0xBF, 0x85,
+        0x4F, 0x06, 0x02, 0x04, 0x01, 0x34, 0x61, 0xB9, 0x30, 0x0A, 0x06, 0x08, 0x2A, 0x86, 0x48,
+        0xCE, 0x3D, 0x04, 0x03, 0x02, 0x03, 0x47, 0x00, 0x30, 0x44, 0x02, 0x20, 0x4B, 0xDC, 0x8E,
+        0x91, 0xE6, 0xAA, 0x4A, 0x81, 0x6D, 0xA2, 0xD7, 0x13, 0x9E, 0x70, 0x12, 0x79, 0xB7, 0x85,
+        0x05, 0xAD, 0x6E, 0x5E, 0x0B, 0x43, 0x3B, 0xAF, 0x9A, 0xA9, 0x29, 0x40, 0xD7, 0x92, 0x02,
+        0x20, 0x2F, 0x39, 0x58, 0xE9, 0x89, 0x1A, 0x14, 0x41, 0x8D, 0xE0, 0xDC, 0x3D, 0x88, 0xF4,
+        0x2C, 0x7C, 0xDA, 0xA1, 0x84, 0xFA, 0x7F, 0xF9, 0x07, 0x97, 0xFB, 0xB5, 0xB7, 0x28, 0x28,
+        0x00, 0x7C, 0xA7,
+    ];
+
+    #[test]
+    fn test_parse_attestation_record() {
+        let record = parse_attestation_record_from_certificate(ATTESTED_CERT).unwrap();
+        assert_eq!(
+            record,
+            AttestationRecord {
+                attestation_version: 3,
+                attestation_security_level: 1,
+                keymint_version: 4,
+                keymint_security_level: 1,
+                attestation_challenge: b"asdfjkl;".to_vec(),
+                root_of_trust: Some(RootOfTrust { device_locked: false, verified_boot_state: 2 }),
+            }
+        );
+
+        assert_eq!(
+            parse_attestation_record_from_certificate(&ATTESTED_CERT[..100]),
+            Err(Error::ExtractAttestationRecordFailed)
+        );
+    }
+
+    /// Wraps `content` in `depth` nested SEQUENCEs. The result must stay below 128 bytes, so
+    /// that short form lengths suffice.
+    fn nested_sequences(depth: usize, content: &[u8]) -> Vec<u8> {
+        let mut der = content.to_vec();
+        for _ in 0..depth {
+            let mut outer = vec![0x30, der.len() as u8];
+            outer.extend_from_slice(&der);
+            der = outer;
+        }
+        der
+    }
+
+    #[test]
+    fn test_parse_subject_from_certificate() {
+        // The subject of ATTESTED_CERT is "CN=Android Keystore Key".
+        let subject = parse_subject_from_certificate(ATTESTED_CERT).unwrap();
+        assert_eq!(&subject[..], &ATTESTED_CERT[0x69..0x8a]);
+
+        // Trailing data, e.g., the rest of a certificate chain, is ignored.
+        let mut chain = ATTESTED_CERT.to_vec();
+        chain.extend_from_slice(ATTESTED_CERT);
+        assert_eq!(parse_subject_from_certificate(&chain).unwrap(), subject);
+    }
+
+    #[test]
+    fn test_split_certificate_chain() {
+        let mut chain = ATTESTED_CERT.to_vec();
+        chain.extend_from_slice(ATTESTED_CERT);
+        assert_eq!(split_certificate_chain(&chain).unwrap(), vec![ATTESTED_CERT, ATTESTED_CERT]);
+        assert_eq!(split_certificate_chain(&[]).unwrap(), Vec::<&[u8]>::new());
+
+        // Trailing data that is not a certificate is rejected.
+        chain.push(0x30);
+        assert_eq!(split_certificate_chain(&chain), Err(Error::ParseCertificateFailed));
+    }
+
+    #[test]
+    fn test_certificate_chain_pem_round_trip() {
+        let mut chain = ATTESTED_CERT.to_vec();
+        chain.extend_from_slice(ATTESTED_CERT);
+        let pem = certificate_chain_to_pem(&chain).unwrap();
+
+        let lines: Vec<&str> = pem.lines().collect();
+        // The 663 bytes of DER are 884 characters of base64, i.e., 14 lines.
+        assert_eq!(lines.len(), 2 * 16);
+        for block in lines.chunks(16) {
+            assert_eq!(block[0], "-----BEGIN CERTIFICATE-----");
+            assert_eq!(
+                block[1],
+                "MIICkzCCAjqgAwIBAgIBATAKBggqhkjOPQQDAjApMRkwFwYDVQQFExA0NGE4MWVh"
+            );
+            assert!(block[1..14].iter().all(|line| line.len() == 64));
+            assert_eq!(block[14].len(), 52);
+            assert!(block[14].ends_with("tygoAHyn"));
+            assert_eq!(block[15], "-----END CERTIFICATE-----");
+        }
+        assert_eq!(certificate_chain_from_pem(&pem).unwrap(), chain);
+        assert_eq!(certificate_chain_to_pem(&[]).unwrap(), "");
+        assert_eq!(certificate_chain_from_pem("").unwrap(), Vec::<u8>::new());
+
+        // Certificates are validated before conversion.
+        let mut malformed = ATTESTED_CERT.to_vec();
+        malformed.extend_from_slice(&ATTESTED_CERT[..100]);
+        assert_eq!(certificate_chain_to_pem(&malformed), Err(Error::ParseCertificateFailed));
+
+        // Broken markers and base64 are rejected.
+        let single = certificate_chain_to_pem(ATTESTED_CERT).unwrap();
+        for broken in [
+            single.replace("-----END CERTIFICATE-----\n", ""),
+            format!("garbage\n{}", single),
+            single.replacen('M', "*", 1),
+            single.replacen("AHyn\n", "AHy\n", 1),
+            single.replacen("MIIC", "MI==", 1),
+        ] {
+            assert_eq!(certificate_chain_from_pem(&broken), Err(Error::ParsePemFailed));
+        }
+        // A block must hold exactly one certificate.
+        let two_in_one = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            base64_encode(&chain)
+        );
+        assert_eq!(certificate_chain_from_pem(&two_in_one), Err(Error::ParseCertificateFailed));
+
+        // Partial quanta are padded.
+        assert_eq!(base64_encode(b"a"), "YQ==");
+        assert_eq!(base64_encode(b"ab"), "YWI=");
+        assert_eq!(base64_decode("YQ==").unwrap(), b"a");
+        assert_eq!(base64_decode("YWI=").unwrap(), b"ab");
+    }
+
+    #[test]
+    fn test_is_certificate_issued_by() {
+        // ATTESTED_CERT is not self-signed.
+        assert_eq!(is_certificate_issued_by(ATTESTED_CERT, ATTESTED_CERT), Ok(false));
+        assert_eq!(
+            is_certificate_issued_by(ATTESTED_CERT, &ATTESTED_CERT[..100]),
+            Err(Error::ParseCertificateFailed)
+        );
+    }
+
+    #[test]
+    fn test_certificate_public_keys_match() {
+        assert_eq!(certificate_public_keys_match(ATTESTED_CERT, ATTESTED_CERT), Ok(true));
+        assert_eq!(
+            certificate_public_keys_match(ATTESTED_CERT, &ATTESTED_CERT[..100]),
+            Err(Error::ParseCertificateFailed)
+        );
+    }
+
+    #[test]
+    fn test_parse_subject_rejects_malformed_der() {
+        // A SEQUENCE of 64 KiB + 1 byte holding a single OCTET STRING.
+        let mut oversized = vec![0x30, 0x83, 0x01, 0x00, 0x01, 0x04, 0x82, 0xff, 0xfd];
+        oversized.resize(oversized.len() + 0xfffd, 0);
+
+        let malformed: &[(&str, Vec<u8>)] = &[
+            ("empty", vec![]),
+            ("truncated", ATTESTED_CERT[..100].to_vec()),
+            ("huge length", vec![0x30, 0x84, 0x7f, 0xff, 0xff, 0xff, 0x02, 0x01, 0x00]),
+            ("length beyond parent", vec![0x30, 0x03, 0x30, 0x05, 0x02, 0x01, 0x00]),
+            ("indefinite length", vec![0x30, 0x80, 0x02, 0x01, 0x00, 0x00, 0x00]),
+            ("non-minimal length", vec![0x30, 0x81, 0x03, 0x02, 0x01, 0x00]),
+            ("not a sequence", vec![0x02, 0x01, 0x00]),
+            ("deeply nested", nested_sequences(60, &[])),
+            ("oversized", oversized),
+        ];
+        for (name, der) in malformed {
+            assert_eq!(
+                parse_subject_from_certificate(der),
+                Err(Error::ExtractSubjectFailed),
+                "{} input was not rejected",
+                name
+            );
+            assert_eq!(
+                parse_attestation_record_from_certificate(der),
+                Err(Error::ExtractAttestationRecordFailed),
+                "{} input was not rejected",
+                name
+            );
+        }
+    }
 }
@@ -41,18 +41,31 @@
 //! from the database module these functions take permission check
 //! callbacks.
 
+mod backup;
 mod perboot;
 pub(crate) mod utils;
 mod versioning;
 
+pub use backup::{BackupEntryInfo, RewrapBlob};
+
+use crate::attestation_cert_cache::ATTESTATION_CERT_CACHE;
 use crate::gc::Gc;
 use crate::globals::get_keymint_dev_by_uuid;
 use crate::impl_metadata; // This is in db_utils.rs
-use crate::key_parameter::{KeyParameter, Tag};
+use crate::key_fingerprint::{FingerprintAlgorithm, KeyFingerprint};
+use crate::key_lifecycle::{notify_key_created, notify_key_deleted};
+use crate::key_parameter::{KeyParameter, KeyParameterValue as KsKeyParamValue, Tag};
 use crate::ks_err;
-use crate::metrics_store::log_rkp_error_stats;
+use crate::log_throttle::log_throttled;
+use crate::metrics_store::{
+    log_database_contention, log_key_id_lock_held_too_long, log_rkp_error_stats,
+};
 use crate::permission::KeyPermSet;
-use crate::utils::{get_current_time_in_milliseconds, watchdog as wd, AID_USER_OFFSET};
+use crate::sysprop::{read_prop_bool, read_prop_duration, read_prop_parsed, read_prop_u32};
+use crate::utils::{
+    check_alias, get_current_time_in_milliseconds, resolve_key_namespace, uid_to_android_user,
+    watchdog as wd, AID_USER_OFFSET,
+};
 use crate::{
     error::{Error as KsError, ErrorCode, ResponseCode},
     super_key::SuperKeyType,
@@ -64,7 +77,7 @@ use utils::SqlField;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     HardwareAuthToken::HardwareAuthToken, HardwareAuthenticatorType::HardwareAuthenticatorType,
-    SecurityLevel::SecurityLevel,
+    KeyParameter::KeyParameter as KmKeyParameter, SecurityLevel::SecurityLevel,
 };
 use android_security_metrics::aidl::android::security::metrics::{
     RkpError::RkpError as MetricsRkpError, Storage::Storage as MetricsStorage,
@@ -73,10 +86,13 @@ use android_security_metrics::aidl::android::security::metrics::{
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor,
 };
+use android_system_keystore2::binder::ThreadState;
 
-use keystore2_crypto::ZVec;
+use keystore2_crypto::{
+    certificate_public_keys_match, parse_subject_from_certificate, split_certificate_chain, ZVec,
+};
 use lazy_static::lazy_static;
-use log::error;
+use log::{error, Level};
 #[cfg(not(test))]
 use rand::prelude::random;
 use rusqlite::{
@@ -92,7 +108,7 @@ use std::{
     collections::{HashMap, HashSet},
     path::Path,
     sync::{Arc, Condvar, Mutex},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 #[cfg(test)]
@@ -117,6 +133,42 @@ impl_metadata!(
         AttestationRawPubKey(Vec<u8>) with accessor attestation_raw_pub_key,
         /// SEC1 public key for ECDH encryption
         Sec1PublicKey(Vec<u8>) with accessor sec1_public_key,
+        /// Set if keystore replaced an oversized attestation challenge by its SHA-256 digest
+        /// before passing it to KeyMint.
+        AttestationChallengeHashed(bool) with accessor attestation_challenge_hashed,
+        /// The SHA-256 digest of the idempotency key supplied by the client when the key was
+        /// generated. Keys with this entry also have an `IdempotencyParamsDigest`.
+        IdempotencyToken(Vec<u8>) with accessor idempotency_token,
+        /// Set if the key is frozen, i.e., it must not be used until it is unfrozen.
+        Frozen(bool) with accessor frozen,
+        /// The number of attestations that a remote provisioned attestation key has signed since
+        /// it was assigned.
+        AttestationUseCount(i64) with accessor attestation_use_count,
+        /// The subject of the certificate of the key, i.e., the issuer subject of the keys that
+        /// it attests. It is re-parsed whenever the certificate is replaced.
+        CertificateSubject(Vec<u8>) with accessor certificate_subject,
+        /// Set if the key material may be wrapped for export to another device.
+        Exportable(bool) with accessor exportable,
+        /// The date after which the key must no longer be used. Expired keys are deleted by the
+        /// garbage collector. It is set when the key is created and never changed.
+        ExpiryDate(DateTime) with accessor expiry_date,
+        /// The named pool of remote provisioned attestation keys that the key belongs to. Keys
+        /// without a pool belong to the default pool of their KeyMint instance.
+        AttestationKeyPool(String) with accessor attestation_key_pool,
+        /// Set if a certificate of the stored attestation chain of the key expired or is about
+        /// to expire, so that the key should be attested again. It is cleared whenever the
+        /// certificate or the certificate chain is replaced.
+        ReattestationRequired(bool) with accessor reattestation_required,
+        /// The digest of the certificate of the key. It is recomputed whenever the
+        /// certificate is replaced, so that clients can detect that the public key changed.
+        PublicKeyFingerprint(Vec<u8>) with accessor public_key_fingerprint,
+        /// The hash algorithm of `PublicKeyFingerprint`. Fingerprints without it are SHA-256
+        /// digests, because they were stored before the algorithm became configurable.
+        PublicKeyFingerprintAlgorithm(FingerprintAlgorithm)
+            with accessor public_key_fingerprint_algorithm,
+        /// The SHA-256 digest of the key parameters of the request that generated the key with
+        /// an `IdempotencyToken`, so that the token is not honored for other parameters.
+        IdempotencyParamsDigest(Vec<u8>) with accessor idempotency_params_digest,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -151,6 +203,30 @@ impl KeyMetaData {
         Ok(Self { data: metadata })
     }
 
+    /// Returns true if this is the metadata of a key that was created at or after
+    /// `created_after` by a request with the idempotency token of `request`, the metadata of a
+    /// new key. Fails with `ErrorCode::INVALID_ARGUMENT` if the token is the same, but the key
+    /// parameters are not.
+    pub fn is_idempotent_retry(
+        &self,
+        request: &KeyMetaData,
+        created_after: DateTime,
+    ) -> Result<bool> {
+        match (self.idempotency_token(), request.idempotency_token(), self.creation_date()) {
+            (Some(token), Some(requested), Some(created))
+                if token == requested && *created >= created_after =>
+            {
+                if self.idempotency_params_digest() != request.idempotency_params_digest() {
+                    return Err(KsError::Km(ErrorCode::INVALID_ARGUMENT)).context(ks_err!(
+                        "The idempotency key was used with other key parameters."
+                    ));
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
     fn store_in_db(&self, key_id: i64, tx: &Transaction) -> Result<()> {
         let mut stmt = tx
             .prepare(
@@ -193,6 +269,29 @@ impl_metadata!(
         /// If the key is encrypted with a MaxBootLevel key, this is the boot level
         /// of that key
         MaxBootLevel(i32) with accessor max_boot_level,
+        /// If the blob is super encrypted with an AAD that binds it to its key entry, this is
+        /// the id of the owning user that is part of the AAD. It only marks the blob as bound;
+        /// the AAD of an unwrap is derived from the owner of the key entry, see
+        /// `super_key::BlobBinding`. Blobs without this field were encrypted without AAD.
+        BoundUserId(i32) with accessor bound_user_id,
+        /// Version of a super key. On a super key blob, this is the version of the super key
+        /// itself. On a blob that is super encrypted with a key from the database, this is the
+        /// version of the super key that it was wrapped with. Blobs without this field are
+        /// version 0.
+        SuperKeyVersion(i32) with accessor super_key_version,
+        /// If the blob is encrypted with a wrapping key derived from an AES super key, this is
+        /// the context that the wrapping key was derived for, see
+        /// `super_key::WrappingContext`. Blobs without this field are encrypted with the super
+        /// key itself.
+        WrappingContext(i32) with accessor wrapping_context,
+        /// If the blob is encrypted with an AES super key, this is the authenticated encryption
+        /// scheme that it is encrypted with, see `super_key::EncryptionScheme`. Blobs without
+        /// this field are encrypted with AES-GCM.
+        EncryptionScheme(i32) with accessor encryption_scheme,
+        /// On a password encrypted super key blob, this is a known test vector encrypted with a
+        /// key derived from the super key, see `super_key::SuperKeyVerification`. Super keys
+        /// without this field cannot be verified.
+        SuperKeyVerificationToken(Vec<u8>) with accessor super_key_verification_token,
         //  --- ADD NEW META DATA FIELDS HERE ---
         // For backwards compatibility add new entries only to
         // end of this list and above this comment.
@@ -241,6 +340,61 @@ impl BlobMetaData {
         }
         Ok(())
     }
+
+    /// Describes the non-secret fields of the metadata in a human readable form, one field per
+    /// line, for diagnostics. Salts, AEAD tags, and ephemeral public keys are described by
+    /// their size only.
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        match self.encrypted_by() {
+            Some(EncryptedBy::Password) => lines.push("encrypted_by: password".to_string()),
+            Some(EncryptedBy::KeyId(key_id)) => lines.push(format!("encrypted_by: key {}", key_id)),
+            None => {}
+        }
+        if let Some(km_uuid) = self.km_uuid() {
+            lines.push(format!("km_uuid: {}", to_hex(&km_uuid[..])));
+            lines.push(format!("security_level: {:?}", km_uuid.security_level()));
+        }
+        if let Some(iv) = self.iv() {
+            lines.push(format!("iv: {}", to_hex(iv)));
+        }
+        if let Some(salt) = self.salt() {
+            lines.push(format!("salt: {} bytes", salt.len()));
+        }
+        if let Some(aead_tag) = self.aead_tag() {
+            lines.push(format!("aead_tag: {} bytes", aead_tag.len()));
+        }
+        if let Some(public_key) = self.public_key() {
+            lines.push(format!("public_key: {} bytes", public_key.len()));
+        }
+        if let Some(max_boot_level) = self.max_boot_level() {
+            lines.push(format!("max_boot_level: {}", max_boot_level));
+        }
+        if let Some(bound_user_id) = self.bound_user_id() {
+            lines.push(format!("bound_user_id: {}", bound_user_id));
+        }
+        if let Some(super_key_version) = self.super_key_version() {
+            lines.push(format!("super_key_version: {}", super_key_version));
+        }
+        if let Some(wrapping_context) = self.wrapping_context() {
+            lines.push(format!("wrapping_context: {}", wrapping_context));
+        }
+        if let Some(encryption_scheme) = self.encryption_scheme() {
+            lines.push(format!("encryption_scheme: {}", encryption_scheme));
+        }
+        lines
+    }
+}
+
+/// Determines how a batch operation handles items that fail.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BatchMode {
+    /// If any item fails, the batch fails as a whole and none of its items takes effect.
+    Atomic,
+    /// Each item succeeds or fails on its own, and the result of each item is reported. The batch
+    /// still fails as a whole if it cannot be attempted at all, e.g., if the key does not exist
+    /// or the caller lacks the permission.
+    BestEffort,
 }
 
 /// Indicates the type of the keyentry.
@@ -278,7 +432,7 @@ impl FromSql for KeyType {
 }
 
 /// Uuid representation that can be stored in the database.
-/// Right now it can only be initialized from SecurityLevel.
+/// Right now it can only be initialized from SecurityLevel or a KeyMint instance name.
 /// Once KeyMint provides a UUID type a corresponding From impl shall be added.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Uuid([u8; 16]);
@@ -297,6 +451,31 @@ impl From<SecurityLevel> for Uuid {
     }
 }
 
+impl Uuid {
+    /// Derives the uuid of an additional KeyMint instance of the given security level from the
+    /// name under which the instance is declared. The primary instance of each security level
+    /// keeps the uuid derived from the security level alone, so that existing keys stay bound
+    /// to it. The last four bytes always hold the security level.
+    pub fn from_keymint_instance(sec_level: SecurityLevel, instance: &str) -> Result<Self> {
+        let digest = keystore2_crypto::sha256(instance.as_bytes())
+            .context(ks_err!("Failed to hash instance name."))?;
+        let mut uuid = [0u8; 16];
+        uuid[..12].copy_from_slice(&digest[..12]);
+        uuid[12..].copy_from_slice(&sec_level.0.to_be_bytes());
+        Ok(Self(uuid))
+    }
+
+    /// Returns the security level held by the last four bytes of the uuid.
+    pub fn security_level(&self) -> SecurityLevel {
+        let [.., b0, b1, b2, b3] = self.0;
+        SecurityLevel(i32::from_be_bytes([b0, b1, b2, b3]))
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 impl ToSql for Uuid {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
         self.0.to_sql()
@@ -353,11 +532,210 @@ impl FromSql for EncryptedBy {
     }
 }
 
+/// Identifies the super key that protects a key blob, but not its key material.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SuperKeyIdentity {
+    /// The key blob is not super encrypted.
+    None,
+    /// The key blob is encrypted with `version` of the super key `key_id`, which is stored with
+    /// `alias`, e.g., "USER_SUPER_KEY".
+    SuperKey {
+        /// The alias of the super key, which tells its type.
+        alias: String,
+        /// The id of the super key.
+        key_id: i64,
+        /// The version of the super key.
+        version: i32,
+    },
+    /// The key blob is encrypted with the boot level key of the given boot level.
+    BootLevel(i32),
+    /// The key blob is encrypted with a key derived from the user's password. This is how the
+    /// super keys themselves are protected.
+    Password,
+}
+
 /// A database representation of wall clock time. DateTime stores unix epoch time as
 /// i64 in milliseconds.
 #[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd)]
 pub struct DateTime(i64);
 
+/// One entry of the key id allocation audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyIdAllocation {
+    /// The allocated key id.
+    pub key_id: i64,
+    /// The wall clock time of the allocation.
+    pub allocation_time: DateTime,
+    /// The UID of the binder caller on whose behalf the key id was allocated. This is
+    /// keystore's own UID for key ids allocated outside of a binder call.
+    pub caller_uid: u32,
+}
+
+/// A record of a deleted key that is retained for audit after the key is gone, see
+/// `KeystoreDB::TOMBSTONE_RETENTION_PROPERTY`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyTombstone {
+    /// The id of the deleted key.
+    pub key_id: i64,
+    /// The wall clock time of the deletion.
+    pub deletion_time: DateTime,
+    /// The UID of the binder caller on whose behalf the key was deleted. This is keystore's own
+    /// UID for keys deleted outside of a binder call, e.g., expired keys.
+    pub caller_uid: u32,
+}
+
+/// The number of keys owned by one namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceKeyCount {
+    /// The domain of the namespace, either `Domain::APP` or `Domain::SELINUX`.
+    pub domain: Domain,
+    /// The namespace, i.e., the app UID or the SELinux namespace.
+    pub namespace: i64,
+    /// The number of live client keys with an alias in the namespace.
+    pub key_count: usize,
+}
+
+/// Dangling references between key entries, blob entries, and grants, see
+/// `KeystoreDB::check_consistency`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    /// The ids of blob entries whose key entry does not exist.
+    pub blobs_without_key: Vec<i64>,
+    /// The ids of live key entries that have no blob entry at all, i.e., neither a key blob
+    /// nor a certificate.
+    pub keys_without_blob: Vec<i64>,
+    /// The ids of grants whose target key entry does not exist or is no longer live.
+    pub grants_without_key: Vec<i64>,
+}
+
+/// The data that determines when a key expires, see `KeystoreDB::get_key_expiry_data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyExpiryData {
+    /// The id of the key entry.
+    pub key_id: i64,
+    /// The alias of the key.
+    pub alias: String,
+    /// The date after which the key must no longer be used, if any.
+    pub expiry_date: Option<DateTime>,
+    /// The certificate of the key followed by its certificate chain, if the key has a
+    /// certificate.
+    pub chain: Option<Vec<u8>>,
+}
+
+/// The persisted state of a resumable operation, see `operation_checkpoint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationCheckpoint {
+    /// The id of the operation, which is also the token to resume it.
+    pub id: i64,
+    /// The UID of the owner of the operation.
+    pub owner: u32,
+    /// The id of the key entry of the operation.
+    pub key_id: i64,
+    /// The KeyMint instance of the operation.
+    pub km_uuid: Uuid,
+    /// The parameters that the operation was created with, including its purpose.
+    pub params: Vec<KmKeyParameter>,
+    /// The input that the operation received so far, as chunks of `(ciphertext, iv, tag)`,
+    /// encrypted with the super key of the owner, see `operation_checkpoint`.
+    pub input: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)>,
+    /// The time at which the operation was created.
+    pub creation_time: DateTime,
+}
+
+/// The non-secret state of one key of a namespace, see `KeystoreDB::snapshot_key_inventory`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeyInventoryEntry {
+    /// The alias of the key.
+    pub alias: String,
+    /// The id of the key entry. It changes if the alias is rebound to a new key.
+    pub key_id: i64,
+    /// SHA-256 over a canonical encoding of the key parameters, the key metadata, the
+    /// certificates, and the metadata of the key blob. The key blob itself is not included.
+    pub digest: Vec<u8>,
+}
+
+/// The public parts of one key, see `KeystoreDB::list_public_parts_past_alias`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKeyParts {
+    /// The alias of the key.
+    pub alias: String,
+    /// The public certificate of the key, if any.
+    pub cert: Option<Vec<u8>>,
+    /// The certificate chain of the key, if any.
+    pub cert_chain: Option<Vec<u8>>,
+}
+
+impl PublicKeyParts {
+    /// Estimates the size of the parts in a binder transaction, like the key descriptors of
+    /// `list_key_entries`, i.e., the alias and the certificates with their length encodings,
+    /// and the domain and namespace of the key descriptor.
+    fn estimated_size(&self) -> usize {
+        4 + 8
+            + 4
+            + self.alias.len()
+            + self.cert.as_ref().map_or(0, |cert| 4 + cert.len())
+            + self.cert_chain.as_ref().map_or(0, |cert_chain| 4 + cert_chain.len())
+    }
+}
+
+/// The live client keys of a namespace at one point in time, sorted by alias.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct KeyInventorySnapshot {
+    /// One entry per key.
+    pub entries: Vec<KeyInventoryEntry>,
+}
+
+/// The changes between two key inventory snapshots, see `KeyInventorySnapshot::diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyInventoryDiff {
+    /// The aliases that exist only in the later snapshot.
+    pub added: Vec<String>,
+    /// The aliases that exist only in the earlier snapshot.
+    pub removed: Vec<String>,
+    /// The aliases whose key was replaced or whose metadata changed.
+    pub changed: Vec<String>,
+}
+
+impl KeyInventorySnapshot {
+    /// Returns a SHA-256 digest of the whole snapshot, so that two snapshots can be compared
+    /// without exchanging them.
+    pub fn digest(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for entry in &self.entries {
+            data.extend_from_slice(&(entry.alias.len() as u64).to_be_bytes());
+            data.extend_from_slice(entry.alias.as_bytes());
+            data.extend_from_slice(&entry.key_id.to_be_bytes());
+            data.extend_from_slice(&entry.digest);
+        }
+        keystore2_crypto::sha256(&data).context(ks_err!("Failed to hash the snapshot."))
+    }
+
+    /// Compares this snapshot with the `later` one. Each list in the result is sorted by alias.
+    pub fn diff(&self, later: &Self) -> KeyInventoryDiff {
+        let earlier: HashMap<&str, &KeyInventoryEntry> =
+            self.entries.iter().map(|e| (e.alias.as_str(), e)).collect();
+        let mut diff = KeyInventoryDiff::default();
+        for entry in &later.entries {
+            match earlier.get(entry.alias.as_str()) {
+                None => diff.added.push(entry.alias.clone()),
+                Some(e) if *e != entry => diff.changed.push(entry.alias.clone()),
+                Some(_) => {}
+            }
+        }
+        let later: HashSet<&str> = later.entries.iter().map(|e| e.alias.as_str()).collect();
+        diff.removed = self
+            .entries
+            .iter()
+            .filter(|e| !later.contains(e.alias.as_str()))
+            .map(|e| e.alias.clone())
+            .collect();
+        diff.added.sort();
+        diff.changed.sort();
+        diff.removed.sort();
+        diff
+    }
+}
+
 /// Error type returned when creating DateTime or converting it from and to
 /// SystemTime.
 #[derive(thiserror::Error, Debug)]
@@ -511,66 +889,289 @@ impl KeyEntryLoadBits {
 
 lazy_static! {
     static ref KEY_ID_LOCK: KeyIdLockDb = KeyIdLockDb::new();
+    static ref KEY_HANDLES: KeyHandleDb = KeyHandleDb::new();
 }
 
+/// Key id locks that are held for longer than this are logged, e.g., `500ms`. Zero disables the
+/// logging.
+const KEY_ID_LOCK_HOLD_THRESHOLD_PROPERTY: &str = "keystore.key_id_lock_hold_threshold";
+const DEFAULT_KEY_ID_LOCK_HOLD_THRESHOLD: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
 struct KeyIdLockDb {
-    locked_keys: Mutex<HashSet<i64>>,
+    locked_keys: Mutex<HashMap<i64, KeyIdLockState>>,
     cond_var: Condvar,
+    hold_threshold: Duration,
+}
+
+/// The mode in which a key id is locked. Any number of shared locks on a key id can be held at
+/// the same time, but an exclusive lock excludes all other locks on the key id. Read-only
+/// accesses may use shared locks, while accesses that modify the key entry need an exclusive
+/// lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyIdLockMode {
+    /// No other lock can be held on the key id.
+    Exclusive,
+    /// Other shared locks can be held on the key id.
+    Shared,
+}
+
+/// The database operations that lock key ids. Locks held for longer than the threshold are
+/// reported with the operation that took them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyIdLockOperation {
+    /// Loading a key entry, see `KeystoreDB::load_key_entry`.
+    LoadKeyEntry,
+    /// Creating or replacing a key entry.
+    CreateKeyEntry,
+    /// Loading a super key.
+    LoadSuperKey,
+    /// Creating a super key, or loading it if it exists.
+    CreateSuperKey,
+    /// Storing a remotely provisioned attestation key.
+    StoreAttestationKey,
+    /// Loading a remotely provisioned attestation key and its certificate chain.
+    LoadAttestationKey,
+    /// Marking expired key entries as unreferenced.
+    MarkKeysUnreferenced,
+}
+
+/// The locks held on a key id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyIdLockState {
+    Exclusive,
+    /// The number of shared locks held.
+    Shared(usize),
 }
 
 /// A locked key. While a guard exists for a given key id, the same key cannot be loaded
-/// from the database a second time. Most functions manipulating the key blob database
-/// require a KeyIdGuard.
-#[derive(Debug)]
-pub struct KeyIdGuard(i64);
+/// from the database a second time, unless both guards are shared. Most functions manipulating
+/// the key blob database require a KeyIdGuard.
+pub struct KeyIdGuard {
+    id: i64,
+    mode: KeyIdLockMode,
+    operation: KeyIdLockOperation,
+    acquired_at: Instant,
+    lock_db: &'static KeyIdLockDb,
+}
+
+impl std::fmt::Debug for KeyIdGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyIdGuard")
+            .field("id", &self.id)
+            .field("mode", &self.mode)
+            .field("operation", &self.operation)
+            .finish()
+    }
+}
 
 impl KeyIdLockDb {
     fn new() -> Self {
-        Self { locked_keys: Mutex::new(HashSet::new()), cond_var: Condvar::new() }
+        Self::with_hold_threshold(read_prop_duration(
+            KEY_ID_LOCK_HOLD_THRESHOLD_PROPERTY,
+            DEFAULT_KEY_ID_LOCK_HOLD_THRESHOLD,
+        ))
+    }
+
+    fn with_hold_threshold(hold_threshold: Duration) -> Self {
+        Self { locked_keys: Mutex::new(HashMap::new()), cond_var: Condvar::new(), hold_threshold }
+    }
+
+    /// Records a lock in `mode` on `key_id` in `locked_keys`, if it does not conflict with the
+    /// locks already held. Returns true if the lock was taken.
+    fn take(
+        locked_keys: &mut HashMap<i64, KeyIdLockState>,
+        key_id: i64,
+        mode: KeyIdLockMode,
+    ) -> bool {
+        match (locked_keys.get_mut(&key_id), mode) {
+            (None, KeyIdLockMode::Exclusive) => {
+                locked_keys.insert(key_id, KeyIdLockState::Exclusive);
+                true
+            }
+            (None, KeyIdLockMode::Shared) => {
+                locked_keys.insert(key_id, KeyIdLockState::Shared(1));
+                true
+            }
+            (Some(KeyIdLockState::Shared(count)), KeyIdLockMode::Shared) => {
+                *count += 1;
+                true
+            }
+            _ => false,
+        }
     }
 
     /// This function blocks until an exclusive lock for the given key entry id can
     /// be acquired. It returns a guard object, that represents the lifecycle of the
-    /// acquired lock.
-    pub fn get(&self, key_id: i64) -> KeyIdGuard {
+    /// acquired lock. `operation` is reported if the lock is held for too long.
+    pub fn get(&'static self, key_id: i64, operation: KeyIdLockOperation) -> KeyIdGuard {
+        self.get_with_mode(key_id, KeyIdLockMode::Exclusive, operation)
+    }
+
+    /// Like `get`, but acquires the lock in the given `mode`.
+    pub fn get_with_mode(
+        &'static self,
+        key_id: i64,
+        mode: KeyIdLockMode,
+        operation: KeyIdLockOperation,
+    ) -> KeyIdGuard {
         let mut locked_keys = self.locked_keys.lock().unwrap();
-        while locked_keys.contains(&key_id) {
+        while !Self::take(&mut locked_keys, key_id, mode) {
             locked_keys = self.cond_var.wait(locked_keys).unwrap();
         }
-        locked_keys.insert(key_id);
-        KeyIdGuard(key_id)
+        self.guard(key_id, mode, operation)
     }
 
     /// This function attempts to acquire an exclusive lock on a given key id. If the
     /// given key id is already taken the function returns None immediately. If a lock
     /// can be acquired this function returns a guard object, that represents the
     /// lifecycle of the acquired lock.
-    pub fn try_get(&self, key_id: i64) -> Option<KeyIdGuard> {
+    pub fn try_get(
+        &'static self,
+        key_id: i64,
+        operation: KeyIdLockOperation,
+    ) -> Option<KeyIdGuard> {
+        self.try_get_with_mode(key_id, KeyIdLockMode::Exclusive, operation)
+    }
+
+    /// Like `try_get`, but attempts to acquire the lock in the given `mode`.
+    pub fn try_get_with_mode(
+        &'static self,
+        key_id: i64,
+        mode: KeyIdLockMode,
+        operation: KeyIdLockOperation,
+    ) -> Option<KeyIdGuard> {
         let mut locked_keys = self.locked_keys.lock().unwrap();
-        if locked_keys.insert(key_id) {
-            Some(KeyIdGuard(key_id))
+        if Self::take(&mut locked_keys, key_id, mode) {
+            Some(self.guard(key_id, mode, operation))
         } else {
             None
         }
     }
+
+    fn guard(
+        &'static self,
+        key_id: i64,
+        mode: KeyIdLockMode,
+        operation: KeyIdLockOperation,
+    ) -> KeyIdGuard {
+        KeyIdGuard { id: key_id, mode, operation, acquired_at: Instant::now(), lock_db: self }
+    }
+
+    /// Reports a lock that was held for `held`, if that exceeds the threshold. Returns true if
+    /// the lock was reported.
+    fn report_hold_time(&self, guard: &KeyIdGuard, held: Duration) -> bool {
+        if self.hold_threshold.is_zero() || held <= self.hold_threshold {
+            return false;
+        }
+        log_key_id_lock_held_too_long(guard.operation, guard.mode);
+        // The hold time is not part of the message, so that repeated reports are throttled.
+        log_throttled(
+            Level::Warn,
+            &format!(
+                "Key id {} was locked ({:?}) by {:?} for longer than {:?}.",
+                guard.id, guard.mode, guard.operation, self.hold_threshold
+            ),
+        );
+        true
+    }
 }
 
 impl KeyIdGuard {
     /// Get the numeric key id of the locked key.
     pub fn id(&self) -> i64 {
-        self.0
+        self.id
+    }
+
+    /// Returns the mode in which the key is locked.
+    pub fn mode(&self) -> KeyIdLockMode {
+        self.mode
+    }
+
+    /// Returns the operation that locked the key.
+    pub fn operation(&self) -> KeyIdLockOperation {
+        self.operation
     }
 }
 
 impl Drop for KeyIdGuard {
     fn drop(&mut self) {
-        let mut locked_keys = KEY_ID_LOCK.locked_keys.lock().unwrap();
-        locked_keys.remove(&self.0);
+        let mut locked_keys = self.lock_db.locked_keys.lock().unwrap();
+        match locked_keys.get_mut(&self.id) {
+            Some(KeyIdLockState::Shared(count)) if *count > 1 => *count -= 1,
+            _ => {
+                locked_keys.remove(&self.id);
+            }
+        }
         drop(locked_keys);
-        KEY_ID_LOCK.cond_var.notify_all();
+        self.lock_db.cond_var.notify_all();
+        self.lock_db.report_hold_time(self, self.acquired_at.elapsed());
+    }
+}
+
+/// An opaque handle of a key entry, see `KeystoreDB::resolve_key_handle`. A handle stands for
+/// a key descriptor that was resolved, and passed the permission check, on behalf of one caller.
+/// Handles are kept in memory. A handle is invalidated when its key entry is deleted, or when
+/// the grants or the namespace of the key entry change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyHandle(i64);
+
+struct KeyHandleEntry {
+    key_id: i64,
+    caller_uid: u32,
+    /// The descriptor by which the permission was checked.
+    access_key_descriptor: KeyDescriptor,
+}
+
+struct KeyHandleDb {
+    handles: Mutex<HashMap<i64, KeyHandleEntry>>,
+}
+
+impl KeyHandleDb {
+    fn new() -> Self {
+        Self { handles: Mutex::new(HashMap::new()) }
+    }
+
+    fn insert(&self, entry: KeyHandleEntry) -> KeyHandle {
+        let mut handles = self.handles.lock().unwrap();
+        loop {
+            let id: i64 = random();
+            if let std::collections::hash_map::Entry::Vacant(e) = handles.entry(id) {
+                e.insert(entry);
+                return KeyHandle(id);
+            }
+        }
+    }
+
+    /// Returns the key id of `handle` if it is valid and was issued to `caller_uid`.
+    fn get(&self, handle: KeyHandle, caller_uid: u32) -> Option<i64> {
+        match self.handles.lock().unwrap().get(&handle.0) {
+            Some(entry) if entry.caller_uid == caller_uid => Some(entry.key_id),
+            _ => None,
+        }
+    }
+
+    fn remove(&self, handle: KeyHandle) {
+        self.handles.lock().unwrap().remove(&handle.0);
+    }
+
+    fn invalidate_key(&self, key_id: i64) {
+        self.handles.lock().unwrap().retain(|_, entry| entry.key_id != key_id);
+    }
+
+    fn invalidate_namespace(&self, domain: Domain, namespace: i64) {
+        self.handles.lock().unwrap().retain(|_, entry| {
+            !(entry.access_key_descriptor.domain == domain
+                && entry.access_key_descriptor.nspace == namespace)
+        });
     }
 }
 
+/// Wraps the certificate chain of a new key entry, given the id of the key entry and the chain,
+/// see `KeystoreDB::store_new_key_with_blob`. Returns the wrapped chain and its metadata, or None
+/// if the chain is stored as is.
+pub type WrapCertChain<'a> = dyn Fn(i64, &[u8]) -> Result<Option<(Vec<u8>, BlobMetaData)>> + 'a;
+
 /// This type represents a certificate and certificate chain entry for a key.
 #[derive(Debug, Default)]
 pub struct CertificateInfo {
@@ -578,6 +1179,40 @@ pub struct CertificateInfo {
     cert_chain: Option<Vec<u8>>,
 }
 
+/// Selects what happens if a new key is stored under an alias that a live key already has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingAlias {
+    /// The alias is rebound to the new key atomically. The old key becomes unreferenced and is
+    /// deleted by the garbage collector.
+    Replace,
+    /// Storing the new key fails with `ResponseCode::INVALID_ARGUMENT`, and the old key is left
+    /// untouched.
+    Fail,
+    /// Like `Replace`, unless the old key was created at or after the given time by an earlier
+    /// request with the idempotency token of the new key, see `KeyMetaData::is_idempotent_retry`.
+    /// Then nothing is stored, and the old key is returned as `StoredKey::IdempotentRetry`.
+    KeepIdempotentRetry(DateTime),
+}
+
+/// The key entry that the alias is bound to after `KeystoreDB::store_new_key_with_blob`.
+pub enum StoredKey {
+    /// The new key was stored.
+    New(KeyIdGuard),
+    /// The new key was not stored, because the live key with this id was created by an earlier
+    /// request with the same idempotency token, see `ExistingAlias::KeepIdempotentRetry`.
+    IdempotentRetry(i64),
+}
+
+impl StoredKey {
+    /// Returns the id of the key entry.
+    pub fn id(&self) -> i64 {
+        match self {
+            Self::New(key_id) => key_id.id(),
+            Self::IdempotentRetry(key_id) => *key_id,
+        }
+    }
+}
+
 /// This type represents a Blob with its metadata and an optional superseded blob.
 #[derive(Debug)]
 pub struct BlobInfo<'a> {
@@ -647,6 +1282,7 @@ pub struct KeyEntry {
     key_blob_info: Option<(Vec<u8>, BlobMetaData)>,
     cert: Option<Vec<u8>>,
     cert_chain: Option<Vec<u8>>,
+    cert_chain_metadata: BlobMetaData,
     km_uuid: Uuid,
     parameters: Vec<KeyParameter>,
     metadata: KeyMetaData,
@@ -682,6 +1318,11 @@ impl KeyEntry {
     pub fn take_cert_chain(&mut self) -> Option<Vec<u8>> {
         self.cert_chain.take()
     }
+    /// Exposes the metadata of the certificate chain. It is empty unless the chain was wrapped
+    /// with a super key, see `SuperKeyManager::unwrap_cert_chain_if_required`.
+    pub fn cert_chain_metadata(&self) -> &BlobMetaData {
+        &self.cert_chain_metadata
+    }
     /// Returns the uuid of the owning KeyMint instance.
     pub fn km_uuid(&self) -> &Uuid {
         &self.km_uuid
@@ -698,6 +1339,23 @@ impl KeyEntry {
     pub fn metadata(&self) -> &KeyMetaData {
         &self.metadata
     }
+    /// Returns true if the key is frozen and must not be used.
+    pub fn is_frozen(&self) -> bool {
+        self.metadata.frozen() == Some(&true)
+    }
+    /// Returns true if the key was marked exportable.
+    pub fn is_exportable(&self) -> bool {
+        self.metadata.exportable() == Some(&true)
+    }
+    /// Returns true if the key has an expiry date that is not after `now`.
+    pub fn is_expired(&self, now: DateTime) -> bool {
+        self.metadata.expiry_date().map_or(false, |expiry| *expiry <= now)
+    }
+    /// Returns true if the key was marked for re-attestation because its attestation chain
+    /// expired or is about to expire.
+    pub fn needs_reattestation(&self) -> bool {
+        self.metadata.reattestation_required() == Some(&true)
+    }
     /// This returns true if the entry is a pure certificate entry with no
     /// private key component.
     pub fn pure_cert(&self) -> bool {
@@ -761,12 +1419,44 @@ impl<T> DoGc<T> for Result<T> {
     }
 }
 
+/// The journal mode of the persistent database, see `KeystoreDB::JOURNAL_MODE_PROPERTY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    /// A rollback journal that is deleted at the end of each transaction. This is SQLite's
+    /// default and the most robust against power loss, at the cost of more writes.
+    Delete,
+    /// A write ahead log. Readers do not block writers and commits are cheaper, but the log
+    /// must be checkpointed into the database file from time to time, see
+    /// `KeystoreDB::checkpoint_wal`.
+    Wal,
+}
+
+impl JournalMode {
+    /// Reads the journal mode from `KeystoreDB::JOURNAL_MODE_PROPERTY`.
+    pub fn from_property() -> Self {
+        read_prop_parsed(KeystoreDB::JOURNAL_MODE_PROPERTY, Self::Delete, |value| match value {
+            "delete" => Some(Self::Delete),
+            "wal" => Some(Self::Wal),
+            _ => None,
+        })
+    }
+
+    fn pragma_value(&self) -> &'static str {
+        match self {
+            Self::Delete => "DELETE",
+            Self::Wal => "WAL",
+        }
+    }
+}
+
 /// KeystoreDB wraps a connection to an SQLite database and tracks its
 /// ownership. It also implements all of Keystore 2.0's database functionality.
 pub struct KeystoreDB {
     conn: Connection,
     gc: Option<Arc<Gc>>,
     perboot: Arc<perboot::PerbootDB>,
+    tombstone_retention: Option<Duration>,
+    journal_mode: JournalMode,
 }
 
 /// Database representation of the monotonic time retrieved from the system call clock_gettime with
@@ -864,18 +1554,79 @@ impl KeystoreDB {
     /// Name of the file that holds the cross-boot persistent database.
     pub const PERSISTENT_DB_FILENAME: &'static str = "persistent.sqlite";
 
+    /// If set, which is the default, SQLite overwrites deleted content of the persistent
+    /// database with zeros. Otherwise, the bytes of deleted key blobs may linger in free pages
+    /// of the database file until they are reused.
+    const SECURE_DELETE_PROPERTY: &'static str = "keystore.db_secure_delete";
+
+    /// The journal mode of the persistent database, "delete", the default, or "wal". All
+    /// connections to the database must use the same mode. A changed mode takes effect after
+    /// keystore restarts.
+    const JOURNAL_MODE_PROPERTY: &'static str = "keystore.db_journal_mode";
+
+    /// Maximum number of attempts of a transaction that fails because the database is busy or
+    /// locked, see `with_transaction`. With the delays below, a transaction is retried for about
+    /// two seconds before it fails.
+    const MAX_TRANSACTION_ATTEMPTS: u32 = 100;
+
+    /// Delay before the first retry of a transaction. The delay doubles with each retry up to
+    /// `TRANSACTION_RETRY_MAX_DELAY`.
+    const TRANSACTION_RETRY_INITIAL_DELAY: Duration = Duration::from_micros(500);
+
+    /// Maximum delay between two attempts of a transaction.
+    const TRANSACTION_RETRY_MAX_DELAY: Duration = Duration::from_millis(20);
+
+    /// Maximum combined size in bytes of the names and values of the custom metadata of a key.
+    pub const MAX_CUSTOM_METADATA_SIZE: usize = 1024;
+
+    /// Overrides the maximum size in bytes of a certificate chain, see `check_cert_chain_size`.
+    const MAX_CERT_CHAIN_SIZE_PROPERTY: &'static str = "keystore.max_cert_chain_size";
+
+    /// Default maximum size in bytes of a certificate chain. Attestation certificate chains
+    /// take a few kilobytes, so this leaves ample room for large certificates.
+    pub const DEFAULT_MAX_CERT_CHAIN_SIZE: usize = 64 * 1024;
+
+    /// Minimum size in bytes of a plausible key blob, see `check_key_blob_size`. Key blobs are
+    /// opaque to keystore, but no KeyMint implementation produces blobs this short.
+    const MIN_KEY_BLOB_SIZE: usize = 4;
+
+    /// How long a tombstone of a deleted key is retained, e.g., "30d". While set to a non zero
+    /// duration, the deletion of a key writes a tombstone with the key id, the deletion time, and
+    /// the deleting UID. The key material is removed as usual. Tombstones are purged by the
+    /// garbage collector once they are older than the retention period. Off by default.
+    const TOMBSTONE_RETENTION_PROPERTY: &'static str = "keystore.key_tombstone_retention";
+
+    /// Number of the most recent entries of the key id allocation audit log that are retained.
+    /// Older entries are purged by the garbage collector, see `purge_key_id_allocations`.
+    const KEY_ID_AUDIT_RETAINED_ENTRIES: i64 = 10_000;
+
     /// This will create a new database connection connecting the two
     /// files persistent.sqlite and perboot.sqlite in the given directory.
     /// It also attempts to initialize all of the tables.
     /// KeystoreDB cannot be used by multiple threads.
     /// Each thread should open their own connection using `thread_local!`.
     pub fn new(db_root: &Path, gc: Option<Arc<Gc>>) -> Result<Self> {
+        Self::new_with_journal_mode(db_root, gc, JournalMode::from_property())
+    }
+
+    /// Like `new`, but opens the persistent database in the given journal mode.
+    pub fn new_with_journal_mode(
+        db_root: &Path,
+        gc: Option<Arc<Gc>>,
+        journal_mode: JournalMode,
+    ) -> Result<Self> {
         let _wp = wd::watch_millis("KeystoreDB::new", 500);
 
         let persistent_path = Self::make_persistent_path(db_root)?;
-        let conn = Self::make_connection(&persistent_path)?;
-
-        let mut db = Self { conn, gc, perboot: perboot::PERBOOT_DB.clone() };
+        let conn = Self::make_connection(&persistent_path, journal_mode)?;
+
+        let mut db = Self {
+            conn,
+            gc,
+            perboot: perboot::PERBOOT_DB.clone(),
+            tombstone_retention: Self::read_tombstone_retention(),
+            journal_mode,
+        };
         db.with_transaction(TransactionBehavior::Immediate, |tx| {
             versioning::upgrade_database(tx, Self::CURRENT_DB_VERSION, Self::UPGRADERS)
                 .context(ks_err!("KeystoreDB::new: trying to upgrade database."))?;
@@ -884,6 +1635,29 @@ impl KeystoreDB {
         Ok(db)
     }
 
+    /// Creates a database that lives entirely in memory, with the same tables as a database
+    /// created by `KeystoreDB::new`. Because the database always starts out empty there is
+    /// nothing to upgrade. Such a database cannot be shared between connections, so it has no
+    /// garbage collector, which needs a connection of its own, and it has its own perboot
+    /// database. This is intended as a fast and isolated backend for unit tests.
+    #[cfg(test)]
+    pub fn new_in_memory() -> Result<Self> {
+        // In memory databases have no journal file, so the journal mode does not matter.
+        let conn = Self::make_connection("file::memory:", JournalMode::Delete)?;
+
+        let mut db = Self {
+            conn,
+            gc: None,
+            perboot: Arc::new(perboot::PerbootDB::new()),
+            tombstone_retention: None,
+            journal_mode: JournalMode::Delete,
+        };
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            Self::init_tables(tx).context("Trying to initialize tables.").no_gc()
+        })?;
+        Ok(db)
+    }
+
     // This upgrade function deletes all MAX_BOOT_LEVEL keys, that were generated before
     // cryptographic binding to the boot level keys was implemented.
     fn from_0_to_1(tx: &Transaction) -> Result<u32> {
@@ -1001,6 +1775,16 @@ impl KeystoreDB {
         )
         .context("Failed to create index keymetadata_keyentryid_index.")?;
 
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.keycustommetadata (
+                     keyentryid INTEGER,
+                     name TEXT,
+                     value BLOB,
+                     UNIQUE (keyentryid, name));",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"keycustommetadata\" table.")?;
+
         tx.execute(
             "CREATE TABLE IF NOT EXISTS persistent.grant (
                     id INTEGER UNIQUE,
@@ -1011,9 +1795,111 @@ impl KeystoreDB {
         )
         .context("Failed to initialize \"grant\" table.")?;
 
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.keyid_audit (
+                    key_id INTEGER,
+                    allocation_time INTEGER,
+                    caller_uid INTEGER);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"keyid_audit\" table.")?;
+
+        // The audit log is append-only, except that entries beyond the retained ones may be purged.
+        // Entries outlive the key entries they refer to.
+        tx.execute(
+            "CREATE TRIGGER IF NOT EXISTS persistent.keyid_audit_no_update
+            BEFORE UPDATE ON keyid_audit
+            BEGIN
+                SELECT RAISE(ABORT, 'keyid_audit is append-only');
+            END;",
+            NO_PARAMS,
+        )
+        .context("Failed to create trigger keyid_audit_no_update.")?;
+
+        tx.execute("DROP TRIGGER IF EXISTS persistent.keyid_audit_no_delete;", NO_PARAMS)
+            .context("Failed to drop trigger keyid_audit_no_delete.")?;
+        tx.execute(
+            &format!(
+                "CREATE TRIGGER IF NOT EXISTS persistent.keyid_audit_retention
+                BEFORE DELETE ON keyid_audit
+                WHEN old.rowid > (SELECT MAX(rowid) FROM keyid_audit) - {}
+                BEGIN
+                    SELECT RAISE(ABORT, 'keyid_audit is append-only');
+                END;",
+                Self::KEY_ID_AUDIT_RETAINED_ENTRIES
+            ),
+            NO_PARAMS,
+        )
+        .context("Failed to create trigger keyid_audit_retention.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.keytombstone (
+                    key_id INTEGER,
+                    deletion_time INTEGER,
+                    caller_uid INTEGER);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"keytombstone\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.noncecounter (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    next INTEGER NOT NULL);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"noncecounter\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.operationcheckpoint (
+                    id INTEGER UNIQUE,
+                    owner INTEGER,
+                    keyentryid INTEGER,
+                    km_uuid BLOB,
+                    params BLOB,
+                    input_len INTEGER,
+                    creation_time INTEGER);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"operationcheckpoint\" table.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.operationcheckpointinput (
+                    id INTEGER PRIMARY KEY,
+                    checkpointid INTEGER,
+                    ciphertext BLOB,
+                    iv BLOB,
+                    tag BLOB);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"operationcheckpointinput\" table.")?;
+
+        tx.execute(
+            "CREATE INDEX IF NOT EXISTS persistent.operationcheckpointinput_checkpointid_index
+            ON operationcheckpointinput(checkpointid);",
+            NO_PARAMS,
+        )
+        .context("Failed to create index operationcheckpointinput_checkpointid_index.")?;
+
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS persistent.keymigration (
+                    keyentryid INTEGER PRIMARY KEY,
+                    staged_keyentryid INTEGER NOT NULL);",
+            NO_PARAMS,
+        )
+        .context("Failed to initialize \"keymigration\" table.")?;
+
         Ok(())
     }
 
+    fn read_tombstone_retention() -> Option<Duration> {
+        Some(read_prop_duration(Self::TOMBSTONE_RETENTION_PROPERTY, Duration::ZERO))
+            .filter(|retention| !retention.is_zero())
+    }
+
+    fn tombstones_enabled(&self) -> bool {
+        self.tombstone_retention.is_some()
+    }
+
     fn make_persistent_path(db_root: &Path) -> Result<String> {
         // Build the path to the sqlite file.
         let mut persistent_path = db_root.to_path_buf();
@@ -1026,7 +1912,7 @@ impl KeystoreDB {
         Ok(persistent_path_str)
     }
 
-    fn make_connection(persistent_file: &str) -> Result<Connection> {
+    fn make_connection(persistent_file: &str, journal_mode: JournalMode) -> Result<Connection> {
         let conn =
             Connection::open_in_memory().context("Failed to initialize SQLite connection.")?;
 
@@ -1049,15 +1935,70 @@ impl KeystoreDB {
         conn.execute("PRAGMA persistent.cache_size = -500;", params![])
             .context("Failed to decrease cache size for persistent db")?;
 
+        // Setting secure_delete returns the new value, so it cannot be executed.
+        let secure_delete = read_prop_bool(Self::SECURE_DELETE_PROPERTY, true);
+        conn.query_row(
+            &format!("PRAGMA persistent.secure_delete = {};", secure_delete as u8),
+            NO_PARAMS,
+            |_| Ok(()),
+        )
+        .context("Failed to configure secure delete for persistent db")?;
+
+        // Setting the journal mode returns the resulting mode. The database may fail to switch,
+        // e.g., out of WAL mode while another connection holds it open. It then keeps working in
+        // its previous mode, so this is not fatal.
+        match conn.query_row(
+            &format!("PRAGMA persistent.journal_mode = {};", journal_mode.pragma_value()),
+            NO_PARAMS,
+            |row| row.get::<_, String>(0),
+        ) {
+            Ok(mode) if mode.eq_ignore_ascii_case(journal_mode.pragma_value()) => {}
+            // In memory databases always report "memory".
+            Ok(mode) if mode == "memory" => {}
+            Ok(mode) => {
+                log::warn!(
+                    "Persistent db uses journal mode {} instead of {:?}.",
+                    mode,
+                    journal_mode
+                )
+            }
+            Err(e) => log::warn!("Failed to set journal mode {:?}: {:?}", journal_mode, e),
+        }
+
         Ok(conn)
     }
 
-    fn do_table_size_query(
-        &mut self,
-        storage_type: MetricsStorage,
-        query: &str,
-        params: &[&str],
-    ) -> Result<StorageStats> {
+    /// Returns the journal mode in which the persistent database was opened.
+    pub fn journal_mode(&self) -> JournalMode {
+        self.journal_mode
+    }
+
+    /// Copies the content of the write ahead log into the database file and truncates the log, so
+    /// that it does not grow without bounds. Does nothing unless the database was opened in WAL
+    /// mode. The garbage collector calls this at the beginning of each collection. Returns
+    /// the number of pages in the log that were checkpointed, or None if the checkpoint did not
+    /// complete because other connections were using the database.
+    pub fn checkpoint_wal(&mut self) -> Result<Option<i64>> {
+        let _wp = wd::watch_millis("KeystoreDB::checkpoint_wal", 500);
+
+        if self.journal_mode != JournalMode::Wal {
+            return Ok(Some(0));
+        }
+        let (busy, checkpointed): (i64, i64) = self
+            .conn
+            .query_row("PRAGMA persistent.wal_checkpoint(TRUNCATE);", NO_PARAMS, |row| {
+                Ok((row.get(0)?, row.get(2)?))
+            })
+            .context(ks_err!("Failed to checkpoint the write ahead log."))?;
+        Ok(if busy == 0 { Some(checkpointed) } else { None })
+    }
+
+    fn do_table_size_query(
+        &mut self,
+        storage_type: MetricsStorage,
+        query: &str,
+        params: &[&str],
+    ) -> Result<StorageStats> {
         let (total, unused) = self.with_transaction(TransactionBehavior::Deferred, |tx| {
             tx.query_row(query, params_from_iter(params), |row| Ok((row.get(0)?, row.get(1)?)))
                 .with_context(|| {
@@ -1155,11 +2096,13 @@ impl KeystoreDB {
     /// superseded key blobs that might need special handling by the garbage collector.
     /// If no further superseded blobs can be found it deletes all other superseded blobs that don't
     /// need special handling and returns None.
+    /// Each superseded blob is returned as `(blob_id, key_id, blob, blob_metadata)`, where
+    /// `key_id` is the id of the key entry that the blob belonged to.
     pub fn handle_next_superseded_blobs(
         &mut self,
         blob_ids_to_delete: &[i64],
         max_blobs: usize,
-    ) -> Result<Vec<(i64, Vec<u8>, BlobMetaData)>> {
+    ) -> Result<Vec<(i64, i64, Vec<u8>, BlobMetaData)>> {
         let _wp = wd::watch_millis("KeystoreDB::handle_next_superseded_blob", 500);
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             // Delete the given blobs.
@@ -1176,10 +2119,10 @@ impl KeystoreDB {
             Self::cleanup_unreferenced(tx).context("Trying to cleanup unreferenced.")?;
 
             // Find up to max_blobx more superseded key blobs, load their metadata and return it.
-            let result: Vec<(i64, Vec<u8>)> = {
+            let result: Vec<(i64, i64, Vec<u8>)> = {
                 let mut stmt = tx
                     .prepare(
-                        "SELECT id, blob FROM persistent.blobentry
+                        "SELECT id, keyentryid, blob FROM persistent.blobentry
                         WHERE subcomponent_type = ?
                         AND (
                             id NOT IN (
@@ -1199,20 +2142,20 @@ impl KeystoreDB {
                             SubComponentType::KEY_BLOB,
                             max_blobs as i64,
                         ],
-                        |row| Ok((row.get(0)?, row.get(1)?)),
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
                     )
                     .context("Trying to query superseded blob.")?;
 
-                rows.collect::<Result<Vec<(i64, Vec<u8>)>, rusqlite::Error>>()
+                rows.collect::<Result<Vec<(i64, i64, Vec<u8>)>, rusqlite::Error>>()
                     .context("Trying to extract superseded blobs.")?
             };
 
             let result = result
                 .into_iter()
-                .map(|(blob_id, blob)| {
-                    Ok((blob_id, blob, BlobMetaData::load_from_db(blob_id, tx)?))
+                .map(|(blob_id, key_id, blob)| {
+                    Ok((blob_id, key_id, blob, BlobMetaData::load_from_db(blob_id, tx)?))
                 })
-                .collect::<Result<Vec<(i64, Vec<u8>, BlobMetaData)>>>()
+                .collect::<Result<Vec<(i64, i64, Vec<u8>, BlobMetaData)>>>()
                 .context("Trying to load blob metadata.")?;
             if !result.is_empty() {
                 return Ok(result).no_gc();
@@ -1246,11 +2189,14 @@ impl KeystoreDB {
     /// Keystore crashed at some point during key generation. Callers may want to log such
     /// occurrences.
     /// Unlike with `mark_unreferenced`, we don't need to purge grants, because only keys that made
-    /// it to `KeyLifeCycle::Live` may have grants.
+    /// it to `KeyLifeCycle::Live` may have grants. Keys staged by `stage_key_backend` are
+    /// discarded as well.
     pub fn cleanup_leftovers(&mut self) -> Result<usize> {
         let _wp = wd::watch_millis("KeystoreDB::cleanup_leftovers", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute("DELETE FROM persistent.keymigration;", NO_PARAMS)
+                .context("Failed to discard staged keys.")?;
             tx.execute(
                 "UPDATE persistent.keyentry SET state = ? WHERE state = ?;",
                 params![KeyLifeCycle::Unreferenced, KeyLifeCycle::Existing],
@@ -1261,6 +2207,201 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
+    /// Finds blob entries whose key entry does not exist, live key entries without any blob
+    /// entry, and grants whose target key is gone. Such dangling references are not created by
+    /// keystore itself but may be left behind, e.g., by a partially restored database. If
+    /// `repair` is true, the key entries without blobs are marked unreferenced, the dangling
+    /// grants are deleted, and the garbage collector is notified, which deletes the blobs
+    /// without key entries, including their key material in KeyMint. The returned report
+    /// describes what was found before the repair.
+    pub fn check_consistency(&mut self, repair: bool) -> Result<ConsistencyReport> {
+        let _wp = wd::watch_millis("KeystoreDB::check_consistency", 500);
+
+        let tombstones = self.tombstones_enabled();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let query_ids = |sql: &str, params: &[&dyn ToSql]| -> Result<Vec<i64>> {
+                let mut stmt = tx.prepare(sql).context(ks_err!("Failed to prepare statement."))?;
+                let ids = stmt
+                    .query_map(params, |row| row.get(0))
+                    .context(ks_err!("Failed to query ids."))?
+                    .collect::<rusqlite::Result<Vec<i64>>>()
+                    .context(ks_err!("Failed to extract ids."))?;
+                Ok(ids)
+            };
+            let report = ConsistencyReport {
+                blobs_without_key: query_ids(
+                    "SELECT id FROM persistent.blobentry
+                    WHERE keyentryid NOT IN (SELECT id FROM persistent.keyentry)
+                    ORDER BY id;",
+                    &[],
+                )?,
+                keys_without_blob: query_ids(
+                    "SELECT id FROM persistent.keyentry
+                    WHERE state = ?
+                    AND id NOT IN (SELECT keyentryid FROM persistent.blobentry)
+                    ORDER BY id;",
+                    &[&KeyLifeCycle::Live],
+                )?,
+                grants_without_key: query_ids(
+                    &format!(
+                        "SELECT id FROM persistent.grant WHERE {} ORDER BY id;",
+                        Self::DANGLING_GRANT_CONDITION
+                    ),
+                    &[&KeyLifeCycle::Live],
+                )?,
+            };
+            if !repair || report == ConsistencyReport::default() {
+                return Ok(report).no_gc();
+            }
+            Self::delete_dangling_grants_internal(tx)?;
+            if report.blobs_without_key.is_empty() && report.keys_without_blob.is_empty() {
+                return Ok(report).no_gc();
+            }
+            for key_id in &report.keys_without_blob {
+                Self::mark_unreferenced(tx, *key_id, tombstones)
+                    .context(ks_err!("Failed to mark key {} unreferenced.", key_id))?;
+            }
+            Ok(report).need_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Selects the grants whose target key entry does not exist or is not live. The only
+    /// parameter is `KeyLifeCycle::Live`.
+    const DANGLING_GRANT_CONDITION: &'static str =
+        "keyentryid NOT IN (SELECT id FROM persistent.keyentry WHERE state = ?)";
+
+    fn delete_dangling_grants_internal(tx: &Transaction) -> Result<usize> {
+        tx.execute(
+            &format!("DELETE FROM persistent.grant WHERE {};", Self::DANGLING_GRANT_CONDITION),
+            params![KeyLifeCycle::Live],
+        )
+        .context(ks_err!("Failed to delete dangling grants."))
+    }
+
+    /// Deletes the grants whose target key entry does not exist or is not live, see
+    /// `check_consistency`. Returns the number of deleted grants.
+    pub fn delete_dangling_grants(&mut self) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_dangling_grants", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            Self::delete_dangling_grants_internal(tx).no_gc()
+        })
+    }
+
+    /// Takes a snapshot of the live client keys in `domain` and `namespace`, which must be
+    /// `Domain::APP` or `Domain::SELINUX`. Each key is summarized by a digest of its non-secret
+    /// state, see `KeyInventoryEntry`, so that snapshots taken at different times can be
+    /// compared with `KeyInventorySnapshot::diff`.
+    pub fn snapshot_key_inventory(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+    ) -> Result<KeyInventorySnapshot> {
+        let _wp = wd::watch_millis("KeystoreDB::snapshot_key_inventory", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id, alias FROM persistent.keyentry
+                     WHERE domain = ? AND namespace = ? AND alias IS NOT NULL
+                     AND state = ? AND key_type = ?
+                     ORDER BY alias ASC;",
+                )
+                .context(ks_err!("Failed to prepare statement."))?;
+            let keys = stmt
+                .query_map(
+                    params![domain.0, namespace, KeyLifeCycle::Live, KeyType::Client],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .context(ks_err!("Failed to query keys."))?
+                .collect::<rusqlite::Result<Vec<(i64, String)>>>()
+                .context(ks_err!("Failed to extract keys."))?;
+
+            let mut entries = Vec::with_capacity(keys.len());
+            for (key_id, alias) in keys {
+                let digest = Self::key_inventory_digest(tx, key_id)
+                    .context(ks_err!("Failed to summarize key {}.", key_id))?;
+                entries.push(KeyInventoryEntry { alias, key_id, digest });
+            }
+            Ok(KeyInventorySnapshot { entries }).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Hashes the rows that describe the key `key_id`, in a canonical order and encoding.
+    fn key_inventory_digest(tx: &Transaction, key_id: i64) -> Result<Vec<u8>> {
+        let queries: [(&str, &[&dyn ToSql]); 4] = [
+            (
+                "SELECT tag, data, security_level FROM persistent.keyparameter
+                 WHERE keyentryid = ? ORDER BY tag, security_level, data;",
+                &[&key_id],
+            ),
+            (
+                "SELECT tag, data FROM persistent.keymetadata
+                 WHERE keyentryid = ? ORDER BY tag;",
+                &[&key_id],
+            ),
+            (
+                "SELECT subcomponent_type, blob FROM persistent.blobentry
+                 WHERE id IN (SELECT MAX(id) FROM persistent.blobentry
+                     WHERE keyentryid = ? AND subcomponent_type IN (?, ?)
+                     GROUP BY subcomponent_type)
+                 ORDER BY subcomponent_type;",
+                &[&key_id, &SubComponentType::CERT, &SubComponentType::CERT_CHAIN],
+            ),
+            (
+                "SELECT tag, data FROM persistent.blobmetadata
+                 WHERE blobentryid = (SELECT MAX(id) FROM persistent.blobentry
+                     WHERE keyentryid = ? AND subcomponent_type = ?)
+                 ORDER BY tag;",
+                &[&key_id, &SubComponentType::KEY_BLOB],
+            ),
+        ];
+        let mut data = Vec::new();
+        for (sql, params) in queries {
+            let mut stmt = tx.prepare(sql).context(ks_err!("Failed to prepare statement."))?;
+            let column_count = stmt.column_count();
+            let mut rows = stmt.query(params).context(ks_err!("Failed to query."))?;
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                for i in 0..column_count {
+                    let value: Value = row.get(i).context("Failed to extract value.")?;
+                    Self::encode_inventory_value(&value, &mut data);
+                }
+                Ok(())
+            })
+            .context(ks_err!("Failed to extract rows."))?;
+            // Separates the tables, so that rows cannot shift from one table to another.
+            data.push(0xff);
+        }
+        keystore2_crypto::sha256(&data).context(ks_err!("Failed to hash the key."))
+    }
+
+    /// Appends a type prefixed, length prefixed encoding of `value` to `data`.
+    fn encode_inventory_value(value: &Value, data: &mut Vec<u8>) {
+        match value {
+            Value::Null => data.push(0),
+            Value::Integer(i) => {
+                data.push(1);
+                data.extend_from_slice(&i.to_be_bytes());
+            }
+            Value::Real(r) => {
+                data.push(2);
+                data.extend_from_slice(&r.to_bits().to_be_bytes());
+            }
+            Value::Text(t) => {
+                data.push(3);
+                data.extend_from_slice(&(t.len() as u64).to_be_bytes());
+                data.extend_from_slice(t.as_bytes());
+            }
+            Value::Blob(b) => {
+                data.push(4);
+                data.extend_from_slice(&(b.len() as u64).to_be_bytes());
+                data.extend_from_slice(b);
+            }
+        }
+    }
+
     /// Checks if a key exists with given key type and key descriptor properties.
     pub fn key_exists(
         &mut self,
@@ -1287,6 +2428,38 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
+    /// Checks if `key` exists and is accessible to `caller_uid`, without loading any of its
+    /// components. Like `load_key_entry`, it resolves the access tuple of the key and calls
+    /// `check_permission` with it, which should check at least the `GetInfo` permission. Returns
+    /// false if the key does not exist.
+    pub fn key_exists_for_caller(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::key_exists_for_caller", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            match Self::load_access_tuple(tx, key, key_type, caller_uid) {
+                Ok((_, access_key_descriptor, access_vector)) => {
+                    // Perform access control. It is vital that we return here if the permission
+                    // is denied. So do not touch that '?' at the end.
+                    check_permission(&access_key_descriptor, access_vector)
+                        .context("While checking permission.")?;
+                    Ok(true)
+                }
+                Err(error) => match error.root_cause().downcast_ref::<KsError>() {
+                    Some(KsError::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(false),
+                    _ => Err(error).context(ks_err!("Failed to get the access tuple.")),
+                },
+            }
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+
     /// Stores a super key in the database.
     pub fn store_super_key(
         &mut self,
@@ -1316,6 +2489,7 @@ impl KeystoreDB {
                 )
             })
             .context("Failed to insert into keyentry table.")?;
+            Self::audit_key_id_allocation(tx, key_id).context(ks_err!())?;
 
             key_metadata.store_in_db(key_id, tx).context("KeyMetaData::store_in_db failed")?;
 
@@ -1355,7 +2529,7 @@ impl KeystoreDB {
                 Ok(id) => {
                     let key_entry = Self::load_key_components(tx, KeyEntryLoadBits::KM, id)
                         .context(ks_err!("Failed to load key entry."))?;
-                    Ok(Some((KEY_ID_LOCK.get(id), key_entry)))
+                    Ok(Some((KEY_ID_LOCK.get(id, KeyIdLockOperation::LoadSuperKey), key_entry)))
                 }
                 Err(error) => match error.root_cause().downcast_ref::<KsError>() {
                     Some(KsError::Rc(ResponseCode::KEY_NOT_FOUND)) => Ok(None),
@@ -1367,6 +2541,138 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
+    /// Replaces the key blobs of the given super keys of `user_id` in a single transaction,
+    /// e.g., after they were re-encrypted. If one of the super keys does not exist, no blob is
+    /// replaced.
+    pub fn replace_super_key_blobs(
+        &mut self,
+        user_id: u32,
+        blobs: &[(&SuperKeyType, Vec<u8>, BlobMetaData)],
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::replace_super_key_blobs", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            for (key_type, blob, blob_metadata) in blobs {
+                let key_descriptor = KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: user_id as i64,
+                    alias: Some(key_type.alias.into()),
+                    blob: None,
+                };
+                let key_id = Self::load_key_entry_id(tx, &key_descriptor, KeyType::Super)
+                    .context(ks_err!("Failed to find super key {}.", key_type.alias))?;
+                Self::set_blob_internal(
+                    tx,
+                    key_id,
+                    SubComponentType::KEY_BLOB,
+                    Some(blob),
+                    Some(blob_metadata),
+                )
+                .context(ks_err!("Failed to store key blob."))?;
+            }
+            Ok(()).need_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns the ids of the live keys whose current key blob is wrapped with version
+    /// `super_key_version` of the super key `super_key_id`, in ascending order. The number of
+    /// ids is the number of keys that depend on this super key version, e.g., before it is
+    /// rotated. Blobs without a recorded super key version count as version 0.
+    pub fn get_keys_wrapped_by_super_key(
+        &mut self,
+        super_key_id: i64,
+        super_key_version: i32,
+    ) -> Result<Vec<i64>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_keys_wrapped_by_super_key", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT blobentry.keyentryid FROM persistent.blobentry
+                    WHERE blobentry.id IN (
+                        SELECT MAX(id) FROM persistent.blobentry
+                        WHERE subcomponent_type = ?
+                        GROUP BY keyentryid)
+                    AND blobentry.keyentryid IN (
+                        SELECT id FROM persistent.keyentry WHERE state = ?)
+                    AND blobentry.id IN (
+                        SELECT blobentryid FROM persistent.blobmetadata
+                        WHERE tag = ? AND data = ?)
+                    AND COALESCE((
+                        SELECT data FROM persistent.blobmetadata
+                        WHERE blobentryid = blobentry.id AND tag = ?), 0) = ?
+                    ORDER BY blobentry.keyentryid;",
+                )
+                .context(ks_err!("Failed to prepare statement."))?;
+            let key_ids = stmt
+                .query_map(
+                    params![
+                        SubComponentType::KEY_BLOB,
+                        KeyLifeCycle::Live,
+                        BlobMetaData::EncryptedBy,
+                        super_key_id,
+                        BlobMetaData::SuperKeyVersion,
+                        super_key_version
+                    ],
+                    |row| row.get(0),
+                )
+                .context(ks_err!("Failed to query keys."))?
+                .collect::<rusqlite::Result<Vec<i64>>>()
+                .context(ks_err!("Failed to extract key ids."))?;
+            Ok(key_ids).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns the super key that protects the current key blob of the key `key_id` according
+    /// to its blob metadata. No key material is loaded. Blobs without a recorded super key
+    /// version are protected by version 0. Fails with `ResponseCode::KEY_NOT_FOUND` if the key
+    /// has no key blob.
+    pub fn get_super_key_identity(&mut self, key_id: i64) -> Result<SuperKeyIdentity> {
+        let _wp = wd::watch_millis("KeystoreDB::get_super_key_identity", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let blob_id: Option<i64> = tx
+                .query_row(
+                    "SELECT MAX(id) FROM persistent.blobentry
+                    WHERE keyentryid = ? AND subcomponent_type = ?;",
+                    params![key_id, SubComponentType::KEY_BLOB],
+                    |row| row.get(0),
+                )
+                .context(ks_err!("Failed to query the key blob."))?;
+            let blob_id = blob_id
+                .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context(ks_err!("Key {} has no key blob.", key_id))?;
+            let metadata = BlobMetaData::load_from_db(blob_id, tx)
+                .context(ks_err!("Failed to load the blob metadata."))?;
+            let identity = match (metadata.encrypted_by(), metadata.max_boot_level()) {
+                (Some(EncryptedBy::KeyId(super_key_id)), _) => {
+                    let alias: String = tx
+                        .query_row(
+                            "SELECT alias FROM persistent.keyentry WHERE id = ? AND key_type = ?;",
+                            params![super_key_id, KeyType::Super],
+                            |row| row.get(0),
+                        )
+                        .optional()
+                        .context(ks_err!("Failed to query the super key."))?
+                        .ok_or_else(KsError::sys)
+                        .context(ks_err!("Super key {} does not exist.", super_key_id))?;
+                    SuperKeyIdentity::SuperKey {
+                        alias,
+                        key_id: *super_key_id,
+                        version: metadata.super_key_version().copied().unwrap_or(0),
+                    }
+                }
+                (Some(EncryptedBy::Password), _) => SuperKeyIdentity::Password,
+                (None, Some(boot_level)) => SuperKeyIdentity::BootLevel(*boot_level),
+                (None, None) => SuperKeyIdentity::None,
+            };
+            Ok(identity).no_gc()
+        })
+        .context(ks_err!())
+    }
+
     /// Atomically loads a key entry and associated metadata or creates it using the
     /// callback create_new_key callback. The callback is called during a database
     /// transaction. This means that implementers should be mindful about using
@@ -1434,6 +2740,7 @@ impl KeystoreDB {
                         )
                     })
                     .context(ks_err!())?;
+                    Self::audit_key_id_allocation(tx, id).context(ks_err!())?;
 
                     let (blob, metadata) = create_new_key().context(ks_err!())?;
                     Self::set_blob_internal(
@@ -1455,19 +2762,25 @@ impl KeystoreDB {
                     )
                 }
             };
-            Ok((KEY_ID_LOCK.get(id), entry)).no_gc()
+            Ok((KEY_ID_LOCK.get(id, KeyIdLockOperation::CreateSuperKey), entry)).no_gc()
         })
         .context(ks_err!())
     }
 
     /// Creates a transaction with the given behavior and executes f with the new transaction.
     /// The transaction is committed only if f returns Ok and retried if DatabaseBusy
-    /// or DatabaseLocked is encountered.
+    /// or DatabaseLocked is encountered. Retries back off exponentially, and the transaction
+    /// fails after `MAX_TRANSACTION_ATTEMPTS` attempts. A failed attempt is rolled back before
+    /// it is retried, so f must not have side effects outside of the transaction. Transactions
+    /// that had to be retried are logged as database contention metrics.
     fn with_transaction<T, F>(&mut self, behavior: TransactionBehavior, f: F) -> Result<T>
     where
         F: Fn(&Transaction) -> Result<(bool, T)>,
     {
+        let mut attempts = 0;
+        let mut delay = Self::TRANSACTION_RETRY_INITIAL_DELAY;
         loop {
+            attempts += 1;
             match self
                 .conn
                 .transaction_with_behavior(behavior)
@@ -1477,14 +2790,23 @@ impl KeystoreDB {
                     tx.commit().context(ks_err!("Failed to commit transaction."))?;
                     Ok(result)
                 }) {
-                Ok(result) => break Ok(result),
+                Ok(result) => {
+                    if attempts > 1 {
+                        log_database_contention(false);
+                    }
+                    break Ok(result);
+                }
                 Err(e) => {
-                    if Self::is_locked_error(&e) {
-                        std::thread::sleep(std::time::Duration::from_micros(500));
-                        continue;
-                    } else {
+                    if !Self::is_locked_error(&e) {
                         return Err(e).context(ks_err!());
                     }
+                    if attempts >= Self::MAX_TRANSACTION_ATTEMPTS {
+                        log_database_contention(true);
+                        return Err(e)
+                            .context(ks_err!("Database still busy after {} attempts.", attempts));
+                    }
+                    std::thread::sleep(delay);
+                    delay = std::cmp::min(delay * 2, Self::TRANSACTION_RETRY_MAX_DELAY);
                 }
             }
         }
@@ -1541,512 +2863,1733 @@ impl KeystoreDB {
                     .context(ks_err!("Domain {:?} must be either App or SELinux.", domain));
             }
         }
-        Ok(KEY_ID_LOCK.get(
-            Self::insert_with_retry(|id| {
-                tx.execute(
-                    "INSERT into persistent.keyentry
+        let key_id = Self::insert_with_retry(|id| {
+            tx.execute(
+                "INSERT into persistent.keyentry
                      (id, key_type, domain, namespace, alias, state, km_uuid)
                      VALUES(?, ?, ?, ?, NULL, ?, ?);",
-                    params![
-                        id,
-                        key_type,
-                        domain.0 as u32,
-                        *namespace,
-                        KeyLifeCycle::Existing,
-                        km_uuid,
-                    ],
-                )
-            })
-            .context(ks_err!())?,
-        ))
+                params![
+                    id,
+                    key_type,
+                    domain.0 as u32,
+                    *namespace,
+                    KeyLifeCycle::Existing,
+                    km_uuid,
+                ],
+            )
+        })
+        .context(ks_err!())?;
+        Self::audit_key_id_allocation(tx, key_id).context(ks_err!())?;
+        Ok(KEY_ID_LOCK.get(key_id, KeyIdLockOperation::CreateKeyEntry))
     }
 
-    /// Creates a new attestation key entry and allocates a new randomized id for the new key.
-    /// The key id gets associated with a domain and namespace later but not with an alias. The
-    /// alias will be used to denote if a key has been signed as each key can only be bound to one
-    /// domain and namespace pairing so there is no need to use them as a value for indexing into
-    /// a key.
-    pub fn create_attestation_key_entry(
-        &mut self,
-        maced_public_key: &[u8],
-        raw_public_key: &[u8],
-        private_key: &[u8],
-        km_uuid: &Uuid,
-    ) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::create_attestation_key_entry", 500);
+    /// Appends an entry for the newly allocated `key_id` to the key id allocation audit log.
+    /// This must be called in the same transaction that inserted the key entry, so that the
+    /// audit log cannot diverge from the allocations.
+    fn audit_key_id_allocation(tx: &Transaction, key_id: i64) -> Result<()> {
+        tx.execute(
+            "INSERT INTO persistent.keyid_audit (key_id, allocation_time, caller_uid)
+             VALUES (?, ?, ?);",
+            params![
+                key_id,
+                DateTime::now().context(ks_err!("Trying to make allocation time."))?,
+                ThreadState::get_calling_uid(),
+            ],
+        )
+        .context(ks_err!("Failed to insert into keyid_audit table."))?;
+        Ok(())
+    }
+
+    /// Deletes the entries of the key id allocation audit log that precede the
+    /// `KEY_ID_AUDIT_RETAINED_ENTRIES` most recent ones. Returns the number of deleted entries.
+    pub fn purge_key_id_allocations(&mut self) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::purge_key_id_allocations", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let key_id = KEY_ID_LOCK.get(
-                Self::insert_with_retry(|id| {
-                    tx.execute(
-                        "INSERT into persistent.keyentry
-                            (id, key_type, domain, namespace, alias, state, km_uuid)
-                            VALUES(?, ?, NULL, NULL, NULL, ?, ?);",
-                        params![id, KeyType::Attestation, KeyLifeCycle::Live, km_uuid],
-                    )
-                })
-                .context(ks_err!())?,
-            );
-            Self::set_blob_internal(
-                tx,
-                key_id.0,
-                SubComponentType::KEY_BLOB,
-                Some(private_key),
-                None,
-            )?;
-            let mut metadata = KeyMetaData::new();
-            metadata.add(KeyMetaEntry::AttestationMacedPublicKey(maced_public_key.to_vec()));
-            metadata.add(KeyMetaEntry::AttestationRawPubKey(raw_public_key.to_vec()));
-            metadata.store_in_db(key_id.0, tx)?;
-            Ok(()).no_gc()
+            tx.execute(
+                "DELETE FROM persistent.keyid_audit
+                 WHERE rowid <= (SELECT MAX(rowid) FROM persistent.keyid_audit) - ?;",
+                params![Self::KEY_ID_AUDIT_RETAINED_ENTRIES],
+            )
+            .context(ks_err!("Failed to purge key id allocations."))
+            .no_gc()
         })
-        .context(ks_err!())
     }
 
-    /// Set a new blob and associates it with the given key id. Each blob
-    /// has a sub component type.
-    /// Each key can have one of each sub component type associated. If more
-    /// are added only the most recent can be retrieved, and superseded blobs
-    /// will get garbage collected.
-    /// Components SubComponentType::CERT and SubComponentType::CERT_CHAIN can be
-    /// removed by setting blob to None.
-    pub fn set_blob(
-        &mut self,
-        key_id: &KeyIdGuard,
-        sc_type: SubComponentType,
-        blob: Option<&[u8]>,
-        blob_metadata: Option<&BlobMetaData>,
-    ) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::set_blob", 500);
+    /// Returns the tombstones of deleted keys in order of deletion.
+    pub fn get_key_tombstones(&mut self) -> Result<Vec<KeyTombstone>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_key_tombstones", 500);
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            Self::set_blob_internal(tx, key_id.0, sc_type, blob, blob_metadata).need_gc()
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT key_id, deletion_time, caller_uid FROM persistent.keytombstone
+                     ORDER BY rowid;",
+                )
+                .context(ks_err!("Failed to prepare statement."))?;
+            let tombstones = stmt
+                .query_map(NO_PARAMS, |row| {
+                    Ok(KeyTombstone {
+                        key_id: row.get(0)?,
+                        deletion_time: row.get(1)?,
+                        caller_uid: row.get(2)?,
+                    })
+                })
+                .context(ks_err!("Failed to query."))?
+                .collect::<rusqlite::Result<Vec<KeyTombstone>>>()
+                .context(ks_err!("Failed to extract tombstones."))?;
+            Ok(tombstones).no_gc()
         })
-        .context(ks_err!())
     }
 
-    /// Why would we insert a deleted blob? This weird function is for the purpose of legacy
-    /// key migration in the case where we bulk delete all the keys of an app or even a user.
-    /// We use this to insert key blobs into the database which can then be garbage collected
-    /// lazily by the key garbage collector.
-    pub fn set_deleted_blob(&mut self, blob: &[u8], blob_metadata: &BlobMetaData) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::set_deleted_blob", 500);
+    /// Deletes the tombstones that are older than the retention period as of `now`. If
+    /// tombstones are disabled, all remaining tombstones are deleted. Returns the number of
+    /// deleted tombstones.
+    pub fn purge_expired_tombstones(&mut self, now: DateTime) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::purge_expired_tombstones", 500);
 
+        let cutoff = match self.tombstone_retention {
+            Some(retention) => DateTime::from_millis_epoch(
+                now.to_millis_epoch().saturating_sub(retention.as_millis() as i64),
+            ),
+            None => DateTime::from_millis_epoch(i64::MAX),
+        };
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            Self::set_blob_internal(
-                tx,
-                Self::UNASSIGNED_KEY_ID,
-                SubComponentType::KEY_BLOB,
-                Some(blob),
-                Some(blob_metadata),
+            tx.execute(
+                "DELETE FROM persistent.keytombstone WHERE deletion_time < ?;",
+                params![cutoff],
             )
-            .need_gc()
+            .context(ks_err!("Failed to delete expired tombstones."))
+            .no_gc()
         })
-        .context(ks_err!())
     }
 
-    fn set_blob_internal(
-        tx: &Transaction,
+    /// Stores the checkpoint of the new resumable operation `id` of `owner` on the key `key_id`,
+    /// which was created with `params` at `creation_time`. The checkpoint has no input yet.
+    /// Fails if there already is a checkpoint with this id.
+    pub fn insert_operation_checkpoint(
+        &mut self,
+        id: i64,
+        owner: u32,
         key_id: i64,
-        sc_type: SubComponentType,
-        blob: Option<&[u8]>,
-        blob_metadata: Option<&BlobMetaData>,
+        km_uuid: &Uuid,
+        params: &[KmKeyParameter],
+        creation_time: DateTime,
     ) -> Result<()> {
-        match (blob, sc_type) {
-            (Some(blob), _) => {
-                tx.execute(
-                    "INSERT INTO persistent.blobentry
-                     (subcomponent_type, keyentryid, blob) VALUES (?, ?, ?);",
-                    params![sc_type, key_id, blob],
+        let _wp = wd::watch_millis("KeystoreDB::insert_operation_checkpoint", 500);
+
+        let values: Vec<KsKeyParamValue> = params.iter().map(KsKeyParamValue::from).collect();
+        let encoded_params =
+            serde_cbor::to_vec(&values).context(ks_err!("Failed to encode parameters."))?;
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "INSERT INTO persistent.operationcheckpoint
+                 (id, owner, keyentryid, km_uuid, params, input_len, creation_time)
+                 VALUES (?, ?, ?, ?, ?, 0, ?);",
+                params![id, owner, key_id, km_uuid, encoded_params, creation_time],
+            )
+            .context(ks_err!("Failed to insert checkpoint."))
+            .map(|_| ())
+            .no_gc()
+        })
+    }
+
+    /// Appends the next chunk of the input of the operation checkpoint `id`, which is `len`
+    /// bytes long and encrypted as `ciphertext` with `iv` and `tag`. Returns the length of the
+    /// input of the checkpoint, or None if there is no checkpoint with this id.
+    pub fn append_operation_checkpoint_input(
+        &mut self,
+        id: i64,
+        ciphertext: &[u8],
+        iv: &[u8],
+        tag: &[u8],
+        len: usize,
+    ) -> Result<Option<usize>> {
+        let _wp = wd::watch_millis("KeystoreDB::append_operation_checkpoint_input", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let stored_len: Option<i64> = tx
+                .query_row(
+                    "SELECT input_len FROM persistent.operationcheckpoint WHERE id = ?;",
+                    params![id],
+                    |row| row.get(0),
                 )
-                .context(ks_err!("Failed to insert blob."))?;
-                if let Some(blob_metadata) = blob_metadata {
-                    let blob_id = tx
-                        .query_row("SELECT MAX(id) FROM persistent.blobentry;", NO_PARAMS, |row| {
-                            row.get(0)
-                        })
-                        .context(ks_err!("Failed to get new blob id."))?;
-                    blob_metadata
-                        .store_in_db(blob_id, tx)
-                        .context(ks_err!("Trying to store blob metadata."))?;
-                }
-            }
-            (None, SubComponentType::CERT) | (None, SubComponentType::CERT_CHAIN) => {
-                tx.execute(
-                    "DELETE FROM persistent.blobentry
-                    WHERE subcomponent_type = ? AND keyentryid = ?;",
-                    params![sc_type, key_id],
+                .optional()
+                .context(ks_err!("Failed to load input length."))?;
+            let input_len = match stored_len {
+                Some(stored_len) => stored_len as usize + len,
+                None => return Ok(None).no_gc(),
+            };
+            tx.execute(
+                "INSERT INTO persistent.operationcheckpointinput (checkpointid, ciphertext, iv, tag)
+                 VALUES (?, ?, ?, ?);",
+                params![id, ciphertext, iv, tag],
+            )
+            .context(ks_err!("Failed to insert input."))?;
+            tx.execute(
+                "UPDATE persistent.operationcheckpoint SET input_len = ? WHERE id = ?;",
+                params![input_len as i64, id],
+            )
+            .context(ks_err!("Failed to update input length."))?;
+            Ok(Some(input_len)).no_gc()
+        })
+    }
+
+    /// Removes the operation checkpoint `id` of `owner` from the database and returns it.
+    /// Returns None if there is no such checkpoint. Checkpoints of other owners are not
+    /// touched.
+    pub fn take_operation_checkpoint(
+        &mut self,
+        id: i64,
+        owner: u32,
+    ) -> Result<Option<OperationCheckpoint>> {
+        let _wp = wd::watch_millis("KeystoreDB::take_operation_checkpoint", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let row = tx
+                .query_row(
+                    "SELECT keyentryid, km_uuid, params, creation_time
+                     FROM persistent.operationcheckpoint WHERE id = ? AND owner = ?;",
+                    params![id, owner],
+                    |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, Uuid>(1)?,
+                            row.get::<_, Vec<u8>>(2)?,
+                            row.get::<_, DateTime>(3)?,
+                        ))
+                    },
                 )
-                .context(ks_err!("Failed to delete blob."))?;
-            }
-            (None, _) => {
-                return Err(KsError::sys())
-                    .context(ks_err!("Other blobs cannot be deleted in this way."));
+                .optional()
+                .context(ks_err!("Failed to load checkpoint."))?;
+            let (key_id, km_uuid, encoded_params, creation_time) = match row {
+                Some(row) => row,
+                None => return Ok(None).no_gc(),
+            };
+            let mut input = Vec::new();
+            {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT ciphertext, iv, tag FROM persistent.operationcheckpointinput
+                         WHERE checkpointid = ? ORDER BY id;",
+                    )
+                    .context(ks_err!("Failed to prepare statement."))?;
+                let mut rows =
+                    stmt.query(params![id]).context(ks_err!("Failed to query input."))?;
+                db_utils::with_rows_extract_all(&mut rows, |row| {
+                    input.push((
+                        row.get(0).context("Failed to read ciphertext.")?,
+                        row.get(1).context("Failed to read iv.")?,
+                        row.get(2).context("Failed to read tag.")?,
+                    ));
+                    Ok(())
+                })
+                .context(ks_err!())?;
             }
-        }
-        Ok(())
+            Self::delete_operation_checkpoint_internal(tx, id).context(ks_err!())?;
+            let values: Vec<KsKeyParamValue> = serde_cbor::from_slice(&encoded_params)
+                .context(ks_err!("Failed to decode parameters."))?;
+            Ok(Some(OperationCheckpoint {
+                id,
+                owner,
+                key_id,
+                km_uuid,
+                params: values.into_iter().map(KmKeyParameter::from).collect(),
+                input,
+                creation_time,
+            }))
+            .no_gc()
+        })
     }
 
-    /// Inserts a collection of key parameters into the `persistent.keyparameter` table
-    /// and associates them with the given `key_id`.
-    #[cfg(test)]
-    fn insert_keyparameter(&mut self, key_id: &KeyIdGuard, params: &[KeyParameter]) -> Result<()> {
+    /// Deletes the operation checkpoint `id`, if any.
+    pub fn delete_operation_checkpoint(&mut self, id: i64) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_operation_checkpoint", 500);
+
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            Self::insert_keyparameter_internal(tx, key_id, params).no_gc()
+            Self::delete_operation_checkpoint_internal(tx, id).context(ks_err!()).no_gc()
         })
-        .context(ks_err!())
     }
 
-    fn insert_keyparameter_internal(
-        tx: &Transaction,
-        key_id: &KeyIdGuard,
-        params: &[KeyParameter],
-    ) -> Result<()> {
-        let mut stmt = tx
-            .prepare(
-                "INSERT into persistent.keyparameter (keyentryid, tag, data, security_level)
-                VALUES (?, ?, ?, ?);",
-            )
-            .context(ks_err!("Failed to prepare statement."))?;
-
-        for p in params.iter() {
-            stmt.insert(params![
-                key_id.0,
-                p.get_tag().0,
-                p.key_parameter_value(),
-                p.security_level().0
-            ])
-            .with_context(|| ks_err!("Failed to insert {:?}", p))?;
-        }
+    fn delete_operation_checkpoint_internal(tx: &Transaction, id: i64) -> Result<()> {
+        tx.execute(
+            "DELETE FROM persistent.operationcheckpointinput WHERE checkpointid = ?;",
+            params![id],
+        )
+        .context("Failed to delete checkpoint input.")?;
+        tx.execute("DELETE FROM persistent.operationcheckpoint WHERE id = ?;", params![id])
+            .context("Failed to delete checkpoint.")?;
         Ok(())
     }
 
-    /// Insert a set of key entry specific metadata into the database.
-    #[cfg(test)]
-    fn insert_key_metadata(&mut self, key_id: &KeyIdGuard, metadata: &KeyMetaData) -> Result<()> {
+    /// Deletes the operation checkpoints that were created before `cutoff`. Returns the number
+    /// of deleted checkpoints.
+    pub fn purge_expired_operation_checkpoints(&mut self, cutoff: DateTime) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::purge_expired_operation_checkpoints", 500);
+
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            metadata.store_in_db(key_id.0, tx).no_gc()
+            tx.execute(
+                "DELETE FROM persistent.operationcheckpointinput WHERE checkpointid IN
+                     (SELECT id FROM persistent.operationcheckpoint WHERE creation_time < ?);",
+                params![cutoff],
+            )
+            .context(ks_err!("Failed to delete the input of expired checkpoints."))?;
+            tx.execute(
+                "DELETE FROM persistent.operationcheckpoint WHERE creation_time < ?;",
+                params![cutoff],
+            )
+            .context(ks_err!("Failed to delete expired checkpoints."))
+            .no_gc()
         })
-        .context(ks_err!())
     }
 
-    /// Stores a signed certificate chain signed by a remote provisioning server, keyed
-    /// on the public key.
-    pub fn store_signed_attestation_certificate_chain(
-        &mut self,
-        raw_public_key: &[u8],
-        batch_cert: &[u8],
-        cert_chain: &[u8],
-        expiration_date: i64,
-        km_uuid: &Uuid,
-    ) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::store_signed_attestation_certificate_chain", 500);
+    /// Reserves `count` consecutive values of the persistent nonce counter and returns the
+    /// first of them. The reservation is committed before this function returns, so the values
+    /// are never handed out again, even across reboots. Fails with `ResponseCode::SYSTEM_ERROR`
+    /// if the counter would overflow.
+    pub fn reserve_nonce_counter_range(&mut self, count: u64) -> Result<u64> {
+        let _wp = wd::watch_millis("KeystoreDB::reserve_nonce_counter_range", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let next: i64 = tx
+                .query_row(
+                    "SELECT next FROM persistent.noncecounter WHERE id = 0;",
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .optional()
+                .context(ks_err!("Failed to read the nonce counter."))?
+                .unwrap_or(0);
+            let end = i64::try_from(count)
+                .ok()
+                .and_then(|count| next.checked_add(count))
+                .ok_or(KsError::sys())
+                .context(ks_err!("The nonce counter is exhausted."))?;
+            tx.execute(
+                "INSERT OR REPLACE INTO persistent.noncecounter (id, next) VALUES (0, ?);",
+                params![end],
+            )
+            .context(ks_err!("Failed to update the nonce counter."))?;
+            Ok(next as u64).no_gc()
+        })
+    }
+
+    /// Returns the key id allocation audit log in order of allocation.
+    pub fn get_key_id_allocations(&mut self) -> Result<Vec<KeyIdAllocation>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_key_id_allocations", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
             let mut stmt = tx
                 .prepare(
-                    "SELECT keyentryid
-                    FROM persistent.keymetadata
-                    WHERE tag = ? AND data = ? AND keyentryid IN
-                    (SELECT id
-                     FROM persistent.keyentry
-                     WHERE
-                        alias IS NULL AND
-                        domain IS NULL AND
-                        namespace IS NULL AND
-                        key_type = ? AND
-                        km_uuid = ?);",
+                    "SELECT key_id, allocation_time, caller_uid FROM persistent.keyid_audit
+                     ORDER BY rowid;",
                 )
-                .context("Failed to store attestation certificate chain.")?;
-            let mut rows = stmt
-                .query(params![
-                    KeyMetaData::AttestationRawPubKey,
-                    raw_public_key,
-                    KeyType::Attestation,
-                    km_uuid
-                ])
-                .context("Failed to fetch keyid")?;
-            let key_id = db_utils::with_rows_extract_one(&mut rows, |row| {
-                row.map_or_else(|| Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND)), Ok)?
-                    .get(0)
-                    .context("Failed to unpack id.")
+                .context(ks_err!("Failed to prepare statement."))?;
+            let mut rows = stmt.query(NO_PARAMS).context(ks_err!("Failed to query."))?;
+            let mut allocations = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                allocations.push(KeyIdAllocation {
+                    key_id: row.get(0).context("Failed to read key_id.")?,
+                    allocation_time: row.get(1).context("Failed to read allocation_time.")?,
+                    caller_uid: row.get(2).context("Failed to read caller_uid.")?,
+                });
+                Ok(())
             })
-            .context("Failed to get key_id.")?;
-            let num_updated = tx
-                .execute(
-                    "UPDATE persistent.keyentry
-                    SET alias = ?
-                    WHERE id = ?;",
-                    params!["signed", key_id],
-                )
-                .context("Failed to update alias.")?;
-            if num_updated != 1 {
-                return Err(KsError::sys()).context("Alias not updated for the key.");
-            }
-            let mut metadata = KeyMetaData::new();
-            metadata.add(KeyMetaEntry::AttestationExpirationDate(DateTime::from_millis_epoch(
-                expiration_date,
-            )));
-            metadata.store_in_db(key_id, tx).context("Failed to insert key metadata.")?;
+            .context(ks_err!())?;
+            Ok(allocations).no_gc()
+        })
+    }
+
+    /// Creates a new attestation key entry and allocates a new randomized id for the new key.
+    /// The key id gets associated with a domain and namespace later but not with an alias. The
+    /// alias will be used to denote if a key has been signed as each key can only be bound to one
+    /// domain and namespace pairing so there is no need to use them as a value for indexing into
+    /// a key. The key is added to the named `pool` of the KeyMint instance, or to its default
+    /// pool if `pool` is None.
+    pub fn create_attestation_key_entry(
+        &mut self,
+        maced_public_key: &[u8],
+        raw_public_key: &[u8],
+        private_key: &[u8],
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::create_attestation_key_entry", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let key_id = KEY_ID_LOCK.get(
+                Self::insert_with_retry(|id| {
+                    tx.execute(
+                        "INSERT into persistent.keyentry
+                            (id, key_type, domain, namespace, alias, state, km_uuid)
+                            VALUES(?, ?, NULL, NULL, NULL, ?, ?);",
+                        params![id, KeyType::Attestation, KeyLifeCycle::Live, km_uuid],
+                    )
+                })
+                .context(ks_err!())?,
+                KeyIdLockOperation::StoreAttestationKey,
+            );
+            Self::audit_key_id_allocation(tx, key_id.id()).context(ks_err!())?;
             Self::set_blob_internal(
                 tx,
-                key_id,
-                SubComponentType::CERT_CHAIN,
-                Some(cert_chain),
+                key_id.id(),
+                SubComponentType::KEY_BLOB,
+                Some(private_key),
                 None,
-            )
-            .context("Failed to insert cert chain")?;
-            Self::set_blob_internal(tx, key_id, SubComponentType::CERT, Some(batch_cert), None)
-                .context("Failed to insert cert")?;
+            )?;
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::AttestationMacedPublicKey(maced_public_key.to_vec()));
+            metadata.add(KeyMetaEntry::AttestationRawPubKey(raw_public_key.to_vec()));
+            if let Some(pool) = pool {
+                metadata.add(KeyMetaEntry::AttestationKeyPool(pool.to_string()));
+            }
+            metadata.store_in_db(key_id.id(), tx)?;
             Ok(()).no_gc()
         })
         .context(ks_err!())
     }
 
-    /// Assigns the next unassigned attestation key to a domain/namespace combo that does not
-    /// currently have a key assigned to it.
-    pub fn assign_attestation_key(
+    /// Set a new blob and associates it with the given key id. Each blob
+    /// has a sub component type.
+    /// Each key can have one of each sub component type associated. If more
+    /// are added only the most recent can be retrieved, and superseded blobs
+    /// will get garbage collected.
+    /// Components SubComponentType::CERT and SubComponentType::CERT_CHAIN can be
+    /// removed by setting blob to None.
+    pub fn set_blob(
         &mut self,
-        domain: Domain,
-        namespace: i64,
-        km_uuid: &Uuid,
+        key_id: &KeyIdGuard,
+        sc_type: SubComponentType,
+        blob: Option<&[u8]>,
+        blob_metadata: Option<&BlobMetaData>,
     ) -> Result<()> {
-        let _wp = wd::watch_millis("KeystoreDB::assign_attestation_key", 500);
+        let _wp = wd::watch_millis("KeystoreDB::set_blob", 500);
 
-        match domain {
-            Domain::APP | Domain::SELINUX => {}
-            _ => {
-                return Err(KsError::sys())
-                    .context(ks_err!("Domain {:?} must be either App or SELinux.", domain));
-            }
-        }
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let result = tx
-                .execute(
-                    "UPDATE persistent.keyentry
-                        SET domain=?1, namespace=?2
-                        WHERE
-                            id =
-                                (SELECT MIN(id)
-                                FROM persistent.keyentry
-                                WHERE ALIAS IS NOT NULL
-                                    AND domain IS NULL
-                                    AND key_type IS ?3
-                                    AND state IS ?4
-                                    AND km_uuid IS ?5)
-                            AND
-                                (SELECT COUNT(*)
-                                FROM persistent.keyentry
-                                WHERE domain=?1
-                                    AND namespace=?2
-                                    AND key_type IS ?3
-                                    AND state IS ?4
-                                    AND km_uuid IS ?5) = 0;",
-                    params![
-                        domain.0 as u32,
-                        namespace,
-                        KeyType::Attestation,
-                        KeyLifeCycle::Live,
-                        km_uuid,
-                    ],
-                )
-                .context("Failed to assign attestation key")?;
-            if result == 0 {
-                let (_, hw_info) = get_keymint_dev_by_uuid(km_uuid)
-                    .context("Error in retrieving keymint device by UUID.")?;
-                log_rkp_error_stats(MetricsRkpError::OUT_OF_KEYS, &hw_info.securityLevel);
-                return Err(KsError::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
-                    .context("Out of keys.");
-            } else if result > 1 {
-                return Err(KsError::sys())
-                    .context(format!("Expected to update 1 entry, instead updated {}", result));
-            }
-            Ok(()).no_gc()
+            Self::set_blob_internal(tx, key_id.id(), sc_type, blob, blob_metadata).need_gc()
         })
-        .context(ks_err!())
+        .context(ks_err!())?;
+        if sc_type == SubComponentType::CERT || sc_type == SubComponentType::CERT_CHAIN {
+            ATTESTATION_CERT_CACHE.invalidate(key_id.id());
+        }
+        Ok(())
     }
 
-    /// Retrieves num_keys number of attestation keys that have not yet been signed by a remote
-    /// provisioning server, or the maximum number available if there are not num_keys number of
-    /// entries in the table.
-    pub fn fetch_unsigned_attestation_keys(
+    /// Stages the move of the key entry whose lock is held by `key_id` to the KeyMint instance
+    /// `km_uuid`, with the key blob `blob_info`, the key parameters `params`, and the
+    /// certificates of `cert_info`. The key entry itself is not changed until the move is
+    /// committed with `commit_key_backend`. The staged key is kept in a key entry of its own
+    /// without an alias, which replaces an earlier staged key of the same key entry. Staged keys
+    /// that are not committed are discarded with the other leftovers when keystore restarts.
+    pub fn stage_key_backend(
         &mut self,
-        num_keys: i32,
+        key_id: &KeyIdGuard,
+        params: &[KeyParameter],
+        blob_info: &BlobInfo,
+        cert_info: &CertificateInfo,
         km_uuid: &Uuid,
-    ) -> Result<Vec<Vec<u8>>> {
-        let _wp = wd::watch_millis("KeystoreDB::fetch_unsigned_attestation_keys", 500);
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::stage_key_backend", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let mut stmt = tx
-                .prepare(
-                    "SELECT data
-                    FROM persistent.keymetadata
-                    WHERE tag = ? AND keyentryid IN
-                        (SELECT id
-                        FROM persistent.keyentry
-                        WHERE
-                            alias IS NULL AND
-                            domain IS NULL AND
-                            namespace IS NULL AND
-                            key_type = ? AND
-                            km_uuid = ?
-                        LIMIT ?);",
+            let (domain, namespace, key_type): (i32, i64, KeyType) = tx
+                .query_row(
+                    "SELECT domain, namespace, key_type FROM persistent.keyentry
+                     WHERE id = ? AND state = ?;",
+                    params![key_id.id(), KeyLifeCycle::Live],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
                 )
-                .context("Failed to prepare statement")?;
-            let rows = stmt
-                .query_map(
-                    params![
-                        KeyMetaData::AttestationMacedPublicKey,
-                        KeyType::Attestation,
-                        km_uuid,
-                        num_keys
-                    ],
-                    |row| row.get(0),
-                )?
-                .collect::<rusqlite::Result<Vec<Vec<u8>>>>()
-                .context("Failed to execute statement")?;
-            Ok(rows).no_gc()
+                .optional()
+                .context("Trying to load the key entry.")?
+                .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context(ks_err!("Key entry {} does not exist.", key_id.id()))?;
+            let discarded = Self::discard_staged_key_backend(tx, key_id.id())
+                .context("Trying to discard the previously staged key.")?;
+            let staged_id =
+                Self::create_key_entry_internal(tx, &Domain(domain), &namespace, key_type, km_uuid)
+                    .context("Trying to create the staged key entry.")?;
+            Self::set_blob_internal(
+                tx,
+                staged_id.id(),
+                SubComponentType::KEY_BLOB,
+                Some(blob_info.blob),
+                Some(blob_info.metadata),
+            )
+            .context("Trying to insert the key blob.")?;
+            Self::set_blob_internal(
+                tx,
+                staged_id.id(),
+                SubComponentType::CERT,
+                cert_info.cert.as_deref(),
+                None,
+            )
+            .context("Trying to insert the certificate.")?;
+            Self::set_blob_internal(
+                tx,
+                staged_id.id(),
+                SubComponentType::CERT_CHAIN,
+                cert_info.cert_chain.as_deref(),
+                None,
+            )
+            .context("Trying to insert the certificate chain.")?;
+            Self::insert_keyparameter_internal(tx, &staged_id, params)
+                .context("Trying to insert the key parameters.")?;
+            tx.execute(
+                "INSERT INTO persistent.keymigration (keyentryid, staged_keyentryid)
+                 VALUES (?, ?);",
+                params![key_id.id(), staged_id.id()],
+            )
+            .context("Trying to record the staged key.")?;
+            Ok(()).do_gc(discarded)
         })
         .context(ks_err!())
     }
 
-    /// Removes any keys that have expired as of the current time. Returns the number of keys
-    /// marked unreferenced that are bound to be garbage collected.
-    pub fn delete_expired_attestation_keys(&mut self) -> Result<i32> {
-        let _wp = wd::watch_millis("KeystoreDB::delete_expired_attestation_keys", 500);
+    /// Moves the key entry whose lock is held by `key_id` to the KeyMint instance of the key
+    /// staged by `stage_key_backend`. In one transaction, the key blob, the key parameters, the
+    /// certificate, and the certificate chain of the key entry are replaced by those of the
+    /// staged key. The superseded key blob is deleted by the garbage collector, i.e., only once
+    /// the new one is committed. Fails with `ResponseCode::KEY_NOT_FOUND` if no key is staged.
+    pub fn commit_key_backend(&mut self, key_id: &KeyIdGuard) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::commit_key_backend", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let mut stmt = tx
-                .prepare(
-                    "SELECT keyentryid, data
-                     FROM persistent.keymetadata
-                     WHERE tag = ? AND keyentryid IN
-                         (SELECT id
-                         FROM persistent.keyentry
-                         WHERE key_type = ?);",
+            let staged_id = Self::staged_key_backend(tx, key_id.id())
+                .context("Trying to look up the staged key.")?
+                .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context(ks_err!("No key is staged for key entry {}.", key_id.id()))?;
+            let km_uuid: Uuid = tx
+                .query_row(
+                    "SELECT km_uuid FROM persistent.keyentry WHERE id = ?;",
+                    params![staged_id],
+                    |row| row.get(0),
                 )
-                .context("Failed to prepare query")?;
-            let key_ids_to_check = stmt
-                .query_map(
-                    params![KeyMetaData::AttestationExpirationDate, KeyType::Attestation],
-                    |row| Ok((row.get(0)?, row.get(1)?)),
-                )?
-                .collect::<rusqlite::Result<Vec<(i64, DateTime)>>>()
-                .context("Failed to get date metadata")?;
-            // Calculate curr_time with a discount factor to avoid a key that's milliseconds away
-            // from expiration dodging this delete call.
-            let curr_time = DateTime::from_millis_epoch(
-                SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64
-                    + EXPIRATION_BUFFER_MS,
-            );
-            let mut num_deleted = 0;
-            for id in key_ids_to_check.iter().filter(|kt| kt.1 < curr_time).map(|kt| kt.0) {
-                if Self::mark_unreferenced(tx, id)? {
-                    num_deleted += 1;
-                }
+                .context("Trying to load the staged KeyMint uuid.")?;
+            let (_, key_blob_info, cert, cert_chain) =
+                Self::load_blob_components(staged_id, KeyEntryLoadBits::BOTH, tx)
+                    .context("Trying to load the staged blobs.")?;
+            let (blob, blob_metadata) = key_blob_info
+                .ok_or_else(KsError::sys)
+                .context(ks_err!("The staged key has no key blob."))?;
+            let params = Self::load_key_parameters(staged_id, tx)
+                .context("Trying to load the staged key parameters.")?;
+
+            // The staged key blob now belongs to the key entry. Its rows are deleted instead of
+            // being left to the garbage collector, which would delete it from KeyMint.
+            tx.execute(
+                "DELETE FROM persistent.blobmetadata WHERE blobentryid IN
+                     (SELECT id FROM persistent.blobentry WHERE keyentryid = ?);",
+                params![staged_id],
+            )
+            .context("Trying to delete the staged blob metadata.")?;
+            tx.execute(
+                "DELETE FROM persistent.blobentry WHERE keyentryid = ?;",
+                params![staged_id],
+            )
+            .context("Trying to delete the staged blobs.")?;
+            Self::discard_staged_key_backend(tx, key_id.id())
+                .context("Trying to delete the staged key entry.")?;
+
+            let updated = tx
+                .execute(
+                    "UPDATE persistent.keyentry SET km_uuid = ? WHERE id = ?;",
+                    params![km_uuid, key_id.id()],
+                )
+                .context("Trying to update the KeyMint uuid.")?;
+            if updated != 1 {
+                return Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context(ks_err!("Key entry {} does not exist.", key_id.id()));
             }
-            Ok(num_deleted).do_gc(num_deleted != 0)
+            Self::set_blob_internal(
+                tx,
+                key_id.id(),
+                SubComponentType::KEY_BLOB,
+                Some(&blob),
+                Some(&blob_metadata),
+            )
+            .context("Trying to insert the key blob.")?;
+            Self::set_blob_internal(tx, key_id.id(), SubComponentType::CERT, cert.as_deref(), None)
+                .context("Trying to replace the certificate.")?;
+            Self::set_blob_internal(
+                tx,
+                key_id.id(),
+                SubComponentType::CERT_CHAIN,
+                cert_chain.as_deref(),
+                None,
+            )
+            .context("Trying to replace the certificate chain.")?;
+            tx.execute(
+                "DELETE FROM persistent.keyparameter WHERE keyentryid = ?;",
+                params![key_id.id()],
+            )
+            .context("Trying to delete the key parameters.")?;
+            Self::insert_keyparameter_internal(tx, key_id, &params)
+                .context("Trying to insert the key parameters.")?;
+            Ok(()).need_gc()
         })
-        .context(ks_err!())
+        .context(ks_err!())?;
+        ATTESTATION_CERT_CACHE.invalidate(key_id.id());
+        Ok(())
     }
 
-    /// Deletes all remotely provisioned attestation keys in the system, regardless of the state
-    /// they are in. This is useful primarily as a testing mechanism.
-    pub fn delete_all_attestation_keys(&mut self) -> Result<i64> {
-        let _wp = wd::watch_millis("KeystoreDB::delete_all_attestation_keys", 500);
+    /// Discards the key staged by `stage_key_backend` for the key entry whose lock is held by
+    /// `key_id`, and leaves its key blob to the garbage collector. Fails with
+    /// `ResponseCode::KEY_NOT_FOUND` if no key is staged.
+    pub fn discard_key_backend(&mut self, key_id: &KeyIdGuard) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::discard_key_backend", 500);
 
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            let mut stmt = tx
-                .prepare(
-                    "SELECT id FROM persistent.keyentry
-                    WHERE key_type IS ?;",
-                )
-                .context("Failed to prepare statement")?;
-            let keys_to_delete = stmt
-                .query_map(params![KeyType::Attestation], |row| row.get(0))?
-                .collect::<rusqlite::Result<Vec<i64>>>()
-                .context("Failed to execute statement")?;
-            let num_deleted = keys_to_delete
-                .iter()
-                .map(|id| Self::mark_unreferenced(tx, *id))
-                .collect::<Result<Vec<bool>>>()
-                .context("Failed to execute mark_unreferenced on a keyid")?
-                .into_iter()
-                .filter(|result| *result)
-                .count() as i64;
-            Ok(num_deleted).do_gc(num_deleted != 0)
+            if !Self::discard_staged_key_backend(tx, key_id.id())
+                .context("Trying to discard the staged key.")?
+            {
+                return Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context(ks_err!("No key is staged for key entry {}.", key_id.id()));
+            }
+            Ok(()).need_gc()
         })
         .context(ks_err!())
     }
 
-    fn query_kid_for_attestation_key_and_cert_chain(
-        &self,
-        tx: &Transaction,
-        domain: Domain,
-        namespace: i64,
-        km_uuid: &Uuid,
-    ) -> Result<Option<i64>> {
-        let mut stmt = tx.prepare(
-            "SELECT id
-             FROM persistent.keyentry
-             WHERE key_type = ?
-                   AND domain = ?
-                   AND namespace = ?
-                   AND state = ?
-                   AND km_uuid = ?;",
-        )?;
-        let rows = stmt
-            .query_map(
-                params![
-                    KeyType::Attestation,
-                    domain.0 as u32,
-                    namespace,
-                    KeyLifeCycle::Live,
-                    km_uuid
-                ],
-                |row| row.get(0),
-            )?
-            .collect::<rusqlite::Result<Vec<i64>>>()
-            .context("query failed.")?;
-        if rows.is_empty() {
-            return Ok(None);
-        }
-        Ok(Some(rows[0]))
+    /// Returns the id of the key entry staged by `stage_key_backend` for `key_id`, if any.
+    fn staged_key_backend(tx: &Transaction, key_id: i64) -> Result<Option<i64>> {
+        tx.query_row(
+            "SELECT staged_keyentryid FROM persistent.keymigration WHERE keyentryid = ?;",
+            params![key_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .context(ks_err!())
     }
 
-    /// Fetches the private key and corresponding certificate chain assigned to a
-    /// domain/namespace pair. Will either return nothing if the domain/namespace is
-    /// not assigned, or one CertificateChain.
-    pub fn retrieve_attestation_key_and_cert_chain(
+    /// Deletes the key entry staged for `key_id`, if any. Returns true if there was one.
+    fn discard_staged_key_backend(tx: &Transaction, key_id: i64) -> Result<bool> {
+        let staged_id = match Self::staged_key_backend(tx, key_id).context(ks_err!())? {
+            Some(staged_id) => staged_id,
+            None => return Ok(false),
+        };
+        tx.execute("DELETE FROM persistent.keymigration WHERE keyentryid = ?;", params![key_id])
+            .context("Trying to delete the staged key record.")?;
+        Self::mark_unreferenced(tx, staged_id, false).context(ks_err!())?;
+        Ok(true)
+    }
+
+    /// Attaches `chain`, a concatenation of DER encoded X.509 certificates starting with the
+    /// leaf certificate, to the key entry whose lock is held by `key_id`, e.g., when the chain
+    /// of a key was provisioned after the key was generated. KeyMint does not reveal the public
+    /// key of a key blob, so the leaf certificate must certify the public key of the stored
+    /// certificate, which KeyMint issued along with the blob. In one transaction, the leaf
+    /// certificate replaces the stored certificate, which also updates the stored subject, and
+    /// the remaining certificates replace the certificate chain. Fails with
+    /// `ResponseCode::INVALID_ARGUMENT` if the chain cannot be parsed, if the key has no
+    /// certificate, or if the public keys differ.
+    pub fn attach_certificate_chain(&mut self, key_id: &KeyIdGuard, chain: &[u8]) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::attach_certificate_chain", 500);
+
+        let certs = match split_certificate_chain(chain) {
+            Ok(certs) if !certs.is_empty() => certs,
+            Ok(_) => {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("The certificate chain is empty."))
+            }
+            Err(e) => {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Failed to parse the certificate chain: {:?}", e))
+            }
+        };
+        let leaf = certs[0];
+        let cert_chain = certs[1..].concat();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let (_, _, stored_cert, _) =
+                Self::load_blob_components(key_id.id(), KeyEntryLoadBits::PUBLIC, tx)?;
+            let stored_cert = stored_cert
+                .ok_or(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("The key has no certificate to match the chain against."))?;
+            match certificate_public_keys_match(leaf, &stored_cert) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                        "The leaf certificate does not certify the public key of the key."
+                    ))
+                }
+                Err(e) => {
+                    return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                        "Failed to compare the public keys of the certificates: {:?}",
+                        e
+                    ))
+                }
+            }
+            Self::set_blob_internal(tx, key_id.id(), SubComponentType::CERT, Some(leaf), None)
+                .context("Trying to replace the certificate.")?;
+            Self::set_blob_internal(
+                tx,
+                key_id.id(),
+                SubComponentType::CERT_CHAIN,
+                (!cert_chain.is_empty()).then_some(&cert_chain[..]),
+                None,
+            )
+            .context("Trying to replace the certificate chain.")?;
+            Ok(()).need_gc()
+        })
+        .context(ks_err!())?;
+        ATTESTATION_CERT_CACHE.invalidate(key_id.id());
+        Ok(())
+    }
+
+    /// Loads the certificate and the certificate chain of the key entry whose lock is held
+    /// by `key_id`.
+    pub fn load_certificates(
         &mut self,
-        domain: Domain,
-        namespace: i64,
-        km_uuid: &Uuid,
-    ) -> Result<Option<(KeyIdGuard, CertificateChain)>> {
-        let _wp = wd::watch_millis("KeystoreDB::retrieve_attestation_key_and_cert_chain", 500);
+        key_id: &KeyIdGuard,
+    ) -> Result<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+        let _wp = wd::watch_millis("KeystoreDB::load_certificates", 500);
 
-        match domain {
-            Domain::APP | Domain::SELINUX => {}
-            _ => {
-                return Err(KsError::sys())
-                    .context(format!("Domain {:?} must be either App or SELinux.", domain));
-            }
-        }
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (_, _, cert, cert_chain) =
+                Self::load_blob_components(key_id.id(), KeyEntryLoadBits::PUBLIC, tx)?;
+            Ok((cert, cert_chain)).no_gc()
+        })
+        .context(ks_err!())
+    }
 
-        self.delete_expired_attestation_keys()
-            .context(ks_err!("Failed to prune expired attestation keys",))?;
+    /// Why would we insert a deleted blob? This weird function is for the purpose of legacy
+    /// key migration in the case where we bulk delete all the keys of an app or even a user.
+    /// We use this to insert key blobs into the database which can then be garbage collected
+    /// lazily by the key garbage collector.
+    pub fn set_deleted_blob(&mut self, blob: &[u8], blob_metadata: &BlobMetaData) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_deleted_blob", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            Self::set_blob_internal(
+                tx,
+                Self::UNASSIGNED_KEY_ID,
+                SubComponentType::KEY_BLOB,
+                Some(blob),
+                Some(blob_metadata),
+            )
+            .need_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Atomically replaces the key blob of the given key with `blob` and `blob_metadata`.
+    /// Unlike `set_blob`, which leaves the previous key blob to the garbage collector, this
+    /// removes all previous key blobs and their metadata in the same transaction. This is
+    /// intended for re-wrapping a key, e.g., under a new super key, where the old and the new
+    /// blob wrap the same KeyMint key. Handing the old blob to the garbage collector would
+    /// delete the key from KeyMint. Certificates and key metadata are left untouched.
+    /// Returns `ResponseCode::KEY_NOT_FOUND` if the key has no key blob to replace.
+    pub fn replace_key_blob(
+        &mut self,
+        key_id: &KeyIdGuard,
+        blob: &[u8],
+        blob_metadata: &BlobMetaData,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::replace_key_blob", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let old_blob_ids: Vec<i64> = {
+                let mut stmt = tx
+                    .prepare(
+                        "SELECT id FROM persistent.blobentry
+                         WHERE keyentryid = ? AND subcomponent_type = ?;",
+                    )
+                    .context(ks_err!("Failed to prepare statement."))?;
+                let rows = stmt
+                    .query_map(params![key_id.id(), SubComponentType::KEY_BLOB], |row| row.get(0))
+                    .context(ks_err!("Failed to query old key blobs."))?;
+                rows.collect::<rusqlite::Result<Vec<i64>>>()
+                    .context(ks_err!("Failed to extract old key blob ids."))?
+            };
+            if old_blob_ids.is_empty() {
+                return Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context(ks_err!("Key {} has no key blob to replace.", key_id.id()));
+            }
+
+            Self::set_blob_internal(
+                tx,
+                key_id.id(),
+                SubComponentType::KEY_BLOB,
+                Some(blob),
+                Some(blob_metadata),
+            )
+            .context(ks_err!("Failed to insert new key blob."))?;
+
+            for blob_id in old_blob_ids {
+                tx.execute(
+                    "DELETE FROM persistent.blobmetadata WHERE blobentryid = ?;",
+                    params![blob_id],
+                )
+                .context(ks_err!("Failed to delete old blob metadata."))?;
+                tx.execute("DELETE FROM persistent.blobentry WHERE id = ?;", params![blob_id])
+                    .context(ks_err!("Failed to delete old key blob."))?;
+            }
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Fails with `ResponseCode::TOO_MUCH_DATA` if `cert_chain` exceeds the maximum size of
+    /// certificate chains, which is `DEFAULT_MAX_CERT_CHAIN_SIZE` unless it is overridden by
+    /// `MAX_CERT_CHAIN_SIZE_PROPERTY`. The size is checked when a chain is stored and again when
+    /// it is loaded, so that oversized chains never reach the callers of keystore.
+    fn check_cert_chain_size(cert_chain: &[u8]) -> Result<()> {
+        let max_size = read_prop_u32(
+            Self::MAX_CERT_CHAIN_SIZE_PROPERTY,
+            Self::DEFAULT_MAX_CERT_CHAIN_SIZE as u32,
+        ) as usize;
+        if cert_chain.len() > max_size {
+            return Err(KsError::Rc(ResponseCode::TOO_MUCH_DATA)).context(ks_err!(
+                "Certificate chain of {} bytes exceeds {} bytes.",
+                cert_chain.len(),
+                max_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rejects a zero length or implausibly short key blob with
+    /// `ResponseCode::VALUE_CORRUPTED` when it is loaded, so that corrupt blobs fail precisely
+    /// instead of confusing KeyMint.
+    fn check_key_blob_size(key_id: i64, key_blob: &[u8]) -> Result<()> {
+        if key_blob.len() < Self::MIN_KEY_BLOB_SIZE {
+            return Err(KsError::Rc(ResponseCode::VALUE_CORRUPTED)).context(ks_err!(
+                "Key blob of key {} is corrupt, it has only {} bytes.",
+                key_id,
+                key_blob.len()
+            ));
+        }
+        Ok(())
+    }
+
+    fn set_blob_internal(
+        tx: &Transaction,
+        key_id: i64,
+        sc_type: SubComponentType,
+        blob: Option<&[u8]>,
+        blob_metadata: Option<&BlobMetaData>,
+    ) -> Result<()> {
+        match (blob, sc_type) {
+            (Some(blob), _) => {
+                if sc_type == SubComponentType::CERT_CHAIN {
+                    Self::check_cert_chain_size(blob).context(ks_err!())?;
+                }
+                tx.execute(
+                    "INSERT INTO persistent.blobentry
+                     (subcomponent_type, keyentryid, blob) VALUES (?, ?, ?);",
+                    params![sc_type, key_id, blob],
+                )
+                .context(ks_err!("Failed to insert blob."))?;
+                if let Some(blob_metadata) = blob_metadata {
+                    let blob_id = tx
+                        .query_row("SELECT MAX(id) FROM persistent.blobentry;", NO_PARAMS, |row| {
+                            row.get(0)
+                        })
+                        .context(ks_err!("Failed to get new blob id."))?;
+                    blob_metadata
+                        .store_in_db(blob_id, tx)
+                        .context(ks_err!("Trying to store blob metadata."))?;
+                }
+            }
+            (None, SubComponentType::CERT) | (None, SubComponentType::CERT_CHAIN) => {
+                tx.execute(
+                    "DELETE FROM persistent.blobentry
+                    WHERE subcomponent_type = ? AND keyentryid = ?;",
+                    params![sc_type, key_id],
+                )
+                .context(ks_err!("Failed to delete blob."))?;
+            }
+            (None, _) => {
+                return Err(KsError::sys())
+                    .context(ks_err!("Other blobs cannot be deleted in this way."));
+            }
+        }
+        if sc_type == SubComponentType::CERT {
+            Self::store_certificate_subject(tx, key_id, blob).context(ks_err!())?;
+            Self::store_key_fingerprint(tx, key_id, blob).context(ks_err!())?;
+        }
+        if sc_type == SubComponentType::CERT || sc_type == SubComponentType::CERT_CHAIN {
+            tx.execute(
+                "DELETE FROM persistent.keymetadata WHERE keyentryid = ? AND tag = ?;",
+                params![key_id, KeyMetaData::ReattestationRequired],
+            )
+            .context(ks_err!("Failed to clear re-attestation mark."))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the stored subject of the certificate of `key_id` by the subject of `cert`.
+    /// If there is no certificate, or it cannot be parsed, e.g., because the key was imported
+    /// with a certificate that is not DER encoded, the stored subject is removed, so that a
+    /// subject is never served for a certificate it was not parsed from.
+    fn store_certificate_subject(tx: &Transaction, key_id: i64, cert: Option<&[u8]>) -> Result<()> {
+        tx.execute(
+            "DELETE FROM persistent.keymetadata WHERE keyentryid = ? AND tag = ?;",
+            params![key_id, KeyMetaData::CertificateSubject],
+        )
+        .context(ks_err!("Failed to delete certificate subject."))?;
+        if let Some(subject) = cert.and_then(|cert| parse_subject_from_certificate(cert).ok()) {
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::CertificateSubject(subject));
+            metadata.store_in_db(key_id, tx).context(ks_err!("Failed to store subject."))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the stored fingerprint of `key_id` by the fingerprint of `cert`, computed with
+    /// the configured algorithm, or removes it if there is no certificate.
+    fn store_key_fingerprint(tx: &Transaction, key_id: i64, cert: Option<&[u8]>) -> Result<()> {
+        tx.execute(
+            "DELETE FROM persistent.keymetadata WHERE keyentryid = ? AND tag IN (?, ?);",
+            params![
+                key_id,
+                KeyMetaData::PublicKeyFingerprint,
+                KeyMetaData::PublicKeyFingerprintAlgorithm
+            ],
+        )
+        .context(ks_err!("Failed to delete key fingerprint."))?;
+        if let Some(cert) = cert {
+            let fingerprint = KeyFingerprint::compute(FingerprintAlgorithm::from_property(), cert)
+                .context(ks_err!("Failed to hash the certificate."))?;
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::PublicKeyFingerprint(fingerprint.digest));
+            metadata.add(KeyMetaEntry::PublicKeyFingerprintAlgorithm(fingerprint.algorithm));
+            metadata.store_in_db(key_id, tx).context(ks_err!("Failed to store fingerprint."))?;
+        }
+        Ok(())
+    }
+
+    /// Loads the stored subject of the certificate of the key entry whose lock is held by
+    /// `key_id`. Returns None if the key has no certificate, its certificate could not be
+    /// parsed, or it was stored before subjects were recorded.
+    pub fn load_certificate_subject(&mut self, key_id: &KeyIdGuard) -> Result<Option<Vec<u8>>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_certificate_subject", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let metadata = KeyMetaData::load_from_db(key_id.id(), tx)?;
+            Ok(metadata.certificate_subject().cloned()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Inserts a collection of key parameters into the `persistent.keyparameter` table
+    /// and associates them with the given `key_id`.
+    #[cfg(test)]
+    fn insert_keyparameter(&mut self, key_id: &KeyIdGuard, params: &[KeyParameter]) -> Result<()> {
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            Self::insert_keyparameter_internal(tx, key_id, params).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    fn insert_keyparameter_internal(
+        tx: &Transaction,
+        key_id: &KeyIdGuard,
+        params: &[KeyParameter],
+    ) -> Result<()> {
+        let mut stmt = tx
+            .prepare(
+                "INSERT into persistent.keyparameter (keyentryid, tag, data, security_level)
+                VALUES (?, ?, ?, ?);",
+            )
+            .context(ks_err!("Failed to prepare statement."))?;
+
+        for p in params.iter() {
+            stmt.insert(params![
+                key_id.id(),
+                p.get_tag().0,
+                p.key_parameter_value(),
+                p.security_level().0
+            ])
+            .with_context(|| ks_err!("Failed to insert {:?}", p))?;
+        }
+        Ok(())
+    }
+
+    /// Insert a set of key entry specific metadata into the database.
+    #[cfg(test)]
+    fn insert_key_metadata(&mut self, key_id: &KeyIdGuard, metadata: &KeyMetaData) -> Result<()> {
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            metadata.store_in_db(key_id.id(), tx).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Stores a signed certificate chain signed by a remote provisioning server, keyed
+    /// on the public key.
+    pub fn store_signed_attestation_certificate_chain(
+        &mut self,
+        raw_public_key: &[u8],
+        batch_cert: &[u8],
+        cert_chain: &[u8],
+        expiration_date: i64,
+        km_uuid: &Uuid,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::store_signed_attestation_certificate_chain", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentryid
+                    FROM persistent.keymetadata
+                    WHERE tag = ? AND data = ? AND keyentryid IN
+                    (SELECT id
+                     FROM persistent.keyentry
+                     WHERE
+                        alias IS NULL AND
+                        domain IS NULL AND
+                        namespace IS NULL AND
+                        key_type = ? AND
+                        km_uuid = ?);",
+                )
+                .context("Failed to store attestation certificate chain.")?;
+            let mut rows = stmt
+                .query(params![
+                    KeyMetaData::AttestationRawPubKey,
+                    raw_public_key,
+                    KeyType::Attestation,
+                    km_uuid
+                ])
+                .context("Failed to fetch keyid")?;
+            let key_id = db_utils::with_rows_extract_one(&mut rows, |row| {
+                row.map_or_else(|| Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND)), Ok)?
+                    .get(0)
+                    .context("Failed to unpack id.")
+            })
+            .context("Failed to get key_id.")?;
+            let num_updated = tx
+                .execute(
+                    "UPDATE persistent.keyentry
+                    SET alias = ?
+                    WHERE id = ?;",
+                    params!["signed", key_id],
+                )
+                .context("Failed to update alias.")?;
+            if num_updated != 1 {
+                return Err(KsError::sys()).context("Alias not updated for the key.");
+            }
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::AttestationExpirationDate(DateTime::from_millis_epoch(
+                expiration_date,
+            )));
+            metadata.store_in_db(key_id, tx).context("Failed to insert key metadata.")?;
+            Self::set_blob_internal(
+                tx,
+                key_id,
+                SubComponentType::CERT_CHAIN,
+                Some(cert_chain),
+                None,
+            )
+            .context("Failed to insert cert chain")?;
+            Self::set_blob_internal(tx, key_id, SubComponentType::CERT, Some(batch_cert), None)
+                .context("Failed to insert cert")?;
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Assigns the next unassigned attestation key of `pool` to a domain/namespace combo that does
+    /// not currently have a key of that pool assigned to it. See `create_attestation_key_entry`
+    /// for pools.
+    pub fn assign_attestation_key(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::assign_attestation_key", 500);
+
+        match domain {
+            Domain::APP | Domain::SELINUX => {}
+            _ => {
+                return Err(KsError::sys())
+                    .context(ks_err!("Domain {:?} must be either App or SELinux.", domain));
+            }
+        }
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let result = tx
+                .execute(
+                    "UPDATE persistent.keyentry
+                        SET domain=?1, namespace=?2
+                        WHERE
+                            id =
+                                (SELECT MIN(id)
+                                FROM persistent.keyentry
+                                WHERE ALIAS IS NOT NULL
+                                    AND domain IS NULL
+                                    AND key_type IS ?3
+                                    AND state IS ?4
+                                    AND km_uuid IS ?5
+                                    AND (SELECT data FROM persistent.keymetadata
+                                        WHERE keyentryid = keyentry.id AND tag = ?6) IS ?7)
+                            AND
+                                (SELECT COUNT(*)
+                                FROM persistent.keyentry
+                                WHERE domain=?1
+                                    AND namespace=?2
+                                    AND key_type IS ?3
+                                    AND state IS ?4
+                                    AND km_uuid IS ?5
+                                    AND (SELECT data FROM persistent.keymetadata
+                                        WHERE keyentryid = keyentry.id AND tag = ?6) IS ?7) = 0;",
+                    params![
+                        domain.0 as u32,
+                        namespace,
+                        KeyType::Attestation,
+                        KeyLifeCycle::Live,
+                        km_uuid,
+                        KeyMetaData::AttestationKeyPool,
+                        pool,
+                    ],
+                )
+                .context("Failed to assign attestation key")?;
+            if result == 0 {
+                let (_, hw_info) = get_keymint_dev_by_uuid(km_uuid)
+                    .context("Error in retrieving keymint device by UUID.")?;
+                log_rkp_error_stats(MetricsRkpError::OUT_OF_KEYS, &hw_info.securityLevel);
+                return Err(KsError::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+                    .context("Out of keys.");
+            } else if result > 1 {
+                return Err(KsError::sys())
+                    .context(format!("Expected to update 1 entry, instead updated {}", result));
+            }
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Counts one use of the assigned attestation key `key_id` and returns the number of uses
+    /// since the key was assigned, including this one.
+    pub fn record_attestation_key_use(&mut self, key_id: i64) -> Result<i64> {
+        let _wp = wd::watch_millis("KeystoreDB::record_attestation_key_use", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let use_count = KeyMetaData::load_from_db(key_id, tx)
+                .context("Failed to load key metadata.")?
+                .attestation_use_count()
+                .copied()
+                .unwrap_or(0)
+                + 1;
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::AttestationUseCount(use_count));
+            metadata.store_in_db(key_id, tx).context("Failed to store use count.")?;
+            Ok(use_count).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Replaces the attestation key `key_id` that is assigned to the domain/namespace combo by
+    /// the next unassigned attestation key of the same pool. The replaced key is deleted. If no unassigned key is
+    /// left, the assignment is left untouched and OUT_OF_KEYS_TRANSIENT_ERROR is returned.
+    pub fn replace_attestation_key(
+        &mut self,
+        key_id: i64,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::replace_attestation_key", 500);
+
+        let tombstones = self.tombstones_enabled();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let replacement: Option<i64> = tx
+                .query_row(
+                    "SELECT MIN(id)
+                    FROM persistent.keyentry
+                    WHERE alias IS NOT NULL
+                        AND domain IS NULL
+                        AND key_type IS ?1
+                        AND state IS ?2
+                        AND km_uuid IS ?3
+                        AND (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = keyentry.id AND tag = ?4) IS
+                            (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = ?5 AND tag = ?4);",
+                    params![
+                        KeyType::Attestation,
+                        KeyLifeCycle::Live,
+                        km_uuid,
+                        KeyMetaData::AttestationKeyPool,
+                        key_id
+                    ],
+                    |row| row.get(0),
+                )
+                .context("Failed to find replacement key.")?;
+            let replacement = match replacement {
+                Some(id) => id,
+                None => {
+                    return Err(KsError::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+                        .context("Out of keys.")
+                }
+            };
+            let assigned: i64 = tx
+                .query_row(
+                    "SELECT COUNT(*)
+                    FROM persistent.keyentry
+                    WHERE id = ? AND domain = ? AND namespace = ? AND key_type IS ?;",
+                    params![key_id, domain.0 as u32, namespace, KeyType::Attestation],
+                    |row| row.get(0),
+                )
+                .context("Failed to check assignment.")?;
+            if assigned != 1 {
+                return Err(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+                    .context("Key is not assigned to the namespace.");
+            }
+            Self::mark_unreferenced(tx, key_id, tombstones)
+                .context("Failed to delete replaced key.")?;
+            tx.execute(
+                "UPDATE persistent.keyentry SET domain = ?, namespace = ? WHERE id = ?;",
+                params![domain.0 as u32, namespace, replacement],
+            )
+            .context("Failed to assign replacement key.")?;
+            Ok(()).need_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Retrieves num_keys number of attestation keys that have not yet been signed by a remote
+    /// provisioning server, or the maximum number available if there are not num_keys number of
+    /// entries in the table.
+    pub fn fetch_unsigned_attestation_keys(
+        &mut self,
+        num_keys: i32,
+        km_uuid: &Uuid,
+    ) -> Result<Vec<Vec<u8>>> {
+        let _wp = wd::watch_millis("KeystoreDB::fetch_unsigned_attestation_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT data
+                    FROM persistent.keymetadata
+                    WHERE tag = ? AND keyentryid IN
+                        (SELECT id
+                        FROM persistent.keyentry
+                        WHERE
+                            alias IS NULL AND
+                            domain IS NULL AND
+                            namespace IS NULL AND
+                            key_type = ? AND
+                            km_uuid = ?
+                        LIMIT ?);",
+                )
+                .context("Failed to prepare statement")?;
+            let rows = stmt
+                .query_map(
+                    params![
+                        KeyMetaData::AttestationMacedPublicKey,
+                        KeyType::Attestation,
+                        km_uuid,
+                        num_keys
+                    ],
+                    |row| row.get(0),
+                )?
+                .collect::<rusqlite::Result<Vec<Vec<u8>>>>()
+                .context("Failed to execute statement")?;
+            Ok(rows).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Counts the remote provisioned attestation keys of the given pool of the KeyMint instance
+    /// that are signed, not yet assigned to a namespace, and do not expire within the expiration
+    /// buffer. This is a read only query; it neither assigns nor locks any key.
+    pub fn count_available_attestation_keys(
+        &mut self,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+    ) -> Result<i32> {
+        let _wp = wd::watch_millis("KeystoreDB::count_available_attestation_keys", 500);
+
+        let curr_time = DateTime::from_millis_epoch(
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64
+                + EXPIRATION_BUFFER_MS,
+        );
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let count = tx
+                .query_row(
+                    "SELECT COUNT(id)
+                    FROM persistent.keyentry
+                    WHERE
+                        alias IS NOT NULL AND
+                        domain IS NULL AND
+                        key_type = ? AND
+                        state = ? AND
+                        km_uuid = ? AND
+                        id IN
+                            (SELECT keyentryid
+                            FROM persistent.keymetadata
+                            WHERE tag = ? AND data > ?) AND
+                        (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = keyentry.id AND tag = ?) IS ?;",
+                    params![
+                        KeyType::Attestation,
+                        KeyLifeCycle::Live,
+                        km_uuid,
+                        KeyMetaData::AttestationExpirationDate,
+                        curr_time,
+                        KeyMetaData::AttestationKeyPool,
+                        pool
+                    ],
+                    |row| row.get(0),
+                )
+                .context("Failed to count available attestation keys.")?;
+            Ok(count).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Sums the remaining uses of the remote provisioned attestation keys of the given pool of
+    /// the KeyMint instance that are assigned to a namespace and do not expire within the
+    /// expiration buffer, where every key can sign `max_uses` attestations, see
+    /// `record_attestation_key_use`. Exhausted keys count as 0. Like
+    /// `count_available_attestation_keys`, this is a read only query.
+    pub fn sum_remaining_attestation_key_uses(
+        &mut self,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+        max_uses: i64,
+    ) -> Result<i64> {
+        let _wp = wd::watch_millis("KeystoreDB::sum_remaining_attestation_key_uses", 500);
+
+        let curr_time = DateTime::from_millis_epoch(
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64
+                + EXPIRATION_BUFFER_MS,
+        );
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let remaining_uses = tx
+                .query_row(
+                    "SELECT COALESCE(SUM(MAX(0, ? - COALESCE(
+                        (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = keyentry.id AND tag = ?), 0))), 0)
+                    FROM persistent.keyentry
+                    WHERE
+                        alias IS NOT NULL AND
+                        domain IS NOT NULL AND
+                        key_type = ? AND
+                        state = ? AND
+                        km_uuid = ? AND
+                        id IN
+                            (SELECT keyentryid
+                            FROM persistent.keymetadata
+                            WHERE tag = ? AND data > ?) AND
+                        (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = keyentry.id AND tag = ?) IS ?;",
+                    params![
+                        max_uses,
+                        KeyMetaData::AttestationUseCount,
+                        KeyType::Attestation,
+                        KeyLifeCycle::Live,
+                        km_uuid,
+                        KeyMetaData::AttestationExpirationDate,
+                        curr_time,
+                        KeyMetaData::AttestationKeyPool,
+                        pool
+                    ],
+                    |row| row.get(0),
+                )
+                .context("Failed to sum remaining attestation key uses.")?;
+            Ok(remaining_uses).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns true if a remote provisioned attestation key of the given pool of the KeyMint
+    /// instance that does not expire within the expiration buffer is assigned to the
+    /// domain/namespace pair. Unlike `retrieve_attestation_key_and_cert_chain`, this is a read
+    /// only query; it neither prunes expired keys nor locks the assigned key.
+    pub fn has_assigned_attestation_key(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+    ) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::has_assigned_attestation_key", 500);
+
+        let curr_time = DateTime::from_millis_epoch(
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64
+                + EXPIRATION_BUFFER_MS,
+        );
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let count: i64 = tx
+                .query_row(
+                    "SELECT COUNT(id)
+                    FROM persistent.keyentry
+                    WHERE
+                        key_type = ? AND
+                        domain = ? AND
+                        namespace = ? AND
+                        state = ? AND
+                        km_uuid = ? AND
+                        id IN
+                            (SELECT keyentryid
+                            FROM persistent.keymetadata
+                            WHERE tag = ? AND data > ?) AND
+                        (SELECT data FROM persistent.keymetadata
+                            WHERE keyentryid = keyentry.id AND tag = ?) IS ?;",
+                    params![
+                        KeyType::Attestation,
+                        domain.0 as u32,
+                        namespace,
+                        KeyLifeCycle::Live,
+                        km_uuid,
+                        KeyMetaData::AttestationExpirationDate,
+                        curr_time,
+                        KeyMetaData::AttestationKeyPool,
+                        pool
+                    ],
+                    |row| row.get(0),
+                )
+                .context("Failed to query assigned attestation key.")?;
+            Ok(count > 0).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Marks the attestation keys that have expired as of the current time, or will within the
+    /// expiration buffer, as unreferenced. Keys that are currently locked, e.g., because they
+    /// are attesting a key that is being generated, are skipped. Returns the KeyMint UUID of
+    /// each key that was marked. If `tombstones` is true, a tombstone is retained for each key.
+    fn mark_expired_attestation_keys_unreferenced(
+        tx: &Transaction,
+        tombstones: bool,
+    ) -> Result<Vec<Uuid>> {
+        let mut stmt = tx
+            .prepare(
+                "SELECT keymetadata.keyentryid, keymetadata.data, keyentry.km_uuid
+                 FROM persistent.keymetadata
+                 INNER JOIN persistent.keyentry ON keyentry.id = keymetadata.keyentryid
+                 WHERE keymetadata.tag = ? AND keyentry.key_type = ?;",
+            )
+            .context("Failed to prepare query")?;
+        let key_ids_to_check = stmt
+            .query_map(
+                params![KeyMetaData::AttestationExpirationDate, KeyType::Attestation],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<(i64, DateTime, Uuid)>>>()
+            .context("Failed to get date metadata")?;
+        // Calculate curr_time with a discount factor to avoid a key that's milliseconds away
+        // from expiration dodging this delete call.
+        let curr_time = DateTime::from_millis_epoch(
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64
+                + EXPIRATION_BUFFER_MS,
+        );
+        let mut marked = Vec::new();
+        for (id, _, km_uuid) in key_ids_to_check.into_iter().filter(|kt| kt.1 < curr_time) {
+            let _key_id_guard = match KEY_ID_LOCK
+                .try_get(id, KeyIdLockOperation::MarkKeysUnreferenced)
+            {
+                Some(guard) => guard,
+                None => {
+                    log::info!("Not pruning expired attestation key {} because it is in use.", id);
+                    continue;
+                }
+            };
+            if Self::mark_unreferenced(tx, id, tombstones)? {
+                marked.push(km_uuid);
+            }
+        }
+        Ok(marked)
+    }
+
+    /// Removes any keys that have expired as of the current time. Returns the number of keys
+    /// marked unreferenced that are bound to be garbage collected.
+    pub fn delete_expired_attestation_keys(&mut self) -> Result<i32> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_expired_attestation_keys", 500);
+
+        let tombstones = self.tombstones_enabled();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let num_deleted =
+                Self::mark_expired_attestation_keys_unreferenced(tx, tombstones)?.len() as i32;
+            Ok(num_deleted).do_gc(num_deleted != 0)
+        })
+        .context(ks_err!())
+    }
+
+    /// Like `delete_expired_attestation_keys`, but returns the KeyMint UUID of each removed key,
+    /// so that the caller can attribute them to a security level. This is used by the garbage
+    /// collector before it collects unreferenced keys.
+    pub fn prune_expired_attestation_keys(&mut self) -> Result<Vec<Uuid>> {
+        let _wp = wd::watch_millis("KeystoreDB::prune_expired_attestation_keys", 500);
+
+        let tombstones = self.tombstones_enabled();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let pruned = Self::mark_expired_attestation_keys_unreferenced(tx, tombstones)?;
+            let need_gc = !pruned.is_empty();
+            Ok(pruned).do_gc(need_gc)
+        })
+        .context(ks_err!())
+    }
+
+    /// Marks the client keys whose expiry date is not after `now` unreferenced, so that they get
+    /// collected. Keys that are in use are skipped, they are marked by a later call. Returns the
+    /// ids of the marked keys.
+    pub fn mark_expired_keys_unreferenced(&mut self, now: DateTime) -> Result<Vec<i64>> {
+        let _wp = wd::watch_millis("KeystoreDB::mark_expired_keys_unreferenced", 500);
+
+        let tombstones = self.tombstones_enabled();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keymetadata.keyentryid
+                     FROM persistent.keymetadata
+                     INNER JOIN persistent.keyentry ON keyentry.id = keymetadata.keyentryid
+                     WHERE keymetadata.tag = ? AND keymetadata.data <= ?
+                         AND keyentry.key_type = ? AND keyentry.state = ?;",
+                )
+                .context("Failed to prepare query")?;
+            let expired = stmt
+                .query_map(
+                    params![KeyMetaData::ExpiryDate, now, KeyType::Client, KeyLifeCycle::Live],
+                    |row| row.get(0),
+                )?
+                .collect::<rusqlite::Result<Vec<i64>>>()
+                .context("Failed to get expired keys")?;
+            let mut marked = Vec::new();
+            for id in expired {
+                let _key_id_guard =
+                    match KEY_ID_LOCK.try_get(id, KeyIdLockOperation::MarkKeysUnreferenced) {
+                        Some(guard) => guard,
+                        None => {
+                            log::info!("Not deleting expired key {} because it is in use.", id);
+                            continue;
+                        }
+                    };
+                if Self::mark_unreferenced(tx, id, tombstones)? {
+                    marked.push(id);
+                }
+            }
+            let need_gc = !marked.is_empty();
+            Ok(marked).do_gc(need_gc)
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns the expiry date and the attestation chain of each live client key with an alias in
+    /// the given namespace, sorted by alias.
+    pub fn get_key_expiry_data(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+    ) -> Result<Vec<KeyExpiryData>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_key_expiry_data", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentry.id, keyentry.alias, keymetadata.data
+                     FROM persistent.keyentry
+                     LEFT JOIN persistent.keymetadata
+                         ON keymetadata.keyentryid = keyentry.id AND keymetadata.tag = ?
+                     WHERE keyentry.domain = ? AND keyentry.namespace = ?
+                         AND keyentry.alias IS NOT NULL
+                         AND keyentry.state = ? AND keyentry.key_type = ?
+                     ORDER BY keyentry.alias ASC;",
+                )
+                .context(ks_err!("Failed to prepare statement."))?;
+            let keys = stmt
+                .query_map(
+                    params![
+                        KeyMetaData::ExpiryDate,
+                        domain.0,
+                        namespace,
+                        KeyLifeCycle::Live,
+                        KeyType::Client
+                    ],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .context(ks_err!("Failed to query keys."))?
+                .collect::<rusqlite::Result<Vec<(i64, String, Option<DateTime>)>>>()
+                .context(ks_err!("Failed to extract keys."))?;
+
+            let mut result = Vec::with_capacity(keys.len());
+            for (key_id, alias, expiry_date) in keys {
+                let (_, _, cert, cert_chain) =
+                    Self::load_blob_components(key_id, KeyEntryLoadBits::PUBLIC, tx)
+                        .context(ks_err!("Failed to load the certificates of key {}.", key_id))?;
+                let chain = cert.map(|mut chain| {
+                    if let Some(cert_chain) = cert_chain {
+                        chain.extend(cert_chain);
+                    }
+                    chain
+                });
+                result.push(KeyExpiryData { key_id, alias, expiry_date, chain });
+            }
+            Ok(result).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns the id of each live client key that has a certificate and is not yet marked for
+    /// re-attestation, together with its certificate followed by its certificate chain, if any,
+    /// i.e., the attestation chain of the key starting from the leaf.
+    pub fn get_attestation_chains_to_check(&mut self) -> Result<Vec<(i64, Vec<u8>)>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_attestation_chains_to_check", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentry.id, blobentry.subcomponent_type, blobentry.blob,
+                         MAX(blobentry.id)
+                     FROM persistent.keyentry
+                     INNER JOIN persistent.blobentry ON blobentry.keyentryid = keyentry.id
+                     WHERE keyentry.key_type = ? AND keyentry.state = ?
+                         AND blobentry.subcomponent_type IN (?, ?)
+                         AND NOT EXISTS (SELECT 1 FROM persistent.keymetadata
+                             WHERE keyentryid = keyentry.id AND tag = ?)
+                     GROUP BY keyentry.id, blobentry.subcomponent_type
+                     ORDER BY keyentry.id, blobentry.subcomponent_type;",
+                )
+                .context("Failed to prepare query")?;
+            let rows = stmt
+                .query_map(
+                    params![
+                        KeyType::Client,
+                        KeyLifeCycle::Live,
+                        SubComponentType::CERT,
+                        SubComponentType::CERT_CHAIN,
+                        KeyMetaData::ReattestationRequired
+                    ],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?
+                .collect::<rusqlite::Result<Vec<(i64, SubComponentType, Vec<u8>)>>>()
+                .context("Failed to get attestation chains")?;
+            let mut chains: Vec<(i64, Vec<u8>)> = Vec::new();
+            for (key_id, sc_type, blob) in rows {
+                match chains.last_mut() {
+                    // The chain is only of interest if there is a leaf certificate, which is
+                    // ordered before the chain.
+                    Some((id, chain)) if *id == key_id => chain.extend_from_slice(&blob),
+                    _ if sc_type == SubComponentType::CERT => chains.push((key_id, blob)),
+                    _ => {}
+                }
+            }
+            Ok(chains).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Marks the keys `key_ids` for re-attestation. See `KeyEntry::needs_reattestation`.
+    pub fn mark_for_reattestation(&mut self, key_ids: &[i64]) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::mark_for_reattestation", 500);
+
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::ReattestationRequired(true));
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            for key_id in key_ids {
+                metadata.store_in_db(*key_id, tx).context(ks_err!())?;
+            }
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Deletes all remotely provisioned attestation keys in the system, regardless of the state
+    /// they are in. This is useful primarily as a testing mechanism.
+    pub fn delete_all_attestation_keys(&mut self) -> Result<i64> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_all_attestation_keys", 500);
+
+        let tombstones = self.tombstones_enabled();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id FROM persistent.keyentry
+                    WHERE key_type IS ?;",
+                )
+                .context("Failed to prepare statement")?;
+            let keys_to_delete = stmt
+                .query_map(params![KeyType::Attestation], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<i64>>>()
+                .context("Failed to execute statement")?;
+            let num_deleted = keys_to_delete
+                .iter()
+                .map(|id| Self::mark_unreferenced(tx, *id, tombstones))
+                .collect::<Result<Vec<bool>>>()
+                .context("Failed to execute mark_unreferenced on a keyid")?
+                .into_iter()
+                .filter(|result| *result)
+                .count() as i64;
+            Ok(num_deleted).do_gc(num_deleted != 0)
+        })
+        .context(ks_err!())
+    }
+
+    fn query_kid_for_attestation_key_and_cert_chain(
+        &self,
+        tx: &Transaction,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+    ) -> Result<Option<i64>> {
+        let mut stmt = tx.prepare(
+            "SELECT id
+             FROM persistent.keyentry
+             WHERE key_type = ?
+                   AND domain = ?
+                   AND namespace = ?
+                   AND state = ?
+                   AND km_uuid = ?
+                   AND (SELECT data FROM persistent.keymetadata
+                       WHERE keyentryid = keyentry.id AND tag = ?) IS ?;",
+        )?;
+        let rows = stmt
+            .query_map(
+                params![
+                    KeyType::Attestation,
+                    domain.0 as u32,
+                    namespace,
+                    KeyLifeCycle::Live,
+                    km_uuid,
+                    KeyMetaData::AttestationKeyPool,
+                    pool
+                ],
+                |row| row.get(0),
+            )?
+            .collect::<rusqlite::Result<Vec<i64>>>()
+            .context("query failed.")?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(rows[0]))
+    }
+
+    /// Fetches the private key and corresponding certificate chain of `pool` assigned to a
+    /// domain/namespace pair. Will either return nothing if the domain/namespace is
+    /// not assigned a key of the pool, or one CertificateChain.
+    pub fn retrieve_attestation_key_and_cert_chain(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+    ) -> Result<Option<(KeyIdGuard, CertificateChain)>> {
+        let _wp = wd::watch_millis("KeystoreDB::retrieve_attestation_key_and_cert_chain", 500);
+
+        match domain {
+            Domain::APP | Domain::SELINUX => {}
+            _ => {
+                return Err(KsError::sys())
+                    .context(format!("Domain {:?} must be either App or SELinux.", domain));
+            }
+        }
+
+        self.delete_expired_attestation_keys()
+            .context(ks_err!("Failed to prune expired attestation keys",))?;
         let tx = self
             .conn
             .unchecked_transaction()
             .context(ks_err!("Failed to initialize transaction."))?;
         let key_id: i64 = match self
-            .query_kid_for_attestation_key_and_cert_chain(&tx, domain, namespace, km_uuid)?
+            .query_kid_for_attestation_key_and_cert_chain(&tx, domain, namespace, km_uuid, pool)?
         {
             None => return Ok(None),
             Some(kid) => kid,
         };
         tx.commit().context(ks_err!("Failed to commit keyid query"))?;
-        let key_id_guard = KEY_ID_LOCK.get(key_id);
+        let key_id_guard = KEY_ID_LOCK.get(key_id, KeyIdLockOperation::LoadAttestationKey);
         let tx = self
             .conn
             .unchecked_transaction()
@@ -2081,6 +4624,7 @@ impl KeystoreDB {
                     km_blob = row.1;
                 }
                 SubComponentType::CERT_CHAIN => {
+                    Self::check_cert_chain_size(&row.1).context(ks_err!())?;
                     cert_chain_blob = row.1;
                 }
                 SubComponentType::CERT => {
@@ -2119,6 +4663,7 @@ impl KeystoreDB {
                     .context(ks_err!("Domain {:?} must be either App or SELinux.", domain));
             }
         }
+        check_alias(alias).context(ks_err!())?;
         let updated = tx
             .execute(
                 "UPDATE persistent.keyentry
@@ -2135,7 +4680,7 @@ impl KeystoreDB {
                 params![
                     alias,
                     KeyLifeCycle::Live,
-                    newid.0,
+                    newid.id(),
                     domain.0 as u32,
                     *namespace,
                     KeyLifeCycle::Existing,
@@ -2154,6 +4699,11 @@ impl KeystoreDB {
 
     /// Moves the key given by KeyIdGuard to the new location at `destination`. If the destination
     /// is already occupied by a key, this function fails with `ResponseCode::INVALID_ARGUMENT`.
+    /// Super encrypted blobs that are bound to their key entry are bound to the key id and the
+    /// user of the owner, see `BlobMetaEntry::BoundUserId`, and they remain encrypted with the
+    /// super key of that user. So keys with such blobs, including wrapped certificate chains,
+    /// cannot be moved to another user, and this function fails with
+    /// `ResponseCode::INVALID_ARGUMENT` as well.
     pub fn migrate_key_namespace(
         &mut self,
         key_id_guard: KeyIdGuard,
@@ -2164,8 +4714,7 @@ impl KeystoreDB {
         let _wp = wd::watch_millis("KeystoreDB::migrate_key_namespace", 500);
 
         let destination = match destination.domain {
-            Domain::APP => KeyDescriptor { nspace: caller_uid as i64, ..(*destination).clone() },
-            Domain::SELINUX => (*destination).clone(),
+            Domain::APP | Domain::SELINUX => resolve_key_namespace(destination, caller_uid),
             domain => {
                 return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
                     .context(format!("Domain {:?} must be either APP or SELINUX.", domain));
@@ -2180,38 +4729,234 @@ impl KeystoreDB {
             .as_ref()
             .ok_or(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
             .context(ks_err!("Alias must be specified."))?;
+        check_alias(alias).context(ks_err!())?;
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            // Query the destination location. If there is a key, the migration request fails.
+            if tx
+                .query_row(
+                    "SELECT id FROM persistent.keyentry
+                     WHERE alias = ? AND domain = ? AND namespace = ?;",
+                    params![alias, destination.domain.0, destination.nspace],
+                    |_| Ok(()),
+                )
+                .optional()
+                .context("Failed to query destination.")?
+                .is_some()
+            {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context("Target already exists.");
+            }
+
+            // Keys outside of the APP domain are owned by the system user.
+            let destination_user_id = match destination.domain {
+                Domain::APP => uid_to_android_user(destination.nspace as u32),
+                _ => 0,
+            };
+            let bound_user_ids = Self::load_bound_user_ids(key_id_guard.id(), tx)
+                .context("Failed to load bound user ids.")?;
+            if bound_user_ids.iter().any(|user_id| *user_id as u32 != destination_user_id) {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT)).context(format!(
+                    "Bound blobs cannot be moved to user {}.",
+                    destination_user_id
+                ));
+            }
+
+            let updated = tx
+                .execute(
+                    "UPDATE persistent.keyentry
+                 SET alias = ?, domain = ?, namespace = ?
+                 WHERE id = ?;",
+                    params![alias, destination.domain.0, destination.nspace, key_id_guard.id()],
+                )
+                .context("Failed to update key entry.")?;
+
+            if updated != 1 {
+                return Err(KsError::sys())
+                    .context(format!("Update succeeded, but {} rows were updated.", updated));
+            }
+            KEY_HANDLES.invalidate_key(key_id_guard.id());
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Renames the client key `key`, which must be given by alias in `Domain::APP` or
+    /// `Domain::SELINUX`, to `new_alias` in the same namespace. The key keeps its id, blobs,
+    /// metadata, and grants. The rename fails with `ResponseCode::INVALID_ARGUMENT` if a key
+    /// with `new_alias` already exists in the namespace.
+    pub fn rename_key(
+        &mut self,
+        key: &KeyDescriptor,
+        new_alias: &str,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::rename_key", 500);
+
+        let access_key = match key.domain {
+            Domain::APP | Domain::SELINUX => resolve_key_namespace(key, caller_uid),
+            domain => {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Domain {:?} must be either APP or SELINUX.", domain));
+            }
+        };
+        check_alias(new_alias).context(ks_err!())?;
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let key_id = Self::load_key_entry_id(tx, &access_key, KeyType::Client)
+                .context(ks_err!("Failed to find key."))?;
+
+            // Security critical: Must return immediately on failure. Do not remove the '?';
+            check_permission(&access_key, None).context(ks_err!("Trying to check permission."))?;
+
+            if tx
+                .query_row(
+                    "SELECT id FROM persistent.keyentry
+                     WHERE alias = ? AND domain = ? AND namespace = ?;",
+                    params![new_alias, access_key.domain.0, access_key.nspace],
+                    |_| Ok(()),
+                )
+                .optional()
+                .context("Failed to query new alias.")?
+                .is_some()
+            {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Alias {:?} already exists.", new_alias));
+            }
+
+            let updated = tx
+                .execute(
+                    "UPDATE persistent.keyentry SET alias = ? WHERE id = ?;",
+                    params![new_alias, key_id],
+                )
+                .context("Failed to update key entry.")?;
+            if updated != 1 {
+                return Err(KsError::sys())
+                    .context(format!("Update succeeded, but {} rows were updated.", updated));
+            }
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Returns the fingerprint of `key`, i.e., the digest of its certificate with the algorithm
+    /// that computed it. It uses the `check_permission` callback like `load_key_entry`. Returns
+    /// None if the key has no certificate, e.g., because it is symmetric, or if it was stored
+    /// before fingerprints were recorded.
+    pub fn load_key_fingerprint(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<Option<KeyFingerprint>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_fingerprint", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (key_id, access_key_descriptor, access_vector) =
+                Self::load_access_tuple(tx, key, key_type, caller_uid).context(ks_err!())?;
+
+            // Perform access control. It is vital that we return here if the permission is
+            // denied. So do not touch that '?' at the end.
+            check_permission(&access_key_descriptor, access_vector).context(ks_err!())?;
+
+            let metadata = KeyMetaData::load_from_db(key_id, tx)?;
+            let algorithm = metadata
+                .public_key_fingerprint_algorithm()
+                .copied()
+                .unwrap_or(FingerprintAlgorithm::Sha256);
+            Ok(metadata
+                .public_key_fingerprint()
+                .map(|digest| KeyFingerprint { algorithm, digest: digest.clone() }))
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// The tags of stored key parameters that `load_key_creation_parameters` withholds, because
+    /// they identify the app, the user, or the device rather than describe the key.
+    const WITHHELD_CREATION_TAGS: &'static [Tag] = &[
+        Tag::APPLICATION_ID,
+        Tag::APPLICATION_DATA,
+        Tag::ATTESTATION_CHALLENGE,
+        Tag::ATTESTATION_APPLICATION_ID,
+        Tag::ATTESTATION_ID_BRAND,
+        Tag::ATTESTATION_ID_DEVICE,
+        Tag::ATTESTATION_ID_PRODUCT,
+        Tag::ATTESTATION_ID_SERIAL,
+        Tag::ATTESTATION_ID_IMEI,
+        Tag::ATTESTATION_ID_SECOND_IMEI,
+        Tag::ATTESTATION_ID_MEID,
+        Tag::ATTESTATION_ID_MANUFACTURER,
+        Tag::ATTESTATION_ID_MODEL,
+        Tag::ROOT_OF_TRUST,
+        Tag::UNIQUE_ID,
+        Tag::USER_SECURE_ID,
+    ];
+
+    /// Returns the stored key parameters of `key`, i.e., the parameters that it was created
+    /// with as enforced by KeyMint and keystore, without the parameters listed in
+    /// `WITHHELD_CREATION_TAGS`. Neither the key blob nor the certificates are loaded. It uses
+    /// the `check_permission` callback like `load_key_entry`.
+    pub fn load_key_creation_parameters(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<Vec<KeyParameter>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_creation_parameters", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (key_id, access_key_descriptor, access_vector) =
+                Self::load_access_tuple(tx, key, key_type, caller_uid).context(ks_err!())?;
+
+            // Perform access control. It is vital that we return here if the permission is
+            // denied. So do not touch that '?' at the end.
+            check_permission(&access_key_descriptor, access_vector).context(ks_err!())?;
+
+            let mut parameters = Self::load_key_parameters(key_id, tx).context(ks_err!())?;
+            parameters.retain(|kp| !Self::WITHHELD_CREATION_TAGS.contains(&kp.get_tag()));
+            Ok(parameters).no_gc()
+        })
+        .context(ks_err!())
+    }
 
-        self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            // Query the destination location. If there is a key, the migration request fails.
-            if tx
-                .query_row(
-                    "SELECT id FROM persistent.keyentry
-                     WHERE alias = ? AND domain = ? AND namespace = ?;",
-                    params![alias, destination.domain.0, destination.nspace],
-                    |_| Ok(()),
-                )
-                .optional()
-                .context("Failed to query destination.")?
-                .is_some()
-            {
-                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
-                    .context("Target already exists.");
-            }
+    /// Returns the uuid of the KeyMint instance that owns the key blob of `key`, as recorded in
+    /// the metadata of the current key blob. Neither the key blob nor the key parameters are
+    /// loaded. It uses the `check_permission` callback like `load_key_entry`. Fails with
+    /// `ResponseCode::KEY_NOT_FOUND` if the key does not exist or has no key blob.
+    pub fn load_key_km_uuid(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<Uuid> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_km_uuid", 500);
 
-            let updated = tx
-                .execute(
-                    "UPDATE persistent.keyentry
-                 SET alias = ?, domain = ?, namespace = ?
-                 WHERE id = ?;",
-                    params![alias, destination.domain.0, destination.nspace, key_id_guard.id()],
-                )
-                .context("Failed to update key entry.")?;
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (key_id, access_key_descriptor, access_vector) =
+                Self::load_access_tuple(tx, key, key_type, caller_uid).context(ks_err!())?;
 
-            if updated != 1 {
-                return Err(KsError::sys())
-                    .context(format!("Update succeeded, but {} rows were updated.", updated));
-            }
-            Ok(()).no_gc()
+            // Perform access control. It is vital that we return here if the permission is
+            // denied. So do not touch that '?' at the end.
+            check_permission(&access_key_descriptor, access_vector).context(ks_err!())?;
+
+            tx.query_row(
+                "SELECT data FROM persistent.blobmetadata
+                     WHERE tag = ? AND blobentryid = (
+                         SELECT MAX(id) FROM persistent.blobentry
+                         WHERE keyentryid = ? AND subcomponent_type = ?);",
+                params![BlobMetaData::KmUuid, key_id, SubComponentType::KEY_BLOB],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(ks_err!("Failed to query the KeyMint uuid."))?
+            .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context(ks_err!("Key has no key blob."))
+            .no_gc()
         })
         .context(ks_err!())
     }
@@ -2232,6 +4977,98 @@ impl KeystoreDB {
         metadata: &KeyMetaData,
         km_uuid: &Uuid,
     ) -> Result<KeyIdGuard> {
+        let BlobInfo { blob, metadata: blob_metadata, superseded_blob } = *blob_info;
+        let stored = self.store_new_key_internal(
+            key,
+            key_type,
+            params,
+            superseded_blob,
+            |tx, key_id| {
+                Self::set_blob_internal(
+                    tx,
+                    key_id,
+                    SubComponentType::KEY_BLOB,
+                    Some(blob),
+                    Some(blob_metadata),
+                )
+            },
+            cert_info,
+            None,
+            metadata,
+            km_uuid,
+            ExistingAlias::Replace,
+        )?;
+        match stored {
+            StoredKey::New(key_id) => Ok(key_id),
+            StoredKey::IdempotentRetry(_) => {
+                Err(KsError::sys()).context(ks_err!("Unexpected idempotent retry."))
+            }
+        }
+    }
+
+    /// Like `store_new_key`, but the key blob and its metadata are produced by `make_blob` from
+    /// the id of the new key entry. This allows binding the key blob to its key entry. Note that
+    /// `make_blob` may be called more than once if the transaction has to be retried.
+    /// `existing_alias` selects whether a live key with the same alias is replaced, the call
+    /// fails, or the live key is kept. If given, `wrap_cert_chain` wraps the certificate chain
+    /// of `cert_info` in the same way before it is stored.
+    #[allow(clippy::too_many_arguments)]
+    pub fn store_new_key_with_blob<F>(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        params: &[KeyParameter],
+        make_blob: F,
+        cert_info: &CertificateInfo,
+        wrap_cert_chain: Option<&WrapCertChain>,
+        metadata: &KeyMetaData,
+        km_uuid: &Uuid,
+        existing_alias: ExistingAlias,
+    ) -> Result<StoredKey>
+    where
+        F: Fn(i64) -> Result<(Vec<u8>, BlobMetaData)>,
+    {
+        self.store_new_key_internal(
+            key,
+            key_type,
+            params,
+            None,
+            |tx, key_id| {
+                let (blob, blob_metadata) =
+                    make_blob(key_id).context("Trying to create the key blob.")?;
+                Self::set_blob_internal(
+                    tx,
+                    key_id,
+                    SubComponentType::KEY_BLOB,
+                    Some(&blob),
+                    Some(&blob_metadata),
+                )
+            },
+            cert_info,
+            wrap_cert_chain,
+            metadata,
+            km_uuid,
+            existing_alias,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn store_new_key_internal<F>(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        params: &[KeyParameter],
+        superseded_blob: Option<(&[u8], &BlobMetaData)>,
+        insert_blob: F,
+        cert_info: &CertificateInfo,
+        wrap_cert_chain: Option<&WrapCertChain>,
+        metadata: &KeyMetaData,
+        km_uuid: &Uuid,
+        existing_alias: ExistingAlias,
+    ) -> Result<StoredKey>
+    where
+        F: Fn(&Transaction, i64) -> Result<()>,
+    {
         let _wp = wd::watch_millis("KeystoreDB::store_new_key", 500);
 
         let (alias, domain, namespace) = match key {
@@ -2245,9 +5082,40 @@ impl KeystoreDB {
             }
         };
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let existing_key_id = match existing_alias {
+                ExistingAlias::Replace => None,
+                _ => tx
+                    .query_row(
+                        "SELECT id FROM persistent.keyentry
+                         WHERE alias = ? AND domain = ? AND namespace = ? AND key_type = ?
+                            AND state = ?;",
+                        params![alias, domain.0, namespace, key_type, KeyLifeCycle::Live],
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .optional()
+                    .context("Failed to query the alias.")?,
+            };
+            match (existing_alias, existing_key_id) {
+                (ExistingAlias::Fail, Some(_)) => {
+                    return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                        .context(ks_err!("Alias {:?} already exists.", alias));
+                }
+                (ExistingAlias::KeepIdempotentRetry(created_after), Some(existing_key_id)) => {
+                    // The check is in the transaction that stores the new key, so that of two
+                    // concurrent requests with the same idempotency token only one stores a key.
+                    if KeyMetaData::load_from_db(existing_key_id, tx)
+                        .context("Failed to load the metadata of the existing key.")?
+                        .is_idempotent_retry(metadata, created_after)
+                        .context(ks_err!())?
+                    {
+                        return Ok(StoredKey::IdempotentRetry(existing_key_id)).no_gc();
+                    }
+                }
+                _ => {}
+            }
+
             let key_id = Self::create_key_entry_internal(tx, &domain, namespace, key_type, km_uuid)
                 .context("Trying to create new key entry.")?;
-            let BlobInfo { blob, metadata: blob_metadata, superseded_blob } = *blob_info;
 
             // In some occasions the key blob is already upgraded during the import.
             // In order to make sure it gets properly deleted it is inserted into the
@@ -2268,25 +5136,29 @@ impl KeystoreDB {
                 false
             };
 
-            Self::set_blob_internal(
-                tx,
-                key_id.id(),
-                SubComponentType::KEY_BLOB,
-                Some(blob),
-                Some(blob_metadata),
-            )
-            .context("Trying to insert the key blob.")?;
+            insert_blob(tx, key_id.id()).context("Trying to insert the key blob.")?;
             if let Some(cert) = &cert_info.cert {
                 Self::set_blob_internal(tx, key_id.id(), SubComponentType::CERT, Some(cert), None)
                     .context("Trying to insert the certificate.")?;
             }
             if let Some(cert_chain) = &cert_info.cert_chain {
+                let wrapped = match wrap_cert_chain {
+                    Some(wrap) => wrap(key_id.id(), cert_chain)
+                        .context("Trying to wrap the certificate chain.")?,
+                    None => None,
+                };
+                let (cert_chain, cert_chain_metadata) = match &wrapped {
+                    Some((wrapped_chain, wrapped_metadata)) => {
+                        (wrapped_chain.as_slice(), Some(wrapped_metadata))
+                    }
+                    None => (cert_chain.as_slice(), None),
+                };
                 Self::set_blob_internal(
                     tx,
                     key_id.id(),
                     SubComponentType::CERT_CHAIN,
                     Some(cert_chain),
-                    None,
+                    cert_chain_metadata,
                 )
                 .context("Trying to insert the certificate chain.")?;
             }
@@ -2296,9 +5168,13 @@ impl KeystoreDB {
             let need_gc = Self::rebind_alias(tx, &key_id, alias, &domain, namespace, key_type)
                 .context("Trying to rebind alias.")?
                 || need_gc;
-            Ok(key_id).do_gc(need_gc)
+            Ok(StoredKey::New(key_id)).do_gc(need_gc)
         })
         .context(ks_err!())
+        .map(|stored| match stored {
+            StoredKey::New(key_id) => StoredKey::New(Self::notify_created(key_id, key_type)),
+            retry => retry,
+        })
     }
 
     /// Store a new certificate
@@ -2348,6 +5224,17 @@ impl KeystoreDB {
             Ok(key_id).do_gc(need_gc)
         })
         .context(ks_err!())
+        .map(|key_id| Self::notify_created(key_id, key_type))
+    }
+
+    /// Emits the key lifecycle event for a newly stored key. Only client keys are observable.
+    /// Certificates that were cached for a previous key with the same id are dropped.
+    fn notify_created(key_id: KeyIdGuard, key_type: KeyType) -> KeyIdGuard {
+        ATTESTATION_CERT_CACHE.invalidate(key_id.id());
+        if key_type == KeyType::Client {
+            notify_key_created(key_id.id());
+        }
+        key_id
     }
 
     // Helper function loading the key_id given the key descriptor
@@ -2408,10 +5295,7 @@ impl KeystoreDB {
             // of the caller supplied namespace if the domain field is
             // Domain::APP.
             Domain::APP | Domain::SELINUX => {
-                let mut access_key = key.clone();
-                if access_key.domain == Domain::APP {
-                    access_key.nspace = caller_uid as i64;
-                }
+                let access_key = resolve_key_namespace(key, caller_uid);
                 let key_id = Self::load_key_entry_id(tx, &access_key, key_type)
                     .with_context(|| format!("With key.domain = {:?}.", access_key.domain))?;
 
@@ -2527,18 +5411,19 @@ impl KeystoreDB {
             has_km_blob = has_km_blob || sub_type == SubComponentType::KEY_BLOB;
             match (sub_type, load_bits.load_public(), load_bits.load_km()) {
                 (SubComponentType::KEY_BLOB, _, true) => {
-                    key_blob = Some((
-                        row.get(0).context("Failed to extract key blob id.")?,
-                        row.get(2).context("Failed to extract key blob.")?,
-                    ));
+                    let blob: Vec<u8> = row.get(2).context("Failed to extract key blob.")?;
+                    Self::check_key_blob_size(key_id, &blob)?;
+                    key_blob = Some((row.get(0).context("Failed to extract key blob id.")?, blob));
                 }
                 (SubComponentType::CERT, true, _) => {
                     cert_blob =
                         Some(row.get(2).context("Failed to extract public certificate blob.")?);
                 }
                 (SubComponentType::CERT_CHAIN, true, _) => {
-                    cert_chain_blob =
-                        Some(row.get(2).context("Failed to extract certificate chain blob.")?);
+                    let blob: Vec<u8> =
+                        row.get(2).context("Failed to extract certificate chain blob.")?;
+                    Self::check_cert_chain_size(&blob)?;
+                    cert_chain_blob = Some(blob);
                 }
                 (SubComponentType::CERT, _, _)
                 | (SubComponentType::CERT_CHAIN, _, _)
@@ -2593,6 +5478,7 @@ impl KeystoreDB {
     pub fn check_and_update_key_usage_count(&mut self, key_id: i64) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::check_and_update_key_usage_count", 500);
 
+        let tombstones = self.tombstones_enabled();
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             let limit: Option<i32> = tx
                 .query_row(
@@ -2616,7 +5502,7 @@ impl KeystoreDB {
             .context("Failed to update key usage count.")?;
 
             match limit {
-                1 => Self::mark_unreferenced(tx, key_id)
+                1 => Self::mark_unreferenced(tx, key_id, tombstones)
                     .map(|need_gc| (need_gc, ()))
                     .context("Trying to mark limited use key for deletion."),
                 0 => Err(KsError::Km(ErrorCode::INVALID_KEY_BLOB)).context("Key is exhausted."),
@@ -2630,7 +5516,7 @@ impl KeystoreDB {
     /// It uses the `check_permission` callback to verify if the access is allowed
     /// given the key access tuple read from the database using `load_access_tuple`.
     /// With `load_bits` the caller may specify which blobs shall be loaded from
-    /// the blob database.
+    /// the blob database. The returned guard holds an exclusive lock on the key.
     pub fn load_key_entry(
         &mut self,
         key: &KeyDescriptor,
@@ -2638,6 +5524,28 @@ impl KeystoreDB {
         load_bits: KeyEntryLoadBits,
         caller_uid: u32,
         check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<(KeyIdGuard, KeyEntry)> {
+        self.load_key_entry_with_mode(
+            key,
+            key_type,
+            load_bits,
+            caller_uid,
+            KeyIdLockMode::Exclusive,
+            check_permission,
+        )
+    }
+
+    /// Like `load_key_entry`, but the returned guard holds a lock in `lock_mode`. Callers that
+    /// only read the key entry may use `KeyIdLockMode::Shared`, so that they do not block each
+    /// other. A guard with a shared lock must not be used to modify the key entry.
+    pub fn load_key_entry_with_mode(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        load_bits: KeyEntryLoadBits,
+        caller_uid: u32,
+        lock_mode: KeyIdLockMode,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
     ) -> Result<(KeyIdGuard, KeyEntry)> {
         let _wp = wd::watch_millis("KeystoreDB::load_key_entry", 500);
 
@@ -2647,6 +5555,7 @@ impl KeystoreDB {
                 key_type,
                 load_bits,
                 caller_uid,
+                lock_mode,
                 &check_permission,
             ) {
                 Ok(result) => break Ok(result),
@@ -2668,13 +5577,18 @@ impl KeystoreDB {
         key_type: KeyType,
         load_bits: KeyEntryLoadBits,
         caller_uid: u32,
+        lock_mode: KeyIdLockMode,
         check_permission: &impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
     ) -> Result<(KeyIdGuard, KeyEntry)> {
         // KEY ID LOCK 1/2
         // If we got a key descriptor with a key id we can get the lock right away.
         // Otherwise we have to defer it until we know the key id.
         let key_id_guard = match key.domain {
-            Domain::KEY_ID => Some(KEY_ID_LOCK.get(key.nspace)),
+            Domain::KEY_ID => Some(KEY_ID_LOCK.get_with_mode(
+                key.nspace,
+                lock_mode,
+                KeyIdLockOperation::LoadKeyEntry,
+            )),
             _ => None,
         };
 
@@ -2703,13 +5617,21 @@ impl KeystoreDB {
         // that the caller had access to the given key. But we need to make sure that the
         // key id still exists. So we have to load the key entry by key id this time.
         let (key_id_guard, tx) = match key_id_guard {
-            None => match KEY_ID_LOCK.try_get(key_id) {
+            None => match KEY_ID_LOCK.try_get_with_mode(
+                key_id,
+                lock_mode,
+                KeyIdLockOperation::LoadKeyEntry,
+            ) {
                 None => {
                     // Roll back the transaction.
                     tx.rollback().context(ks_err!("Failed to roll back transaction."))?;
 
                     // Block until we have a key id lock.
-                    let key_id_guard = KEY_ID_LOCK.get(key_id);
+                    let key_id_guard = KEY_ID_LOCK.get_with_mode(
+                        key_id,
+                        lock_mode,
+                        KeyIdLockOperation::LoadKeyEntry,
+                    );
 
                     // Create a new transaction.
                     let tx = self
@@ -2745,16 +5667,120 @@ impl KeystoreDB {
         Ok((key_id_guard, key_entry))
     }
 
-    fn mark_unreferenced(tx: &Transaction, key_id: i64) -> Result<bool> {
+    /// Resolves `key` on behalf of `caller_uid` and checks the permission like `load_key_entry`.
+    /// Returns a handle that the caller can present to `load_key_entry_by_handle` to load the key
+    /// entry again without repeating the resolution and the permission check.
+    pub fn resolve_key_handle(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<KeyHandle> {
+        let _wp = wd::watch_millis("KeystoreDB::resolve_key_handle", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let (key_id, access_key_descriptor, access_vector) =
+                Self::load_access_tuple(tx, key, key_type, caller_uid)
+                    .context("Trying to get access tuple.")?;
+
+            // Perform access control. It is vital that we return here if the permission is denied.
+            // So do not touch that '?' at the end.
+            check_permission(&access_key_descriptor, access_vector)
+                .context("While checking permission.")?;
+
+            Ok(KEY_HANDLES.insert(KeyHandleEntry { key_id, caller_uid, access_key_descriptor }))
+                .no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Loads the key entry of `handle` like `load_key_entry`, but without resolving a key
+    /// descriptor and without checking the permission again. Fails with
+    /// `ResponseCode::KEY_NOT_FOUND` if the handle was not issued to `caller_uid` by
+    /// `resolve_key_handle`, or if it was invalidated. The caller must then resolve the key
+    /// descriptor again.
+    pub fn load_key_entry_by_handle(
+        &mut self,
+        handle: KeyHandle,
+        key_type: KeyType,
+        load_bits: KeyEntryLoadBits,
+        caller_uid: u32,
+    ) -> Result<(KeyIdGuard, KeyEntry)> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_entry_by_handle", 500);
+
+        let key_id = KEY_HANDLES
+            .get(handle, caller_uid)
+            .ok_or(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context(ks_err!("Invalid key handle."))?;
+        let key_id_guard = KEY_ID_LOCK.get(key_id, KeyIdLockOperation::LoadKeyEntry);
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            // The key entry may have been deleted since the handle was checked.
+            Self::load_access_tuple(
+                tx,
+                &KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() },
+                key_type,
+                caller_uid,
+            )
+            .context("Trying to check that the key entry exists.")?;
+            Self::load_key_components(tx, load_bits, key_id).no_gc()
+        })
+        .map(|key_entry| (key_id_guard, key_entry))
+        .map_err(|e| {
+            if matches!(
+                e.root_cause().downcast_ref::<KsError>(),
+                Some(KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+            ) {
+                KEY_HANDLES.remove(handle);
+            }
+            e
+        })
+        .context(ks_err!())
+    }
+
+    /// Deletes the key entry with the given id and everything that refers to it except for its
+    /// blobs, which are left to the garbage collector. If `tombstone` is true, a tombstone of the
+    /// key is written, see `KeyTombstone`. Returns true if a key entry was deleted.
+    fn mark_unreferenced(tx: &Transaction, key_id: i64, tombstone: bool) -> Result<bool> {
+        KEY_HANDLES.invalidate_key(key_id);
         let updated = tx
             .execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])
             .context("Trying to delete keyentry.")?;
+        if updated != 0 && tombstone {
+            tx.execute(
+                "INSERT INTO persistent.keytombstone (key_id, deletion_time, caller_uid)
+                 VALUES (?, ?, ?);",
+                params![
+                    key_id,
+                    DateTime::now().context(ks_err!("Trying to make deletion time."))?,
+                    ThreadState::get_calling_uid(),
+                ],
+            )
+            .context("Trying to insert tombstone.")?;
+        }
         tx.execute("DELETE FROM persistent.keymetadata WHERE keyentryid = ?;", params![key_id])
             .context("Trying to delete keymetadata.")?;
+        tx.execute(
+            "DELETE FROM persistent.keycustommetadata WHERE keyentryid = ?;",
+            params![key_id],
+        )
+        .context("Trying to delete keycustommetadata.")?;
         tx.execute("DELETE FROM persistent.keyparameter WHERE keyentryid = ?;", params![key_id])
             .context("Trying to delete keyparameters.")?;
         tx.execute("DELETE FROM persistent.grant WHERE keyentryid = ?;", params![key_id])
             .context("Trying to delete grants.")?;
+        tx.execute(
+            "DELETE FROM persistent.operationcheckpointinput WHERE checkpointid IN
+                 (SELECT id FROM persistent.operationcheckpoint WHERE keyentryid = ?);",
+            params![key_id],
+        )
+        .context("Trying to delete the input of operation checkpoints.")?;
+        tx.execute(
+            "DELETE FROM persistent.operationcheckpoint WHERE keyentryid = ?;",
+            params![key_id],
+        )
+        .context("Trying to delete operation checkpoints.")?;
+        Self::discard_staged_key_backend(tx, key_id).context("Trying to delete staged keys.")?;
         Ok(updated != 0)
     }
 
@@ -2769,6 +5795,7 @@ impl KeystoreDB {
     ) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::unbind_key", 500);
 
+        let tombstones = self.tombstones_enabled();
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             let (key_id, access_key_descriptor, access_vector) =
                 Self::load_access_tuple(tx, key, key_type, caller_uid)
@@ -2779,11 +5806,16 @@ impl KeystoreDB {
             check_permission(&access_key_descriptor, access_vector)
                 .context("While checking permission.")?;
 
-            Self::mark_unreferenced(tx, key_id)
-                .map(|need_gc| (need_gc, ()))
+            Self::mark_unreferenced(tx, key_id, tombstones)
+                .map(|need_gc| (need_gc, key_id))
                 .context("Trying to mark the key unreferenced.")
         })
         .context(ks_err!())
+        .map(|key_id| {
+            if key_type == KeyType::Client {
+                notify_key_deleted(key_id, caller_uid);
+            }
+        })
     }
 
     fn get_key_km_uuid(tx: &Transaction, key_id: i64) -> Result<Uuid> {
@@ -2795,15 +5827,40 @@ impl KeystoreDB {
         .context(ks_err!())
     }
 
+    /// Deletes all keys of the app with the given namespace, e.g., when the app is uninstalled,
+    /// see `unbind_keys_for_namespace`. Returns the number of deleted keys.
+    pub fn delete_namespace(&mut self, namespace: i64) -> Result<usize> {
+        self.unbind_keys_for_namespace(Domain::APP, namespace).context(ks_err!())
+    }
+
     /// Delete all artifacts belonging to the namespace given by the domain-namespace tuple.
-    /// This leaves all of the blob entries orphaned for subsequent garbage collection.
-    pub fn unbind_keys_for_namespace(&mut self, domain: Domain, namespace: i64) -> Result<()> {
+    /// This leaves all of the blob entries orphaned for subsequent garbage collection, which
+    /// deletes them from KeyMint and the database. Returns the number of deleted keys.
+    pub fn unbind_keys_for_namespace(&mut self, domain: Domain, namespace: i64) -> Result<usize> {
         let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_namespace", 500);
 
         if !(domain == Domain::APP || domain == Domain::SELINUX) {
             return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!());
         }
+        KEY_HANDLES.invalidate_namespace(domain, namespace);
+        let tombstones = self.tombstones_enabled();
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            if tombstones {
+                tx.execute(
+                    "INSERT INTO persistent.keytombstone (key_id, deletion_time, caller_uid)
+                     SELECT id, ?, ? FROM persistent.keyentry
+                     WHERE domain = ? AND namespace = ? AND (key_type = ? OR key_type = ?);",
+                    params![
+                        DateTime::now().context(ks_err!("Trying to make deletion time."))?,
+                        ThreadState::get_calling_uid(),
+                        domain.0,
+                        namespace,
+                        KeyType::Client,
+                        KeyType::Attestation
+                    ],
+                )
+                .context("Trying to insert tombstones.")?;
+            }
             tx.execute(
                 "DELETE FROM persistent.keymetadata
                 WHERE keyentryid IN (
@@ -2813,6 +5870,15 @@ impl KeystoreDB {
                 params![domain.0, namespace, KeyType::Client, KeyType::Attestation],
             )
             .context("Trying to delete keymetadata.")?;
+            tx.execute(
+                "DELETE FROM persistent.keycustommetadata
+                WHERE keyentryid IN (
+                    SELECT id FROM persistent.keyentry
+                    WHERE domain = ? AND namespace = ? AND (key_type = ? OR key_type = ?)
+                );",
+                params![domain.0, namespace, KeyType::Client, KeyType::Attestation],
+            )
+            .context("Trying to delete keycustommetadata.")?;
             tx.execute(
                 "DELETE FROM persistent.keyparameter
                 WHERE keyentryid IN (
@@ -2831,13 +5897,14 @@ impl KeystoreDB {
                 params![domain.0, namespace, KeyType::Client, KeyType::Attestation],
             )
             .context("Trying to delete grants.")?;
-            tx.execute(
-                "DELETE FROM persistent.keyentry
-                 WHERE domain = ? AND namespace = ? AND (key_type = ? OR key_type = ?);",
-                params![domain.0, namespace, KeyType::Client, KeyType::Attestation],
-            )
-            .context("Trying to delete keyentry.")?;
-            Ok(()).need_gc()
+            let deleted = tx
+                .execute(
+                    "DELETE FROM persistent.keyentry
+                     WHERE domain = ? AND namespace = ? AND (key_type = ? OR key_type = ?);",
+                    params![domain.0, namespace, KeyType::Client, KeyType::Attestation],
+                )
+                .context("Trying to delete keyentry.")?;
+            Ok(deleted).need_gc()
         })
         .context(ks_err!())
     }
@@ -2854,6 +5921,15 @@ impl KeystoreDB {
                 params![KeyLifeCycle::Unreferenced],
             )
             .context("Trying to delete keymetadata.")?;
+            tx.execute(
+                "DELETE FROM persistent.keycustommetadata
+            WHERE keyentryid IN (
+                SELECT id FROM persistent.keyentry
+                WHERE state = ?
+            );",
+                params![KeyLifeCycle::Unreferenced],
+            )
+            .context("Trying to delete keycustommetadata.")?;
             tx.execute(
                 "DELETE FROM persistent.keyparameter
             WHERE keyentryid IN (
@@ -2894,6 +5970,7 @@ impl KeystoreDB {
     ) -> Result<()> {
         let _wp = wd::watch_millis("KeystoreDB::unbind_keys_for_user", 500);
 
+        let tombstones = self.tombstones_enabled();
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
             let mut stmt = tx
                 .prepare(&format!(
@@ -2938,27 +6015,165 @@ impl KeystoreDB {
             .context(ks_err!())?;
 
             let mut notify_gc = false;
-            for key_id in key_ids {
-                if keep_non_super_encrypted_keys {
-                    // Load metadata and filter out non-super-encrypted keys.
-                    if let (_, Some((_, blob_metadata)), _, _) =
-                        Self::load_blob_components(key_id, KeyEntryLoadBits::KM, tx)
-                            .context(ks_err!("Trying to load blob info."))?
-                    {
-                        if blob_metadata.encrypted_by().is_none() {
-                            continue;
-                        }
-                    }
-                }
-                notify_gc = Self::mark_unreferenced(tx, key_id)
-                    .context("In unbind_keys_for_user.")?
+            for key_id in key_ids {
+                if keep_non_super_encrypted_keys {
+                    // Load metadata and filter out non-super-encrypted keys.
+                    if let (_, Some((_, blob_metadata)), _, _) =
+                        Self::load_blob_components(key_id, KeyEntryLoadBits::KM, tx)
+                            .context(ks_err!("Trying to load blob info."))?
+                    {
+                        if blob_metadata.encrypted_by().is_none() {
+                            continue;
+                        }
+                    }
+                }
+                notify_gc = Self::mark_unreferenced(tx, key_id, tombstones)
+                    .context("In unbind_keys_for_user.")?
+                    || notify_gc;
+            }
+            Ok(()).do_gc(notify_gc)
+        })
+        .context(ks_err!())
+    }
+
+    fn load_keys_bound_to_sid(tx: &Transaction, sid: i64) -> Result<Vec<(i64, KeyDescriptor)>> {
+        let mut stmt = tx
+            .prepare(
+                "SELECT DISTINCT keyentry.id, keyentry.domain, keyentry.namespace, keyentry.alias
+                 FROM persistent.keyentry
+                 INNER JOIN persistent.keyparameter
+                    ON keyentry.id = keyparameter.keyentryid
+                 WHERE keyparameter.tag = ?
+                 AND keyparameter.data = ?
+                 AND keyentry.key_type = ?
+                 AND keyentry.state = ?
+                 ORDER BY keyentry.id ASC;",
+            )
+            .context("Failed to prepare the query to find the keys bound to the secure user id.")?;
+        let mut rows = stmt
+            .query(params![Tag::USER_SECURE_ID.0, sid, KeyType::Client, KeyLifeCycle::Live])
+            .context("Failed to query the keys bound to the secure user id.")?;
+
+        let mut keys: Vec<(i64, KeyDescriptor)> = Vec::new();
+        db_utils::with_rows_extract_all(&mut rows, |row| {
+            keys.push((
+                row.get(0).context("Failed to read key id.")?,
+                KeyDescriptor {
+                    domain: Domain(row.get(1).context("Failed to read domain.")?),
+                    nspace: row.get(2).context("Failed to read namespace.")?,
+                    alias: row.get(3).context("Failed to read alias.")?,
+                    blob: None,
+                },
+            ));
+            Ok(())
+        })
+        .context("Failed to extract the keys bound to the secure user id.")?;
+        Ok(keys)
+    }
+
+    /// Lists all client keys that are bound to the given secure user id, e.g., the SID of a
+    /// biometric enrollment, through a `Tag::USER_SECURE_ID` key parameter.
+    pub fn list_keys_bound_to_sid(&mut self, sid: i64) -> Result<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_keys_bound_to_sid", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            Self::load_keys_bound_to_sid(tx, sid)
+                .map(|keys| keys.into_iter().map(|(_, key)| key).collect())
+                .no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Deletes all client keys that are bound to the given secure user id in one transaction.
+    /// This is used when the corresponding authenticator enrollment was removed, because such
+    /// keys can never be authorized again. Returns the number of deleted keys.
+    pub fn revoke_keys_bound_to_sid(&mut self, sid: i64) -> Result<usize> {
+        let _wp = wd::watch_millis("KeystoreDB::revoke_keys_bound_to_sid", 500);
+
+        let tombstones = self.tombstones_enabled();
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let keys = Self::load_keys_bound_to_sid(tx, sid).context(ks_err!())?;
+            let mut notify_gc = false;
+            for (key_id, _) in &keys {
+                notify_gc = Self::mark_unreferenced(tx, *key_id, tombstones)
+                    .context("In revoke_keys_bound_to_sid.")?
                     || notify_gc;
             }
-            Ok(()).do_gc(notify_gc)
+            Ok(keys.len()).do_gc(notify_gc)
         })
         .context(ks_err!())
     }
 
+    /// Freezes or unfreezes the given key. A frozen key stays in the database with all its
+    /// components, but must not be used until it is unfrozen. It uses the `check_permission`
+    /// callback to verify if the access is allowed given the key access tuple read from the
+    /// database using `load_access_tuple`.
+    pub fn set_key_frozen(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        frozen: bool,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_key_frozen", 500);
+
+        self.set_key_metadata_entry(
+            key,
+            key_type,
+            caller_uid,
+            KeyMetaEntry::Frozen(frozen),
+            check_permission,
+        )
+        .context(ks_err!())
+    }
+
+    /// Marks the given key as exportable or not. Only exportable keys may be wrapped for export
+    /// to another device, see `key_export`. It uses the `check_permission` callback to verify if
+    /// the access is allowed given the key access tuple read from the database using
+    /// `load_access_tuple`.
+    pub fn set_key_exportable(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        exportable: bool,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_key_exportable", 500);
+
+        self.set_key_metadata_entry(
+            key,
+            key_type,
+            caller_uid,
+            KeyMetaEntry::Exportable(exportable),
+            check_permission,
+        )
+        .context(ks_err!())
+    }
+
+    fn set_key_metadata_entry(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        entry: KeyMetaEntry,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<()> {
+        let mut metadata = KeyMetaData::new();
+        metadata.add(entry);
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let (key_id, access_key_descriptor, access_vector) =
+                Self::load_access_tuple(tx, key, key_type, caller_uid).context(ks_err!())?;
+
+            // Perform access control. It is vital that we return here if the permission is
+            // denied. So do not touch that '?' at the end.
+            check_permission(&access_key_descriptor, access_vector).context(ks_err!())?;
+
+            metadata.store_in_db(key_id, tx).no_gc()
+        })
+    }
+
     fn load_key_components(
         tx: &Transaction,
         load_bits: KeyEntryLoadBits,
@@ -2968,6 +6183,11 @@ impl KeystoreDB {
 
         let (has_km_blob, key_blob_info, cert_blob, cert_chain_blob) =
             Self::load_blob_components(key_id, load_bits, tx).context("In load_key_components.")?;
+        let cert_chain_metadata = match cert_chain_blob {
+            Some(_) => Self::load_cert_chain_metadata(key_id, tx)
+                .context("In load_key_components: Trying to load the cert chain metadata.")?,
+            None => BlobMetaData::new(),
+        };
 
         let parameters = Self::load_key_parameters(key_id, tx)
             .context("In load_key_components: Trying to load key parameters.")?;
@@ -2980,6 +6200,7 @@ impl KeystoreDB {
             key_blob_info,
             cert: cert_blob,
             cert_chain: cert_chain_blob,
+            cert_chain_metadata,
             km_uuid,
             parameters,
             metadata,
@@ -2987,6 +6208,41 @@ impl KeystoreDB {
         })
     }
 
+    /// Loads the metadata of the most recent certificate chain blob of the key entry `key_id`.
+    fn load_cert_chain_metadata(key_id: i64, tx: &Transaction) -> Result<BlobMetaData> {
+        let blob_id: i64 = tx
+            .query_row(
+                "SELECT MAX(id) FROM persistent.blobentry
+                    WHERE keyentryid = ? AND subcomponent_type = ?;",
+                params![key_id, SubComponentType::CERT_CHAIN],
+                |row| row.get(0),
+            )
+            .context(ks_err!("Failed to get the cert chain blob id."))?;
+        BlobMetaData::load_from_db(blob_id, tx).context(ks_err!())
+    }
+
+    /// Returns the user ids recorded in the metadata of the most recent blobs of the key entry
+    /// `key_id` that are bound to their key entry, see `BlobMetaEntry::BoundUserId`.
+    fn load_bound_user_ids(key_id: i64, tx: &Transaction) -> Result<Vec<i32>> {
+        let mut stmt = tx
+            .prepare(
+                "SELECT MAX(id) FROM persistent.blobentry
+                    WHERE keyentryid = ? GROUP BY subcomponent_type;",
+            )
+            .context(ks_err!("Failed to prepare statement."))?;
+        let blob_ids = stmt
+            .query_map(params![key_id], |row| row.get(0))
+            .context(ks_err!("Failed to query blob ids."))?
+            .collect::<rusqlite::Result<Vec<i64>>>()
+            .context(ks_err!("Failed to extract blob ids."))?;
+        let mut user_ids = Vec::new();
+        for blob_id in blob_ids {
+            let metadata = BlobMetaData::load_from_db(blob_id, tx).context(ks_err!())?;
+            user_ids.extend(metadata.bound_user_id());
+        }
+        Ok(user_ids)
+    }
+
     /// Returns a list of KeyDescriptors in the selected domain/namespace whose
     /// aliases are greater than the specified 'start_past_alias'. If no value
     /// is provided, returns all KeyDescriptors.
@@ -3047,6 +6303,79 @@ impl KeystoreDB {
         })
     }
 
+    /// Returns the public parts of the keys in the selected domain/namespace whose aliases are
+    /// greater than `start_past_alias`, sorted by alias, in a single transaction. The list ends
+    /// before the estimated size of the parts exceeds `max_bytes`, but contains at least one
+    /// key unless there is none, so that the caller can page through all keys by passing the
+    /// last alias of one page as `start_past_alias` of the next. Key blobs are never loaded.
+    /// Domain must be APP or SELINUX, the caller must make sure of that.
+    pub fn list_public_parts_past_alias(
+        &mut self,
+        domain: Domain,
+        namespace: i64,
+        key_type: KeyType,
+        start_past_alias: Option<&str>,
+        max_bytes: usize,
+    ) -> Result<Vec<PublicKeyParts>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_public_parts_past_alias", 500);
+
+        let query = format!(
+            "SELECT id, alias FROM persistent.keyentry
+                     WHERE domain = ?
+                     AND namespace = ?
+                     AND alias IS NOT NULL
+                     AND state = ?
+                     AND key_type = ?
+                     {}
+                     ORDER BY alias ASC;",
+            if start_past_alias.is_some() { " AND alias > ?" } else { "" }
+        );
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx.prepare(&query).context(ks_err!("Failed to prepare."))?;
+
+            let mut rows = match start_past_alias {
+                Some(past_alias) => stmt
+                    .query(params![
+                        domain.0 as u32,
+                        namespace,
+                        KeyLifeCycle::Live,
+                        key_type,
+                        past_alias
+                    ])
+                    .context(ks_err!("Failed to query."))?,
+                None => stmt
+                    .query(params![domain.0 as u32, namespace, KeyLifeCycle::Live, key_type])
+                    .context(ks_err!("Failed to query."))?,
+            };
+
+            let mut keys: Vec<(i64, String)> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                keys.push((
+                    row.get(0).context("Trying to extract key id.")?,
+                    row.get(1).context("Trying to extract alias.")?,
+                ));
+                Ok(())
+            })
+            .context(ks_err!("Failed to extract rows."))?;
+
+            let mut parts: Vec<PublicKeyParts> = Vec::new();
+            let mut total_bytes: usize = 0;
+            for (key_id, alias) in keys {
+                let (_, _, cert, cert_chain) =
+                    Self::load_blob_components(key_id, KeyEntryLoadBits::PUBLIC, tx)
+                        .context(ks_err!("Failed to load the certificates of {}.", alias))?;
+                let key_parts = PublicKeyParts { alias, cert, cert_chain };
+                total_bytes += key_parts.estimated_size();
+                if total_bytes > max_bytes && !parts.is_empty() {
+                    break;
+                }
+                parts.push(key_parts);
+            }
+            Ok(parts).no_gc()
+        })
+    }
+
     /// Returns a number of KeyDescriptors in the selected domain/namespace.
     /// Domain must be APP or SELINUX, the caller must make sure of that.
     pub fn count_keys(
@@ -3074,6 +6403,226 @@ impl KeystoreDB {
         Ok(num_keys)
     }
 
+    /// Loads the current key blobs of up to `limit` live client keys, ordered by key id. If
+    /// `after_key_id` is given, only keys with a greater key id are loaded. This allows callers
+    /// to walk all key blobs in batches. Returns the key id, the domain and namespace of the
+    /// owner of the key, the key blob, and its metadata.
+    pub fn load_key_blobs_after(
+        &mut self,
+        after_key_id: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<(i64, KeyDescriptor, Vec<u8>, BlobMetaData)>> {
+        let _wp = wd::watch_millis("KeystoreDB::load_key_blobs_after", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT keyentry.id, MAX(blobentry.id), blobentry.blob,
+                            keyentry.domain, keyentry.namespace
+                     FROM persistent.keyentry
+                     INNER JOIN persistent.blobentry ON keyentry.id = blobentry.keyentryid
+                     WHERE (?1 IS NULL OR keyentry.id > ?1)
+                     AND keyentry.state = ?2
+                     AND keyentry.key_type = ?3
+                     AND blobentry.subcomponent_type = ?4
+                     GROUP BY keyentry.id
+                     ORDER BY keyentry.id
+                     LIMIT ?5;",
+                )
+                .context(ks_err!("Failed to prepare statement."))?;
+            let mut rows = stmt
+                .query(params![
+                    after_key_id,
+                    KeyLifeCycle::Live,
+                    KeyType::Client,
+                    SubComponentType::KEY_BLOB,
+                    limit as i64
+                ])
+                .context(ks_err!("Failed to query."))?;
+            let mut blobs: Vec<(i64, i64, Vec<u8>, KeyDescriptor)> = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                blobs.push((
+                    row.get(0).context("Failed to read key id.")?,
+                    row.get(1).context("Failed to read blob id.")?,
+                    row.get(2).context("Failed to read key blob.")?,
+                    KeyDescriptor {
+                        domain: Domain(row.get(3).context("Failed to read domain.")?),
+                        nspace: row.get(4).context("Failed to read namespace.")?,
+                        ..Default::default()
+                    },
+                ));
+                Ok(())
+            })
+            .context(ks_err!())?;
+            blobs
+                .into_iter()
+                .map(|(key_id, blob_id, blob, owner)| {
+                    let metadata = BlobMetaData::load_from_db(blob_id, tx)
+                        .context(ks_err!("Failed to load blob metadata."))?;
+                    Ok((key_id, owner, blob, metadata))
+                })
+                .collect::<Result<Vec<_>>>()
+                .no_gc()
+        })
+    }
+
+    /// Returns all namespaces of the domains APP and SELINUX that own at least one live client
+    /// key, together with the number of keys in each namespace, ordered by domain and namespace.
+    /// This exposes the namespaces of all clients, so the caller must make sure that it is only
+    /// served to privileged callers.
+    pub fn list_namespaces_with_keys(&mut self) -> Result<Vec<NamespaceKeyCount>> {
+        let _wp = wd::watch_millis("KeystoreDB::list_namespaces_with_keys", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT domain, namespace, COUNT(alias) FROM persistent.keyentry
+                     WHERE domain IN (?, ?)
+                     AND alias IS NOT NULL
+                     AND state = ?
+                     AND key_type = ?
+                     GROUP BY domain, namespace
+                     ORDER BY domain, namespace;",
+                )
+                .context(ks_err!("Failed to prepare statement."))?;
+            let mut rows = stmt
+                .query(params![
+                    Domain::APP.0 as u32,
+                    Domain::SELINUX.0 as u32,
+                    KeyLifeCycle::Live,
+                    KeyType::Client
+                ])
+                .context(ks_err!("Failed to query."))?;
+            let mut namespaces = Vec::new();
+            db_utils::with_rows_extract_all(&mut rows, |row| {
+                namespaces.push(NamespaceKeyCount {
+                    domain: Domain(row.get(0).context("Failed to read domain.")?),
+                    namespace: row.get(1).context("Failed to read namespace.")?,
+                    key_count: row.get(2).context("Failed to read key count.")?,
+                });
+                Ok(())
+            })
+            .context(ks_err!())?;
+            Ok(namespaces).no_gc()
+        })
+    }
+
+    /// Looks up the key id of `key` and performs the access control for it using
+    /// `check_permission`, like `load_key_entry`.
+    fn check_key_access(
+        tx: &Transaction,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<i64> {
+        let (key_id, access_key_descriptor, access_vector) =
+            Self::load_access_tuple(tx, key, key_type, caller_uid)
+                .context("Trying to get access tuple.")?;
+
+        // Perform access control. It is vital that we return here if the permission is denied.
+        // So do not touch that '?' at the end.
+        check_permission(&access_key_descriptor, access_vector)
+            .context("While checking permission.")?;
+        Ok(key_id)
+    }
+
+    /// Returns the value of the custom metadata entry `name` of the given key, if present.
+    /// Custom metadata is opaque descriptive data that clients attach to their keys. It is not
+    /// used by keystore. The caller must check the `GetInfo` permission in `check_permission`.
+    pub fn get_custom_metadata(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        name: &str,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<Option<Vec<u8>>> {
+        let _wp = wd::watch_millis("KeystoreDB::get_custom_metadata", 500);
+
+        self.with_transaction(TransactionBehavior::Deferred, |tx| {
+            let key_id = Self::check_key_access(tx, key, key_type, caller_uid, &check_permission)?;
+            tx.query_row(
+                "SELECT value FROM persistent.keycustommetadata
+                     WHERE keyentryid = ? AND name = ?;",
+                params![key_id, name],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to query custom metadata.")
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Sets the custom metadata entry `name` of the given key to `value`, replacing an existing
+    /// entry of the same name. Fails with `ResponseCode::TOO_MUCH_DATA` if the combined size of
+    /// the names and values of all entries of the key would exceed `MAX_CUSTOM_METADATA_SIZE`.
+    /// The caller must check the `Update` permission in `check_permission`.
+    pub fn set_custom_metadata(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        name: &str,
+        value: &[u8],
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<()> {
+        let _wp = wd::watch_millis("KeystoreDB::set_custom_metadata", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let key_id = Self::check_key_access(tx, key, key_type, caller_uid, &check_permission)?;
+            let others_size: i64 = tx
+                .query_row(
+                    "SELECT COALESCE(SUM(LENGTH(CAST(name AS BLOB)) + LENGTH(value)), 0)
+                         FROM persistent.keycustommetadata
+                         WHERE keyentryid = ? AND name != ?;",
+                    params![key_id, name],
+                    |row| row.get(0),
+                )
+                .context("Failed to query the custom metadata size.")?;
+            if others_size as usize + name.len() + value.len() > Self::MAX_CUSTOM_METADATA_SIZE {
+                return Err(KsError::Rc(ResponseCode::TOO_MUCH_DATA)).context(format!(
+                    "Custom metadata of key exceeds {} bytes.",
+                    Self::MAX_CUSTOM_METADATA_SIZE
+                ));
+            }
+            tx.execute(
+                "INSERT or REPLACE INTO persistent.keycustommetadata (keyentryid, name, value)
+                     VALUES (?, ?, ?);",
+                params![key_id, name, value],
+            )
+            .context("Failed to insert custom metadata.")?;
+            Ok(()).no_gc()
+        })
+        .context(ks_err!())
+    }
+
+    /// Deletes the custom metadata entry `name` of the given key. Returns false if there was no
+    /// such entry. The caller must check the `Update` permission in `check_permission`.
+    pub fn delete_custom_metadata(
+        &mut self,
+        key: &KeyDescriptor,
+        key_type: KeyType,
+        caller_uid: u32,
+        name: &str,
+        check_permission: impl Fn(&KeyDescriptor, Option<KeyPermSet>) -> Result<()>,
+    ) -> Result<bool> {
+        let _wp = wd::watch_millis("KeystoreDB::delete_custom_metadata", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let key_id = Self::check_key_access(tx, key, key_type, caller_uid, &check_permission)?;
+            tx.execute(
+                "DELETE FROM persistent.keycustommetadata WHERE keyentryid = ? AND name = ?;",
+                params![key_id, name],
+            )
+            .map(|deleted| deleted != 0)
+            .context("Failed to delete custom metadata.")
+            .no_gc()
+        })
+        .context(ks_err!())
+    }
+
     /// Adds a grant to the grant table.
     /// Like `load_key_entry` this function loads the access tuple before
     /// it uses the callback for a permission check. Upon success,
@@ -3087,62 +6636,153 @@ impl KeystoreDB {
         grantee_uid: u32,
         access_vector: KeyPermSet,
         check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
-    ) -> Result<KeyDescriptor> {
-        let _wp = wd::watch_millis("KeystoreDB::grant", 500);
+    ) -> Result<KeyDescriptor> {
+        let _wp = wd::watch_millis("KeystoreDB::grant", 500);
+
+        self.with_transaction(TransactionBehavior::Immediate, |tx| {
+            // Load the key_id and complete the access control tuple.
+            // We ignore the access vector here because grants cannot be granted.
+            // The access vector returned here expresses the permissions the
+            // grantee has if key.domain == Domain::GRANT. But this vector
+            // cannot include the grant permission by design, so there is no way the
+            // subsequent permission check can pass.
+            // We could check key.domain == Domain::GRANT and fail early.
+            // But even if we load the access tuple by grant here, the permission
+            // check denies the attempt to create a grant by grant descriptor.
+            let (key_id, access_key_descriptor, _) =
+                Self::load_access_tuple(tx, key, KeyType::Client, caller_uid).context(ks_err!())?;
+
+            // Perform access control. It is vital that we return here if the permission
+            // was denied. So do not touch that '?' at the end of the line.
+            // This permission check checks if the caller has the grant permission
+            // for the given key and in addition to all of the permissions
+            // expressed in `access_vector`.
+            check_permission(&access_key_descriptor, &access_vector)
+                .context(ks_err!("check_permission failed"))?;
+
+            let grant_id = Self::grant_in_tx(tx, key_id, grantee_uid, access_vector)?;
+            Ok(KeyDescriptor { domain: Domain::GRANT, nspace: grant_id, alias: None, blob: None })
+                .no_gc()
+        })
+    }
+
+    /// Like `grant`, but grants the key to each of `grantee_uids` in a single transaction and
+    /// returns the grant descriptors in the same order. The permission check is performed once
+    /// for the key. Each grantee must differ from the caller and may be listed only once. If any
+    /// grantee is rejected, no grant of the batch is created or updated.
+    pub fn grant_batch(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        grantee_uids: &[u32],
+        access_vector: KeyPermSet,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
+    ) -> Result<Vec<KeyDescriptor>> {
+        self.grant_batch_with_mode(
+            key,
+            caller_uid,
+            grantee_uids,
+            access_vector,
+            BatchMode::Atomic,
+            check_permission,
+        )?
+        .into_iter()
+        .collect()
+    }
+
+    /// Like `grant_batch`, but in `BatchMode::BestEffort` a rejected grantee only fails its own
+    /// item, and the grants to the other grantees are still created or updated. Returns the
+    /// result of each grantee in the order of `grantee_uids`. In `BatchMode::Atomic` either all
+    /// items succeed or the batch fails as a whole.
+    pub fn grant_batch_with_mode(
+        &mut self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        grantee_uids: &[u32],
+        access_vector: KeyPermSet,
+        mode: BatchMode,
+        check_permission: impl Fn(&KeyDescriptor, &KeyPermSet) -> Result<()>,
+    ) -> Result<Vec<Result<KeyDescriptor>>> {
+        let _wp = wd::watch_millis("KeystoreDB::grant_batch", 500);
 
+        if grantee_uids.is_empty() {
+            return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("No grantees given."));
+        }
         self.with_transaction(TransactionBehavior::Immediate, |tx| {
-            // Load the key_id and complete the access control tuple.
-            // We ignore the access vector here because grants cannot be granted.
-            // The access vector returned here expresses the permissions the
-            // grantee has if key.domain == Domain::GRANT. But this vector
-            // cannot include the grant permission by design, so there is no way the
-            // subsequent permission check can pass.
-            // We could check key.domain == Domain::GRANT and fail early.
-            // But even if we load the access tuple by grant here, the permission
-            // check denies the attempt to create a grant by grant descriptor.
+            // See `grant` for why the access vector of the tuple is ignored.
             let (key_id, access_key_descriptor, _) =
                 Self::load_access_tuple(tx, key, KeyType::Client, caller_uid).context(ks_err!())?;
 
             // Perform access control. It is vital that we return here if the permission
             // was denied. So do not touch that '?' at the end of the line.
-            // This permission check checks if the caller has the grant permission
-            // for the given key and in addition to all of the permissions
-            // expressed in `access_vector`.
             check_permission(&access_key_descriptor, &access_vector)
                 .context(ks_err!("check_permission failed"))?;
 
-            let grant_id = if let Some(grant_id) = tx
-                .query_row(
-                    "SELECT id FROM persistent.grant
+            let mut results = Vec::with_capacity(grantee_uids.len());
+            for (i, grantee_uid) in grantee_uids.iter().enumerate() {
+                let result =
+                    if *grantee_uid == caller_uid || grantee_uids[..i].contains(grantee_uid) {
+                        Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                            .context(ks_err!("Invalid grantee {}.", grantee_uid))
+                    } else {
+                        Self::grant_in_tx(tx, key_id, *grantee_uid, access_vector).map(|grant_id| {
+                            KeyDescriptor {
+                                domain: Domain::GRANT,
+                                nspace: grant_id,
+                                alias: None,
+                                blob: None,
+                            }
+                        })
+                    };
+                match (mode, result) {
+                    // Returning an error drops the transaction, which rolls back the grants
+                    // of this batch that were already created.
+                    (BatchMode::Atomic, Err(e)) => return Err(e),
+                    (_, result) => results.push(result),
+                }
+            }
+            Ok(results).no_gc()
+        })
+    }
+
+    /// Creates a grant of the key `key_id` to `grantee_uid`, or updates the access vector of the
+    /// existing grant, and returns the grant id.
+    fn grant_in_tx(
+        tx: &Transaction,
+        key_id: i64,
+        grantee_uid: u32,
+        access_vector: KeyPermSet,
+    ) -> Result<i64> {
+        KEY_HANDLES.invalidate_key(key_id);
+        if let Some(grant_id) = tx
+            .query_row(
+                "SELECT id FROM persistent.grant
                 WHERE keyentryid = ? AND grantee = ?;",
-                    params![key_id, grantee_uid],
-                    |row| row.get(0),
-                )
-                .optional()
-                .context(ks_err!("Failed get optional existing grant id."))?
-            {
-                tx.execute(
-                    "UPDATE persistent.grant
+                params![key_id, grantee_uid],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(ks_err!("Failed get optional existing grant id."))?
+        {
+            tx.execute(
+                "UPDATE persistent.grant
                     SET access_vector = ?
                     WHERE id = ?;",
-                    params![i32::from(access_vector), grant_id],
-                )
-                .context(ks_err!("Failed to update existing grant."))?;
-                grant_id
-            } else {
-                Self::insert_with_retry(|id| {
-                    tx.execute(
-                        "INSERT INTO persistent.grant (id, grantee, keyentryid, access_vector)
+                params![i32::from(access_vector), grant_id],
+            )
+            .context(ks_err!("Failed to update existing grant."))?;
+            Ok(grant_id)
+        } else {
+            Self::insert_with_retry(|id| {
+                tx.execute(
+                    "INSERT INTO persistent.grant (id, grantee, keyentryid, access_vector)
                         VALUES (?, ?, ?, ?);",
-                        params![id, grantee_uid, key_id, i32::from(access_vector)],
-                    )
-                })
-                .context(ks_err!())?
-            };
-
-            Ok(KeyDescriptor { domain: Domain::GRANT, nspace: grant_id, alias: None, blob: None })
-                .no_gc()
-        })
+                    params![id, grantee_uid, key_id, i32::from(access_vector)],
+                )
+            })
+            .context(ks_err!())
+        }
     }
 
     /// This function checks permissions like `grant` and `load_key_entry`
@@ -3173,6 +6813,7 @@ impl KeystoreDB {
                 params![key_id, grantee_uid],
             )
             .context("Failed to delete grant.")?;
+            KEY_HANDLES.invalidate_key(key_id);
 
             Ok(()).no_gc()
         })
@@ -3270,7 +6911,10 @@ pub mod tests {
     };
     use crate::key_perm_set;
     use crate::permission::{KeyPerm, KeyPermSet};
-    use crate::super_key::{SuperKeyManager, USER_SUPER_KEY, SuperEncryptionAlgorithm, SuperKeyType};
+    use crate::super_key::{
+        SuperEncryptionAlgorithm, SuperKeyManager, SuperKeyType, USER_SCREEN_LOCK_BOUND_KEY,
+        USER_SUPER_KEY,
+    };
     use keystore2_test_utils::TempDir;
     use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
         HardwareAuthToken::HardwareAuthToken,
@@ -3289,17 +6933,23 @@ pub mod tests {
     use std::thread;
     use std::time::{Duration, SystemTime};
     use crate::utils::AesGcm;
+    use crate::utils::MAX_ALIAS_LENGTH;
+    use crate::metrics_store::METRICS_STORE;
+    use crate::legacy_blob::test_utils::legacy_blob_test_vectors::{
+        LOADED_CACERT_AUTHBOUND, LOADED_CERT_AUTHBOUND,
+    };
+    use keystore2_crypto::{sha256, sha512};
+    use android_security_metrics::aidl::android::security::metrics::{
+        AtomID::AtomID, DatabaseContentionStats::DatabaseContentionStats,
+        KeyIdLockHoldStats::KeyIdLockHoldStats,
+        KeyIdLockOperation::KeyIdLockOperation as MetricsKeyIdLockOperation,
+        KeystoreAtomPayload::KeystoreAtomPayload,
+    };
     #[cfg(disabled)]
     use std::time::Instant;
 
     pub fn new_test_db() -> Result<KeystoreDB> {
-        let conn = KeystoreDB::make_connection("file::memory:")?;
-
-        let mut db = KeystoreDB { conn, gc: None, perboot: Arc::new(perboot::PerbootDB::new()) };
-        db.with_transaction(TransactionBehavior::Immediate, |tx| {
-            KeystoreDB::init_tables(tx).context("Failed to initialize tables.").no_gc()
-        })?;
-        Ok(db)
+        KeystoreDB::new_in_memory()
     }
 
     fn new_test_db_with_gc<F>(path: &Path, cb: F) -> Result<KeystoreDB>
@@ -3374,13 +7024,44 @@ pub mod tests {
             .prepare("SELECT name from persistent.sqlite_master WHERE type='table' ORDER BY name;")?
             .query_map(params![], |row| row.get(0))?
             .collect::<rusqlite::Result<Vec<String>>>()?;
-        assert_eq!(tables.len(), 6);
+        assert_eq!(tables.len(), 13);
         assert_eq!(tables[0], "blobentry");
         assert_eq!(tables[1], "blobmetadata");
         assert_eq!(tables[2], "grant");
-        assert_eq!(tables[3], "keyentry");
-        assert_eq!(tables[4], "keymetadata");
-        assert_eq!(tables[5], "keyparameter");
+        assert_eq!(tables[3], "keycustommetadata");
+        assert_eq!(tables[4], "keyentry");
+        assert_eq!(tables[5], "keyid_audit");
+        assert_eq!(tables[6], "keymetadata");
+        assert_eq!(tables[7], "keymigration");
+        assert_eq!(tables[8], "keyparameter");
+        assert_eq!(tables[9], "keytombstone");
+        assert_eq!(tables[10], "noncecounter");
+        assert_eq!(tables[11], "operationcheckpoint");
+        assert_eq!(tables[12], "operationcheckpointinput");
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_in_memory_create_and_load_key() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let (_, key_entry) = db.load_key_entry(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            1,
+            |_k, _av| Ok(()),
+        )?;
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+
+        // Each in-memory database is isolated from all others.
+        let mut other_db = KeystoreDB::new_in_memory()?;
+        assert!(!other_db.key_exists(Domain::APP, 1, TEST_ALIAS, KeyType::Client)?);
         Ok(())
     }
 
@@ -3457,6 +7138,242 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_store_new_key_with_existing_alias() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let store_key = |db: &mut KeystoreDB, blob: &'static [u8], existing: ExistingAlias| {
+            db.store_new_key_with_blob(
+                &key,
+                KeyType::Client,
+                &[],
+                |_| Ok((blob.to_vec(), BlobMetaData::new())),
+                &CertificateInfo::new(None, None),
+                None,
+                &KeyMetaData::new(),
+                &KEYSTORE_UUID,
+                existing,
+            )
+            .map(|key_id| key_id.id())
+        };
+        let load_blob = |db: &mut KeystoreDB| -> Result<Vec<u8>> {
+            let (_, mut key_entry) =
+                db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 1, |_, _| Ok(()))?;
+            Ok(key_entry.take_key_blob_info().unwrap().0)
+        };
+
+        // Failing if the alias exists does not prevent the creation of a new key.
+        let old_id = store_key(&mut db, b"old blob", ExistingAlias::Fail)?;
+        assert_eq!(load_blob(&mut db)?, b"old blob");
+
+        // A second key with the same alias is rejected, and the existing key stays untouched.
+        assert_eq!(
+            store_key(&mut db, b"rejected blob", ExistingAlias::Fail)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+        );
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, old_id);
+        assert_eq!(entries[0].state, KeyLifeCycle::Live);
+        assert_eq!(load_blob(&mut db)?, b"old blob");
+        assert!(db.handle_next_superseded_blobs(&[], 20)?.is_empty());
+
+        // Replacing rebinds the alias in one step and leaves the old key to the garbage
+        // collector, which deletes its blob.
+        let new_id = store_key(&mut db, b"new blob", ExistingAlias::Replace)?;
+        assert_eq!(load_blob(&mut db)?, b"new blob");
+        let states: Vec<(i64, KeyLifeCycle)> =
+            get_keyentry(&db)?.iter().map(|e| (e.id, e.state)).collect();
+        assert!(states.contains(&(old_id, KeyLifeCycle::Unreferenced)));
+        assert!(states.contains(&(new_id, KeyLifeCycle::Live)));
+        let superseded = db.handle_next_superseded_blobs(&[], 20)?;
+        assert_eq!(superseded.len(), 1);
+        let (blob_id, _, blob, _) = &superseded[0];
+        assert_eq!(blob, b"old blob");
+        assert!(db.handle_next_superseded_blobs(&[*blob_id], 20)?.is_empty());
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, new_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_new_key_with_wrapped_cert_chain() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let wrapped_metadata = || {
+            let mut metadata = BlobMetaData::new();
+            metadata.add(BlobMetaEntry::Iv(vec![1, 2, 3]));
+            metadata
+        };
+        let wrap_cert_chain = |key_id: i64, cert_chain: &[u8]| -> Result<_> {
+            let mut wrapped = key_id.to_be_bytes().to_vec();
+            wrapped.extend_from_slice(cert_chain);
+            Ok(Some((wrapped, wrapped_metadata())))
+        };
+        let store_key = |db: &mut KeystoreDB, wrap: Option<&WrapCertChain>| {
+            db.store_new_key_with_blob(
+                &key,
+                KeyType::Client,
+                &[],
+                |_| Ok((TEST_KEY_BLOB.to_vec(), BlobMetaData::new())),
+                &CertificateInfo::new(
+                    Some(TEST_CERT_BLOB.to_vec()),
+                    Some(TEST_CERT_CHAIN_BLOB.to_vec()),
+                ),
+                wrap,
+                &KeyMetaData::new(),
+                &KEYSTORE_UUID,
+                ExistingAlias::Replace,
+            )
+            .map(|key_id| key_id.id())
+        };
+        let load_entry = |db: &mut KeystoreDB| -> Result<KeyEntry> {
+            let (_, key_entry) =
+                db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::PUBLIC, 1, |_, _| {
+                    Ok(())
+                })?;
+            Ok(key_entry)
+        };
+
+        // The chain is wrapped for the new key entry and stored with its metadata, while the
+        // certificate is stored as is.
+        let key_id = store_key(&mut db, Some(&wrap_cert_chain))?;
+        let key_entry = load_entry(&mut db)?;
+        let mut expected_chain = key_id.to_be_bytes().to_vec();
+        expected_chain.extend_from_slice(TEST_CERT_CHAIN_BLOB);
+        assert_eq!(key_entry.cert().as_deref(), Some(TEST_CERT_BLOB));
+        assert_eq!(key_entry.cert_chain(), &Some(expected_chain));
+        assert_eq!(key_entry.cert_chain_metadata(), &wrapped_metadata());
+
+        // Without a wrapper, the chain is stored as is and has no metadata.
+        store_key(&mut db, None)?;
+        let key_entry = load_entry(&mut db)?;
+        assert_eq!(key_entry.cert_chain().as_deref(), Some(TEST_CERT_CHAIN_BLOB));
+        assert_eq!(key_entry.cert_chain_metadata(), &BlobMetaData::new());
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_new_key_keeps_idempotent_retry() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let created = DateTime::from_millis_epoch(1_000_000);
+        let request = |token: &[u8], params_digest: &[u8]| {
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::CreationDate(created));
+            metadata.add(KeyMetaEntry::IdempotencyToken(token.to_vec()));
+            metadata.add(KeyMetaEntry::IdempotencyParamsDigest(params_digest.to_vec()));
+            metadata
+        };
+        let store_key = |db: &mut KeystoreDB, metadata: &KeyMetaData, created_after: i64| {
+            db.store_new_key_with_blob(
+                &key,
+                KeyType::Client,
+                &[],
+                |_| Ok((b"key blob".to_vec(), BlobMetaData::new())),
+                &CertificateInfo::new(None, None),
+                None,
+                metadata,
+                &KEYSTORE_UUID,
+                ExistingAlias::KeepIdempotentRetry(DateTime::from_millis_epoch(created_after)),
+            )
+        };
+
+        let first = match store_key(&mut db, &request(b"token", b"params"), 0)? {
+            StoredKey::New(key_id) => key_id.id(),
+            StoredKey::IdempotentRetry(_) => panic!("The first request stored no key."),
+        };
+
+        // The second request with the same token returns the first key and stores nothing.
+        match store_key(&mut db, &request(b"token", b"params"), 0)? {
+            StoredKey::IdempotentRetry(key_id) => assert_eq!(key_id, first),
+            StoredKey::New(_) => panic!("The retry stored another key."),
+        }
+        assert_eq!(get_keyentry(&db)?.len(), 1);
+
+        // The token is not honored for other key parameters.
+        assert_eq!(
+            store_key(&mut db, &request(b"token", b"other params"), 0)
+                .map(|stored| stored.id())
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::Km(ErrorCode::INVALID_ARGUMENT))
+        );
+
+        // Another token, or a key created before the window, gets a new key under the alias.
+        let second = store_key(&mut db, &request(b"other token", b"params"), 0)?;
+        assert!(matches!(second, StoredKey::New(_)));
+        let third =
+            store_key(&mut db, &request(b"other token", b"params"), created.to_millis_epoch() + 1)?;
+        assert!(matches!(third, StoredKey::New(_)));
+        let live: Vec<i64> = get_keyentry(&db)?
+            .iter()
+            .filter(|e| e.state == KeyLifeCycle::Live)
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(live, vec![third.id()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_secure_delete() -> Result<()> {
+        const BLOB: &[u8] = b"a key blob that must not outlive its key entry";
+        let temp_dir = TempDir::new("test_secure_delete")?;
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        let secure_delete: i64 =
+            db.conn.query_row("PRAGMA persistent.secure_delete;", NO_PARAMS, |row| row.get(0))?;
+        assert_eq!(secure_delete, 1);
+
+        let key_id = db.create_key_entry(&Domain::APP, &1, KeyType::Client, &KEYSTORE_UUID)?;
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(BLOB), None)?;
+        rebind_alias(&mut db, &key_id, TEST_ALIAS, Domain::APP, 1)?;
+        drop(key_id);
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        db.unbind_key(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+
+        // Let the garbage collector delete the blob.
+        let superseded = db.handle_next_superseded_blobs(&[], 20)?;
+        assert_eq!(superseded.len(), 1);
+        let (blob_id, _, blob, _) = &superseded[0];
+        assert_eq!(blob, BLOB);
+        assert!(db.handle_next_superseded_blobs(&[*blob_id], 20)?.is_empty());
+
+        // The blob is gone from the database and from the database file.
+        assert!(matches!(
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 1, |_, _| Ok(())),
+            Err(e) if e.root_cause().downcast_ref::<KsError>()
+                == Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+        ));
+        let content = std::fs::read(temp_dir.path().join(KeystoreDB::PERSISTENT_DB_FILENAME))?;
+        assert!(!content.windows(BLOB.len()).any(|w| w == BLOB));
+        Ok(())
+    }
+
     #[test]
     fn test_create_key_entry() -> Result<()> {
         fn extractor(ke: &KeyEntryRow) -> (Domain, i64, Option<&str>, Uuid) {
@@ -3501,6 +7418,7 @@ pub mod tests {
             &raw_public_key,
             &private_key,
             &KEYSTORE_UUID,
+            None,
         )?;
         let keys = db.fetch_unsigned_attestation_keys(5, &KEYSTORE_UUID)?;
         assert_eq!(keys.len(), 1);
@@ -3519,8 +7437,12 @@ pub mod tests {
         let base_byte: u8 = 1;
         let loaded_values =
             load_attestation_key_pool(&mut db, expiration_date, namespace, base_byte)?;
-        let chain =
-            db.retrieve_attestation_key_and_cert_chain(Domain::APP, namespace, &KEYSTORE_UUID)?;
+        let chain = db.retrieve_attestation_key_and_cert_chain(
+            Domain::APP,
+            namespace,
+            &KEYSTORE_UUID,
+            None,
+        )?;
         assert!(chain.is_some());
         let (_, cert_chain) = chain.unwrap();
         assert_eq!(cert_chain.private_key.to_vec(), loaded_values.priv_key);
@@ -3529,6 +7451,49 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_attestation_cert_chain_size_limit() -> Result<()> {
+        let mut db = new_test_db()?;
+        let expiration_date: i64 =
+            SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64
+                + EXPIRATION_BUFFER_MS
+                + 10000;
+        let store_chain = |db: &mut KeystoreDB, raw_public_key: &[u8], cert_chain: &[u8]| {
+            db.create_attestation_key_entry(
+                &[0x01],
+                raw_public_key,
+                &[0x02],
+                &KEYSTORE_UUID,
+                None,
+            )?;
+            db.store_signed_attestation_certificate_chain(
+                raw_public_key,
+                &[0x03],
+                cert_chain,
+                expiration_date,
+                &KEYSTORE_UUID,
+            )
+        };
+
+        let oversized = vec![0u8; KeystoreDB::DEFAULT_MAX_CERT_CHAIN_SIZE + 1];
+        assert_eq!(
+            store_chain(&mut db, &[0x0a], &oversized)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::TOO_MUCH_DATA))
+        );
+
+        let chain = vec![0u8; KeystoreDB::DEFAULT_MAX_CERT_CHAIN_SIZE];
+        store_chain(&mut db, &[0x0b], &chain)?;
+        db.assign_attestation_key(Domain::APP, 30, &KEYSTORE_UUID, None)?;
+        let (_, cert_chain) = db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, 30, &KEYSTORE_UUID, None)?
+            .unwrap();
+        assert_eq!(cert_chain.cert_chain, chain);
+        Ok(())
+    }
+
     #[test]
     fn test_remove_expired_certs() -> Result<()> {
         let temp_dir =
@@ -3560,8 +7525,12 @@ pub mod tests {
 
         assert_eq!(db.delete_expired_attestation_keys()?, 2);
 
-        let mut cert_chain =
-            db.retrieve_attestation_key_and_cert_chain(Domain::APP, namespace, &KEYSTORE_UUID)?;
+        let mut cert_chain = db.retrieve_attestation_key_and_cert_chain(
+            Domain::APP,
+            namespace,
+            &KEYSTORE_UUID,
+            None,
+        )?;
         assert!(cert_chain.is_some());
         let (_, value) = cert_chain.unwrap();
         assert_eq!(entry_values.batch_cert, value.batch_cert);
@@ -3572,12 +7541,14 @@ pub mod tests {
             Domain::APP,
             namespace_del1,
             &KEYSTORE_UUID,
+            None,
         )?;
         assert!(cert_chain.is_none());
         cert_chain = db.retrieve_attestation_key_and_cert_chain(
             Domain::APP,
             namespace_del2,
             &KEYSTORE_UUID,
+            None,
         )?;
         assert!(cert_chain.is_none());
 
@@ -3595,6 +7566,286 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_count_available_attestation_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        let now: i64 = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+        let valid_date = now + EXPIRATION_BUFFER_MS + 10000;
+        let other_uuid = Uuid([1; 16]);
+        let mut add_key = |base_byte: u8, expiration_date: Option<i64>, km_uuid: &Uuid| {
+            let public_key = vec![base_byte, 0x01];
+            let raw_public_key = vec![base_byte, 0x02];
+            db.create_attestation_key_entry(
+                &public_key,
+                &raw_public_key,
+                &[base_byte],
+                km_uuid,
+                None,
+            )?;
+            if let Some(expiration_date) = expiration_date {
+                db.store_signed_attestation_certificate_chain(
+                    &raw_public_key,
+                    &[base_byte, 0x03],
+                    &[base_byte, 0x04],
+                    expiration_date,
+                    km_uuid,
+                )?;
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+        add_key(0x10, Some(valid_date), &KEYSTORE_UUID)?;
+        add_key(0x11, Some(valid_date), &KEYSTORE_UUID)?;
+        // Expired, or expiring within the buffer.
+        add_key(0x12, Some(now - 1000), &KEYSTORE_UUID)?;
+        add_key(0x13, Some(now + EXPIRATION_BUFFER_MS - 1000), &KEYSTORE_UUID)?;
+        // Not yet signed.
+        add_key(0x14, None, &KEYSTORE_UUID)?;
+        // Belongs to a different KeyMint instance.
+        add_key(0x15, Some(valid_date), &other_uuid)?;
+
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, None)?, 2);
+        assert_eq!(db.count_available_attestation_keys(&other_uuid, None)?, 1);
+        // Counting does not consume keys.
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, None)?, 2);
+
+        // Assigned keys are no longer available.
+        db.assign_attestation_key(Domain::APP, 30, &KEYSTORE_UUID, None)?;
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, None)?, 1);
+        load_attestation_key_pool(&mut db, valid_date, 45, 0x01)?;
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, None)?, 1);
+
+        let rem_prov_state = crate::remote_provisioning::RemProvState::new(
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+            "default",
+            KEYSTORE_UUID,
+        );
+        assert_eq!(rem_prov_state.get_attestation_pool_size(None, &mut db)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_assigned_attestation_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        let now: i64 = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as i64;
+        assert!(!db.has_assigned_attestation_key(Domain::APP, 30, &KEYSTORE_UUID, None)?);
+        load_attestation_key_pool(&mut db, now + EXPIRATION_BUFFER_MS + 10000, 30, 0x01)?;
+        load_attestation_key_pool(&mut db, now - 1000, 31, 0x02)?;
+
+        assert!(db.has_assigned_attestation_key(Domain::APP, 30, &KEYSTORE_UUID, None)?);
+        assert!(!db.has_assigned_attestation_key(Domain::SELINUX, 30, &KEYSTORE_UUID, None)?);
+        assert!(!db.has_assigned_attestation_key(Domain::APP, 30, &Uuid([1; 16]), None)?);
+
+        // Expired keys do not count, but are not pruned by the query.
+        assert!(!db.has_assigned_attestation_key(Domain::APP, 31, &KEYSTORE_UUID, None)?);
+        assert_eq!(db.delete_expired_attestation_keys()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_expired_attestation_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        let valid_date: i64 = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis()
+            as i64
+            + EXPIRATION_BUFFER_MS
+            + 10000;
+        let (namespace_valid, namespace_expired, namespace_in_use) = (30, 45, 60);
+        load_attestation_key_pool(&mut db, valid_date, namespace_valid, 0x01)?;
+        load_attestation_key_pool(&mut db, 45, namespace_expired, 0x02)?;
+        load_attestation_key_pool(&mut db, 45, namespace_in_use, 0x03)?;
+
+        let key_id = |db: &mut KeystoreDB, namespace: i64| -> Result<Option<i64>> {
+            db.conn
+                .query_row(
+                    "SELECT id FROM persistent.keyentry WHERE namespace = ? AND key_type = ?;",
+                    params![namespace, KeyType::Attestation],
+                    |row| row.get(0),
+                )
+                .optional()
+                .context("Failed to query key id.")
+        };
+
+        // Simulate an operation that is using the expired key.
+        let in_use_id = key_id(&mut db, namespace_in_use)?.unwrap();
+        let guard = KEY_ID_LOCK.try_get(in_use_id, KeyIdLockOperation::LoadKeyEntry).unwrap();
+        assert_eq!(db.prune_expired_attestation_keys()?, vec![KEYSTORE_UUID]);
+        assert!(key_id(&mut db, namespace_valid)?.is_some());
+        assert!(key_id(&mut db, namespace_expired)?.is_none());
+        assert_eq!(key_id(&mut db, namespace_in_use)?, Some(in_use_id));
+
+        // Once the operation has finished, the key gets pruned as well.
+        drop(guard);
+        assert_eq!(db.prune_expired_attestation_keys()?, vec![KEYSTORE_UUID]);
+        assert!(key_id(&mut db, namespace_in_use)?.is_none());
+        assert!(key_id(&mut db, namespace_valid)?.is_some());
+        assert!(db.prune_expired_attestation_keys()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_id_allocation_audit() -> Result<()> {
+        let mut db = new_test_db()?;
+        let before = DateTime::now()?;
+        let key_ids = (0..3)
+            .map(|i| {
+                make_test_key_entry(&mut db, Domain::APP, 1, &format!("key{}", i), None)
+                    .map(|guard| guard.id())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let after = DateTime::now()?;
+
+        let allocations = db.get_key_id_allocations()?;
+        assert_eq!(key_ids, allocations.iter().map(|a| a.key_id).collect::<Vec<_>>());
+        for allocation in &allocations {
+            assert_eq!(allocation.caller_uid, ThreadState::get_calling_uid());
+            assert!(before <= allocation.allocation_time && allocation.allocation_time <= after);
+        }
+
+        // Audit entries outlive the keys.
+        db.unbind_keys_for_namespace(Domain::APP, 1)?;
+        assert_eq!(db.get_key_id_allocations()?, allocations);
+
+        // The audit log cannot be modified.
+        assert!(db.conn.execute("DELETE FROM persistent.keyid_audit;", NO_PARAMS).is_err());
+        assert!(db
+            .conn
+            .execute("UPDATE persistent.keyid_audit SET caller_uid = 0;", NO_PARAMS)
+            .is_err());
+        assert_eq!(db.get_key_id_allocations()?, allocations);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_id_allocation_audit_retention() -> Result<()> {
+        let mut db = new_test_db()?;
+        let total = KeystoreDB::KEY_ID_AUDIT_RETAINED_ENTRIES + 5;
+        {
+            let tx = db.conn.transaction()?;
+            for key_id in 0..total {
+                tx.execute(
+                    "INSERT INTO persistent.keyid_audit (key_id, allocation_time, caller_uid)
+                     VALUES (?, ?, ?);",
+                    params![key_id, DateTime::from_millis_epoch(key_id), 1],
+                )?;
+            }
+            tx.commit()?;
+        }
+
+        // The retained entries cannot be deleted, not even the oldest of them.
+        assert!(db
+            .conn
+            .execute("DELETE FROM persistent.keyid_audit WHERE key_id = 5;", NO_PARAMS)
+            .is_err());
+
+        assert_eq!(db.purge_key_id_allocations()?, 5);
+        let allocations = db.get_key_id_allocations()?;
+        assert_eq!(allocations.len() as i64, KeystoreDB::KEY_ID_AUDIT_RETAINED_ENTRIES);
+        assert_eq!(allocations.first().map(|a| a.key_id), Some(5));
+        assert_eq!(allocations.last().map(|a| a.key_id), Some(total - 1));
+        assert_eq!(db.purge_key_id_allocations()?, 0);
+
+        // New allocations keep the log at its size once purged.
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        assert_eq!(db.purge_key_id_allocations()?, 1);
+        let allocations = db.get_key_id_allocations()?;
+        assert_eq!(allocations.len() as i64, KeystoreDB::KEY_ID_AUDIT_RETAINED_ENTRIES);
+        assert_eq!(allocations.last().map(|a| a.key_id), Some(key_id));
+        Ok(())
+    }
+
+    fn unbind_test_key(db: &mut KeystoreDB, alias: &str) -> Result<()> {
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some(alias.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            1,
+            |_, _| Ok(()),
+        )
+    }
+
+    #[test]
+    fn test_key_deletion_writes_tombstone() -> Result<()> {
+        let mut db = new_test_db()?;
+        let untracked = make_test_key_entry(&mut db, Domain::APP, 1, "untracked", None)?.id();
+        unbind_test_key(&mut db, "untracked")?;
+        // Tombstones are off by default.
+        assert!(db.get_key_tombstones()?.is_empty());
+
+        db.tombstone_retention = Some(Duration::from_secs(3600));
+        let before = DateTime::now()?;
+        let tracked = make_test_key_entry(&mut db, Domain::APP, 1, "tracked", None)?.id();
+        unbind_test_key(&mut db, "tracked")?;
+        let in_namespace = make_test_key_entry(&mut db, Domain::APP, 2, "app", None)?.id();
+        db.unbind_keys_for_namespace(Domain::APP, 2)?;
+        let after = DateTime::now()?;
+
+        let tombstones = db.get_key_tombstones()?;
+        assert_eq!(
+            vec![tracked, in_namespace],
+            tombstones.iter().map(|t| t.key_id).collect::<Vec<_>>()
+        );
+        assert!(!tombstones.iter().any(|t| t.key_id == untracked));
+        for tombstone in &tombstones {
+            assert_eq!(tombstone.caller_uid, ThreadState::get_calling_uid());
+            assert!(before <= tombstone.deletion_time && tombstone.deletion_time <= after);
+        }
+        // The key material is removed regardless.
+        assert_eq!(
+            db.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 1,
+                    alias: Some("tracked".to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                1,
+                |_, _| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstones_expire_after_retention() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.tombstone_retention = Some(Duration::from_secs(3600));
+        make_test_key_entry(&mut db, Domain::APP, 1, "old", None)?;
+        unbind_test_key(&mut db, "old")?;
+        let old = db.get_key_tombstones()?;
+        assert_eq!(old.len(), 1);
+        let deleted_at = old[0].deletion_time.to_millis_epoch();
+
+        // A tombstone is retained for the whole retention period.
+        assert_eq!(db.purge_expired_tombstones(DateTime::from_millis_epoch(deleted_at))?, 0);
+        assert_eq!(
+            db.purge_expired_tombstones(DateTime::from_millis_epoch(deleted_at + 3600 * 1000))?,
+            0
+        );
+        assert_eq!(db.get_key_tombstones()?, old);
+        assert_eq!(
+            db.purge_expired_tombstones(DateTime::from_millis_epoch(deleted_at + 3600 * 1000 + 1))?,
+            1
+        );
+        assert!(db.get_key_tombstones()?.is_empty());
+
+        // Once tombstones are disabled, the remaining ones are purged right away.
+        make_test_key_entry(&mut db, Domain::APP, 1, "new", None)?;
+        unbind_test_key(&mut db, "new")?;
+        db.tombstone_retention = None;
+        assert_eq!(db.purge_expired_tombstones(DateTime::now()?)?, 1);
+        assert!(db.get_key_tombstones()?.is_empty());
+        Ok(())
+    }
+
     fn compare_rem_prov_values(
         expected: &RemoteProvValues,
         actual: Option<(KeyIdGuard, CertificateChain)>,
@@ -3637,16 +7888,28 @@ pub mod tests {
         // one key, one certificate chain, and one certificate.
         assert_eq!(blob_entry_row_count, 9);
 
-        let mut cert_chain =
-            db.retrieve_attestation_key_and_cert_chain(Domain::APP, namespace1, &KEYSTORE_UUID)?;
+        let mut cert_chain = db.retrieve_attestation_key_and_cert_chain(
+            Domain::APP,
+            namespace1,
+            &KEYSTORE_UUID,
+            None,
+        )?;
         compare_rem_prov_values(&entry_values1, cert_chain);
 
-        cert_chain =
-            db.retrieve_attestation_key_and_cert_chain(Domain::APP, namespace2, &KEYSTORE_UUID)?;
+        cert_chain = db.retrieve_attestation_key_and_cert_chain(
+            Domain::APP,
+            namespace2,
+            &KEYSTORE_UUID,
+            None,
+        )?;
         compare_rem_prov_values(&entry_values2, cert_chain);
 
-        cert_chain =
-            db.retrieve_attestation_key_and_cert_chain(Domain::APP, namespace3, &KEYSTORE_UUID)?;
+        cert_chain = db.retrieve_attestation_key_and_cert_chain(
+            Domain::APP,
+            namespace3,
+            &KEYSTORE_UUID,
+            None,
+        )?;
         compare_rem_prov_values(&entry_values3, cert_chain);
 
         // Give the garbage collector half a second to catch up.
@@ -3702,7 +7965,13 @@ pub mod tests {
         );
 
         // Test that the first call to rebind_alias sets the alias.
-        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[0].id), "foo", Domain::APP, 42)?;
+        rebind_alias(
+            &mut db,
+            &KEY_ID_LOCK.get(entries[0].id, KeyIdLockOperation::CreateKeyEntry),
+            "foo",
+            Domain::APP,
+            42,
+        )?;
         let entries = get_keyentry(&db)?;
         assert_eq!(entries.len(), 2);
         assert_eq!(
@@ -3715,7 +7984,13 @@ pub mod tests {
         );
 
         // Test that the second call to rebind_alias also empties the old one.
-        rebind_alias(&mut db, &KEY_ID_LOCK.get(entries[1].id), "foo", Domain::APP, 42)?;
+        rebind_alias(
+            &mut db,
+            &KEY_ID_LOCK.get(entries[1].id, KeyIdLockOperation::CreateKeyEntry),
+            "foo",
+            Domain::APP,
+            42,
+        )?;
         let entries = get_keyentry(&db)?;
         assert_eq!(entries.len(), 2);
         assert_eq!(extractor(&entries[0]), (None, None, None, Some(KEYSTORE_UUID)));
@@ -3726,21 +8001,45 @@ pub mod tests {
 
         // Test that we must pass in a valid Domain.
         check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::GRANT, 42),
+            rebind_alias(
+                &mut db,
+                &KEY_ID_LOCK.get(0, KeyIdLockOperation::CreateKeyEntry),
+                "foo",
+                Domain::GRANT,
+                42,
+            ),
             &format!("Domain {:?} must be either App or SELinux.", Domain::GRANT),
         );
         check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::BLOB, 42),
+            rebind_alias(
+                &mut db,
+                &KEY_ID_LOCK.get(0, KeyIdLockOperation::CreateKeyEntry),
+                "foo",
+                Domain::BLOB,
+                42,
+            ),
             &format!("Domain {:?} must be either App or SELinux.", Domain::BLOB),
         );
         check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::KEY_ID, 42),
+            rebind_alias(
+                &mut db,
+                &KEY_ID_LOCK.get(0, KeyIdLockOperation::CreateKeyEntry),
+                "foo",
+                Domain::KEY_ID,
+                42,
+            ),
             &format!("Domain {:?} must be either App or SELinux.", Domain::KEY_ID),
         );
 
         // Test that we correctly handle setting an alias for something that does not exist.
         check_result_is_error_containing_string(
-            rebind_alias(&mut db, &KEY_ID_LOCK.get(0), "foo", Domain::SELINUX, 42),
+            rebind_alias(
+                &mut db,
+                &KEY_ID_LOCK.get(0, KeyIdLockOperation::CreateKeyEntry),
+                "foo",
+                Domain::SELINUX,
+                42,
+            ),
             "Expected to update a single entry but instead updated 0",
         );
         // Test that we correctly abort the transaction in this case.
@@ -3755,6 +8054,190 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_rebind_rejects_invalid_alias() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = db.create_key_entry(&Domain::APP, &42, KeyType::Client, &KEYSTORE_UUID)?;
+        for alias in ["".to_string(), "a".repeat(MAX_ALIAS_LENGTH + 1), "foo\0".to_string()] {
+            assert_eq!(
+                rebind_alias(&mut db, &key_id, &alias, Domain::APP, 42)
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<KsError>(),
+                Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+            );
+        }
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].alias, None);
+
+        rebind_alias(&mut db, &key_id, &"a".repeat(MAX_ALIAS_LENGTH), Domain::APP, 42)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant_batch() -> Result<()> {
+        const CALLER_UID: u32 = 15;
+        const PVEC: KeyPermSet = key_perm_set![KeyPerm::Use, KeyPerm::GetInfo];
+
+        let mut db = new_test_db()?;
+        let key_id =
+            make_test_key_entry(&mut db, Domain::APP, CALLER_UID as i64, "key", None)?.id();
+        let app_key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some("key".to_string()),
+            blob: None,
+        };
+
+        let granted = db.grant_batch(&app_key, CALLER_UID, &[20, 21, 22], PVEC, |k, a| {
+            assert_eq!(*a, PVEC);
+            assert_eq!(k.nspace, CALLER_UID as i64);
+            Ok(())
+        })?;
+        assert_eq!(granted.len(), 3);
+        for (grantee_uid, grant) in [20u32, 21, 22].iter().zip(&granted) {
+            assert_eq!(grant.domain, Domain::GRANT);
+            let (key_id_guard, _) = db.load_key_entry(
+                grant,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                *grantee_uid,
+                |_, _| Ok(()),
+            )?;
+            assert_eq!(key_id_guard.id(), key_id);
+        }
+
+        // The permission is checked once for the whole batch.
+        assert!(db
+            .grant_batch(&app_key, CALLER_UID, &[23], PVEC, |_, _| Err(KsError::perm().into()))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant_batch_with_invalid_grantee_is_rolled_back() -> Result<()> {
+        const CALLER_UID: u32 = 15;
+        const PVEC: KeyPermSet = key_perm_set![KeyPerm::Use];
+
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, CALLER_UID as i64, "key", None)?;
+        let app_key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some("key".to_string()),
+            blob: None,
+        };
+        let grant_count = |db: &KeystoreDB| -> Result<i64> {
+            Ok(db
+                .conn
+                .query_row("SELECT COUNT(*) FROM persistent.grant;", NO_PARAMS, |row| row.get(0))?)
+        };
+
+        // The caller itself and duplicates are invalid grantees, no matter where they appear in
+        // the batch.
+        for grantees in [&[20, CALLER_UID, 21][..], &[20, 21, 20], &[]] {
+            assert_eq!(
+                db.grant_batch(&app_key, CALLER_UID, grantees, PVEC, |_, _| Ok(()))
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<KsError>(),
+                Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+            );
+            assert_eq!(grant_count(&db)?, 0);
+        }
+
+        // Existing grants are not updated by a rejected batch either.
+        db.grant_batch(&app_key, CALLER_UID, &[20], PVEC, |_, _| Ok(()))?;
+        let all = key_perm_set![KeyPerm::Use, KeyPerm::Delete];
+        assert!(db
+            .grant_batch(&app_key, CALLER_UID, &[20, CALLER_UID], all, |_, _| Ok(()))
+            .is_err());
+        let access_vector: i32 = db.conn.query_row(
+            "SELECT access_vector FROM persistent.grant WHERE grantee = 20;",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        assert_eq!(KeyPermSet::from(access_vector), PVEC);
+        assert_eq!(grant_count(&db)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grant_batch_best_effort() -> Result<()> {
+        const CALLER_UID: u32 = 15;
+        const PVEC: KeyPermSet = key_perm_set![KeyPerm::Use];
+
+        let mut db = new_test_db()?;
+        let key_id =
+            make_test_key_entry(&mut db, Domain::APP, CALLER_UID as i64, "key", None)?.id();
+        let app_key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some("key".to_string()),
+            blob: None,
+        };
+
+        let results = db.grant_batch_with_mode(
+            &app_key,
+            CALLER_UID,
+            &[20, CALLER_UID, 21, 20],
+            PVEC,
+            BatchMode::BestEffort,
+            |_, _| Ok(()),
+        )?;
+        assert_eq!(results.len(), 4);
+        // The caller itself and the duplicate fail, the other grantees get their grants.
+        for i in [1, 3] {
+            assert_eq!(
+                results[i].as_ref().unwrap_err().root_cause().downcast_ref::<KsError>(),
+                Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+            );
+        }
+        for (i, grantee_uid) in [(0, 20u32), (2, 21)] {
+            let grant = results[i].as_ref().unwrap();
+            let (key_id_guard, _) = db.load_key_entry(
+                grant,
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                grantee_uid,
+                |_, _| Ok(()),
+            )?;
+            assert_eq!(key_id_guard.id(), key_id);
+        }
+
+        // The same batch is rejected as a whole in atomic mode.
+        assert!(db
+            .grant_batch_with_mode(
+                &app_key,
+                CALLER_UID,
+                &[22, CALLER_UID],
+                PVEC,
+                BatchMode::Atomic,
+                |_, _| Ok(()),
+            )
+            .is_err());
+        let grantees: Vec<u32> = db
+            .conn
+            .prepare("SELECT grantee FROM persistent.grant ORDER BY grantee;")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(grantees, vec![20, 21]);
+
+        // A denied permission still fails the whole batch.
+        assert!(db
+            .grant_batch_with_mode(
+                &app_key,
+                CALLER_UID,
+                &[23],
+                PVEC,
+                BatchMode::BestEffort,
+                |_, _| { Err(KsError::perm().into()) }
+            )
+            .is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_grant_ungrant() -> Result<()> {
         const CALLER_UID: u32 = 15;
@@ -3897,65 +8380,463 @@ pub mod tests {
             assert!(rows.next().is_none());
         }
 
-        debug_dump_keyentry_table(&mut db)?;
-        println!("app_key {:?}", app_key);
-        println!("selinux_key {:?}", selinux_key);
+        debug_dump_keyentry_table(&mut db)?;
+        println!("app_key {:?}", app_key);
+        println!("selinux_key {:?}", selinux_key);
+
+        db.ungrant(&app_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
+        db.ungrant(&selinux_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
+
+        Ok(())
+    }
+
+    static TEST_KEY_BLOB: &[u8] = b"my test blob";
+    static TEST_CERT_BLOB: &[u8] = b"my test cert";
+    static TEST_CERT_CHAIN_BLOB: &[u8] = b"my test cert_chain";
+
+    #[test]
+    fn test_set_blob() -> Result<()> {
+        let key_id = KEY_ID_LOCK.get(3000, KeyIdLockOperation::CreateKeyEntry);
+        let mut db = new_test_db()?;
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        db.set_blob(
+            &key_id,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
+        drop(key_id);
+
+        let mut stmt = db.conn.prepare(
+            "SELECT subcomponent_type, keyentryid, blob, id FROM persistent.blobentry
+                ORDER BY subcomponent_type ASC;",
+        )?;
+        let mut rows = stmt
+            .query_map::<((SubComponentType, i64, Vec<u8>), i64), _, _>(NO_PARAMS, |row| {
+                Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
+            })?;
+        let (r, id) = rows.next().unwrap().unwrap();
+        assert_eq!(r, (SubComponentType::KEY_BLOB, 3000, TEST_KEY_BLOB.to_vec()));
+        let (r, _) = rows.next().unwrap().unwrap();
+        assert_eq!(r, (SubComponentType::CERT, 3000, TEST_CERT_BLOB.to_vec()));
+        let (r, _) = rows.next().unwrap().unwrap();
+        assert_eq!(r, (SubComponentType::CERT_CHAIN, 3000, TEST_CERT_CHAIN_BLOB.to_vec()));
+
+        drop(rows);
+        drop(stmt);
+
+        assert_eq!(
+            db.with_transaction(TransactionBehavior::Immediate, |tx| {
+                BlobMetaData::load_from_db(id, tx).no_gc()
+            })
+            .expect("Should find blob metadata."),
+            blob_metadata
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cert_chain_size_limit() -> Result<()> {
+        let key_id = KEY_ID_LOCK.get(3000, KeyIdLockOperation::CreateKeyEntry);
+        let mut db = new_test_db()?;
+        fn is_too_much_data<T: std::fmt::Debug>(result: Result<T>) -> bool {
+            result.unwrap_err().root_cause().downcast_ref::<KsError>()
+                == Some(&KsError::Rc(ResponseCode::TOO_MUCH_DATA))
+        }
+
+        let chain = vec![0u8; KeystoreDB::DEFAULT_MAX_CERT_CHAIN_SIZE];
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(&chain), None)?;
+        assert_eq!(db.load_certificates(&key_id)?, (None, Some(chain.clone())));
+
+        let oversized = vec![0u8; KeystoreDB::DEFAULT_MAX_CERT_CHAIN_SIZE + 1];
+        assert!(is_too_much_data(db.set_blob(
+            &key_id,
+            SubComponentType::CERT_CHAIN,
+            Some(&oversized),
+            None
+        )));
+        assert_eq!(db.load_certificates(&key_id)?, (None, Some(chain)));
+
+        // A chain that exceeds the limit, e.g., because it was stored when the limit was larger,
+        // is rejected when it is loaded.
+        db.conn.execute(
+            "INSERT INTO persistent.blobentry (subcomponent_type, keyentryid, blob)
+                VALUES (?, ?, ?);",
+            params![SubComponentType::CERT_CHAIN, 3000, oversized],
+        )?;
+        assert!(is_too_much_data(db.load_certificates(&key_id)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_key_blob_is_rejected_on_load() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let key_descriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let set_key_blob = |db: &mut KeystoreDB, blob: &[u8]| {
+            let key_id = KEY_ID_LOCK.get(key_id, KeyIdLockOperation::CreateKeyEntry);
+            db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(blob), None)
+        };
+        let load = |db: &mut KeystoreDB, load_bits| {
+            db.load_key_entry(&key_descriptor, KeyType::Client, load_bits, 1, |_, _| Ok(()))
+        };
+        load(&mut db, KeyEntryLoadBits::KM)?;
+
+        for corrupt_blob in [&[][..], &[1, 2, 3][..]] {
+            set_key_blob(&mut db, corrupt_blob)?;
+            assert_eq!(
+                load(&mut db, KeyEntryLoadBits::KM)
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<KsError>(),
+                Some(&KsError::Rc(ResponseCode::VALUE_CORRUPTED))
+            );
+            // The public parts of the key can still be loaded, e.g., to diagnose it.
+            let (_, key_entry) = load(&mut db, KeyEntryLoadBits::PUBLIC)?;
+            assert_eq!(key_entry.cert(), &Some(TEST_CERT_BLOB.to_vec()));
+        }
+
+        // Replacing the corrupt blob makes the key usable again.
+        set_key_blob(&mut db, TEST_KEY_BLOB)?;
+        let (_, mut key_entry) = load(&mut db, KeyEntryLoadBits::KM)?;
+        assert_eq!(
+            key_entry.take_key_blob_info().map(|(blob, _)| blob),
+            Some(TEST_KEY_BLOB.to_vec())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_key_blob() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let key_descriptor = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+
+        let mut new_blob_metadata = BlobMetaData::new();
+        new_blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(1001)));
+        new_blob_metadata.add(BlobMetaEntry::Iv(vec![4, 5, 6]));
+        new_blob_metadata.add(BlobMetaEntry::AeadTag(vec![6, 5, 4]));
+        new_blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        db.replace_key_blob(&key_id, b"my rewrapped blob", &new_blob_metadata)?;
+        let id = key_id.id();
+        drop(key_id);
+
+        // The old blob and its metadata must be gone, not merely superseded.
+        let blobs: Vec<Vec<u8>> = db
+            .conn
+            .prepare(
+                "SELECT blob FROM persistent.blobentry
+                 WHERE keyentryid = ? AND subcomponent_type = ?;",
+            )?
+            .query_map(params![id, SubComponentType::KEY_BLOB], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<Vec<u8>>>>()?;
+        assert_eq!(blobs, vec![b"my rewrapped blob".to_vec()]);
+        let metadata_count: i64 = db.conn.query_row(
+            "SELECT COUNT(*) FROM persistent.blobmetadata
+             WHERE blobentryid NOT IN (SELECT id FROM persistent.blobentry);",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        assert_eq!(metadata_count, 0);
+
+        let (_, key_entry) = db.load_key_entry(
+            &key_descriptor,
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            1,
+            |_k, _av| Ok(()),
+        )?;
+        let mut expected = make_test_key_entry_test_vector(id, None);
+        expected.key_blob_info = Some((b"my rewrapped blob".to_vec(), new_blob_metadata));
+        assert_eq!(key_entry, expected);
+
+        // Replacing the blob of a key without key blob fails.
+        let cert_only = db.create_key_entry(&Domain::APP, &1, KeyType::Client, &KEYSTORE_UUID)?;
+        db.set_blob(&cert_only, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        check_result_is_error_containing_string(
+            db.replace_key_blob(&cert_only, TEST_KEY_BLOB, &BlobMetaData::new()),
+            "has no key blob to replace",
+        );
+        Ok(())
+    }
+
+    static TEST_ALIAS: &str = "my super duper key";
+
+    #[test]
+    fn test_cert_update_invalidates_attestation_cert_cache() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let load_cert = |db: &mut KeystoreDB| {
+            ATTESTATION_CERT_CACHE
+                .get_or_load(key_id.id(), || Ok(db.load_certificates(&key_id)?.0.unwrap()))
+        };
+
+        assert_eq!(
+            db.load_certificates(&key_id)?,
+            (Some(TEST_CERT_BLOB.to_vec()), Some(TEST_CERT_CHAIN_BLOB.to_vec()))
+        );
+        assert_eq!(load_cert(&mut db)?, TEST_CERT_BLOB);
+        assert!(ATTESTATION_CERT_CACHE.contains(key_id.id()));
+
+        // Replacing the certificate drops the cached one, so the new one is served.
+        db.set_blob(&key_id, SubComponentType::CERT, Some(b"updated cert"), None)?;
+        assert!(!ATTESTATION_CERT_CACHE.contains(key_id.id()));
+        assert_eq!(load_cert(&mut db)?, b"updated cert");
+
+        // So does removing the certificate chain.
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, None, None)?;
+        assert!(!ATTESTATION_CERT_CACHE.contains(key_id.id()));
+
+        // Updating the key blob leaves the cached certificate in place.
+        assert_eq!(load_cert(&mut db)?, b"updated cert");
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(TEST_KEY_BLOB), None)?;
+        assert!(ATTESTATION_CERT_CACHE.contains(key_id.id()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cert_update_restores_certificate_subject() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let ca_certs = split_certificate_chain(LOADED_CACERT_AUTHBOUND)?;
+        let leaf_subject = parse_subject_from_certificate(LOADED_CERT_AUTHBOUND)?;
+        let ca_subject = parse_subject_from_certificate(ca_certs[0])?;
+        assert_ne!(leaf_subject, ca_subject);
+
+        // The test certificate of the key entry is no DER certificate, so it has no subject.
+        assert_eq!(db.load_certificate_subject(&key_id)?, None);
 
-        db.ungrant(&app_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
-        db.ungrant(&selinux_key, CALLER_UID, GRANTEE_UID, |_| Ok(()))?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(LOADED_CERT_AUTHBOUND), None)?;
+        assert_eq!(db.load_certificate_subject(&key_id)?, Some(leaf_subject));
 
+        // Replacing the certificate re-parses the subject and drops the cached one.
+        assert_eq!(
+            ATTESTATION_CERT_CACHE.get_or_load(key_id.id(), || Ok(b"cached".to_vec()))?,
+            b"cached"
+        );
+        db.set_blob(&key_id, SubComponentType::CERT, Some(ca_certs[0]), None)?;
+        assert_eq!(db.load_certificate_subject(&key_id)?, Some(ca_subject));
+        assert!(!ATTESTATION_CERT_CACHE.contains(key_id.id()));
+
+        // A certificate that cannot be parsed or removing the certificate leaves no stale subject.
+        db.set_blob(&key_id, SubComponentType::CERT, Some(b"not a certificate"), None)?;
+        assert_eq!(db.load_certificate_subject(&key_id)?, None);
+        db.set_blob(&key_id, SubComponentType::CERT, Some(ca_certs[0]), None)?;
+        db.set_blob(&key_id, SubComponentType::CERT, None, None)?;
+        assert_eq!(db.load_certificate_subject(&key_id)?, None);
         Ok(())
     }
 
-    static TEST_KEY_BLOB: &[u8] = b"my test blob";
-    static TEST_CERT_BLOB: &[u8] = b"my test cert";
-    static TEST_CERT_CHAIN_BLOB: &[u8] = b"my test cert_chain";
+    #[test]
+    fn test_attach_certificate_chain() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(LOADED_CERT_AUTHBOUND), None)?;
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, None, None)?;
+        assert_eq!(
+            ATTESTATION_CERT_CACHE.get_or_load(key_id.id(), || Ok(b"cached".to_vec()))?,
+            b"cached"
+        );
+
+        let chain = [LOADED_CERT_AUTHBOUND, LOADED_CACERT_AUTHBOUND].concat();
+        db.attach_certificate_chain(&key_id, &chain)?;
+        assert_eq!(
+            db.load_certificates(&key_id)?,
+            (Some(LOADED_CERT_AUTHBOUND.to_vec()), Some(LOADED_CACERT_AUTHBOUND.to_vec()))
+        );
+        assert_eq!(
+            db.load_certificate_subject(&key_id)?,
+            Some(parse_subject_from_certificate(LOADED_CERT_AUTHBOUND)?)
+        );
+        assert!(!ATTESTATION_CERT_CACHE.contains(key_id.id()));
+
+        // A chain of only the leaf certificate removes the certificate chain.
+        db.attach_certificate_chain(&key_id, LOADED_CERT_AUTHBOUND)?;
+        assert_eq!(db.load_certificates(&key_id)?, (Some(LOADED_CERT_AUTHBOUND.to_vec()), None));
+        Ok(())
+    }
 
     #[test]
-    fn test_set_blob() -> Result<()> {
-        let key_id = KEY_ID_LOCK.get(3000);
+    fn test_attach_non_matching_certificate_chain() -> Result<()> {
         let mut db = new_test_db()?;
-        let mut blob_metadata = BlobMetaData::new();
-        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
-        db.set_blob(
-            &key_id,
-            SubComponentType::KEY_BLOB,
-            Some(TEST_KEY_BLOB),
-            Some(&blob_metadata),
-        )?;
-        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
-        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
-        drop(key_id);
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(LOADED_CERT_AUTHBOUND), None)?;
+        let ca_certs = split_certificate_chain(LOADED_CACERT_AUTHBOUND)?;
+        let is_invalid_argument = |result: Result<()>| {
+            result.unwrap_err().root_cause().downcast_ref::<KsError>()
+                == Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+        };
 
-        let mut stmt = db.conn.prepare(
-            "SELECT subcomponent_type, keyentryid, blob, id FROM persistent.blobentry
-                ORDER BY subcomponent_type ASC;",
-        )?;
-        let mut rows = stmt
-            .query_map::<((SubComponentType, i64, Vec<u8>), i64), _, _>(NO_PARAMS, |row| {
-                Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
-            })?;
-        let (r, id) = rows.next().unwrap().unwrap();
-        assert_eq!(r, (SubComponentType::KEY_BLOB, 3000, TEST_KEY_BLOB.to_vec()));
-        let (r, _) = rows.next().unwrap().unwrap();
-        assert_eq!(r, (SubComponentType::CERT, 3000, TEST_CERT_BLOB.to_vec()));
-        let (r, _) = rows.next().unwrap().unwrap();
-        assert_eq!(r, (SubComponentType::CERT_CHAIN, 3000, TEST_CERT_CHAIN_BLOB.to_vec()));
+        // The leaf certificate of the chain certifies another public key.
+        assert!(is_invalid_argument(db.attach_certificate_chain(&key_id, LOADED_CACERT_AUTHBOUND)));
+        assert!(is_invalid_argument(
+            db.attach_certificate_chain(&key_id, &[ca_certs[0], LOADED_CERT_AUTHBOUND].concat())
+        ));
+        // Chains that cannot be parsed are rejected.
+        assert!(is_invalid_argument(db.attach_certificate_chain(&key_id, &[])));
+        assert!(is_invalid_argument(db.attach_certificate_chain(&key_id, b"not a certificate")));
+        // Rejected chains leave the certificates in place.
+        assert_eq!(
+            db.load_certificates(&key_id)?,
+            (Some(LOADED_CERT_AUTHBOUND.to_vec()), Some(TEST_CERT_CHAIN_BLOB.to_vec()))
+        );
 
-        drop(rows);
-        drop(stmt);
+        // Without a certificate, there is no public key to match the chain against.
+        db.set_blob(&key_id, SubComponentType::CERT, None, None)?;
+        assert!(is_invalid_argument(db.attach_certificate_chain(&key_id, LOADED_CERT_AUTHBOUND)));
+        Ok(())
+    }
 
+    #[test]
+    fn test_key_fingerprint() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let load_fingerprint =
+            |db: &mut KeystoreDB| db.load_key_fingerprint(&key, KeyType::Client, 1, |_, _| Ok(()));
+
+        // The fingerprint is computed when the key is created and stable across loads.
+        let fingerprint = load_fingerprint(&mut db)?.unwrap();
+        assert_eq!(fingerprint.algorithm, FingerprintAlgorithm::Sha256);
+        assert_eq!(fingerprint.digest, sha256(TEST_CERT_BLOB)?);
+        assert_eq!(load_fingerprint(&mut db)?, Some(fingerprint.clone()));
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(b"new chain"), None)?;
+        assert_eq!(load_fingerprint(&mut db)?, Some(fingerprint.clone()));
+
+        // It changes with the certificate, and goes away with it.
+        db.set_blob(&key_id, SubComponentType::CERT, Some(LOADED_CERT_AUTHBOUND), None)?;
+        let new_fingerprint = load_fingerprint(&mut db)?.unwrap();
+        assert_ne!(new_fingerprint, fingerprint);
+        assert_eq!(new_fingerprint.digest, sha256(LOADED_CERT_AUTHBOUND)?);
+        db.set_blob(&key_id, SubComponentType::CERT, None, None)?;
+        assert_eq!(load_fingerprint(&mut db)?, None);
+
+        // The permission is checked.
         assert_eq!(
-            db.with_transaction(TransactionBehavior::Immediate, |tx| {
-                BlobMetaData::load_from_db(id, tx).no_gc()
+            db.load_key_fingerprint(&key, KeyType::Client, 1, |_, _| Err(KsError::perm().into()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::perm())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_creation_parameters() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, Some(3))?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+
+        let mut parameters =
+            db.load_key_creation_parameters(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+        let mut expected: Vec<KeyParameter> = make_test_params(Some(3))
+            .into_iter()
+            .filter(|kp| !KeystoreDB::WITHHELD_CREATION_TAGS.contains(&kp.get_tag()))
+            .collect();
+        parameters.sort();
+        expected.sort();
+        assert_eq!(parameters, expected);
+
+        // The parameters that describe the key are returned, those that identify the app, the
+        // user, or the device are not.
+        for value in [
+            KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+            KeyParameterValue::Algorithm(Algorithm::RSA),
+            KeyParameterValue::KeySize(1024),
+            KeyParameterValue::Digest(Digest::SHA_2_256),
+            KeyParameterValue::UsageCountLimit(3),
+        ] {
+            assert!(parameters.iter().any(|kp| *kp.key_parameter_value() == value), "{:?}", value);
+        }
+        for tag in [Tag::APPLICATION_ID, Tag::USER_SECURE_ID, Tag::ATTESTATION_ID_SERIAL] {
+            assert!(parameters.iter().all(|kp| kp.get_tag() != tag), "{:?}", tag);
+        }
+
+        // The permission is checked.
+        assert_eq!(
+            db.load_key_creation_parameters(&key, KeyType::Client, 1, |_, _| {
+                Err(KsError::perm().into())
             })
-            .expect("Should find blob metadata."),
-            blob_metadata
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>(),
+            Some(&KsError::perm())
         );
         Ok(())
     }
 
-    static TEST_ALIAS: &str = "my super duper key";
+    #[test]
+    fn test_key_fingerprint_algorithm() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let load_fingerprint =
+            |db: &mut KeystoreDB| db.load_key_fingerprint(&key, KeyType::Client, 1, |_, _| Ok(()));
+        let fingerprint = load_fingerprint(&mut db)?.unwrap();
+
+        // Fingerprints that were stored without an algorithm are SHA-256 digests.
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            tx.execute(
+                "DELETE FROM persistent.keymetadata WHERE keyentryid = ? AND tag = ?;",
+                params![key_id.id(), KeyMetaData::PublicKeyFingerprintAlgorithm],
+            )?;
+            Ok(()).no_gc()
+        })?;
+        assert_eq!(load_fingerprint(&mut db)?, Some(fingerprint.clone()));
+
+        // A stored fingerprint keeps its algorithm, so it differs from one of the same
+        // certificate with another algorithm.
+        db.with_transaction(TransactionBehavior::Immediate, |tx| {
+            let mut metadata = KeyMetaData::new();
+            metadata.add(KeyMetaEntry::PublicKeyFingerprint(sha512(TEST_CERT_BLOB)?));
+            metadata.add(KeyMetaEntry::PublicKeyFingerprintAlgorithm(FingerprintAlgorithm::Sha512));
+            metadata.store_in_db(key_id.id(), tx)?;
+            Ok(()).no_gc()
+        })?;
+        let sha512_fingerprint = load_fingerprint(&mut db)?.unwrap();
+        assert_eq!(sha512_fingerprint.algorithm, FingerprintAlgorithm::Sha512);
+        assert_eq!(sha512_fingerprint.digest, sha512(TEST_CERT_BLOB)?);
+        assert_ne!(sha512_fingerprint, fingerprint);
+        assert_eq!(sha512_fingerprint.compare(&fingerprint), None);
+        assert_eq!(
+            fingerprint.recompute(FingerprintAlgorithm::Sha512, TEST_CERT_BLOB)?,
+            sha512_fingerprint
+        );
+
+        // Replacing the certificate recomputes the fingerprint with the configured algorithm.
+        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        assert_eq!(load_fingerprint(&mut db)?, Some(fingerprint));
+        Ok(())
+    }
 
     #[test]
     fn test_insert_and_load_full_keyentry_domain_app() -> Result<()> {
@@ -4545,6 +9426,164 @@ pub mod tests {
         Ok(())
     }
 
+    // Bound blobs can be moved within but not across users, see `migrate_key_namespace`.
+    #[test]
+    fn test_migrate_key_with_bound_blobs() -> Result<()> {
+        let mut db = new_test_db()?;
+        const SOURCE_UID: u32 = 1u32;
+        const OTHER_USER_UID: u32 = AID_USER_OFFSET + 1;
+        let destination = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        let mut bound_metadata = BlobMetaData::new();
+        bound_metadata.add(BlobMetaEntry::Iv(vec![2, 3, 1]));
+        bound_metadata.add(BlobMetaEntry::AeadTag(vec![3, 1, 2]));
+        bound_metadata.add(BlobMetaEntry::BoundUserId(0));
+
+        for (i, sc_type) in
+            [SubComponentType::KEY_BLOB, SubComponentType::CERT_CHAIN].into_iter().enumerate()
+        {
+            let alias = format!("SOURCE_ALIAS {}", i);
+            let key_id_guard =
+                make_test_key_entry(&mut db, Domain::APP, SOURCE_UID as i64, &alias, None)?;
+            db.set_blob(&key_id_guard, sc_type, Some(TEST_KEY_BLOB), Some(&bound_metadata))?;
+            let key_id = key_id_guard.id();
+            assert_eq!(
+                Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+                db.migrate_key_namespace(
+                    key_id_guard,
+                    &destination("DESTINATION_ALIAS"),
+                    OTHER_USER_UID,
+                    |_k| Ok(())
+                )
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+            );
+            // The key stays where it was.
+            assert_eq!(
+                db.load_key_descriptor(key_id)?.map(|k| (k.nspace, k.alias)),
+                Some((SOURCE_UID as i64, Some(alias)))
+            );
+
+            db.migrate_key_namespace(
+                KEY_ID_LOCK.get(key_id, KeyIdLockOperation::LoadKeyEntry),
+                &destination(&format!("DESTINATION_ALIAS {}", i)),
+                SOURCE_UID + 1,
+                |_k| Ok(()),
+            )?;
+            assert_eq!(
+                db.load_key_descriptor(key_id)?.map(|k| k.nspace),
+                Some(SOURCE_UID as i64 + 1)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        const UID: u32 = 1u32;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, UID as i64, "old_alias", None)?.id();
+        let descriptor = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+
+        db.rename_key(&descriptor("old_alias"), "new_alias", UID, |k, av| {
+            assert_eq!(Domain::APP, k.domain);
+            assert_eq!(UID as i64, k.nspace);
+            assert_eq!(Some("old_alias"), k.alias.as_deref());
+            assert!(av.is_none());
+            Ok(())
+        })?;
+
+        let (_, key_entry) = db.load_key_entry(
+            &descriptor("new_alias"),
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            UID,
+            |_k, _av| Ok(()),
+        )?;
+        assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND)),
+            db.load_key_entry(
+                &descriptor("old_alias"),
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                UID,
+                |_k, _av| Ok(()),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>()
+        );
+
+        // A denied permission leaves the key untouched.
+        let deny = |_k: &KeyDescriptor, _av: Option<KeyPermSet>| -> Result<()> {
+            Err(KsError::perm().into())
+        };
+        assert_eq!(
+            Some(&KsError::perm()),
+            db.rename_key(&descriptor("new_alias"), "other_alias", UID, deny)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        db.load_key_entry(
+            &descriptor("new_alias"),
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            UID,
+            |_k, _av| Ok(()),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_key_to_existing_alias() -> Result<()> {
+        let mut db = new_test_db()?;
+        const UID: u32 = 1u32;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, UID as i64, "alias1", None)?.id();
+        let other_key_id =
+            make_test_key_entry(&mut db, Domain::APP, UID as i64, "alias2", None)?.id();
+        // The same alias in another namespace does not get in the way.
+        make_test_key_entry(&mut db, Domain::APP, UID as i64 + 1, "alias3", None)?;
+        let descriptor = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: -1,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+
+        assert_eq!(
+            Some(&KsError::Rc(ResponseCode::INVALID_ARGUMENT)),
+            db.rename_key(&descriptor("alias1"), "alias2", UID, |_k, _av| Ok(()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>()
+        );
+        for (alias, id) in [("alias1", key_id), ("alias2", other_key_id)] {
+            let (key_id_guard, _) = db.load_key_entry(
+                &descriptor(alias),
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                UID,
+                |_k, _av| Ok(()),
+            )?;
+            assert_eq!(key_id_guard.id(), id);
+        }
+
+        db.rename_key(&descriptor("alias1"), "alias3", UID, |_k, _av| Ok(()))?;
+        Ok(())
+    }
+
     #[test]
     fn test_upgrade_0_to_1() {
         const ALIAS1: &str = "test_upgrade_0_to_1_1";
@@ -4767,6 +9806,138 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_shared_and_exclusive_key_loads() -> Result<()> {
+        let temp_dir = Arc::new(TempDir::new("id_lock_mode_test")?);
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        make_test_key_entry(&mut db, Domain::APP, 33, KEY_LOCK_TEST_ALIAS, None)?;
+        let load = |db: &mut KeystoreDB, lock_mode: KeyIdLockMode| {
+            db.load_key_entry_with_mode(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 0,
+                    alias: Some(KEY_LOCK_TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::PUBLIC,
+                33,
+                lock_mode,
+                |_k, _av| Ok(()),
+            )
+        };
+
+        let (shared_guard, _) = load(&mut db, KeyIdLockMode::Shared)?;
+        assert_eq!(shared_guard.mode(), KeyIdLockMode::Shared);
+
+        // A second shared load in another thread does not wait for the first guard.
+        let temp_dir_clone = temp_dir.clone();
+        let handle = thread::spawn(move || {
+            let mut db = KeystoreDB::new(temp_dir_clone.path(), None).unwrap();
+            let (guard, _) = load(&mut db, KeyIdLockMode::Shared).unwrap();
+            guard.id()
+        });
+        assert_eq!(handle.join().unwrap(), shared_guard.id());
+
+        // An exclusive load waits until the shared guard is released.
+        let state = Arc::new(AtomicU8::new(1));
+        let state2 = state.clone();
+        let temp_dir_clone = temp_dir.clone();
+        let handle = thread::spawn(move || {
+            let mut db = KeystoreDB::new(temp_dir_clone.path(), None).unwrap();
+            let (guard, _) = load(&mut db, KeyIdLockMode::Exclusive).unwrap();
+            assert_eq!(2, state2.load(Ordering::Relaxed));
+            guard
+        });
+        thread::sleep(std::time::Duration::from_millis(500));
+        assert_eq!(Ok(1), state.compare_exchange(1, 2, Ordering::Relaxed, Ordering::Relaxed));
+        drop(shared_guard);
+        let exclusive_guard = handle.join().unwrap();
+        assert_eq!(exclusive_guard.mode(), KeyIdLockMode::Exclusive);
+
+        // While the exclusive guard is held, no shared lock can be taken.
+        assert!(KEY_ID_LOCK
+            .try_get_with_mode(
+                exclusive_guard.id(),
+                KeyIdLockMode::Shared,
+                KeyIdLockOperation::LoadKeyEntry
+            )
+            .is_none());
+        drop(exclusive_guard);
+        Ok(())
+    }
+
+    fn key_id_lock_hold_count(operation: MetricsKeyIdLockOperation, exclusive: bool) -> i32 {
+        let expected =
+            KeystoreAtomPayload::KeyIdLockHoldStats(KeyIdLockHoldStats { operation, exclusive });
+        METRICS_STORE
+            .get_atoms(AtomID::KEY_ID_LOCK_HOLD_STATS)
+            .unwrap()
+            .iter()
+            .find(|atom| atom.payload == expected)
+            .map_or(0, |atom| atom.count)
+    }
+
+    #[test]
+    fn test_key_id_lock_held_too_long() {
+        let lock_db: &'static KeyIdLockDb =
+            Box::leak(Box::new(KeyIdLockDb::with_hold_threshold(Duration::from_millis(50))));
+        let before = key_id_lock_hold_count(MetricsKeyIdLockOperation::LOAD_ATTESTATION_KEY, true);
+
+        let guard = lock_db.get(1, KeyIdLockOperation::LoadAttestationKey);
+        assert!(!lock_db.report_hold_time(&guard, Duration::from_millis(10)));
+        drop(guard);
+
+        let guard = lock_db.get(1, KeyIdLockOperation::LoadAttestationKey);
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        // Other tests may hold locks of this operation for long, too.
+        assert!(
+            key_id_lock_hold_count(MetricsKeyIdLockOperation::LOAD_ATTESTATION_KEY, true) > before
+        );
+
+        // A threshold of zero disables the reports.
+        let lock_db: &'static KeyIdLockDb =
+            Box::leak(Box::new(KeyIdLockDb::with_hold_threshold(Duration::ZERO)));
+        let guard = lock_db.get(1, KeyIdLockOperation::LoadAttestationKey);
+        assert!(!lock_db.report_hold_time(&guard, Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_journal_modes() -> Result<()> {
+        for journal_mode in [JournalMode::Delete, JournalMode::Wal] {
+            let temp_dir = TempDir::new("test_journal_modes_")?;
+            let mut db = KeystoreDB::new_with_journal_mode(temp_dir.path(), None, journal_mode)?;
+            assert_eq!(db.journal_mode(), journal_mode);
+            let mode: String =
+                db.conn
+                    .query_row("PRAGMA persistent.journal_mode;", NO_PARAMS, |row| row.get(0))?;
+            assert_eq!(mode.to_uppercase(), journal_mode.pragma_value());
+
+            let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+
+            // A second connection sees the key, and so does the first one after a checkpoint.
+            let mut db2 = KeystoreDB::new_with_journal_mode(temp_dir.path(), None, journal_mode)?;
+            let (_, entry) = db2.load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 0,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::BOTH,
+                1,
+                |_k, _av| Ok(()),
+            )?;
+            assert_eq!(entry.id(), key_id);
+            drop(db2);
+            assert!(db.checkpoint_wal()?.is_some());
+            assert_eq!(db.list_past_alias(Domain::APP, 1, KeyType::Client, None)?.len(), 1);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_database_busy_error_code() {
         let temp_dir =
@@ -4799,6 +9970,72 @@ pub mod tests {
         )
     }
 
+    fn database_contention_logged(retries_exhausted: bool) -> bool {
+        let expected = KeystoreAtomPayload::DatabaseContentionStats(DatabaseContentionStats {
+            retries_exhausted,
+        });
+        METRICS_STORE
+            .get_atoms(AtomID::DATABASE_CONTENTION_STATS)
+            .unwrap()
+            .iter()
+            .any(|atom| atom.payload == expected && atom.count > 0)
+    }
+
+    #[test]
+    fn test_transaction_retries_while_database_is_busy() -> Result<()> {
+        let temp_dir = TempDir::new("test_transaction_retries_while_database_is_busy_")
+            .expect("Failed to create temp dir.");
+        let mut db = KeystoreDB::new(temp_dir.path(), None).expect("Failed to open database1.");
+
+        // Another connection holds a write transaction for a while, so that the transactions of
+        // the first connection fail with DatabaseBusy until it is rolled back.
+        let (locked_sender, locked_receiver) = std::sync::mpsc::channel();
+        let path = temp_dir.path().to_owned();
+        let handle = thread::spawn(move || {
+            let mut db = KeystoreDB::new(&path, None).expect("Failed to open database2.");
+            let tx = db
+                .conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .expect("Failed to create transaction.");
+            locked_sender.send(()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            tx.rollback().expect("Failed to roll back transaction.");
+        });
+        locked_receiver.recv().unwrap();
+
+        let key_id = db.create_key_entry(&Domain::APP, &100, KeyType::Client, &KEYSTORE_UUID)?;
+        handle.join().unwrap();
+
+        // The failed attempts were rolled back, so the key entry was created exactly once.
+        let entries = get_keyentry(&db)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, key_id.id());
+        assert!(database_contention_logged(false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_fails_if_database_stays_busy() -> Result<()> {
+        let temp_dir = TempDir::new("test_transaction_fails_if_database_stays_busy_")
+            .expect("Failed to create temp dir.");
+        let mut db1 = KeystoreDB::new(temp_dir.path(), None).expect("Failed to open database1.");
+        let mut db2 = KeystoreDB::new(temp_dir.path(), None).expect("Failed to open database2.");
+
+        let tx1 = db1
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .expect("Failed to create first transaction.");
+        let error = db2
+            .create_key_entry(&Domain::APP, &100, KeyType::Client, &KEYSTORE_UUID)
+            .expect_err("The database should stay busy.");
+        assert!(KeystoreDB::is_locked_error(&error));
+        assert!(database_contention_logged(true));
+        tx1.rollback()?;
+
+        db2.create_key_entry(&Domain::APP, &100, KeyType::Client, &KEYSTORE_UUID)?;
+        Ok(())
+    }
+
     #[cfg(disabled)]
     #[test]
     fn test_large_number_of_concurrent_db_manipulations() -> Result<()> {
@@ -5065,7 +10302,13 @@ pub mod tests {
         let priv_key: Vec<u8> = vec![0x05 * base_byte, 0x06 * base_byte];
         let raw_public_key: Vec<u8> = vec![0x0b * base_byte, 0x0c * base_byte];
         let batch_cert: Vec<u8> = vec![base_byte * 0x0d, base_byte * 0x0e];
-        db.create_attestation_key_entry(&public_key, &raw_public_key, &priv_key, &KEYSTORE_UUID)?;
+        db.create_attestation_key_entry(
+            &public_key,
+            &raw_public_key,
+            &priv_key,
+            &KEYSTORE_UUID,
+            None,
+        )?;
         db.store_signed_attestation_certificate_chain(
             &raw_public_key,
             &batch_cert,
@@ -5073,7 +10316,7 @@ pub mod tests {
             expiration_date,
             &KEYSTORE_UUID,
         )?;
-        db.assign_attestation_key(Domain::APP, namespace, &KEYSTORE_UUID)?;
+        db.assign_attestation_key(Domain::APP, namespace, &KEYSTORE_UUID, None)?;
         Ok(RemoteProvValues { cert_chain, priv_key, batch_cert })
     }
 
@@ -5325,209 +10568,1045 @@ pub mod tests {
         blob_metadata.add(BlobMetaEntry::AeadTag(vec![3, 1, 2]));
         blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
 
-        db.set_blob(
-            &key_id,
-            SubComponentType::KEY_BLOB,
-            Some(TEST_KEY_BLOB),
-            Some(&blob_metadata),
-        )?;
-        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
-        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
+        db.set_blob(
+            &key_id,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
+
+        let params = make_test_params(max_usage_count);
+        db.insert_keyparameter(&key_id, &params)?;
+
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
+        db.insert_key_metadata(&key_id, &metadata)?;
+        rebind_alias(db, &key_id, alias, domain, namespace)?;
+        Ok(key_id)
+    }
+
+    fn make_test_key_entry_test_vector(key_id: i64, max_usage_count: Option<i32>) -> KeyEntry {
+        let params = make_test_params(max_usage_count);
+
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::Password));
+        blob_metadata.add(BlobMetaEntry::Salt(vec![1, 2, 3]));
+        blob_metadata.add(BlobMetaEntry::Iv(vec![2, 3, 1]));
+        blob_metadata.add(BlobMetaEntry::AeadTag(vec![3, 1, 2]));
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
+        metadata.add(KeyMetaEntry::PublicKeyFingerprint(sha256(TEST_CERT_BLOB).unwrap()));
+        metadata.add(KeyMetaEntry::PublicKeyFingerprintAlgorithm(FingerprintAlgorithm::Sha256));
+
+        KeyEntry {
+            id: key_id,
+            key_blob_info: Some((TEST_KEY_BLOB.to_vec(), blob_metadata)),
+            cert: Some(TEST_CERT_BLOB.to_vec()),
+            cert_chain: Some(TEST_CERT_CHAIN_BLOB.to_vec()),
+            cert_chain_metadata: BlobMetaData::new(),
+            km_uuid: KEYSTORE_UUID,
+            parameters: params,
+            metadata,
+            pure_cert: false,
+        }
+    }
+
+    fn make_bootlevel_key_entry(
+        db: &mut KeystoreDB,
+        domain: Domain,
+        namespace: i64,
+        alias: &str,
+        logical_only: bool,
+    ) -> Result<KeyIdGuard> {
+        let key_id = db.create_key_entry(&domain, &namespace, KeyType::Client, &KEYSTORE_UUID)?;
+        let mut blob_metadata = BlobMetaData::new();
+        if !logical_only {
+            blob_metadata.add(BlobMetaEntry::MaxBootLevel(3));
+        }
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+
+        db.set_blob(
+            &key_id,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
+
+        let mut params = make_test_params(None);
+        params.push(KeyParameter::new(KeyParameterValue::MaxBootLevel(3), SecurityLevel::KEYSTORE));
+
+        db.insert_keyparameter(&key_id, &params)?;
+
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
+        db.insert_key_metadata(&key_id, &metadata)?;
+        rebind_alias(db, &key_id, alias, domain, namespace)?;
+        Ok(key_id)
+    }
+
+    fn make_bootlevel_test_key_entry_test_vector(key_id: i64, logical_only: bool) -> KeyEntry {
+        let mut params = make_test_params(None);
+        params.push(KeyParameter::new(KeyParameterValue::MaxBootLevel(3), SecurityLevel::KEYSTORE));
+
+        let mut blob_metadata = BlobMetaData::new();
+        if !logical_only {
+            blob_metadata.add(BlobMetaEntry::MaxBootLevel(3));
+        }
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
+        metadata.add(KeyMetaEntry::PublicKeyFingerprint(sha256(TEST_CERT_BLOB).unwrap()));
+        metadata.add(KeyMetaEntry::PublicKeyFingerprintAlgorithm(FingerprintAlgorithm::Sha256));
+
+        KeyEntry {
+            id: key_id,
+            key_blob_info: Some((TEST_KEY_BLOB.to_vec(), blob_metadata)),
+            cert: Some(TEST_CERT_BLOB.to_vec()),
+            cert_chain: Some(TEST_CERT_CHAIN_BLOB.to_vec()),
+            cert_chain_metadata: BlobMetaData::new(),
+            km_uuid: KEYSTORE_UUID,
+            parameters: params,
+            metadata,
+            pure_cert: false,
+        }
+    }
+
+    fn debug_dump_keyentry_table(db: &mut KeystoreDB) -> Result<()> {
+        let mut stmt = db.conn.prepare(
+            "SELECT id, key_type, domain, namespace, alias, state, km_uuid FROM persistent.keyentry;",
+        )?;
+        let rows = stmt.query_map::<(i64, KeyType, i32, i64, String, KeyLifeCycle, Uuid), _, _>(
+            NO_PARAMS,
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            },
+        )?;
+
+        println!("Key entry table rows:");
+        for r in rows {
+            let (id, key_type, domain, namespace, alias, state, km_uuid) = r.unwrap();
+            println!(
+                "    id: {} KeyType: {:?} Domain: {} Namespace: {} Alias: {} State: {:?} KmUuid: {:?}",
+                id, key_type, domain, namespace, alias, state, km_uuid
+            );
+        }
+        Ok(())
+    }
+
+    fn debug_dump_grant_table(db: &mut KeystoreDB) -> Result<()> {
+        let mut stmt = db
+            .conn
+            .prepare("SELECT id, grantee, keyentryid, access_vector FROM persistent.grant;")?;
+        let rows = stmt.query_map::<(i64, i64, i64, i64), _, _>(NO_PARAMS, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+
+        println!("Grant table rows:");
+        for r in rows {
+            let (id, gt, ki, av) = r.unwrap();
+            println!("    id: {} grantee: {} key_id: {} access_vector: {}", id, gt, ki, av);
+        }
+        Ok(())
+    }
+
+    // Use a custom random number generator that repeats each number once.
+    // This allows us to test repeated elements.
+
+    thread_local! {
+        static RANDOM_COUNTER: RefCell<i64> = RefCell::new(0);
+    }
+
+    fn reset_random() {
+        RANDOM_COUNTER.with(|counter| {
+            *counter.borrow_mut() = 0;
+        })
+    }
+
+    pub fn random() -> i64 {
+        RANDOM_COUNTER.with(|counter| {
+            let result = *counter.borrow() / 2;
+            *counter.borrow_mut() += 1;
+            result
+        })
+    }
+
+    #[test]
+    fn test_last_off_body() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.insert_last_off_body(MonotonicRawTime::now());
+        let tx = db.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.commit()?;
+        let last_off_body_1 = db.get_last_off_body();
+        let one_second = Duration::from_secs(1);
+        thread::sleep(one_second);
+        db.update_last_off_body(MonotonicRawTime::now());
+        let tx2 = db.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx2.commit()?;
+        let last_off_body_2 = db.get_last_off_body();
+        assert!(last_off_body_1 < last_off_body_2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_namespace() -> Result<()> {
+        const APP: i64 = 10001;
+        const OTHER_APP: u32 = 10002;
+        let mut db = new_test_db()?;
+        let mut key_ids = Vec::new();
+        for alias in ["a", "b"] {
+            let key_id = make_test_key_entry(&mut db, Domain::APP, APP, alias, None)?.id();
+            let key = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: APP,
+                alias: Some(alias.to_string()),
+                blob: None,
+            };
+            db.grant(&key, APP as u32, OTHER_APP, key_perm_set![KeyPerm::Use], |_, _| Ok(()))?;
+            db.set_custom_metadata(&key, KeyType::Client, APP as u32, "name", b"value", |_, _| {
+                Ok(())
+            })?;
+            key_ids.push(key_id);
+        }
+        let other_key_id =
+            make_test_key_entry(&mut db, Domain::APP, OTHER_APP as i64, "a", None)?.id();
+
+        assert_eq!(db.delete_namespace(APP)?, 2);
+        assert_eq!(db.delete_namespace(APP)?, 0);
+
+        for table in ["keyentry", "keyparameter", "keymetadata", "keycustommetadata", "grant"] {
+            let key_column = if table == "keyentry" { "id" } else { "keyentryid" };
+            let remaining: Vec<i64> = db
+                .conn
+                .prepare(&format!("SELECT DISTINCT {} FROM persistent.{};", key_column, table))?
+                .query_map(NO_PARAMS, |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            assert!(key_ids.iter().all(|id| !remaining.contains(id)), "{}: {:?}", table, remaining);
+        }
+        assert_eq!(
+            1,
+            db.list_past_alias(Domain::APP, OTHER_APP as i64, KeyType::Client, None)?.len()
+        );
+        db.load_key_entry(
+            &KeyDescriptor {
+                domain: Domain::KEY_ID,
+                nspace: other_key_id,
+                alias: None,
+                blob: None,
+            },
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            OTHER_APP,
+            |_, _| Ok(()),
+        )?;
+
+        // The blobs of the deleted keys are left to the garbage collector.
+        let superseded = db.handle_next_superseded_blobs(&[], 20)?;
+        let mut orphaned: Vec<i64> = superseded.iter().map(|(_, key_id, _, _)| *key_id).collect();
+        orphaned.sort();
+        orphaned.dedup();
+        assert_eq!(orphaned, key_ids);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbind_keys_for_user() -> Result<()> {
+        let mut db = new_test_db()?;
+        db.unbind_keys_for_user(1, false)?;
+
+        make_test_key_entry(&mut db, Domain::APP, 210000, TEST_ALIAS, None)?;
+        make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
+        db.unbind_keys_for_user(2, false)?;
+
+        assert_eq!(1, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
+        assert_eq!(0, db.list_past_alias(Domain::APP, 210000, KeyType::Client, None)?.len());
+
+        db.unbind_keys_for_user(1, true)?;
+        assert_eq!(0, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_revoke_keys_bound_to_sid() -> Result<()> {
+        const REMOVED_SID: i64 = 7;
+        let mut db = new_test_db()?;
+        // All test keys are bound to SID 42; key "b" is bound to the removed SID as well.
+        make_test_key_entry(&mut db, Domain::APP, 1, "a", None)?;
+        let key_b = make_test_key_entry(&mut db, Domain::APP, 1, "b", None)?;
+        db.insert_keyparameter(
+            &key_b,
+            &[KeyParameter::new(
+                KeyParameterValue::UserSecureID(REMOVED_SID),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            )],
+        )?;
+        let key_c = db.create_key_entry(&Domain::APP, &2, KeyType::Client, &KEYSTORE_UUID)?;
+        db.insert_keyparameter(
+            &key_c,
+            &[KeyParameter::new(
+                KeyParameterValue::UserSecureID(REMOVED_SID),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            )],
+        )?;
+        rebind_alias(&mut db, &key_c, "c", Domain::APP, 2)?;
+
+        let aliases = |keys: Vec<KeyDescriptor>| -> Vec<String> {
+            keys.into_iter().map(|k| k.alias.unwrap()).collect()
+        };
+        assert_eq!(aliases(db.list_keys_bound_to_sid(REMOVED_SID)?), vec!["b", "c"]);
+        assert_eq!(aliases(db.list_keys_bound_to_sid(42)?), vec!["a", "b"]);
+
+        assert_eq!(db.revoke_keys_bound_to_sid(REMOVED_SID)?, 2);
+        assert!(db.list_keys_bound_to_sid(REMOVED_SID)?.is_empty());
+        assert_eq!(aliases(db.list_keys_bound_to_sid(42)?), vec!["a"]);
+        assert_eq!(1, db.list_past_alias(Domain::APP, 1, KeyType::Client, None)?.len());
+        assert_eq!(0, db.list_past_alias(Domain::APP, 2, KeyType::Client, None)?.len());
+
+        assert_eq!(db.revoke_keys_bound_to_sid(REMOVED_SID)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_freeze_and_unfreeze_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let is_frozen = |db: &mut KeystoreDB| -> Result<bool> {
+            let (_, key_entry) =
+                db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::NONE, 1, |_, _| Ok(()))?;
+            Ok(key_entry.is_frozen())
+        };
+        assert!(!is_frozen(&mut db)?);
+
+        // A key may be frozen by id or by alias, and freezing it twice is fine.
+        let by_id =
+            KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, alias: None, blob: None };
+        db.set_key_frozen(&by_id, KeyType::Client, 2, true, |_, _| Ok(()))?;
+        db.set_key_frozen(&key, KeyType::Client, 1, true, |_, _| Ok(()))?;
+        assert!(is_frozen(&mut db)?);
 
-        let params = make_test_params(max_usage_count);
-        db.insert_keyparameter(&key_id, &params)?;
+        // The permission is checked before the key is touched.
+        assert_eq!(
+            db.set_key_frozen(&key, KeyType::Client, 1, false, |_, _| Err(KsError::perm().into()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::perm())
+        );
+        assert!(is_frozen(&mut db)?);
 
-        let mut metadata = KeyMetaData::new();
-        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
-        db.insert_key_metadata(&key_id, &metadata)?;
-        rebind_alias(db, &key_id, alias, domain, namespace)?;
-        Ok(key_id)
+        db.set_key_frozen(&key, KeyType::Client, 1, false, |_, _| Ok(()))?;
+        assert!(!is_frozen(&mut db)?);
+
+        // Frozen keys can still be deleted.
+        db.set_key_frozen(&key, KeyType::Client, 1, true, |_, _| Ok(()))?;
+        db.unbind_key(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+        assert_eq!(0, db.list_past_alias(Domain::APP, 1, KeyType::Client, None)?.len());
+        Ok(())
     }
 
-    fn make_test_key_entry_test_vector(key_id: i64, max_usage_count: Option<i32>) -> KeyEntry {
-        let params = make_test_params(max_usage_count);
+    #[test]
+    fn test_key_namespace_derivation() -> Result<()> {
+        const APP_1: u32 = 10001;
+        const APP_2: u32 = 10002;
+        const SELINUX_NAMESPACE: i64 = 101;
+        let mut db = new_test_db()?;
+        let app_1_key_id =
+            make_test_key_entry(&mut db, Domain::APP, APP_1 as i64, TEST_ALIAS, None)?.id();
+        let selinux_key_id =
+            make_test_key_entry(&mut db, Domain::SELINUX, SELINUX_NAMESPACE, TEST_ALIAS, None)?
+                .id();
+        let key = |domain, nspace| KeyDescriptor {
+            domain,
+            nspace,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let load = |db: &mut KeystoreDB, key: &KeyDescriptor, caller_uid| {
+            db.load_key_entry(key, KeyType::Client, KeyEntryLoadBits::NONE, caller_uid, |k, _| {
+                // The permission check always sees the resolved namespace.
+                match k.domain {
+                    Domain::APP => assert_eq!(k.nspace, caller_uid as i64),
+                    _ => assert_eq!(k.nspace, SELINUX_NAMESPACE),
+                }
+                Ok(())
+            })
+        };
 
-        let mut blob_metadata = BlobMetaData::new();
-        blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::Password));
-        blob_metadata.add(BlobMetaEntry::Salt(vec![1, 2, 3]));
-        blob_metadata.add(BlobMetaEntry::Iv(vec![2, 3, 1]));
-        blob_metadata.add(BlobMetaEntry::AeadTag(vec![3, 1, 2]));
-        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        // Domain::APP ignores the supplied namespace in favor of the namespace of the caller.
+        let (guard, _) = load(&mut db, &key(Domain::APP, 0), APP_1)?;
+        assert_eq!(guard.id(), app_1_key_id);
+        drop(guard);
 
-        let mut metadata = KeyMetaData::new();
-        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
+        // Another app cannot reach the key by supplying the namespace of its owner.
+        assert_eq!(
+            load(&mut db, &key(Domain::APP, APP_1 as i64), APP_2)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+        );
 
-        KeyEntry {
-            id: key_id,
-            key_blob_info: Some((TEST_KEY_BLOB.to_vec(), blob_metadata)),
-            cert: Some(TEST_CERT_BLOB.to_vec()),
-            cert_chain: Some(TEST_CERT_CHAIN_BLOB.to_vec()),
-            km_uuid: KEYSTORE_UUID,
-            parameters: params,
-            metadata,
-            pure_cert: false,
-        }
+        // Domain::SELINUX uses the supplied namespace, whoever the caller is. Whether the caller
+        // may access it is decided by the permission check.
+        let (guard, _) = load(&mut db, &key(Domain::SELINUX, SELINUX_NAMESPACE), APP_2)?;
+        assert_eq!(guard.id(), selinux_key_id);
+        drop(guard);
+        assert_eq!(
+            db.load_key_entry(
+                &key(Domain::SELINUX, SELINUX_NAMESPACE),
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                APP_2,
+                |_, _| Err(KsError::Rc(ResponseCode::PERMISSION_DENIED)).context("denied"),
+            )
+            .unwrap_err()
+            .root_cause()
+            .downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::PERMISSION_DENIED))
+        );
+        Ok(())
     }
 
-    fn make_bootlevel_key_entry(
-        db: &mut KeystoreDB,
-        domain: Domain,
-        namespace: i64,
-        alias: &str,
-        logical_only: bool,
-    ) -> Result<KeyIdGuard> {
-        let key_id = db.create_key_entry(&domain, &namespace, KeyType::Client, &KEYSTORE_UUID)?;
-        let mut blob_metadata = BlobMetaData::new();
-        if !logical_only {
-            blob_metadata.add(BlobMetaEntry::MaxBootLevel(3));
+    #[test]
+    fn test_key_inventory_snapshot_diff() -> Result<()> {
+        let mut db = new_test_db()?;
+        for alias in ["unchanged", "exported", "rebound", "deleted"] {
+            make_test_key_entry(&mut db, Domain::APP, 1, alias, None)?;
         }
-        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        // Keys of other namespaces are not part of the snapshot.
+        make_test_key_entry(&mut db, Domain::APP, 2, "other", None)?;
+        let baseline = db.snapshot_key_inventory(Domain::APP, 1)?;
+        let aliases: Vec<&str> = baseline.entries.iter().map(|e| e.alias.as_str()).collect();
+        assert_eq!(aliases, vec!["deleted", "exported", "rebound", "unchanged"]);
+        assert_eq!(db.snapshot_key_inventory(Domain::APP, 1)?, baseline);
+        assert!(baseline.diff(&baseline).changed.is_empty());
+
+        let key = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        db.set_key_exportable(&key("exported"), KeyType::Client, 1, true, |_, _| Ok(()))?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "rebound", None)?;
+        db.unbind_key(&key("deleted"), KeyType::Client, 1, |_, _| Ok(()))?;
+        make_test_key_entry(&mut db, Domain::APP, 1, "added", None)?;
+        let later = db.snapshot_key_inventory(Domain::APP, 1)?;
 
-        db.set_blob(
-            &key_id,
-            SubComponentType::KEY_BLOB,
-            Some(TEST_KEY_BLOB),
-            Some(&blob_metadata),
-        )?;
-        db.set_blob(&key_id, SubComponentType::CERT, Some(TEST_CERT_BLOB), None)?;
-        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(TEST_CERT_CHAIN_BLOB), None)?;
+        assert_eq!(
+            baseline.diff(&later),
+            KeyInventoryDiff {
+                added: vec!["added".to_string()],
+                removed: vec!["deleted".to_string()],
+                changed: vec!["exported".to_string(), "rebound".to_string()],
+            }
+        );
+        assert_ne!(baseline.digest()?, later.digest()?);
+        assert_eq!(baseline.digest()?, baseline.clone().digest()?);
+        Ok(())
+    }
 
-        let mut params = make_test_params(None);
-        params.push(KeyParameter::new(KeyParameterValue::MaxBootLevel(3), SecurityLevel::KEYSTORE));
+    #[test]
+    fn test_check_consistency() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        assert_eq!(db.check_consistency(true)?, ConsistencyReport::default());
 
-        db.insert_keyparameter(&key_id, &params)?;
+        // A blob whose key entry is gone.
+        db.conn.execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])?;
+        let blob_ids: Vec<i64> = db
+            .conn
+            .prepare("SELECT id FROM persistent.blobentry WHERE keyentryid = ? ORDER BY id;")?
+            .query_map(params![key_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert!(!blob_ids.is_empty());
+        // A key entry whose blobs are gone.
+        let other_id = make_test_key_entry(&mut db, Domain::APP, 2, TEST_ALIAS, None)?.id();
+        db.conn
+            .execute("DELETE FROM persistent.blobentry WHERE keyentryid = ?;", params![other_id])?;
+        let expected = ConsistencyReport {
+            blobs_without_key: blob_ids,
+            keys_without_blob: vec![other_id],
+            grants_without_key: vec![],
+        };
 
-        let mut metadata = KeyMetaData::new();
-        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
-        db.insert_key_metadata(&key_id, &metadata)?;
-        rebind_alias(db, &key_id, alias, domain, namespace)?;
-        Ok(key_id)
+        // Without repair, nothing changes.
+        assert_eq!(db.check_consistency(false)?, expected);
+        assert_eq!(db.check_consistency(false)?, expected);
+
+        // The key entry without blobs is removed. The orphaned key blob is left to the garbage
+        // collector, which deletes it with KeyMint.
+        assert_eq!(db.check_consistency(true)?, expected);
+        let report = db.check_consistency(false)?;
+        assert!(report.keys_without_blob.is_empty());
+        assert!(!db.key_exists(Domain::APP, 2, TEST_ALIAS, KeyType::Client)?);
+        assert_eq!(report.blobs_without_key, expected.blobs_without_key);
+        let superseded = db.handle_next_superseded_blobs(&[], 20)?;
+        assert_eq!(superseded.len(), 1);
+        assert!(db.handle_next_superseded_blobs(&[superseded[0].0], 20)?.is_empty());
+        assert_eq!(db.check_consistency(false)?, ConsistencyReport::default());
+        Ok(())
     }
 
-    fn make_bootlevel_test_key_entry_test_vector(key_id: i64, logical_only: bool) -> KeyEntry {
-        let mut params = make_test_params(None);
-        params.push(KeyParameter::new(KeyParameterValue::MaxBootLevel(3), SecurityLevel::KEYSTORE));
-
-        let mut blob_metadata = BlobMetaData::new();
-        if !logical_only {
-            blob_metadata.add(BlobMetaEntry::MaxBootLevel(3));
-        }
-        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+    #[test]
+    fn test_check_consistency_dangling_grants() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let grant = db.grant(&key, 1, 2, key_perm_set![KeyPerm::Use], |_, _| Ok(()))?;
+        assert_eq!(db.check_consistency(true)?, ConsistencyReport::default());
+
+        // The target key of the grant is gone, but the grant is not.
+        db.conn.execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])?;
+        let report = db.check_consistency(false)?;
+        assert_eq!(report.grants_without_key, vec![grant.nspace]);
+        assert_eq!(db.check_consistency(false)?, report);
+
+        // The repair deletes the dangling grant.
+        assert_eq!(db.check_consistency(true)?, report);
+        assert!(db.check_consistency(false)?.grants_without_key.is_empty());
+        let grant_count: i64 =
+            db.conn
+                .query_row("SELECT COUNT(*) FROM persistent.grant;", NO_PARAMS, |row| row.get(0))?;
+        assert_eq!(grant_count, 0);
+
+        // The garbage collector deletes dangling grants too.
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        db.grant(&key, 1, 2, key_perm_set![KeyPerm::Use], |_, _| Ok(()))?;
+        assert_eq!(db.delete_dangling_grants()?, 0);
+        db.conn.execute("DELETE FROM persistent.keyentry WHERE id = ?;", params![key_id])?;
+        assert_eq!(db.delete_dangling_grants()?, 1);
+        assert!(db.check_consistency(false)?.grants_without_key.is_empty());
+        Ok(())
+    }
 
-        let mut metadata = KeyMetaData::new();
-        metadata.add(KeyMetaEntry::CreationDate(DateTime::from_millis_epoch(123456789)));
+    #[test]
+    fn test_get_keys_wrapped_by_super_key() -> Result<()> {
+        const SUPER_KEY_ID: i64 = 7;
+        let mut db = new_test_db()?;
+        let wrap =
+            |db: &mut KeystoreDB, key_id: &KeyIdGuard, super_key_id: i64, version: Option<i32>| {
+                let mut blob_metadata = BlobMetaData::new();
+                blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_id)));
+                if let Some(version) = version {
+                    blob_metadata.add(BlobMetaEntry::SuperKeyVersion(version));
+                }
+                db.set_blob(
+                    key_id,
+                    SubComponentType::KEY_BLOB,
+                    Some(TEST_KEY_BLOB),
+                    Some(&blob_metadata),
+                )
+            };
 
-        KeyEntry {
-            id: key_id,
-            key_blob_info: Some((TEST_KEY_BLOB.to_vec(), blob_metadata)),
-            cert: Some(TEST_CERT_BLOB.to_vec()),
-            cert_chain: Some(TEST_CERT_CHAIN_BLOB.to_vec()),
-            km_uuid: KEYSTORE_UUID,
-            parameters: params,
-            metadata,
-            pure_cert: false,
+        let mut v1_ids = vec![];
+        for nspace in 1..=3 {
+            let key_id = make_test_key_entry(&mut db, Domain::APP, nspace, TEST_ALIAS, None)?;
+            wrap(&mut db, &key_id, SUPER_KEY_ID, Some(1))?;
+            v1_ids.push(key_id.id());
         }
+        let legacy_id = make_test_key_entry(&mut db, Domain::APP, 4, TEST_ALIAS, None)?;
+        wrap(&mut db, &legacy_id, SUPER_KEY_ID, None)?;
+        let legacy_id = legacy_id.id();
+        let other_id = make_test_key_entry(&mut db, Domain::APP, 5, TEST_ALIAS, None)?;
+        wrap(&mut db, &other_id, SUPER_KEY_ID + 1, Some(1))?;
+        let other_id = other_id.id();
+        // Keys that are not super encrypted do not depend on any super key.
+        make_test_key_entry(&mut db, Domain::APP, 6, TEST_ALIAS, None)?;
+
+        assert_eq!(db.get_keys_wrapped_by_super_key(SUPER_KEY_ID, 1)?, v1_ids);
+        assert_eq!(db.get_keys_wrapped_by_super_key(SUPER_KEY_ID, 0)?, vec![legacy_id]);
+        assert_eq!(db.get_keys_wrapped_by_super_key(SUPER_KEY_ID + 1, 1)?, vec![other_id]);
+        assert!(db.get_keys_wrapped_by_super_key(SUPER_KEY_ID, 2)?.is_empty());
+
+        // Only the current key blob counts. Superseded blobs wait for garbage collection.
+        let rewrapped = KEY_ID_LOCK.get(v1_ids[0], KeyIdLockOperation::CreateKeyEntry);
+        wrap(&mut db, &rewrapped, SUPER_KEY_ID, Some(2))?;
+        drop(rewrapped);
+        assert_eq!(db.get_keys_wrapped_by_super_key(SUPER_KEY_ID, 1)?, v1_ids[1..]);
+        assert_eq!(db.get_keys_wrapped_by_super_key(SUPER_KEY_ID, 2)?, vec![v1_ids[0]]);
+
+        // Deleted keys no longer depend on the super key.
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 2,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            2,
+            |_, _| Ok(()),
+        )?;
+        assert_eq!(db.get_keys_wrapped_by_super_key(SUPER_KEY_ID, 1)?, vec![v1_ids[2]]);
+        Ok(())
     }
 
-    fn debug_dump_keyentry_table(db: &mut KeystoreDB) -> Result<()> {
-        let mut stmt = db.conn.prepare(
-            "SELECT id, key_type, domain, namespace, alias, state, km_uuid FROM persistent.keyentry;",
+    #[test]
+    fn test_get_super_key_identity() -> Result<()> {
+        let mut db = new_test_db()?;
+        let mut super_key_ids = vec![];
+        for key_type in [&USER_SUPER_KEY, &USER_SCREEN_LOCK_BOUND_KEY] {
+            let mut blob_metadata = BlobMetaData::new();
+            blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::Password));
+            let super_key = db.store_super_key(
+                1,
+                key_type,
+                TEST_KEY_BLOB,
+                &blob_metadata,
+                &KeyMetaData::new(),
+            )?;
+            // The super keys themselves are protected by the password.
+            assert_eq!(db.get_super_key_identity(super_key.id())?, SuperKeyIdentity::Password);
+            super_key_ids.push(super_key.id());
+        }
+
+        let protect = |db: &mut KeystoreDB, nspace: i64, entries: Vec<BlobMetaEntry>| {
+            let key_id = make_test_key_entry(db, Domain::APP, nspace, TEST_ALIAS, None)?;
+            let mut blob_metadata = BlobMetaData::new();
+            for entry in entries {
+                blob_metadata.add(entry);
+            }
+            db.set_blob(
+                &key_id,
+                SubComponentType::KEY_BLOB,
+                Some(TEST_KEY_BLOB),
+                Some(&blob_metadata),
+            )?;
+            Ok::<_, anyhow::Error>(key_id.id())
+        };
+        let lskf_bound = protect(
+            &mut db,
+            1,
+            vec![
+                BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_ids[0])),
+                BlobMetaEntry::SuperKeyVersion(2),
+            ],
         )?;
-        let rows = stmt.query_map::<(i64, KeyType, i32, i64, String, KeyLifeCycle, Uuid), _, _>(
-            NO_PARAMS,
-            |row| {
-                Ok((
-                    row.get(0)?,
-                    row.get(1)?,
-                    row.get(2)?,
-                    row.get(3)?,
-                    row.get(4)?,
-                    row.get(5)?,
-                    row.get(6)?,
-                ))
-            },
+        let screen_lock_bound = protect(
+            &mut db,
+            2,
+            vec![BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(super_key_ids[1]))],
         )?;
+        let boot_level = protect(&mut db, 3, vec![BlobMetaEntry::MaxBootLevel(30)])?;
+        let unprotected = protect(&mut db, 4, vec![])?;
 
-        println!("Key entry table rows:");
-        for r in rows {
-            let (id, key_type, domain, namespace, alias, state, km_uuid) = r.unwrap();
-            println!(
-                "    id: {} KeyType: {:?} Domain: {} Namespace: {} Alias: {} State: {:?} KmUuid: {:?}",
-                id, key_type, domain, namespace, alias, state, km_uuid
-            );
-        }
+        assert_eq!(
+            db.get_super_key_identity(lskf_bound)?,
+            SuperKeyIdentity::SuperKey {
+                alias: USER_SUPER_KEY.alias.to_string(),
+                key_id: super_key_ids[0],
+                version: 2
+            }
+        );
+        // Blobs without a recorded super key version are protected by version 0.
+        assert_eq!(
+            db.get_super_key_identity(screen_lock_bound)?,
+            SuperKeyIdentity::SuperKey {
+                alias: USER_SCREEN_LOCK_BOUND_KEY.alias.to_string(),
+                key_id: super_key_ids[1],
+                version: 0
+            }
+        );
+        assert_eq!(db.get_super_key_identity(boot_level)?, SuperKeyIdentity::BootLevel(30));
+        assert_eq!(db.get_super_key_identity(unprotected)?, SuperKeyIdentity::None);
+
+        // Only the current key blob counts.
+        let rewrapped = KEY_ID_LOCK.get(unprotected, KeyIdLockOperation::CreateKeyEntry);
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::MaxBootLevel(31));
+        db.set_blob(
+            &rewrapped,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+        drop(rewrapped);
+        assert_eq!(db.get_super_key_identity(unprotected)?, SuperKeyIdentity::BootLevel(31));
+
+        assert_eq!(
+            db.get_super_key_identity(unprotected + 1000)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+        );
         Ok(())
     }
 
-    fn debug_dump_grant_table(db: &mut KeystoreDB) -> Result<()> {
-        let mut stmt = db
-            .conn
-            .prepare("SELECT id, grantee, keyentryid, access_vector FROM persistent.grant;")?;
-        let rows = stmt.query_map::<(i64, i64, i64, i64), _, _>(NO_PARAMS, |row| {
-            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-        })?;
+    #[test]
+    fn test_load_key_km_uuid() -> Result<()> {
+        let mut db = new_test_db()?;
+        let instance_uuid =
+            Uuid::from_keymint_instance(SecurityLevel::TRUSTED_ENVIRONMENT, "other")?;
+        let backends = [
+            ("tee_key", Uuid::from(SecurityLevel::TRUSTED_ENVIRONMENT)),
+            ("strongbox_key", Uuid::from(SecurityLevel::STRONGBOX)),
+            ("instance_key", instance_uuid),
+        ];
+        for (alias, km_uuid) in &backends {
+            let key_id = make_test_key_entry(&mut db, Domain::APP, 1, alias, None)?;
+            let mut blob_metadata = BlobMetaData::new();
+            blob_metadata.add(BlobMetaEntry::KmUuid(*km_uuid));
+            db.set_blob(
+                &key_id,
+                SubComponentType::KEY_BLOB,
+                Some(TEST_KEY_BLOB),
+                Some(&blob_metadata),
+            )?;
+        }
 
-        println!("Grant table rows:");
-        for r in rows {
-            let (id, gt, ki, av) = r.unwrap();
-            println!("    id: {} grantee: {} key_id: {} access_vector: {}", id, gt, ki, av);
+        for (alias, km_uuid) in &backends {
+            let key = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(alias.to_string()),
+                blob: None,
+            };
+            let uuid = db.load_key_km_uuid(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+            assert_eq!(uuid, *km_uuid);
         }
+        assert_eq!(backends[0].1.security_level(), SecurityLevel::TRUSTED_ENVIRONMENT);
+        assert_eq!(backends[1].1.security_level(), SecurityLevel::STRONGBOX);
+        assert_eq!(instance_uuid.security_level(), SecurityLevel::TRUSTED_ENVIRONMENT);
+
+        let missing = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some("missing".to_string()),
+            blob: None,
+        };
+        assert_eq!(
+            db.load_key_km_uuid(&missing, KeyType::Client, 1, |_, _| Ok(()))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_blob_metadata() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::EncryptedBy(EncryptedBy::KeyId(7)));
+        blob_metadata.add(BlobMetaEntry::Iv(vec![0xab, 0x01, 0xff]));
+        blob_metadata.add(BlobMetaEntry::AeadTag(vec![1; 16]));
+        blob_metadata.add(BlobMetaEntry::KmUuid(SecurityLevel::STRONGBOX.into()));
+        blob_metadata.add(BlobMetaEntry::BoundUserId(10));
+        blob_metadata.add(BlobMetaEntry::SuperKeyVersion(2));
+        db.set_blob(
+            &key_id,
+            SubComponentType::KEY_BLOB,
+            Some(TEST_KEY_BLOB),
+            Some(&blob_metadata),
+        )?;
+
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let (_, key_entry) =
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 1, |_, _| Ok(()))?;
+        let (_, metadata) = key_entry.key_blob_info().as_ref().unwrap();
+        assert_eq!(
+            metadata.describe(),
+            vec![
+                "encrypted_by: key 7".to_string(),
+                "km_uuid: 00000000000000000000000000000002".to_string(),
+                format!("security_level: {:?}", SecurityLevel::STRONGBOX),
+                "iv: ab01ff".to_string(),
+                "aead_tag: 16 bytes".to_string(),
+                "bound_user_id: 10".to_string(),
+                "super_key_version: 2".to_string(),
+            ]
+        );
         Ok(())
     }
 
-    // Use a custom random number generator that repeats each number once.
-    // This allows us to test repeated elements.
+    #[test]
+    fn test_custom_metadata() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let get = |db: &mut KeystoreDB, name| {
+            db.get_custom_metadata(&key, KeyType::Client, 1, name, |_, _| Ok(()))
+        };
 
-    thread_local! {
-        static RANDOM_COUNTER: RefCell<i64> = RefCell::new(0);
-    }
+        assert_eq!(get(&mut db, "label")?, None);
+        db.set_custom_metadata(&key, KeyType::Client, 1, "label", b"work", |_, _| Ok(()))?;
+        db.set_custom_metadata(&key, KeyType::Client, 1, "policy", b"7", |_, _| Ok(()))?;
+        assert_eq!(get(&mut db, "label")?, Some(b"work".to_vec()));
+        assert_eq!(get(&mut db, "policy")?, Some(b"7".to_vec()));
 
-    fn reset_random() {
-        RANDOM_COUNTER.with(|counter| {
-            *counter.borrow_mut() = 0;
-        })
+        // Setting an existing entry overwrites it.
+        db.set_custom_metadata(&key, KeyType::Client, 1, "label", b"home", |_, _| Ok(()))?;
+        assert_eq!(get(&mut db, "label")?, Some(b"home".to_vec()));
+
+        assert!(db.delete_custom_metadata(&key, KeyType::Client, 1, "label", |_, _| Ok(()))?);
+        assert!(!db.delete_custom_metadata(&key, KeyType::Client, 1, "label", |_, _| Ok(()))?);
+        assert_eq!(get(&mut db, "label")?, None);
+        assert_eq!(get(&mut db, "policy")?, Some(b"7".to_vec()));
+
+        // Access is denied if the permission check fails.
+        let denied = db.set_custom_metadata(&key, KeyType::Client, 1, "label", b"x", |_, _| {
+            Err(KsError::Rc(ResponseCode::PERMISSION_DENIED)).context("denied")
+        });
+        assert_eq!(
+            denied.unwrap_err().root_cause().downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::PERMISSION_DENIED))
+        );
+        assert_eq!(get(&mut db, "label")?, None);
+
+        // The custom metadata is deleted together with the key.
+        db.unbind_key(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+        let remaining: i64 = db.conn.query_row(
+            "SELECT COUNT(*) FROM persistent.keycustommetadata WHERE keyentryid = ?;",
+            params![key_id],
+            |row| row.get(0),
+        )?;
+        assert_eq!(remaining, 0);
+        Ok(())
     }
 
-    pub fn random() -> i64 {
-        RANDOM_COUNTER.with(|counter| {
-            let result = *counter.borrow() / 2;
-            *counter.borrow_mut() += 1;
-            result
-        })
+    #[test]
+    fn test_custom_metadata_size_cap() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let is_too_much_data = |result: Result<()>| {
+            result.unwrap_err().root_cause().downcast_ref::<KsError>()
+                == Some(&KsError::Rc(ResponseCode::TOO_MUCH_DATA))
+        };
+
+        // The size of the name counts towards the cap.
+        let value = vec![0u8; KeystoreDB::MAX_CUSTOM_METADATA_SIZE - "a".len()];
+        db.set_custom_metadata(&key, KeyType::Client, 1, "a", &value, |_, _| Ok(()))?;
+        assert!(is_too_much_data(db.set_custom_metadata(
+            &key,
+            KeyType::Client,
+            1,
+            "b",
+            b"",
+            |_, _| Ok(())
+        )));
+
+        // Replacing an entry only counts its new size.
+        db.set_custom_metadata(&key, KeyType::Client, 1, "a", b"small", |_, _| Ok(()))?;
+        db.set_custom_metadata(&key, KeyType::Client, 1, "b", b"", |_, _| Ok(()))?;
+        let value = vec![0u8; KeystoreDB::MAX_CUSTOM_METADATA_SIZE];
+        assert!(is_too_much_data(db.set_custom_metadata(
+            &key,
+            KeyType::Client,
+            1,
+            "a",
+            &value,
+            |_, _| Ok(())
+        )));
+        assert_eq!(
+            db.get_custom_metadata(&key, KeyType::Client, 1, "a", |_, _| Ok(()))?,
+            Some(b"small".to_vec())
+        );
+        Ok(())
     }
 
     #[test]
-    fn test_last_off_body() -> Result<()> {
+    fn test_key_exists_for_caller() -> Result<()> {
+        const OWNER: u32 = 1;
+        const GRANTEE: u32 = 2;
         let mut db = new_test_db()?;
-        db.insert_last_off_body(MonotonicRawTime::now());
-        let tx = db.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
-        tx.commit()?;
-        let last_off_body_1 = db.get_last_off_body();
-        let one_second = Duration::from_secs(1);
-        thread::sleep(one_second);
-        db.update_last_off_body(MonotonicRawTime::now());
-        let tx2 = db.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
-        tx2.commit()?;
-        let last_off_body_2 = db.get_last_off_body();
-        assert!(last_off_body_1 < last_off_body_2);
+        make_test_key_entry(&mut db, Domain::APP, OWNER as i64, TEST_ALIAS, None)?;
+        let key = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 0,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+
+        assert!(db.key_exists_for_caller(&key(TEST_ALIAS), KeyType::Client, OWNER, |_, _| Ok(()))?);
+        assert!(!db.key_exists_for_caller(&key("missing"), KeyType::Client, OWNER, |_, _| Ok(()))?);
+        // Domain::APP keys are looked up in the namespace of the caller.
+        assert!(!db.key_exists_for_caller(
+            &key(TEST_ALIAS),
+            KeyType::Client,
+            GRANTEE,
+            |_, _| Ok(())
+        )?);
+
+        // Grants are resolved, and the access vector is passed to the permission check.
+        let granted =
+            db.grant(&key(TEST_ALIAS), OWNER, GRANTEE, key_perm_set![KeyPerm::GetInfo], |_, _| {
+                Ok(())
+            })?;
+        assert!(db.key_exists_for_caller(
+            &granted,
+            KeyType::Client,
+            GRANTEE,
+            |_, access_vector| {
+                assert_eq!(access_vector, Some(key_perm_set![KeyPerm::GetInfo]));
+                Ok(())
+            }
+        )?);
+
+        // The permission check is enforced.
+        let denied = db.key_exists_for_caller(&key(TEST_ALIAS), KeyType::Client, OWNER, |_, _| {
+            Err(KsError::Rc(ResponseCode::PERMISSION_DENIED)).context("denied")
+        });
+        assert_eq!(
+            denied.unwrap_err().root_cause().downcast_ref::<KsError>(),
+            Some(&KsError::Rc(ResponseCode::PERMISSION_DENIED))
+        );
         Ok(())
     }
 
     #[test]
-    fn test_unbind_keys_for_user() -> Result<()> {
+    fn test_list_public_parts_past_alias() -> Result<()> {
         let mut db = new_test_db()?;
-        db.unbind_keys_for_user(1, false)?;
+        let aliases = ["a", "b", "c", "d", "e"];
+        for alias in aliases {
+            make_test_key_entry(&mut db, Domain::APP, 1, alias, None)?;
+        }
+        // The key blobs are not loaded, so even a corrupt key blob does not prevent the export.
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, "f", None)?;
+        db.set_blob(&key_id, SubComponentType::KEY_BLOB, Some(&[][..]), None)?;
+        drop(key_id);
+        // Certificate only entries are public parts as well.
+        db.store_new_certificate(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 1,
+                alias: Some("g".to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            TEST_CERT_CHAIN_BLOB,
+            &KEYSTORE_UUID,
+        )?;
+        // Keys of other namespaces are not exported.
+        make_test_key_entry(&mut db, Domain::APP, 2, "other", None)?;
 
-        make_test_key_entry(&mut db, Domain::APP, 210000, TEST_ALIAS, None)?;
-        make_test_key_entry(&mut db, Domain::APP, 110000, TEST_ALIAS, None)?;
-        db.unbind_keys_for_user(2, false)?;
+        let key_parts = |alias: &str| PublicKeyParts {
+            alias: alias.to_string(),
+            cert: Some(TEST_CERT_BLOB.to_vec()),
+            cert_chain: Some(TEST_CERT_CHAIN_BLOB.to_vec()),
+        };
+        let mut expected: Vec<PublicKeyParts> =
+            aliases.iter().chain(&["f"]).map(|alias| key_parts(alias)).collect();
+        expected.push(PublicKeyParts {
+            alias: "g".to_string(),
+            cert: None,
+            cert_chain: Some(TEST_CERT_CHAIN_BLOB.to_vec()),
+        });
+        assert_eq!(
+            db.list_public_parts_past_alias(Domain::APP, 1, KeyType::Client, None, usize::MAX)?,
+            expected
+        );
 
-        assert_eq!(1, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
-        assert_eq!(0, db.list_past_alias(Domain::APP, 210000, KeyType::Client, None)?.len());
+        // Paging through the keys with room for two keys per page returns every key once.
+        let max_bytes = 2 * key_parts("a").estimated_size();
+        let mut pages = vec![];
+        let mut start_past_alias: Option<String> = None;
+        loop {
+            let page = db.list_public_parts_past_alias(
+                Domain::APP,
+                1,
+                KeyType::Client,
+                start_past_alias.as_deref(),
+                max_bytes,
+            )?;
+            match page.last() {
+                Some(last) => start_past_alias = Some(last.alias.clone()),
+                None => break,
+            }
+            pages.push(page);
+        }
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 2, 2, 1]);
+        assert_eq!(pages.concat(), expected);
 
-        db.unbind_keys_for_user(1, true)?;
-        assert_eq!(0, db.list_past_alias(Domain::APP, 110000, KeyType::Client, None)?.len());
+        // A page holds at least one key, however small the limit.
+        assert_eq!(
+            db.list_public_parts_past_alias(Domain::APP, 1, KeyType::Client, Some("c"), 1)?,
+            vec![key_parts("d")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_namespaces_with_keys() -> Result<()> {
+        let mut db = new_test_db()?;
+        assert!(db.list_namespaces_with_keys()?.is_empty());
+
+        make_test_key_entry(&mut db, Domain::APP, 10001, "a", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 10001, "b", None)?;
+        make_test_key_entry(&mut db, Domain::APP, 10002, "a", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 101, "a", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 101, "b", None)?;
+        make_test_key_entry(&mut db, Domain::SELINUX, 101, "c", None)?;
+        // Unbound keys and keys without an alias are not counted.
+        make_test_key_entry(&mut db, Domain::APP, 10003, "unbound", None)?;
+        db.unbind_key(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 10003,
+                alias: Some("unbound".to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            10003,
+            |_, _| Ok(()),
+        )?;
+        db.create_key_entry(&Domain::APP, &10004, KeyType::Client, &KEYSTORE_UUID)?;
 
+        let count =
+            |domain, namespace, key_count| NamespaceKeyCount { domain, namespace, key_count };
+        assert_eq!(
+            db.list_namespaces_with_keys()?,
+            vec![
+                count(Domain::APP, 10001, 2),
+                count(Domain::APP, 10002, 1),
+                count(Domain::SELINUX, 101, 3)
+            ]
+        );
         Ok(())
     }
 
@@ -5870,6 +11949,124 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_cross_user_key_access_is_denied() -> Result<()> {
+        const OWNER_UID: u32 = 10001;
+        const OTHER_USER_UID: u32 = AID_USER_OFFSET + 10001;
+        let mut db = new_test_db()?;
+        let key_id =
+            make_test_key_entry(&mut db, Domain::APP, OWNER_UID as i64, TEST_ALIAS, None)?.id();
+        let by_alias = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: OWNER_UID as i64,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let by_id = KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() };
+        // Only the user partition is checked on loading, like the assertion of
+        // `check_key_permission` in debug builds.
+        let load = |db: &mut KeystoreDB, key: &KeyDescriptor, caller_uid| {
+            db.load_key_entry(key, KeyType::Client, KeyEntryLoadBits::NONE, caller_uid, |k, av| {
+                crate::utils::check_user_partition(k, av, caller_uid)
+            })
+            .map(|_| ())
+        };
+        let fails_with = |result: Result<()>, error: KsError| {
+            result.unwrap_err().root_cause().downcast_ref::<KsError>() == Some(&error)
+        };
+
+        load(&mut db, &by_alias, OWNER_UID)?;
+        load(&mut db, &by_id, OWNER_UID)?;
+        // The alias resolves to the namespace of the caller, so the other user cannot address
+        // the key by alias at all.
+        assert!(fails_with(
+            load(&mut db, &by_alias, OTHER_USER_UID),
+            KsError::Rc(ResponseCode::KEY_NOT_FOUND)
+        ));
+        // The key id resolves to the key of the owner, which is denied.
+        assert!(fails_with(load(&mut db, &by_id, OTHER_USER_UID), KsError::perm()));
+
+        // A grant of the owner gives the other user access.
+        let granted_key =
+            db.grant(&by_alias, OWNER_UID, OTHER_USER_UID, key_perm_set![KeyPerm::Use], |_, _| {
+                Ok(())
+            })?;
+        load(&mut db, &granted_key, OTHER_USER_UID)?;
+        load(&mut db, &by_id, OTHER_USER_UID)?;
+
+        // Revoking the grant restores the partition.
+        db.ungrant(&by_alias, OWNER_UID, OTHER_USER_UID, |_| Ok(()))?;
+        assert!(fails_with(load(&mut db, &by_id, OTHER_USER_UID), KsError::perm()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_handles() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_id = make_test_key_entry(&mut db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(TEST_ALIAS.to_string()),
+            blob: None,
+        };
+        let is_not_found = |result: Result<(KeyIdGuard, KeyEntry)>| {
+            result.unwrap_err().root_cause().downcast_ref::<KsError>()
+                == Some(&KsError::Rc(ResponseCode::KEY_NOT_FOUND))
+        };
+
+        // A denied permission check issues no handle.
+        assert!(db
+            .resolve_key_handle(&key, KeyType::Client, 1, |_, _| Err(KsError::perm().into()))
+            .is_err());
+
+        // The handle loads the key entry repeatedly, but only for the caller it was issued to.
+        let handle = db.resolve_key_handle(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+        for _ in 0..3 {
+            let (key_guard, key_entry) =
+                db.load_key_entry_by_handle(handle, KeyType::Client, KeyEntryLoadBits::BOTH, 1)?;
+            assert_eq!(key_guard.id(), key_id);
+            assert_eq!(key_entry, make_test_key_entry_test_vector(key_id, None));
+        }
+        assert!(is_not_found(db.load_key_entry_by_handle(
+            handle,
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            2
+        )));
+
+        // Changing the grants of the key invalidates its handles.
+        let granted_key = db.grant(&key, 1, 2, key_perm_set![KeyPerm::Use], |_, _| Ok(()))?;
+        assert!(is_not_found(db.load_key_entry_by_handle(
+            handle,
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            1
+        )));
+        let grantee_handle =
+            db.resolve_key_handle(&granted_key, KeyType::Client, 2, |_, _| Ok(()))?;
+        db.load_key_entry_by_handle(grantee_handle, KeyType::Client, KeyEntryLoadBits::NONE, 2)?;
+        db.ungrant(&key, 1, 2, |_| Ok(()))?;
+        assert!(is_not_found(db.load_key_entry_by_handle(
+            grantee_handle,
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            2
+        )));
+
+        // Deleting the key invalidates its handles.
+        let handle = db.resolve_key_handle(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+        db.load_key_entry_by_handle(handle, KeyType::Client, KeyEntryLoadBits::NONE, 1)?;
+        db.unbind_key(&key, KeyType::Client, 1, |_, _| Ok(()))?;
+        assert!(is_not_found(db.load_key_entry_by_handle(
+            handle,
+            KeyType::Client,
+            KeyEntryLoadBits::NONE,
+            1
+        )));
+        Ok(())
+    }
+
     #[test]
     fn test_load_key_descriptor() -> Result<()> {
         let mut db = new_test_db()?;
@@ -5885,4 +12082,208 @@ pub mod tests {
         assert_eq!(db.load_key_descriptor(key_id + 1)?, None);
         Ok(())
     }
+
+    /// Loads the key entry with `alias`. The id is zeroed, because imports assign new ids.
+    fn load_backup_test_key(db: &mut KeystoreDB, alias: &str, uid: u32) -> Result<KeyEntry> {
+        let (_, entry) = db.load_key_entry(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(alias.to_string()),
+                blob: None,
+            },
+            KeyType::Client,
+            KeyEntryLoadBits::BOTH,
+            uid,
+            |_k, _av| Ok(()),
+        )?;
+        Ok(KeyEntry { id: 0, ..entry })
+    }
+
+    fn make_backup_test_key_set(db: &mut KeystoreDB) -> Result<(i64, i64, i64)> {
+        let first = make_test_key_entry(db, Domain::APP, 1, TEST_ALIAS, None)?.id();
+        let second = make_test_key_entry(db, Domain::APP, 2, "second key", Some(3))?.id();
+        // A certificate only entry.
+        let cert_only = db
+            .store_new_certificate(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 1,
+                    alias: Some("cert".to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                b"a certificate",
+                &KEYSTORE_UUID,
+            )?
+            .id();
+        db.grant(
+            &KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 0,
+                alias: Some(TEST_ALIAS.to_string()),
+                blob: None,
+            },
+            1,
+            3,
+            key_perm_set![KeyPerm::Use],
+            |_k, _av| Ok(()),
+        )?;
+        Ok((first, second, cert_only))
+    }
+
+    #[test]
+    fn test_backup_round_trip() -> Result<()> {
+        let mut db = new_test_db()?;
+        let (first, second, cert_only) = make_backup_test_key_set(&mut db)?;
+        // Superseded blobs are not exported.
+        db.set_blob(
+            &KEY_ID_LOCK.get(second, KeyIdLockOperation::CreateKeyEntry),
+            SubComponentType::KEY_BLOB,
+            Some(&b"new blob"[..]),
+            None,
+        )?;
+        // Super keys are not exported.
+        db.store_super_key(
+            1,
+            &USER_SUPER_KEY,
+            TEST_KEY_BLOB,
+            &BlobMetaData::new(),
+            &KeyMetaData::new(),
+        )?;
+
+        let pw: keystore2_crypto::Password = (&b"backup passphrase"[..]).into();
+        let (archive, manifest) = db.export_backup(&pw)?;
+        let mut expected_manifest = vec![
+            BackupEntryInfo { key_id: first, hardware_bound: true },
+            BackupEntryInfo { key_id: second, hardware_bound: true },
+            BackupEntryInfo { key_id: cert_only, hardware_bound: false },
+        ];
+        expected_manifest.sort_by_key(|e| e.key_id);
+        assert_eq!(manifest, expected_manifest);
+
+        let mut new_db = new_test_db()?;
+        // The archive ids are in use by unrelated entries of the target database.
+        for (i, key_id) in [first, second, cert_only].into_iter().enumerate() {
+            new_db.conn.execute(
+                "INSERT INTO persistent.keyentry (id, key_type, domain, namespace, alias, state)
+                 VALUES (?, ?, ?, ?, ?, ?);",
+                params![
+                    key_id,
+                    KeyType::Client,
+                    Domain::APP.0 as u32,
+                    5,
+                    format!("unrelated {}", i),
+                    KeyLifeCycle::Live
+                ],
+            )?;
+        }
+        let imported = new_db.import_backup(&archive, &pw, &|_, _, _, _, _, _| Ok(None))?;
+        assert_eq!(
+            imported.iter().map(|e| e.hardware_bound).collect::<Vec<_>>(),
+            expected_manifest.iter().map(|e| e.hardware_bound).collect::<Vec<_>>()
+        );
+        for entry in &imported {
+            assert!(![first, second, cert_only].contains(&entry.key_id));
+            assert!(new_db.load_key_descriptor(entry.key_id)?.is_some());
+        }
+
+        assert_eq!(
+            load_backup_test_key(&mut new_db, TEST_ALIAS, 1)?,
+            load_backup_test_key(&mut db, TEST_ALIAS, 1)?
+        );
+        assert_eq!(
+            load_backup_test_key(&mut new_db, "second key", 2)?,
+            load_backup_test_key(&mut db, "second key", 2)?
+        );
+        assert_eq!(
+            load_backup_test_key(&mut new_db, "cert", 1)?,
+            load_backup_test_key(&mut db, "cert", 1)?
+        );
+        assert_eq!(
+            load_backup_test_key(&mut new_db, "second key", 2)?
+                .key_blob_info()
+                .as_ref()
+                .map(|(b, _)| b.clone()),
+            Some(b"new blob".to_vec())
+        );
+        // The grant was restored and refers to the imported entry.
+        let grant_key_ids: Vec<i64> = new_db
+            .conn
+            .prepare("SELECT keyentryid FROM persistent.grant;")?
+            .query_map(NO_PARAMS, |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let imported_first = new_db
+            .load_key_entry(
+                &KeyDescriptor {
+                    domain: Domain::APP,
+                    nspace: 0,
+                    alias: Some(TEST_ALIAS.to_string()),
+                    blob: None,
+                },
+                KeyType::Client,
+                KeyEntryLoadBits::NONE,
+                1,
+                |_k, _av| Ok(()),
+            )?
+            .0
+            .id();
+        assert_eq!(grant_key_ids, vec![imported_first]);
+        // The unrelated entries are untouched.
+        assert!(new_db.key_exists(Domain::APP, 5, "unrelated 0", KeyType::Client)?);
+        let super_keys: i64 = new_db.conn.query_row(
+            "SELECT COUNT(*) FROM persistent.keyentry WHERE key_type = ?;",
+            params![KeyType::Super],
+            |row| row.get(0),
+        )?;
+        assert_eq!(super_keys, 0);
+
+        // Importing the same archive again conflicts with the imported entries.
+        check_backup_import_error(
+            new_db.import_backup(&archive, &pw, &|_, _, _, _, _, _| Ok(None)),
+            ResponseCode::INVALID_ARGUMENT,
+        );
+        Ok(())
+    }
+
+    fn check_backup_import_error<T: std::fmt::Debug>(result: Result<T>, rc: ResponseCode) {
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<KsError>(),
+            Some(&KsError::Rc(rc))
+        );
+    }
+
+    #[test]
+    fn test_backup_integrity() -> Result<()> {
+        let mut db = new_test_db()?;
+        make_backup_test_key_set(&mut db)?;
+        let pw: keystore2_crypto::Password = (&b"backup passphrase"[..]).into();
+        let (archive, _) = db.export_backup(&pw)?;
+
+        let mut new_db = new_test_db()?;
+        let wrong_pw: keystore2_crypto::Password = (&b"wrong passphrase"[..]).into();
+        check_backup_import_error(
+            new_db.import_backup(&archive, &wrong_pw, &|_, _, _, _, _, _| Ok(None)),
+            ResponseCode::VALUE_CORRUPTED,
+        );
+
+        let mut tampered = archive.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        check_backup_import_error(
+            new_db.import_backup(&tampered, &pw, &|_, _, _, _, _, _| Ok(None)),
+            ResponseCode::VALUE_CORRUPTED,
+        );
+
+        check_backup_import_error(
+            new_db.import_backup(&archive[..20], &pw, &|_, _, _, _, _, _| Ok(None)),
+            ResponseCode::VALUE_CORRUPTED,
+        );
+
+        // Nothing was written by the failed imports.
+        assert!(!new_db.key_exists(Domain::APP, 1, TEST_ALIAS, KeyType::Client)?);
+        new_db.import_backup(&archive, &pw, &|_, _, _, _, _, _| Ok(None))?;
+        assert!(new_db.key_exists(Domain::APP, 1, TEST_ALIAS, KeyType::Client)?);
+        Ok(())
+    }
 }
@@ -0,0 +1,577 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the export of the key database into an encrypted archive and the
+//! import of such an archive.
+//!
+//! The archive contains all live key entries together with their current blobs, blob metadata,
+//! key parameters, key metadata, and grants. It is encrypted with AES-256-GCM under a key
+//! derived from a caller supplied passphrase, so it is integrity protected as a whole, and the
+//! import decrypts and parses the entire archive before anything is written to the database.
+//!
+//! Note that the key blobs of most entries are bound to the KeyMint instance that created
+//! them. Exporting them preserves the entry, its certificates and metadata, but the key
+//! material cannot be used on different hardware. Such entries are marked as hardware bound in
+//! the archive manifest and in the result of the import, so that the caller can arrange for
+//! them to be recreated. Entries without a key blob, i.e., certificate only entries, are fully
+//! portable.
+//!
+//! Imported entries get new key ids, because the ids of the archive may be in use by other
+//! entries of the database, so the key ids in the result of the import differ from those in the
+//! archive manifest. Grants get new ids as well. An import fails as a whole if an entry of the
+//! archive has the alias of an existing entry.
+//!
+//! Super keys are not exported. They are specific to the device and the user, and the importing
+//! device has super keys of its own. Super encrypted blobs are exported as they are, so they
+//! remain encrypted with the super keys of the exporting device. Blobs that are bound to their
+//! key entry are rewrapped for the new key id of their entry during the import, which requires
+//! the super key to be in memory, see `SuperKeyManager::rewrap_imported_blob`. Blobs that cannot
+//! be rewrapped, e.g., because the archive was exported on another device, are imported as
+//! they are and cannot be used, like the key blobs of hardware bound entries.
+//!
+//! Archive layout: `MAGIC | salt | iv | aead tag | ciphertext`. The plaintext starts with
+//! `MAGIC` and the format version followed by the manifest and the rows of each table.
+
+use super::{BlobMetaData, KeyLifeCycle, KeyType, KeystoreDB, SubComponentType};
+use crate::error::{Error as KsError, ResponseCode};
+use crate::ks_err;
+use crate::utils::watchdog as wd;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{
+    aes_gcm_decrypt, aes_gcm_encrypt, generate_salt, Password, ZVec, AES_256_KEY_LENGTH,
+    GCM_IV_LENGTH, SALT_LENGTH, TAG_LENGTH,
+};
+use rusqlite::{params, params_from_iter, types::Value, Transaction, TransactionBehavior};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const MAGIC: &[u8; 8] = b"KS2BKUP\0";
+const FORMAT_VERSION: u32 = 1;
+
+/// Selects the exported rows of each table. All queries are restricted to live key entries other
+/// than super keys and, for blobs, to the most recent blob of each subcomponent type.
+const KEYENTRY_QUERY: &str =
+    "SELECT id, key_type, domain, namespace, alias, km_uuid FROM persistent.keyentry
+     WHERE state = ?1 AND key_type != ?2 ORDER BY id;";
+const BLOBENTRY_QUERY: &str =
+    "SELECT id, subcomponent_type, keyentryid, blob FROM persistent.blobentry
+     WHERE id IN (
+         SELECT MAX(id) FROM persistent.blobentry GROUP BY keyentryid, subcomponent_type
+     ) AND keyentryid IN (
+         SELECT id FROM persistent.keyentry WHERE state = ?1 AND key_type != ?2
+     ) ORDER BY id;";
+const BLOBMETADATA_QUERY: &str = "SELECT blobentryid, tag, data FROM persistent.blobmetadata
+     WHERE blobentryid IN (
+         SELECT MAX(id) FROM persistent.blobentry GROUP BY keyentryid, subcomponent_type
+     ) AND blobentryid IN (
+         SELECT id FROM persistent.blobentry WHERE keyentryid IN (
+             SELECT id FROM persistent.keyentry WHERE state = ?1 AND key_type != ?2
+         )
+     ) ORDER BY blobentryid, tag;";
+const KEYPARAMETER_QUERY: &str =
+    "SELECT keyentryid, tag, data, security_level FROM persistent.keyparameter
+     WHERE keyentryid IN (SELECT id FROM persistent.keyentry WHERE state = ?1 AND key_type != ?2)
+     ORDER BY keyentryid, tag;";
+const KEYMETADATA_QUERY: &str = "SELECT keyentryid, tag, data FROM persistent.keymetadata
+     WHERE keyentryid IN (SELECT id FROM persistent.keyentry WHERE state = ?1 AND key_type != ?2)
+     ORDER BY keyentryid, tag;";
+const GRANT_QUERY: &str = "SELECT id, grantee, keyentryid, access_vector FROM persistent.grant
+     WHERE keyentryid IN (SELECT id FROM persistent.keyentry WHERE state = ?1 AND key_type != ?2)
+     ORDER BY id;";
+
+/// Rewraps a blob of an imported key entry that is bound to its key entry, see
+/// `KeystoreDB::import_backup`. It is given the owner of the entry, the key id of the entry in
+/// the archive, the new key id of the entry, the subcomponent type of the blob, the blob, and
+/// its metadata. Returns the rewrapped blob and its metadata, or None if the blob is imported as
+/// is.
+pub type RewrapBlob<'a> = dyn Fn(
+        &KeyDescriptor,
+        i64,
+        i64,
+        SubComponentType,
+        &[u8],
+        &BlobMetaData,
+    ) -> Result<Option<(Vec<u8>, BlobMetaData)>>
+    + 'a;
+
+/// Describes one key entry contained in a backup archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupEntryInfo {
+    /// The key id of the entry.
+    pub key_id: i64,
+    /// True if the entry's key blob is bound to the KeyMint instance that created it and cannot
+    /// be used on other hardware.
+    pub hardware_bound: bool,
+}
+
+/// The content of a backup archive.
+#[derive(Debug, PartialEq)]
+struct Archive {
+    manifest: Vec<BackupEntryInfo>,
+    keyentry: Vec<Vec<Value>>,
+    blobentry: Vec<Vec<Value>>,
+    blobmetadata: Vec<Vec<Value>>,
+    keyparameter: Vec<Vec<Value>>,
+    keymetadata: Vec<Vec<Value>>,
+    grant: Vec<Vec<Value>>,
+}
+
+fn corrupted() -> KsError {
+    KsError::Rc(ResponseCode::VALUE_CORRUPTED)
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, v: &[u8]) -> Result<()> {
+    write_u32(out, v.len().try_into().context(ks_err!("Value too large."))?);
+    out.extend_from_slice(v);
+    Ok(())
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) -> Result<()> {
+    match value {
+        Value::Null => out.push(0),
+        Value::Integer(i) => {
+            out.push(1);
+            out.extend_from_slice(&i.to_be_bytes());
+        }
+        Value::Real(r) => {
+            out.push(2);
+            out.extend_from_slice(&r.to_bits().to_be_bytes());
+        }
+        Value::Text(t) => {
+            out.push(3);
+            write_bytes(out, t.as_bytes())?;
+        }
+        Value::Blob(b) => {
+            out.push(4);
+            write_bytes(out, b)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_rows(out: &mut Vec<u8>, rows: &[Vec<Value>]) -> Result<()> {
+    write_u32(out, rows.len().try_into().context(ks_err!("Too many rows."))?);
+    for row in rows {
+        write_u32(out, row.len().try_into().context(ks_err!("Too many columns."))?);
+        for value in row {
+            write_value(out, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Cursor over the plaintext of an archive. All reads fail with `ResponseCode::VALUE_CORRUPTED`
+/// if the input is truncated.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(corrupted()).context(ks_err!("Archive truncated."));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    fn read_value(&mut self) -> Result<Value> {
+        Ok(match self.read_u8()? {
+            0 => Value::Null,
+            1 => Value::Integer(self.read_u64()? as i64),
+            2 => Value::Real(f64::from_bits(self.read_u64()?)),
+            3 => Value::Text(
+                String::from_utf8(self.read_bytes()?.to_vec())
+                    .map_err(|_| corrupted())
+                    .context(ks_err!("Invalid text value."))?,
+            ),
+            4 => Value::Blob(self.read_bytes()?.to_vec()),
+            t => return Err(corrupted()).context(ks_err!("Unknown value type {}.", t)),
+        })
+    }
+
+    /// Reads a table with exactly `columns` columns per row.
+    fn read_rows(&mut self, columns: usize) -> Result<Vec<Vec<Value>>> {
+        let count = self.read_u32()?;
+        let mut rows = Vec::new();
+        for _ in 0..count {
+            if self.read_u32()? as usize != columns {
+                return Err(corrupted()).context(ks_err!("Unexpected number of columns."));
+            }
+            rows.push((0..columns).map(|_| self.read_value()).collect::<Result<Vec<Value>>>()?);
+        }
+        Ok(rows)
+    }
+}
+
+impl Archive {
+    fn serialize(&self) -> Result<Vec<u8>> {
+        let mut out = MAGIC.to_vec();
+        write_u32(&mut out, FORMAT_VERSION);
+        write_u32(&mut out, self.manifest.len().try_into().context(ks_err!("Too many keys."))?);
+        for entry in &self.manifest {
+            out.extend_from_slice(&entry.key_id.to_be_bytes());
+            out.push(entry.hardware_bound as u8);
+        }
+        for rows in [
+            &self.keyentry,
+            &self.blobentry,
+            &self.blobmetadata,
+            &self.keyparameter,
+            &self.keymetadata,
+            &self.grant,
+        ] {
+            write_rows(&mut out, rows)?;
+        }
+        Ok(out)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut r = Reader(data);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(corrupted()).context(ks_err!("Not a keystore backup."));
+        }
+        let version = r.read_u32()?;
+        if version != FORMAT_VERSION {
+            return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Unsupported backup format version {}.", version));
+        }
+        let manifest = (0..r.read_u32()?)
+            .map(|_| {
+                Ok(BackupEntryInfo {
+                    key_id: r.read_u64()? as i64,
+                    hardware_bound: r.read_u8()? != 0,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let archive = Archive {
+            manifest,
+            keyentry: r.read_rows(6)?,
+            blobentry: r.read_rows(4)?,
+            blobmetadata: r.read_rows(3)?,
+            keyparameter: r.read_rows(4)?,
+            keymetadata: r.read_rows(3)?,
+            grant: r.read_rows(4)?,
+        };
+        if !r.0.is_empty() {
+            return Err(corrupted()).context(ks_err!("Trailing data in archive."));
+        }
+        Ok(archive)
+    }
+}
+
+fn query_rows(tx: &Transaction, query: &str) -> Result<Vec<Vec<Value>>> {
+    let mut stmt = tx.prepare(query).context(ks_err!("Failed to prepare statement."))?;
+    let columns = stmt.column_count();
+    let rows = stmt
+        .query_map(params![KeyLifeCycle::Live, KeyType::Super], |row| {
+            (0..columns).map(|i| row.get::<_, Value>(i)).collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .context(ks_err!("Failed to query rows."))?;
+    rows.collect::<rusqlite::Result<Vec<_>>>().context(ks_err!("Failed to extract rows."))
+}
+
+fn as_i64(value: &Value) -> Result<i64> {
+    match value {
+        Value::Integer(i) => Ok(*i),
+        _ => Err(corrupted()).context(ks_err!("Expected integer, found {:?}.", value)),
+    }
+}
+
+fn derive_backup_key(pw: &Password, salt: &[u8]) -> Result<ZVec> {
+    pw.derive_key(salt, AES_256_KEY_LENGTH).context(ks_err!("Failed to derive backup key."))
+}
+
+impl KeystoreDB {
+    fn read_archive(tx: &Transaction) -> Result<Archive> {
+        let manifest = {
+            let mut stmt = tx
+                .prepare(
+                    "SELECT id, id IN (
+                         SELECT keyentryid FROM persistent.blobentry WHERE subcomponent_type = ?3
+                     ) FROM persistent.keyentry WHERE state = ?1 AND key_type != ?2 ORDER BY id;",
+                )
+                .context(ks_err!("Failed to prepare manifest statement."))?;
+            let rows = stmt
+                .query_map(
+                    params![KeyLifeCycle::Live, KeyType::Super, SubComponentType::KEY_BLOB],
+                    |row| Ok(BackupEntryInfo { key_id: row.get(0)?, hardware_bound: row.get(1)? }),
+                )
+                .context(ks_err!("Failed to query manifest."))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+                .context(ks_err!("Failed to extract manifest."))?
+        };
+        Ok(Archive {
+            manifest,
+            keyentry: query_rows(tx, KEYENTRY_QUERY)?,
+            blobentry: query_rows(tx, BLOBENTRY_QUERY)?,
+            blobmetadata: query_rows(tx, BLOBMETADATA_QUERY)?,
+            keyparameter: query_rows(tx, KEYPARAMETER_QUERY)?,
+            keymetadata: query_rows(tx, KEYMETADATA_QUERY)?,
+            grant: query_rows(tx, GRANT_QUERY)?,
+        })
+    }
+
+    /// Writes the entries of `archive` with new key ids, rewrapping bound blobs with `rewrap`.
+    /// Returns the new key ids by the key ids of the archive.
+    fn write_archive(
+        tx: &Transaction,
+        archive: &Archive,
+        rewrap: &RewrapBlob,
+    ) -> Result<HashMap<i64, i64>> {
+        let mut key_ids: HashMap<i64, i64> = HashMap::new();
+        for row in &archive.keyentry {
+            let conflicts: i64 = tx
+                .query_row(
+                    "SELECT COUNT(*) FROM persistent.keyentry
+                     WHERE key_type = ?1 AND domain = ?2 AND namespace = ?3 AND alias = ?4
+                     AND state = ?5;",
+                    params![row[1], row[2], row[3], row[4], KeyLifeCycle::Live],
+                    |row| row.get(0),
+                )
+                .context(ks_err!("Failed to check for conflicting key entries."))?;
+            if conflicts != 0 {
+                return Err(KsError::Rc(ResponseCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Key entry {:?} conflicts with an existing entry.", row[0]));
+            }
+            let key_id = Self::insert_with_retry(|id| {
+                tx.execute(
+                    "INSERT INTO persistent.keyentry
+                     (id, key_type, domain, namespace, alias, km_uuid, state)
+                     VALUES (?, ?, ?, ?, ?, ?, ?);",
+                    params![id, row[1], row[2], row[3], row[4], row[5], KeyLifeCycle::Live],
+                )
+            })
+            .context(ks_err!("Failed to insert key entry."))?;
+            Self::audit_key_id_allocation(tx, key_id).context(ks_err!())?;
+            if key_ids.insert(as_i64(&row[0])?, key_id).is_some() {
+                return Err(corrupted()).context(ks_err!("Duplicate key entry {:?}.", row[0]));
+            }
+        }
+        let key_id = |value: &Value| -> Result<i64> {
+            key_ids
+                .get(&as_i64(value)?)
+                .copied()
+                .ok_or_else(corrupted)
+                .context(ks_err!("Row of unknown key entry {:?}.", value))
+        };
+
+        // Blob entry ids are allocated by SQLite, so the metadata has to be remapped.
+        let mut blob_ids: HashMap<i64, i64> = HashMap::new();
+        for row in &archive.blobentry {
+            tx.execute(
+                "INSERT INTO persistent.blobentry (subcomponent_type, keyentryid, blob)
+                 VALUES (?, ?, ?);",
+                params![row[1], key_id(&row[2])?, row[3]],
+            )
+            .context(ks_err!("Failed to insert blob entry."))?;
+            blob_ids.insert(as_i64(&row[0])?, tx.last_insert_rowid());
+        }
+        for row in &archive.blobmetadata {
+            let blob_id = blob_ids
+                .get(&as_i64(&row[0])?)
+                .ok_or_else(corrupted)
+                .context(ks_err!("Blob metadata without blob."))?;
+            tx.execute(
+                "INSERT INTO persistent.blobmetadata (blobentryid, tag, data) VALUES (?, ?, ?);",
+                params![blob_id, row[1], row[2]],
+            )
+            .context(ks_err!("Failed to insert blob metadata."))?;
+        }
+        for row in &archive.blobentry {
+            Self::rewrap_imported_blob(tx, row, &blob_ids, &key_id, rewrap).context(ks_err!())?;
+        }
+
+        for (table, rows) in [
+            (
+                "persistent.keyparameter (keyentryid, tag, data, security_level)",
+                &archive.keyparameter,
+            ),
+            ("persistent.keymetadata (keyentryid, tag, data)", &archive.keymetadata),
+        ] {
+            for row in rows {
+                let mut row = row.clone();
+                row[0] = Value::Integer(key_id(&row[0])?);
+                let placeholders = vec!["?"; row.len()].join(", ");
+                tx.execute(
+                    &format!("INSERT INTO {} VALUES ({});", table, placeholders),
+                    params_from_iter(row.iter()),
+                )
+                .with_context(|| ks_err!("Failed to insert into {}.", table))?;
+            }
+        }
+
+        for row in &archive.grant {
+            let keyentryid = key_id(&row[2])?;
+            Self::insert_with_retry(|id| {
+                tx.execute(
+                    "INSERT INTO persistent.grant (id, grantee, keyentryid, access_vector)
+                     VALUES (?, ?, ?, ?);",
+                    params![id, row[1], keyentryid, row[3]],
+                )
+            })
+            .context(ks_err!("Failed to insert grant."))?;
+        }
+        Ok(key_ids)
+    }
+
+    /// Super encrypted blobs that are bound to their key entry are bound to the key id of the
+    /// entry in the archive. Rewraps such a blob of the imported blob entry `row` for the new
+    /// key id of its entry with `rewrap`.
+    fn rewrap_imported_blob(
+        tx: &Transaction,
+        row: &[Value],
+        blob_ids: &HashMap<i64, i64>,
+        key_id: &dyn Fn(&Value) -> Result<i64>,
+        rewrap: &RewrapBlob,
+    ) -> Result<()> {
+        let blob_id = *blob_ids.get(&as_i64(&row[0])?).ok_or_else(corrupted).context(ks_err!())?;
+        let metadata = BlobMetaData::load_from_db(blob_id, tx).context(ks_err!())?;
+        if metadata.bound_user_id().is_none() {
+            return Ok(());
+        }
+        let sc_type = SubComponentType(
+            as_i64(&row[1])?.try_into().map_err(|_| corrupted()).context(ks_err!())?,
+        );
+        let blob = match &row[3] {
+            Value::Blob(blob) => blob,
+            _ => return Err(corrupted()).context(ks_err!("Expected blob, found {:?}.", row[3])),
+        };
+        let new_key_id = key_id(&row[2])?;
+        let owner = tx
+            .query_row(
+                "SELECT domain, namespace, alias FROM persistent.keyentry WHERE id = ?;",
+                params![new_key_id],
+                |row| {
+                    Ok(KeyDescriptor {
+                        domain: Domain(row.get(0)?),
+                        nspace: row.get(1)?,
+                        alias: row.get(2)?,
+                        blob: None,
+                    })
+                },
+            )
+            .context(ks_err!("Failed to load the owner of key entry {}.", new_key_id))?;
+        let (blob, metadata) =
+            match rewrap(&owner, as_i64(&row[2])?, new_key_id, sc_type, blob, &metadata)
+                .context(ks_err!("Failed to rewrap blob of key entry {}.", new_key_id))?
+            {
+                Some(rewrapped) => rewrapped,
+                None => return Ok(()),
+            };
+        tx.execute(
+            "UPDATE persistent.blobentry SET blob = ? WHERE id = ?;",
+            params![blob, blob_id],
+        )
+        .context(ks_err!("Failed to update blob."))?;
+        tx.execute("DELETE FROM persistent.blobmetadata WHERE blobentryid = ?;", params![blob_id])
+            .context(ks_err!("Failed to delete blob metadata."))?;
+        metadata.store_in_db(blob_id, tx).context(ks_err!("Failed to store blob metadata."))
+    }
+
+    /// Exports all live key entries into an archive encrypted with a key derived from `pw`.
+    /// Returns the archive and the list of exported entries. See the module documentation for
+    /// the meaning of `BackupEntryInfo::hardware_bound`.
+    pub fn export_backup(&mut self, pw: &Password) -> Result<(Vec<u8>, Vec<BackupEntryInfo>)> {
+        let _wp = wd::watch_millis("KeystoreDB::export_backup", 500);
+
+        let archive = self
+            .with_transaction(TransactionBehavior::Deferred, |tx| Self::read_archive(tx).no_gc())
+            .context(ks_err!())?;
+        let plaintext = ZVec::try_from(archive.serialize()?)
+            .context(ks_err!("Failed to allocate plaintext."))?;
+
+        let salt = generate_salt().context(ks_err!("Failed to generate salt."))?;
+        let key = derive_backup_key(pw, &salt)?;
+        let (ciphertext, iv, tag) =
+            aes_gcm_encrypt(&plaintext, &key).context(ks_err!("Failed to encrypt archive."))?;
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&salt);
+        out.extend_from_slice(&iv);
+        out.extend_from_slice(&tag);
+        out.extend_from_slice(&ciphertext);
+        Ok((out, archive.manifest))
+    }
+
+    /// Imports an archive created by `export_backup`. The archive is decrypted, authenticated,
+    /// and parsed completely before the entries are inserted in a single transaction. The import
+    /// fails without modifying the database if the passphrase is wrong, if the archive was
+    /// tampered with (`ResponseCode::VALUE_CORRUPTED`), or if any entry conflicts with the
+    /// alias of an existing entry (`ResponseCode::INVALID_ARGUMENT`). Blobs that are bound to
+    /// their key entry are rewrapped for the new key id of their entry with `rewrap` in the same
+    /// transaction, see the module documentation.
+    /// Returns the list of imported entries with their new key ids.
+    pub fn import_backup(
+        &mut self,
+        data: &[u8],
+        pw: &Password,
+        rewrap: &RewrapBlob,
+    ) -> Result<Vec<BackupEntryInfo>> {
+        let _wp = wd::watch_millis("KeystoreDB::import_backup", 500);
+
+        let mut r = Reader(data);
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(corrupted()).context(ks_err!("Not a keystore backup."));
+        }
+        let salt = r.take(SALT_LENGTH)?;
+        let iv = r.take(GCM_IV_LENGTH)?;
+        let tag = r.take(TAG_LENGTH)?;
+        let key = derive_backup_key(pw, salt)?;
+        let plaintext = aes_gcm_decrypt(r.0, iv, tag, &key)
+            .map_err(|_| corrupted())
+            .context(ks_err!("Failed to decrypt archive. Wrong passphrase or corrupted."))?;
+        let archive = Archive::deserialize(&plaintext).context(ks_err!())?;
+
+        let key_ids = self
+            .with_transaction(TransactionBehavior::Immediate, |tx| {
+                Self::write_archive(tx, &archive, rewrap).no_gc()
+            })
+            .context(ks_err!())?;
+        archive
+            .manifest
+            .iter()
+            .map(|entry| {
+                key_ids
+                    .get(&entry.key_id)
+                    .map(|key_id| BackupEntryInfo { key_id: *key_id, ..entry.clone() })
+                    .ok_or_else(corrupted)
+                    .context(ks_err!("Manifest lists unknown key entry {}.", entry.key_id))
+            })
+            .collect()
+    }
+}
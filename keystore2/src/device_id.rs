@@ -0,0 +1,115 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module derives stable device identifiers for pairing protocols.
+//!
+//! Instead of handing out raw hardware identifiers, keystore computes an HMAC over the caller's
+//! UID and a caller supplied context. The HMAC key is derived from an internal KeyMint HMAC key
+//! that never leaves the TEE, so the identifiers are stable for the lifetime of the key database
+//! but cannot be linked across callers or contexts, and they do not survive a factory reset.
+
+use crate::database::{KeyType, KeystoreDB};
+use crate::key_parameter::KeyParameterValue;
+use crate::ks_err;
+use crate::raw_device::KeyMintDevice;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{hmac_sha256, ZVec};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+
+const DEVICE_ID_KEY_ALIAS: &str = "device_id_key";
+const DEVICE_ID_SECRET_INPUT: &[u8] = b"Create device identifier secret";
+
+/// Serializes the lookup or generation of the internal KeyMint key.
+static DEVICE_ID_KEY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Uses the internal KeyMint HMAC key to compute a secret from `input`. The key is generated on
+/// first use. Different inputs yield independent secrets, so the key can serve several
+/// derivations, e.g., of device identifiers and of app keys.
+pub fn get_internal_secret(db: &mut KeystoreDB, input: &[u8]) -> Result<ZVec> {
+    let _lock = DEVICE_ID_KEY_LOCK.lock().unwrap();
+    let km_dev = KeyMintDevice::get(SecurityLevel::TRUSTED_ENVIRONMENT)
+        .context(ks_err!("Get TEE instance failed."))?;
+    let params = vec![
+        KeyParameterValue::Algorithm(Algorithm::HMAC).into(),
+        KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+        KeyParameterValue::KeySize(256).into(),
+        KeyParameterValue::MinMacLength(256).into(),
+        KeyParameterValue::KeyPurpose(KeyPurpose::SIGN).into(),
+        KeyParameterValue::NoAuthRequired.into(),
+    ];
+    let key_desc = KeyMintDevice::internal_descriptor(DEVICE_ID_KEY_ALIAS.to_string());
+    let (key_id_guard, key_blob) = km_dev
+        .lookup_or_generate_key(db, &key_desc, KeyType::Client, &params, |_| true)
+        .context(ks_err!("lookup_or_generate_key failed."))?;
+
+    let params = [
+        KeyParameterValue::MacLength(256).into(),
+        KeyParameterValue::Digest(Digest::SHA_2_256).into(),
+    ];
+    let secret = km_dev
+        .use_key_in_one_step(db, &key_id_guard, &key_blob, KeyPurpose::SIGN, &params, None, input)
+        .context(ks_err!("use_key_in_one_step failed."))?;
+    ZVec::try_from(secret).context(ks_err!("conversion to ZVec failed."))
+}
+
+/// Computes the identifier for the given caller and context from `secret`. The UID has a fixed
+/// size, so the HMAC input is unambiguous.
+fn derive_device_identifier(secret: &[u8], caller_uid: u32, context: &[u8]) -> Result<Vec<u8>> {
+    let mut input = Vec::with_capacity(std::mem::size_of::<u32>() + context.len());
+    input.extend_from_slice(&caller_uid.to_be_bytes());
+    input.extend_from_slice(context);
+    hmac_sha256(secret, &input).context(ks_err!("hmac_sha256 failed."))
+}
+
+/// Returns a stable identifier for the device that is specific to `caller_uid` and `context`.
+pub fn get_device_identifier(
+    db: &mut KeystoreDB,
+    caller_uid: u32,
+    context: &[u8],
+) -> Result<Vec<u8>> {
+    let secret = get_internal_secret(db, DEVICE_ID_SECRET_INPUT).context(ks_err!())?;
+    derive_device_identifier(&secret, caller_uid, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_identifier_is_stable() {
+        let id1 = derive_device_identifier(SECRET, 10001, b"pairing").unwrap();
+        let id2 = derive_device_identifier(SECRET, 10001, b"pairing").unwrap();
+        assert_eq!(id1, id2);
+        assert_eq!(id1.len(), keystore2_crypto::HMAC_SHA256_LEN);
+    }
+
+    #[test]
+    fn test_identifier_diverges() {
+        let id = derive_device_identifier(SECRET, 10001, b"pairing").unwrap();
+        assert_ne!(id, derive_device_identifier(SECRET, 10001, b"pairing2").unwrap());
+        assert_ne!(id, derive_device_identifier(SECRET, 10002, b"pairing").unwrap());
+        assert_ne!(id, derive_device_identifier(SECRET, 10001, b"").unwrap());
+        assert_ne!(
+            id,
+            derive_device_identifier(b"fedcba9876543210fedcba9876543210", 10001, b"pairing")
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module enforces a configurable minimum strength of EC curves.
+//!
+//! Like the RSA minimum, this retires weak keys: the generation and import of EC keys on a curve
+//! smaller than the configured minimum fail with `ErrorCode::UNSUPPORTED_EC_CURVE`. The strength
+//! of a curve is its size in bits, e.g., 256 for P-256 and Curve 25519. Keys that only specify
+//! a key size, e.g., 192 for P-192, are judged by that size. No minimum is enforced by default.
+
+use crate::error::{Error, ErrorCode};
+use crate::ks_err;
+use crate::sysprop::read_prop_u32;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, EcCurve::EcCurve, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, Tag::Tag,
+};
+use anyhow::{Context, Result};
+
+/// The minimum EC curve size in bits. 0, the default, enforces no minimum.
+const MIN_CURVE_SIZE_PROPERTY: &str = "keystore.ec_min_curve_size";
+
+/// Returns the size of `curve` in bits, or None if the curve is unknown.
+fn curve_size(curve: EcCurve) -> Option<u32> {
+    match curve {
+        EcCurve::P_224 => Some(224),
+        EcCurve::P_256 | EcCurve::CURVE_25519 => Some(256),
+        EcCurve::P_384 => Some(384),
+        EcCurve::P_521 => Some(521),
+        _ => None,
+    }
+}
+
+/// Decides whether the curve of an EC key is acceptable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EcCurvePolicy {
+    min_curve_size: u32,
+}
+
+impl EcCurvePolicy {
+    /// Reads the policy from the `keystore.ec_min_curve_size` system property. The property is
+    /// read on every call, so changes take effect with the next request.
+    pub fn from_property() -> Self {
+        Self { min_curve_size: read_prop_u32(MIN_CURVE_SIZE_PROPERTY, 0) }
+    }
+
+    /// Checks the parameters of a key generation or import, or the characteristics of an
+    /// imported key. Fails with `ErrorCode::UNSUPPORTED_EC_CURVE` if they describe an EC key on
+    /// a curve that is smaller than the minimum, or on a curve of unknown size. Parameters
+    /// without a curve and key size pass, because the curve of an imported key is only known
+    /// once KeyMint has parsed the key material.
+    pub fn check(&self, params: &[KeyParameter]) -> Result<()> {
+        if self.min_curve_size == 0 {
+            return Ok(());
+        }
+        let is_ec = params.iter().any(|kp| {
+            matches!(
+                (kp.tag, &kp.value),
+                (Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::EC))
+            )
+        });
+        if !is_ec {
+            return Ok(());
+        }
+        let curve = params.iter().find_map(|kp| match (kp.tag, &kp.value) {
+            (Tag::EC_CURVE, KeyParameterValue::EcCurve(curve)) => Some(*curve),
+            _ => None,
+        });
+        let size = match curve {
+            Some(curve) => curve_size(curve),
+            // KeyMint selects the curve by the key size if no curve is given.
+            None => match params.iter().find_map(|kp| match (kp.tag, &kp.value) {
+                (Tag::KEY_SIZE, KeyParameterValue::Integer(size)) => Some(*size),
+                _ => None,
+            }) {
+                Some(size) => Some(u32::try_from(size).unwrap_or(0)),
+                None => return Ok(()),
+            },
+        };
+        match size {
+            Some(size) if size >= self.min_curve_size => Ok(()),
+            _ => Err(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE)).context(ks_err!(
+                "EC curve {:?} of size {:?} is below the minimum of {}.",
+                curve,
+                size,
+                self.min_curve_size
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN_256: EcCurvePolicy = EcCurvePolicy { min_curve_size: 256 };
+
+    fn ec_params(curve: Option<EcCurve>, key_size: Option<i32>) -> Vec<KeyParameter> {
+        let mut params = vec![KeyParameter {
+            tag: Tag::ALGORITHM,
+            value: KeyParameterValue::Algorithm(Algorithm::EC),
+        }];
+        if let Some(curve) = curve {
+            params.push(KeyParameter {
+                tag: Tag::EC_CURVE,
+                value: KeyParameterValue::EcCurve(curve),
+            });
+        }
+        if let Some(size) = key_size {
+            params
+                .push(KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Integer(size) });
+        }
+        params
+    }
+
+    fn is_unsupported_curve(result: Result<()>) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>()
+            == Some(&Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE))
+    }
+
+    #[test]
+    fn test_allowed_curves_pass() {
+        for curve in [EcCurve::P_256, EcCurve::P_384, EcCurve::P_521, EcCurve::CURVE_25519] {
+            assert!(MIN_256.check(&ec_params(Some(curve), None)).is_ok(), "{:?}", curve);
+        }
+        assert!(MIN_256.check(&ec_params(None, Some(384))).is_ok());
+    }
+
+    #[test]
+    fn test_weak_curves_are_rejected() {
+        assert!(is_unsupported_curve(MIN_256.check(&ec_params(Some(EcCurve::P_224), None))));
+        // P-192 has no EcCurve value, but may be requested by its key size.
+        assert!(is_unsupported_curve(MIN_256.check(&ec_params(None, Some(192)))));
+        assert!(is_unsupported_curve(MIN_256.check(&ec_params(None, Some(-1)))));
+        // Curves of unknown size are rejected as well.
+        assert!(is_unsupported_curve(MIN_256.check(&ec_params(Some(EcCurve(42)), None))));
+        // The curve takes precedence over the key size.
+        assert!(is_unsupported_curve(MIN_256.check(&ec_params(Some(EcCurve::P_224), Some(256)))));
+    }
+
+    #[test]
+    fn test_other_requests_pass() {
+        // The curve of imported keys may only be known after the import.
+        assert!(MIN_256.check(&ec_params(None, None)).is_ok());
+        // Other algorithms are not affected.
+        let aes = [
+            KeyParameter {
+                tag: Tag::ALGORITHM,
+                value: KeyParameterValue::Algorithm(Algorithm::AES),
+            },
+            KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Integer(128) },
+        ];
+        assert!(MIN_256.check(&aes).is_ok());
+        // Without a minimum, everything passes.
+        let no_minimum = EcCurvePolicy { min_curve_size: 0 };
+        assert!(no_minimum.check(&ec_params(None, Some(192))).is_ok());
+    }
+}
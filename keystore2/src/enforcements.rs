@@ -14,10 +14,13 @@
 
 //! This is the Keystore 2.0 Enforcements module.
 // TODO: more description to follow.
+use crate::clock_rollback::{Clock, SystemClock};
 use crate::ks_err;
 use crate::error::{map_binder_status, Error, ErrorCode};
 use crate::globals::{get_timestamp_service, ASYNC_TASK, DB, ENFORCEMENTS};
+use crate::key_flags::KEY_FLAG_SIGN_ONLY_WHILE_LOCKED;
 use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::sysprop::read_prop_duration;
 use crate::{authorization::Error as AuthzError, super_key::SuperEncryptionType};
 use crate::{
     database::{AuthTokenEntry, MonotonicRawTime},
@@ -43,9 +46,14 @@ use std::{
         mpsc::{channel, Receiver, Sender, TryRecvError},
         Arc, Mutex, Weak,
     },
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
+/// The maximum age of an auth token that authorizes an operation with a timeout-bound key,
+/// regardless of the timeout of the key and of whether it may be used while on body. Unset or
+/// zero, the default, does not bound the age beyond the timeout of the key.
+const MAX_AUTH_TOKEN_AGE_PROPERTY: &str = "keystore.max_auth_token_age";
+
 #[derive(Debug)]
 enum AuthRequestState {
     /// An outstanding per operation authorization request.
@@ -396,7 +404,8 @@ impl Enforcements {
     ///
     /// If no key parameters are given (typically when the client is self managed
     /// (see Domain.Blob)) nothing is enforced.
-    /// If the key is time-bound, find a matching auth token from the database.
+    /// If the key is time-bound, find a matching auth token from the database, which must be
+    /// no older than the maximum auth token age configured with `keystore.max_auth_token_age`.
     /// If the above step is successful, and if requires_timestamp is given, the returned
     /// AuthInfo will provide a Timestamp token as appropriate.
     pub fn authorize_create(
@@ -637,6 +646,13 @@ impl Enforcements {
         // Now check the validity of the auth token if the key is timeout bound.
         let hat = match (hat_and_last_off_body, key_time_out) {
             (Some((hat, last_off_body)), Some(key_time_out)) => {
+                Self::check_auth_token_age(
+                    &SystemClock,
+                    hat.time_received(),
+                    read_prop_duration(MAX_AUTH_TOKEN_AGE_PROPERTY, Duration::ZERO),
+                )
+                .context(ks_err!())?;
+
                 let now = MonotonicRawTime::now();
                 let token_age = now
                     .checked_sub(&hat.time_received())
@@ -685,6 +701,28 @@ impl Enforcements {
         })
     }
 
+    /// Fails with `Error::StaleAuthToken` if an auth token that was received at
+    /// `time_received` is older than `max_age` according to the monotonic time of `clock`.
+    /// A `max_age` of zero does not bound the age.
+    fn check_auth_token_age(
+        clock: &dyn Clock,
+        time_received: MonotonicRawTime,
+        max_age: Duration,
+    ) -> Result<()> {
+        if max_age.is_zero() {
+            return Ok(());
+        }
+        let token_age = clock.monotonic_millis().saturating_sub(time_received.milliseconds());
+        if token_age > max_age.as_millis() as i64 {
+            return Err(Error::StaleAuthToken).context(ks_err!(
+                "The auth token is {} ms old, the maximum age is {} ms.",
+                token_age,
+                max_age.as_millis()
+            ));
+        }
+        Ok(())
+    }
+
     fn find_auth_token<F>(p: F) -> Option<(AuthTokenEntry, MonotonicRawTime)>
     where
         F: Fn(&AuthTokenEntry) -> bool,
@@ -752,24 +790,28 @@ impl Enforcements {
         key_parameters: &[KeyParameter],
         flags: Option<i32>,
     ) -> SuperEncryptionType {
-        if let Some(flags) = flags {
-            if (flags & KEY_FLAG_AUTH_BOUND_WITHOUT_CRYPTOGRAPHIC_LSKF_BINDING) != 0 {
-                return SuperEncryptionType::None;
-            }
+        let flags = flags.unwrap_or(0);
+        if (flags & KEY_FLAG_AUTH_BOUND_WITHOUT_CRYPTOGRAPHIC_LSKF_BINDING) != 0 {
+            return SuperEncryptionType::None;
         }
         // Each answer has a priority, numerically largest priority wins.
         struct Candidate {
             priority: u32,
             enc_type: SuperEncryptionType,
         }
-        let mut result = Candidate { priority: 0, enc_type: SuperEncryptionType::None };
+        let mut result = if (flags & KEY_FLAG_SIGN_ONLY_WHILE_LOCKED) != 0 && *domain == Domain::APP
+        {
+            Candidate { priority: 2, enc_type: SuperEncryptionType::SignOnlyWhileLocked }
+        } else {
+            Candidate { priority: 0, enc_type: SuperEncryptionType::None }
+        };
         for kp in key_parameters {
             let t = match kp.key_parameter_value() {
                 KeyParameterValue::MaxBootLevel(level) => {
-                    Candidate { priority: 3, enc_type: SuperEncryptionType::BootLevel(*level) }
+                    Candidate { priority: 4, enc_type: SuperEncryptionType::BootLevel(*level) }
                 }
                 KeyParameterValue::UnlockedDeviceRequired if *domain == Domain::APP => {
-                    Candidate { priority: 2, enc_type: SuperEncryptionType::ScreenLockBound }
+                    Candidate { priority: 3, enc_type: SuperEncryptionType::ScreenLockBound }
                 }
                 KeyParameterValue::UserSecureID(_) if *domain == Domain::APP => {
                     Candidate { priority: 1, enc_type: SuperEncryptionType::LskfBound }
@@ -843,3 +885,36 @@ impl Enforcements {
 }
 
 // TODO: Add tests to enforcement module (b/175578618).
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+
+    #[test]
+    fn test_auth_token_age_is_bounded() {
+        let clock = Arc::new(FakeClock::default());
+        let time_received = MonotonicRawTime::now();
+        clock.advance(time_received.milliseconds());
+        let max_age = Duration::from_secs(30);
+
+        // A fresh token is accepted.
+        assert!(Enforcements::check_auth_token_age(&clock, time_received, max_age).is_ok());
+        clock.advance(30_000);
+        assert!(Enforcements::check_auth_token_age(&clock, time_received, max_age).is_ok());
+
+        // A stale token is rejected with a distinct error.
+        clock.advance(1);
+        assert_eq!(
+            Enforcements::check_auth_token_age(&clock, time_received, max_age)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::StaleAuthToken)
+        );
+
+        // A maximum age of zero does not bound the age.
+        clock.advance(24 * 60 * 60 * 1000);
+        assert!(Enforcements::check_auth_token_age(&clock, time_received, Duration::ZERO).is_ok());
+    }
+}
@@ -30,6 +30,8 @@
 //! Keystore functions should use `anyhow::Result` to return error conditions, and
 //! context should be added every time an error is forwarded.
 
+use crate::log_throttle::log_throttled;
+use crate::sysprop::read_prop_parsed;
 pub use android_hardware_security_keymint::aidl::android::hardware::security::keymint::ErrorCode::ErrorCode;
 pub use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use android_system_keystore2::binder::{
@@ -59,6 +61,25 @@ pub enum Error {
     /// AIDL interface spec.
     #[error("Error::Rp({0:?})")]
     Rp(ErrorCode),
+    /// The super key that protects a key has not been unlocked since boot, see
+    /// `super_key_wait`. It is reported to clients as `ResponseCode::LOCKED`.
+    #[error("Error::SuperKeyUnavailable")]
+    SuperKeyUnavailable,
+    /// The operation is no longer active and has no checkpoint to resume it from, e.g., because
+    /// keystore restarted and the operation was not resumable, see `operation_checkpoint`. It
+    /// is reported to clients as `ErrorCode::INVALID_OPERATION_HANDLE`.
+    #[error("Error::OperationLost")]
+    OperationLost,
+    /// The secure hardware of the backend reset, e.g., because StrongBox rebooted, and lost the
+    /// operation, see "Hardware Resets" in `operation`. It is reported to clients as
+    /// `ResponseCode::BACKEND_BUSY`, so that they retry.
+    #[error("Error::HardwareReset")]
+    HardwareReset,
+    /// The auth token that would authorize an operation with an auth-bound key is older than
+    /// the maximum auth token age, see `enforcements`. It is reported to clients as
+    /// `ErrorCode::KEY_USER_NOT_AUTHENTICATED`, so that they ask the user to authenticate again.
+    #[error("Error::StaleAuthToken")]
+    StaleAuthToken,
 }
 
 impl Error {
@@ -95,6 +116,13 @@ pub fn map_km_error<T>(r: BinderResult<T>) -> Result<T, Error> {
                     Error::Binder(ExceptionCode::SERVICE_SPECIFIC, se)
                 }
             }
+            // A dead KeyMint binder is preserved, because it indicates a hardware reset
+            // during operations, see "Hardware Resets" in `operation`.
+            ExceptionCode::TRANSACTION_FAILED
+                if s.transaction_error() == StatusCode::DEAD_OBJECT =>
+            {
+                Error::BinderTransaction(StatusCode::DEAD_OBJECT)
+            }
             // We create `Error::Binder` to preserve the exception code
             // for logging.
             // `map_or_log_err` will map this on a system error.
@@ -130,15 +158,353 @@ pub fn map_binder_status<T>(r: BinderResult<T>) -> Result<T, Error> {
     })
 }
 
+/// Helper function to map the binder status we get from calls into keystore's own interfaces,
+/// e.g., `IKeystoreOperation`, to a Keystore Error. Negative service specific errors are
+/// KeyMint error codes, and non negative ones are Keystore response codes.
+pub fn map_ks_error<T>(r: BinderResult<T>) -> Result<T, Error> {
+    r.map_err(|s| match s.exception_code() {
+        ExceptionCode::SERVICE_SPECIFIC => {
+            let se = s.service_specific_error();
+            if se < 0 {
+                Error::Km(ErrorCode(se))
+            } else {
+                Error::Rc(ResponseCode(se))
+            }
+        }
+        e_code => Error::Binder(e_code, 0),
+    })
+}
+
 /// This function maps a status code onto a Keystore Error.
 pub fn map_binder_status_code<T>(r: Result<T, StatusCode>) -> Result<T, Error> {
     r.map_err(Error::BinderTransaction)
 }
 
+/// The kind of action that is likely to resolve a KeyMint error. This is a hint for triage only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Remediation {
+    /// The request is malformed or not permitted by the key; the caller has to change it.
+    FixRequest,
+    /// The backend does not support the request; a different algorithm, parameter, or security
+    /// level is required.
+    Unsupported,
+    /// The user has to authenticate or confirm, or the device has to be unlocked.
+    Authenticate,
+    /// The condition is transient, so the request may succeed later.
+    Retry,
+    /// The key can no longer be used, so a new key is required.
+    ReplaceKey,
+    /// The key blob has to be upgraded, which keystore normally does transparently.
+    UpgradeKey,
+    /// Keystore or KeyMint failed or is misconfigured, which the caller cannot fix.
+    ReportBug,
+}
+
+/// Returns a stable human readable description of the KeyMint error code `ec` and the kind of
+/// action that is likely to resolve it. Error codes unknown to keystore are described as such
+/// and should be reported.
+pub fn describe_km_error(ec: ErrorCode) -> (&'static str, Remediation) {
+    match ec {
+        ErrorCode::OK => ("The operation succeeded", Remediation::ReportBug),
+        ErrorCode::ROOT_OF_TRUST_ALREADY_SET => {
+            ("The root of trust was already set", Remediation::ReportBug)
+        }
+        ErrorCode::UNSUPPORTED_PURPOSE => {
+            ("The key purpose is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::INCOMPATIBLE_PURPOSE => {
+            ("The key was not created for the requested purpose", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_ALGORITHM => {
+            ("The key algorithm is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::INCOMPATIBLE_ALGORITHM => {
+            ("The requested operation does not fit the key algorithm", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_KEY_SIZE => {
+            ("The key size is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::UNSUPPORTED_BLOCK_MODE => {
+            ("The block mode is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::INCOMPATIBLE_BLOCK_MODE => {
+            ("The key is not authorized for the requested block mode", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_MAC_LENGTH => {
+            ("The MAC length is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::UNSUPPORTED_PADDING_MODE => {
+            ("The padding mode is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::INCOMPATIBLE_PADDING_MODE => {
+            ("The key is not authorized for the requested padding mode", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_DIGEST => ("The digest is not supported", Remediation::Unsupported),
+        ErrorCode::INCOMPATIBLE_DIGEST => {
+            ("The key is not authorized for the requested digest", Remediation::FixRequest)
+        }
+        ErrorCode::INVALID_EXPIRATION_TIME => {
+            ("The expiration time is invalid", Remediation::FixRequest)
+        }
+        ErrorCode::INVALID_USER_ID => ("The user id is invalid", Remediation::FixRequest),
+        ErrorCode::INVALID_AUTHORIZATION_TIMEOUT => {
+            ("The authorization timeout is invalid", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_KEY_FORMAT => {
+            ("The key format is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::INCOMPATIBLE_KEY_FORMAT => {
+            ("The key format does not fit the key algorithm", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_KEY_ENCRYPTION_ALGORITHM => {
+            ("The key encryption algorithm is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::UNSUPPORTED_KEY_VERIFICATION_ALGORITHM => {
+            ("The key verification algorithm is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::INVALID_INPUT_LENGTH => {
+            ("The input length is invalid for the operation", Remediation::FixRequest)
+        }
+        ErrorCode::KEY_EXPORT_OPTIONS_INVALID => {
+            ("The key export options are invalid", Remediation::FixRequest)
+        }
+        ErrorCode::DELEGATION_NOT_ALLOWED => ("Delegation is not allowed", Remediation::FixRequest),
+        ErrorCode::KEY_NOT_YET_VALID => ("The key is not yet valid", Remediation::Retry),
+        ErrorCode::KEY_EXPIRED => ("The key has expired", Remediation::ReplaceKey),
+        ErrorCode::KEY_USER_NOT_AUTHENTICATED => (
+            "The user has not authenticated recently enough to use the key",
+            Remediation::Authenticate,
+        ),
+        ErrorCode::OUTPUT_PARAMETER_NULL => {
+            ("An output parameter is missing", Remediation::ReportBug)
+        }
+        ErrorCode::INVALID_OPERATION_HANDLE => {
+            ("The operation handle is invalid or the operation has ended", Remediation::FixRequest)
+        }
+        ErrorCode::INSUFFICIENT_BUFFER_SPACE => {
+            ("The output buffer is too small", Remediation::FixRequest)
+        }
+        ErrorCode::VERIFICATION_FAILED => {
+            ("The signature or tag did not verify", Remediation::FixRequest)
+        }
+        ErrorCode::TOO_MANY_OPERATIONS => {
+            ("Too many operations are in progress", Remediation::Retry)
+        }
+        ErrorCode::UNEXPECTED_NULL_POINTER => {
+            ("An unexpected null pointer was passed to KeyMint", Remediation::ReportBug)
+        }
+        ErrorCode::INVALID_KEY_BLOB => (
+            "The key blob is invalid or was created by another KeyMint instance",
+            Remediation::ReplaceKey,
+        ),
+        ErrorCode::IMPORTED_KEY_NOT_ENCRYPTED => {
+            ("The imported key is not encrypted", Remediation::FixRequest)
+        }
+        ErrorCode::IMPORTED_KEY_DECRYPTION_FAILED => {
+            ("The imported key failed to decrypt", Remediation::FixRequest)
+        }
+        ErrorCode::IMPORTED_KEY_NOT_SIGNED => {
+            ("The imported key is not signed", Remediation::FixRequest)
+        }
+        ErrorCode::IMPORTED_KEY_VERIFICATION_FAILED => {
+            ("The imported key failed to verify", Remediation::FixRequest)
+        }
+        ErrorCode::INVALID_ARGUMENT => ("An argument is invalid", Remediation::FixRequest),
+        ErrorCode::UNSUPPORTED_TAG => ("A tag is not supported", Remediation::Unsupported),
+        ErrorCode::INVALID_TAG => ("A tag is invalid for the operation", Remediation::FixRequest),
+        ErrorCode::MEMORY_ALLOCATION_FAILED => ("KeyMint ran out of memory", Remediation::Retry),
+        ErrorCode::IMPORT_PARAMETER_MISMATCH => {
+            ("The import parameters do not match the imported key", Remediation::FixRequest)
+        }
+        ErrorCode::SECURE_HW_ACCESS_DENIED => {
+            ("Access to the secure hardware was denied", Remediation::ReportBug)
+        }
+        ErrorCode::OPERATION_CANCELLED => ("The operation was cancelled", Remediation::Retry),
+        ErrorCode::CONCURRENT_ACCESS_CONFLICT => {
+            ("The key is used by a conflicting concurrent operation", Remediation::Retry)
+        }
+        ErrorCode::SECURE_HW_BUSY => ("The secure hardware is busy", Remediation::Retry),
+        ErrorCode::SECURE_HW_COMMUNICATION_FAILED => {
+            ("The communication with the secure hardware failed", Remediation::Retry)
+        }
+        ErrorCode::UNSUPPORTED_EC_FIELD => {
+            ("The EC field is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::MISSING_NONCE => ("The operation requires a nonce", Remediation::FixRequest),
+        ErrorCode::INVALID_NONCE => ("The nonce is invalid", Remediation::FixRequest),
+        ErrorCode::MISSING_MAC_LENGTH => {
+            ("The operation requires a MAC length", Remediation::FixRequest)
+        }
+        ErrorCode::KEY_RATE_LIMIT_EXCEEDED => {
+            ("The key was used too frequently", Remediation::Retry)
+        }
+        ErrorCode::CALLER_NONCE_PROHIBITED => {
+            ("The key does not permit a caller provided nonce", Remediation::FixRequest)
+        }
+        ErrorCode::KEY_MAX_OPS_EXCEEDED => {
+            ("The key has exhausted its permitted number of uses", Remediation::ReplaceKey)
+        }
+        ErrorCode::INVALID_MAC_LENGTH => {
+            ("The MAC length is invalid for the key", Remediation::FixRequest)
+        }
+        ErrorCode::MISSING_MIN_MAC_LENGTH => {
+            ("The key requires a minimum MAC length", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_MIN_MAC_LENGTH => {
+            ("The minimum MAC length is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::UNSUPPORTED_KDF => {
+            ("The key derivation function is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::UNSUPPORTED_EC_CURVE => {
+            ("The EC curve is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::KEY_REQUIRES_UPGRADE => {
+            ("The key blob must be upgraded", Remediation::UpgradeKey)
+        }
+        ErrorCode::ATTESTATION_CHALLENGE_MISSING => {
+            ("The attestation requires a challenge", Remediation::FixRequest)
+        }
+        ErrorCode::KEYMINT_NOT_CONFIGURED => ("KeyMint is not configured", Remediation::ReportBug),
+        ErrorCode::ATTESTATION_APPLICATION_ID_MISSING => {
+            ("The attestation requires an application id", Remediation::ReportBug)
+        }
+        ErrorCode::CANNOT_ATTEST_IDS => {
+            ("The device identifiers cannot be attested", Remediation::Unsupported)
+        }
+        ErrorCode::ROLLBACK_RESISTANCE_UNAVAILABLE => {
+            ("Rollback resistance is not available", Remediation::Unsupported)
+        }
+        ErrorCode::HARDWARE_TYPE_UNAVAILABLE => {
+            ("The requested security level is not available", Remediation::Unsupported)
+        }
+        ErrorCode::PROOF_OF_PRESENCE_REQUIRED => {
+            ("The key requires a proof of presence", Remediation::Authenticate)
+        }
+        ErrorCode::CONCURRENT_PROOF_OF_PRESENCE_REQUESTED => {
+            ("A proof of presence was already requested", Remediation::Retry)
+        }
+        ErrorCode::NO_USER_CONFIRMATION => {
+            ("The key requires a user confirmation", Remediation::Authenticate)
+        }
+        ErrorCode::DEVICE_LOCKED => {
+            ("The key can only be used while the device is unlocked", Remediation::Authenticate)
+        }
+        ErrorCode::EARLY_BOOT_ENDED => {
+            ("The key can only be used during early boot", Remediation::FixRequest)
+        }
+        ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED => {
+            ("The device has no attestation keys", Remediation::Retry)
+        }
+        ErrorCode::ATTESTATION_IDS_NOT_PROVISIONED => {
+            ("The device identifiers are not provisioned", Remediation::Unsupported)
+        }
+        ErrorCode::INVALID_OPERATION => {
+            ("The operation is invalid in the current state", Remediation::FixRequest)
+        }
+        ErrorCode::STORAGE_KEY_UNSUPPORTED => {
+            ("Storage keys are not supported", Remediation::Unsupported)
+        }
+        ErrorCode::INCOMPATIBLE_MGF_DIGEST => {
+            ("The key is not authorized for the requested MGF digest", Remediation::FixRequest)
+        }
+        ErrorCode::UNSUPPORTED_MGF_DIGEST => {
+            ("The MGF digest is not supported", Remediation::Unsupported)
+        }
+        ErrorCode::MISSING_NOT_BEFORE => {
+            ("The key requires a certificate start date", Remediation::FixRequest)
+        }
+        ErrorCode::MISSING_NOT_AFTER => {
+            ("The key requires a certificate end date", Remediation::FixRequest)
+        }
+        ErrorCode::MISSING_ISSUER_SUBJECT => {
+            ("The attestation requires an issuer subject", Remediation::FixRequest)
+        }
+        ErrorCode::INVALID_ISSUER_SUBJECT => {
+            ("The issuer subject is invalid", Remediation::FixRequest)
+        }
+        ErrorCode::BOOT_LEVEL_EXCEEDED => {
+            ("The key can no longer be used at the current boot level", Remediation::FixRequest)
+        }
+        ErrorCode::HARDWARE_NOT_YET_AVAILABLE => {
+            ("The secure hardware is not yet available", Remediation::Retry)
+        }
+        ErrorCode::UNIMPLEMENTED => {
+            ("The operation is not implemented by KeyMint", Remediation::Unsupported)
+        }
+        ErrorCode::VERSION_MISMATCH => {
+            ("The KeyMint version does not match", Remediation::ReportBug)
+        }
+        ErrorCode::UNKNOWN_ERROR => {
+            ("KeyMint failed with an unknown error", Remediation::ReportBug)
+        }
+        _ => ("Unknown KeyMint error", Remediation::ReportBug),
+    }
+}
+
+/// How much of the context chain of an error is logged: "full", "outermost", or "root_cause".
+const ERROR_LOG_VERBOSITY_PROPERTY: &str = "keystore.error_log_verbosity";
+
+/// The verbosity with which errors are logged by `map_or_log_err`. Only the log is affected.
+/// The message of the returned service specific error always carries the full context chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorLogVerbosity {
+    /// The full context chain down to the root cause. This is the default.
+    Full,
+    /// The outermost context and the root cause.
+    Outermost,
+    /// The root cause only.
+    RootCause,
+}
+
+impl ErrorLogVerbosity {
+    /// Reads the verbosity from the `keystore.error_log_verbosity` system property. The
+    /// property is read on every call, so changes take effect with the next logged error.
+    pub fn from_property() -> Self {
+        read_prop_parsed(ERROR_LOG_VERBOSITY_PROPERTY, Self::Full, Self::parse)
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "full" => Some(Self::Full),
+            "outermost" => Some(Self::Outermost),
+            "root_cause" => Some(Self::RootCause),
+            _ => None,
+        }
+    }
+}
+
+/// Formats `e` for logging and for the message of the returned service specific error. If the
+/// root cause is a KeyMint error, its description is appended.
+fn format_error(e: &anyhow::Error) -> String {
+    format_error_with_verbosity(e, ErrorLogVerbosity::Full)
+}
+
+/// Like `format_error`, but with only as much of the context chain as `verbosity` selects.
+fn format_error_with_verbosity(e: &anyhow::Error, verbosity: ErrorLogVerbosity) -> String {
+    let chain = match verbosity {
+        ErrorLogVerbosity::Full => format!("{:?}", e),
+        ErrorLogVerbosity::Outermost => match e.chain().count() {
+            1 => format!("{}", e),
+            2 => format!("{}\n\nCaused by:\n    {}", e, e.root_cause()),
+            n => format!("{}\n\nCaused by:\n    ... {} more\n    {}", e, n - 2, e.root_cause()),
+        },
+        ErrorLogVerbosity::RootCause => format!("{}", e.root_cause()),
+    };
+    match e.root_cause().downcast_ref::<Error>() {
+        Some(Error::Km(ec)) => {
+            let (description, remediation) = describe_km_error(*ec);
+            format!("{}\n\n{:?}: {} (remediation: {:?}).", chain, ec, description, remediation)
+        }
+        _ => chain,
+    }
+}
+
 /// This function should be used by Keystore service calls to translate error conditions
 /// into service specific exceptions.
 ///
-/// All error conditions get logged by this function, except for KEY_NOT_FOUND error.
+/// All error conditions get logged by this function, except for KEY_NOT_FOUND error. The
+/// context chain is logged as selected by `ErrorLogVerbosity::from_property`.
 ///
 /// All `Error::Rc(x)` and `Error::Km(x)` variants get mapped onto a service specific error
 /// code of x. This is possible because KeyMint `ErrorCode` errors are always negative and
@@ -172,12 +538,16 @@ where
     map_err_with(
         result,
         |e| {
-            // Make the key not found errors silent.
+            // Make the key not found errors silent. Identical errors are throttled, because
+            // a failing backend fails every request in the same way.
             if !matches!(
                 e.root_cause().downcast_ref::<Error>(),
                 Some(Error::Rc(ResponseCode::KEY_NOT_FOUND))
             ) {
-                log::error!("{:?}", e);
+                log_throttled(
+                    log::Level::Error,
+                    &format_error_with_verbosity(&e, ErrorLogVerbosity::from_property()),
+                );
             }
             e
         },
@@ -187,10 +557,11 @@ where
 
 /// This function turns an anyhow error into an optional CString.
 /// This is especially useful to add a message string to a service specific error.
+/// KeyMint errors are described, see `describe_km_error`.
 /// If the formatted string was not convertible because it contained a nul byte,
 /// None is returned and a warning is logged.
 pub fn anyhow_error_to_cstring(e: &anyhow::Error) -> Option<CString> {
-    match CString::new(format!("{:?}", e)) {
+    match CString::new(format_error(e)) {
         Ok(msg) => Some(msg),
         Err(_) => {
             log::warn!("Cannot convert error message to CStr. It contained a nul byte.");
@@ -231,6 +602,10 @@ pub fn get_error_code(e: &anyhow::Error) -> i32 {
         Some(Error::Rc(rcode)) => rcode.0,
         Some(Error::Km(ec)) => ec.0,
         Some(Error::Rp(_)) => ResponseCode::SYSTEM_ERROR.0,
+        Some(Error::SuperKeyUnavailable) => ResponseCode::LOCKED.0,
+        Some(Error::OperationLost) => ErrorCode::INVALID_OPERATION_HANDLE.0,
+        Some(Error::HardwareReset) => ResponseCode::BACKEND_BUSY.0,
+        Some(Error::StaleAuthToken) => ErrorCode::KEY_USER_NOT_AUTHENTICATED.0,
         // If an Error::Binder reaches this stage we report a system error.
         // The exception code and possible service specific error will be
         // printed in the error log above.
@@ -398,6 +773,77 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_error_log_verbosity() {
+        let e = nested_rc(ResponseCode::LOCKED).unwrap_err();
+        let full = format_error_with_verbosity(&e, ErrorLogVerbosity::Full);
+        assert_eq!(full, format!("{:?}", e));
+        assert!(full.contains("nested rc") && full.contains("nested nested rc"));
+
+        let outermost = format_error_with_verbosity(&e, ErrorLogVerbosity::Outermost);
+        assert!(outermost.contains("nested rc"));
+        assert!(!outermost.contains("nested nested rc"));
+        assert!(outermost.contains("... 1 more"));
+        assert!(outermost.contains(&e.root_cause().to_string()));
+
+        let root_cause = format_error_with_verbosity(&e, ErrorLogVerbosity::RootCause);
+        assert!(!root_cause.contains("nested"));
+        assert_eq!(root_cause, e.root_cause().to_string());
+
+        // The error itself keeps the full context chain.
+        assert_eq!(e.chain().count(), 3);
+        assert_eq!(e.root_cause().downcast_ref::<Error>(), Some(&Error::Rc(ResponseCode::LOCKED)));
+        assert_eq!(anyhow_error_to_cstring(&e).unwrap().to_str().unwrap(), full);
+
+        // KeyMint errors are described at any verbosity.
+        let e = nested_ec(ErrorCode::KEY_EXPIRED).unwrap_err();
+        let root_cause = format_error_with_verbosity(&e, ErrorLogVerbosity::RootCause);
+        assert!(root_cause.ends_with(&format!(
+            "{} (remediation: {:?}).",
+            describe_km_error(ErrorCode::KEY_EXPIRED).0,
+            describe_km_error(ErrorCode::KEY_EXPIRED).1
+        )));
+
+        assert_eq!(ErrorLogVerbosity::parse("outermost"), Some(ErrorLogVerbosity::Outermost));
+        assert_eq!(ErrorLogVerbosity::parse("root_cause"), Some(ErrorLogVerbosity::RootCause));
+        assert_eq!(ErrorLogVerbosity::parse("verbose"), None);
+    }
+
+    #[test]
+    fn test_all_known_km_errors_are_described() {
+        let (unknown, _) = describe_km_error(ErrorCode(-999));
+        for ec in ErrorCode::UNKNOWN_ERROR.0..=ErrorCode::OK.0 {
+            let ec = ErrorCode(ec);
+            // The debug representation of error codes that are not defined by KeyMint is the
+            // plain number.
+            let known = format!("{:?}", ec).parse::<i32>().is_err();
+            let (description, _) = describe_km_error(ec);
+            assert_eq!(description != unknown, known, "{:?}", ec);
+        }
+    }
+
+    #[test]
+    fn test_km_error_description() {
+        assert_eq!(
+            describe_km_error(ErrorCode::KEY_USER_NOT_AUTHENTICATED).1,
+            Remediation::Authenticate
+        );
+        assert_eq!(describe_km_error(ErrorCode::SECURE_HW_BUSY).1, Remediation::Retry);
+        assert_eq!(
+            describe_km_error(ErrorCode(-1234)),
+            ("Unknown KeyMint error", Remediation::ReportBug)
+        );
+
+        let e = nested_ec(ErrorCode::KEY_EXPIRED).unwrap_err();
+        let msg = anyhow_error_to_cstring(&e).unwrap().into_string().unwrap();
+        assert!(msg.starts_with(&format!("{:?}", e)));
+        assert!(msg.ends_with("KEY_EXPIRED: The key has expired (remediation: ReplaceKey)."));
+
+        // Other errors are formatted as before.
+        let e = nested_rc(ResponseCode::LOCKED).unwrap_err();
+        assert_eq!(anyhow_error_to_cstring(&e).unwrap().into_string().unwrap(), format!("{:?}", e));
+    }
+
     //Helper function to test whether error cases are handled as expected.
     pub fn check_result_contains_error_string<T>(
         result: anyhow::Result<T>,
@@ -0,0 +1,193 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the FIPS mode of keystore.
+//!
+//! Some deployments must only use FIPS approved algorithms. In FIPS mode, keystore rejects the
+//! generation and import of keys, and operations with keys, that request an algorithm, EC curve,
+//! or digest that is not on the allowlists below, before KeyMint is called. The mode is off by
+//! default.
+
+use crate::error::{Error, ErrorCode};
+use crate::key_parameter::KeyParameter as KsKeyParam;
+use crate::ks_err;
+use crate::sysprop::read_prop_bool;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, Tag::Tag,
+};
+use anyhow::{Context, Result};
+
+/// If true, only FIPS approved algorithms may be used.
+const FIPS_MODE_PROPERTY: &str = "keystore.fips_mode";
+
+/// The approved key algorithms. Triple DES is no longer approved.
+const APPROVED_ALGORITHMS: &[Algorithm] =
+    &[Algorithm::RSA, Algorithm::EC, Algorithm::AES, Algorithm::HMAC];
+
+/// The approved EC curves. Curve 25519 is not approved.
+const APPROVED_EC_CURVES: &[EcCurve] =
+    &[EcCurve::P_224, EcCurve::P_256, EcCurve::P_384, EcCurve::P_521];
+
+/// The approved digests. `Digest::NONE`, where the caller digests the message, is allowed, but
+/// MD5 and SHA-1 are not.
+const APPROVED_DIGESTS: &[Digest] =
+    &[Digest::NONE, Digest::SHA_2_224, Digest::SHA_2_256, Digest::SHA_2_384, Digest::SHA_2_512];
+
+/// Decides whether key parameters are acceptable in FIPS mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FipsPolicy {
+    enabled: bool,
+}
+
+impl FipsPolicy {
+    /// Reads the mode from the `keystore.fips_mode` system property. The property is read on
+    /// every call, so changes take effect with the next request.
+    pub fn from_property() -> Self {
+        Self { enabled: read_prop_bool(FIPS_MODE_PROPERTY, false) }
+    }
+
+    /// Checks the parameters of a key generation, import, or operation. In FIPS mode, requests
+    /// for an algorithm, curve, or digest that is not approved fail with
+    /// `ErrorCode::UNSUPPORTED_ALGORITHM`, `ErrorCode::UNSUPPORTED_EC_CURVE`, or
+    /// `ErrorCode::UNSUPPORTED_DIGEST` respectively.
+    pub fn check(&self, params: &[KeyParameter]) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        for kp in params {
+            match (kp.tag, &kp.value) {
+                (Tag::ALGORITHM, KeyParameterValue::Algorithm(algorithm))
+                    if !APPROVED_ALGORITHMS.contains(algorithm) =>
+                {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_ALGORITHM))
+                        .context(ks_err!("Algorithm {:?} is not FIPS approved.", algorithm));
+                }
+                (Tag::EC_CURVE, KeyParameterValue::EcCurve(curve))
+                    if !APPROVED_EC_CURVES.contains(curve) =>
+                {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE))
+                        .context(ks_err!("EC curve {:?} is not FIPS approved.", curve));
+                }
+                (Tag::DIGEST | Tag::RSA_OAEP_MGF_DIGEST, KeyParameterValue::Digest(digest))
+                    if !APPROVED_DIGESTS.contains(digest) =>
+                {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_DIGEST))
+                        .context(ks_err!("Digest {:?} is not FIPS approved.", digest));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `check`, for the stored parameters of a key.
+    pub fn check_key(&self, key_params: &[KsKeyParam]) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let params: Vec<KeyParameter> =
+            key_params.iter().map(|kp| kp.key_parameter_value().clone().into()).collect();
+        self.check(&params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+
+    const ON: FipsPolicy = FipsPolicy { enabled: true };
+    const OFF: FipsPolicy = FipsPolicy { enabled: false };
+
+    fn algorithm(algorithm: Algorithm) -> KeyParameter {
+        KeyParameter { tag: Tag::ALGORITHM, value: KeyParameterValue::Algorithm(algorithm) }
+    }
+
+    fn curve(curve: EcCurve) -> KeyParameter {
+        KeyParameter { tag: Tag::EC_CURVE, value: KeyParameterValue::EcCurve(curve) }
+    }
+
+    fn digest(tag: Tag, digest: Digest) -> KeyParameter {
+        KeyParameter { tag, value: KeyParameterValue::Digest(digest) }
+    }
+
+    fn assert_rejected(params: &[KeyParameter], expected: ErrorCode) {
+        assert_eq!(
+            ON.check(params).unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(expected)),
+            "{:?}",
+            params
+        );
+    }
+
+    #[test]
+    fn test_approved_algorithms_pass() {
+        let requests = [
+            vec![algorithm(Algorithm::AES), digest(Tag::DIGEST, Digest::NONE)],
+            vec![algorithm(Algorithm::HMAC), digest(Tag::DIGEST, Digest::SHA_2_256)],
+            vec![algorithm(Algorithm::EC), curve(EcCurve::P_256)],
+            vec![
+                algorithm(Algorithm::RSA),
+                digest(Tag::DIGEST, Digest::SHA_2_512),
+                digest(Tag::RSA_OAEP_MGF_DIGEST, Digest::SHA_2_384),
+            ],
+        ];
+        for params in &requests {
+            assert!(ON.check(params).is_ok(), "{:?}", params);
+            assert!(OFF.check(params).is_ok(), "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn test_unapproved_algorithms_are_rejected() {
+        let requests = [
+            (vec![algorithm(Algorithm::TRIPLE_DES)], ErrorCode::UNSUPPORTED_ALGORITHM),
+            (
+                vec![algorithm(Algorithm::EC), curve(EcCurve::CURVE_25519)],
+                ErrorCode::UNSUPPORTED_EC_CURVE,
+            ),
+            (
+                vec![algorithm(Algorithm::HMAC), digest(Tag::DIGEST, Digest::MD5)],
+                ErrorCode::UNSUPPORTED_DIGEST,
+            ),
+            (
+                vec![algorithm(Algorithm::RSA), digest(Tag::RSA_OAEP_MGF_DIGEST, Digest::SHA1)],
+                ErrorCode::UNSUPPORTED_DIGEST,
+            ),
+        ];
+        for (params, expected) in &requests {
+            assert_rejected(params, *expected);
+            // Everything passes if the mode is off.
+            assert!(OFF.check(params).is_ok(), "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn test_stored_key_parameters_are_checked() {
+        let key_params = [
+            KsKeyParam::new(
+                KsKeyParamValue::Algorithm(Algorithm::TRIPLE_DES),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KsKeyParam::new(KsKeyParamValue::Digest(Digest::NONE), SecurityLevel::KEYSTORE),
+        ];
+        assert_eq!(
+            ON.check_key(&key_params).unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::UNSUPPORTED_ALGORITHM))
+        );
+        assert!(OFF.check_key(&key_params).is_ok());
+    }
+}
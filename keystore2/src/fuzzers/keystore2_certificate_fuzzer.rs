@@ -0,0 +1,26 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fuzzes the certificate parsers of libkeystore2_crypto with raw DER input. Malformed input
+//! must be rejected with an error and must neither crash nor hang the parsers.
+
+#![no_main]
+
+use keystore2_crypto::{parse_attestation_record_from_certificate, parse_subject_from_certificate};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _res = parse_subject_from_certificate(data);
+    let _res = parse_attestation_record_from_certificate(data);
+});
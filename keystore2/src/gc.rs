@@ -17,23 +17,31 @@
 //! a thread on demand which will query the database for unreferenced key entries,
 //! optionally dispose of sensitive key material appropriately, and then delete
 //! the key entry from the database.
+//! A collection in progress can be cancelled with `cancel()`. It then stops after the key
+//! that is currently being processed.
 
+use crate::attestation_expiry::AttestationExpiryCheck;
+use crate::clock_rollback::{Clock, SystemClock};
 use crate::ks_err;
+use crate::metrics_store::log_rkp_keys_pruned;
+use crate::operation_checkpoint::MAX_CHECKPOINT_AGE;
 use crate::{
     async_task,
-    database::{BlobMetaData, KeystoreDB, Uuid},
-    super_key::SuperKeyManager,
+    database::{BlobMetaData, DateTime, KeystoreDB, Uuid},
+    super_key::{BlobBinding, SuperKeyManager},
 };
 use anyhow::{Context, Result};
-use async_task::AsyncTask;
+use async_task::{AsyncTask, CancellationToken};
 use std::sync::{
     atomic::{AtomicU8, Ordering},
-    Arc, RwLock,
+    Arc, Mutex, RwLock,
 };
 
 pub struct Gc {
     async_task: Arc<AsyncTask>,
     notified: Arc<AtomicU8>,
+    /// Cancellation token of the most recently scheduled collection.
+    cancellation: Mutex<CancellationToken>,
 }
 
 impl Gc {
@@ -67,9 +75,11 @@ impl Gc {
                 async_task: weak_at,
                 super_key,
                 notified,
+                clock: Box::new(SystemClock),
+                attestation_expiry: Default::default(),
             });
         });
-        Self { async_task, notified }
+        Self { async_task, notified, cancellation: Default::default() }
     }
 
     /// Notifies the key garbage collector to iterate through orphaned and superseded blobs and
@@ -77,19 +87,35 @@ impl Gc {
     /// attempt by queueing it in the async_task (low priority) queue.
     pub fn notify_gc(&self) {
         if let Ok(0) = self.notified.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed) {
-            self.async_task.queue_lo(|shelf| shelf.get_downcast_mut::<GcInternal>().unwrap().step())
+            let cancellation = CancellationToken::new();
+            *self.cancellation.lock().unwrap() = cancellation.clone();
+            self.async_task.queue_lo(move |shelf| {
+                shelf.get_downcast_mut::<GcInternal>().unwrap().step(cancellation)
+            })
         }
     }
+
+    /// Cancels the collection in progress, e.g., during shutdown. The garbage collector stops
+    /// after the key it is currently processing and commits the deletion of all keys processed
+    /// so far. Keys that were not processed remain in the database and will be collected after
+    /// the next call to `notify_gc`.
+    pub fn cancel(&self) {
+        self.cancellation.lock().unwrap().cancel();
+    }
 }
 
 struct GcInternal {
     deleted_blob_ids: Vec<i64>,
-    superseded_blobs: Vec<(i64, Vec<u8>, BlobMetaData)>,
+    superseded_blobs: Vec<(i64, i64, Vec<u8>, BlobMetaData)>,
     invalidate_key: Box<dyn Fn(&Uuid, &[u8]) -> Result<()> + Send + 'static>,
     db: KeystoreDB,
     async_task: std::sync::Weak<AsyncTask>,
     super_key: Arc<RwLock<SuperKeyManager>>,
     notified: Arc<AtomicU8>,
+    /// The clock against which the expiry dates of keys are checked.
+    clock: Box<dyn Clock>,
+    /// Throttles the check of stored attestation chains for expiry.
+    attestation_expiry: AttestationExpiryCheck,
 }
 
 impl GcInternal {
@@ -101,6 +127,18 @@ impl GcInternal {
     /// with threads on the critical path, deleted blobs are loaded in batches.
     fn process_one_key(&mut self) -> Result<()> {
         if self.superseded_blobs.is_empty() {
+            // At the beginning of each collection cycle, prune the expired remote provisioned
+            // attestation keys and client keys, so that they get collected in this cycle.
+            if self.deleted_blob_ids.is_empty() {
+                self.prune_expired_attestation_keys();
+                self.prune_expired_keys();
+                self.purge_expired_tombstones();
+                self.purge_key_id_allocations();
+                self.purge_expired_operation_checkpoints();
+                self.delete_dangling_grants();
+                self.check_attestation_expiry();
+                self.checkpoint_wal();
+            }
             let blobs = self
                 .db
                 .handle_next_superseded_blobs(&self.deleted_blob_ids, 20)
@@ -109,7 +147,7 @@ impl GcInternal {
             self.superseded_blobs = blobs;
         }
 
-        if let Some((blob_id, blob, blob_metadata)) = self.superseded_blobs.pop() {
+        if let Some((blob_id, key_id, blob, blob_metadata)) = self.superseded_blobs.pop() {
             // Add the next blob_id to the deleted blob ids list. So it will be
             // removed from the database regardless of whether the following
             // succeeds or not.
@@ -120,11 +158,24 @@ impl GcInternal {
             // (At this time keys may get deleted without having the super encryption
             // key in this case we can only delete the key from the database.)
             if let Some(uuid) = blob_metadata.km_uuid() {
+                let binding = match blob_metadata.bound_user_id() {
+                    None => None,
+                    Some(bound_user_id) => match self
+                        .db
+                        .load_key_descriptor(key_id)
+                        .context(ks_err!("Trying to load the owner of the blob."))?
+                    {
+                        Some(owner) => Some(BlobBinding::new(key_id, &owner)),
+                        // The key entry was deleted already. The recorded user id is only used
+                        // to delete the orphaned blob from KeyMint, never to use it.
+                        None => Some(BlobBinding { key_id, user_id: *bound_user_id as u32 }),
+                    },
+                };
                 let blob = self
                     .super_key
                     .read()
                     .unwrap()
-                    .unwrap_key_if_required(&blob_metadata, &blob)
+                    .unwrap_key_if_required(&blob_metadata, &blob, binding.as_ref())
                     .context(ks_err!("Trying to unwrap to-be-deleted blob.",))?;
                 (self.invalidate_key)(uuid, &blob).context(ks_err!("Trying to invalidate key."))?;
             }
@@ -132,9 +183,136 @@ impl GcInternal {
         Ok(())
     }
 
-    /// Processes one key and then schedules another attempt until it runs out of blobs to delete.
-    fn step(&mut self) {
+    /// Marks expired remote provisioned attestation keys as unreferenced. Failing to do so
+    /// must not keep the garbage collector from collecting other keys, so errors are only logged.
+    fn prune_expired_attestation_keys(&mut self) {
+        match self.db.prune_expired_attestation_keys() {
+            Ok(pruned) => {
+                if !pruned.is_empty() {
+                    log::info!("Pruned {} expired attestation keys.", pruned.len());
+                    log_rkp_keys_pruned(&pruned);
+                }
+            }
+            Err(e) => log::error!("Error trying to prune expired attestation keys. {:?}", e),
+        }
+    }
+
+    /// Marks client keys that are past their expiry date as unreferenced. Like the pruning of
+    /// attestation keys, this must not keep the garbage collector from collecting other keys.
+    fn prune_expired_keys(&mut self) {
+        let now = DateTime::from_millis_epoch(self.clock.wall_millis());
+        match self.db.mark_expired_keys_unreferenced(now) {
+            Ok(pruned) => {
+                if !pruned.is_empty() {
+                    log::info!("Deleting {} expired keys.", pruned.len());
+                }
+            }
+            Err(e) => log::error!("Error trying to prune expired keys. {:?}", e),
+        }
+    }
+
+    /// Deletes the tombstones of deleted keys that are past their retention period. Errors are
+    /// only logged, like those of the pruning.
+    fn purge_expired_tombstones(&mut self) {
+        let now = DateTime::from_millis_epoch(self.clock.wall_millis());
+        match self.db.purge_expired_tombstones(now) {
+            Ok(purged) => {
+                if purged != 0 {
+                    log::info!("Purged {} expired key tombstones.", purged);
+                }
+            }
+            Err(e) => log::error!("Error trying to purge expired key tombstones. {:?}", e),
+        }
+    }
+
+    /// Deletes the oldest entries of the key id allocation audit log beyond those that are
+    /// retained. Errors are only logged, like those of the pruning.
+    fn purge_key_id_allocations(&mut self) {
+        match self.db.purge_key_id_allocations() {
+            Ok(purged) => {
+                if purged != 0 {
+                    log::info!("Purged {} key id allocation audit entries.", purged);
+                }
+            }
+            Err(e) => log::error!("Error trying to purge key id allocations. {:?}", e),
+        }
+    }
+
+    /// Deletes the checkpoints of lost operations that were not resumed in time. Errors are only
+    /// logged, like those of the pruning.
+    fn purge_expired_operation_checkpoints(&mut self) {
+        let cutoff = DateTime::from_millis_epoch(
+            self.clock.wall_millis().saturating_sub(MAX_CHECKPOINT_AGE.as_millis() as i64),
+        );
+        match self.db.purge_expired_operation_checkpoints(cutoff) {
+            Ok(purged) => {
+                if purged != 0 {
+                    log::info!("Purged {} expired operation checkpoints.", purged);
+                }
+            }
+            Err(e) => log::error!("Error trying to purge expired operation checkpoints. {:?}", e),
+        }
+    }
+
+    /// Deletes the grants whose target key no longer exists. Errors are only logged, like those
+    /// of the pruning.
+    fn delete_dangling_grants(&mut self) {
+        match self.db.delete_dangling_grants() {
+            Ok(deleted) => {
+                if deleted != 0 {
+                    log::warn!("Deleted {} grants without key.", deleted);
+                }
+            }
+            Err(e) => log::error!("Error trying to delete dangling grants. {:?}", e),
+        }
+    }
+
+    /// Marks the keys whose attestation chain expired or is about to expire for re-attestation,
+    /// at most once per check interval. Errors are only logged, like those of the pruning.
+    fn check_attestation_expiry(&mut self) {
+        match self.attestation_expiry.run_if_due(&mut self.db, self.clock.as_ref()) {
+            Ok(Some(marked)) if !marked.is_empty() => {
+                log::info!("Marked {} keys for re-attestation.", marked.len());
+            }
+            Ok(_) => {}
+            Err(e) => log::error!("Error trying to check attestation chains for expiry. {:?}", e),
+        }
+    }
+
+    /// Checkpoints the write ahead log of the database if it is in WAL mode. Errors are only
+    /// logged, like those of the pruning.
+    fn checkpoint_wal(&mut self) {
+        match self.db.checkpoint_wal() {
+            Ok(Some(_)) => {}
+            Ok(None) => log::info!("Checkpoint of the write ahead log is incomplete."),
+            Err(e) => log::error!("Error trying to checkpoint the write ahead log. {:?}", e),
+        }
+    }
+
+    /// Removes the blobs that were processed so far from the database and forgets about the
+    /// loaded blobs that were not yet processed. They are still in the database and will be
+    /// loaded again by the next collection.
+    fn commit_progress(&mut self) -> Result<()> {
+        self.superseded_blobs.clear();
+        self.db
+            .handle_next_superseded_blobs(&self.deleted_blob_ids, 0)
+            .context(ks_err!("Trying to delete processed blobs."))?;
+        self.deleted_blob_ids.clear();
+        Ok(())
+    }
+
+    /// Processes one key and then schedules another attempt until it runs out of blobs to delete,
+    /// or until the collection is cancelled.
+    fn step(&mut self, cancellation: CancellationToken) {
         self.notified.store(0, Ordering::Relaxed);
+        // Between two keys is a safe point to stop.
+        if cancellation.is_cancelled() {
+            log::info!("Garbage collection cancelled.");
+            if let Err(e) = self.commit_progress() {
+                log::error!("Error trying to commit garbage collection progress. {:?}", e);
+            }
+            return;
+        }
         if let Err(e) = self.process_one_key() {
             log::error!("Error trying to delete blob entry. {:?}", e);
         }
@@ -145,10 +323,145 @@ impl GcInternal {
                     self.notified.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed)
                 {
                     at.queue_lo(move |shelf| {
-                        shelf.get_downcast_mut::<GcInternal>().unwrap().step()
+                        shelf.get_downcast_mut::<GcInternal>().unwrap().step(cancellation)
                     });
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+    use crate::database::{
+        BlobInfo, BlobMetaEntry, CertificateInfo, KeyEntryLoadBits, KeyMetaData, KeyMetaEntry,
+        KeyType, KEYSTORE_UUID,
+    };
+    use android_system_keystore2::aidl::android::system::keystore2::{
+        Domain::Domain, KeyDescriptor::KeyDescriptor,
+    };
+    use std::sync::Weak;
+
+    #[test]
+    fn test_cancel_commits_progress() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        for i in 0..5u8 {
+            db.set_deleted_blob(&[i], &blob_metadata)?;
+        }
+
+        let invalidated = Arc::new(Mutex::new(Vec::new()));
+        let invalidated_clone = invalidated.clone();
+        // Without an async task, each step processes exactly one key.
+        let mut gc = GcInternal {
+            deleted_blob_ids: vec![],
+            superseded_blobs: vec![],
+            invalidate_key: Box::new(move |_, blob| {
+                invalidated_clone.lock().unwrap().push(blob.to_vec());
+                Ok(())
+            }),
+            db,
+            async_task: Weak::new(),
+            super_key: Default::default(),
+            notified: Default::default(),
+            clock: Box::new(SystemClock),
+            attestation_expiry: Default::default(),
+        };
+
+        let cancellation = CancellationToken::new();
+        gc.step(cancellation.clone());
+        gc.step(cancellation.clone());
+        assert_eq!(invalidated.lock().unwrap().len(), 2);
+
+        cancellation.cancel();
+        gc.step(cancellation.clone());
+        assert_eq!(invalidated.lock().unwrap().len(), 2);
+        assert!(gc.deleted_blob_ids.is_empty());
+        assert!(gc.superseded_blobs.is_empty());
+
+        // The processed blobs are gone, the others remain for the next collection.
+        let mut remaining: Vec<Vec<u8>> = gc
+            .db
+            .handle_next_superseded_blobs(&[], 20)?
+            .into_iter()
+            .map(|(_, _, blob, _)| blob)
+            .collect();
+        remaining.extend(invalidated.lock().unwrap().iter().cloned());
+        remaining.sort();
+        assert_eq!(remaining, (0..5u8).map(|i| vec![i]).collect::<Vec<_>>());
+
+        // A new collection is not affected by the cancellation of the previous one.
+        gc.step(CancellationToken::new());
+        assert_eq!(invalidated.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_keys_are_collected() -> Result<()> {
+        let expiry = 1_700_000_000_000;
+        let mut db = KeystoreDB::new_in_memory()?;
+        let key = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        for (alias, expiry_date) in [("expiring", Some(expiry)), ("forever", None)] {
+            let mut metadata = KeyMetaData::new();
+            if let Some(expiry_date) = expiry_date {
+                metadata.add(KeyMetaEntry::ExpiryDate(DateTime::from_millis_epoch(expiry_date)));
+            }
+            db.store_new_key(
+                &key(alias),
+                KeyType::Client,
+                &[],
+                &BlobInfo::new(alias.as_bytes(), &blob_metadata),
+                &CertificateInfo::new(None, None),
+                &metadata,
+                &KEYSTORE_UUID,
+            )?;
+        }
+
+        let invalidated = Arc::new(Mutex::new(Vec::new()));
+        let invalidated_clone = invalidated.clone();
+        let clock = Arc::new(FakeClock::default());
+        clock.move_wall_by(expiry - 1);
+        let mut gc = GcInternal {
+            deleted_blob_ids: vec![],
+            superseded_blobs: vec![],
+            invalidate_key: Box::new(move |_, blob| {
+                invalidated_clone.lock().unwrap().push(blob.to_vec());
+                Ok(())
+            }),
+            db,
+            async_task: Weak::new(),
+            super_key: Default::default(),
+            notified: Default::default(),
+            clock: Box::new(clock.clone()),
+            attestation_expiry: Default::default(),
+        };
+        let exists = |gc: &mut GcInternal, alias: &str| {
+            gc.db
+                .load_key_entry(&key(alias), KeyType::Client, KeyEntryLoadBits::NONE, 1, |_, _| {
+                    Ok(())
+                })
+                .is_ok()
+        };
+
+        gc.step(CancellationToken::new());
+        assert!(invalidated.lock().unwrap().is_empty());
+        assert!(exists(&mut gc, "expiring"));
+
+        clock.advance(1);
+        gc.step(CancellationToken::new());
+        assert_eq!(*invalidated.lock().unwrap(), vec![b"expiring".to_vec()]);
+        assert!(!exists(&mut gc, "expiring"));
+        assert!(exists(&mut gc, "forever"));
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the defaults for key generation parameters of each KeyMint backend.
+//!
+//! Clients may omit parameters that have a sensible default, e.g., the key size. Before a
+//! generation request is passed to KeyMint, the parameters that the client omitted are filled
+//! in from the defaults of the backend for the algorithm of the key. Parameters given by the
+//! client are never replaced. The key sizes can be configured per backend with a property that
+//! holds a comma separated list of `<algorithm>=<key size>` pairs, e.g., `RSA=3072,AES=128`.
+
+use crate::sysprop::read_prop_parsed;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, EcCurve::EcCurve, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use std::collections::HashMap;
+
+/// The default public exponent of RSA keys, F4.
+const DEFAULT_RSA_PUBLIC_EXPONENT: i64 = 65537;
+
+fn key_size_property(security_level: SecurityLevel) -> Option<&'static str> {
+    match security_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => Some("keystore.tee.default_key_sizes"),
+        SecurityLevel::STRONGBOX => Some("keystore.strongbox.default_key_sizes"),
+        _ => None,
+    }
+}
+
+/// Parses a comma separated list of `<algorithm>=<key size>` pairs. Returns None if an entry is
+/// malformed, so that a broken property does not apply only half of the configuration.
+fn parse_key_sizes(value: &str) -> Option<Vec<(Algorithm, i32)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (algorithm, key_size) = entry.split_once('=')?;
+            let algorithm = match algorithm.trim() {
+                "RSA" => Algorithm::RSA,
+                "AES" => Algorithm::AES,
+                "HMAC" => Algorithm::HMAC,
+                "TRIPLE_DES" => Algorithm::TRIPLE_DES,
+                _ => return None,
+            };
+            Some((algorithm, key_size.trim().parse().ok().filter(|size| *size > 0)?))
+        })
+        .collect()
+}
+
+fn key_size(size: i32) -> KeyParameter {
+    KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Integer(size) }
+}
+
+/// The defaults for the key generation parameters of a KeyMint backend, per algorithm.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationDefaults {
+    defaults: HashMap<Algorithm, Vec<KeyParameter>>,
+}
+
+impl GenerationDefaults {
+    /// Returns the built-in defaults of the backend of `security_level`, with the key sizes
+    /// replaced by those configured in the property of the backend.
+    pub fn for_security_level(security_level: SecurityLevel) -> Self {
+        let mut defaults = Self::builtin(security_level);
+        if let Some(property) = key_size_property(security_level) {
+            for (algorithm, size) in read_prop_parsed(property, Vec::new(), parse_key_sizes) {
+                defaults.set_key_size(algorithm, size);
+            }
+        }
+        defaults
+    }
+
+    /// The built-in defaults. StrongBox implementations are only required to support 2048 bit
+    /// RSA keys and 128 bit AES keys, whereas the TEE defaults to the stronger key sizes.
+    fn builtin(security_level: SecurityLevel) -> Self {
+        let is_strongbox = security_level == SecurityLevel::STRONGBOX;
+        let mut defaults = HashMap::new();
+        defaults.insert(
+            Algorithm::RSA,
+            vec![
+                key_size(if is_strongbox { 2048 } else { 3072 }),
+                KeyParameter {
+                    tag: Tag::RSA_PUBLIC_EXPONENT,
+                    value: KeyParameterValue::LongInteger(DEFAULT_RSA_PUBLIC_EXPONENT),
+                },
+            ],
+        );
+        defaults.insert(
+            Algorithm::EC,
+            vec![KeyParameter {
+                tag: Tag::EC_CURVE,
+                value: KeyParameterValue::EcCurve(EcCurve::P_256),
+            }],
+        );
+        defaults.insert(Algorithm::AES, vec![key_size(if is_strongbox { 128 } else { 256 })]);
+        defaults.insert(Algorithm::HMAC, vec![key_size(256)]);
+        defaults.insert(Algorithm::TRIPLE_DES, vec![key_size(168)]);
+        Self { defaults }
+    }
+
+    fn set_key_size(&mut self, algorithm: Algorithm, size: i32) {
+        let params = self.defaults.entry(algorithm).or_default();
+        params.retain(|kp| kp.tag != Tag::KEY_SIZE);
+        params.push(key_size(size));
+    }
+
+    /// Returns `params` followed by the defaults for each parameter that the client omitted.
+    /// Returns None if `params` name no algorithm or nothing was omitted. For EC keys, the
+    /// curve is only filled in if the client specified neither a curve nor a key size, because
+    /// KeyMint derives the curve from the key size.
+    pub fn apply(&self, params: &[KeyParameter]) -> Option<Vec<KeyParameter>> {
+        let algorithm = params.iter().find_map(|kp| match kp.value {
+            KeyParameterValue::Algorithm(algorithm) if kp.tag == Tag::ALGORITHM => Some(algorithm),
+            _ => None,
+        })?;
+        let has_tag = |tag: Tag| params.iter().any(|kp| kp.tag == tag);
+        let omitted: Vec<KeyParameter> = self
+            .defaults
+            .get(&algorithm)?
+            .iter()
+            .filter(|kp| !has_tag(kp.tag))
+            .filter(|kp| !(kp.tag == Tag::EC_CURVE && has_tag(Tag::KEY_SIZE)))
+            .cloned()
+            .collect();
+        if omitted.is_empty() {
+            return None;
+        }
+        Some(params.iter().cloned().chain(omitted).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::KeyPurpose::KeyPurpose;
+
+    fn algorithm(algorithm: Algorithm) -> KeyParameter {
+        KeyParameter { tag: Tag::ALGORITHM, value: KeyParameterValue::Algorithm(algorithm) }
+    }
+
+    fn purpose(purpose: KeyPurpose) -> KeyParameter {
+        KeyParameter { tag: Tag::PURPOSE, value: KeyParameterValue::KeyPurpose(purpose) }
+    }
+
+    fn ec_curve(curve: EcCurve) -> KeyParameter {
+        KeyParameter { tag: Tag::EC_CURVE, value: KeyParameterValue::EcCurve(curve) }
+    }
+
+    fn rsa_public_exponent() -> KeyParameter {
+        KeyParameter {
+            tag: Tag::RSA_PUBLIC_EXPONENT,
+            value: KeyParameterValue::LongInteger(DEFAULT_RSA_PUBLIC_EXPONENT),
+        }
+    }
+
+    #[test]
+    fn test_defaults_fill_in_omitted_parameters() {
+        let tee = GenerationDefaults::builtin(SecurityLevel::TRUSTED_ENVIRONMENT);
+        let strongbox = GenerationDefaults::builtin(SecurityLevel::STRONGBOX);
+        let params = vec![algorithm(Algorithm::RSA), purpose(KeyPurpose::SIGN)];
+
+        let mut expected = params.clone();
+        expected.extend([key_size(3072), rsa_public_exponent()]);
+        assert_eq!(tee.apply(&params), Some(expected));
+
+        let mut expected = params;
+        expected.extend([key_size(2048), rsa_public_exponent()]);
+        assert_eq!(strongbox.apply(&expected[..2]), Some(expected));
+
+        assert_eq!(
+            tee.apply(&[algorithm(Algorithm::EC)]),
+            Some(vec![algorithm(Algorithm::EC), ec_curve(EcCurve::P_256)])
+        );
+        assert_eq!(
+            strongbox.apply(&[algorithm(Algorithm::AES)]),
+            Some(vec![algorithm(Algorithm::AES), key_size(128)])
+        );
+    }
+
+    #[test]
+    fn test_explicit_parameters_override_defaults() {
+        let tee = GenerationDefaults::builtin(SecurityLevel::TRUSTED_ENVIRONMENT);
+
+        // Only the omitted public exponent is filled in.
+        assert_eq!(
+            tee.apply(&[algorithm(Algorithm::RSA), key_size(4096)]),
+            Some(vec![algorithm(Algorithm::RSA), key_size(4096), rsa_public_exponent()])
+        );
+        // Nothing was omitted.
+        assert_eq!(tee.apply(&[algorithm(Algorithm::AES), key_size(128)]), None);
+        assert_eq!(tee.apply(&[algorithm(Algorithm::EC), ec_curve(EcCurve::P_384)]), None);
+        // KeyMint derives the curve from the key size, so no curve is added.
+        assert_eq!(tee.apply(&[algorithm(Algorithm::EC), key_size(384)]), None);
+        // Without an algorithm, no defaults apply.
+        assert_eq!(tee.apply(&[purpose(KeyPurpose::SIGN)]), None);
+    }
+
+    #[test]
+    fn test_configured_key_sizes() {
+        assert_eq!(
+            parse_key_sizes(" RSA=4096, AES =128,"),
+            Some(vec![(Algorithm::RSA, 4096), (Algorithm::AES, 128)])
+        );
+        assert_eq!(parse_key_sizes(""), Some(vec![]));
+        for malformed in ["RSA", "RSA=", "RSA=-1", "RSA=big", "EC=256", "RSA=2048,DSA=1024"] {
+            assert_eq!(parse_key_sizes(malformed), None, "{:?}", malformed);
+        }
+
+        let mut defaults = GenerationDefaults::builtin(SecurityLevel::TRUSTED_ENVIRONMENT);
+        defaults.set_key_size(Algorithm::RSA, 4096);
+        assert_eq!(
+            defaults.apply(&[algorithm(Algorithm::RSA)]),
+            Some(vec![algorithm(Algorithm::RSA), rsa_public_exponent(), key_size(4096)])
+        );
+    }
+}
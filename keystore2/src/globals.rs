@@ -16,6 +16,7 @@
 //! database connections and connections to services that Keystore needs
 //! to talk to.
 
+use crate::aead_nonce::NonceGenerator;
 use crate::ks_err;
 use crate::gc::Gc;
 use crate::legacy_blob::LegacyBlobLoader;
@@ -29,6 +30,7 @@ use crate::{
     error::{map_binder_status, map_binder_status_code, Error, ErrorCode},
 };
 use crate::km_compat::{KeyMintV1, BacklevelKeyMintWrapper};
+use crate::km_features::BackendInfoCache;
 use crate::{enforcements::Enforcements, error::map_km_error};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     IKeyMintDevice::BpKeyMintDevice, IKeyMintDevice::IKeyMintDevice,
@@ -40,7 +42,6 @@ use android_hardware_security_secureclock::aidl::android::hardware::security::se
 use android_hardware_security_keymint::binder::{StatusCode, Strong};
 use android_security_compat::aidl::android::security::compat::IKeystoreCompatService::IKeystoreCompatService;
 use anyhow::{Context, Result};
-use binder::FromIBinder;
 use binder::get_declared_instances;
 use lazy_static::lazy_static;
 use std::sync::{Arc, Mutex, RwLock};
@@ -89,46 +90,56 @@ thread_local! {
             RefCell::new(create_thread_local_db());
 }
 
-struct DevicesMap<T: FromIBinder + ?Sized> {
-    devices_by_uuid: HashMap<Uuid, (Strong<T>, KeyMintHardwareInfo)>,
-    uuid_by_sec_level: HashMap<SecurityLevel, Uuid>,
+struct DevicesMap<D: Clone> {
+    devices_by_uuid: HashMap<Uuid, (D, KeyMintHardwareInfo)>,
+    uuid_by_instance: HashMap<String, Uuid>,
 }
 
-impl<T: FromIBinder + ?Sized> DevicesMap<T> {
-    fn dev_by_sec_level(
-        &self,
-        sec_level: &SecurityLevel,
-    ) -> Option<(Strong<T>, KeyMintHardwareInfo, Uuid)> {
-        self.uuid_by_sec_level.get(sec_level).and_then(|uuid| self.dev_by_uuid(uuid))
+impl<D: Clone> DevicesMap<D> {
+    fn dev_by_instance(&self, instance: &str) -> Option<(D, KeyMintHardwareInfo, Uuid)> {
+        self.uuid_by_instance.get(instance).and_then(|uuid| self.dev_by_uuid(uuid))
     }
 
-    fn dev_by_uuid(&self, uuid: &Uuid) -> Option<(Strong<T>, KeyMintHardwareInfo, Uuid)> {
+    fn dev_by_uuid(&self, uuid: &Uuid) -> Option<(D, KeyMintHardwareInfo, Uuid)> {
         self.devices_by_uuid
             .get(uuid)
             .map(|(dev, hw_info)| ((*dev).clone(), (*hw_info).clone(), *uuid))
     }
 
-    fn devices(&self) -> Vec<Strong<T>> {
+    fn devices(&self) -> Vec<D> {
         self.devices_by_uuid.values().map(|(dev, _)| dev.clone()).collect()
     }
 
     /// The requested security level and the security level of the actual implementation may
-    /// differ. So we map the requested security level to the uuid of the implementation
+    /// differ. So we map the requested instance to the uuid of the implementation
     /// so that there cannot be any confusion as to which KeyMint instance is requested.
-    fn insert(&mut self, sec_level: SecurityLevel, dev: Strong<T>, hw_info: KeyMintHardwareInfo) {
-        // For now we use the reported security level of the KM instance as UUID.
+    fn insert(
+        &mut self,
+        sec_level: SecurityLevel,
+        instance: &str,
+        dev: D,
+        hw_info: KeyMintHardwareInfo,
+    ) -> Result<Uuid> {
+        let is_primary = primary_keymint_instance(&sec_level)? == instance;
+        // For now we use the reported security level of the KM instance as UUID of the primary
+        // instance and derive the UUID of additional instances from their instance name.
         // TODO update this section once UUID was added to the KM hardware info.
-        let uuid: Uuid = sec_level.into();
+        let uuid: Uuid = if is_primary {
+            sec_level.into()
+        } else {
+            Uuid::from_keymint_instance(sec_level, instance).context(ks_err!())?
+        };
         self.devices_by_uuid.insert(uuid, (dev, hw_info));
-        self.uuid_by_sec_level.insert(sec_level, uuid);
+        self.uuid_by_instance.insert(instance.to_string(), uuid);
+        Ok(uuid)
     }
 }
 
-impl<T: FromIBinder + ?Sized> Default for DevicesMap<T> {
+impl<D: Clone> Default for DevicesMap<D> {
     fn default() -> Self {
         Self {
-            devices_by_uuid: HashMap::<Uuid, (Strong<T>, KeyMintHardwareInfo)>::new(),
-            uuid_by_sec_level: Default::default(),
+            devices_by_uuid: HashMap::<Uuid, (D, KeyMintHardwareInfo)>::new(),
+            uuid_by_instance: Default::default(),
         }
     }
 }
@@ -137,10 +148,15 @@ lazy_static! {
     /// The path where keystore stores all its keys.
     pub static ref DB_PATH: RwLock<PathBuf> = RwLock::new(
         Path::new("/data/misc/keystore").to_path_buf());
+    /// Generates the nonces with which blobs are encrypted under a super key.
+    pub static ref BLOB_NONCE_GENERATOR: NonceGenerator = NonceGenerator::from_property();
     /// Runtime database of unwrapped super keys.
     pub static ref SUPER_KEY: Arc<RwLock<SuperKeyManager>> = Default::default();
     /// Map of KeyMint devices.
-    static ref KEY_MINT_DEVICES: Mutex<DevicesMap<dyn IKeyMintDevice>> = Default::default();
+    static ref KEY_MINT_DEVICES: Mutex<DevicesMap<Strong<dyn IKeyMintDevice>>> =
+        Default::default();
+    /// The KeyMint version and features of each KeyMint device.
+    pub static ref KEYMINT_BACKEND_INFO: BackendInfoCache = Default::default();
     /// Timestamp service.
     static ref TIME_STAMP_DEVICE: Mutex<Option<Strong<dyn ISecureClock>>> = Default::default();
     /// A single on-demand worker thread that handles deferred tasks with two different
@@ -158,7 +174,8 @@ lazy_static! {
     /// Background thread which handles logging via statsd and logd
     pub static ref LOGS_HANDLER: Arc<AsyncTask> = Default::default();
 
-    static ref GC: Arc<Gc> = Arc::new(Gc::new_init_with(ASYNC_TASK.clone(), || {
+    /// Garbage collector for superseded and orphaned key blobs.
+    pub static ref GC: Arc<Gc> = Arc::new(Gc::new_init_with(ASYNC_TASK.clone(), || {
         (
             Box::new(|uuid, blob| {
                 let km_dev = get_keymint_dev_by_uuid(uuid).map(|(dev, _)| dev)?;
@@ -173,49 +190,91 @@ lazy_static! {
     }));
 }
 
-/// Determine the service name for a KeyMint device of the given security level
+/// Instance name of the TEE KeyMint device.
+const TEE_INSTANCE: &str = "default";
+/// Instance name of the primary StrongBox KeyMint device. Additional StrongBox devices are
+/// declared with instance names of the form "strongbox_<suffix>".
+const STRONGBOX_INSTANCE: &str = "strongbox";
+
+/// Returns the instance name of the primary KeyMint device of the given security level. This is
+/// the instance that is used whenever a KeyMint device is requested by security level alone.
+pub fn primary_keymint_instance(security_level: &SecurityLevel) -> Result<&'static str> {
+    match *security_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => Ok(TEE_INSTANCE),
+        SecurityLevel::STRONGBOX => Ok(STRONGBOX_INSTANCE),
+        _ => Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE)).context(ks_err!(
+            "Trying to find keymint for security level: {:?}",
+            security_level
+        )),
+    }
+}
+
+/// Returns true if `instance` names a KeyMint instance of the given security level.
+fn is_keymint_instance_of(security_level: &SecurityLevel, instance: &str) -> bool {
+    match *security_level {
+        SecurityLevel::TRUSTED_ENVIRONMENT => instance == TEE_INSTANCE,
+        SecurityLevel::STRONGBOX => {
+            instance == STRONGBOX_INSTANCE
+                || instance
+                    .strip_prefix(STRONGBOX_INSTANCE)
+                    .and_then(|suffix| suffix.strip_prefix('_'))
+                    .map_or(false, |suffix| !suffix.is_empty())
+        }
+        _ => false,
+    }
+}
+
+/// Returns the names of all KeyMint instances of the given security level. The primary
+/// instance always comes first, because it may also be provided by the compatibility service,
+/// followed by the additional declared instances in lexicographic order.
+pub fn get_keymint_instances(security_level: &SecurityLevel) -> Result<Vec<String>> {
+    let primary = primary_keymint_instance(security_level).context(ks_err!())?;
+    let keymint_descriptor: &str = <BpKeyMintDevice as IKeyMintDevice>::get_descriptor();
+    let mut additional: Vec<String> = get_declared_instances(keymint_descriptor)
+        .unwrap()
+        .into_iter()
+        .filter(|instance| instance != primary && is_keymint_instance_of(security_level, instance))
+        .collect();
+    additional.sort();
+    Ok(std::iter::once(primary.to_string()).chain(additional).collect())
+}
+
+/// Determine the service name for the KeyMint device `instance` of the given security level
 /// which implements at least the specified version of the `IKeyMintDevice`
 /// interface.
-fn keymint_service_name(security_level: &SecurityLevel) -> Result<Option<String>> {
+fn keymint_service_name(security_level: &SecurityLevel, instance: &str) -> Result<Option<String>> {
+    if !is_keymint_instance_of(security_level, instance) {
+        return Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE)).context(ks_err!(
+            "Instance {} is not a keymint for security level: {:?}",
+            instance,
+            security_level
+        ));
+    }
+
     let keymint_descriptor: &str = <BpKeyMintDevice as IKeyMintDevice>::get_descriptor();
     let keymint_instances = get_declared_instances(keymint_descriptor).unwrap();
 
-    let service_name = match *security_level {
-        SecurityLevel::TRUSTED_ENVIRONMENT => {
-            if keymint_instances.iter().any(|instance| *instance == "default") {
-                Some(format!("{}/default", keymint_descriptor))
-            } else {
-                None
-            }
-        }
-        SecurityLevel::STRONGBOX => {
-            if keymint_instances.iter().any(|instance| *instance == "strongbox") {
-                Some(format!("{}/strongbox", keymint_descriptor))
-            } else {
-                None
-            }
-        }
-        _ => {
-            return Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE)).context(ks_err!(
-                "Trying to find keymint for security level: {:?}",
-                security_level
-            ));
-        }
+    let service_name = if keymint_instances.iter().any(|declared| *declared == instance) {
+        Some(format!("{}/{}", keymint_descriptor, instance))
+    } else {
+        None
     };
 
     Ok(service_name)
 }
 
-/// Make a new connection to a KeyMint device of the given security level.
-/// If no native KeyMint device can be found this function also brings
+/// Make a new connection to the KeyMint device `instance` of the given security level.
+/// If no native KeyMint device can be found for the primary instance this function also brings
 /// up the compatibility service and attempts to connect to the legacy wrapper.
 fn connect_keymint(
     security_level: &SecurityLevel,
+    instance: &str,
 ) -> Result<(Strong<dyn IKeyMintDevice>, KeyMintHardwareInfo)> {
     // Connects to binder to get the current keymint interface and
-    // based on the security level returns a service name to connect
+    // based on the security level and instance returns a service name to connect
     // to.
-    let service_name = keymint_service_name(security_level).context(ks_err!("Get service name"))?;
+    let service_name =
+        keymint_service_name(security_level, instance).context(ks_err!("Get service name"))?;
 
     let (keymint, hal_version) = if let Some(service_name) = service_name {
         let km: Strong<dyn IKeyMintDevice> =
@@ -228,6 +287,10 @@ fn connect_keymint(
         // etc.
         let km_version = km.getInterfaceVersion()?;
         (km, Some(km_version * 100))
+    } else if instance != primary_keymint_instance(security_level)? {
+        // The compatibility service only provides a single device per security level.
+        return Err(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
+            .context(ks_err!("KeyMint instance {} is not declared.", instance));
     } else {
         // This is a no-op if it was called before.
         keystore2_km_compat::add_keymint_device_service();
@@ -321,28 +384,42 @@ fn connect_keymint(
     Ok((keymint, hw_info))
 }
 
-/// Get a keymint device for the given security level either from our cache or
+/// Get the primary keymint device for the given security level either from our cache or
 /// by making a new connection. Returns the device, the hardware info and the uuid.
 /// TODO the latter can be removed when the uuid is part of the hardware info.
 pub fn get_keymint_device(
     security_level: &SecurityLevel,
+) -> Result<(Strong<dyn IKeyMintDevice>, KeyMintHardwareInfo, Uuid)> {
+    let instance = primary_keymint_instance(security_level).context(ks_err!())?;
+    get_keymint_device_by_instance(security_level, instance)
+}
+
+/// Get the keymint device `instance` of the given security level either from our cache or
+/// by making a new connection. Returns the device, the hardware info and the uuid.
+pub fn get_keymint_device_by_instance(
+    security_level: &SecurityLevel,
+    instance: &str,
 ) -> Result<(Strong<dyn IKeyMintDevice>, KeyMintHardwareInfo, Uuid)> {
     let mut devices_map = KEY_MINT_DEVICES.lock().unwrap();
-    if let Some((dev, hw_info, uuid)) = devices_map.dev_by_sec_level(security_level) {
+    if let Some((dev, hw_info, uuid)) = devices_map.dev_by_instance(instance) {
         Ok((dev, hw_info, uuid))
     } else {
-        let (dev, hw_info) =
-            connect_keymint(security_level).context(ks_err!("Cannot connect to Keymint"))?;
-        devices_map.insert(*security_level, dev, hw_info);
+        let (dev, hw_info) = connect_keymint(security_level, instance)
+            .context(ks_err!("Cannot connect to Keymint"))?;
+        if instance == primary_keymint_instance(security_level)? {
+            // A new connection may have negotiated a different version.
+            KEYMINT_BACKEND_INFO.invalidate(*security_level);
+        }
+        devices_map.insert(*security_level, instance, dev, hw_info).context(ks_err!())?;
         // Unwrap must succeed because we just inserted it.
-        Ok(devices_map.dev_by_sec_level(security_level).unwrap())
+        Ok(devices_map.dev_by_instance(instance).unwrap())
     }
 }
 
 /// Get a keymint device for the given uuid. This will only access the cache, but will not
 /// attempt to establish a new connection. It is assumed that the cache is already populated
 /// when this is called. This is a fair assumption, because service.rs iterates through all
-/// security levels when it gets instantiated.
+/// KeyMint instances of all security levels when it gets instantiated.
 pub fn get_keymint_dev_by_uuid(
     uuid: &Uuid,
 ) -> Result<(Strong<dyn IKeyMintDevice>, KeyMintHardwareInfo)> {
@@ -414,28 +491,89 @@ pub fn get_timestamp_service() -> Result<Strong<dyn ISecureClock>> {
 static REMOTE_PROVISIONING_HAL_SERVICE_NAME: &str =
     "android.hardware.security.keymint.IRemotelyProvisionedComponent";
 
-/// Get the service name of a remotely provisioned component corresponding to given security level.
-pub fn get_remotely_provisioned_component_name(security_level: &SecurityLevel) -> Result<String> {
+/// Get the service name of the remotely provisioned component that belongs to the KeyMint device
+/// `instance` of the given security level. Remotely provisioned components are declared with the
+/// same instance name as the KeyMint device they provision.
+pub fn get_remotely_provisioned_component_name(
+    security_level: &SecurityLevel,
+    instance: &str,
+) -> Result<String> {
     let remotely_prov_instances =
         get_declared_instances(REMOTE_PROVISIONING_HAL_SERVICE_NAME).unwrap();
 
-    match *security_level {
-        SecurityLevel::TRUSTED_ENVIRONMENT => {
-            if remotely_prov_instances.iter().any(|instance| *instance == "default") {
-                Some(format!("{}/default", REMOTE_PROVISIONING_HAL_SERVICE_NAME))
-            } else {
-                None
-            }
-        }
-        SecurityLevel::STRONGBOX => {
-            if remotely_prov_instances.iter().any(|instance| *instance == "strongbox") {
-                Some(format!("{}/strongbox", REMOTE_PROVISIONING_HAL_SERVICE_NAME))
-            } else {
-                None
-            }
-        }
-        _ => None,
+    if is_keymint_instance_of(security_level, instance)
+        && remotely_prov_instances.iter().any(|declared| *declared == instance)
+    {
+        Some(format!("{}/{}", REMOTE_PROVISIONING_HAL_SERVICE_NAME, instance))
+    } else {
+        None
     }
     .ok_or(Error::Km(ErrorCode::HARDWARE_TYPE_UNAVAILABLE))
     .context(ks_err!())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strongbox_hw_info(name: &str) -> KeyMintHardwareInfo {
+        KeyMintHardwareInfo {
+            securityLevel: SecurityLevel::STRONGBOX,
+            keyMintName: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_keymint_instance_names() {
+        assert!(is_keymint_instance_of(&SecurityLevel::STRONGBOX, "strongbox"));
+        assert!(is_keymint_instance_of(&SecurityLevel::STRONGBOX, "strongbox_2"));
+        assert!(!is_keymint_instance_of(&SecurityLevel::STRONGBOX, "strongbox_"));
+        assert!(!is_keymint_instance_of(&SecurityLevel::STRONGBOX, "strongbox2"));
+        assert!(!is_keymint_instance_of(&SecurityLevel::STRONGBOX, "default"));
+        assert!(is_keymint_instance_of(&SecurityLevel::TRUSTED_ENVIRONMENT, "default"));
+        assert!(!is_keymint_instance_of(&SecurityLevel::TRUSTED_ENVIRONMENT, "strongbox_2"));
+        assert!(!is_keymint_instance_of(&SecurityLevel::SOFTWARE, "default"));
+    }
+
+    #[test]
+    fn test_multiple_strongbox_instances() -> Result<()> {
+        let mut devices = DevicesMap::<&'static str>::default();
+        let primary_uuid = devices.insert(
+            SecurityLevel::STRONGBOX,
+            "strongbox",
+            "primary mock",
+            strongbox_hw_info("primary"),
+        )?;
+        let second_uuid = devices.insert(
+            SecurityLevel::STRONGBOX,
+            "strongbox_2",
+            "second mock",
+            strongbox_hw_info("second"),
+        )?;
+
+        // The primary instance keeps the uuid that existing StrongBox keys are bound to.
+        assert_eq!(primary_uuid, SecurityLevel::STRONGBOX.into());
+        assert_ne!(primary_uuid, second_uuid);
+        assert_eq!(
+            second_uuid,
+            Uuid::from_keymint_instance(SecurityLevel::STRONGBOX, "strongbox_2")?
+        );
+
+        // Keys record the uuid of the instance that created them, so looking up a key's uuid
+        // must yield the instance the key belongs to.
+        let (dev, hw_info, _) = devices.dev_by_uuid(&primary_uuid).unwrap();
+        assert_eq!(dev, "primary mock");
+        assert_eq!(hw_info.keyMintName, "primary");
+        let (dev, hw_info, _) = devices.dev_by_uuid(&second_uuid).unwrap();
+        assert_eq!(dev, "second mock");
+        assert_eq!(hw_info.keyMintName, "second");
+
+        let (dev, _, uuid) = devices.dev_by_instance("strongbox_2").unwrap();
+        assert_eq!(dev, "second mock");
+        assert_eq!(uuid, second_uuid);
+        assert!(devices.dev_by_instance("strongbox_3").is_none());
+        assert_eq!(devices.devices().len(), 2);
+        Ok(())
+    }
+}
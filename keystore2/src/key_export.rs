@@ -0,0 +1,240 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the wrapping of key material for export to another device, e.g.,
+//! for device-to-device migration.
+//!
+//! The key material is encrypted to a public key of the recipient with ECDH and AES-256-GCM,
+//! see `ECDHPrivateKey::encrypt_message`. Only keys that were explicitly marked exportable may
+//! be wrapped, and only if they are not bound to secure hardware: the key blobs of TEE and
+//! StrongBox backed keys can only be used by the KeyMint instance that created them, so
+//! exporting them would be pointless at best.
+//!
+//! Wrapped key layout: `MAGIC | version | sender public key | salt | iv | ciphertext | tag`,
+//! where the version is a big endian u32 and each of the other fields is prefixed with its
+//! length as big endian u32.
+
+use crate::database::KeyEntry;
+use crate::ec_crypto::ECDHPrivateKey;
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use anyhow::{Context, Result};
+use keystore2_crypto::ZVec;
+use std::convert::TryInto;
+
+const MAGIC: &[u8; 8] = b"KS2EXPT\0";
+const FORMAT_VERSION: u32 = 1;
+
+/// Returns true if the key is bound to secure hardware, i.e., any of its parameters is enforced
+/// by a TEE or StrongBox.
+pub fn is_hardware_bound(key_entry: &KeyEntry) -> bool {
+    key_entry.key_parameters().iter().any(|kp| {
+        matches!(
+            *kp.security_level(),
+            SecurityLevel::TRUSTED_ENVIRONMENT | SecurityLevel::STRONGBOX
+        )
+    })
+}
+
+/// Checks that the key may be wrapped for export. The key must be marked exportable, else
+/// `ResponseCode::PERMISSION_DENIED`, it must not be frozen, else
+/// `ResponseCode::PERMISSION_DENIED`, and it must not be hardware bound, else
+/// `ResponseCode::INVALID_ARGUMENT`.
+pub fn check_exportable(key_entry: &KeyEntry) -> Result<()> {
+    if !key_entry.is_exportable() {
+        return Err(Error::perm()).context(ks_err!("Key {} is not exportable.", key_entry.id()));
+    }
+    if key_entry.is_frozen() {
+        return Err(Error::perm()).context(ks_err!("Key {} is frozen.", key_entry.id()));
+    }
+    if is_hardware_bound(key_entry) {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+            "Key {} is bound to secure hardware and cannot be exported.",
+            key_entry.id()
+        ));
+    }
+    Ok(())
+}
+
+/// Wraps `key_blob`, the plaintext key material of `key_entry`, i.e., without super encryption,
+/// for the recipient with the SEC1 encoded public key `recipient_public_key`. See
+/// `check_exportable` for the keys that may be wrapped.
+pub fn wrap_key_for_export(
+    key_entry: &KeyEntry,
+    key_blob: &[u8],
+    recipient_public_key: &[u8],
+) -> Result<Vec<u8>> {
+    check_exportable(key_entry).context(ks_err!())?;
+    let (sender_public_key, salt, iv, ciphertext, tag) =
+        ECDHPrivateKey::encrypt_message(recipient_public_key, key_blob)
+            .context(ks_err!("Failed to encrypt key material."))?;
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+    for field in [&sender_public_key, &salt, &iv, &ciphertext, &tag] {
+        let len: u32 = field.len().try_into().context(ks_err!("Field too large."))?;
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(field);
+    }
+    Ok(out)
+}
+
+fn malformed() -> Error {
+    Error::Rc(ResponseCode::INVALID_ARGUMENT)
+}
+
+/// Splits the first `n` bytes off `rest`.
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if rest.len() < n {
+        return Err(malformed()).context(ks_err!("Wrapped key is truncated."));
+    }
+    let (field, remainder) = rest.split_at(n);
+    *rest = remainder;
+    Ok(field)
+}
+
+/// Unwraps key material that was wrapped by `wrap_key_for_export` for `recipient`. Returns
+/// `ResponseCode::INVALID_ARGUMENT` if `wrapped` is malformed.
+pub fn unwrap_exported_key(recipient: &ECDHPrivateKey, wrapped: &[u8]) -> Result<ZVec> {
+    let mut rest = wrapped
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(malformed)
+        .context(ks_err!("Not a wrapped key."))?;
+    let version = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(malformed()).context(ks_err!("Unsupported format version {}.", version));
+    }
+    let mut fields = Vec::with_capacity(5);
+    for _ in 0..5 {
+        let len = u32::from_be_bytes(take(&mut rest, 4)?.try_into().unwrap());
+        fields.push(take(&mut rest, len as usize)?);
+    }
+    if !rest.is_empty() {
+        return Err(malformed()).context(ks_err!("Trailing data after wrapped key."));
+    }
+    recipient
+        .decrypt_message(fields[0], fields[1], fields[2], fields[3], fields[4])
+        .context(ks_err!("Failed to decrypt key material."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::new_test_db;
+    use crate::database::{
+        BlobInfo, BlobMetaData, CertificateInfo, KeyEntryLoadBits, KeyMetaData, KeyMetaEntry,
+        KeyType, KeystoreDB, KEYSTORE_UUID,
+    };
+    use crate::key_parameter::{KeyParameter, KeyParameterValue};
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::Algorithm::Algorithm;
+    use android_system_keystore2::aidl::android::system::keystore2::{
+        Domain::Domain, KeyDescriptor::KeyDescriptor,
+    };
+
+    const KEY_BLOB: &[u8] = b"software key material";
+
+    fn store_key(
+        db: &mut KeystoreDB,
+        alias: &str,
+        security_level: SecurityLevel,
+        exportable: bool,
+    ) -> Result<KeyEntry> {
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::Exportable(exportable));
+        db.store_new_key(
+            &key,
+            KeyType::Client,
+            &[KeyParameter::new(KeyParameterValue::Algorithm(Algorithm::AES), security_level)],
+            &BlobInfo::new(KEY_BLOB, &BlobMetaData::new()),
+            &CertificateInfo::new(None, None),
+            &metadata,
+            &KEYSTORE_UUID,
+        )?;
+        let (_, key_entry) =
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 1, |_, _| Ok(()))?;
+        Ok(key_entry)
+    }
+
+    fn assert_error(result: Result<Vec<u8>>, expected: Error) {
+        assert_eq!(result.unwrap_err().root_cause().downcast_ref::<Error>(), Some(&expected));
+    }
+
+    #[test]
+    fn test_export_round_trip() -> Result<()> {
+        let mut db = new_test_db()?;
+        let key_entry = store_key(&mut db, "exportable", SecurityLevel::SOFTWARE, true)?;
+        let (blob, _) = key_entry.key_blob_info().as_ref().unwrap();
+        let recipient = ECDHPrivateKey::generate()?;
+
+        let wrapped = wrap_key_for_export(&key_entry, blob, &recipient.public_key()?)?;
+        assert!(!wrapped.windows(KEY_BLOB.len()).any(|w| w == KEY_BLOB));
+        let unwrapped = unwrap_exported_key(&recipient, &wrapped)?;
+        assert_eq!(&*unwrapped, KEY_BLOB);
+
+        // Only the recipient can unwrap the key material.
+        assert!(unwrap_exported_key(&ECDHPrivateKey::generate()?, &wrapped).is_err());
+        // Truncated or extended wrapped keys are rejected.
+        let extended = [wrapped.as_slice(), &[0u8][..]].concat();
+        for malformed in [&wrapped[..wrapped.len() - 1], extended.as_slice()] {
+            assert_eq!(
+                unwrap_exported_key(&recipient, malformed)
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_exportable_software_keys_are_exported() -> Result<()> {
+        let mut db = new_test_db()?;
+        let recipient = ECDHPrivateKey::generate()?.public_key()?;
+
+        let key_entry = store_key(&mut db, "not_exportable", SecurityLevel::SOFTWARE, false)?;
+        assert_error(wrap_key_for_export(&key_entry, KEY_BLOB, &recipient), Error::perm());
+
+        for security_level in [SecurityLevel::TRUSTED_ENVIRONMENT, SecurityLevel::STRONGBOX] {
+            let key_entry = store_key(&mut db, "hardware_bound", security_level, true)?;
+            assert!(is_hardware_bound(&key_entry));
+            assert_error(
+                wrap_key_for_export(&key_entry, KEY_BLOB, &recipient),
+                Error::Rc(ResponseCode::INVALID_ARGUMENT),
+            );
+        }
+
+        // Exportable keys may be marked not exportable again.
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some("exportable".to_string()),
+            blob: None,
+        };
+        let key_entry = store_key(&mut db, "exportable", SecurityLevel::KEYSTORE, true)?;
+        assert!(!is_hardware_bound(&key_entry));
+        assert!(wrap_key_for_export(&key_entry, KEY_BLOB, &recipient).is_ok());
+        db.set_key_exportable(&key, KeyType::Client, 1, false, |_, _| Ok(()))?;
+        let (_, key_entry) =
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 1, |_, _| Ok(()))?;
+        assert_error(wrap_key_for_export(&key_entry, KEY_BLOB, &recipient), Error::perm());
+        Ok(())
+    }
+}
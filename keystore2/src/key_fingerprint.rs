@@ -0,0 +1,198 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the fingerprints of keys, i.e., the digests of their certificates.
+//!
+//! Every fingerprint is stored together with the hash algorithm that computed it, so that the
+//! algorithm can be changed, e.g., from SHA-256 to something stronger, without breaking the
+//! fingerprints that are already stored. New fingerprints are computed with the algorithm that
+//! the `keystore.fingerprint_algorithm` system property selects. Stored fingerprints keep their
+//! algorithm until the certificate of their key is replaced. Fingerprints that were stored
+//! before the algorithm was recorded are SHA-256 digests.
+//!
+//! Fingerprints computed with different algorithms never compare equal. To compare them anyway,
+//! one of them has to be recomputed with the algorithm of the other from the certificate that
+//! it was computed from.
+
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use crate::sysprop::read_prop_parsed;
+use android_security_maintenance::aidl::android::security::maintenance::{
+    FingerprintAlgorithm::FingerprintAlgorithm as AidlFingerprintAlgorithm,
+    KeyFingerprint::KeyFingerprint as AidlKeyFingerprint,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::{sha256, sha512};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef};
+
+/// The algorithm of new fingerprints: "sha256", the default, or "sha512".
+const FINGERPRINT_ALGORITHM_PROPERTY: &str = "keystore.fingerprint_algorithm";
+
+/// The hash algorithm of a key fingerprint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FingerprintAlgorithm {
+    /// SHA-256, the algorithm of all fingerprints that were stored without an algorithm.
+    Sha256,
+    /// SHA-512.
+    Sha512,
+}
+
+impl FingerprintAlgorithm {
+    /// Reads the algorithm of new fingerprints from the `keystore.fingerprint_algorithm` system
+    /// property. The property is read on every call, so changes take effect with the next
+    /// certificate that is stored.
+    pub fn from_property() -> Self {
+        read_prop_parsed(FINGERPRINT_ALGORITHM_PROPERTY, Self::Sha256, |value| match value {
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        })
+    }
+
+    /// Computes the digest of `data` with this algorithm.
+    pub fn digest(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Sha256 => sha256(data),
+            Self::Sha512 => sha512(data),
+        }
+        .context(ks_err!("Failed to compute the {:?} digest.", self))
+    }
+
+    /// Returns the AIDL representation of the algorithm.
+    pub fn to_aidl(self) -> AidlFingerprintAlgorithm {
+        match self {
+            Self::Sha256 => AidlFingerprintAlgorithm::SHA_256,
+            Self::Sha512 => AidlFingerprintAlgorithm::SHA_512,
+        }
+    }
+}
+
+// The algorithms are stored with the values of their AIDL representation.
+impl ToSql for FingerprintAlgorithm {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::Owned(Value::Integer(self.to_aidl().0.into())))
+    }
+}
+
+impl FromSql for FingerprintAlgorithm {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match i64::column_result(value)? {
+            v if v == AidlFingerprintAlgorithm::SHA_256.0 as i64 => Ok(Self::Sha256),
+            v if v == AidlFingerprintAlgorithm::SHA_512.0 as i64 => Ok(Self::Sha512),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
+/// The fingerprint of a key, i.e., the digest of its certificate tagged with the algorithm that
+/// computed it. Two fingerprints are only equal if both their algorithms and their digests are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyFingerprint {
+    /// The algorithm that computed the digest.
+    pub algorithm: FingerprintAlgorithm,
+    /// The digest of the certificate.
+    pub digest: Vec<u8>,
+}
+
+impl KeyFingerprint {
+    /// Computes the fingerprint of `cert` with `algorithm`.
+    pub fn compute(algorithm: FingerprintAlgorithm, cert: &[u8]) -> Result<Self> {
+        Ok(Self { algorithm, digest: algorithm.digest(cert).context(ks_err!())? })
+    }
+
+    /// Returns true if the fingerprint was computed from `cert`.
+    pub fn matches_certificate(&self, cert: &[u8]) -> Result<bool> {
+        Ok(self.algorithm.digest(cert).context(ks_err!())? == self.digest)
+    }
+
+    /// Returns whether the fingerprints are of the same certificate, or None if they were
+    /// computed with different algorithms and cannot be compared, see `recompute`.
+    pub fn compare(&self, other: &Self) -> Option<bool> {
+        if self.algorithm == other.algorithm {
+            Some(self.digest == other.digest)
+        } else {
+            None
+        }
+    }
+
+    /// Recomputes the fingerprint with `algorithm` from `cert`, e.g., to compare it with a
+    /// fingerprint of another algorithm. Fails with `ResponseCode::INVALID_ARGUMENT` if the
+    /// fingerprint was not computed from `cert`.
+    pub fn recompute(&self, algorithm: FingerprintAlgorithm, cert: &[u8]) -> Result<Self> {
+        if !self.matches_certificate(cert).context(ks_err!())? {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("The fingerprint was not computed from the certificate."));
+        }
+        Self::compute(algorithm, cert).context(ks_err!())
+    }
+
+    /// Returns the AIDL representation of the fingerprint.
+    pub fn to_aidl(&self) -> AidlKeyFingerprint {
+        AidlKeyFingerprint { algorithm: self.algorithm.to_aidl(), digest: self.digest.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &[u8] = b"certificate";
+    const OTHER_CERT: &[u8] = b"other certificate";
+
+    #[test]
+    fn test_algorithms_are_distinguishable() -> Result<()> {
+        let sha256_fingerprint = KeyFingerprint::compute(FingerprintAlgorithm::Sha256, CERT)?;
+        let sha512_fingerprint = KeyFingerprint::compute(FingerprintAlgorithm::Sha512, CERT)?;
+        assert_eq!(sha256_fingerprint.digest, sha256(CERT)?);
+        assert_eq!(sha512_fingerprint.digest, sha512(CERT)?);
+        assert_ne!(sha256_fingerprint, sha512_fingerprint);
+        assert_ne!(sha256_fingerprint.to_aidl(), sha512_fingerprint.to_aidl());
+
+        // Equal digests with different algorithms are still different fingerprints.
+        let mislabeled = KeyFingerprint {
+            algorithm: FingerprintAlgorithm::Sha512,
+            digest: sha256_fingerprint.digest.clone(),
+        };
+        assert_ne!(mislabeled, sha256_fingerprint);
+        assert_eq!(sha256_fingerprint.compare(&mislabeled), None);
+        assert!(!mislabeled.matches_certificate(CERT)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_recompute() -> Result<()> {
+        let old = KeyFingerprint::compute(FingerprintAlgorithm::Sha256, CERT)?;
+        let new = KeyFingerprint::compute(FingerprintAlgorithm::Sha512, CERT)?;
+        let other = KeyFingerprint::compute(FingerprintAlgorithm::Sha512, OTHER_CERT)?;
+        assert_eq!(new.compare(&new.clone()), Some(true));
+        assert_eq!(new.compare(&other), Some(false));
+        assert_eq!(old.compare(&new), None);
+
+        // Recomputing makes fingerprints of different algorithms comparable.
+        let recomputed = old.recompute(FingerprintAlgorithm::Sha512, CERT)?;
+        assert_eq!(recomputed, new);
+        assert_eq!(recomputed.compare(&other), Some(false));
+        assert_eq!(new.recompute(FingerprintAlgorithm::Sha256, CERT)?, old);
+
+        // A fingerprint cannot be recomputed from another certificate.
+        assert_eq!(
+            old.recompute(FingerprintAlgorithm::Sha512, OTHER_CERT)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        );
+        Ok(())
+    }
+}
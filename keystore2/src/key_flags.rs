@@ -0,0 +1,74 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module defines the keystore specific flags of `generateKey` and `importKey`.
+//!
+//! They extend the flags defined by IKeystoreSecurityLevel, and start well above them, so that
+//! new flags of the interface do not collide with them. Each flag is a distinct bit. The details
+//! of each flag are documented where keystore applies it, mostly in `crate::security_level`.
+//! Options that carry a value are keystore specific key parameters instead, see
+//! `crate::key_tags`.
+
+/// App keys can sign while the device is locked, and be used for all purposes while it is
+/// unlocked.
+pub const KEY_FLAG_SIGN_ONLY_WHILE_LOCKED: i32 = 0x10000;
+
+/// An attestation challenge longer than `MAX_ATTESTATION_CHALLENGE_LEN` is replaced by its
+/// SHA-256 digest, which the caller must then expect in the attestation.
+pub const KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE: i32 = 0x20000;
+
+/// Tests specify the creation date through `Tag::CREATION_DATETIME`.
+pub const KEY_FLAG_TEST_CREATION_DATETIME: i32 = 0x80000;
+
+/// Keystore verifies the attestation of the new key before storing it.
+pub const KEY_FLAG_VERIFY_ATTESTATION: i32 = 0x100000;
+
+/// Tests specify the certificate validity window explicitly.
+pub const KEY_FLAG_TEST_CERTIFICATE_VALIDITY: i32 = 0x200000;
+
+/// Keystore refuses to use the key after its `Tag::USAGE_EXPIRE_DATETIME` and deletes it.
+pub const KEY_FLAG_DELETE_ON_EXPIRY: i32 = 0x1000000;
+
+/// Tests attest the verified boot state configured by system properties.
+pub const KEY_FLAG_TEST_VERIFIED_BOOT_STATE: i32 = 0x2000000;
+
+/// Creating a key under an alias that is already in use fails instead of replacing the key.
+pub const KEY_FLAG_FAIL_IF_EXISTS: i32 = 0x4000000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_system_keystore2::aidl::android::system::keystore2::IKeystoreSecurityLevel::KEY_FLAG_AUTH_BOUND_WITHOUT_CRYPTOGRAPHIC_LSKF_BINDING;
+
+    #[test]
+    fn test_key_flags_are_distinct() {
+        let flags = [
+            KEY_FLAG_AUTH_BOUND_WITHOUT_CRYPTOGRAPHIC_LSKF_BINDING,
+            KEY_FLAG_SIGN_ONLY_WHILE_LOCKED,
+            KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE,
+            KEY_FLAG_TEST_CREATION_DATETIME,
+            KEY_FLAG_VERIFY_ATTESTATION,
+            KEY_FLAG_TEST_CERTIFICATE_VALIDITY,
+            KEY_FLAG_DELETE_ON_EXPIRY,
+            KEY_FLAG_TEST_VERIFIED_BOOT_STATE,
+            KEY_FLAG_FAIL_IF_EXISTS,
+        ];
+        let mut seen = 0;
+        for flag in flags {
+            assert_eq!(flag.count_ones(), 1, "{:#x} is not a single bit", flag);
+            assert_eq!(seen & flag, 0, "{:#x} is defined twice", flag);
+            seen |= flag;
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module allows observing the lifecycle of client keys.
+//!
+//! Observers get notified when a key is created, used in an operation, or deleted. Events only
+//! carry non-sensitive information. They are delivered on the logs handler thread, so observers
+//! never delay the code paths that emit the events, and they must not rely on being notified
+//! before the emitting request completes. Events are delivered in the order they were emitted.
+//! Clients register an `IKeyLifecycleObserver` through `IKeystoreMaintenance`, which is wrapped
+//! in a `BinderObserver`.
+
+use crate::database::DateTime;
+use crate::globals::LOGS_HANDLER;
+use android_security_maintenance::aidl::android::security::maintenance::{
+    IKeyLifecycleObserver::IKeyLifecycleObserver,
+    KeyLifecycleEvent::KeyLifecycleEvent as AidlEvent,
+    KeyLifecycleEventType::KeyLifecycleEventType as AidlEventType,
+};
+use android_security_maintenance::binder::{Interface, SpIBinder, StatusCode, Strong};
+use android_system_keystore2::binder::ThreadState;
+use lazy_static::lazy_static;
+use std::sync::{Arc, Mutex};
+
+/// The kind of a key lifecycle event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyLifecycleEventType {
+    /// A new key was stored in the database.
+    Created,
+    /// An operation was started with the key.
+    Used,
+    /// The key was deleted from the database.
+    Deleted,
+}
+
+/// A key lifecycle event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyLifecycleEvent {
+    /// The database id of the key.
+    pub key_id: i64,
+    /// The UID of the caller that caused the event.
+    pub uid: u32,
+    /// What happened to the key.
+    pub event_type: KeyLifecycleEventType,
+    /// When it happened.
+    pub timestamp: DateTime,
+}
+
+impl KeyLifecycleEvent {
+    fn to_aidl(&self) -> AidlEvent {
+        AidlEvent {
+            keyId: self.key_id,
+            uid: self.uid as i32,
+            eventType: match self.event_type {
+                KeyLifecycleEventType::Created => AidlEventType::CREATED,
+                KeyLifecycleEventType::Used => AidlEventType::USED,
+                KeyLifecycleEventType::Deleted => AidlEventType::DELETED,
+            },
+            timestampMs: self.timestamp.to_millis_epoch(),
+        }
+    }
+}
+
+/// Observers of key lifecycle events.
+pub trait KeyLifecycleObserver: Send + Sync {
+    /// Called for each key lifecycle event on the logs handler thread.
+    fn on_key_lifecycle_event(&self, event: &KeyLifecycleEvent);
+}
+
+lazy_static! {
+    static ref OBSERVERS: Mutex<Vec<Arc<dyn KeyLifecycleObserver>>> = Default::default();
+    /// The observers registered through binder, by the binder object of the client's observer.
+    static ref BINDER_OBSERVERS: Mutex<Vec<(SpIBinder, Arc<dyn KeyLifecycleObserver>)>> =
+        Default::default();
+}
+
+/// Registers an observer for all subsequent key lifecycle events.
+pub fn register_observer(observer: Arc<dyn KeyLifecycleObserver>) {
+    OBSERVERS.lock().unwrap().push(observer);
+}
+
+/// Unregisters an observer that was registered with `register_observer`. Events emitted before
+/// this call may still be delivered to it.
+pub fn unregister_observer(observer: &Arc<dyn KeyLifecycleObserver>) {
+    OBSERVERS.lock().unwrap().retain(|o| !Arc::ptr_eq(o, observer));
+}
+
+/// Forwards the events to an `IKeyLifecycleObserver` of a client.
+struct BinderObserver(Strong<dyn IKeyLifecycleObserver>);
+
+impl KeyLifecycleObserver for BinderObserver {
+    fn on_key_lifecycle_event(&self, event: &KeyLifecycleEvent) {
+        match self.0.onKeyLifecycleEvent(&event.to_aidl()) {
+            Ok(()) => {}
+            Err(e) if e.transaction_error() == StatusCode::DEAD_OBJECT => {
+                log::info!("Key lifecycle observer died, unregistering it.");
+                unregister_binder_observer(&self.0);
+            }
+            Err(e) => log::warn!("Failed to notify key lifecycle observer: {:?}", e),
+        }
+    }
+}
+
+/// Registers the observer of a client. Registering the same observer again has no effect.
+pub fn register_binder_observer(observer: &Strong<dyn IKeyLifecycleObserver>) {
+    let mut binder_observers = BINDER_OBSERVERS.lock().unwrap();
+    let binder = observer.as_binder();
+    if binder_observers.iter().any(|(b, _)| *b == binder) {
+        return;
+    }
+    let wrapped: Arc<dyn KeyLifecycleObserver> = Arc::new(BinderObserver(observer.clone()));
+    register_observer(wrapped.clone());
+    binder_observers.push((binder, wrapped));
+}
+
+/// Unregisters the observer of a client, if it is registered.
+pub fn unregister_binder_observer(observer: &Strong<dyn IKeyLifecycleObserver>) {
+    let mut binder_observers = BINDER_OBSERVERS.lock().unwrap();
+    let binder = observer.as_binder();
+    if let Some(index) = binder_observers.iter().position(|(b, _)| *b == binder) {
+        let (_, wrapped) = binder_observers.remove(index);
+        unregister_observer(&wrapped);
+    }
+}
+
+/// Dispatches the event to the observers registered at this time. This returns without waiting
+/// for the observers.
+fn notify(key_id: i64, uid: u32, event_type: KeyLifecycleEventType) {
+    let observers = OBSERVERS.lock().unwrap().clone();
+    if observers.is_empty() {
+        return;
+    }
+    let timestamp = match DateTime::now() {
+        Ok(timestamp) => timestamp,
+        Err(e) => {
+            log::error!("Failed to get the time for a key lifecycle event: {:?}", e);
+            return;
+        }
+    };
+    let event = KeyLifecycleEvent { key_id, uid, event_type, timestamp };
+    LOGS_HANDLER.queue_lo(move |_| {
+        for observer in observers {
+            observer.on_key_lifecycle_event(&event);
+        }
+    });
+}
+
+/// Emits a `Created` event on behalf of the current binder caller.
+pub fn notify_key_created(key_id: i64) {
+    notify(key_id, ThreadState::get_calling_uid(), KeyLifecycleEventType::Created);
+}
+
+/// Emits a `Used` event.
+pub fn notify_key_used(key_id: i64, uid: u32) {
+    notify(key_id, uid, KeyLifecycleEventType::Used);
+}
+
+/// Emits a `Deleted` event.
+pub fn notify_key_deleted(key_id: i64, uid: u32) {
+    notify(key_id, uid, KeyLifecycleEventType::Deleted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        BlobInfo, BlobMetaData, CertificateInfo, KeyMetaData, KeyType, KeystoreDB, KEYSTORE_UUID,
+    };
+    use android_system_keystore2::aidl::android::system::keystore2::{
+        Domain::Domain, KeyDescriptor::KeyDescriptor,
+    };
+    use anyhow::Result;
+    use std::sync::mpsc::channel;
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<KeyLifecycleEvent>>,
+    }
+
+    impl KeyLifecycleObserver for RecordingObserver {
+        fn on_key_lifecycle_event(&self, event: &KeyLifecycleEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    /// Waits until all events emitted so far have been delivered.
+    fn flush() {
+        let (sender, receiver) = channel();
+        LOGS_HANDLER.queue_lo(move |_| sender.send(()).unwrap());
+        receiver.recv().unwrap();
+    }
+
+    #[test]
+    fn test_create_use_delete() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let recorder = Arc::new(RecordingObserver::default());
+        let observer: Arc<dyn KeyLifecycleObserver> = recorder.clone();
+        register_observer(observer.clone());
+
+        let uid = ThreadState::get_calling_uid();
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: uid as i64,
+            alias: Some("lifecycle_key".to_string()),
+            blob: None,
+        };
+        let before = DateTime::now()?;
+        let key_id = db
+            .store_new_key(
+                &key,
+                KeyType::Client,
+                &[],
+                &BlobInfo::new(b"key blob", &BlobMetaData::new()),
+                &CertificateInfo::new(None, None),
+                &KeyMetaData::new(),
+                &KEYSTORE_UUID,
+            )?
+            .id();
+        notify_key_used(key_id, uid);
+        db.unbind_key(&key, KeyType::Client, uid, |_, _| Ok(()))?;
+        flush();
+        let after = DateTime::now()?;
+        unregister_observer(&observer);
+
+        // Other tests may emit events concurrently.
+        let events: Vec<KeyLifecycleEvent> = recorder
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.key_id == key_id)
+            .cloned()
+            .collect();
+        assert_eq!(
+            events.iter().map(|e| (e.event_type, e.uid)).collect::<Vec<_>>(),
+            vec![
+                (KeyLifecycleEventType::Created, uid),
+                (KeyLifecycleEventType::Used, uid),
+                (KeyLifecycleEventType::Deleted, uid),
+            ]
+        );
+        assert!(events.iter().all(|e| before <= e.timestamp && e.timestamp <= after));
+        assert!(events.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        // Unregistered observers are not notified anymore.
+        notify_key_used(key_id, uid);
+        flush();
+        assert_eq!(
+            recorder.events.lock().unwrap().iter().filter(|e| e.key_id == key_id).count(),
+            3
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,618 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the migration of keys to a more secure KeyMint backend, e.g., from
+//! software to the TEE, or to StrongBox once a device gains one.
+//!
+//! Key blobs can only be used by the KeyMint instance that created them, so the key blob itself
+//! cannot be moved. Instead, an equivalent key is created on the target instance from the
+//! authorizations of the original key. If the caller holds the key material of the original
+//! key, it is imported, so the key material stays the same. Otherwise, the key can only be
+//! regenerated, which irreversibly replaces its key material, and with it, e.g., its public
+//! key. So keys are only regenerated if the caller explicitly allows it. Imported key material
+//! must be verified to be that of the original key, which is only possible for asymmetric keys
+//! by the public key in their certificate. So symmetric keys, and asymmetric keys without a
+//! certificate, can only be regenerated.
+//!
+//! Keys with an attestation chain cannot migrate either way. The attestation of the equivalent
+//! key would require the attestation challenge and application id of the original request,
+//! which keystore does not keep, so the chain could not be reissued.
+//!
+//! The equivalent key is staged next to the original key, which stays in use. Only once the
+//! caller commits the migration, the key entry is updated to reference the new key blob, its
+//! characteristics, and its certificates in one transaction. The original key blob is
+//! superseded by that update and deleted from its KeyMint instance by the garbage collector.
+//! If the caller discards the migration instead, the staged key is deleted and the original key
+//! is kept. Keys that are already bound to secure hardware cannot migrate, because their key
+//! material never leaves that hardware.
+
+use crate::database::{
+    BlobInfo, BlobMetaData, BlobMetaEntry, CertificateInfo, KeyEntry, KeyIdGuard, KeystoreDB, Uuid,
+};
+use crate::error::{Error, ResponseCode};
+use crate::key_export::is_hardware_bound;
+use crate::key_parameter::{KeyParameter, KeyParameterValue};
+use crate::ks_err;
+use crate::raw_device::KeyMintDevice;
+use crate::utils::key_characteristics_to_internal;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, KeyCreationResult::KeyCreationResult, KeyFormat::KeyFormat,
+    KeyParameter::KeyParameter as KmKeyParameter, SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::certificate_public_keys_match;
+
+/// Tags that are added by KeyMint or keystore when a key is created. They describe the key but
+/// must not be requested when the equivalent key is generated.
+const GENERATED_TAGS: &[Tag] = &[
+    Tag::ORIGIN,
+    Tag::OS_VERSION,
+    Tag::OS_PATCHLEVEL,
+    Tag::VENDOR_PATCHLEVEL,
+    Tag::BOOT_PATCHLEVEL,
+    Tag::CREATION_DATETIME,
+    Tag::ROOT_OF_TRUST,
+    Tag::UNIQUE_ID,
+    Tag::USER_ID,
+];
+
+/// A KeyMint instance to which keys can migrate.
+pub trait MigrationTarget {
+    /// Returns the security level of the instance.
+    fn security_level(&self) -> SecurityLevel;
+    /// Returns the uuid of the instance.
+    fn km_uuid(&self) -> Uuid;
+    /// Generates a key with `params` on the instance.
+    fn generate_key(&self, params: &[KmKeyParameter]) -> Result<KeyCreationResult>;
+    /// Imports `key_material` in `format` as a key with `params` on the instance.
+    fn import_key(
+        &self,
+        params: &[KmKeyParameter],
+        format: KeyFormat,
+        key_material: &[u8],
+    ) -> Result<KeyCreationResult>;
+}
+
+impl MigrationTarget for KeyMintDevice {
+    fn security_level(&self) -> SecurityLevel {
+        KeyMintDevice::security_level(self)
+    }
+
+    fn km_uuid(&self) -> Uuid {
+        KeyMintDevice::km_uuid(self)
+    }
+
+    fn generate_key(&self, params: &[KmKeyParameter]) -> Result<KeyCreationResult> {
+        KeyMintDevice::generate_key(self, params)
+    }
+
+    fn import_key(
+        &self,
+        params: &[KmKeyParameter],
+        format: KeyFormat,
+        key_material: &[u8],
+    ) -> Result<KeyCreationResult> {
+        KeyMintDevice::import_key(self, params, format, key_material)
+    }
+}
+
+/// How the equivalent key is created on the target instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationSource<'a> {
+    /// The PKCS#8 encoded key material of the original asymmetric key is imported, so the key
+    /// material stays the same.
+    Import(&'a [u8]),
+    /// A new key is generated, which replaces the key material.
+    Regenerate,
+}
+
+impl<'a> MigrationSource<'a> {
+    /// Returns the source for a caller that gave `key_material`, if it holds the key material
+    /// of the key, and that may or may not `allow_regeneration`. Fails with
+    /// `ResponseCode::INVALID_ARGUMENT` if the key would have to be regenerated but the caller
+    /// did not allow it.
+    pub fn select(key_material: Option<&'a [u8]>, allow_regeneration: bool) -> Result<Self> {
+        match (key_material, allow_regeneration) {
+            (Some(key_material), _) => Ok(Self::Import(key_material)),
+            (None, true) => Ok(Self::Regenerate),
+            (None, false) => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                "Without its key material, the key can only migrate by regeneration."
+            )),
+        }
+    }
+}
+
+/// Checks that `key_entry` may migrate to a KeyMint instance with `target` security level.
+/// Fails with `ResponseCode::INVALID_ARGUMENT` if the key is bound to secure hardware, if it has
+/// an attestation chain, or if the target is not secure hardware. Fails with
+/// `ResponseCode::PERMISSION_DENIED` if the key is frozen.
+pub fn check_migratable(key_entry: &KeyEntry, target: SecurityLevel) -> Result<()> {
+    if !matches!(target, SecurityLevel::TRUSTED_ENVIRONMENT | SecurityLevel::STRONGBOX) {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Keys can only migrate to secure hardware, not to {:?}.", target));
+    }
+    if key_entry.is_frozen() {
+        return Err(Error::perm()).context(ks_err!("Key {} is frozen.", key_entry.id()));
+    }
+    if is_hardware_bound(key_entry) {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+            "Key {} is bound to secure hardware and cannot migrate.",
+            key_entry.id()
+        ));
+    }
+    if key_entry.cert_chain().is_some() {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+            "Key {} has an attestation chain, which cannot be reissued.",
+            key_entry.id()
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the parameters with which the equivalent of `key_entry` is generated, i.e., its
+/// authorizations without those added by KeyMint or keystore.
+pub fn generation_params(key_entry: &KeyEntry) -> Vec<KmKeyParameter> {
+    let mut params: Vec<KmKeyParameter> = vec![];
+    for kp in key_entry.key_parameters() {
+        if GENERATED_TAGS.contains(&kp.get_tag()) {
+            continue;
+        }
+        let param: KmKeyParameter = kp.key_parameter_value().clone().into();
+        // Keystore and software enforced copies of the same authorization are requested once.
+        if !params.contains(&param) {
+            params.push(param);
+        }
+    }
+    params
+}
+
+/// Returns the format in which the key material of `key_entry` is imported. Only asymmetric
+/// keys can be imported, see `migrate_key`.
+fn import_format(key_entry: &KeyEntry) -> Result<KeyFormat> {
+    let algorithm =
+        key_entry.key_parameters().iter().find_map(|kp| match kp.key_parameter_value() {
+            KeyParameterValue::Algorithm(algorithm) => Some(*algorithm),
+            _ => None,
+        });
+    match algorithm {
+        Some(Algorithm::RSA | Algorithm::EC) => Ok(KeyFormat::PKCS8),
+        _ => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Key {} cannot be imported.", key_entry.id())),
+    }
+}
+
+/// Stages the migration of the key entry `key_entry`, whose lock is held by `key_id`, to the
+/// KeyMint instance `target`, see `KeystoreDB::stage_key_backend`. The equivalent key is
+/// created from `source`. If the key material is imported, the certificates of both keys must
+/// certify the same public key, else `ResponseCode::INVALID_ARGUMENT`. So keys without a
+/// certificate, e.g., symmetric keys, cannot be imported, only regenerated. The
+/// new key blob is passed through `encrypt`, which returns the blob to be stored and its
+/// metadata, so that the super encryption of the original key blob can be reapplied. The key
+/// entry is left unchanged until the migration is committed with `KeystoreDB::commit_key_backend`.
+/// Returns the key parameters of the new key.
+pub fn migrate_key<F>(
+    db: &mut KeystoreDB,
+    key_id: &KeyIdGuard,
+    key_entry: &KeyEntry,
+    target: &dyn MigrationTarget,
+    source: MigrationSource,
+    encrypt: F,
+) -> Result<Vec<KeyParameter>>
+where
+    F: FnOnce(&[u8]) -> Result<(Vec<u8>, Option<BlobMetaData>)>,
+{
+    check_migratable(key_entry, target.security_level()).context(ks_err!())?;
+
+    let params = generation_params(key_entry);
+    let KeyCreationResult {
+        keyBlob: key_blob,
+        keyCharacteristics: key_characteristics,
+        certificateChain: mut certificate_chain,
+    } = match source {
+        MigrationSource::Import(key_material) => {
+            if key_entry.cert().is_none() {
+                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                    "Key {} has no certificate to verify the key material with.",
+                    key_entry.id()
+                ));
+            }
+            let format = import_format(key_entry).context(ks_err!())?;
+            target
+                .import_key(&params, format, key_material)
+                .context(ks_err!("Failed to import the key material."))?
+        }
+        MigrationSource::Regenerate => target
+            .generate_key(&params)
+            .context(ks_err!("Failed to generate the equivalent key."))?,
+    };
+    if let (MigrationSource::Import(_), Some(cert)) = (source, key_entry.cert().as_deref()) {
+        let matches = match certificate_chain.first() {
+            Some(new_cert) => {
+                matches!(
+                    certificate_public_keys_match(&new_cert.encodedCertificate, cert),
+                    Ok(true)
+                )
+            }
+            None => false,
+        };
+        if !matches {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("The imported key material is not that of the key."));
+        }
+    }
+
+    let cert_info = CertificateInfo::new(
+        match certificate_chain.len() {
+            0 => None,
+            _ => Some(certificate_chain.remove(0).encodedCertificate),
+        },
+        match certificate_chain.len() {
+            0 => None,
+            _ => Some(
+                certificate_chain
+                    .iter()
+                    .flat_map(|c| c.encodedCertificate.iter())
+                    .copied()
+                    .collect(),
+            ),
+        },
+    );
+
+    let mut key_parameters = key_characteristics_to_internal(key_characteristics);
+    // The user id is a keystore parameter of the key entry, not of the key.
+    key_parameters.extend(
+        key_entry.key_parameters().iter().filter(|kp| kp.get_tag() == Tag::USER_ID).cloned(),
+    );
+
+    let (blob, blob_metadata) =
+        encrypt(&key_blob).context(ks_err!("Failed to encrypt the new key blob."))?;
+    let mut blob_metadata = blob_metadata.unwrap_or_default();
+    blob_metadata.add(BlobMetaEntry::KmUuid(target.km_uuid()));
+
+    db.stage_key_backend(
+        key_id,
+        &key_parameters,
+        &BlobInfo::new(&blob, &blob_metadata),
+        &cert_info,
+        &target.km_uuid(),
+    )
+    .context(ks_err!("Failed to stage the equivalent key."))?;
+    log::info!(
+        "Staged the migration of key {} to {:?} by {}.",
+        key_id.id(),
+        target.security_level(),
+        match source {
+            MigrationSource::Import(_) => "import",
+            MigrationSource::Regenerate => "regeneration",
+        }
+    );
+    Ok(key_parameters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::new_test_db;
+    use crate::database::{
+        KeyEntryLoadBits, KeyMetaData, KeyType, SubComponentType, KEYSTORE_UUID,
+    };
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        Certificate::Certificate, KeyCharacteristics::KeyCharacteristics, KeyOrigin::KeyOrigin,
+        KeyPurpose::KeyPurpose,
+    };
+    use android_system_keystore2::aidl::android::system::keystore2::{
+        Domain::Domain, KeyDescriptor::KeyDescriptor,
+    };
+    use std::cell::RefCell;
+
+    const SOFTWARE_BLOB: &[u8] = b"software key blob";
+    const TEE_BLOB: &[u8] = b"tee key blob";
+
+    /// A TEE that generates or imports keys with the requested parameters, or fails.
+    struct MockTee {
+        fail: bool,
+        requested: RefCell<Vec<KmKeyParameter>>,
+        imported: RefCell<Option<(KeyFormat, Vec<u8>)>>,
+    }
+
+    impl MockTee {
+        fn new(fail: bool) -> Self {
+            Self { fail, requested: RefCell::new(vec![]), imported: RefCell::new(None) }
+        }
+    }
+
+    impl MigrationTarget for MockTee {
+        fn security_level(&self) -> SecurityLevel {
+            SecurityLevel::TRUSTED_ENVIRONMENT
+        }
+
+        fn km_uuid(&self) -> Uuid {
+            SecurityLevel::TRUSTED_ENVIRONMENT.into()
+        }
+
+        fn generate_key(&self, params: &[KmKeyParameter]) -> Result<KeyCreationResult> {
+            if self.fail {
+                return Err(Error::sys()).context("Mock TEE failure.");
+            }
+            *self.requested.borrow_mut() = params.to_vec();
+            let mut authorizations = params.to_vec();
+            authorizations.push(KeyParameterValue::KeyOrigin(KeyOrigin::GENERATED).into());
+            Ok(KeyCreationResult {
+                keyBlob: TEE_BLOB.to_vec(),
+                keyCharacteristics: vec![KeyCharacteristics {
+                    securityLevel: SecurityLevel::TRUSTED_ENVIRONMENT,
+                    authorizations,
+                }],
+                certificateChain: vec![
+                    Certificate { encodedCertificate: b"tee leaf".to_vec() },
+                    Certificate { encodedCertificate: b"tee root".to_vec() },
+                ],
+            })
+        }
+
+        fn import_key(
+            &self,
+            params: &[KmKeyParameter],
+            format: KeyFormat,
+            key_material: &[u8],
+        ) -> Result<KeyCreationResult> {
+            *self.imported.borrow_mut() = Some((format, key_material.to_vec()));
+            let mut result = self.generate_key(params)?;
+            if !params.contains(&KeyParameterValue::Algorithm(Algorithm::EC).into()) {
+                // Symmetric keys have no certificates.
+                result.certificateChain.clear();
+            }
+            Ok(result)
+        }
+    }
+
+    fn key(alias: &str) -> KeyDescriptor {
+        KeyDescriptor { domain: Domain::APP, nspace: 1, alias: Some(alias.to_string()), blob: None }
+    }
+
+    fn store_key(
+        db: &mut KeystoreDB,
+        alias: &str,
+        algorithm: Algorithm,
+        security_level: SecurityLevel,
+    ) -> Result<(KeyIdGuard, KeyEntry)> {
+        let params = [
+            KeyParameter::new(KeyParameterValue::Algorithm(algorithm), security_level),
+            KeyParameter::new(KeyParameterValue::KeyPurpose(KeyPurpose::SIGN), security_level),
+            KeyParameter::new(KeyParameterValue::KeyOrigin(KeyOrigin::GENERATED), security_level),
+            KeyParameter::new(KeyParameterValue::OSVersion(13), security_level),
+            KeyParameter::new(KeyParameterValue::CreationDateTime(1000), SecurityLevel::KEYSTORE),
+            KeyParameter::new(KeyParameterValue::UserID(0), SecurityLevel::SOFTWARE),
+        ];
+        let mut blob_metadata = BlobMetaData::new();
+        blob_metadata.add(BlobMetaEntry::KmUuid(KEYSTORE_UUID));
+        db.store_new_key(
+            &key(alias),
+            KeyType::Client,
+            &params,
+            &BlobInfo::new(SOFTWARE_BLOB, &blob_metadata),
+            &CertificateInfo::new(Some(b"software cert".to_vec()), None),
+            &KeyMetaData::new(),
+            &KEYSTORE_UUID,
+        )?;
+        db.load_key_entry(&key(alias), KeyType::Client, KeyEntryLoadBits::BOTH, 1, |_, _| Ok(()))
+    }
+
+    fn not_encrypted(blob: &[u8]) -> Result<(Vec<u8>, Option<BlobMetaData>)> {
+        Ok((blob.to_vec(), None))
+    }
+
+    fn load_key(db: &mut KeystoreDB, alias: &str) -> Result<(KeyIdGuard, KeyEntry)> {
+        db.load_key_entry(&key(alias), KeyType::Client, KeyEntryLoadBits::BOTH, 1, |_, _| Ok(()))
+    }
+
+    fn is_invalid_argument<T: std::fmt::Debug>(result: Result<T>) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>()
+            == Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT))
+    }
+
+    #[test]
+    fn test_migrate_software_key_to_tee() -> Result<()> {
+        let mut db = new_test_db()?;
+        let (key_id, key_entry) =
+            store_key(&mut db, "software", Algorithm::EC, SecurityLevel::SOFTWARE)?;
+        let tee = MockTee::new(false);
+
+        migrate_key(
+            &mut db,
+            &key_id,
+            &key_entry,
+            &tee,
+            MigrationSource::Regenerate,
+            not_encrypted,
+        )?;
+
+        // Only the authorizations chosen by the caller are requested.
+        assert_eq!(
+            *tee.requested.borrow(),
+            vec![
+                KmKeyParameter::from(KeyParameterValue::Algorithm(Algorithm::EC)),
+                KeyParameterValue::KeyPurpose(KeyPurpose::SIGN).into(),
+            ]
+        );
+        assert!(tee.imported.borrow().is_none());
+
+        // The original key stays in use until the migration is committed.
+        drop(key_id);
+        let (key_id, staged) = load_key(&mut db, "software")?;
+        assert_eq!(staged.key_blob_info(), key_entry.key_blob_info());
+        assert_eq!(staged.km_uuid(), &KEYSTORE_UUID);
+        assert!(db.handle_next_superseded_blobs(&[], 10)?.is_empty());
+        db.commit_key_backend(&key_id)?;
+        drop(key_id);
+
+        let (_, key_entry) = load_key(&mut db, "software")?;
+        let (blob, blob_metadata) = key_entry.key_blob_info().as_ref().unwrap();
+        assert_eq!(blob, TEE_BLOB);
+        assert_eq!(blob_metadata.km_uuid(), Some(&tee.km_uuid()));
+        assert_eq!(key_entry.km_uuid(), &tee.km_uuid());
+        assert!(is_hardware_bound(&key_entry));
+        assert!(key_entry
+            .key_parameters()
+            .iter()
+            .any(|kp| *kp.key_parameter_value() == KeyParameterValue::UserID(0)));
+        assert_eq!(key_entry.cert().as_deref(), Some(&b"tee leaf"[..]));
+        assert_eq!(key_entry.cert_chain().as_deref(), Some(&b"tee root"[..]));
+
+        // The software key blob is only deleted now, by the garbage collector.
+        let superseded = db.handle_next_superseded_blobs(&[], 10)?;
+        assert_eq!(superseded.len(), 1);
+        assert_eq!(superseded[0].2, SOFTWARE_BLOB);
+        assert_eq!(superseded[0].3.km_uuid(), Some(&KEYSTORE_UUID));
+
+        // Now that the key lives in the TEE, it cannot migrate again, and nothing is staged.
+        let (key_id, key_entry) = load_key(&mut db, "software")?;
+        assert!(is_invalid_argument(migrate_key(
+            &mut db,
+            &key_id,
+            &key_entry,
+            &tee,
+            MigrationSource::Regenerate,
+            not_encrypted
+        )));
+        assert_eq!(
+            db.commit_key_backend(&key_id).unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::KEY_NOT_FOUND))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_regeneration_requires_opt_in() {
+        assert!(is_invalid_argument(MigrationSource::select(None, false)));
+        assert_eq!(MigrationSource::select(None, true).unwrap(), MigrationSource::Regenerate);
+        assert_eq!(
+            MigrationSource::select(Some(b"key material"), false).unwrap(),
+            MigrationSource::Import(b"key material")
+        );
+    }
+
+    #[test]
+    fn test_migrate_by_import() -> Result<()> {
+        let mut db = new_test_db()?;
+        let (key_id, key_entry) =
+            store_key(&mut db, "symmetric", Algorithm::AES, SecurityLevel::SOFTWARE)?;
+        let tee = MockTee::new(false);
+
+        // Symmetric key material cannot be verified, so it is not imported.
+        let source = MigrationSource::Import(b"aes key material");
+        assert!(is_invalid_argument(migrate_key(
+            &mut db,
+            &key_id,
+            &key_entry,
+            &tee,
+            source,
+            not_encrypted
+        )));
+        assert!(tee.imported.borrow().is_none());
+        assert!(db.handle_next_superseded_blobs(&[], 10)?.is_empty());
+
+        // Asymmetric key material must match the public key of the original key.
+        let (key_id, key_entry) =
+            store_key(&mut db, "asymmetric", Algorithm::EC, SecurityLevel::SOFTWARE)?;
+        let source = MigrationSource::Import(b"other key material");
+        assert!(is_invalid_argument(migrate_key(
+            &mut db,
+            &key_id,
+            &key_entry,
+            &tee,
+            source,
+            not_encrypted
+        )));
+        assert_eq!(tee.imported.borrow().as_ref().unwrap().0, KeyFormat::PKCS8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_attested_key_cannot_migrate() -> Result<()> {
+        let mut db = new_test_db()?;
+        let (key_id, _) = store_key(&mut db, "attested", Algorithm::EC, SecurityLevel::SOFTWARE)?;
+        db.set_blob(&key_id, SubComponentType::CERT_CHAIN, Some(b"attestation chain"), None)?;
+        drop(key_id);
+        let (key_id, key_entry) = load_key(&mut db, "attested")?;
+        let tee = MockTee::new(false);
+
+        assert!(is_invalid_argument(check_migratable(&key_entry, tee.security_level())));
+        assert!(is_invalid_argument(migrate_key(
+            &mut db,
+            &key_id,
+            &key_entry,
+            &tee,
+            MigrationSource::Regenerate,
+            not_encrypted
+        )));
+        assert!(tee.requested.borrow().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_discarded_migration_keeps_key() -> Result<()> {
+        let mut db = new_test_db()?;
+        let (key_id, key_entry) =
+            store_key(&mut db, "software", Algorithm::EC, SecurityLevel::SOFTWARE)?;
+        let tee = MockTee::new(false);
+
+        // Staging again replaces the staged key, which the garbage collector deletes.
+        for _ in 0..2 {
+            let source = MigrationSource::Regenerate;
+            migrate_key(&mut db, &key_id, &key_entry, &tee, source, not_encrypted)?;
+        }
+        let superseded = db.handle_next_superseded_blobs(&[], 10)?;
+        assert_eq!(superseded.len(), 1);
+        assert_eq!(superseded[0].2, TEE_BLOB);
+
+        db.discard_key_backend(&key_id)?;
+        let superseded = db.handle_next_superseded_blobs(&[superseded[0].0], 10)?;
+        assert_eq!(superseded.len(), 1);
+        assert_eq!(superseded[0].2, TEE_BLOB);
+        assert_eq!(superseded[0].3.km_uuid(), Some(&tee.km_uuid()));
+        drop(key_id);
+
+        let (key_id, reloaded) = load_key(&mut db, "software")?;
+        assert_eq!(reloaded.key_blob_info(), key_entry.key_blob_info());
+        assert_eq!(reloaded.km_uuid(), &KEYSTORE_UUID);
+        assert_eq!(
+            db.discard_key_backend(&key_id).unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::KEY_NOT_FOUND))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_migration_leaves_key_unchanged() -> Result<()> {
+        let mut db = new_test_db()?;
+        let (key_id, key_entry) =
+            store_key(&mut db, "software", Algorithm::EC, SecurityLevel::SOFTWARE)?;
+
+        let tee = MockTee::new(true);
+        let source = MigrationSource::Regenerate;
+        assert!(migrate_key(&mut db, &key_id, &key_entry, &tee, source, not_encrypted).is_err());
+        drop(key_id);
+
+        let (_, reloaded) = load_key(&mut db, "software")?;
+        assert_eq!(reloaded.key_blob_info(), key_entry.key_blob_info());
+        assert_eq!(reloaded.km_uuid(), &KEYSTORE_UUID);
+        assert_eq!(reloaded.key_parameters(), key_entry.key_parameters());
+        assert!(db.handle_next_superseded_blobs(&[], 10)?.is_empty());
+
+        // Keys can only migrate to secure hardware.
+        assert!(is_invalid_argument(check_migratable(&reloaded, SecurityLevel::SOFTWARE)));
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the operation logs of keys, which help debugging a specific key.
+//!
+//! Logging is opt-in per key. Once it is enabled for a key, every operation with the key is
+//! recorded when it is finalized, with its purpose, its outcome, and the time at which it was
+//! finalized, but never its parameters or data. Each log is a ring buffer of bounded size that
+//! drops its oldest record when it is full. The logs are kept in memory only, so they do not
+//! survive a restart of keystore.
+
+use crate::clock_rollback::Clock;
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use crate::operation::Outcome;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::KeyPurpose::KeyPurpose;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The largest number of records that the log of a key holds.
+pub const MAX_LOG_CAPACITY: usize = 64;
+
+/// The maximum number of keys whose operations are logged at the same time.
+const MAX_LOGGED_KEYS: usize = 16;
+
+lazy_static! {
+    /// The operation logs of keystore.
+    pub static ref KEY_OPERATION_LOGS: KeyOperationLogs = Default::default();
+}
+
+/// The record of one finalized operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyOperationRecord {
+    /// The purpose of the operation.
+    pub purpose: KeyPurpose,
+    /// The outcome of the operation.
+    pub outcome: Outcome,
+    /// The wall clock time in milliseconds since the epoch at which the operation was
+    /// finalized.
+    pub timestamp_millis: i64,
+}
+
+/// The ring buffer of the records of one key.
+struct KeyOperationLog {
+    capacity: usize,
+    records: VecDeque<KeyOperationRecord>,
+}
+
+/// The operation logs of the keys for which logging is enabled.
+#[derive(Default)]
+pub struct KeyOperationLogs {
+    logs: Mutex<HashMap<i64, KeyOperationLog>>,
+}
+
+impl KeyOperationLogs {
+    /// Enables logging the operations of the key `key_id` into a log of `capacity` records, or
+    /// disables it if `capacity` is zero, which discards the log. Changing the capacity of an
+    /// enabled log keeps its most recent records. Fails with `ResponseCode::INVALID_ARGUMENT`
+    /// if `capacity` exceeds `MAX_LOG_CAPACITY`, and with `ResponseCode::BACKEND_BUSY` if the
+    /// operations of too many keys are logged already.
+    pub fn set_capacity(&self, key_id: i64, capacity: usize) -> Result<()> {
+        if capacity > MAX_LOG_CAPACITY {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("The capacity must be at most {}.", MAX_LOG_CAPACITY));
+        }
+        let mut logs = self.logs.lock().unwrap();
+        if capacity == 0 {
+            logs.remove(&key_id);
+            return Ok(());
+        }
+        if !logs.contains_key(&key_id) && logs.len() >= MAX_LOGGED_KEYS {
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(ks_err!("The operations of too many keys are logged."));
+        }
+        let log = logs
+            .entry(key_id)
+            .or_insert_with(|| KeyOperationLog { capacity, records: VecDeque::new() });
+        log.capacity = capacity;
+        while log.records.len() > capacity {
+            log.records.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Records an operation with `purpose` on the key `key_id` that was finalized with
+    /// `outcome` at the wall clock time of `clock`, if logging is enabled for the key.
+    pub fn record(&self, clock: &dyn Clock, key_id: i64, purpose: KeyPurpose, outcome: Outcome) {
+        let mut logs = self.logs.lock().unwrap();
+        if let Some(log) = logs.get_mut(&key_id) {
+            if log.records.len() >= log.capacity {
+                log.records.pop_front();
+            }
+            log.records.push_back(KeyOperationRecord {
+                purpose,
+                outcome,
+                timestamp_millis: clock.wall_millis(),
+            });
+        }
+    }
+
+    /// Returns the records of the key `key_id`, oldest first, or None if logging is not
+    /// enabled for the key.
+    pub fn records(&self, key_id: i64) -> Option<Vec<KeyOperationRecord>> {
+        self.logs.lock().unwrap().get(&key_id).map(|log| log.records.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+    use crate::error::ErrorCode;
+    use std::sync::Arc;
+
+    const KEY_ID: i64 = 42;
+
+    #[test]
+    fn test_log_records_operations_in_order() -> Result<()> {
+        let clock = Arc::new(FakeClock::default());
+        let logs = KeyOperationLogs::default();
+
+        // Operations are only recorded once logging is enabled.
+        logs.record(&clock, KEY_ID, KeyPurpose::SIGN, Outcome::Success);
+        assert_eq!(logs.records(KEY_ID), None);
+
+        logs.set_capacity(KEY_ID, 3)?;
+        assert_eq!(logs.records(KEY_ID), Some(vec![]));
+        let operations = [
+            (KeyPurpose::SIGN, Outcome::Success),
+            (KeyPurpose::VERIFY, Outcome::Abort),
+            (KeyPurpose::SIGN, Outcome::ErrorCode(ErrorCode::KEY_EXPIRED)),
+            (KeyPurpose::DECRYPT, Outcome::Pruned),
+            (KeyPurpose::SIGN, Outcome::Dropped),
+        ];
+        for (purpose, outcome) in operations {
+            clock.advance(1000);
+            logs.record(&clock, KEY_ID, purpose, outcome);
+            // Other keys are not logged.
+            logs.record(&clock, KEY_ID + 1, purpose, outcome);
+        }
+
+        // The log keeps the most recent operations, oldest first.
+        let expected: Vec<KeyOperationRecord> = operations[2..]
+            .iter()
+            .zip([3000, 4000, 5000])
+            .map(|((purpose, outcome), timestamp_millis)| KeyOperationRecord {
+                purpose: *purpose,
+                outcome: *outcome,
+                timestamp_millis,
+            })
+            .collect();
+        assert_eq!(logs.records(KEY_ID), Some(expected.clone()));
+        assert_eq!(logs.records(KEY_ID + 1), None);
+
+        // Shrinking the log drops the oldest records, disabling it discards them.
+        logs.set_capacity(KEY_ID, 1)?;
+        assert_eq!(logs.records(KEY_ID), Some(expected[2..].to_vec()));
+        logs.set_capacity(KEY_ID, 0)?;
+        assert_eq!(logs.records(KEY_ID), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_size_is_bounded() -> Result<()> {
+        let logs = KeyOperationLogs::default();
+        assert_eq!(
+            logs.set_capacity(KEY_ID, MAX_LOG_CAPACITY + 1)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT))
+        );
+
+        for key_id in 0..MAX_LOGGED_KEYS as i64 {
+            logs.set_capacity(key_id, MAX_LOG_CAPACITY)?;
+        }
+        assert_eq!(
+            logs.set_capacity(MAX_LOGGED_KEYS as i64, 1)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::BACKEND_BUSY))
+        );
+        // Logs that are enabled already can be changed.
+        logs.set_capacity(0, 1)?;
+        Ok(())
+    }
+}
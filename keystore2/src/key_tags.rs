@@ -0,0 +1,174 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module defines the keystore specific key parameters of `generateKey` and
+//! `createOperation`.
+//!
+//! They carry options of the request that keystore applies itself. Keystore takes them from the
+//! parameters of the request before it applies any policy, so they are never passed to KeyMint
+//! or stored with the key. Their tags are bytes tags with numbers well above those defined by
+//! KeyMint, so that new KeyMint tags do not collide with them. The details of each parameter
+//! are documented where keystore applies it, mostly in `crate::security_level`.
+
+use crate::error::{Error, ErrorCode};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, Tag::Tag, TagType::TagType,
+};
+use anyhow::{Context, Result};
+
+/// An idempotency key, so that retries of the request return the same key.
+pub const KEY_TAG_IDEMPOTENCY_KEY: Tag = Tag(TagType::BYTES.0 | 0x7000);
+
+/// The UTF-8 encoded name of an attestation template.
+pub const KEY_TAG_ATTESTATION_TEMPLATE: Tag = Tag(TagType::BYTES.0 | 0x7001);
+
+/// The package that the attestation application id names.
+pub const KEY_TAG_ATTESTATION_PACKAGE: Tag = Tag(TagType::BYTES.0 | 0x7002);
+
+/// The id of a lost operation that `createOperation` resumes, see `crate::operation_checkpoint`.
+/// It only applies to `createOperation`, which handles it separately, so it is not among the
+/// tags of `KeystoreParams`.
+pub const KEY_TAG_RESUME_OPERATION: Tag = Tag(TagType::ULONG.0 | 0x7010);
+
+const KEY_TAGS: [Tag; 3] =
+    [KEY_TAG_IDEMPOTENCY_KEY, KEY_TAG_ATTESTATION_TEMPLATE, KEY_TAG_ATTESTATION_PACKAGE];
+
+/// Returns true if `tag` is a keystore specific tag.
+pub fn is_keystore_tag(tag: Tag) -> bool {
+    KEY_TAGS.contains(&tag)
+}
+
+/// The keystore specific parameters of a key generation request.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeystoreParams {
+    /// See `KEY_TAG_IDEMPOTENCY_KEY`.
+    pub idempotency_key: Option<Vec<u8>>,
+    /// See `KEY_TAG_ATTESTATION_TEMPLATE`.
+    pub attestation_template: Option<String>,
+    /// See `KEY_TAG_ATTESTATION_PACKAGE`.
+    pub attestation_package: Option<Vec<u8>>,
+}
+
+impl KeystoreParams {
+    /// Takes the keystore specific parameters from `params`. Returns them, and the remaining
+    /// parameters if `params` had any keystore specific parameters. Fails with
+    /// `ErrorCode::INVALID_ARGUMENT` if a keystore specific parameter is given more than once,
+    /// if its value is not a non-empty blob, or if the template name is not valid UTF-8.
+    pub fn take(params: &[KeyParameter]) -> Result<(Self, Option<Vec<KeyParameter>>)> {
+        if !params.iter().any(|kp| is_keystore_tag(kp.tag)) {
+            return Ok((Self::default(), None));
+        }
+        let mut result = Self::default();
+        let mut remaining = Vec::with_capacity(params.len());
+        for kp in params {
+            if !is_keystore_tag(kp.tag) {
+                remaining.push(kp.clone());
+                continue;
+            }
+            let value = match &kp.value {
+                KeyParameterValue::Blob(value) if !value.is_empty() => value.clone(),
+                value => {
+                    return Err(Error::Km(ErrorCode::INVALID_ARGUMENT)).context(ks_err!(
+                        "Invalid value {:?} of tag {:?}.",
+                        value,
+                        kp.tag
+                    ))
+                }
+            };
+            let duplicate = match kp.tag {
+                KEY_TAG_IDEMPOTENCY_KEY => result.idempotency_key.replace(value).is_some(),
+                KEY_TAG_ATTESTATION_TEMPLATE => {
+                    let name = String::from_utf8(value)
+                        .map_err(|_| Error::Km(ErrorCode::INVALID_ARGUMENT))
+                        .context(ks_err!("The attestation template name is not valid UTF-8."))?;
+                    result.attestation_template.replace(name).is_some()
+                }
+                _ => result.attestation_package.replace(value).is_some(),
+            };
+            if duplicate {
+                return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Tag {:?} is given more than once.", kp.tag));
+            }
+        }
+        Ok((result, Some(remaining)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob_param(tag: Tag, value: &[u8]) -> KeyParameter {
+        KeyParameter { tag, value: KeyParameterValue::Blob(value.to_vec()) }
+    }
+
+    fn is_invalid_argument<T: std::fmt::Debug>(result: Result<T>) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>()
+            == Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+    }
+
+    #[test]
+    fn test_keystore_params_are_taken() -> Result<()> {
+        let challenge = blob_param(Tag::ATTESTATION_CHALLENGE, b"challenge");
+
+        // Requests without keystore specific parameters are passed on as they are.
+        assert_eq!(KeystoreParams::take(&[challenge.clone()])?, (KeystoreParams::default(), None));
+
+        let (keystore_params, remaining) = KeystoreParams::take(&[
+            blob_param(KEY_TAG_IDEMPOTENCY_KEY, b"request 1"),
+            challenge.clone(),
+            blob_param(KEY_TAG_ATTESTATION_TEMPLATE, b"template"),
+            blob_param(KEY_TAG_ATTESTATION_PACKAGE, b"com.example.a"),
+        ])?;
+        assert_eq!(
+            keystore_params,
+            KeystoreParams {
+                idempotency_key: Some(b"request 1".to_vec()),
+                attestation_template: Some("template".to_string()),
+                attestation_package: Some(b"com.example.a".to_vec()),
+            }
+        );
+        assert_eq!(remaining, Some(vec![challenge]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_keystore_params_are_rejected() {
+        for params in [
+            vec![blob_param(KEY_TAG_IDEMPOTENCY_KEY, b"")],
+            vec![blob_param(KEY_TAG_ATTESTATION_TEMPLATE, &[0xff, 0xfe])],
+            vec![KeyParameter {
+                tag: KEY_TAG_ATTESTATION_PACKAGE,
+                value: KeyParameterValue::Integer(1),
+            }],
+            vec![
+                blob_param(KEY_TAG_ATTESTATION_PACKAGE, b"com.example.a"),
+                blob_param(KEY_TAG_ATTESTATION_PACKAGE, b"com.example.b"),
+            ],
+        ] {
+            assert!(is_invalid_argument(KeystoreParams::take(&params)), "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn test_keystore_tags_are_not_keymint_tags() {
+        for tag in KEY_TAGS.into_iter().chain([KEY_TAG_RESUME_OPERATION]) {
+            assert_eq!(
+                crate::key_parameter::KeyParameterValue::from(blob_param(tag, b"v")).get_tag(),
+                Tag::INVALID
+            );
+        }
+    }
+}
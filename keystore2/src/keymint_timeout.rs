@@ -0,0 +1,233 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the timeouts of KeyMint calls.
+//!
+//! The types of KeyMint calls have very different latencies, e.g., generating an attested key
+//! can take seconds while a signature usually takes milliseconds. So every type of call has its
+//! own timeout, which can be configured with a system property. A call that takes longer than
+//! its timeout is reported by the watchdog while it is running, and a metric with the type of
+//! the call is logged when it returns. A timeout of zero disables both for its type of call.
+
+use crate::metrics_store::log_keymint_timeout;
+use crate::sysprop::read_prop_duration;
+use crate::utils::watchdog as wd;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, SecurityLevel::SecurityLevel, Tag::Tag,
+};
+use std::time::{Duration, Instant};
+
+/// The types of KeyMint calls that have their own timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMintCallType {
+    /// Generating a key without attestation.
+    Generate,
+    /// Generating a key with attestation.
+    AttestedGenerate,
+    /// Importing a key, wrapped or not.
+    Import,
+    /// Beginning an operation.
+    Begin,
+    /// Updating, finishing, or aborting an operation, e.g., a signature.
+    Operation,
+    /// All other calls, e.g., deleting a key.
+    Other,
+}
+
+impl KeyMintCallType {
+    /// Returns the type of a call that generates a key with `params`.
+    pub fn of_generation(params: &[KeyParameter]) -> Self {
+        if params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
+            Self::AttestedGenerate
+        } else {
+            Self::Generate
+        }
+    }
+
+    /// The system property that configures the timeout of this type of call.
+    fn property(self) -> &'static str {
+        match self {
+            Self::Generate => "keystore.keymint_timeout.generate",
+            Self::AttestedGenerate => "keystore.keymint_timeout.attested_generate",
+            Self::Import => "keystore.keymint_timeout.import",
+            Self::Begin => "keystore.keymint_timeout.begin",
+            Self::Operation => "keystore.keymint_timeout.operation",
+            Self::Other => "keystore.keymint_timeout.other",
+        }
+    }
+
+    /// The timeout of this type of call if its property is not set.
+    fn default_timeout(self) -> Duration {
+        match self {
+            Self::Generate => Duration::from_secs(2),
+            // Attestation can take a little longer.
+            Self::AttestedGenerate => Duration::from_secs(5),
+            Self::Import | Self::Begin | Self::Operation | Self::Other => {
+                Duration::from_millis(500)
+            }
+        }
+    }
+
+    /// Reads the timeout of this type of call from its system property. The property is read on
+    /// every call, so changes take effect with the next KeyMint call.
+    pub fn timeout(self) -> Duration {
+        self.timeout_with(read_prop_duration)
+    }
+
+    fn timeout_with<F>(self, read: F) -> Duration
+    where
+        F: FnOnce(&str, Duration) -> Duration,
+    {
+        read(self.property(), self.default_timeout())
+    }
+}
+
+/// Watches a KeyMint call while it is alive. It must be created right before the call and
+/// dropped right after it.
+pub struct KeyMintCallWatch {
+    call_type: KeyMintCallType,
+    sec_level: SecurityLevel,
+    timeout: Duration,
+    started: Instant,
+    _wp: Option<wd::WatchPoint>,
+}
+
+impl KeyMintCallWatch {
+    /// Starts watching a KeyMint call of `call_type` on the backend of `sec_level` with the
+    /// timeout configured for `call_type`. The watchdog reports the call with `id`.
+    pub fn new(call_type: KeyMintCallType, sec_level: SecurityLevel, id: &'static str) -> Self {
+        Self::with_timeout(call_type, sec_level, id, call_type.timeout())
+    }
+
+    fn with_timeout(
+        call_type: KeyMintCallType,
+        sec_level: SecurityLevel,
+        id: &'static str,
+        timeout: Duration,
+    ) -> Self {
+        let _wp = if timeout.is_zero() {
+            None
+        } else {
+            wd::watch_millis_with(id, timeout.as_millis() as u64, move || {
+                format!("SecurityLevel {:?}, {:?}", sec_level, call_type)
+            })
+        };
+        Self { call_type, sec_level, timeout, started: Instant::now(), _wp }
+    }
+
+    /// Logs the timeout metric if a call that took `elapsed` exceeded the timeout. Returns true
+    /// if it did.
+    fn report(&self, elapsed: Duration) -> bool {
+        if self.timeout.is_zero() || elapsed <= self.timeout {
+            return false;
+        }
+        log::warn!(
+            "KeyMint call {:?} on {:?} took {} ms, longer than its timeout of {} ms.",
+            self.call_type,
+            self.sec_level,
+            elapsed.as_millis(),
+            self.timeout.as_millis()
+        );
+        log_keymint_timeout(self.call_type, self.sec_level);
+        true
+    }
+}
+
+impl Drop for KeyMintCallWatch {
+    fn drop(&mut self) {
+        self.report(self.started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const CALL_TYPES: [KeyMintCallType; 6] = [
+        KeyMintCallType::Generate,
+        KeyMintCallType::AttestedGenerate,
+        KeyMintCallType::Import,
+        KeyMintCallType::Begin,
+        KeyMintCallType::Operation,
+        KeyMintCallType::Other,
+    ];
+
+    #[test]
+    fn test_each_call_type_uses_its_timeout() {
+        // Every type of call has its own property.
+        let configured: HashMap<&str, Duration> = CALL_TYPES
+            .iter()
+            .enumerate()
+            .map(|(i, call_type)| {
+                (call_type.property(), Duration::from_millis(100 * (i as u64 + 1)))
+            })
+            .collect();
+        assert_eq!(configured.len(), CALL_TYPES.len());
+
+        for (i, call_type) in CALL_TYPES.iter().enumerate() {
+            let timeout = call_type.timeout_with(|name, default| {
+                assert_eq!(default, call_type.default_timeout());
+                configured.get(name).copied().unwrap_or(default)
+            });
+            assert_eq!(timeout, Duration::from_millis(100 * (i as u64 + 1)), "{:?}", call_type);
+
+            // Without a configuration, the default applies.
+            assert_eq!(
+                call_type.timeout_with(|_, default| default),
+                call_type.default_timeout(),
+                "{:?}",
+                call_type
+            );
+        }
+        assert!(
+            KeyMintCallType::AttestedGenerate.default_timeout()
+                > KeyMintCallType::Operation.default_timeout()
+        );
+    }
+
+    #[test]
+    fn test_generation_call_type() {
+        let challenge = KeyParameter { tag: Tag::ATTESTATION_CHALLENGE, ..Default::default() };
+        let other = KeyParameter { tag: Tag::ALGORITHM, ..Default::default() };
+        assert_eq!(KeyMintCallType::of_generation(&[]), KeyMintCallType::Generate);
+        assert_eq!(KeyMintCallType::of_generation(&[other.clone()]), KeyMintCallType::Generate);
+        assert_eq!(
+            KeyMintCallType::of_generation(&[other, challenge]),
+            KeyMintCallType::AttestedGenerate
+        );
+    }
+
+    #[test]
+    fn test_report_timeout() {
+        let timeout = Duration::from_millis(200);
+        let watch = KeyMintCallWatch::with_timeout(
+            KeyMintCallType::Begin,
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+            "test_report_timeout",
+            timeout,
+        );
+        assert!(!watch.report(timeout));
+        assert!(watch.report(timeout + Duration::from_millis(1)));
+
+        // A timeout of zero disables the report.
+        let watch = KeyMintCallWatch::with_timeout(
+            KeyMintCallType::Begin,
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+            "test_report_timeout",
+            Duration::ZERO,
+        );
+        assert!(!watch.report(Duration::from_secs(60)));
+    }
+}
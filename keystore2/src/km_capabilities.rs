@@ -0,0 +1,339 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module validates key generation parameters against the capabilities of a KeyMint
+//! backend without generating a key.
+//!
+//! The capabilities of a backend are the key sizes, EC curves, digests, and purposes that the
+//! KeyMint specification requires of a backend of its security level and with its features, see
+//! `km_features`. A request that passes the validation may still be rejected by KeyMint, e.g.,
+//! for combinations of parameters that are inconsistent regardless of the backend, but a request
+//! that fails it would be rejected by KeyMint with the same error code.
+
+use crate::error::{Error, ErrorCode};
+use crate::km_features::{get_backend_info, KeyMintFeatures};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, KeyParameter::KeyParameter,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    Tag::Tag,
+};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// The EC curves and the key sizes that select them.
+const EC_CURVE_SIZES: [(EcCurve, i32); 5] = [
+    (EcCurve::P_224, 224),
+    (EcCurve::P_256, 256),
+    (EcCurve::P_384, 384),
+    (EcCurve::P_521, 521),
+    (EcCurve::CURVE_25519, 256),
+];
+
+/// What a KeyMint backend supports, per algorithm where the support depends on the algorithm.
+#[derive(Debug, Clone, Default)]
+pub struct BackendCapabilities {
+    key_sizes: HashMap<Algorithm, Vec<i32>>,
+    purposes: HashMap<Algorithm, Vec<KeyPurpose>>,
+    ec_curves: Vec<EcCurve>,
+    digests: Vec<Digest>,
+}
+
+impl BackendCapabilities {
+    /// Returns the capabilities that the KeyMint specification requires of a backend of the
+    /// given security level with the given features. StrongBox implementations are only required
+    /// to support 2048 bit RSA keys, the curve P-256, and the digest SHA-256.
+    pub fn for_backend(security_level: SecurityLevel, features: KeyMintFeatures) -> Self {
+        let is_strongbox = security_level == SecurityLevel::STRONGBOX;
+        let mut ec_curves = vec![EcCurve::P_256];
+        if !is_strongbox {
+            ec_curves.extend([EcCurve::P_224, EcCurve::P_384]);
+        }
+        if features.contains(KeyMintFeatures::EC_CURVE_P_521) {
+            ec_curves.push(EcCurve::P_521);
+        }
+        if features.contains(KeyMintFeatures::CURVE_25519) {
+            ec_curves.push(EcCurve::CURVE_25519);
+        }
+        let ec_key_sizes = EC_CURVE_SIZES
+            .iter()
+            .filter(|(curve, _)| ec_curves.contains(curve))
+            .map(|(_, size)| *size)
+            .collect();
+        let digests = if is_strongbox {
+            vec![Digest::NONE, Digest::SHA_2_256]
+        } else {
+            vec![
+                Digest::NONE,
+                Digest::MD5,
+                Digest::SHA1,
+                Digest::SHA_2_224,
+                Digest::SHA_2_256,
+                Digest::SHA_2_384,
+                Digest::SHA_2_512,
+            ]
+        };
+
+        let key_sizes = HashMap::from([
+            (Algorithm::RSA, if is_strongbox { vec![2048] } else { vec![1024, 2048, 3072, 4096] }),
+            (Algorithm::EC, ec_key_sizes),
+            (Algorithm::AES, if is_strongbox { vec![128, 256] } else { vec![128, 192, 256] }),
+            (Algorithm::HMAC, (64..=512).step_by(8).collect()),
+            (Algorithm::TRIPLE_DES, vec![168]),
+        ]);
+
+        let mut asymmetric_purposes = vec![KeyPurpose::SIGN, KeyPurpose::VERIFY];
+        if features.contains(KeyMintFeatures::ATTEST_KEY) {
+            asymmetric_purposes.push(KeyPurpose::ATTEST_KEY);
+        }
+        let mut rsa_purposes = asymmetric_purposes.clone();
+        rsa_purposes.extend([KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT, KeyPurpose::WRAP_KEY]);
+        let mut ec_purposes = asymmetric_purposes;
+        ec_purposes.push(KeyPurpose::AGREE_KEY);
+        let cipher_purposes = vec![KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT];
+        let purposes = HashMap::from([
+            (Algorithm::RSA, rsa_purposes),
+            (Algorithm::EC, ec_purposes),
+            (Algorithm::AES, cipher_purposes.clone()),
+            (Algorithm::HMAC, vec![KeyPurpose::SIGN, KeyPurpose::VERIFY]),
+            (Algorithm::TRIPLE_DES, cipher_purposes),
+        ]);
+
+        Self { key_sizes, purposes, ec_curves, digests }
+    }
+
+    /// Checks the parameters of a key generation against the capabilities. An algorithm that is
+    /// missing or not supported fails with `ErrorCode::UNSUPPORTED_ALGORITHM`. A key size,
+    /// purpose, or EC curve that is not supported for the algorithm fails with
+    /// `ErrorCode::UNSUPPORTED_KEY_SIZE`, `ErrorCode::UNSUPPORTED_PURPOSE`, or
+    /// `ErrorCode::UNSUPPORTED_EC_CURVE` respectively. A digest that is not supported fails with
+    /// `ErrorCode::UNSUPPORTED_DIGEST`, or `ErrorCode::UNSUPPORTED_MGF_DIGEST` for the digest of
+    /// the OAEP mask generation function.
+    pub fn validate(&self, params: &[KeyParameter]) -> Result<()> {
+        let algorithm = match params.iter().find_map(|kp| match kp.value {
+            KeyParameterValue::Algorithm(algorithm) if kp.tag == Tag::ALGORITHM => Some(algorithm),
+            _ => None,
+        }) {
+            Some(algorithm) => algorithm,
+            None => {
+                return Err(Error::Km(ErrorCode::UNSUPPORTED_ALGORITHM))
+                    .context(ks_err!("No algorithm given."))
+            }
+        };
+        let key_sizes = match self.key_sizes.get(&algorithm) {
+            Some(key_sizes) => key_sizes,
+            None => {
+                return Err(Error::Km(ErrorCode::UNSUPPORTED_ALGORITHM))
+                    .context(ks_err!("Algorithm {:?} is not supported.", algorithm))
+            }
+        };
+        let purposes = self.purposes.get(&algorithm).map(Vec::as_slice).unwrap_or(&[]);
+        for kp in params {
+            match (kp.tag, &kp.value) {
+                (Tag::KEY_SIZE, KeyParameterValue::Integer(size)) if !key_sizes.contains(size) => {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_KEY_SIZE)).context(ks_err!(
+                        "Key size {} is not supported for {:?}.",
+                        size,
+                        algorithm
+                    ));
+                }
+                (Tag::PURPOSE, KeyParameterValue::KeyPurpose(purpose))
+                    if !purposes.contains(purpose) =>
+                {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_PURPOSE)).context(ks_err!(
+                        "Purpose {:?} is not supported for {:?}.",
+                        purpose,
+                        algorithm
+                    ));
+                }
+                (Tag::EC_CURVE, KeyParameterValue::EcCurve(curve))
+                    if algorithm == Algorithm::EC && !self.ec_curves.contains(curve) =>
+                {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE))
+                        .context(ks_err!("EC curve {:?} is not supported.", curve));
+                }
+                (Tag::DIGEST, KeyParameterValue::Digest(digest))
+                    if !self.digests.contains(digest) =>
+                {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_DIGEST))
+                        .context(ks_err!("Digest {:?} is not supported.", digest));
+                }
+                (Tag::RSA_OAEP_MGF_DIGEST, KeyParameterValue::Digest(digest))
+                    if !self.digests.contains(digest) =>
+                {
+                    return Err(Error::Km(ErrorCode::UNSUPPORTED_MGF_DIGEST))
+                        .context(ks_err!("MGF digest {:?} is not supported.", digest));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks `params` against the capabilities of the backend of `security_level`, as they follow
+/// from its features, without generating a key. See `BackendCapabilities::validate` for the
+/// errors.
+pub fn validate_generation_params(
+    params: &[KeyParameter],
+    security_level: SecurityLevel,
+) -> Result<()> {
+    let info = get_backend_info(security_level).context(ks_err!())?;
+    BackendCapabilities::for_backend(security_level, info.features).validate(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn algorithm(algorithm: Algorithm) -> KeyParameter {
+        KeyParameter { tag: Tag::ALGORITHM, value: KeyParameterValue::Algorithm(algorithm) }
+    }
+
+    fn key_size(size: i32) -> KeyParameter {
+        KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Integer(size) }
+    }
+
+    fn purpose(purpose: KeyPurpose) -> KeyParameter {
+        KeyParameter { tag: Tag::PURPOSE, value: KeyParameterValue::KeyPurpose(purpose) }
+    }
+
+    fn curve(curve: EcCurve) -> KeyParameter {
+        KeyParameter { tag: Tag::EC_CURVE, value: KeyParameterValue::EcCurve(curve) }
+    }
+
+    fn digest(tag: Tag, digest: Digest) -> KeyParameter {
+        KeyParameter { tag, value: KeyParameterValue::Digest(digest) }
+    }
+
+    /// A backend that supports only 2048 bit RSA signing keys with SHA-256 and P-256 EC keys.
+    fn limited_backend() -> BackendCapabilities {
+        BackendCapabilities {
+            key_sizes: HashMap::from([(Algorithm::RSA, vec![2048]), (Algorithm::EC, vec![256])]),
+            purposes: HashMap::from([
+                (Algorithm::RSA, vec![KeyPurpose::SIGN, KeyPurpose::VERIFY]),
+                (Algorithm::EC, vec![KeyPurpose::SIGN]),
+            ]),
+            ec_curves: vec![EcCurve::P_256],
+            digests: vec![Digest::SHA_2_256],
+        }
+    }
+
+    /// Returns the error code with which `capabilities` reject `params`, if any.
+    fn validation_error(
+        capabilities: &BackendCapabilities,
+        params: &[KeyParameter],
+    ) -> Option<ErrorCode> {
+        capabilities.validate(params).err().map(|e| match e.root_cause().downcast_ref::<Error>() {
+            Some(Error::Km(ec)) => *ec,
+            _ => panic!("Unexpected error {:?}", e),
+        })
+    }
+
+    #[test]
+    fn test_supported_params_pass() {
+        let backend = limited_backend();
+        let requests = [
+            vec![
+                algorithm(Algorithm::RSA),
+                key_size(2048),
+                purpose(KeyPurpose::SIGN),
+                digest(Tag::DIGEST, Digest::SHA_2_256),
+            ],
+            vec![algorithm(Algorithm::EC), curve(EcCurve::P_256), purpose(KeyPurpose::SIGN)],
+            // Parameters that are not constrained by the capabilities are passed on to KeyMint.
+            vec![algorithm(Algorithm::EC), key_size(256)],
+        ];
+        for params in &requests {
+            assert_eq!(validation_error(&backend, params), None, "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_params_are_rejected() {
+        let backend = limited_backend();
+        let requests = [
+            (vec![key_size(2048)], ErrorCode::UNSUPPORTED_ALGORITHM),
+            (vec![algorithm(Algorithm::AES), key_size(256)], ErrorCode::UNSUPPORTED_ALGORITHM),
+            (vec![algorithm(Algorithm::RSA), key_size(4096)], ErrorCode::UNSUPPORTED_KEY_SIZE),
+            (
+                vec![algorithm(Algorithm::RSA), purpose(KeyPurpose::DECRYPT)],
+                ErrorCode::UNSUPPORTED_PURPOSE,
+            ),
+            (
+                vec![algorithm(Algorithm::EC), purpose(KeyPurpose::AGREE_KEY)],
+                ErrorCode::UNSUPPORTED_PURPOSE,
+            ),
+            (
+                vec![algorithm(Algorithm::EC), curve(EcCurve::P_384)],
+                ErrorCode::UNSUPPORTED_EC_CURVE,
+            ),
+            (
+                vec![algorithm(Algorithm::RSA), digest(Tag::DIGEST, Digest::SHA1)],
+                ErrorCode::UNSUPPORTED_DIGEST,
+            ),
+            (
+                vec![algorithm(Algorithm::RSA), digest(Tag::RSA_OAEP_MGF_DIGEST, Digest::SHA1)],
+                ErrorCode::UNSUPPORTED_MGF_DIGEST,
+            ),
+        ];
+        for (params, expected) in &requests {
+            assert_eq!(validation_error(&backend, params), Some(*expected), "{:?}", params);
+        }
+    }
+
+    #[test]
+    fn test_capabilities_by_backend() {
+        let tee = BackendCapabilities::for_backend(
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+            KeyMintFeatures::EC_CURVE_P_521,
+        );
+        let strongbox =
+            BackendCapabilities::for_backend(SecurityLevel::STRONGBOX, KeyMintFeatures::default());
+
+        let rsa_4096 = [algorithm(Algorithm::RSA), key_size(4096)];
+        assert_eq!(validation_error(&tee, &rsa_4096), None);
+        assert_eq!(validation_error(&strongbox, &rsa_4096), Some(ErrorCode::UNSUPPORTED_KEY_SIZE));
+
+        let p_521 = [algorithm(Algorithm::EC), curve(EcCurve::P_521)];
+        assert_eq!(validation_error(&tee, &p_521), None);
+        assert_eq!(validation_error(&strongbox, &p_521), Some(ErrorCode::UNSUPPORTED_EC_CURVE));
+        // Curve 25519 requires the feature.
+        assert_eq!(
+            validation_error(&tee, &[algorithm(Algorithm::EC), curve(EcCurve::CURVE_25519)]),
+            Some(ErrorCode::UNSUPPORTED_EC_CURVE)
+        );
+
+        let hmac_sha_512 =
+            [algorithm(Algorithm::HMAC), key_size(512), digest(Tag::DIGEST, Digest::SHA_2_512)];
+        assert_eq!(validation_error(&tee, &hmac_sha_512), None);
+        assert_eq!(
+            validation_error(&strongbox, &hmac_sha_512),
+            Some(ErrorCode::UNSUPPORTED_DIGEST)
+        );
+        assert_eq!(
+            validation_error(&tee, &[algorithm(Algorithm::HMAC), key_size(100)]),
+            Some(ErrorCode::UNSUPPORTED_KEY_SIZE)
+        );
+
+        // Attestation keys require the feature.
+        let attest_key = [algorithm(Algorithm::EC), purpose(KeyPurpose::ATTEST_KEY)];
+        assert_eq!(validation_error(&tee, &attest_key), Some(ErrorCode::UNSUPPORTED_PURPOSE));
+        let tee_v1 = BackendCapabilities::for_backend(
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+            KeyMintFeatures::ATTEST_KEY,
+        );
+        assert_eq!(validation_error(&tee_v1, &attest_key), None);
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module derives the KeyMint version and the feature set of each KeyMint backend from
+//! its hardware info, so that callers can learn what a backend supports before making requests.
+//! The result is cached per security level until keystore reconnects to the backend.
+
+use crate::globals::{get_keymint_device, KEYMINT_BACKEND_INFO};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyMintHardwareInfo::KeyMintHardwareInfo, SecurityLevel::SecurityLevel,
+};
+use android_security_maintenance::aidl::android::security::maintenance::KeyMintBackendInfo::KeyMintBackendInfo;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The first Keymaster version that supports Tag::EARLY_BOOT_ONLY.
+const KEY_MASTER_V4_1: i32 = 41;
+/// Versions of KeyMint as used by keystore, see `KeyMintBackendInfo::keyMintVersion`.
+const KEY_MINT_V1: i32 = 100;
+const KEY_MINT_V2: i32 = 200;
+
+/// Bitset of the features of a KeyMint backend. The bits match the `FEATURE_*` constants of
+/// `KeyMintBackendInfo`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct KeyMintFeatures(i32);
+
+impl KeyMintFeatures {
+    /// See `KeyMintBackendInfo::FEATURE_EC_CURVE_P_521`.
+    pub const EC_CURVE_P_521: Self = Self(KeyMintBackendInfo::FEATURE_EC_CURVE_P_521);
+    /// See `KeyMintBackendInfo::FEATURE_CURVE_25519`.
+    pub const CURVE_25519: Self = Self(KeyMintBackendInfo::FEATURE_CURVE_25519);
+    /// See `KeyMintBackendInfo::FEATURE_DEVICE_UNIQUE_ATTESTATION`.
+    pub const DEVICE_UNIQUE_ATTESTATION: Self =
+        Self(KeyMintBackendInfo::FEATURE_DEVICE_UNIQUE_ATTESTATION);
+    /// See `KeyMintBackendInfo::FEATURE_ATTEST_KEY`.
+    pub const ATTEST_KEY: Self = Self(KeyMintBackendInfo::FEATURE_ATTEST_KEY);
+    /// See `KeyMintBackendInfo::FEATURE_EARLY_BOOT_ONLY`.
+    pub const EARLY_BOOT_ONLY: Self = Self(KeyMintBackendInfo::FEATURE_EARLY_BOOT_ONLY);
+    /// See `KeyMintBackendInfo::FEATURE_TIMESTAMP_TOKEN_REQUIRED`.
+    pub const TIMESTAMP_TOKEN_REQUIRED: Self =
+        Self(KeyMintBackendInfo::FEATURE_TIMESTAMP_TOKEN_REQUIRED);
+
+    /// Derives the features of a backend from its hardware info. `hw_info.versionNumber` must be
+    /// the version as normalized by keystore when connecting to the backend.
+    pub fn from_hw_info(hw_info: &KeyMintHardwareInfo) -> Self {
+        let is_strongbox = hw_info.securityLevel == SecurityLevel::STRONGBOX;
+        let version = hw_info.versionNumber;
+        [
+            (Self::EC_CURVE_P_521, !is_strongbox),
+            (Self::CURVE_25519, !is_strongbox && version >= KEY_MINT_V2),
+            (Self::DEVICE_UNIQUE_ATTESTATION, is_strongbox || version >= KEY_MINT_V2),
+            (Self::ATTEST_KEY, version >= KEY_MINT_V1),
+            (Self::EARLY_BOOT_ONLY, version >= KEY_MASTER_V4_1),
+            (Self::TIMESTAMP_TOKEN_REQUIRED, hw_info.timestampTokenRequired),
+        ]
+        .into_iter()
+        .filter(|(_, supported)| *supported)
+        .fold(Self::default(), |acc, (feature, _)| Self(acc.0 | feature.0))
+    }
+
+    /// Returns true if all features in `other` are present.
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the raw bitset.
+    pub fn bits(&self) -> i32 {
+        self.0
+    }
+}
+
+/// The negotiated KeyMint version and the features of a backend.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BackendInfo {
+    /// The KeyMint version, see `KeyMintBackendInfo::keyMintVersion`.
+    pub version: i32,
+    /// The features of the backend.
+    pub features: KeyMintFeatures,
+}
+
+impl From<BackendInfo> for KeyMintBackendInfo {
+    fn from(info: BackendInfo) -> Self {
+        Self { keyMintVersion: info.version, features: info.features.bits() }
+    }
+}
+
+/// Caches the `BackendInfo` of each security level.
+#[derive(Debug, Default)]
+pub struct BackendInfoCache {
+    entries: Mutex<HashMap<SecurityLevel, BackendInfo>>,
+}
+
+impl BackendInfoCache {
+    /// Returns the cached info for `security_level`. On a cache miss, the hardware info is
+    /// obtained from `negotiate` and the derived info is cached.
+    pub fn get_or_negotiate<F>(
+        &self,
+        security_level: SecurityLevel,
+        negotiate: F,
+    ) -> Result<BackendInfo>
+    where
+        F: FnOnce() -> Result<KeyMintHardwareInfo>,
+    {
+        if let Some(info) = self.entries.lock().unwrap().get(&security_level) {
+            return Ok(*info);
+        }
+        // The lock is not held while negotiating, because connecting to the backend
+        // invalidates the cache.
+        let hw_info =
+            negotiate().context(ks_err!("Failed to negotiate with {:?}.", security_level))?;
+        let info = BackendInfo {
+            version: hw_info.versionNumber,
+            features: KeyMintFeatures::from_hw_info(&hw_info),
+        };
+        self.entries.lock().unwrap().insert(security_level, info);
+        Ok(info)
+    }
+
+    /// Drops the cached info for `security_level`. Must be called whenever keystore
+    /// (re)connects to the backend.
+    pub fn invalidate(&self, security_level: SecurityLevel) {
+        self.entries.lock().unwrap().remove(&security_level);
+    }
+}
+
+/// Returns the KeyMint version and the features of the backend of the given security level.
+pub fn get_backend_info(security_level: SecurityLevel) -> Result<BackendInfo> {
+    KEYMINT_BACKEND_INFO
+        .get_or_negotiate(security_level, || {
+            get_keymint_device(&security_level).map(|(_, hw_info, _)| hw_info)
+        })
+        .context(ks_err!())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::cell::Cell;
+
+    fn hw_info(security_level: SecurityLevel, version_number: i32) -> KeyMintHardwareInfo {
+        KeyMintHardwareInfo {
+            versionNumber: version_number,
+            securityLevel: security_level,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_features_by_backend() {
+        let tee_v2 =
+            KeyMintFeatures::from_hw_info(&hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, 200));
+        assert!(tee_v2.contains(KeyMintFeatures::CURVE_25519));
+        assert!(tee_v2.contains(KeyMintFeatures::EC_CURVE_P_521));
+        assert!(tee_v2.contains(KeyMintFeatures::DEVICE_UNIQUE_ATTESTATION));
+        assert!(tee_v2.contains(KeyMintFeatures::ATTEST_KEY));
+        assert!(!tee_v2.contains(KeyMintFeatures::TIMESTAMP_TOKEN_REQUIRED));
+
+        let tee_v1 =
+            KeyMintFeatures::from_hw_info(&hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, 100));
+        assert!(!tee_v1.contains(KeyMintFeatures::CURVE_25519));
+        assert!(!tee_v1.contains(KeyMintFeatures::DEVICE_UNIQUE_ATTESTATION));
+        assert!(tee_v1.contains(KeyMintFeatures::ATTEST_KEY));
+
+        let strongbox = KeyMintFeatures::from_hw_info(&KeyMintHardwareInfo {
+            timestampTokenRequired: true,
+            ..hw_info(SecurityLevel::STRONGBOX, 300)
+        });
+        assert!(!strongbox.contains(KeyMintFeatures::CURVE_25519));
+        assert!(!strongbox.contains(KeyMintFeatures::EC_CURVE_P_521));
+        assert!(strongbox.contains(KeyMintFeatures::DEVICE_UNIQUE_ATTESTATION));
+        assert!(strongbox.contains(KeyMintFeatures::TIMESTAMP_TOKEN_REQUIRED));
+
+        let keymaster_4_0 =
+            KeyMintFeatures::from_hw_info(&hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, 40));
+        assert!(!keymaster_4_0.contains(KeyMintFeatures::ATTEST_KEY));
+        assert!(!keymaster_4_0.contains(KeyMintFeatures::EARLY_BOOT_ONLY));
+        assert!(KeyMintFeatures::from_hw_info(&hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, 41))
+            .contains(KeyMintFeatures::EARLY_BOOT_ONLY));
+    }
+
+    #[test]
+    fn test_backend_info_is_cached_until_invalidated() {
+        let cache = BackendInfoCache::default();
+        let negotiations = Cell::new(0);
+        let backend = |version| -> Result<KeyMintHardwareInfo> {
+            negotiations.set(negotiations.get() + 1);
+            Ok(hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, version))
+        };
+
+        let info =
+            cache.get_or_negotiate(SecurityLevel::TRUSTED_ENVIRONMENT, || backend(100)).unwrap();
+        assert_eq!(info.version, 100);
+        assert!(!info.features.contains(KeyMintFeatures::CURVE_25519));
+        // The cached info is returned without negotiating again.
+        let info =
+            cache.get_or_negotiate(SecurityLevel::TRUSTED_ENVIRONMENT, || backend(200)).unwrap();
+        assert_eq!(info.version, 100);
+        assert_eq!(negotiations.get(), 1);
+
+        // Other security levels are cached independently.
+        let info = cache
+            .get_or_negotiate(SecurityLevel::STRONGBOX, || {
+                Ok(hw_info(SecurityLevel::STRONGBOX, 300))
+            })
+            .unwrap();
+        assert_eq!(info.version, 300);
+
+        // After reconnecting, the backend is asked again.
+        cache.invalidate(SecurityLevel::TRUSTED_ENVIRONMENT);
+        let info =
+            cache.get_or_negotiate(SecurityLevel::TRUSTED_ENVIRONMENT, || backend(200)).unwrap();
+        assert_eq!(info.version, 200);
+        assert!(info.features.contains(KeyMintFeatures::CURVE_25519));
+        assert_eq!(negotiations.get(), 2);
+        assert_eq!(
+            cache.get_or_negotiate(SecurityLevel::STRONGBOX, || panic!()).unwrap().version,
+            300
+        );
+    }
+
+    #[test]
+    fn test_failed_negotiation_is_not_cached() {
+        let cache = BackendInfoCache::default();
+        assert!(cache
+            .get_or_negotiate(SecurityLevel::STRONGBOX, || Err(anyhow!("not available")))
+            .is_err());
+        let info = cache
+            .get_or_negotiate(SecurityLevel::STRONGBOX, || {
+                Ok(hw_info(SecurityLevel::STRONGBOX, 200))
+            })
+            .unwrap();
+        assert_eq!(info.version, 200);
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the latency budget of key generation.
+//!
+//! Key generation is on the critical path of unlock and boot for some clients, so regressions of
+//! its latency matter. If a budget is configured, the time of a key generation is attributed to
+//! the steps it consists of, and a metric is logged naming the slowest step whenever the total
+//! exceeds the budget. Time that is not attributed to any step is charged to keystore itself.
+
+use crate::metrics_store::log_key_generation_latency_exceeded;
+use crate::sysprop::{read_prop_bool, read_prop_duration};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+/// The latency budget of key generation, e.g., `250ms`. Zero, the default, disables the budget.
+const BUDGET_PROPERTY: &str = "keystore.key_generation_latency_budget";
+
+/// If true, key generations that exceed the budget are logged with the time of each step.
+const LOG_STEPS_PROPERTY: &str = "keystore.log_slow_key_generation_steps";
+
+/// The steps of key generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationStep {
+    /// Keystore's own processing outside of the other steps.
+    Keystore,
+    /// Database access.
+    Database,
+    /// Cryptographic operations of keystore, e.g., super encryption.
+    Crypto,
+    /// The call to KeyMint.
+    KeyMint,
+    /// Selection of the attestation key and verification of the attestation.
+    Attestation,
+}
+
+const STEPS: [GenerationStep; 5] = [
+    GenerationStep::Keystore,
+    GenerationStep::Database,
+    GenerationStep::Crypto,
+    GenerationStep::KeyMint,
+    GenerationStep::Attestation,
+];
+
+#[derive(Debug)]
+struct Timer {
+    started: Instant,
+    current: GenerationStep,
+    since: Instant,
+    spent: [Duration; STEPS.len()],
+}
+
+impl Timer {
+    /// Charges the time since the last switch to the current step and makes `step` the current
+    /// step. Returns the previous step.
+    fn switch_to(&mut self, step: GenerationStep) -> GenerationStep {
+        let now = Instant::now();
+        self.spent[self.current as usize] += now.duration_since(self.since);
+        self.since = now;
+        std::mem::replace(&mut self.current, step)
+    }
+}
+
+/// Attributes the time of a key generation to its steps if a budget is configured. The default
+/// has no budget and measures nothing.
+#[derive(Debug, Default)]
+pub struct LatencyBudget {
+    budget: Duration,
+    timer: Option<RefCell<Timer>>,
+}
+
+impl LatencyBudget {
+    /// Starts the timer of a key generation against the configured budget.
+    pub fn start() -> Self {
+        Self::with_budget(read_prop_duration(BUDGET_PROPERTY, Duration::ZERO))
+    }
+
+    /// Starts the timer against `budget`. No time is measured if `budget` is zero.
+    pub fn with_budget(budget: Duration) -> Self {
+        let timer = (!budget.is_zero()).then(|| {
+            let now = Instant::now();
+            RefCell::new(Timer {
+                started: now,
+                current: GenerationStep::Keystore,
+                since: now,
+                spent: Default::default(),
+            })
+        });
+        Self { budget, timer }
+    }
+
+    /// Calls `f` and attributes the time it takes to `step`. Steps may be nested, in which case
+    /// the time of the inner step is not charged to the outer one.
+    pub fn measure<T, F>(&self, step: GenerationStep, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let timer = match &self.timer {
+            Some(timer) => timer,
+            None => return f(),
+        };
+        let previous = timer.borrow_mut().switch_to(step);
+        let result = f();
+        timer.borrow_mut().switch_to(previous);
+        result
+    }
+
+    /// Stops the timer. If the budget was exceeded, logs the metric for the KeyMint backend of
+    /// `sec_level` and returns the slowest step.
+    pub fn finish(self, sec_level: SecurityLevel) -> Option<GenerationStep> {
+        let mut timer = self.timer?.into_inner();
+        timer.switch_to(GenerationStep::Keystore);
+        let total = timer.since.duration_since(timer.started);
+        if total <= self.budget {
+            return None;
+        }
+        let slowest_step = STEPS
+            .into_iter()
+            .max_by_key(|step| timer.spent[*step as usize])
+            .unwrap_or(GenerationStep::Keystore);
+        log_key_generation_latency_exceeded(sec_level, slowest_step);
+        if read_prop_bool(LOG_STEPS_PROPERTY, false) {
+            log::warn!(
+                "Key generation took {:?}, exceeding the budget of {:?}. Slowest step: {:?}. {:?}",
+                total,
+                self.budget,
+                slowest_step,
+                STEPS.iter().zip(timer.spent.iter()).collect::<Vec<_>>()
+            );
+        }
+        Some(slowest_step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics_store::METRICS_STORE;
+    use android_security_metrics::aidl::android::security::metrics::{
+        AtomID::AtomID, KeyGenerationLatencyStats::KeyGenerationLatencyStats,
+        KeyGenerationStep::KeyGenerationStep as MetricsKeyGenerationStep,
+        KeystoreAtomPayload::KeystoreAtomPayload,
+        SecurityLevel::SecurityLevel as MetricsSecurityLevel,
+    };
+    use std::thread;
+
+    fn latency_exceeded_count(slowest_step: MetricsKeyGenerationStep) -> i32 {
+        let expected = KeystoreAtomPayload::KeyGenerationLatencyStats(KeyGenerationLatencyStats {
+            security_level: MetricsSecurityLevel::SECURITY_LEVEL_STRONGBOX,
+            slowest_step,
+        });
+        METRICS_STORE
+            .get_atoms(AtomID::KEY_GENERATION_LATENCY_STATS)
+            .unwrap()
+            .iter()
+            .find(|atom| atom.payload == expected)
+            .map_or(0, |atom| atom.count)
+    }
+
+    #[test]
+    fn test_slow_step_exceeds_budget() {
+        let before = latency_exceeded_count(MetricsKeyGenerationStep::KEYMINT);
+        let budget = LatencyBudget::with_budget(Duration::from_millis(20));
+        let result = budget.measure(GenerationStep::Database, || {
+            // The nested KeyMint call is not charged to the database.
+            budget.measure(GenerationStep::KeyMint, || thread::sleep(Duration::from_millis(50)));
+            7
+        });
+        assert_eq!(result, 7);
+        assert_eq!(budget.finish(SecurityLevel::STRONGBOX), Some(GenerationStep::KeyMint));
+        assert_eq!(latency_exceeded_count(MetricsKeyGenerationStep::KEYMINT), before + 1);
+    }
+
+    #[test]
+    fn test_fast_generation_and_disabled_budget() {
+        let budget = LatencyBudget::with_budget(Duration::from_secs(60));
+        budget.measure(GenerationStep::Crypto, || ());
+        assert_eq!(budget.finish(SecurityLevel::STRONGBOX), None);
+
+        let before = latency_exceeded_count(MetricsKeyGenerationStep::ATTESTATION);
+        let budget = LatencyBudget::with_budget(Duration::ZERO);
+        budget.measure(GenerationStep::Attestation, || thread::sleep(Duration::from_millis(5)));
+        assert_eq!(budget.finish(SecurityLevel::STRONGBOX), None);
+        assert_eq!(latency_exceeded_count(MetricsKeyGenerationStep::ATTESTATION), before);
+    }
+}
@@ -27,7 +27,10 @@ use crate::utils::{
     key_characteristics_to_internal, uid_to_android_user, upgrade_keyblob_if_required_with,
     watchdog as wd, AesGcm,
 };
-use crate::{async_task::AsyncTask, legacy_blob::LegacyBlobLoader};
+use crate::{
+    async_task::{AsyncTask, CancellationToken},
+    legacy_blob::LegacyBlobLoader,
+};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
@@ -57,6 +60,9 @@ pub struct LegacyImporter {
     /// When transitioning from READY to EMPTY, spurious calls may occur for a brief period
     /// of time. This is tolerable in favor of the common case.
     state: AtomicU8,
+    /// Cancellation token handed to bulk deletions. It is replaced by a fresh token when the
+    /// bulk deletions in progress get cancelled.
+    bulk_delete_cancellation: Mutex<CancellationToken>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -99,6 +105,7 @@ impl LegacyImporter {
             async_task,
             initializer: Default::default(),
             state: AtomicU8::new(Self::STATE_UNINITIALIZED),
+            bulk_delete_cancellation: Default::default(),
         }
     }
 
@@ -383,8 +390,9 @@ impl LegacyImporter {
             _ => return Ok(()),
         };
 
+        let cancellation = self.bulk_delete_cancellation.lock().unwrap().clone();
         let result = self.do_serialized(move |importer_state| {
-            importer_state.bulk_delete(BulkDeleteRequest::Uid(uid), false)
+            importer_state.bulk_delete(BulkDeleteRequest::Uid(uid), false, &cancellation)
         });
 
         result.unwrap_or(Ok(()))
@@ -399,14 +407,28 @@ impl LegacyImporter {
     ) -> Result<()> {
         let _wp = wd::watch_millis("LegacyImporter::bulk_delete_user", 500);
 
+        let cancellation = self.bulk_delete_cancellation.lock().unwrap().clone();
         let result = self.do_serialized(move |importer_state| {
-            importer_state
-                .bulk_delete(BulkDeleteRequest::User(user_id), keep_non_super_encrypted_keys)
+            importer_state.bulk_delete(
+                BulkDeleteRequest::User(user_id),
+                keep_non_super_encrypted_keys,
+                &cancellation,
+            )
         });
 
         result.unwrap_or(Ok(()))
     }
 
+    /// Cancels all bulk deletions that are in progress or scheduled, e.g., during shutdown.
+    /// They stop before the next key and fail with `ResponseCode::SYSTEM_ERROR`, but the keys
+    /// that were deleted so far stay deleted. Bulk deletions requested afterwards are not
+    /// affected.
+    pub fn cancel_bulk_deletes(&self) {
+        let mut cancellation = self.bulk_delete_cancellation.lock().unwrap();
+        cancellation.cancel();
+        *cancellation = CancellationToken::new();
+    }
+
     /// Queries the legacy database for the presence of a super key for the given user.
     pub fn has_super_key(&self, user_id: u32) -> Result<bool> {
         let result =
@@ -740,10 +762,13 @@ impl LegacyImporterState {
 
     /// Key importer request to be run by do_serialized.
     /// See LegacyImporter::bulk_delete_uid and LegacyImporter::bulk_delete_user.
+    /// Each key is removed from the legacy database individually, so if the request is
+    /// cancelled, it stops between two keys without losing the progress made so far.
     fn bulk_delete(
         &mut self,
         bulk_delete_request: BulkDeleteRequest,
         keep_non_super_encrypted_keys: bool,
+        cancellation: &CancellationToken,
     ) -> Result<()> {
         let (aliases, user_id) = match bulk_delete_request {
             BulkDeleteRequest::Uid(uid) => (
@@ -775,6 +800,10 @@ impl LegacyImporterState {
             .into_iter()
             .flat_map(|(uid, aliases)| aliases.into_iter().map(move |alias| (uid, alias)))
         {
+            if cancellation.is_cancelled() {
+                return Err(Error::sys()).context(ks_err!("Bulk delete cancelled."));
+            }
+
             let (km_blob_params, _, _) = self
                 .legacy_loader
                 .load_by_uid_alias(uid, &alias, &None)
@@ -17,6 +17,8 @@
 
 pub mod apc;
 pub mod async_task;
+pub mod attestation_cert_cache;
+pub mod attestation_verification;
 pub mod authorization;
 pub mod boot_level_keys;
 pub mod database;
@@ -26,9 +28,15 @@ pub mod entropy;
 pub mod error;
 pub mod globals;
 pub mod id_rotation;
+pub mod key_export;
+pub mod key_flags;
+pub mod key_lifecycle;
+pub mod key_migration;
 /// Internal Representation of Key Parameter and convenience functions.
 pub mod key_parameter;
+pub mod key_tags;
 pub mod ks_err;
+pub mod latency_budget;
 pub mod legacy_blob;
 pub mod legacy_importer;
 pub mod maintenance;
@@ -42,13 +50,43 @@ pub mod rkpd_client;
 pub mod security_level;
 pub mod service;
 pub mod shared_secret_negotiation;
+pub mod sysprop;
 pub mod utils;
 
+mod aead_nonce;
+mod app_key;
+mod attestation_expiry;
+mod attestation_ids;
 mod attestation_key_utils;
+mod attestation_self_test;
+mod attestation_templates;
 mod audit_log;
+mod boot_state_override;
+mod cert_chain_order;
+mod circuit_breaker;
+mod clock_rollback;
+mod device_id;
+mod ec_curve_strength;
+mod fips_mode;
 mod gc;
+mod generation_defaults;
+mod key_fingerprint;
+mod key_operation_log;
+mod keymint_timeout;
+mod km_capabilities;
 mod km_compat;
+mod km_features;
+mod log_throttle;
+mod operation_checkpoint;
+mod operation_token;
+mod rsa_key_size;
+mod shared_uid_attestation;
 mod super_key;
+mod super_key_wait;
+mod unknown_tags;
 
 #[cfg(feature = "watchdog")]
 mod watchdog;
+
+#[cfg(test)]
+mod zeroize_check;
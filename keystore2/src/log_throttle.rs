@@ -0,0 +1,183 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a log throttle that deduplicates identical log messages.
+//!
+//! Under error storms, e.g., when a KeyMint backend fails every request, the same error is
+//! logged for every request and floods the log. The throttle logs the first occurrence of a
+//! message immediately and suppresses identical messages for the duration of the window.
+//! The first occurrence after the window expired is logged again together with a summary of
+//! how many identical messages were suppressed.
+
+use crate::sysprop::read_prop_duration;
+use lazy_static::lazy_static;
+use log::Level;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time for which identical messages are suppressed after they were logged. A window of 0
+/// disables throttling.
+const WINDOW_PROPERTY: &str = "keystore.log_throttle.window";
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+/// Maximum number of distinct messages that are tracked. Messages whose window expired are
+/// evicted when this limit is reached.
+const MAX_TRACKED_MESSAGES: usize = 128;
+
+lazy_static! {
+    static ref LOG_THROTTLE: LogThrottle =
+        LogThrottle::new(read_prop_duration(WINDOW_PROPERTY, DEFAULT_WINDOW));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    /// Log the message. `suppressed` identical messages were dropped since it was last logged.
+    Log {
+        suppressed: u64,
+    },
+    Suppress,
+}
+
+#[derive(Debug)]
+struct Entry {
+    logged_at: Instant,
+    suppressed: u64,
+}
+
+/// Deduplicates identical log messages within a time window.
+#[derive(Debug)]
+struct LogThrottle {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl LogThrottle {
+    fn new(window: Duration) -> Self {
+        Self { window, entries: Default::default() }
+    }
+
+    /// Decides whether `msg` shall be logged at `now`. Returns the verdict and the summaries,
+    /// i.e., messages with their suppression count, of evicted entries.
+    fn check(&self, msg: &str, now: Instant) -> (Verdict, Vec<(String, u64)>) {
+        if self.window.is_zero() {
+            return (Verdict::Log { suppressed: 0 }, vec![]);
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(msg) {
+            if now.saturating_duration_since(entry.logged_at) < self.window {
+                entry.suppressed += 1;
+                return (Verdict::Suppress, vec![]);
+            }
+            let suppressed = entry.suppressed;
+            *entry = Entry { logged_at: now, suppressed: 0 };
+            return (Verdict::Log { suppressed }, vec![]);
+        }
+
+        let mut evicted = vec![];
+        if entries.len() >= MAX_TRACKED_MESSAGES {
+            let window = self.window;
+            entries.retain(|msg, entry| {
+                let expired = now.saturating_duration_since(entry.logged_at) >= window;
+                if expired && entry.suppressed != 0 {
+                    evicted.push((msg.clone(), entry.suppressed));
+                }
+                !expired
+            });
+        }
+        // If all tracked messages are still within their window, the new message is logged
+        // but not tracked.
+        if entries.len() < MAX_TRACKED_MESSAGES {
+            entries.insert(msg.to_string(), Entry { logged_at: now, suppressed: 0 });
+        }
+        (Verdict::Log { suppressed: 0 }, evicted)
+    }
+
+    fn log(&self, level: Level, msg: &str) {
+        let (verdict, evicted) = self.check(msg, Instant::now());
+        for (evicted_msg, suppressed) in evicted {
+            log::log!(level, "Suppressed {} more identical messages: {}", suppressed, evicted_msg);
+        }
+        if let Verdict::Log { suppressed } = verdict {
+            log::log!(level, "{}", msg);
+            if suppressed != 0 {
+                log::log!(
+                    level,
+                    "The above message was suppressed {} times within the last {:?}.",
+                    suppressed,
+                    self.window
+                );
+            }
+        }
+    }
+}
+
+/// Logs `msg` with the given level unless an identical message was logged within the
+/// throttle window, which is configured by the system property `keystore.log_throttle.window`.
+pub fn log_throttled(level: Level, msg: &str) {
+    LOG_THROTTLE.log(level, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn test_identical_messages_are_suppressed_within_window() {
+        let throttle = LogThrottle::new(WINDOW);
+        let start = Instant::now();
+
+        assert_eq!(throttle.check("backend failed", start).0, Verdict::Log { suppressed: 0 });
+        assert_eq!(throttle.check("backend failed", start).0, Verdict::Suppress);
+        assert_eq!(
+            throttle.check("backend failed", start + Duration::from_secs(9)).0,
+            Verdict::Suppress
+        );
+        // A different message is not affected.
+        assert_eq!(throttle.check("other error", start).0, Verdict::Log { suppressed: 0 });
+
+        // After the window expired, the message is logged with the suppression count.
+        assert_eq!(
+            throttle.check("backend failed", start + WINDOW).0,
+            Verdict::Log { suppressed: 2 }
+        );
+        assert_eq!(throttle.check("backend failed", start + WINDOW).0, Verdict::Suppress);
+    }
+
+    #[test]
+    fn test_zero_window_disables_throttling() {
+        let throttle = LogThrottle::new(Duration::ZERO);
+        let now = Instant::now();
+
+        assert_eq!(throttle.check("backend failed", now).0, Verdict::Log { suppressed: 0 });
+        assert_eq!(throttle.check("backend failed", now).0, Verdict::Log { suppressed: 0 });
+    }
+
+    #[test]
+    fn test_expired_entries_are_evicted_with_summary() {
+        let throttle = LogThrottle::new(WINDOW);
+        let start = Instant::now();
+
+        for i in 0..MAX_TRACKED_MESSAGES {
+            throttle.check(&format!("message {}", i), start);
+        }
+        throttle.check("message 0", start);
+
+        let (verdict, evicted) = throttle.check("new message", start + WINDOW);
+        assert_eq!(verdict, Verdict::Log { suppressed: 0 });
+        assert_eq!(evicted, vec![("message 0".to_string(), 1)]);
+        assert_eq!(throttle.check("new message", start + WINDOW).0, Verdict::Suppress);
+    }
+}
@@ -14,32 +14,83 @@
 
 //! This module implements IKeystoreMaintenance AIDL interface.
 
-use crate::database::{KeyEntryLoadBits, KeyType, MonotonicRawTime};
+use crate::app_key::get_app_key;
+use crate::attestation_expiry::{list_keys_expiring_within, ExpiryCause};
+use crate::attestation_templates::ATTESTATION_TEMPLATES;
+use crate::clock_rollback::SystemClock;
+use crate::database::{
+    BatchMode, ConsistencyReport, DateTime, KeyEntryLoadBits, KeyType, MonotonicRawTime,
+    SuperKeyIdentity as DbSuperKeyIdentity,
+};
+use crate::device_id::get_device_identifier;
 use crate::error::map_km_error;
 use crate::error::map_or_log_err;
-use crate::error::Error;
-use crate::globals::get_keymint_device;
-use crate::globals::{DB, LEGACY_IMPORTER, SUPER_KEY};
+use crate::error::{get_error_code, Error};
+use crate::globals::{get_keymint_device, primary_keymint_instance};
+use crate::globals::{ASYNC_TASK, DB, GC, LEGACY_IMPORTER, SUPER_KEY};
+use crate::key_export;
+use crate::key_lifecycle::{register_binder_observer, unregister_binder_observer};
+use crate::key_migration;
+use crate::key_operation_log::KEY_OPERATION_LOGS;
+use crate::km_capabilities;
+use crate::km_features::get_backend_info;
 use crate::ks_err;
+use crate::operation::{abort_operation_by_id, list_operation_ids, list_operations_for_key};
+use crate::operation_checkpoint;
+use crate::operation_token::OPERATION_TOKENS;
 use crate::permission::{KeyPerm, KeystorePerm};
-use crate::super_key::{SuperKeyManager, UserState};
+use crate::raw_device::KeyMintDevice;
+use crate::remote_provisioning::RemProvState;
+use crate::super_key::{BlobBinding, SuperKeyManager, UserState};
+use crate::sysprop::read_prop_parsed;
 use crate::utils::{
-    check_key_permission, check_keystore_permission, uid_to_android_user, watchdog as wd,
+    check_grant_permission, check_key_permission, check_keystore_permission,
+    get_key_descriptor_for_lookup, key_parameters_to_authorizations, uid_to_android_user,
+    watchdog as wd, RESPONSE_SIZE_LIMIT,
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    IKeyMintDevice::IKeyMintDevice, SecurityLevel::SecurityLevel,
+    IKeyMintDevice::IKeyMintDevice, KeyParameter::KeyParameter, KeyPurpose::KeyPurpose,
+    SecurityLevel::SecurityLevel,
 };
 use android_security_maintenance::aidl::android::security::maintenance::{
+    BackupEntry::BackupEntry,
+    ExpiringKey::ExpiringKey,
+    GrantResult::GrantResult,
+    IKeyLifecycleObserver::IKeyLifecycleObserver,
     IKeystoreMaintenance::{BnKeystoreMaintenance, IKeystoreMaintenance},
+    KeyBackend::KeyBackend,
+    KeyFingerprint::KeyFingerprint,
+    KeyIdAllocation::KeyIdAllocation,
+    KeyInventoryEntry::KeyInventoryEntry,
+    KeyMintBackendInfo::KeyMintBackendInfo,
+    KeyOperationRecord::KeyOperationRecord,
+    OperationInfo::OperationInfo,
+    PublicKeyEntry::PublicKeyEntry,
+    SuperKeyIdentity::SuperKeyIdentity,
+    SuperKeyProtection::SuperKeyProtection,
     UserState::UserState as AidlUserState,
 };
 use android_security_maintenance::binder::{
     BinderFeatures, Interface, Result as BinderResult, Strong, ThreadState,
 };
+use android_system_keystore2::aidl::android::system::keystore2::Authorization::Authorization;
+use android_system_keystore2::aidl::android::system::keystore2::CreateOperationResponse::CreateOperationResponse;
 use android_system_keystore2::aidl::android::system::keystore2::KeyDescriptor::KeyDescriptor;
 use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use anyhow::{Context, Result};
-use keystore2_crypto::Password;
+use keystore2_crypto::{certificate_chain_to_pem, Password};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+
+/// Number of key blobs that `verifyKeyBlobs` verifies per job on the async task and worker.
+const VERIFY_KEY_BLOBS_BATCH_SIZE: usize = 32;
+
+/// Number of threads that `verifyKeyBlobs` unwraps key blobs with in parallel, from 1, the
+/// default, to `MAX_VERIFY_KEY_BLOBS_WORKERS`.
+const VERIFY_KEY_BLOBS_WORKERS_PROPERTY: &str = "keystore.verify_key_blobs.workers";
+
+/// The maximum number of threads that `verifyKeyBlobs` unwraps key blobs with.
+const MAX_VERIFY_KEY_BLOBS_WORKERS: usize = 8;
 
 /// Reexport Domain for the benefit of DeleteListener
 pub use android_system_keystore2::aidl::android::system::keystore2::Domain::Domain;
@@ -159,6 +210,13 @@ impl Maintenance {
         }
     }
 
+    fn is_user_unlocked(user_id: i32) -> Result<bool> {
+        // Check permission. Function should return if this failed. Therefore having '?' at the end
+        // is very important.
+        check_keystore_permission(KeystorePerm::GetState).context(ks_err!())?;
+        Ok(SUPER_KEY.read().unwrap().is_user_unlocked(user_id as u32))
+    }
+
     fn call_with_watchdog<F>(sec_level: SecurityLevel, name: &'static str, op: &F) -> Result<()>
     where
         F: Fn(Strong<dyn IKeyMintDevice>) -> binder::Result<()>,
@@ -268,6 +326,889 @@ impl Maintenance {
         })
     }
 
+    fn get_device_identifier(context: &[u8]) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::GetDeviceIdentifier).context(ks_err!())?;
+
+        // The identifier is bound to the calling UID, so callers can only ever learn their own
+        // identifiers.
+        let caller_uid = ThreadState::get_calling_uid();
+        DB.with(|db| get_device_identifier(&mut db.borrow_mut(), caller_uid, context))
+            .context(ks_err!())
+    }
+
+    fn derive_app_key(context: &[u8]) -> Result<Vec<u8>> {
+        // No permission check: the key is bound to the calling UID, so callers can only ever
+        // derive their own keys.
+        let caller_uid = ThreadState::get_calling_uid();
+        let key = DB
+            .with(|db| get_app_key(&mut db.borrow_mut(), caller_uid, context))
+            .context(ks_err!())?;
+        Ok(key.to_vec())
+    }
+
+    fn get_key_id_allocations() -> Result<Vec<KeyIdAllocation>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::PullMetrics).context(ks_err!())?;
+
+        let allocations =
+            DB.with(|db| db.borrow_mut().get_key_id_allocations()).context(ks_err!())?;
+        Ok(allocations
+            .into_iter()
+            .map(|a| KeyIdAllocation {
+                keyId: a.key_id,
+                allocationTimeMillis: a.allocation_time.to_millis_epoch(),
+                callerUid: a.caller_uid as i32,
+            })
+            .collect())
+    }
+
+    fn get_keymint_backend_info(security_level: SecurityLevel) -> Result<KeyMintBackendInfo> {
+        // No permission check: the backend info is not sensitive, and every caller that can
+        // generate keys can find out by trial and error.
+        get_backend_info(security_level).map(Into::into).context(ks_err!())
+    }
+
+    fn force_rkp_provisioning(security_level: SecurityLevel) -> Result<()> {
+        // Only the clients of the remotely provisioned key pool may force provisioning.
+        check_keystore_permission(KeystorePerm::GetAttestationKey).context(ks_err!())?;
+        let (_, _, km_uuid) = get_keymint_device(&security_level).context(ks_err!())?;
+        let instance = primary_keymint_instance(&security_level).context(ks_err!())?;
+        RemProvState::new(security_level, instance, km_uuid).force_provision().context(ks_err!())
+    }
+
+    fn validate_generation_params(
+        security_level: SecurityLevel,
+        params: &[KeyParameter],
+    ) -> Result<()> {
+        // No permission check, for the same reason as for `get_keymint_backend_info`. Nothing
+        // is generated.
+        km_capabilities::validate_generation_params(params, security_level).context(ks_err!())
+    }
+
+    fn get_keys_bound_to_sid(sid: i64) -> Result<Vec<KeyDescriptor>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+
+        DB.with(|db| db.borrow_mut().list_keys_bound_to_sid(sid)).context(ks_err!())
+    }
+
+    fn revoke_keys_bound_to_sid(sid: i64) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+
+        let revoked =
+            DB.with(|db| db.borrow_mut().revoke_keys_bound_to_sid(sid)).context(ks_err!())?;
+        log::info!("Revoked {} keys bound to a removed authenticator enrollment.", revoked);
+        Ok(())
+    }
+
+    fn register_attestation_template(name: &str, params: &[KeyParameter]) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ChangeUser).context(ks_err!())?;
+
+        ATTESTATION_TEMPLATES.register(name, params).context(ks_err!())
+    }
+
+    fn dump_key_blob_metadata(key: &KeyDescriptor) -> Result<Vec<String>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+
+        let calling_uid = ThreadState::get_calling_uid();
+        // The keystore permission above authorizes the diagnostic for any key, and only
+        // non-secret metadata is returned, so no key permission is required.
+        let (_, key_entry) = DB
+            .with(|db| {
+                db.borrow_mut().load_key_entry(
+                    key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::KM,
+                    calling_uid,
+                    |_, _| Ok(()),
+                )
+            })
+            .context(ks_err!("Failed to load key entry."))?;
+        key_entry
+            .key_blob_info()
+            .as_ref()
+            .map(|(_, metadata)| metadata.describe())
+            .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context(ks_err!("Key has no key blob."))
+    }
+
+    fn verify_key_blobs() -> Result<Vec<KeyDescriptor>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+
+        let workers = read_prop_parsed(VERIFY_KEY_BLOBS_WORKERS_PROPERTY, 1, |v| {
+            v.parse::<usize>()
+                .ok()
+                .filter(|workers| (1..=MAX_VERIFY_KEY_BLOBS_WORKERS).contains(workers))
+        });
+        let (sender, receiver) = channel();
+        Self::verify_key_blobs_on_async_task(None, workers, Vec::new(), sender);
+        let failed = receiver
+            .recv()
+            .context(ks_err!("The verification was aborted."))?
+            .context(ks_err!("Failed to verify the key blobs."))?;
+        if !failed.is_empty() {
+            log::warn!("{} key blobs failed to unwrap.", failed.len());
+        }
+
+        failed
+            .into_iter()
+            .filter_map(|key_id| {
+                DB.with(|db| db.borrow_mut().load_key_descriptor(key_id))
+                    .context(ks_err!("Failed to load the descriptor of key {}.", key_id))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    /// Verifies one batch of key blobs as low priority job on the async task, and queues the
+    /// next batch. This keeps the async task responsive to high priority jobs while the
+    /// verification is in progress. Each batch is unwrapped by `workers` threads in parallel
+    /// and holds `VERIFY_KEY_BLOBS_BATCH_SIZE` blobs per worker. The ids of all keys whose blob
+    /// failed to unwrap are sent through `sender` once all key blobs were visited.
+    fn verify_key_blobs_on_async_task(
+        after_key_id: Option<i64>,
+        workers: usize,
+        mut failed: Vec<i64>,
+        sender: Sender<Result<Vec<i64>>>,
+    ) {
+        ASYNC_TASK.queue_lo(move |_shelf| {
+            let result = DB.with(|db| {
+                SUPER_KEY.read().unwrap().verify_key_blobs(
+                    &mut db.borrow_mut(),
+                    after_key_id,
+                    VERIFY_KEY_BLOBS_BATCH_SIZE * workers,
+                    workers,
+                )
+            });
+            match result {
+                Ok((mut batch_failed, next)) => {
+                    failed.append(&mut batch_failed);
+                    match next {
+                        Some(next) => {
+                            log::debug!(
+                                "Verified key blobs up to key {} with {} workers, {} failed.",
+                                next,
+                                workers,
+                                failed.len()
+                            );
+                            Self::verify_key_blobs_on_async_task(
+                                Some(next),
+                                workers,
+                                failed,
+                                sender,
+                            )
+                        }
+                        None => {
+                            // The receiver may be gone if the binder call was aborted.
+                            let _ = sender.send(Ok(failed));
+                        }
+                    }
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                }
+            }
+        });
+    }
+
+    fn check_database_consistency(repair: bool) -> Result<Vec<String>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+        if repair {
+            // Security critical permission check. This statement must return on fail.
+            check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+        }
+
+        let report = DB.with(|db| db.borrow_mut().check_consistency(repair)).context(ks_err!())?;
+        let skewed = Self::find_version_skew().context(ks_err!())?;
+        if report != ConsistencyReport::default() {
+            log::warn!(
+                "Found {} blobs without key, {} keys without blob, and {} grants without key{}.",
+                report.blobs_without_key.len(),
+                report.keys_without_blob.len(),
+                report.grants_without_key.len(),
+                if repair { ", repairing" } else { "" }
+            );
+        }
+        Ok(report
+            .blobs_without_key
+            .iter()
+            .map(|blob_id| format!("blob {} has no key", blob_id))
+            .chain(
+                report.keys_without_blob.iter().map(|key_id| format!("key {} has no blob", key_id)),
+            )
+            .chain(
+                report
+                    .grants_without_key
+                    .iter()
+                    .map(|grant_id| format!("grant {} has no key", grant_id)),
+            )
+            .chain(skewed.iter().map(|key_id| {
+                format!("key {} is wrapped with a retired super key version", key_id)
+            }))
+            .collect())
+    }
+
+    /// Returns the ids of all keys whose blob is wrapped with a super key version that is no
+    /// longer present, see `SuperKeyManager::find_version_skew`. These blobs are reported but
+    /// cannot be repaired here, because only the rotation that retired the version still holds
+    /// it, see `SuperKeyManager::repair_version_skew`.
+    fn find_version_skew() -> Result<Vec<i64>> {
+        let mut skewed = Vec::new();
+        let mut after_key_id = None;
+        loop {
+            let (mut batch_skewed, next) = DB
+                .with(|db| {
+                    SUPER_KEY.read().unwrap().find_version_skew(
+                        &mut db.borrow_mut(),
+                        after_key_id,
+                        VERIFY_KEY_BLOBS_BATCH_SIZE,
+                    )
+                })
+                .context(ks_err!("Failed to check the key blobs for version skew."))?;
+            skewed.append(&mut batch_skewed);
+            match next {
+                Some(next) => after_key_id = Some(next),
+                None => return Ok(skewed),
+            }
+        }
+    }
+
+    fn list_operations() -> Result<Vec<i64>> {
+        let caller_uid = ThreadState::get_calling_uid();
+        let owner = match check_keystore_permission(KeystorePerm::ClearUID) {
+            Ok(()) => None,
+            Err(_) => Some(caller_uid),
+        };
+        Ok(list_operation_ids(owner))
+    }
+
+    fn abort_operation(operation_id: i64) -> Result<()> {
+        let caller_uid = ThreadState::get_calling_uid();
+        // Callers may abort their own operations. Aborting the operations of other callers
+        // requires the 'ClearUID' permission, which abort_operation_by_id enforces.
+        let privileged = check_keystore_permission(KeystorePerm::ClearUID).is_ok();
+        abort_operation_by_id(operation_id, caller_uid, privileged).context(ks_err!())
+    }
+
+    fn resume_operation(operation_id: i64) -> Result<CreateOperationResponse> {
+        operation_checkpoint::resume(operation_id, ThreadState::get_calling_uid())
+            .context(ks_err!())
+    }
+
+    fn mint_operation_token(
+        key: &KeyDescriptor,
+        purpose: KeyPurpose,
+        delegate_uid: i32,
+        validity_ms: i64,
+    ) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::DelegateOperation).context(ks_err!())?;
+
+        let caller_uid = ThreadState::get_calling_uid();
+        let validity = u64::try_from(validity_ms)
+            .map(Duration::from_millis)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("The validity must not be negative."))?;
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+        let (key_id_guard, _) = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::NONE,
+                        caller_uid,
+                        // Security critical permission check. This statement must return on fail.
+                        |k, av| check_key_permission(KeyPerm::Use, k, &av),
+                    )
+                })
+            })
+            .context(ks_err!("Failed to load key entry."))?;
+        OPERATION_TOKENS
+            .mint(&SystemClock, key_id_guard.id(), purpose, delegate_uid as u32, validity)
+            .context(ks_err!())
+    }
+
+    fn list_operations_for_key(key_id: i64) -> Result<Vec<OperationInfo>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+
+        Ok(list_operations_for_key(key_id)
+            .into_iter()
+            .map(|op| OperationInfo {
+                operationId: op.id,
+                ownerUid: op.owner as i32,
+                securityLevel: op.sec_level,
+                purpose: op.purpose,
+                forced: op.forced,
+                idleMillis: op.idle.as_millis().try_into().unwrap_or(i64::MAX),
+            })
+            .collect())
+    }
+
+    fn set_key_operation_log_capacity(key_id: i64, capacity: i32) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+
+        let capacity = usize::try_from(capacity)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("The capacity must not be negative."))?;
+        KEY_OPERATION_LOGS.set_capacity(key_id, capacity).context(ks_err!())?;
+        log::info!("Set the operation log capacity of key {} to {}.", key_id, capacity);
+        Ok(())
+    }
+
+    fn get_key_operation_log(key_id: i64) -> Result<Option<Vec<KeyOperationRecord>>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+
+        Ok(KEY_OPERATION_LOGS.records(key_id).map(|records| {
+            records
+                .into_iter()
+                .map(|record| KeyOperationRecord {
+                    purpose: record.purpose,
+                    outcome: format!("{:?}", record.outcome),
+                    timestampMillis: record.timestamp_millis,
+                })
+                .collect()
+        }))
+    }
+
+    fn set_key_frozen(key: &KeyDescriptor, frozen: bool) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+
+        let calling_uid = ThreadState::get_calling_uid();
+        // The keystore permission above authorizes freezing any key, so no key permission is
+        // required.
+        DB.with(|db| {
+            db.borrow_mut().set_key_frozen(key, KeyType::Client, calling_uid, frozen, |_, _| Ok(()))
+        })
+        .context(ks_err!("Failed to set the frozen state of the key."))?;
+        log::info!(
+            "{} key with domain {:?} and namespace {}.",
+            if frozen { "Froze" } else { "Unfroze" },
+            key.domain,
+            key.nspace
+        );
+        Ok(())
+    }
+
+    fn set_key_exportable(key: &KeyDescriptor, exportable: bool) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+
+        let calling_uid = ThreadState::get_calling_uid();
+        // The keystore permission above authorizes marking any key, so no key permission is
+        // required.
+        DB.with(|db| {
+            db.borrow_mut().set_key_exportable(
+                key,
+                KeyType::Client,
+                calling_uid,
+                exportable,
+                |_, _| Ok(()),
+            )
+        })
+        .context(ks_err!("Failed to set the exportable state of the key."))?;
+        log::info!(
+            "Marked key with domain {:?} and namespace {} {}.",
+            key.domain,
+            key.nspace,
+            if exportable { "exportable" } else { "not exportable" }
+        );
+        Ok(())
+    }
+
+    fn get_key_backend(key: &KeyDescriptor) -> Result<KeyBackend> {
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+        let km_uuid = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_km_uuid(
+                        key,
+                        KeyType::Client,
+                        caller_uid,
+                        // Security critical permission check. This statement must return on fail.
+                        |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
+                    )
+                })
+            })
+            .context(ks_err!("Failed to load the KeyMint uuid of the key."))?;
+        Ok(KeyBackend { securityLevel: km_uuid.security_level(), kmUuid: km_uuid.to_vec() })
+    }
+
+    fn get_key_inventory_snapshot(domain: Domain, nspace: i64) -> Result<Vec<KeyInventoryEntry>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+
+        if !(domain == Domain::APP || domain == Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Domain {:?} must be either APP or SELINUX.", domain));
+        }
+        let snapshot = DB
+            .with(|db| db.borrow_mut().snapshot_key_inventory(domain, nspace))
+            .context(ks_err!("Failed to take the snapshot."))?;
+        Ok(snapshot
+            .entries
+            .into_iter()
+            .map(|e| KeyInventoryEntry { alias: e.alias, keyId: e.key_id, digest: e.digest })
+            .collect())
+    }
+
+    fn list_expiring_keys(domain: Domain, nspace: i64, window_ms: i64) -> Result<Vec<ExpiringKey>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+
+        if !(domain == Domain::APP || domain == Domain::SELINUX) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Domain {:?} must be either APP or SELINUX.", domain));
+        }
+        let window = u64::try_from(window_ms)
+            .map(Duration::from_millis)
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Window {} must not be negative.", window_ms))?;
+        let now = DateTime::now().context(ks_err!("Failed to get the current time."))?;
+        let expiring = DB
+            .with(|db| list_keys_expiring_within(&mut db.borrow_mut(), domain, nspace, now, window))
+            .context(ks_err!("Failed to list the expiring keys."))?;
+        Ok(expiring
+            .into_iter()
+            .map(|key| ExpiringKey {
+                alias: key.alias,
+                keyId: key.key_id,
+                expiryTimeMs: key.expires.to_millis_epoch(),
+                attestationChainExpiry: key.cause == ExpiryCause::AttestationChain,
+            })
+            .collect())
+    }
+
+    fn wrap_key_for_export(key: &KeyDescriptor, recipient_public_key: &[u8]) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::ClearUID).context(ks_err!())?;
+
+        let calling_uid = ThreadState::get_calling_uid();
+        let (key_id_guard, mut key_entry) = DB
+            .with(|db| {
+                db.borrow_mut().load_key_entry(
+                    key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::KM,
+                    calling_uid,
+                    |_, _| Ok(()),
+                )
+            })
+            .context(ks_err!("Failed to load key entry."))?;
+        // Check before the key material is decrypted.
+        key_export::check_exportable(&key_entry).context(ks_err!())?;
+        let (blob, blob_metadata) = key_entry
+            .take_key_blob_info()
+            .ok_or_else(Error::sys)
+            .context(ks_err!("Key entry has no key blob."))?;
+        let binding = DB
+            .with(|db| {
+                BlobBinding::load_if_bound(&mut db.borrow_mut(), key_id_guard.id(), &blob_metadata)
+            })
+            .context(ks_err!())?;
+        let key_blob = SUPER_KEY
+            .read()
+            .unwrap()
+            .unwrap_key_for_export(&blob_metadata, &blob, binding.as_ref())
+            .context(ks_err!("Failed to handle super encryption."))?;
+        let wrapped = key_export::wrap_key_for_export(&key_entry, &key_blob, recipient_public_key)
+            .context(ks_err!())?;
+        log::info!(
+            "Wrapped key {} with domain {:?} and namespace {} for export.",
+            key_id_guard.id(),
+            key.domain,
+            key.nspace
+        );
+        Ok(wrapped)
+    }
+
+    fn migrate_key_security_level(
+        key: &KeyDescriptor,
+        security_level: SecurityLevel,
+        key_material: Option<&[u8]>,
+        allow_regeneration: bool,
+    ) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::MigrateSecurityLevel).context(ks_err!())?;
+
+        let source = key_migration::MigrationSource::select(key_material, allow_regeneration)
+            .context(ks_err!())?;
+        let target = KeyMintDevice::get(security_level)
+            .context(ks_err!("Failed to get the KeyMint device for {:?}.", security_level))?;
+        let calling_uid = ThreadState::get_calling_uid();
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let (key_id_guard, key_entry) = db
+                .load_key_entry(
+                    key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::BOTH,
+                    calling_uid,
+                    |_, _| Ok(()),
+                )
+                .context(ks_err!("Failed to load key entry."))?;
+            // Check before the key material is decrypted.
+            key_migration::check_migratable(&key_entry, target.security_level())
+                .context(ks_err!())?;
+            let (blob, blob_metadata) = key_entry
+                .key_blob_info()
+                .as_ref()
+                .ok_or_else(Error::sys)
+                .context(ks_err!("Key entry has no key blob."))?;
+            let owner = db
+                .load_key_descriptor(key_id_guard.id())
+                .context(ks_err!("Failed to load the key descriptor."))?
+                .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+                .context(ks_err!("Key entry is gone."))?;
+            let binding =
+                blob_metadata.bound_user_id().map(|_| BlobBinding::new(key_id_guard.id(), &owner));
+            let key_blob = SUPER_KEY
+                .read()
+                .unwrap()
+                .unwrap_key_if_required(blob_metadata, blob, binding.as_ref())
+                .context(ks_err!("Failed to handle super encryption."))?;
+            key_migration::migrate_key(
+                &mut db,
+                &key_id_guard,
+                &key_entry,
+                &target,
+                source,
+                |new_blob| {
+                    // The new key blob is super encrypted like the original one.
+                    let (new_blob, blob_metadata) =
+                        SuperKeyManager::reencrypt_if_required(&key_blob, new_blob, || {
+                            Ok(BlobBinding::new(key_id_guard.id(), &owner))
+                        })?;
+                    Ok((new_blob.to_vec(), blob_metadata))
+                },
+            )
+            .context(ks_err!("Failed to migrate the key."))
+        })
+        .map(|_| ())
+    }
+
+    fn finish_key_migration(key: &KeyDescriptor, commit: bool) -> Result<()> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::MigrateSecurityLevel).context(ks_err!())?;
+
+        let calling_uid = ThreadState::get_calling_uid();
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let (key_id_guard, _) = db
+                .load_key_entry(
+                    key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::NONE,
+                    calling_uid,
+                    |_, _| Ok(()),
+                )
+                .context(ks_err!("Failed to load key entry."))?;
+            if commit {
+                db.commit_key_backend(&key_id_guard).context(ks_err!())?;
+                log::info!("Committed the migration of key {}.", key_id_guard.id());
+            } else {
+                db.discard_key_backend(&key_id_guard).context(ks_err!())?;
+                log::info!("Discarded the migration of key {}.", key_id_guard.id());
+            }
+            Ok(())
+        })
+    }
+
+    fn get_key_fingerprint(key: &KeyDescriptor) -> Result<Option<KeyFingerprint>> {
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+        DB.with(|db| {
+            LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                db.borrow_mut().load_key_fingerprint(
+                    key,
+                    KeyType::Client,
+                    caller_uid,
+                    // Security critical permission check. This statement must return on fail.
+                    |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
+                )
+            })
+        })
+        .map(|fingerprint| fingerprint.map(|f| f.to_aidl()))
+        .context(ks_err!("Failed to load the key fingerprint."))
+    }
+
+    fn get_key_creation_parameters(key: &KeyDescriptor) -> Result<Vec<Authorization>> {
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+        DB.with(|db| {
+            LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                db.borrow_mut().load_key_creation_parameters(
+                    key,
+                    KeyType::Client,
+                    caller_uid,
+                    // Security critical permission check. This statement must return on fail.
+                    |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
+                )
+            })
+        })
+        .map(key_parameters_to_authorizations)
+        .context(ks_err!("Failed to load the creation parameters of the key."))
+    }
+
+    fn get_super_key_identity(key_id: i64) -> Result<SuperKeyIdentity> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+
+        // The keystore permission above authorizes the diagnostic for any key, and no key
+        // material is returned, so no key permission is required.
+        let identity = DB
+            .with(|db| db.borrow_mut().get_super_key_identity(key_id))
+            .context(ks_err!("Failed to get the super key identity."))?;
+        Ok(match identity {
+            DbSuperKeyIdentity::None => {
+                SuperKeyIdentity { protection: SuperKeyProtection::NONE, ..Default::default() }
+            }
+            DbSuperKeyIdentity::SuperKey { alias, key_id, version } => SuperKeyIdentity {
+                protection: SuperKeyProtection::SUPER_KEY,
+                alias: Some(alias),
+                superKeyId: key_id,
+                version,
+                ..Default::default()
+            },
+            DbSuperKeyIdentity::BootLevel(boot_level) => SuperKeyIdentity {
+                protection: SuperKeyProtection::BOOT_LEVEL,
+                bootLevel: boot_level,
+                ..Default::default()
+            },
+            DbSuperKeyIdentity::Password => {
+                SuperKeyIdentity { protection: SuperKeyProtection::PASSWORD, ..Default::default() }
+            }
+        })
+    }
+
+    fn export_public_keys(
+        domain: Domain,
+        nspace: i64,
+        start_past_alias: Option<&str>,
+    ) -> Result<Vec<PublicKeyEntry>> {
+        // Security critical permission check. This statement must return on fail.
+        let k = get_key_descriptor_for_lookup(domain, nspace).context(ks_err!())?;
+
+        let parts = DB
+            .with(|db| {
+                db.borrow_mut().list_public_parts_past_alias(
+                    k.domain,
+                    k.nspace,
+                    KeyType::Client,
+                    start_past_alias,
+                    RESPONSE_SIZE_LIMIT,
+                )
+            })
+            .context(ks_err!("Failed to load the public parts of the keys."))?;
+        Ok(parts
+            .into_iter()
+            .map(|p| PublicKeyEntry {
+                alias: p.alias,
+                certificate: p.cert,
+                certificateChain: p.cert_chain,
+            })
+            .collect())
+    }
+
+    fn export_backup(passphrase: &[u8]) -> Result<Vec<u8>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Backup).context(ks_err!())?;
+
+        let (archive, _) = DB
+            .with(|db| db.borrow_mut().export_backup(&passphrase.into()))
+            .context(ks_err!("Failed to export the backup."))?;
+        Ok(archive)
+    }
+
+    fn import_backup(archive: &[u8], passphrase: &[u8]) -> Result<Vec<BackupEntry>> {
+        // Security critical permission check. This statement must return on fail.
+        check_keystore_permission(KeystorePerm::Backup).context(ks_err!())?;
+
+        let entries = DB
+            .with(|db| {
+                db.borrow_mut().import_backup(
+                    archive,
+                    &passphrase.into(),
+                    &|owner, old_key_id, new_key_id, sc_type, blob, metadata| {
+                        SUPER_KEY.read().unwrap().rewrap_imported_blob(
+                            owner, old_key_id, new_key_id, sc_type, blob, metadata,
+                        )
+                    },
+                )
+            })
+            .context(ks_err!("Failed to import the backup."))?;
+        Ok(entries
+            .into_iter()
+            .map(|e| BackupEntry { keyId: e.key_id, hardwareBound: e.hardware_bound })
+            .collect())
+    }
+
+    fn export_attestation_chain_pem(key: &KeyDescriptor) -> Result<Option<String>> {
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+        let (_, mut key_entry) = DB
+            .with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::PUBLIC,
+                        caller_uid,
+                        // Security critical permission check. This statement must return on fail.
+                        |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
+                    )
+                })
+            })
+            .context(ks_err!("Failed to load key entry."))?;
+        let mut chain = match key_entry.take_cert() {
+            Some(cert) => cert,
+            None => return Ok(None),
+        };
+        let cert_chain = DB
+            .with(|db| {
+                SUPER_KEY.read().unwrap().take_cert_chain(&mut db.borrow_mut(), &mut key_entry)
+            })
+            .context(ks_err!("Failed to unwrap the certificate chain."))?;
+        if let Some(cert_chain) = cert_chain {
+            chain.extend(cert_chain);
+        }
+        certificate_chain_to_pem(&chain)
+            .map(Some)
+            .map_err(|_| Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            .context(ks_err!("Stored certificate chain is malformed."))
+    }
+
+    fn attach_certificate_chain(key: &KeyDescriptor, certificate_chain: &[u8]) -> Result<()> {
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+        DB.with(|db| {
+            let (key_id_guard, _) = LEGACY_IMPORTER
+                .with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().load_key_entry(
+                        key,
+                        KeyType::Client,
+                        KeyEntryLoadBits::NONE,
+                        caller_uid,
+                        // Security critical permission check. This statement must return on fail.
+                        |k, av| check_key_permission(KeyPerm::Update, k, &av),
+                    )
+                })
+                .context(ks_err!("Failed to load key entry."))?;
+            db.borrow_mut()
+                .attach_certificate_chain(&key_id_guard, certificate_chain)
+                .context(ks_err!("Failed to attach the certificate chain."))
+        })
+    }
+
+    fn grant_batch(
+        key: &KeyDescriptor,
+        grantee_uids: &[i32],
+        access_vector: i32,
+    ) -> Result<Vec<KeyDescriptor>> {
+        let grantee_uids = grantee_uids
+            .iter()
+            .map(|uid| u32::try_from(*uid))
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Grantee uids must not be negative."))?;
+        let caller_uid = ThreadState::get_calling_uid();
+        let super_key =
+            SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+
+        DB.with(|db| {
+            LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                db.borrow_mut().grant_batch(
+                    key,
+                    caller_uid,
+                    &grantee_uids,
+                    access_vector.into(),
+                    // Security critical permission check. Like IKeystoreService::grant, this
+                    // requires the grant permission for the key and all of the granted
+                    // permissions.
+                    |k, av| check_grant_permission(*av, k).context("During grant_batch."),
+                )
+            })
+        })
+        .context(ks_err!("Failed to grant key."))
+    }
+
+    fn grant_batch_best_effort(
+        key: &KeyDescriptor,
+        grantee_uids: &[i32],
+        access_vector: i32,
+    ) -> Result<Vec<GrantResult>> {
+        if grantee_uids.is_empty() {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("No grantees given."));
+        }
+        // Negative uids only fail their own entry. The others are granted as one batch.
+        let valid_uids: Vec<u32> =
+            grantee_uids.iter().filter_map(|uid| u32::try_from(*uid).ok()).collect();
+        let results = if valid_uids.is_empty() {
+            vec![]
+        } else {
+            let caller_uid = ThreadState::get_calling_uid();
+            let super_key = SUPER_KEY
+                .read()
+                .unwrap()
+                .get_per_boot_key_by_user_id(uid_to_android_user(caller_uid));
+            DB.with(|db| {
+                LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
+                    db.borrow_mut().grant_batch_with_mode(
+                        key,
+                        caller_uid,
+                        &valid_uids,
+                        access_vector.into(),
+                        BatchMode::BestEffort,
+                        // Security critical permission check. See `grant_batch`.
+                        |k, av| {
+                            check_grant_permission(*av, k)
+                                .context("During grant_batch_best_effort.")
+                        },
+                    )
+                })
+            })
+            .context(ks_err!("Failed to grant key."))?
+        };
+        let mut results = results.into_iter();
+        Ok(grantee_uids
+            .iter()
+            .map(|uid| {
+                let result = match u32::try_from(*uid) {
+                    Ok(_) => results.next().unwrap_or_else(|| {
+                        Err(Error::sys()).context(ks_err!("Missing result for grantee {}.", uid))
+                    }),
+                    Err(_) => Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                        .context(ks_err!("Grantee uid {} is negative.", uid)),
+                };
+                match result {
+                    Ok(grant) => GrantResult { grant: Some(grant), errorCode: 0 },
+                    Err(e) => {
+                        log::warn!("Failed to grant key to {}: {:?}", uid, e);
+                        GrantResult { grant: None, errorCode: get_error_code(&e) }
+                    }
+                }
+            })
+            .collect())
+    }
+
     fn delete_all_keys() -> Result<()> {
         // Security critical permission check. This statement must return on fail.
         check_keystore_permission(KeystorePerm::DeleteAllKeys)
@@ -276,6 +1217,32 @@ impl Maintenance {
 
         Maintenance::call_on_all_security_levels("deleteAllKeys", |dev| dev.deleteAllKeys())
     }
+
+    fn cancel_background_work() -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::ChangeUser).context(ks_err!())?;
+        log::info!("In cancel_background_work.");
+
+        GC.cancel();
+        LEGACY_IMPORTER.cancel_bulk_deletes();
+        Ok(())
+    }
+
+    fn register_key_lifecycle_observer(observer: &Strong<dyn IKeyLifecycleObserver>) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+        register_binder_observer(observer);
+        Ok(())
+    }
+
+    fn unregister_key_lifecycle_observer(
+        observer: &Strong<dyn IKeyLifecycleObserver>,
+    ) -> Result<()> {
+        // Permission check. Must return on error. Do not touch the '?'.
+        check_keystore_permission(KeystorePerm::List).context(ks_err!())?;
+        unregister_binder_observer(observer);
+        Ok(())
+    }
 }
 
 impl Interface for Maintenance {}
@@ -306,6 +1273,11 @@ impl IKeystoreMaintenance for Maintenance {
         map_or_log_err(Self::get_state(user_id), Ok)
     }
 
+    fn isUserUnlocked(&self, user_id: i32) -> BinderResult<bool> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::isUserUnlocked", 500);
+        map_or_log_err(Self::is_user_unlocked(user_id), Ok)
+    }
+
     fn earlyBootEnded(&self) -> BinderResult<()> {
         let _wp = wd::watch_millis("IKeystoreMaintenance::earlyBootEnded", 500);
         map_or_log_err(Self::early_boot_ended(), Ok)
@@ -329,4 +1301,272 @@ impl IKeystoreMaintenance for Maintenance {
         let _wp = wd::watch_millis("IKeystoreMaintenance::deleteAllKeys", 500);
         map_or_log_err(Self::delete_all_keys(), Ok)
     }
+
+    fn getDeviceIdentifier(&self, context: &[u8]) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getDeviceIdentifier", 500);
+        map_or_log_err(Self::get_device_identifier(context), Ok)
+    }
+
+    fn deriveAppKey(&self, context: &[u8]) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::deriveAppKey", 500);
+        map_or_log_err(Self::derive_app_key(context), Ok)
+    }
+
+    fn getKeyIdAllocations(&self) -> BinderResult<Vec<KeyIdAllocation>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyIdAllocations", 500);
+        map_or_log_err(Self::get_key_id_allocations(), Ok)
+    }
+
+    fn getKeyMintBackendInfo(
+        &self,
+        security_level: SecurityLevel,
+    ) -> BinderResult<KeyMintBackendInfo> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyMintBackendInfo", 500);
+        map_or_log_err(Self::get_keymint_backend_info(security_level), Ok)
+    }
+
+    fn forceRkpProvisioning(&self, security_level: SecurityLevel) -> BinderResult<()> {
+        // Provisioning involves RKPD and the network, so it may take a while.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::forceRkpProvisioning", 5000);
+        map_or_log_err(Self::force_rkp_provisioning(security_level), Ok)
+    }
+
+    fn validateGenerationParams(
+        &self,
+        security_level: SecurityLevel,
+        params: &[KeyParameter],
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::validateGenerationParams", 500);
+        map_or_log_err(Self::validate_generation_params(security_level, params), Ok)
+    }
+
+    fn getKeysBoundToSid(&self, secure_user_id: i64) -> BinderResult<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeysBoundToSid", 500);
+        map_or_log_err(Self::get_keys_bound_to_sid(secure_user_id), Ok)
+    }
+
+    fn revokeKeysBoundToSid(&self, secure_user_id: i64) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::revokeKeysBoundToSid", 500);
+        map_or_log_err(Self::revoke_keys_bound_to_sid(secure_user_id), Ok)
+    }
+
+    fn dumpKeyBlobMetaData(&self, key: &KeyDescriptor) -> BinderResult<Vec<String>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::dumpKeyBlobMetaData", 500);
+        map_or_log_err(Self::dump_key_blob_metadata(key), Ok)
+    }
+
+    fn checkDatabaseConsistency(&self, repair: bool) -> BinderResult<Vec<String>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::checkDatabaseConsistency", 500);
+        map_or_log_err(Self::check_database_consistency(repair), Ok)
+    }
+
+    fn registerAttestationTemplate(&self, name: &str, params: &[KeyParameter]) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::registerAttestationTemplate", 500);
+        map_or_log_err(Self::register_attestation_template(name, params), Ok)
+    }
+
+    fn verifyKeyBlobs(&self) -> BinderResult<Vec<KeyDescriptor>> {
+        // The verification visits every key blob, so allow for more time than usual.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::verifyKeyBlobs", 10000);
+        map_or_log_err(Self::verify_key_blobs(), Ok)
+    }
+
+    fn listOperations(&self) -> BinderResult<Vec<i64>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::listOperations", 500);
+        map_or_log_err(Self::list_operations(), Ok)
+    }
+
+    fn abortOperation(&self, operation_id: i64) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::abortOperation", 500);
+        map_or_log_err(Self::abort_operation(operation_id), Ok)
+    }
+
+    fn resumeOperation(&self, operation_id: i64) -> BinderResult<CreateOperationResponse> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::resumeOperation", 500);
+        map_or_log_err(Self::resume_operation(operation_id), Ok)
+    }
+
+    fn mintOperationToken(
+        &self,
+        key: &KeyDescriptor,
+        purpose: KeyPurpose,
+        delegate_uid: i32,
+        validity_ms: i64,
+    ) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::mintOperationToken", 500);
+        map_or_log_err(Self::mint_operation_token(key, purpose, delegate_uid, validity_ms), Ok)
+    }
+
+    fn listOperationsForKey(&self, key_id: i64) -> BinderResult<Vec<OperationInfo>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::listOperationsForKey", 500);
+        map_or_log_err(Self::list_operations_for_key(key_id), Ok)
+    }
+
+    fn setKeyOperationLogCapacity(&self, key_id: i64, capacity: i32) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setKeyOperationLogCapacity", 500);
+        map_or_log_err(Self::set_key_operation_log_capacity(key_id, capacity), Ok)
+    }
+
+    fn getKeyOperationLog(&self, key_id: i64) -> BinderResult<Option<Vec<KeyOperationRecord>>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyOperationLog", 500);
+        map_or_log_err(Self::get_key_operation_log(key_id), Ok)
+    }
+
+    fn setKeyFrozen(&self, key: &KeyDescriptor, frozen: bool) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setKeyFrozen", 500);
+        map_or_log_err(Self::set_key_frozen(key, frozen), Ok)
+    }
+
+    fn grantBatch(
+        &self,
+        key: &KeyDescriptor,
+        grantee_uids: &[i32],
+        access_vector: i32,
+    ) -> BinderResult<Vec<KeyDescriptor>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::grantBatch", 500);
+        map_or_log_err(Self::grant_batch(key, grantee_uids, access_vector), Ok)
+    }
+
+    fn grantBatchBestEffort(
+        &self,
+        key: &KeyDescriptor,
+        grantee_uids: &[i32],
+        access_vector: i32,
+    ) -> BinderResult<Vec<GrantResult>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::grantBatchBestEffort", 500);
+        map_or_log_err(Self::grant_batch_best_effort(key, grantee_uids, access_vector), Ok)
+    }
+
+    fn setKeyExportable(&self, key: &KeyDescriptor, exportable: bool) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::setKeyExportable", 500);
+        map_or_log_err(Self::set_key_exportable(key, exportable), Ok)
+    }
+
+    fn wrapKeyForExport(
+        &self,
+        key: &KeyDescriptor,
+        recipient_public_key: &[u8],
+    ) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::wrapKeyForExport", 500);
+        map_or_log_err(Self::wrap_key_for_export(key, recipient_public_key), Ok)
+    }
+
+    fn getKeyBackend(&self, key: &KeyDescriptor) -> BinderResult<KeyBackend> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyBackend", 500);
+        map_or_log_err(Self::get_key_backend(key), Ok)
+    }
+
+    fn getKeyInventorySnapshot(
+        &self,
+        domain: Domain,
+        nspace: i64,
+    ) -> BinderResult<Vec<KeyInventoryEntry>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyInventorySnapshot", 500);
+        map_or_log_err(Self::get_key_inventory_snapshot(domain, nspace), Ok)
+    }
+
+    fn migrateKeySecurityLevel(
+        &self,
+        key: &KeyDescriptor,
+        security_level: SecurityLevel,
+        key_material: Option<&[u8]>,
+        allow_regeneration: bool,
+    ) -> BinderResult<()> {
+        // Generating the equivalent key, especially an RSA key, may take a while.
+        let _wp = wd::watch_millis("IKeystoreMaintenance::migrateKeySecurityLevel", 5000);
+        map_or_log_err(
+            Self::migrate_key_security_level(key, security_level, key_material, allow_regeneration),
+            Ok,
+        )
+    }
+
+    fn commitKeyMigration(&self, key: &KeyDescriptor) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::commitKeyMigration", 500);
+        map_or_log_err(Self::finish_key_migration(key, true), Ok)
+    }
+
+    fn discardKeyMigration(&self, key: &KeyDescriptor) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::discardKeyMigration", 500);
+        map_or_log_err(Self::finish_key_migration(key, false), Ok)
+    }
+
+    fn getKeyFingerprint(&self, key: &KeyDescriptor) -> BinderResult<Option<KeyFingerprint>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyFingerprint", 500);
+        map_or_log_err(Self::get_key_fingerprint(key), Ok)
+    }
+
+    fn exportAttestationChainPem(&self, key: &KeyDescriptor) -> BinderResult<Option<String>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportAttestationChainPem", 500);
+        map_or_log_err(Self::export_attestation_chain_pem(key), Ok)
+    }
+
+    fn listExpiringKeys(
+        &self,
+        domain: Domain,
+        nspace: i64,
+        window_ms: i64,
+    ) -> BinderResult<Vec<ExpiringKey>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::listExpiringKeys", 500);
+        map_or_log_err(Self::list_expiring_keys(domain, nspace, window_ms), Ok)
+    }
+
+    fn attachCertificateChain(
+        &self,
+        key: &KeyDescriptor,
+        certificate_chain: &[u8],
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::attachCertificateChain", 500);
+        map_or_log_err(Self::attach_certificate_chain(key, certificate_chain), Ok)
+    }
+
+    fn getKeyCreationParameters(&self, key: &KeyDescriptor) -> BinderResult<Vec<Authorization>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getKeyCreationParameters", 500);
+        map_or_log_err(Self::get_key_creation_parameters(key), Ok)
+    }
+
+    fn getSuperKeyIdentity(&self, key_id: i64) -> BinderResult<SuperKeyIdentity> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::getSuperKeyIdentity", 500);
+        map_or_log_err(Self::get_super_key_identity(key_id), Ok)
+    }
+
+    fn exportPublicKeys(
+        &self,
+        domain: Domain,
+        nspace: i64,
+        start_past_alias: Option<&str>,
+    ) -> BinderResult<Vec<PublicKeyEntry>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportPublicKeys", 500);
+        map_or_log_err(Self::export_public_keys(domain, nspace, start_past_alias), Ok)
+    }
+
+    fn exportBackup(&self, passphrase: &[u8]) -> BinderResult<Vec<u8>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::exportBackup", 500);
+        map_or_log_err(Self::export_backup(passphrase), Ok)
+    }
+
+    fn importBackup(&self, archive: &[u8], passphrase: &[u8]) -> BinderResult<Vec<BackupEntry>> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::importBackup", 500);
+        map_or_log_err(Self::import_backup(archive, passphrase), Ok)
+    }
+
+    fn cancelBackgroundWork(&self) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::cancelBackgroundWork", 500);
+        map_or_log_err(Self::cancel_background_work(), Ok)
+    }
+
+    fn registerKeyLifecycleObserver(
+        &self,
+        observer: &Strong<dyn IKeyLifecycleObserver>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::registerKeyLifecycleObserver", 500);
+        map_or_log_err(Self::register_key_lifecycle_observer(observer), Ok)
+    }
+
+    fn unregisterKeyLifecycleObserver(
+        &self,
+        observer: &Strong<dyn IKeyLifecycleObserver>,
+    ) -> BinderResult<()> {
+        let _wp = wd::watch_millis("IKeystoreMaintenance::unregisterKeyLifecycleObserver", 500);
+        map_or_log_err(Self::unregister_key_lifecycle_observer(observer), Ok)
+    }
 }
@@ -45,6 +45,13 @@ impl Metrics {
         check_keystore_permission(KeystorePerm::PullMetrics).context(ks_err!())?;
         METRICS_STORE.get_atoms(atom_id)
     }
+
+    fn get_metrics_snapshot(&self) -> Result<String> {
+        // Check permission. Function should return if this failed. Therefore having '?' at the end
+        // is very important.
+        check_keystore_permission(KeystorePerm::PullMetrics).context(ks_err!())?;
+        Ok(METRICS_STORE.render_text_snapshot())
+    }
 }
 
 impl Interface for Metrics {}
@@ -54,4 +61,9 @@ impl IKeystoreMetrics for Metrics {
         let _wp = wd::watch_millis("IKeystoreMetrics::pullMetrics", 500);
         map_or_log_err(self.pull_metrics(atom_id), Ok)
     }
+
+    fn getMetricsSnapshot(&self) -> BinderResult<String> {
+        let _wp = wd::watch_millis("IKeystoreMetrics::getMetricsSnapshot", 500);
+        map_or_log_err(self.get_metrics_snapshot(), Ok)
+    }
 }
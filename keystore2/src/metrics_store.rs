@@ -17,11 +17,16 @@
 //!    stores them in an in-memory store.
 //! 2. Returns the collected metrics when requested by the statsd proxy.
 
-use crate::error::get_error_code;
-use crate::globals::DB;
+use crate::attestation_self_test::SelfTestOutcome;
+use crate::database::{KeyIdLockMode, KeyIdLockOperation, Uuid};
+use crate::error::{get_error_code, Error, ErrorCode};
+use crate::globals::{get_keymint_dev_by_uuid, DB};
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+use crate::keymint_timeout::KeyMintCallType;
 use crate::ks_err;
+use crate::latency_budget::GenerationStep;
 use crate::operation::Outcome;
+use crate::sysprop::read_prop_parsed;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
     HardwareAuthenticatorType::HardwareAuthenticatorType, KeyOrigin::KeyOrigin,
@@ -29,23 +34,37 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
     SecurityLevel::SecurityLevel,
 };
 use android_security_metrics::aidl::android::security::metrics::{
-    Algorithm::Algorithm as MetricsAlgorithm, AtomID::AtomID, CrashStats::CrashStats,
-    EcCurve::EcCurve as MetricsEcCurve,
+    Algorithm::Algorithm as MetricsAlgorithm, AtomID::AtomID,
+    AttestationCertExpiryStats::AttestationCertExpiryStats,
+    AttestationFailureReason::AttestationFailureReason as MetricsAttestationFailureReason,
+    AttestationFailureStats::AttestationFailureStats,
+    AttestationSelfTestOutcome::AttestationSelfTestOutcome as MetricsAttestationSelfTestOutcome,
+    AttestationSelfTestStats::AttestationSelfTestStats, CrashStats::CrashStats,
+    DatabaseContentionStats::DatabaseContentionStats, EcCurve::EcCurve as MetricsEcCurve,
     HardwareAuthenticatorType::HardwareAuthenticatorType as MetricsHardwareAuthenticatorType,
+    ImportedKeyPurposeStats::ImportedKeyPurposeStats,
     KeyCreationWithAuthInfo::KeyCreationWithAuthInfo,
     KeyCreationWithGeneralInfo::KeyCreationWithGeneralInfo,
     KeyCreationWithPurposeAndModesInfo::KeyCreationWithPurposeAndModesInfo,
+    KeyGenerationLatencyStats::KeyGenerationLatencyStats,
+    KeyGenerationStep::KeyGenerationStep as MetricsKeyGenerationStep,
+    KeyIdLockHoldStats::KeyIdLockHoldStats,
+    KeyIdLockOperation::KeyIdLockOperation as MetricsKeyIdLockOperation,
+    KeyMintCallType::KeyMintCallType as MetricsKeyMintCallType,
+    KeyMintCircuitBreakerStats::KeyMintCircuitBreakerStats,
+    KeyMintTimeoutStats::KeyMintTimeoutStats,
     KeyOperationWithGeneralInfo::KeyOperationWithGeneralInfo,
     KeyOperationWithPurposeAndModesInfo::KeyOperationWithPurposeAndModesInfo,
     KeyOrigin::KeyOrigin as MetricsKeyOrigin, Keystore2AtomWithOverflow::Keystore2AtomWithOverflow,
     KeystoreAtom::KeystoreAtom, KeystoreAtomPayload::KeystoreAtomPayload,
     Outcome::Outcome as MetricsOutcome, Purpose::Purpose as MetricsPurpose,
     RkpError::RkpError as MetricsRkpError, RkpErrorStats::RkpErrorStats,
-    SecurityLevel::SecurityLevel as MetricsSecurityLevel, Storage::Storage as MetricsStorage,
+    RkpKeyPrunedStats::RkpKeyPrunedStats, SecurityLevel::SecurityLevel as MetricsSecurityLevel,
+    Storage::Storage as MetricsStorage,
 };
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
 use anyhow::{Context, Result};
 use lazy_static::lazy_static;
-use rustutils::system_properties::PropertyWatcherError;
 use std::collections::HashMap;
 use std::sync::Mutex;
 
@@ -95,7 +114,9 @@ impl MetricsStore {
         if AtomID::CRASH_STATS == atom_id {
             return Ok(vec![KeystoreAtom {
                 payload: KeystoreAtomPayload::CrashStats(CrashStats {
-                    count_of_crash_events: read_keystore_crash_count()?,
+                    count_of_crash_events: read_keystore_crash_count()
+                        .ok_or_else(Error::sys)
+                        .context(ks_err!("Crash count not set."))?,
                 }),
                 ..Default::default()
             }]);
@@ -139,6 +160,135 @@ impl MetricsStore {
             }
         }
     }
+
+    /// Renders the atoms in the metrics store in the Prometheus text exposition format, for on
+    /// device debugging. Each atom ID becomes a counter named after the atom, and each atom
+    /// object becomes a series of that counter, labeled with the fields of the atom object. The
+    /// atoms hold the same information as the atoms pulled by statsd, so they contain no secret
+    /// data. The pulled atoms StorageStats and CrashStats are not held in memory, so they are not
+    /// rendered. Counters and series are sorted, so that the output is stable.
+    pub fn render_text_snapshot(&self) -> String {
+        // It is ok to unwrap here since the mutex cannot be poisoned according to the way it is
+        // used in this module. And the lock is not acquired by this thread before.
+        let metrics_store_guard = self.metrics_store.lock().unwrap();
+        let mut atom_ids: Vec<&AtomID> = metrics_store_guard.keys().collect();
+        atom_ids.sort();
+
+        let mut snapshot = String::new();
+        for atom_id in atom_ids {
+            let name = format!("keystore2_{:?}", atom_id).to_lowercase();
+            let mut series: Vec<String> = metrics_store_guard[atom_id]
+                .iter()
+                .map(|(atom, count)| {
+                    let labels: Vec<String> = atom_labels(atom)
+                        .into_iter()
+                        .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(&value)))
+                        .collect();
+                    format!("{}{{{}}} {}\n", name, labels.join(","), count)
+                })
+                .collect();
+            series.sort();
+            snapshot.push_str(&format!("# TYPE {} counter\n", name));
+            snapshot.extend(series);
+        }
+        snapshot
+    }
+}
+
+/// Returns the fields of an atom object as pairs of label and value.
+fn atom_labels(atom: &KeystoreAtomPayload) -> Vec<(&'static str, String)> {
+    match atom {
+        KeystoreAtomPayload::KeyCreationWithGeneralInfo(info) => vec![
+            ("algorithm", format!("{:?}", info.algorithm)),
+            ("key_size", info.key_size.to_string()),
+            ("ec_curve", format!("{:?}", info.ec_curve)),
+            ("key_origin", format!("{:?}", info.key_origin)),
+            ("error_code", info.error_code.to_string()),
+            ("attestation_requested", info.attestation_requested.to_string()),
+        ],
+        KeystoreAtomPayload::KeyCreationWithAuthInfo(info) => vec![
+            ("user_auth_type", format!("{:?}", info.user_auth_type)),
+            ("log10_auth_key_timeout_seconds", info.log10_auth_key_timeout_seconds.to_string()),
+            ("security_level", format!("{:?}", info.security_level)),
+        ],
+        KeystoreAtomPayload::KeyCreationWithPurposeAndModesInfo(info) => vec![
+            ("algorithm", format!("{:?}", info.algorithm)),
+            ("purpose_bitmap", info.purpose_bitmap.to_string()),
+            ("padding_mode_bitmap", info.padding_mode_bitmap.to_string()),
+            ("digest_bitmap", info.digest_bitmap.to_string()),
+            ("block_mode_bitmap", info.block_mode_bitmap.to_string()),
+        ],
+        KeystoreAtomPayload::Keystore2AtomWithOverflow(info) => {
+            vec![("atom_id", format!("{:?}", info.atom_id))]
+        }
+        KeystoreAtomPayload::KeyOperationWithPurposeAndModesInfo(info) => vec![
+            ("purpose", format!("{:?}", info.purpose)),
+            ("padding_mode_bitmap", info.padding_mode_bitmap.to_string()),
+            ("digest_bitmap", info.digest_bitmap.to_string()),
+            ("block_mode_bitmap", info.block_mode_bitmap.to_string()),
+        ],
+        KeystoreAtomPayload::KeyOperationWithGeneralInfo(info) => vec![
+            ("outcome", format!("{:?}", info.outcome)),
+            ("error_code", info.error_code.to_string()),
+            ("key_upgraded", info.key_upgraded.to_string()),
+            ("security_level", format!("{:?}", info.security_level)),
+        ],
+        KeystoreAtomPayload::RkpErrorStats(info) => vec![
+            ("rkp_error", format!("{:?}", info.rkpError)),
+            ("security_level", format!("{:?}", info.security_level)),
+        ],
+        KeystoreAtomPayload::KeyMintCircuitBreakerStats(info) => vec![
+            ("security_level", format!("{:?}", info.security_level)),
+            ("circuit_open", info.circuit_open.to_string()),
+        ],
+        KeystoreAtomPayload::RkpKeyPrunedStats(info) => {
+            vec![("security_level", format!("{:?}", info.security_level))]
+        }
+        KeystoreAtomPayload::DatabaseContentionStats(info) => {
+            vec![("retries_exhausted", info.retries_exhausted.to_string())]
+        }
+        KeystoreAtomPayload::ImportedKeyPurposeStats(info) => vec![
+            ("algorithm", format!("{:?}", info.algorithm)),
+            ("purpose_bitmap", info.purpose_bitmap.to_string()),
+            ("security_level", format!("{:?}", info.security_level)),
+        ],
+        KeystoreAtomPayload::KeyGenerationLatencyStats(info) => vec![
+            ("security_level", format!("{:?}", info.security_level)),
+            ("slowest_step", format!("{:?}", info.slowest_step)),
+        ],
+        KeystoreAtomPayload::AttestationCertExpiryStats(info) => {
+            vec![("expired", info.expired.to_string())]
+        }
+        KeystoreAtomPayload::AttestationFailureStats(info) => vec![
+            ("reason", format!("{:?}", info.reason)),
+            ("security_level", format!("{:?}", info.security_level)),
+        ],
+        KeystoreAtomPayload::KeyIdLockHoldStats(info) => vec![
+            ("operation", format!("{:?}", info.operation)),
+            ("exclusive", info.exclusive.to_string()),
+        ],
+        KeystoreAtomPayload::KeyMintTimeoutStats(info) => vec![
+            ("call_type", format!("{:?}", info.call_type)),
+            ("security_level", format!("{:?}", info.security_level)),
+        ],
+        KeystoreAtomPayload::AttestationSelfTestStats(info) => vec![
+            ("outcome", format!("{:?}", info.outcome)),
+            ("security_level", format!("{:?}", info.security_level)),
+        ],
+        KeystoreAtomPayload::StorageStats(info) => vec![
+            ("storage_type", format!("{:?}", info.storage_type)),
+            ("size", info.size.to_string()),
+            ("unused_size", info.unused_size.to_string()),
+        ],
+        KeystoreAtomPayload::CrashStats(info) => {
+            vec![("count_of_crash_events", info.count_of_crash_events.to_string())]
+        }
+    }
+}
+
+/// Escapes a label value as required by the Prometheus text exposition format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
 }
 
 /// Log key creation events to be sent to statsd.
@@ -208,14 +358,7 @@ fn process_key_creation_event_stats<U>(
     for key_param in key_params.iter().map(KsKeyParamValue::from) {
         match key_param {
             KsKeyParamValue::Algorithm(a) => {
-                let algorithm = match a {
-                    Algorithm::RSA => MetricsAlgorithm::RSA,
-                    Algorithm::EC => MetricsAlgorithm::EC,
-                    Algorithm::AES => MetricsAlgorithm::AES,
-                    Algorithm::TRIPLE_DES => MetricsAlgorithm::TRIPLE_DES,
-                    Algorithm::HMAC => MetricsAlgorithm::HMAC,
-                    _ => MetricsAlgorithm::ALGORITHM_UNSPECIFIED,
-                };
+                let algorithm = process_algorithm(a);
                 key_creation_with_general_info.algorithm = algorithm;
                 key_creation_with_purpose_and_modes_info.algorithm = algorithm;
             }
@@ -371,6 +514,10 @@ fn process_key_operation_event_stats(
         Outcome::Success => MetricsOutcome::SUCCESS,
         Outcome::Abort => MetricsOutcome::ABORT,
         Outcome::Pruned => MetricsOutcome::PRUNED,
+        // Cancellations are counted apart from errors and do not report an error code.
+        Outcome::Cancelled => MetricsOutcome::CANCELLED,
+        // Operations lost to a hardware reset failed without an error code of their own.
+        Outcome::HardwareReset => MetricsOutcome::ERROR,
         Outcome::ErrorCode(e) => {
             key_operation_with_general_info.error_code = e.0;
             MetricsOutcome::ERROR
@@ -421,6 +568,17 @@ fn process_security_level(sec_level: SecurityLevel) -> MetricsSecurityLevel {
     }
 }
 
+fn process_algorithm(algorithm: Algorithm) -> MetricsAlgorithm {
+    match algorithm {
+        Algorithm::RSA => MetricsAlgorithm::RSA,
+        Algorithm::EC => MetricsAlgorithm::EC,
+        Algorithm::AES => MetricsAlgorithm::AES,
+        Algorithm::TRIPLE_DES => MetricsAlgorithm::TRIPLE_DES,
+        Algorithm::HMAC => MetricsAlgorithm::HMAC,
+        _ => MetricsAlgorithm::ALGORITHM_UNSPECIFIED,
+    }
+}
+
 fn compute_padding_mode_bitmap(padding_mode_bitmap: &mut i32, padding_mode: PaddingMode) {
     match padding_mode {
         PaddingMode::NONE => {
@@ -560,32 +718,187 @@ pub fn log_rkp_error_stats(rkp_error: MetricsRkpError, sec_level: &SecurityLevel
     METRICS_STORE.insert_atom(AtomID::RKP_ERROR_STATS, rkp_error_stats);
 }
 
+/// Log the transitions of the circuit breaker guarding the KeyMint backend of the given security
+/// level.
+pub fn log_circuit_breaker_transition(sec_level: &SecurityLevel, circuit_open: bool) {
+    let circuit_breaker_stats =
+        KeystoreAtomPayload::KeyMintCircuitBreakerStats(KeyMintCircuitBreakerStats {
+            security_level: process_security_level(*sec_level),
+            circuit_open,
+        });
+    METRICS_STORE.insert_atom(AtomID::KEYMINT_CIRCUIT_BREAKER_STATS, circuit_breaker_stats);
+}
+
+/// Log the remote provisioned attestation keys that were pruned because they expired. Each entry
+/// of `km_uuids` identifies the KeyMint instance a pruned key belonged to.
+pub fn log_rkp_keys_pruned(km_uuids: &[Uuid]) {
+    for km_uuid in km_uuids {
+        let security_level = match get_keymint_dev_by_uuid(km_uuid) {
+            Ok((_, hw_info)) => process_security_level(hw_info.securityLevel),
+            Err(_) => MetricsSecurityLevel::SECURITY_LEVEL_UNSPECIFIED,
+        };
+        let rkp_key_pruned_stats =
+            KeystoreAtomPayload::RkpKeyPrunedStats(RkpKeyPrunedStats { security_level });
+        METRICS_STORE.insert_atom(AtomID::RKP_KEY_PRUNED_STATS, rkp_key_pruned_stats);
+    }
+}
+
+/// Log a database transaction that had to be retried because the database was busy or locked.
+/// `retries_exhausted` indicates that the transaction failed because the database was still busy
+/// after the maximum number of attempts.
+pub fn log_database_contention(retries_exhausted: bool) {
+    let database_contention_stats =
+        KeystoreAtomPayload::DatabaseContentionStats(DatabaseContentionStats { retries_exhausted });
+    METRICS_STORE.insert_atom(AtomID::DATABASE_CONTENTION_STATS, database_contention_stats);
+}
+
+/// Log the import of a key whose purposes are unusually broad, i.e., a key that combines purposes
+/// of more than one kind, such as signing and decryption.
+pub fn log_imported_key_with_broad_purposes(
+    sec_level: SecurityLevel,
+    algorithm: Algorithm,
+    purposes: &[KeyPurpose],
+) {
+    let mut purpose_bitmap = 0;
+    for purpose in purposes {
+        compute_purpose_bitmap(&mut purpose_bitmap, *purpose);
+    }
+    let imported_key_purpose_stats =
+        KeystoreAtomPayload::ImportedKeyPurposeStats(ImportedKeyPurposeStats {
+            algorithm: process_algorithm(algorithm),
+            purpose_bitmap,
+            security_level: process_security_level(sec_level),
+        });
+    METRICS_STORE.insert_atom(AtomID::IMPORTED_KEY_PURPOSE_STATS, imported_key_purpose_stats);
+}
+
+/// Log a key generation that exceeded the latency budget, along with the step that took the most
+/// time.
+pub fn log_key_generation_latency_exceeded(sec_level: SecurityLevel, slowest_step: GenerationStep) {
+    let key_generation_latency_stats =
+        KeystoreAtomPayload::KeyGenerationLatencyStats(KeyGenerationLatencyStats {
+            security_level: process_security_level(sec_level),
+            slowest_step: match slowest_step {
+                GenerationStep::Keystore => {
+                    MetricsKeyGenerationStep::KEY_GENERATION_STEP_UNSPECIFIED
+                }
+                GenerationStep::Database => MetricsKeyGenerationStep::DATABASE,
+                GenerationStep::Crypto => MetricsKeyGenerationStep::CRYPTO,
+                GenerationStep::KeyMint => MetricsKeyGenerationStep::KEYMINT,
+                GenerationStep::Attestation => MetricsKeyGenerationStep::ATTESTATION,
+            },
+        });
+    METRICS_STORE.insert_atom(AtomID::KEY_GENERATION_LATENCY_STATS, key_generation_latency_stats);
+}
+
+/// Log a key whose stored attestation certificate chain expired, or is about to expire if
+/// `expired` is false, and that was marked for re-attestation.
+pub fn log_attestation_cert_expiry(expired: bool) {
+    let attestation_cert_expiry_stats =
+        KeystoreAtomPayload::AttestationCertExpiryStats(AttestationCertExpiryStats { expired });
+    METRICS_STORE.insert_atom(AtomID::ATTESTATION_CERT_EXPIRY_STATS, attestation_cert_expiry_stats);
+}
+
+/// Classifies the error with which the attestation of a new key failed by its root cause.
+fn attestation_failure_reason(e: &anyhow::Error) -> MetricsAttestationFailureReason {
+    match e.root_cause().downcast_ref::<Error>() {
+        Some(Error::Rc(
+            ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR
+            | ResponseCode::OUT_OF_KEYS_PERMANENT_ERROR
+            | ResponseCode::OUT_OF_KEYS_PENDING_INTERNET_CONNECTIVITY
+            | ResponseCode::OUT_OF_KEYS_REQUIRES_SYSTEM_UPGRADE,
+        ))
+        | Some(Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED)) => {
+            MetricsAttestationFailureReason::MISSING_KEYS
+        }
+        Some(Error::Rc(
+            ResponseCode::PERMISSION_DENIED | ResponseCode::KEY_PERMANENTLY_INVALIDATED,
+        )) => MetricsAttestationFailureReason::PERMISSION_DENIED,
+        Some(Error::Rc(ResponseCode::BACKEND_BUSY))
+        | Some(Error::Km(ErrorCode::SECURE_HW_COMMUNICATION_FAILED | ErrorCode::SECURE_HW_BUSY))
+        | Some(Error::BinderTransaction(_)) => MetricsAttestationFailureReason::BACKEND_TIMEOUT,
+        Some(Error::Km(ErrorCode::VERIFICATION_FAILED)) => {
+            MetricsAttestationFailureReason::VERIFICATION_FAILED
+        }
+        Some(Error::Km(_)) => MetricsAttestationFailureReason::KEYMINT_REJECTED,
+        _ => MetricsAttestationFailureReason::ATTESTATION_FAILURE_REASON_UNSPECIFIED,
+    }
+}
+
+/// Log the failed attestation of a new key on the backend of the given security level, tagged
+/// with the reason derived from `e`.
+pub fn log_attestation_failure(sec_level: SecurityLevel, e: &anyhow::Error) {
+    let attestation_failure_stats =
+        KeystoreAtomPayload::AttestationFailureStats(AttestationFailureStats {
+            reason: attestation_failure_reason(e),
+            security_level: process_security_level(sec_level),
+        });
+    METRICS_STORE.insert_atom(AtomID::ATTESTATION_FAILURE_STATS, attestation_failure_stats);
+}
+
+/// Log a key id lock that was held by `operation` in `mode` for longer than the threshold.
+pub fn log_key_id_lock_held_too_long(operation: KeyIdLockOperation, mode: KeyIdLockMode) {
+    let key_id_lock_hold_stats = KeystoreAtomPayload::KeyIdLockHoldStats(KeyIdLockHoldStats {
+        operation: match operation {
+            KeyIdLockOperation::LoadKeyEntry => MetricsKeyIdLockOperation::LOAD_KEY_ENTRY,
+            KeyIdLockOperation::CreateKeyEntry => MetricsKeyIdLockOperation::CREATE_KEY_ENTRY,
+            KeyIdLockOperation::LoadSuperKey => MetricsKeyIdLockOperation::LOAD_SUPER_KEY,
+            KeyIdLockOperation::CreateSuperKey => MetricsKeyIdLockOperation::CREATE_SUPER_KEY,
+            KeyIdLockOperation::StoreAttestationKey => {
+                MetricsKeyIdLockOperation::STORE_ATTESTATION_KEY
+            }
+            KeyIdLockOperation::LoadAttestationKey => {
+                MetricsKeyIdLockOperation::LOAD_ATTESTATION_KEY
+            }
+            KeyIdLockOperation::MarkKeysUnreferenced => {
+                MetricsKeyIdLockOperation::MARK_KEYS_UNREFERENCED
+            }
+        },
+        exclusive: mode == KeyIdLockMode::Exclusive,
+    });
+    METRICS_STORE.insert_atom(AtomID::KEY_ID_LOCK_HOLD_STATS, key_id_lock_hold_stats);
+}
+
+/// Log a KeyMint call of the given type on the backend of the given security level that took
+/// longer than the timeout of its type.
+pub fn log_keymint_timeout(call_type: KeyMintCallType, sec_level: SecurityLevel) {
+    let keymint_timeout_stats = KeystoreAtomPayload::KeyMintTimeoutStats(KeyMintTimeoutStats {
+        call_type: match call_type {
+            KeyMintCallType::Generate => MetricsKeyMintCallType::GENERATE,
+            KeyMintCallType::AttestedGenerate => MetricsKeyMintCallType::ATTESTED_GENERATE,
+            KeyMintCallType::Import => MetricsKeyMintCallType::IMPORT,
+            KeyMintCallType::Begin => MetricsKeyMintCallType::BEGIN,
+            KeyMintCallType::Operation => MetricsKeyMintCallType::OPERATION,
+            KeyMintCallType::Other => MetricsKeyMintCallType::OTHER,
+        },
+        security_level: process_security_level(sec_level),
+    });
+    METRICS_STORE.insert_atom(AtomID::KEYMINT_TIMEOUT_STATS, keymint_timeout_stats);
+}
+
+/// Log the outcome of the attestation self-test on the backend of the given security level.
+pub fn log_attestation_self_test(outcome: SelfTestOutcome, sec_level: SecurityLevel) {
+    let attestation_self_test_stats =
+        KeystoreAtomPayload::AttestationSelfTestStats(AttestationSelfTestStats {
+            outcome: match outcome {
+                SelfTestOutcome::Passed => MetricsAttestationSelfTestOutcome::PASSED,
+                SelfTestOutcome::Failed => MetricsAttestationSelfTestOutcome::FAILED,
+                SelfTestOutcome::Skipped => MetricsAttestationSelfTestOutcome::SKIPPED,
+            },
+            security_level: process_security_level(sec_level),
+        });
+    METRICS_STORE.insert_atom(AtomID::ATTESTATION_SELF_TEST_STATS, attestation_self_test_stats);
+}
+
 /// This function tries to read and update the system property: keystore.crash_count.
 /// If the property is absent, it sets the property with value 0. If the property is present, it
 /// increments the value. This helps tracking keystore crashes internally.
 pub fn update_keystore_crash_sysprop() {
-    let crash_count = read_keystore_crash_count();
-    let new_count = match crash_count {
-        Ok(count) => count + 1,
-        Err(error) => {
-            // If the property is absent, this is the first start up during the boot.
-            // Proceed to write the system property with value 0. Otherwise, log and return.
-            if !matches!(
-                error.root_cause().downcast_ref::<PropertyWatcherError>(),
-                Some(PropertyWatcherError::SystemPropertyAbsent)
-            ) {
-                log::warn!(
-                    concat!(
-                        "In update_keystore_crash_sysprop: ",
-                        "Failed to read the existing system property due to: {:?}.",
-                        "Therefore, keystore crashes will not be logged."
-                    ),
-                    error
-                );
-                return;
-            }
-            0
-        }
+    // If the property is absent, this is the first start up during the boot. A property that
+    // cannot be read or parsed is logged by the accessor and restarts the count as well.
+    let new_count = match read_keystore_crash_count() {
+        Some(count) => count.saturating_add(1),
+        None => 0,
     };
 
     if let Err(e) =
@@ -601,13 +914,9 @@ pub fn update_keystore_crash_sysprop() {
     }
 }
 
-/// Read the system property: keystore.crash_count.
-pub fn read_keystore_crash_count() -> Result<i32> {
-    rustutils::system_properties::read("keystore.crash_count")
-        .context(ks_err!("Failed read property."))?
-        .context(ks_err!("Property not set."))?
-        .parse::<i32>()
-        .map_err(std::convert::Into::into)
+/// Read the system property: keystore.crash_count. Returns None if it is not set or invalid.
+pub fn read_keystore_crash_count() -> Option<i32> {
+    read_prop_parsed(KEYSTORE_CRASH_COUNT_PROPERTY, None, |v| v.parse::<i32>().ok().map(Some))
 }
 
 /// Enum defining the bit position for each padding mode. Since padding mode can be repeatable, it
@@ -685,3 +994,107 @@ enum KeyPurposeBitPosition {
     ///Bit position in the KeyPurpose bitmap for Attest Key.
     ATTEST_KEY_BIT_POS = 7,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_text_snapshot() {
+        let metrics_store = MetricsStore::default();
+        assert_eq!(metrics_store.render_text_snapshot(), "");
+
+        let circuit_breaker_stats = |circuit_open| {
+            KeystoreAtomPayload::KeyMintCircuitBreakerStats(KeyMintCircuitBreakerStats {
+                security_level: MetricsSecurityLevel::SECURITY_LEVEL_TRUSTED_ENVIRONMENT,
+                circuit_open,
+            })
+        };
+        metrics_store
+            .insert_atom(AtomID::KEYMINT_CIRCUIT_BREAKER_STATS, circuit_breaker_stats(true));
+        metrics_store
+            .insert_atom(AtomID::KEYMINT_CIRCUIT_BREAKER_STATS, circuit_breaker_stats(true));
+        metrics_store
+            .insert_atom(AtomID::KEYMINT_CIRCUIT_BREAKER_STATS, circuit_breaker_stats(false));
+        metrics_store.insert_atom(
+            AtomID::RKP_ERROR_STATS,
+            KeystoreAtomPayload::RkpErrorStats(RkpErrorStats {
+                rkpError: MetricsRkpError::OUT_OF_KEYS,
+                security_level: MetricsSecurityLevel::SECURITY_LEVEL_STRONGBOX,
+            }),
+        );
+
+        assert_eq!(
+            metrics_store.render_text_snapshot(),
+            concat!(
+                "# TYPE keystore2_rkp_error_stats counter\n",
+                "keystore2_rkp_error_stats{rkp_error=\"OUT_OF_KEYS\",",
+                "security_level=\"SECURITY_LEVEL_STRONGBOX\"} 1\n",
+                "# TYPE keystore2_keymint_circuit_breaker_stats counter\n",
+                "keystore2_keymint_circuit_breaker_stats{",
+                "security_level=\"SECURITY_LEVEL_TRUSTED_ENVIRONMENT\",circuit_open=\"false\"} 1\n",
+                "keystore2_keymint_circuit_breaker_stats{",
+                "security_level=\"SECURITY_LEVEL_TRUSTED_ENVIRONMENT\",circuit_open=\"true\"} 2\n",
+            )
+        );
+    }
+
+    fn attestation_failure_count(reason: MetricsAttestationFailureReason) -> i32 {
+        let expected = KeystoreAtomPayload::AttestationFailureStats(AttestationFailureStats {
+            reason,
+            security_level: MetricsSecurityLevel::SECURITY_LEVEL_STRONGBOX,
+        });
+        METRICS_STORE
+            .get_atoms(AtomID::ATTESTATION_FAILURE_STATS)
+            .unwrap()
+            .iter()
+            .find(|atom| atom.payload == expected)
+            .map_or(0, |atom| atom.count)
+    }
+
+    #[test]
+    fn test_attestation_failure_reasons() {
+        let failures = [
+            (
+                Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR),
+                MetricsAttestationFailureReason::MISSING_KEYS,
+            ),
+            (
+                Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED),
+                MetricsAttestationFailureReason::MISSING_KEYS,
+            ),
+            (
+                Error::Km(ErrorCode::INVALID_ARGUMENT),
+                MetricsAttestationFailureReason::KEYMINT_REJECTED,
+            ),
+            (Error::perm(), MetricsAttestationFailureReason::PERMISSION_DENIED),
+            (
+                Error::Rc(ResponseCode::BACKEND_BUSY),
+                MetricsAttestationFailureReason::BACKEND_TIMEOUT,
+            ),
+            (
+                Error::Km(ErrorCode::SECURE_HW_COMMUNICATION_FAILED),
+                MetricsAttestationFailureReason::BACKEND_TIMEOUT,
+            ),
+            (
+                Error::Km(ErrorCode::VERIFICATION_FAILED),
+                MetricsAttestationFailureReason::VERIFICATION_FAILED,
+            ),
+            (Error::sys(), MetricsAttestationFailureReason::ATTESTATION_FAILURE_REASON_UNSPECIFIED),
+        ];
+        for (error, reason) in failures {
+            let e = anyhow::Error::new(error).context("Trying to get an attestation key");
+            assert_eq!(attestation_failure_reason(&e), reason, "{:?}", e);
+
+            let before = attestation_failure_count(reason);
+            log_attestation_failure(SecurityLevel::STRONGBOX, &e);
+            assert_eq!(attestation_failure_count(reason), before + 1, "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("plain"), "plain");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -124,23 +124,83 @@
 //! the operation is either being touched, which changes its pruning resistance,
 //! or it transitions to its end-of-life, which means we may get a free slot.
 //! Either way, we have to revaluate the pruning scores.
+//!
+//! ## Aborting Operations by Id
+//! Every operation has an id that is unique within keystore. Operations can be looked up by id
+//! across the operation databases of all security levels and aborted, e.g., to clean up after
+//! tests. This frees the KeyMint operation slot immediately and sets the outcome to
+//! `Outcome::Abort`. Only the owner of an operation or a privileged caller may abort it.
+//!
+//! ## Operation Limits
+//! To keep a single UID from monopolizing the operation slots of a KeyMint backend, the
+//! operation database can cap the number of concurrent operations per UID. A UID at its limit
+//! gets `ErrorCode::TOO_MANY_OPERATIONS` for a new operation until it finishes or aborts one of
+//! its operations. The limit does not prune any operations; pruning remains reserved for
+//! reclaiming the slots of the KeyMint backend, see "Operation Pruning". Forced operations are
+//! not counted for the limit. The limit is configured with the system property
+//! `keystore.max_operations_per_uid`, and can be overridden for system UIDs with
+//! `keystore.max_operations_per_system_uid`. A limit of 0 disables the cap, which is the
+//! default.
+//!
+//! ## Maximum Operation Lifetime
+//! Pruning only reclaims operations that are not in use, so a client that keeps trickling data
+//! into an operation could hold its slot forever. Therefore, operations have an absolute
+//! lifetime regardless of activity. The first call on an operation after its lifetime has
+//! elapsed aborts it, sets the outcome to `Outcome::Pruned`, and fails with
+//! `ErrorCode::INVALID_OPERATION_HANDLE`, like a call on a pruned operation. The lifetime is
+//! configured with the system property `keystore.operation_max_lifetime`. A lifetime of 0
+//! disables the cap.
+//!
+//! Some algorithms are much slower than others, e.g., RSA with large keys compared to EC, so the
+//! lifetime can be overridden per key algorithm with the system properties
+//! `keystore.operation_max_lifetime.<algorithm>`, where `<algorithm>` is one of `rsa`, `ec`,
+//! `aes`, `3des`, or `hmac`. Algorithms without an override use the default lifetime, and an
+//! override of 0 disables the cap for that algorithm.
+//!
+//! ## Operation Checkpoints
+//! Operations do not survive a restart of keystore. If checkpointing is enabled, resumable
+//! operations, i.e., signing and verification operations, persist their parameters and input,
+//! so that their owner can resume them after a restart, see `operation_checkpoint`. The
+//! checkpoint is stored under the id of the operation. Operation ids are random, so that they
+//! stay unique across restarts. The checkpoint is deleted when the operation is dropped.
+//!
+//! ## Hardware Resets
+//! If the secure hardware of a backend resets, e.g., because StrongBox rebooted, KeyMint loses
+//! all of its operations. Keystore detects this when KeyMint no longer knows the handle of an
+//! operation that keystore considers active, or when the binder of KeyMint died. The operation
+//! fails with `Error::HardwareReset`, which clients see as `ResponseCode::BACKEND_BUSY`, so that
+//! they retry with a new operation. All other operations of the same backend that were created
+//! before the reset are lost with it. They are no longer counted as active, and the next call
+//! on each of them aborts it and fails with the same error. Their outcome is
+//! `Outcome::HardwareReset`. The cached info of the backend is invalidated, because the backend
+//! may have come back with another version.
 
+use crate::clock_rollback::{Clock, SystemClock};
 use crate::enforcements::AuthInfo;
 use crate::error::{map_err_with, map_km_error, map_or_log_err, Error, ErrorCode, ResponseCode};
+use crate::globals::KEYMINT_BACKEND_INFO;
+use crate::key_operation_log::KEY_OPERATION_LOGS;
+use crate::keymint_timeout::{KeyMintCallType, KeyMintCallWatch};
 use crate::ks_err;
+use crate::log_throttle::log_throttled;
 use crate::metrics_store::log_key_operation_event_stats;
-use crate::utils::watchdog as wd;
+use crate::operation_checkpoint;
+use crate::sysprop::{read_prop_duration, read_prop_u32};
+use crate::utils::{watchdog as wd, AID_APP_START, AID_USER_OFFSET};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    IKeyMintOperation::IKeyMintOperation, KeyParameter::KeyParameter, KeyPurpose::KeyPurpose,
-    SecurityLevel::SecurityLevel,
+    Algorithm::Algorithm, IKeyMintOperation::IKeyMintOperation, KeyParameter::KeyParameter,
+    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
 };
-use android_hardware_security_keymint::binder::{BinderFeatures, Strong};
+use android_hardware_security_keymint::binder::{BinderFeatures, StatusCode, Strong};
 use android_system_keystore2::aidl::android::system::keystore2::{
     IKeystoreOperation::BnKeystoreOperation, IKeystoreOperation::IKeystoreOperation,
 };
 use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use log::Level;
 use std::{
     collections::HashMap,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     sync::{Arc, Mutex, MutexGuard, Weak},
     time::Duration,
     time::Instant,
@@ -161,6 +221,11 @@ pub enum Outcome {
     Dropped,
     /// Operation is pruned.
     Pruned,
+    /// Operation was cancelled, i.e., KeyMint reported `ErrorCode::OPERATION_CANCELLED`.
+    /// Cancellations are not failures and are counted apart from them in the statistic.
+    Cancelled,
+    /// Operation was lost, because the secure hardware of its backend reset.
+    HardwareReset,
     /// Operation is failed with the error code.
     ErrorCode(ErrorCode),
 }
@@ -171,13 +236,43 @@ pub enum Outcome {
 pub struct Operation {
     // The index of this operation in the OperationDb.
     index: usize,
+    // The id of this operation, which is unique within keystore.
+    id: i64,
     km_op: Strong<dyn IKeyMintOperation>,
     last_usage: Mutex<Instant>,
     outcome: Mutex<Outcome>,
-    owner: u32, // Uid of the operation's owner.
+    owner: u32,          // Uid of the operation's owner.
+    key_id: Option<i64>, // Id of the key, None for keys given as Domain::BLOB.
     auth_info: Mutex<AuthInfo>,
     forced: bool,
     logging_info: LoggingInfo,
+    deadline: Option<Deadline>,
+    // Whether the operation has a checkpoint, see `operation_checkpoint`.
+    checkpointed: AtomicBool,
+    // The number of hardware resets of the backend, shared with the OperationDb, and its value
+    // when the operation was created. The operation is lost if the two differ.
+    hardware_resets: Arc<AtomicU64>,
+    resets_at_creation: u64,
+}
+
+/// The time at which an operation is aborted regardless of activity, see
+/// "Maximum Operation Lifetime".
+pub struct Deadline {
+    clock: Arc<dyn Clock>,
+    /// The monotonic time of the deadline in milliseconds.
+    at_millis: i64,
+}
+
+impl Deadline {
+    fn has_passed(&self) -> bool {
+        self.clock.monotonic_millis() >= self.at_millis
+    }
+}
+
+impl std::fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deadline").field("at_millis", &self.at_millis).finish()
+    }
 }
 
 /// Keeps track of the information required for logging operations.
@@ -212,28 +307,55 @@ struct PruningInfo {
 const MAX_RECEIVE_DATA: usize = 0x8000;
 
 impl Operation {
-    /// Constructor
+    /// Constructor. The operation gets a random id, so that it does not collide with the ids of
+    /// the checkpoints of operations from before a restart of keystore.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         index: usize,
         km_op: binder::Strong<dyn IKeyMintOperation>,
         owner: u32,
+        key_id: Option<i64>,
         auth_info: AuthInfo,
         forced: bool,
         logging_info: LoggingInfo,
+        deadline: Option<Deadline>,
+        hardware_resets: Arc<AtomicU64>,
     ) -> Self {
+        let resets_at_creation = hardware_resets.load(Ordering::SeqCst);
         Self {
             index,
+            id: rand::random(),
             km_op,
             last_usage: Mutex::new(Instant::now()),
             outcome: Mutex::new(Outcome::Unknown),
             owner,
+            key_id,
             auth_info: Mutex::new(auth_info),
             forced,
             logging_info,
+            deadline,
+            checkpointed: AtomicBool::new(false),
+            hardware_resets,
+            resets_at_creation,
         }
     }
 
+    /// Marks the operation as checkpointed after its checkpoint was stored under its id, so
+    /// that its input is recorded and the checkpoint is deleted with the operation.
+    pub fn enable_checkpoint(&self) {
+        self.checkpointed.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns the id of the operation, which is unique within keystore.
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
     fn get_pruning_info(&self) -> Option<PruningInfo> {
+        // An operation that was lost to a hardware reset is as good as finalized.
+        if self.is_lost() {
+            return None;
+        }
         // An operation may be finalized.
         if let Ok(guard) = self.outcome.try_lock() {
             match *guard {
@@ -283,11 +405,11 @@ impl Operation {
         }
         *locked_outcome = Outcome::Pruned;
 
-        let _wp = wd::watch_millis("In Operation::prune: calling abort()", 500);
+        let _wp = self.watch_keymint("In Operation::prune: calling abort()");
 
         // We abort the operation. If there was an error we log it but ignore it.
         if let Err(e) = map_km_error(self.km_op.abort()) {
-            log::error!("In prune: KeyMint::abort failed with {:?}.", e);
+            log_throttled(Level::Error, &format!("In prune: KeyMint::abort failed with {:?}.", e));
         }
 
         Ok(())
@@ -305,6 +427,12 @@ impl Operation {
         err: Result<T, Error>,
     ) -> Result<T, Error> {
         match &err {
+            Err(e) if indicates_hardware_reset(e) => {
+                *locked_outcome = Outcome::HardwareReset;
+                self.report_hardware_reset();
+                return Err(Error::HardwareReset);
+            }
+            Err(Error::Km(ErrorCode::OPERATION_CANCELLED)) => *locked_outcome = Outcome::Cancelled,
             Err(Error::Km(e)) => *locked_outcome = Outcome::ErrorCode(*e),
             Err(_) => *locked_outcome = Outcome::ErrorCode(ErrorCode::UNKNOWN_ERROR),
             Ok(_) => (),
@@ -318,14 +446,43 @@ impl Operation {
     // ErrorCode::INVALID_OPERATION_HANDLE indicating that this operation has
     // been finalized and is no longer active.
     fn check_active(&self) -> Result<MutexGuard<Outcome>> {
-        let guard = self.outcome.lock().expect("In check_active.");
+        let mut guard = self.outcome.lock().expect("In check_active.");
         match *guard {
+            Outcome::Unknown if self.is_lost() => {
+                *guard = Outcome::HardwareReset;
+                // KeyMint most likely lost the operation already. Abort it nonetheless in case
+                // it did not, so that its slot is freed.
+                let _wp = self.watch_keymint("In Operation::check_active: calling abort()");
+                if let Err(e) = map_km_error(self.km_op.abort()) {
+                    log::info!("Aborting operation lost to a hardware reset: {:?}.", e);
+                }
+                Err(Error::HardwareReset)
+                    .context(ks_err!("The operation was lost to a hardware reset."))
+            }
             Outcome::Unknown => Ok(guard),
             _ => Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE))
                 .context(ks_err!("Call on finalized operation with outcome: {:?}.", *guard)),
         }
     }
 
+    // This function aborts the operation if its deadline has passed. The precondition to this
+    // call must be *locked_outcome == Outcome::Unknown, see `update_outcome`.
+    fn check_deadline(&self, locked_outcome: &mut Outcome) -> Result<()> {
+        if !self.deadline.as_ref().map_or(false, |d| d.has_passed()) {
+            return Ok(());
+        }
+        *locked_outcome = Outcome::Pruned;
+        let _wp = self.watch_keymint("In Operation::check_deadline: calling abort()");
+        if let Err(e) = map_km_error(self.km_op.abort()) {
+            log_throttled(
+                Level::Error,
+                &format!("In check_deadline: KeyMint::abort failed with {:?}.", e),
+            );
+        }
+        Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE))
+            .context(ks_err!("Operation exceeded its maximum lifetime."))
+    }
+
     // This function checks the amount of input data sent to us. We reject any buffer
     // exceeding MAX_RECEIVE_DATA bytes as input to `update`, `update_aad`, and `finish`
     // in order to force clients into using reasonable limits.
@@ -345,10 +502,39 @@ impl Operation {
         *self.last_usage.lock().expect("In touch.") = Instant::now();
     }
 
+    // Watches a call of the KeyMint operation with the timeout of operation calls.
+    fn watch_keymint(&self, id: &'static str) -> KeyMintCallWatch {
+        KeyMintCallWatch::new(KeyMintCallType::Operation, self.logging_info.sec_level, id)
+    }
+
+    // Returns true if the backend reset after the operation was created, see "Hardware Resets".
+    fn is_lost(&self) -> bool {
+        self.hardware_resets.load(Ordering::SeqCst) != self.resets_at_creation
+    }
+
+    // Records that the backend lost this operation to a hardware reset. Only the first
+    // operation that notices a reset counts it, so that the other lost operations do not
+    // count the same reset again.
+    fn report_hardware_reset(&self) {
+        if self
+            .hardware_resets
+            .compare_exchange(
+                self.resets_at_creation,
+                self.resets_at_creation + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+        {
+            invalidate_after_hardware_reset(self.logging_info.sec_level);
+        }
+    }
+
     /// Implementation of `IKeystoreOperation::updateAad`.
     /// Refer to the AIDL spec at system/hardware/interfaces/keystore2 for details.
     fn update_aad(&self, aad_input: &[u8]) -> Result<()> {
         let mut outcome = self.check_active().context("In update_aad")?;
+        self.check_deadline(&mut outcome).context("In update_aad")?;
         Self::check_input_length(aad_input).context("In update_aad")?;
         self.touch();
 
@@ -360,7 +546,7 @@ impl Operation {
             .context(ks_err!("Trying to get auth tokens."))?;
 
         self.update_outcome(&mut outcome, {
-            let _wp = wd::watch_millis("Operation::update_aad: calling updateAad", 500);
+            let _wp = self.watch_keymint("Operation::update_aad: calling updateAad");
             map_km_error(self.km_op.updateAad(aad_input, hat.as_ref(), tst.as_ref()))
         })
         .context(ks_err!("Update failed."))?;
@@ -372,6 +558,7 @@ impl Operation {
     /// Refer to the AIDL spec at system/hardware/interfaces/keystore2 for details.
     fn update(&self, input: &[u8]) -> Result<Option<Vec<u8>>> {
         let mut outcome = self.check_active().context("In update")?;
+        self.check_deadline(&mut outcome).context("In update")?;
         Self::check_input_length(input).context("In update")?;
         self.touch();
 
@@ -384,11 +571,17 @@ impl Operation {
 
         let output = self
             .update_outcome(&mut outcome, {
-                let _wp = wd::watch_millis("Operation::update: calling update", 500);
+                let _wp = self.watch_keymint("Operation::update: calling update");
                 map_km_error(self.km_op.update(input, hat.as_ref(), tst.as_ref()))
             })
             .context(ks_err!("Update failed."))?;
 
+        if self.checkpointed.load(Ordering::Relaxed)
+            && !operation_checkpoint::record_input(self.id, self.owner, input)
+        {
+            self.checkpointed.store(false, Ordering::Relaxed);
+        }
+
         if output.is_empty() {
             Ok(None)
         } else {
@@ -400,6 +593,7 @@ impl Operation {
     /// Refer to the AIDL spec at system/hardware/interfaces/keystore2 for details.
     fn finish(&self, input: Option<&[u8]>, signature: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
         let mut outcome = self.check_active().context("In finish")?;
+        self.check_deadline(&mut outcome).context("In finish")?;
         if let Some(input) = input {
             Self::check_input_length(input).context("In finish")?;
         }
@@ -414,7 +608,7 @@ impl Operation {
 
         let output = self
             .update_outcome(&mut outcome, {
-                let _wp = wd::watch_millis("Operation::finish: calling finish", 500);
+                let _wp = self.watch_keymint("Operation::finish: calling finish");
                 map_km_error(self.km_op.finish(
                     input,
                     signature,
@@ -445,7 +639,7 @@ impl Operation {
         *locked_outcome = outcome;
 
         {
-            let _wp = wd::watch_millis("Operation::abort: calling abort", 500);
+            let _wp = self.watch_keymint("Operation::abort: calling abort");
             map_km_error(self.km_op.abort()).context(ks_err!("KeyMint::abort failed."))
         }
     }
@@ -469,37 +663,340 @@ impl Drop for Operation {
                 log::error!("While dropping Operation: abort failed:\n    {:?}", e);
             }
         }
+        if let Some(key_id) = self.key_id {
+            let outcome = *self.outcome.lock().expect("In drop.");
+            KEY_OPERATION_LOGS.record(&SystemClock, key_id, self.logging_info.purpose, outcome);
+        }
+        if self.checkpointed.load(Ordering::Relaxed) {
+            operation_checkpoint::discard(self.id);
+        }
+    }
+}
+
+const MAX_OPERATIONS_PER_UID_PROPERTY: &str = "keystore.max_operations_per_uid";
+/// App UIDs are not limited by default.
+const DEFAULT_MAX_OPERATIONS_PER_UID: u32 = 0;
+const MAX_OPERATIONS_PER_SYSTEM_UID_PROPERTY: &str = "keystore.max_operations_per_system_uid";
+/// System UIDs are not limited by default.
+const DEFAULT_MAX_OPERATIONS_PER_SYSTEM_UID: u32 = 0;
+
+/// The limits on the number of concurrent operations per UID. A limit of 0 means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OperationLimits {
+    per_uid: u32,
+    /// Overrides `per_uid` for UIDs below AID_APP_START.
+    per_system_uid: u32,
+}
+
+impl OperationLimits {
+    fn from_properties() -> Self {
+        Self {
+            per_uid: read_prop_u32(MAX_OPERATIONS_PER_UID_PROPERTY, DEFAULT_MAX_OPERATIONS_PER_UID),
+            per_system_uid: read_prop_u32(
+                MAX_OPERATIONS_PER_SYSTEM_UID_PROPERTY,
+                DEFAULT_MAX_OPERATIONS_PER_SYSTEM_UID,
+            ),
+        }
+    }
+
+    fn limit_for(&self, uid: u32) -> u32 {
+        if uid % AID_USER_OFFSET < AID_APP_START {
+            self.per_system_uid
+        } else {
+            self.per_uid
+        }
+    }
+
+    /// Checks if `uid`, which currently owns `active` operations, may start another one.
+    fn check(&self, uid: u32, active: usize) -> Result<(), Error> {
+        match self.limit_for(uid) {
+            0 => Ok(()),
+            limit if active < limit as usize => Ok(()),
+            limit => {
+                log_throttled(
+                    Level::Warn,
+                    &format!("UID {} reached its limit of {} concurrent operations.", uid, limit),
+                );
+                Err(Error::Km(ErrorCode::TOO_MANY_OPERATIONS))
+            }
+        }
     }
 }
 
+const OPERATION_MAX_LIFETIME_PROPERTY: &str = "keystore.operation_max_lifetime";
+/// Even long running operations, e.g., over large files, finish well within an hour.
+const DEFAULT_OPERATION_MAX_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// The algorithms whose lifetime can be overridden, with the suffix of their property.
+const ALGORITHM_PROPERTY_SUFFIXES: [(Algorithm, &str); 5] = [
+    (Algorithm::RSA, "rsa"),
+    (Algorithm::EC, "ec"),
+    (Algorithm::AES, "aes"),
+    (Algorithm::TRIPLE_DES, "3des"),
+    (Algorithm::HMAC, "hmac"),
+];
+
+/// The absolute lifetime of operations. None means unlimited.
+struct LifetimeCap {
+    clock: Arc<dyn Clock>,
+    max_lifetime: Option<Duration>,
+    /// Overrides of `max_lifetime` for operations with keys of the given algorithms.
+    per_algorithm: Vec<(Algorithm, Option<Duration>)>,
+}
+
+impl LifetimeCap {
+    fn from_property() -> Self {
+        let max_lifetime =
+            read_prop_duration(OPERATION_MAX_LIFETIME_PROPERTY, DEFAULT_OPERATION_MAX_LIFETIME);
+        let per_algorithm = ALGORITHM_PROPERTY_SUFFIXES
+            .iter()
+            .map(|(algorithm, suffix)| {
+                let property = format!("{}.{}", OPERATION_MAX_LIFETIME_PROPERTY, suffix);
+                (*algorithm, Self::cap(read_prop_duration(&property, max_lifetime)))
+            })
+            .collect();
+        Self { clock: Arc::new(SystemClock), max_lifetime: Self::cap(max_lifetime), per_algorithm }
+    }
+
+    fn cap(lifetime: Duration) -> Option<Duration> {
+        if lifetime.is_zero() {
+            None
+        } else {
+            Some(lifetime)
+        }
+    }
+
+    /// Returns the lifetime of operations with keys of `algorithm`.
+    fn max_lifetime_for(&self, algorithm: Option<Algorithm>) -> Option<Duration> {
+        algorithm
+            .and_then(|algorithm| {
+                self.per_algorithm.iter().find(|(a, _)| *a == algorithm).map(|(_, l)| *l)
+            })
+            .unwrap_or(self.max_lifetime)
+    }
+
+    /// Returns the deadline of an operation with a key of `algorithm` created now.
+    fn deadline(&self, algorithm: Option<Algorithm>) -> Option<Deadline> {
+        self.max_lifetime_for(algorithm).map(|max_lifetime| Deadline {
+            clock: self.clock.clone(),
+            at_millis: self
+                .clock
+                .monotonic_millis()
+                .saturating_add(max_lifetime.as_millis().try_into().unwrap_or(i64::MAX)),
+        })
+    }
+}
+
+impl std::fmt::Debug for LifetimeCap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LifetimeCap")
+            .field("max_lifetime", &self.max_lifetime)
+            .field("per_algorithm", &self.per_algorithm)
+            .finish()
+    }
+}
+
+/// Returns true if `e`, the error of a KeyMint call on an operation that keystore considers
+/// active, indicates that the secure hardware of the backend reset, see "Hardware Resets".
+fn indicates_hardware_reset(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::Km(ErrorCode::INVALID_OPERATION_HANDLE)
+            | Error::BinderTransaction(StatusCode::DEAD_OBJECT)
+    )
+}
+
+/// Drops the cached state of the backend of `sec_level` after it reset.
+fn invalidate_after_hardware_reset(sec_level: SecurityLevel) {
+    log::warn!("The KeyMint backend of {:?} reset and lost its operations.", sec_level);
+    KEYMINT_BACKEND_INFO.invalidate(sec_level);
+}
+
+lazy_static! {
+    /// The registered operation databases, i.e., those of all security levels.
+    static ref OPERATION_DBS: Mutex<Vec<Weak<OperationDb>>> = Default::default();
+}
+
+/// Aborts the active operation `id` of any registered operation database. The operation must
+/// be owned by `caller_uid` unless the caller is `privileged`. Returns
+/// `ErrorCode::INVALID_OPERATION_HANDLE` if there is no such operation.
+pub fn abort_operation_by_id(id: i64, caller_uid: u32, privileged: bool) -> Result<()> {
+    let dbs: Vec<Arc<OperationDb>> =
+        OPERATION_DBS.lock().unwrap().iter().filter_map(|db| db.upgrade()).collect();
+    for db in dbs {
+        if db.abort_operation(id, caller_uid, privileged).context(ks_err!())? {
+            return Ok(());
+        }
+    }
+    Err(Error::Km(ErrorCode::INVALID_OPERATION_HANDLE))
+        .context(ks_err!("No active operation with id {}.", id))
+}
+
+/// Returns the ids of the active operations of all registered operation databases. If `owner`
+/// is given, only the operations of that UID are returned.
+pub fn list_operation_ids(owner: Option<u32>) -> Vec<i64> {
+    let dbs: Vec<Arc<OperationDb>> =
+        OPERATION_DBS.lock().unwrap().iter().filter_map(|db| db.upgrade()).collect();
+    dbs.iter().flat_map(|db| db.active_operation_ids(owner)).collect()
+}
+
+/// Returns the active operations on the key `key_id` of all registered operation databases.
+pub fn list_operations_for_key(key_id: i64) -> Vec<OperationSummary> {
+    let dbs: Vec<Arc<OperationDb>> =
+        OPERATION_DBS.lock().unwrap().iter().filter_map(|db| db.upgrade()).collect();
+    dbs.iter().flat_map(|db| db.active_operations_for_key(key_id)).collect()
+}
+
 /// The OperationDb holds weak references to all ongoing operations.
 /// Its main purpose is to facilitate operation pruning.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct OperationDb {
     // TODO replace Vec with WeakTable when the weak_table crate becomes
     // available.
     operations: Mutex<Vec<Weak<Operation>>>,
+    limits: OperationLimits,
+    lifetime_cap: LifetimeCap,
+    // The number of hardware resets of the backend, see "Hardware Resets".
+    hardware_resets: Arc<AtomicU64>,
 }
 
 impl OperationDb {
-    /// Creates a new OperationDb.
+    /// Creates a new OperationDb. The per UID operation limits and the maximum operation
+    /// lifetime are read from the system properties.
     pub fn new() -> Self {
-        Self { operations: Mutex::new(Vec::new()) }
+        Self {
+            operations: Mutex::new(Vec::new()),
+            limits: OperationLimits::from_properties(),
+            lifetime_cap: LifetimeCap::from_property(),
+            hardware_resets: Default::default(),
+        }
+    }
+
+    /// Creates a new OperationDb like `new` and registers it, so that its operations can be
+    /// found by `abort_operation_by_id` and `list_operation_ids`.
+    pub fn new_registered() -> Arc<Self> {
+        let db = Arc::new(Self::new());
+        let mut dbs = OPERATION_DBS.lock().unwrap();
+        dbs.retain(|db| db.strong_count() != 0);
+        dbs.push(Arc::downgrade(&db));
+        db
+    }
+
+    /// Returns the ids of the operations that have not been finalized. If `owner` is given,
+    /// only the operations of that UID are returned.
+    pub fn active_operation_ids(&self, owner: Option<u32>) -> Vec<i64> {
+        let operations = self.operations.lock().expect("In active_operation_ids.");
+        operations
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .filter(|op| owner.map_or(true, |owner| op.owner == owner))
+            .filter(|op| op.get_pruning_info().is_some())
+            .map(|op| op.id)
+            .collect()
+    }
+
+    /// Returns the operations on the key `key_id` that have not been finalized.
+    pub fn active_operations_for_key(&self, key_id: i64) -> Vec<OperationSummary> {
+        let operations = self.operations.lock().expect("In active_operations_for_key.");
+        operations
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .filter(|op| op.key_id == Some(key_id))
+            .filter(|op| op.get_pruning_info().is_some())
+            .map(|op| op.summary())
+            .collect()
+    }
+
+    /// Aborts the operation `id`, freeing its KeyMint operation slot. The operation must be
+    /// owned by `caller_uid` unless the caller is `privileged`. Returns false if this database
+    /// has no operation with that id, and `ErrorCode::INVALID_OPERATION_HANDLE` if the
+    /// operation was finalized already.
+    pub fn abort_operation(&self, id: i64, caller_uid: u32, privileged: bool) -> Result<bool> {
+        let op = self
+            .operations
+            .lock()
+            .expect("In abort_operation.")
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .find(|op| op.id == id);
+        let op = match op {
+            Some(op) => op,
+            None => return Ok(false),
+        };
+        if op.owner != caller_uid && !privileged {
+            return Err(Error::perm()).context(ks_err!(
+                "Caller {} may not abort operation {} of {}.",
+                caller_uid,
+                id,
+                op.owner
+            ));
+        }
+        op.abort(Outcome::Abort).context(ks_err!())?;
+        Ok(true)
+    }
+
+    /// Returns the number of operations of `owner` that count for its limit of concurrent
+    /// operations, i.e., the operations that are neither finalized nor forced.
+    fn count_limited_operations(operations: &[Weak<Operation>], owner: u32) -> usize {
+        operations
+            .iter()
+            .filter_map(|op| op.upgrade())
+            .filter(|op| op.owner == owner)
+            .filter_map(|op| op.get_pruning_info())
+            .filter(|info| !info.forced)
+            .count()
+    }
+
+    /// Checks that `owner` is within its limit of concurrent operations. Forced operations are
+    /// exempt from the limit. Returns `ErrorCode::TOO_MANY_OPERATIONS` if `owner` is at its
+    /// limit. This is intended to be called before beginning an operation with KeyMint, so that
+    /// no backend slot is wasted.
+    pub fn enforce_operation_limit(&self, owner: u32, forced: bool) -> Result<(), Error> {
+        if forced {
+            return Ok(());
+        }
+        let operations = self.operations.lock().expect("In enforce_operation_limit.");
+        self.limits.check(owner, Self::count_limited_operations(&operations, owner))
+    }
+
+    /// Records that the backend of `sec_level`, whose operations this database holds, reset,
+    /// e.g., because its binder died while beginning a new operation. All operations that were
+    /// created before are lost, see "Hardware Resets".
+    pub fn report_hardware_reset(&self, sec_level: SecurityLevel) {
+        self.hardware_resets.fetch_add(1, Ordering::SeqCst);
+        invalidate_after_hardware_reset(sec_level);
     }
 
     /// Creates a new operation.
     /// This function takes a KeyMint operation and an associated
     /// owner uid and returns a new Operation wrapped in a `std::sync::Arc`.
+    /// If the owner has reached its limit of concurrent operations in the meantime, the KeyMint
+    /// operation is aborted and `ErrorCode::TOO_MANY_OPERATIONS` is returned.
+    /// The lifetime of the operation is chosen by `algorithm`, the algorithm of its key.
+    #[allow(clippy::too_many_arguments)]
     pub fn create_operation(
         &self,
         km_op: binder::Strong<dyn IKeyMintOperation>,
         owner: u32,
+        key_id: Option<i64>,
+        algorithm: Option<Algorithm>,
         auth_info: AuthInfo,
         forced: bool,
         logging_info: LoggingInfo,
-    ) -> Arc<Operation> {
+    ) -> Result<Arc<Operation>, Error> {
         // We use unwrap because we don't allow code that can panic while locked.
         let mut operations = self.operations.lock().expect("In create_operation.");
+        if !forced {
+            if let Err(e) =
+                self.limits.check(owner, Self::count_limited_operations(&operations, owner))
+            {
+                drop(operations);
+                if let Err(abort_error) = map_km_error(km_op.abort()) {
+                    log::error!("Failed to abort operation over the limit: {:?}", abort_error);
+                }
+                return Err(e);
+            }
+        }
 
         let mut index: usize = 0;
         // First we iterate through the operation slots to try and find an unused
@@ -513,24 +1010,30 @@ impl OperationDb {
                     index - 1,
                     km_op,
                     owner,
+                    key_id,
                     auth_info,
                     forced,
                     logging_info,
+                    self.lifetime_cap.deadline(algorithm),
+                    self.hardware_resets.clone(),
                 ));
                 *free_slot = Arc::downgrade(&new_op);
-                new_op
+                Ok(new_op)
             }
             None => {
                 let new_op = Arc::new(Operation::new(
                     operations.len(),
                     km_op,
                     owner,
+                    key_id,
                     auth_info,
                     forced,
                     logging_info,
+                    self.lifetime_cap.deadline(algorithm),
+                    self.hardware_resets.clone(),
                 ));
                 operations.push(Arc::downgrade(&new_op));
-                new_op
+                Ok(new_op)
             }
         }
     }
@@ -875,3 +1378,494 @@ impl IKeystoreOperation for KeystoreOperation {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+    use crate::enforcements::Enforcements;
+    use crate::error::get_error_code;
+    use crate::metrics_store::METRICS_STORE;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        HardwareAuthToken::HardwareAuthToken, IKeyMintOperation::BnKeyMintOperation,
+    };
+    use android_hardware_security_secureclock::aidl::android::hardware::security::secureclock::TimeStampToken::TimeStampToken;
+    use android_security_metrics::aidl::android::security::metrics::{
+        AtomID::AtomID, KeystoreAtomPayload::KeystoreAtomPayload,
+        Outcome::Outcome as MetricsOutcome,
+    };
+
+    const APP_UID: u32 = 10001;
+    const OTHER_APP_UID: u32 = 10002;
+    const SYSTEM_UID: u32 = 1000;
+
+    fn is_over_limit(result: Result<(), Error>) -> bool {
+        result == Err(Error::Km(ErrorCode::TOO_MANY_OPERATIONS))
+    }
+
+    #[test]
+    fn test_operation_limit_per_uid() {
+        let limits = OperationLimits { per_uid: 3, per_system_uid: 0 };
+        // Fill the quota of one UID.
+        for active in 0..3 {
+            assert_eq!(limits.check(APP_UID, active), Ok(()));
+        }
+        assert!(is_over_limit(limits.check(APP_UID, 3)));
+        assert!(is_over_limit(limits.check(APP_UID, 4)));
+        // Other UIDs can still operate, also in other users.
+        assert_eq!(limits.check(OTHER_APP_UID, 0), Ok(()));
+        assert_eq!(limits.check(AID_USER_OFFSET + APP_UID, 2), Ok(()));
+        // System UIDs are not limited.
+        assert_eq!(limits.check(SYSTEM_UID, 100), Ok(()));
+    }
+
+    #[test]
+    fn test_operation_limit_overrides() {
+        let limits = OperationLimits { per_uid: 0, per_system_uid: 2 };
+        assert_eq!(limits.check(APP_UID, 100), Ok(()));
+        assert_eq!(limits.check(SYSTEM_UID, 1), Ok(()));
+        assert!(is_over_limit(limits.check(SYSTEM_UID, 2)));
+        assert!(is_over_limit(limits.check(AID_USER_OFFSET + SYSTEM_UID, 2)));
+    }
+
+    #[test]
+    fn test_operation_limit_rejects_new_operations() {
+        let clock = Arc::new(FakeClock::default());
+        let db = OperationDb {
+            limits: OperationLimits { per_uid: 2, per_system_uid: 0 },
+            ..capped_operation_db(&clock, None)
+        };
+        let first = create_abortable_operation(&db, APP_UID);
+        let second = create_abortable_operation(&db, APP_UID);
+        let other = create_abortable_operation(&db, OTHER_APP_UID);
+
+        // Forced operations are exempt from the limit.
+        assert_eq!(db.enforce_operation_limit(APP_UID, true), Ok(()));
+
+        // At its limit, the UID gets no new operations, and none of its operations are pruned.
+        assert!(is_over_limit(db.enforce_operation_limit(APP_UID, false)));
+        assert_eq!(db.enforce_operation_limit(OTHER_APP_UID, false), Ok(()));
+        assert_eq!(outcome(&first), Outcome::Unknown);
+        assert_eq!(outcome(&second), Outcome::Unknown);
+        assert_eq!(outcome(&other), Outcome::Unknown);
+
+        // Finishing an operation makes room for another one.
+        first.abort(Outcome::Abort).unwrap();
+        assert_eq!(db.enforce_operation_limit(APP_UID, false), Ok(()));
+    }
+
+    /// A KeyMint operation that fails every call with the given error code. With
+    /// `ErrorCode::OK`, every call succeeds.
+    struct FailingKeyMintOperation(ErrorCode);
+
+    impl binder::Interface for FailingKeyMintOperation {}
+
+    impl FailingKeyMintOperation {
+        fn status(&self) -> binder::Status {
+            binder::Status::new_service_specific_error(self.0 .0, None)
+        }
+
+        fn result<T>(&self, value: T) -> binder::Result<T> {
+            if self.0 == ErrorCode::OK {
+                Ok(value)
+            } else {
+                Err(self.status())
+            }
+        }
+    }
+
+    impl IKeyMintOperation for FailingKeyMintOperation {
+        fn updateAad(
+            &self,
+            _input: &[u8],
+            _auth_token: Option<&HardwareAuthToken>,
+            _timestamp_token: Option<&TimeStampToken>,
+        ) -> binder::Result<()> {
+            self.result(())
+        }
+
+        fn update(
+            &self,
+            _input: &[u8],
+            _auth_token: Option<&HardwareAuthToken>,
+            _timestamp_token: Option<&TimeStampToken>,
+        ) -> binder::Result<Vec<u8>> {
+            self.result(vec![])
+        }
+
+        fn finish(
+            &self,
+            _input: Option<&[u8]>,
+            _signature: Option<&[u8]>,
+            _auth_token: Option<&HardwareAuthToken>,
+            _timestamp_token: Option<&TimeStampToken>,
+            _confirmation_token: Option<&[u8]>,
+        ) -> binder::Result<Vec<u8>> {
+            self.result(vec![])
+        }
+
+        fn abort(&self) -> binder::Result<()> {
+            self.result(())
+        }
+    }
+
+    fn failing_operation(error_code: ErrorCode, purpose: KeyPurpose) -> Operation {
+        let (_, auth_info) =
+            Enforcements::default().authorize_create(purpose, None, &[], false).unwrap();
+        Operation::new(
+            0,
+            BnKeyMintOperation::new_binder(
+                FailingKeyMintOperation(error_code),
+                BinderFeatures::default(),
+            ),
+            APP_UID,
+            None,
+            auth_info,
+            false,
+            LoggingInfo::new(SecurityLevel::TRUSTED_ENVIRONMENT, purpose, vec![], false),
+            None,
+            Default::default(),
+        )
+    }
+
+    fn outcome(op: &Operation) -> Outcome {
+        *op.outcome.lock().unwrap()
+    }
+
+    fn create_abortable_operation(db: &OperationDb, owner: u32) -> Arc<Operation> {
+        create_operation_on_key(db, owner, None)
+    }
+
+    fn create_operation_on_key(
+        db: &OperationDb,
+        owner: u32,
+        key_id: Option<i64>,
+    ) -> Arc<Operation> {
+        create_operation_with_algorithm(db, owner, key_id, None)
+    }
+
+    fn create_operation_with_algorithm(
+        db: &OperationDb,
+        owner: u32,
+        key_id: Option<i64>,
+        algorithm: Option<Algorithm>,
+    ) -> Arc<Operation> {
+        let (_, auth_info) =
+            Enforcements::default().authorize_create(KeyPurpose::SIGN, None, &[], false).unwrap();
+        db.create_operation(
+            BnKeyMintOperation::new_binder(
+                FailingKeyMintOperation(ErrorCode::OK),
+                BinderFeatures::default(),
+            ),
+            owner,
+            key_id,
+            algorithm,
+            auth_info,
+            false,
+            LoggingInfo::new(SecurityLevel::TRUSTED_ENVIRONMENT, KeyPurpose::SIGN, vec![], false),
+        )
+        .unwrap()
+    }
+
+    fn assert_abort_error(result: Result<bool>, expected: Error) {
+        assert_eq!(result.unwrap_err().root_cause().downcast_ref::<Error>(), Some(&expected));
+    }
+
+    #[test]
+    fn test_abort_owned_operation_by_id() {
+        let db = OperationDb::new();
+        let op = create_abortable_operation(&db, APP_UID);
+        let other_op = create_abortable_operation(&db, APP_UID);
+        assert_ne!(op.id(), other_op.id());
+        assert_eq!(db.active_operation_ids(Some(APP_UID)), vec![op.id(), other_op.id()]);
+        assert!(db.active_operation_ids(Some(OTHER_APP_UID)).is_empty());
+
+        assert!(db.abort_operation(op.id(), APP_UID, false).unwrap());
+        assert_eq!(outcome(&op), Outcome::Abort);
+        assert_eq!(outcome(&other_op), Outcome::Unknown);
+        assert_eq!(db.active_operation_ids(None), vec![other_op.id()]);
+
+        // The operation cannot be aborted twice, and unknown ids are not found.
+        assert_abort_error(
+            db.abort_operation(op.id(), APP_UID, false),
+            Error::Km(ErrorCode::INVALID_OPERATION_HANDLE),
+        );
+        assert!(!db.abort_operation(-1, APP_UID, false).unwrap());
+    }
+
+    #[test]
+    fn test_abort_operation_of_other_caller() {
+        let db = OperationDb::new();
+        let op = create_abortable_operation(&db, APP_UID);
+
+        assert_abort_error(db.abort_operation(op.id(), OTHER_APP_UID, false), Error::perm());
+        assert_eq!(outcome(&op), Outcome::Unknown);
+
+        // Privileged callers may abort any operation.
+        assert!(db.abort_operation(op.id(), SYSTEM_UID, true).unwrap());
+        assert_eq!(outcome(&op), Outcome::Abort);
+    }
+
+    #[test]
+    fn test_list_operations_for_key() {
+        let db = OperationDb::new();
+        let op = create_operation_on_key(&db, APP_UID, Some(7));
+        let other_op = create_operation_on_key(&db, OTHER_APP_UID, Some(7));
+        let _op_on_other_key = create_operation_on_key(&db, APP_UID, Some(8));
+        let _blob_op = create_abortable_operation(&db, APP_UID);
+
+        let summaries = db.active_operations_for_key(7);
+        assert_eq!(
+            summaries.iter().map(|s| (s.id, s.owner)).collect::<Vec<_>>(),
+            vec![(op.id(), APP_UID), (other_op.id(), OTHER_APP_UID)]
+        );
+        for summary in &summaries {
+            assert_eq!(summary.sec_level, SecurityLevel::TRUSTED_ENVIRONMENT);
+            assert_eq!(summary.purpose, KeyPurpose::SIGN);
+            assert!(!summary.forced);
+        }
+
+        // Finalized operations are no longer reported.
+        assert!(db.abort_operation(op.id(), APP_UID, false).unwrap());
+        assert_eq!(
+            db.active_operations_for_key(7).iter().map(|s| s.id).collect::<Vec<_>>(),
+            vec![other_op.id()]
+        );
+        drop(other_op);
+        assert!(db.active_operations_for_key(7).is_empty());
+        assert!(db.active_operations_for_key(9).is_empty());
+    }
+
+    #[test]
+    fn test_operations_are_logged_for_key() -> Result<()> {
+        const KEY_ID: i64 = 4711;
+        KEY_OPERATION_LOGS.set_capacity(KEY_ID, 2)?;
+        let db = OperationDb::new();
+
+        let finished = create_operation_on_key(&db, APP_UID, Some(KEY_ID));
+        assert_eq!(finished.finish(None, None)?, None);
+        // Operations are recorded when they are dropped, i.e., once their outcome is final.
+        assert_eq!(KEY_OPERATION_LOGS.records(KEY_ID), Some(vec![]));
+        drop(finished);
+        let aborted = create_operation_on_key(&db, APP_UID, Some(KEY_ID));
+        assert!(db.abort_operation(aborted.id(), APP_UID, false)?);
+        drop(aborted);
+        let outcomes = || -> Vec<(KeyPurpose, Outcome)> {
+            KEY_OPERATION_LOGS
+                .records(KEY_ID)
+                .unwrap()
+                .iter()
+                .map(|record| (record.purpose, record.outcome))
+                .collect()
+        };
+        assert_eq!(
+            outcomes(),
+            vec![(KeyPurpose::SIGN, Outcome::Success), (KeyPurpose::SIGN, Outcome::Abort)]
+        );
+
+        // Operations on other keys are not logged, and the log keeps the most recent records.
+        drop(create_operation_on_key(&db, APP_UID, Some(KEY_ID + 1)));
+        drop(create_abortable_operation(&db, APP_UID));
+        drop(create_operation_on_key(&db, APP_UID, Some(KEY_ID)));
+        assert_eq!(
+            outcomes(),
+            vec![(KeyPurpose::SIGN, Outcome::Abort), (KeyPurpose::SIGN, Outcome::Dropped)]
+        );
+        KEY_OPERATION_LOGS.set_capacity(KEY_ID, 0)?;
+        Ok(())
+    }
+
+    fn capped_operation_db(clock: &Arc<FakeClock>, max_lifetime: Option<Duration>) -> OperationDb {
+        OperationDb {
+            operations: Mutex::new(Vec::new()),
+            limits: OperationLimits { per_uid: 0, per_system_uid: 0 },
+            lifetime_cap: LifetimeCap {
+                clock: Arc::new(clock.clone()),
+                max_lifetime,
+                per_algorithm: vec![],
+            },
+            hardware_resets: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_active_operation_is_aborted_at_max_lifetime() {
+        let clock = Arc::new(FakeClock::default());
+        let db = capped_operation_db(&clock, Some(Duration::from_secs(10)));
+        let op = create_abortable_operation(&db, APP_UID);
+
+        // Continuous activity does not extend the lifetime.
+        for _ in 0..9 {
+            clock.advance(1000);
+            assert_eq!(op.update(b"data").unwrap(), None);
+        }
+        clock.advance(999);
+        assert!(op.update_aad(b"aad").is_ok());
+        assert_eq!(outcome(&op), Outcome::Unknown);
+
+        clock.advance(1);
+        assert_eq!(
+            op.update(b"data").unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_OPERATION_HANDLE))
+        );
+        assert_eq!(outcome(&op), Outcome::Pruned);
+        assert!(db.active_operation_ids(None).is_empty());
+
+        // Without a cap, operations live until they are finalized.
+        let db = capped_operation_db(&clock, None);
+        let op = create_abortable_operation(&db, APP_UID);
+        clock.advance(i64::MAX / 2);
+        assert_eq!(op.finish(None, None).unwrap(), None);
+        assert_eq!(outcome(&op), Outcome::Success);
+    }
+
+    #[test]
+    fn test_max_lifetime_per_algorithm() {
+        let clock = Arc::new(FakeClock::default());
+        let mut db = capped_operation_db(&clock, Some(Duration::from_secs(10)));
+        db.lifetime_cap.per_algorithm = vec![
+            (Algorithm::RSA, Some(Duration::from_secs(60))),
+            (Algorithm::EC, Some(Duration::from_secs(5))),
+            (Algorithm::AES, None),
+        ];
+        let rsa_op = create_operation_with_algorithm(&db, APP_UID, Some(1), Some(Algorithm::RSA));
+        let ec_op = create_operation_with_algorithm(&db, APP_UID, Some(2), Some(Algorithm::EC));
+        let aes_op = create_operation_with_algorithm(&db, APP_UID, Some(3), Some(Algorithm::AES));
+        let hmac_op = create_operation_with_algorithm(&db, APP_UID, Some(4), Some(Algorithm::HMAC));
+        let blob_op = create_operation_on_key(&db, APP_UID, None);
+
+        // The fast EC operation reaches its shorter lifetime first.
+        clock.advance(5000);
+        assert!(ec_op.update(b"data").is_err());
+        assert_eq!(outcome(&ec_op), Outcome::Pruned);
+        assert_eq!(rsa_op.update(b"data").unwrap(), None);
+
+        // Algorithms without an override, and keys of unknown algorithm, use the default.
+        clock.advance(5000);
+        assert!(hmac_op.update(b"data").is_err());
+        assert!(blob_op.update(b"data").is_err());
+        assert_eq!(outcome(&hmac_op), Outcome::Pruned);
+        assert_eq!(outcome(&blob_op), Outcome::Pruned);
+        assert_eq!(rsa_op.update(b"data").unwrap(), None);
+
+        // The slow RSA operation gets its longer lifetime.
+        clock.advance(49_999);
+        assert_eq!(rsa_op.update(b"data").unwrap(), None);
+        clock.advance(1);
+        assert_eq!(
+            rsa_op.update(b"data").unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_OPERATION_HANDLE))
+        );
+        assert_eq!(outcome(&rsa_op), Outcome::Pruned);
+
+        // An override of 0 disables the cap for the algorithm.
+        assert_eq!(aes_op.finish(None, None).unwrap(), None);
+        assert_eq!(outcome(&aes_op), Outcome::Success);
+    }
+
+    fn create_strongbox_operation(db: &OperationDb, error_code: ErrorCode) -> Arc<Operation> {
+        let (_, auth_info) =
+            Enforcements::default().authorize_create(KeyPurpose::SIGN, None, &[], false).unwrap();
+        db.create_operation(
+            BnKeyMintOperation::new_binder(
+                FailingKeyMintOperation(error_code),
+                BinderFeatures::default(),
+            ),
+            APP_UID,
+            None,
+            None,
+            auth_info,
+            false,
+            LoggingInfo::new(SecurityLevel::STRONGBOX, KeyPurpose::SIGN, vec![], false),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_hardware_reset_aborts_lost_operations() {
+        let negotiated = std::cell::Cell::new(0);
+        let negotiate = || {
+            negotiated.set(negotiated.get() + 1);
+            Ok(Default::default())
+        };
+        KEYMINT_BACKEND_INFO.invalidate(SecurityLevel::STRONGBOX);
+        KEYMINT_BACKEND_INFO.get_or_negotiate(SecurityLevel::STRONGBOX, negotiate).unwrap();
+        assert_eq!(negotiated.get(), 1);
+
+        // KeyMint no longer knows the handle of the first operation after the reset.
+        let db = OperationDb::new();
+        let reset_op = create_strongbox_operation(&db, ErrorCode::INVALID_OPERATION_HANDLE);
+        let other_op = create_strongbox_operation(&db, ErrorCode::OK);
+        let error = reset_op.update(b"data").unwrap_err();
+        assert_eq!(error.root_cause().downcast_ref::<Error>(), Some(&Error::HardwareReset));
+        assert_eq!(get_error_code(&error), ResponseCode::BACKEND_BUSY.0);
+        assert_eq!(outcome(&reset_op), Outcome::HardwareReset);
+
+        // The other operation was lost with the reset, although KeyMint would still accept it.
+        assert!(db.active_operation_ids(None).is_empty());
+        assert_eq!(
+            other_op.update(b"data").unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::HardwareReset)
+        );
+        assert_eq!(outcome(&other_op), Outcome::HardwareReset);
+
+        // The cached info of the backend was invalidated.
+        KEYMINT_BACKEND_INFO.get_or_negotiate(SecurityLevel::STRONGBOX, negotiate).unwrap();
+        assert_eq!(negotiated.get(), 2);
+
+        // Operations created after the reset are unaffected.
+        let new_op = create_strongbox_operation(&db, ErrorCode::OK);
+        assert_eq!(db.active_operation_ids(None), vec![new_op.id()]);
+        assert_eq!(new_op.finish(None, None).unwrap(), None);
+        assert_eq!(outcome(&new_op), Outcome::Success);
+
+        // A reset that is noticed while beginning a new operation loses the existing ones, too.
+        let op = create_strongbox_operation(&db, ErrorCode::OK);
+        db.report_hardware_reset(SecurityLevel::STRONGBOX);
+        assert!(op.finish(None, None).is_err());
+        assert_eq!(outcome(&op), Outcome::HardwareReset);
+    }
+
+    #[test]
+    fn test_cancellation_is_not_an_error() {
+        let op = failing_operation(ErrorCode::OPERATION_CANCELLED, KeyPurpose::SIGN);
+        assert_eq!(
+            op.update(b"data").unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::OPERATION_CANCELLED))
+        );
+        assert_eq!(outcome(&op), Outcome::Cancelled);
+
+        // Real failures are still recorded with their error code.
+        let op = failing_operation(ErrorCode::UNKNOWN_ERROR, KeyPurpose::SIGN);
+        assert!(op.finish(None, None).is_err());
+        assert_eq!(outcome(&op), Outcome::ErrorCode(ErrorCode::UNKNOWN_ERROR));
+    }
+
+    #[test]
+    fn test_client_abort_is_not_an_error() {
+        // The outcome of an aborted operation stays `Outcome::Abort` even if KeyMint reports
+        // the cancellation as error.
+        let op = failing_operation(ErrorCode::OPERATION_CANCELLED, KeyPurpose::SIGN);
+        assert!(op.abort(Outcome::Abort).is_err());
+        assert_eq!(outcome(&op), Outcome::Abort);
+    }
+
+    #[test]
+    fn test_cancellation_is_logged_as_cancelled() {
+        let op = failing_operation(ErrorCode::OPERATION_CANCELLED, KeyPurpose::SIGN);
+        assert!(op.update_aad(b"aad").is_err());
+        drop(op);
+
+        let atoms = METRICS_STORE.get_atoms(AtomID::KEY_OPERATION_WITH_GENERAL_INFO).unwrap();
+        assert!(atoms.iter().any(|atom| matches!(
+            &atom.payload,
+            KeystoreAtomPayload::KeyOperationWithGeneralInfo(info)
+                if info.outcome == MetricsOutcome::CANCELLED
+        )));
+        assert!(!atoms.iter().any(|atom| matches!(
+            &atom.payload,
+            KeystoreAtomPayload::KeyOperationWithGeneralInfo(info)
+                if info.error_code == ErrorCode::OPERATION_CANCELLED.0
+        )));
+    }
+}
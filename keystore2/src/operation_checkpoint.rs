@@ -0,0 +1,503 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the checkpointing of operations, so that they can be resumed after
+//! keystore restarts.
+//!
+//! KeyMint operations do not survive a restart of keystore, because KeyMint aborts them when
+//! keystore's references to them die. Signing and verification operations produce no output
+//! before `finish`, so feeding their input so far to a new KeyMint operation with the same
+//! parameters yields an equivalent operation. If enabled with the system property
+//! `keystore.operation_checkpoints`, keystore persists the parameters and the input of such
+//! operations as a checkpoint in the database. After a restart, the owner may resume an
+//! operation, presenting the id of the operation as token. Clients resume their operations with
+//! `IKeystoreSecurityLevel::createOperation`, passing the id as the only operation parameter
+//! with the tag `KEY_TAG_RESUME_OPERATION`. The parameters and the key of the new operation are
+//! those of the checkpoint, so the key descriptor of the request is not used. System components
+//! may also use `IKeystoreMaintenance::resumeOperation`. A checkpoint can only be resumed once,
+//! and the resumed operation gets a new id.
+//!
+//! Operation ids are random, whether or not the operation is checkpointed, so the ids of new
+//! operations do not collide with those of the checkpoints of operations from before the
+//! restart. Each update appends its input to the checkpoint as a separate chunk, which is
+//! encrypted with the per-boot super key of the owner's user. This limits checkpointing in two
+//! ways:
+//!  * The per-boot super key only exists for users with an LSKF, so the operations of users
+//!    without an LSKF are never checkpointed.
+//!  * The per-boot super key is only held in memory, so after a restart of keystore, a
+//!    checkpoint can only be resumed once the user has unlocked the device with their LSKF
+//!    again. Until then, resuming fails with `ResponseCode::LOCKED` and the checkpoint stays in
+//!    place, so that it can be resumed later, up to `MAX_CHECKPOINT_AGE`.
+//!
+//! A key that survives restarts without the LSKF would leave the input at rest protected by
+//! keys that are available without the user's knowledge factor, so the per-boot super key is
+//! used despite these limits.
+//!
+//! Other operations, e.g., encryption, return output with every update, which cannot be
+//! replayed without the client noticing, and operations on keys that require user
+//! authentication or count their uses cannot be replayed either. Resuming such an operation, or
+//! an operation whose checkpoint was discarded, fails with `Error::OperationLost`. Because the
+//! checkpoints contain the input of operations, checkpointing is disabled by default, their
+//! input is capped, and they are deleted when the operation concludes or after
+//! `MAX_CHECKPOINT_AGE`.
+
+use crate::database::{DateTime, OperationCheckpoint, Uuid};
+use crate::error::{map_ks_error, Error, ErrorCode};
+use crate::globals::{DB, SUPER_KEY};
+use crate::key_parameter::{KeyParameter as KsKeyParameter, KeyParameterValue as KsKeyParamValue};
+use crate::key_tags::KEY_TAG_RESUME_OPERATION;
+use crate::ks_err;
+use crate::operation::list_operation_ids;
+use crate::security_level::get_security_level_by_uuid;
+use crate::sysprop::read_prop_bool;
+use crate::utils::{uid_to_android_user, AesGcm};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    Tag::Tag,
+};
+use android_system_keystore2::aidl::android::system::keystore2::ResponseCode::ResponseCode;
+use android_system_keystore2::aidl::android::system::keystore2::{
+    CreateOperationResponse::CreateOperationResponse, Domain::Domain, KeyDescriptor::KeyDescriptor,
+};
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Whether resumable operations are checkpointed, false by default.
+const OPERATION_CHECKPOINTS_PROPERTY: &str = "keystore.operation_checkpoints";
+
+/// Operations whose input exceeds this size are no longer checkpointed.
+const MAX_CHECKPOINT_INPUT: usize = 0x100000;
+
+/// The input is replayed in chunks of the maximum size that `IKeystoreOperation::update`
+/// accepts.
+const REPLAY_CHUNK_SIZE: usize = 0x8000;
+
+/// Checkpoints that were not resumed within this time are deleted by the garbage collector.
+pub const MAX_CHECKPOINT_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns true if an operation with `purpose` and `op_params` on a key with `key_params` can
+/// be resumed by replaying its input.
+pub fn is_resumable(
+    purpose: KeyPurpose,
+    key_params: &[KsKeyParameter],
+    op_params: &[KeyParameter],
+) -> bool {
+    matches!(purpose, KeyPurpose::SIGN | KeyPurpose::VERIFY)
+        && !key_params.iter().any(|kp| {
+            matches!(
+                kp.get_tag(),
+                Tag::USER_SECURE_ID
+                    | Tag::TRUSTED_CONFIRMATION_REQUIRED
+                    | Tag::USAGE_COUNT_LIMIT
+                    | Tag::MAX_USES_PER_BOOT
+            )
+        })
+        // Parameters that keystore does not know cannot be stored.
+        && op_params.iter().all(|kp| KsKeyParamValue::from(kp).get_tag() == kp.tag)
+}
+
+/// Returns the super key with which the checkpointed input of the operations of `owner` is
+/// encrypted, if it is available.
+fn input_key(owner: u32) -> Option<Arc<dyn AesGcm + Send + Sync>> {
+    SUPER_KEY.read().unwrap().get_per_boot_key_by_user_id(uid_to_android_user(owner))
+}
+
+/// Stores the checkpoint of the new operation `id` of `owner` on the key `key_id` if
+/// checkpointing is enabled, the operation is resumable, and the super key of the owner is
+/// available. `op_params` are the parameters of the operation without its purpose. Returns true
+/// if the operation is checkpointed. Failing to store the checkpoint only makes the operation
+/// not resumable, so errors are only logged.
+pub fn create_checkpoint(
+    id: i64,
+    owner: u32,
+    key_id: i64,
+    km_uuid: &Uuid,
+    purpose: KeyPurpose,
+    key_params: &[KsKeyParameter],
+    op_params: &[KeyParameter],
+) -> bool {
+    if !read_prop_bool(OPERATION_CHECKPOINTS_PROPERTY, false)
+        || !is_resumable(purpose, key_params, op_params)
+        || input_key(owner).is_none()
+    {
+        return false;
+    }
+    let mut params = op_params.to_vec();
+    params.push(KsKeyParamValue::KeyPurpose(purpose).into());
+    let result = DateTime::now().context(ks_err!("Failed to get the creation time.")).and_then(
+        |creation_time| {
+            DB.with(|db| {
+                db.borrow_mut().insert_operation_checkpoint(
+                    id,
+                    owner,
+                    key_id,
+                    km_uuid,
+                    &params,
+                    creation_time,
+                )
+            })
+        },
+    );
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            log::error!("Failed to create operation checkpoint: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Appends `input` to the checkpoint of the operation `id` of `owner`. Returns false if the
+/// operation is no longer checkpointed, e.g., because its input grew too large, or because the
+/// super key of the owner is no longer available, in which case its checkpoint is discarded.
+pub fn record_input(id: i64, owner: u32, input: &[u8]) -> bool {
+    let result = input_key(owner)
+        .ok_or(Error::Rc(ResponseCode::LOCKED))
+        .context(ks_err!("The super key of the owner is not available."))
+        .and_then(|key| key.encrypt(input).context(ks_err!("Failed to encrypt the input.")))
+        .and_then(|(ciphertext, iv, tag)| {
+            DB.with(|db| {
+                db.borrow_mut().append_operation_checkpoint_input(
+                    id,
+                    &ciphertext,
+                    &iv,
+                    &tag,
+                    input.len(),
+                )
+            })
+        });
+    match result {
+        Ok(Some(len)) if len <= MAX_CHECKPOINT_INPUT => return true,
+        Ok(Some(_)) => log::info!("Operation {} is too large to be resumed.", id),
+        Ok(None) => return false,
+        Err(e) => log::error!("Failed to checkpoint the input of operation {}: {:?}", id, e),
+    }
+    discard(id);
+    false
+}
+
+/// Deletes the checkpoint of the operation `id`, e.g., because the operation concluded.
+pub fn discard(id: i64) {
+    if let Err(e) = DB.with(|db| db.borrow_mut().delete_operation_checkpoint(id)) {
+        log::error!("Failed to delete the checkpoint of operation {}: {:?}", id, e);
+    }
+}
+
+/// Returns the super key with which the checkpointed input of the operations of `owner` is
+/// decrypted. Fails with `ResponseCode::LOCKED` if the key is not available, e.g., because the
+/// user has not unlocked the device since keystore restarted.
+pub fn resume_key(owner: u32) -> Result<Arc<dyn AesGcm + Send + Sync>> {
+    input_key(owner)
+        .ok_or(Error::Rc(ResponseCode::LOCKED))
+        .context(ks_err!("The super key of the owner is not available."))
+}
+
+/// Returns the id of the lost operation that the parameters `op_params` of
+/// `IKeystoreSecurityLevel::createOperation` resume, or None if they begin a new operation.
+/// Fails with `ErrorCode::INVALID_ARGUMENT` if `KEY_TAG_RESUME_OPERATION` has no long integer
+/// value or comes with other parameters, which are taken from the checkpoint instead.
+pub fn resume_request(op_params: &[KeyParameter]) -> Result<Option<i64>> {
+    match op_params {
+        [KeyParameter {
+            tag: KEY_TAG_RESUME_OPERATION,
+            value: KeyParameterValue::LongInteger(id),
+        }] => Ok(Some(*id)),
+        _ if op_params.iter().any(|kp| kp.tag == KEY_TAG_RESUME_OPERATION) => {
+            Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context(ks_err!("The id of the operation to resume must be the only parameter."))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Resumes the lost operation `operation_id` of `caller_uid` from its checkpoint. Fails with
+/// `ResponseCode::INVALID_ARGUMENT` if the operation is still active, with
+/// `ResponseCode::LOCKED` if the super key of the caller is not available, in which case the
+/// checkpoint stays in place, and with `Error::OperationLost` if there is no checkpoint.
+pub fn resume(operation_id: i64, caller_uid: u32) -> Result<CreateOperationResponse> {
+    if list_operation_ids(Some(caller_uid)).contains(&operation_id) {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Operation {} is still active.", operation_id));
+    }
+    // The checkpoint stays in place if its input cannot be decrypted yet.
+    let input_key = resume_key(caller_uid).context(ks_err!())?;
+    // Only checkpoints of the caller are taken, so the checkpoints of other callers can
+    // neither be resumed nor discarded.
+    let checkpoint = DB
+        .with(|db| db.borrow_mut().take_operation_checkpoint(operation_id, caller_uid))
+        .context(ks_err!())?;
+    // The new operation is created through the security level, which performs the same
+    // permission checks and enforcements as for any other operation.
+    resume_operation(checkpoint, operation_id, input_key.as_ref(), |km_uuid, key, params| {
+        let sec_level = get_security_level_by_uuid(km_uuid).context(ks_err!())?;
+        map_ks_error(sec_level.createOperation(key, params, false)).context(ks_err!())
+    })
+    .context(ks_err!())
+}
+
+/// Resumes the operation `id` from its `checkpoint`, which must have been taken from the
+/// database by the owner of the operation, whose super key `key` decrypts the checkpointed
+/// input. `begin` begins the new operation on the KeyMint instance of the checkpoint, given the
+/// key and the parameters, like `IKeystoreSecurityLevel::createOperation`. The checkpointed
+/// input is then replayed on the new operation, and the new operation is returned. Fails with
+/// `Error::OperationLost` if there is no checkpoint.
+pub fn resume_operation<F>(
+    checkpoint: Option<OperationCheckpoint>,
+    id: i64,
+    key: &dyn AesGcm,
+    begin: F,
+) -> Result<CreateOperationResponse>
+where
+    F: FnOnce(&Uuid, &KeyDescriptor, &[KeyParameter]) -> Result<CreateOperationResponse>,
+{
+    let checkpoint = match checkpoint {
+        Some(checkpoint) => checkpoint,
+        None => {
+            return Err(Error::OperationLost)
+                .context(ks_err!("Operation {} cannot be resumed.", id));
+        }
+    };
+    let mut input = Vec::new();
+    for (ciphertext, iv, tag) in &checkpoint.input {
+        input.extend_from_slice(
+            &key.decrypt(ciphertext, iv, tag).context(ks_err!("Failed to decrypt the input."))?,
+        );
+    }
+    let key_descriptor = KeyDescriptor {
+        domain: Domain::KEY_ID,
+        nspace: checkpoint.key_id,
+        alias: None,
+        blob: None,
+    };
+    let response = begin(&checkpoint.km_uuid, &key_descriptor, &checkpoint.params)
+        .context(ks_err!("Failed to begin the resumed operation."))?;
+    let operation = response
+        .iOperation
+        .as_ref()
+        .ok_or_else(Error::sys)
+        .context(ks_err!("The resumed operation has no operation interface."))?;
+    for chunk in input.chunks(REPLAY_CHUNK_SIZE) {
+        map_ks_error(operation.update(chunk)).context(ks_err!("Failed to replay the input."))?;
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::KeystoreDB;
+    use crate::error::get_error_code;
+    use crate::utils::{AesGcmKey, AID_USER_OFFSET};
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        Digest::Digest, SecurityLevel::SecurityLevel,
+    };
+    use android_system_keystore2::aidl::android::system::keystore2::IKeystoreOperation::{
+        BnKeystoreOperation, IKeystoreOperation,
+    };
+    use android_system_keystore2::binder::{self, BinderFeatures, Interface};
+    use keystore2_crypto::{generate_aes256_key, sha256, ZVec};
+    use keystore2_test_utils::TempDir;
+    use std::sync::Mutex;
+
+    const OWNER: u32 = 10001;
+    const OTHER_UID: u32 = 10002;
+    const KEY_ID: i64 = 42;
+
+    /// An operation that returns the SHA-256 digest of its input on finish.
+    #[derive(Default)]
+    struct HashOperation(Mutex<Vec<u8>>);
+
+    impl Interface for HashOperation {}
+
+    impl IKeystoreOperation for HashOperation {
+        fn updateAad(&self, _aad_input: &[u8]) -> binder::Result<()> {
+            Ok(())
+        }
+
+        fn update(&self, input: &[u8]) -> binder::Result<Option<Vec<u8>>> {
+            self.0.lock().unwrap().extend_from_slice(input);
+            Ok(None)
+        }
+
+        fn finish(
+            &self,
+            input: Option<&[u8]>,
+            _signature: Option<&[u8]>,
+        ) -> binder::Result<Option<Vec<u8>>> {
+            let mut data = self.0.lock().unwrap();
+            data.extend_from_slice(input.unwrap_or_default());
+            Ok(Some(sha256(&data).unwrap()))
+        }
+
+        fn abort(&self) -> binder::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct TestKey(ZVec);
+
+    impl AesGcmKey for TestKey {
+        fn key(&self) -> &[u8] {
+            &self.0
+        }
+    }
+
+    /// Appends `input` encrypted with `key` to the checkpoint `id` in `db`.
+    fn append_input(
+        db: &mut KeystoreDB,
+        id: i64,
+        key: &TestKey,
+        input: &[u8],
+    ) -> Result<Option<usize>> {
+        let (ciphertext, iv, tag) = key.encrypt(input)?;
+        db.append_operation_checkpoint_input(id, &ciphertext, &iv, &tag, input.len())
+    }
+
+    fn sign_params() -> Vec<KeyParameter> {
+        vec![
+            KsKeyParamValue::Digest(Digest::SHA_2_256).into(),
+            KsKeyParamValue::KeyPurpose(KeyPurpose::SIGN).into(),
+        ]
+    }
+
+    #[test]
+    fn test_resume_hash_operation_after_restart() -> Result<()> {
+        let temp_dir = TempDir::new("operation_checkpoint_test")?;
+        let km_uuid = Uuid::from(SecurityLevel::TRUSTED_ENVIRONMENT);
+        let large_input = vec![0xa5; REPLAY_CHUNK_SIZE + 1];
+        let key = TestKey(generate_aes256_key()?);
+        let id = 0x1234;
+        {
+            let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+            db.insert_operation_checkpoint(
+                id,
+                OWNER,
+                KEY_ID,
+                &km_uuid,
+                &sign_params(),
+                DateTime::now()?,
+            )?;
+            // The id of a checkpoint cannot be reused.
+            assert!(db
+                .insert_operation_checkpoint(
+                    id,
+                    OTHER_UID,
+                    KEY_ID,
+                    &km_uuid,
+                    &sign_params(),
+                    DateTime::now()?,
+                )
+                .is_err());
+            assert_eq!(append_input(&mut db, id, &key, b"checkpointed ")?, Some(13));
+            assert_eq!(
+                append_input(&mut db, id, &key, &large_input)?,
+                Some(13 + large_input.len())
+            );
+            // Keystore restarts, and all operations are lost.
+        }
+
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        // Other callers cannot resume the operation.
+        assert_eq!(db.take_operation_checkpoint(id, OTHER_UID)?, None);
+        let checkpoint = db.take_operation_checkpoint(id, OWNER)?;
+        assert_eq!(checkpoint.as_ref().map(|c| c.params.clone()), Some(sign_params()));
+
+        // The input cannot be decrypted without the key of the owner.
+        let other_key = TestKey(generate_aes256_key()?);
+        assert!(resume_operation(checkpoint.clone(), id, &other_key, |_, _, _| {
+            panic!("Must not begin.")
+        })
+        .is_err());
+
+        let response = resume_operation(checkpoint, id, &key, |uuid, key, params| {
+            assert_eq!(*uuid, km_uuid);
+            assert_eq!((key.domain, key.nspace), (Domain::KEY_ID, KEY_ID));
+            assert_eq!(params, sign_params().as_slice());
+            Ok(CreateOperationResponse {
+                iOperation: Some(BnKeystoreOperation::new_binder(
+                    HashOperation::default(),
+                    BinderFeatures::default(),
+                )),
+                operationChallenge: None,
+                parameters: None,
+                upgradedBlob: None,
+            })
+        })?;
+        let digest = response.iOperation.unwrap().finish(Some(b"!"), None)?;
+        let mut expected = b"checkpointed ".to_vec();
+        expected.extend_from_slice(&large_input);
+        expected.extend_from_slice(b"!");
+        assert_eq!(digest, Some(sha256(&expected)?));
+
+        // A checkpoint can only be resumed once.
+        let checkpoint = db.take_operation_checkpoint(id, OWNER)?;
+        let error = resume_operation(checkpoint, id, &key, |_, _, _| panic!("Must not begin."))
+            .unwrap_err();
+        assert_eq!(error.root_cause().downcast_ref::<Error>(), Some(&Error::OperationLost));
+        assert_eq!(get_error_code(&error), ErrorCode::INVALID_OPERATION_HANDLE.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_request() -> Result<()> {
+        let resume = KeyParameter {
+            tag: KEY_TAG_RESUME_OPERATION,
+            value: KeyParameterValue::LongInteger(0x1234),
+        };
+        assert_eq!(resume_request(&sign_params())?, None);
+        assert_eq!(resume_request(&[resume.clone()])?, Some(0x1234));
+
+        // The parameters of the resumed operation are those of the checkpoint.
+        let mut params = sign_params();
+        params.push(resume);
+        let invalid_value =
+            KeyParameter { tag: KEY_TAG_RESUME_OPERATION, value: KeyParameterValue::Integer(1) };
+        for params in [params, vec![invalid_value]] {
+            assert_eq!(
+                resume_request(&params).unwrap_err().root_cause().downcast_ref::<Error>(),
+                Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_resume_without_super_key() {
+        // Users without an LSKF have no super key, and neither have users that have not unlocked
+        // the device since keystore restarted.
+        let owner = 98 * AID_USER_OFFSET + OWNER;
+        assert!(input_key(owner).is_none());
+        assert_eq!(
+            resume_key(owner).unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::LOCKED))
+        );
+    }
+
+    #[test]
+    fn test_resumable_operations() {
+        let params = sign_params();
+        assert!(is_resumable(KeyPurpose::SIGN, &[], &params));
+        assert!(is_resumable(KeyPurpose::VERIFY, &[], &params));
+        assert!(!is_resumable(KeyPurpose::ENCRYPT, &[], &params));
+        assert!(!is_resumable(KeyPurpose::DECRYPT, &[], &params));
+
+        // Keys that require authentication cannot be resumed.
+        let auth_bound = [KsKeyParameter::new(
+            KsKeyParamValue::UserSecureID(1),
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        )];
+        assert!(!is_resumable(KeyPurpose::SIGN, &auth_bound, &params));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements operation tokens, which delegate a single operation with a key.
+//!
+//! A caller with the `Use` permission for a key and the keystore permission
+//! `DelegateOperation` can mint a token, with `IKeystoreMaintenance::mintOperationToken`, that
+//! authorizes one operation with that key and a given purpose, e.g., one signature, by another
+//! UID, the delegate. The delegate presents the token instead of holding the `Use` permission:
+//! it creates the operation with a `Domain::KEY_ID` key descriptor for the key whose `blob`
+//! field carries the token. The token is consumed when it is presented, whether or not the
+//! operation can be created, and it expires after a short validity period. Tokens are kept in
+//! memory only, so they do not survive a restart of keystore.
+
+use crate::clock_rollback::Clock;
+use crate::error::{Error, ResponseCode};
+use crate::ks_err;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    Tag::Tag,
+};
+use anyhow::{Context, Result};
+use keystore2_crypto::generate_random_data;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The longest validity period of a token.
+pub const MAX_TOKEN_VALIDITY: Duration = Duration::from_secs(60);
+
+/// The length of a token in bytes.
+const TOKEN_LENGTH: usize = 32;
+
+/// The maximum number of tokens that are minted but neither redeemed nor expired.
+const MAX_OUTSTANDING_TOKENS: usize = 256;
+
+lazy_static! {
+    /// The tokens of keystore.
+    pub static ref OPERATION_TOKENS: OperationTokens = Default::default();
+}
+
+/// The operation that a token authorizes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Delegation {
+    key_id: i64,
+    purpose: KeyPurpose,
+    delegate_uid: u32,
+    /// The monotonic time in milliseconds after which the token is expired.
+    expires_at: i64,
+}
+
+/// The outstanding tokens.
+#[derive(Default)]
+pub struct OperationTokens {
+    tokens: Mutex<HashMap<Vec<u8>, Delegation>>,
+}
+
+impl OperationTokens {
+    /// Mints a token that authorizes `delegate_uid` to perform one operation with the key
+    /// `key_id` and `purpose` within `validity` according to the monotonic time of `clock`.
+    /// The caller must have checked that the minting UID may use the key. Fails with
+    /// `ResponseCode::INVALID_ARGUMENT` if the validity is zero or longer than
+    /// `MAX_TOKEN_VALIDITY`, and with `ResponseCode::BACKEND_BUSY` if too many tokens are
+    /// outstanding.
+    pub fn mint(
+        &self,
+        clock: &dyn Clock,
+        key_id: i64,
+        purpose: KeyPurpose,
+        delegate_uid: u32,
+        validity: Duration,
+    ) -> Result<Vec<u8>> {
+        if validity.is_zero() || validity > MAX_TOKEN_VALIDITY {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                "The validity must be between 1 ms and {} ms.",
+                MAX_TOKEN_VALIDITY.as_millis()
+            ));
+        }
+        let now = clock.monotonic_millis();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, delegation| delegation.expires_at >= now);
+        if tokens.len() >= MAX_OUTSTANDING_TOKENS {
+            return Err(Error::Rc(ResponseCode::BACKEND_BUSY))
+                .context(ks_err!("Too many outstanding operation tokens."));
+        }
+        let token = generate_random_data(TOKEN_LENGTH).context(ks_err!())?;
+        tokens.insert(
+            token.clone(),
+            Delegation {
+                key_id,
+                purpose,
+                delegate_uid,
+                expires_at: now.saturating_add(validity.as_millis() as i64),
+            },
+        );
+        Ok(token)
+    }
+
+    /// Consumes `token` if it authorizes `caller_uid` to begin an operation with the key
+    /// `key_id` and the purpose given by `operation_parameters`. A token can only be redeemed
+    /// once. Fails with `ResponseCode::PERMISSION_DENIED` if the token is unknown, was
+    /// redeemed before, expired according to the monotonic time of `clock`, or authorizes
+    /// another operation.
+    pub fn redeem(
+        &self,
+        clock: &dyn Clock,
+        token: &[u8],
+        key_id: i64,
+        operation_parameters: &[KeyParameter],
+        caller_uid: u32,
+    ) -> Result<()> {
+        let delegation = self
+            .tokens
+            .lock()
+            .unwrap()
+            .remove(token)
+            .ok_or_else(Error::perm)
+            .context(ks_err!("Unknown operation token."))?;
+        if delegation.expires_at < clock.monotonic_millis() {
+            return Err(Error::perm()).context(ks_err!("The operation token expired."));
+        }
+        let purpose = operation_parameters.iter().find_map(|kp| match kp {
+            KeyParameter { tag: Tag::PURPOSE, value: KeyParameterValue::KeyPurpose(p) } => Some(*p),
+            _ => None,
+        });
+        if delegation.key_id != key_id
+            || purpose != Some(delegation.purpose)
+            || delegation.delegate_uid != caller_uid
+        {
+            return Err(Error::perm())
+                .context(ks_err!("The operation token authorizes another operation."));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+    use std::sync::Arc;
+
+    const KEY_ID: i64 = 42;
+    const DELEGATE_UID: u32 = 10002;
+
+    fn sign_params() -> Vec<KeyParameter> {
+        vec![KeyParameter {
+            tag: Tag::PURPOSE,
+            value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+        }]
+    }
+
+    fn is_permission_denied(result: Result<()>) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>() == Some(&Error::perm())
+    }
+
+    #[test]
+    fn test_token_is_single_use() -> Result<()> {
+        let clock = Arc::new(FakeClock::default());
+        let tokens = OperationTokens::default();
+        let validity = Duration::from_secs(10);
+        let token = tokens.mint(&clock, KEY_ID, KeyPurpose::SIGN, DELEGATE_UID, validity)?;
+        assert_eq!(token.len(), TOKEN_LENGTH);
+
+        tokens.redeem(&clock, &token, KEY_ID, &sign_params(), DELEGATE_UID)?;
+        // Reusing the token fails.
+        assert!(is_permission_denied(tokens.redeem(
+            &clock,
+            &token,
+            KEY_ID,
+            &sign_params(),
+            DELEGATE_UID
+        )));
+
+        // Every token is distinct.
+        let other = tokens.mint(&clock, KEY_ID, KeyPurpose::SIGN, DELEGATE_UID, validity)?;
+        assert_ne!(other, token);
+        assert!(is_permission_denied(tokens.redeem(
+            &clock,
+            &[0; TOKEN_LENGTH],
+            KEY_ID,
+            &sign_params(),
+            DELEGATE_UID
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_expires() -> Result<()> {
+        let clock = Arc::new(FakeClock::default());
+        let tokens = OperationTokens::default();
+        let validity = Duration::from_secs(10);
+        let token = tokens.mint(&clock, KEY_ID, KeyPurpose::SIGN, DELEGATE_UID, validity)?;
+        clock.advance(10_000);
+        tokens.redeem(&clock, &token, KEY_ID, &sign_params(), DELEGATE_UID)?;
+
+        let token = tokens.mint(&clock, KEY_ID, KeyPurpose::SIGN, DELEGATE_UID, validity)?;
+        clock.advance(10_001);
+        assert!(is_permission_denied(tokens.redeem(
+            &clock,
+            &token,
+            KEY_ID,
+            &sign_params(),
+            DELEGATE_UID
+        )));
+
+        // Tokens are short lived.
+        for validity in [Duration::ZERO, MAX_TOKEN_VALIDITY + Duration::from_millis(1)] {
+            assert_eq!(
+                tokens
+                    .mint(&clock, KEY_ID, KeyPurpose::SIGN, DELEGATE_UID, validity)
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_authorizes_one_operation() -> Result<()> {
+        let clock = Arc::new(FakeClock::default());
+        let tokens = OperationTokens::default();
+        let validity = Duration::from_secs(10);
+        let decrypt_params = vec![KeyParameter {
+            tag: Tag::PURPOSE,
+            value: KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT),
+        }];
+        let sign_params = sign_params();
+        let cases: [(i64, &[KeyParameter], u32); 4] = [
+            (KEY_ID + 1, &sign_params, DELEGATE_UID),
+            (KEY_ID, &decrypt_params, DELEGATE_UID),
+            (KEY_ID, &[], DELEGATE_UID),
+            (KEY_ID, &sign_params, DELEGATE_UID + 1),
+        ];
+        for (key_id, params, caller_uid) in cases {
+            let token = tokens.mint(&clock, KEY_ID, KeyPurpose::SIGN, DELEGATE_UID, validity)?;
+            assert!(is_permission_denied(
+                tokens.redeem(&clock, &token, key_id, params, caller_uid)
+            ));
+            // A token presented for another operation is consumed nonetheless.
+            assert!(is_permission_denied(tokens.redeem(
+                &clock,
+                &token,
+                KEY_ID,
+                &sign_params,
+                DELEGATE_UID
+            )));
+        }
+        Ok(())
+    }
+}
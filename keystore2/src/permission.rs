@@ -152,6 +152,19 @@ implement_class!(
         /// Checked on calls to IRemotelyProvisionedKeyPool::getAttestationKey
         #[selinux(name = get_attestation_key)]
         GetAttestationKey,
+        /// Checked when IKeystoreMaintenance::exportBackup or importBackup is called.
+        #[selinux(name = backup)]
+        Backup,
+        /// Checked when IKeystoreMaintenance::mintOperationToken is called.
+        #[selinux(name = delegate_operation)]
+        DelegateOperation,
+        /// Checked when IKeystoreMaintenance::migrateKeySecurityLevel, commitKeyMigration, or
+        /// discardKeyMigration is called.
+        #[selinux(name = migrate_security_level)]
+        MigrateSecurityLevel,
+        /// Checked when IKeystoreMaintenance::getDeviceIdentifier is called.
+        #[selinux(name = get_device_identifier)]
+        GetDeviceIdentifier,
     }
 );
 
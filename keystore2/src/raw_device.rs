@@ -29,8 +29,8 @@ use crate::{
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     HardwareAuthToken::HardwareAuthToken, IKeyMintDevice::IKeyMintDevice,
     IKeyMintOperation::IKeyMintOperation, KeyCharacteristics::KeyCharacteristics,
-    KeyCreationResult::KeyCreationResult, KeyParameter::KeyParameter, KeyPurpose::KeyPurpose,
-    SecurityLevel::SecurityLevel,
+    KeyCreationResult::KeyCreationResult, KeyFormat::KeyFormat, KeyParameter::KeyParameter,
+    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
@@ -102,6 +102,27 @@ impl KeyMintDevice {
         self.security_level
     }
 
+    /// Returns the uuid of the KeyMint device.
+    pub fn km_uuid(&self) -> Uuid {
+        self.km_uuid
+    }
+
+    /// Generates a KM key with `params` without storing it.
+    pub fn generate_key(&self, params: &[KeyParameter]) -> Result<KeyCreationResult> {
+        map_km_error(self.km_dev.generateKey(params, None)).context(ks_err!("generateKey failed"))
+    }
+
+    /// Imports `key_material` in `format` as a KM key with `params` without storing it.
+    pub fn import_key(
+        &self,
+        params: &[KeyParameter],
+        format: KeyFormat,
+        key_material: &[u8],
+    ) -> Result<KeyCreationResult> {
+        map_km_error(self.km_dev.importKey(params, format, key_material, None))
+            .context(ks_err!("importKey failed"))
+    }
+
     /// Create a KM key and store in the database.
     pub fn create_and_store_key<F>(
         &self,
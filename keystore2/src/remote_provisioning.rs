@@ -25,29 +25,469 @@ use android_hardware_security_keymint::aidl::android::hardware::security::keymin
     Tag::Tag,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
-    Domain::Domain, KeyDescriptor::KeyDescriptor,
+    Domain::Domain, KeyDescriptor::KeyDescriptor, ResponseCode::ResponseCode,
 };
 use anyhow::{Context, Result};
 use keystore2_crypto::parse_subject_from_certificate;
+use lazy_static::lazy_static;
+use log::Level;
 
-use crate::database::Uuid;
+use crate::database::{KeyIdGuard, KeystoreDB, Uuid};
+use crate::error::{Error, ErrorCode};
+use crate::globals::{ASYNC_TASK, DB};
 use crate::ks_err;
+use crate::log_throttle::log_throttled;
 use crate::metrics_store::log_rkp_error_stats;
 use crate::rkpd_client::get_rkpd_attestation_key;
+use crate::sysprop::{read_prop_bool, read_prop_duration, read_prop_u32};
+use crate::utils::{AID_APP_START, AID_KEYSTORE, AID_USER_OFFSET};
 use android_security_metrics::aidl::android::security::metrics::RkpError::RkpError as MetricsRkpError;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The number of attestations that a remote provisioned attestation key from keystore's own key
+/// pool can sign before it is exhausted. 0 means that the uses of attestation keys are not
+/// limited, which disables the warm rotation.
+const ATTESTATION_KEY_MAX_USES_PROPERTY: &str = "remote_provisioning.attestation_key_max_uses";
+
+/// If fewer uses than this remain on an attestation key, a replacement is provisioned.
+const ATTESTATION_KEY_ROTATION_THRESHOLD_PROPERTY: &str =
+    "remote_provisioning.attestation_key_rotation_threshold";
+
+const DEFAULT_ATTESTATION_KEY_ROTATION_THRESHOLD: u32 = 16;
+
+/// The replacement of an attestation key is scheduled at most once within this interval. If the
+/// replacement fails, e.g., because the key pool is empty, it is retried after the interval.
+const ATTESTATION_KEY_ROTATION_DEBOUNCE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long a reservation of an attestation key from keystore's own key pool is held before it
+/// expires unclaimed.
+const RESERVATION_LIFETIME: Duration = Duration::from_secs(30);
+
+/// The number of remote provisioned attestation keys that an app UID may consume within the
+/// window. 0, the default, disables the limit.
+const RKP_UID_LIMIT_PROPERTY: &str = "keystore.rkp_uid_limit.max_keys";
+
+/// The window of the per UID limit, e.g., `1h`.
+const RKP_UID_LIMIT_WINDOW_PROPERTY: &str = "keystore.rkp_uid_limit.window";
+const DEFAULT_RKP_UID_LIMIT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// The number of remote provisioned attestation keys that each app UID may consume within a
+/// sliding window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RkpUidLimit {
+    /// The number of keys per window. 0 means that the consumption is not limited.
+    pub max_keys: u32,
+    /// The length of the window.
+    pub window: Duration,
+}
+
+impl RkpUidLimit {
+    /// Reads the limit from the `keystore.rkp_uid_limit.max_keys` and
+    /// `keystore.rkp_uid_limit.window` system properties. The properties are read on every call,
+    /// so changes take effect with the next key generation.
+    pub fn from_property() -> Self {
+        Self {
+            max_keys: read_prop_u32(RKP_UID_LIMIT_PROPERTY, 0),
+            window: read_prop_duration(RKP_UID_LIMIT_WINDOW_PROPERTY, DEFAULT_RKP_UID_LIMIT_WINDOW),
+        }
+    }
+}
+
+/// The times at which app UIDs consumed remote provisioned attestation keys, so that one app
+/// cannot drain the key pool or its share of RKPD by attesting at a high rate. Each attestation
+/// with a remote provisioned key counts, because it uses up the key. System UIDs are exempt.
+#[derive(Debug, Default)]
+struct RkpKeyConsumption {
+    consumed: Mutex<HashMap<u32, VecDeque<Instant>>>,
+}
+
+impl RkpKeyConsumption {
+    fn is_exempt(uid: u32) -> bool {
+        uid % AID_USER_OFFSET < AID_APP_START
+    }
+
+    /// Fails with OUT_OF_KEYS_TRANSIENT_ERROR if `uid` consumed `limit.max_keys` keys within the
+    /// window before `now`.
+    fn check(&self, limit: &RkpUidLimit, uid: u32, now: Instant) -> Result<()> {
+        if limit.max_keys == 0 || Self::is_exempt(uid) {
+            return Ok(());
+        }
+        let mut consumed = self.consumed.lock().unwrap();
+        let times = match consumed.get_mut(&uid) {
+            Some(times) => times,
+            None => return Ok(()),
+        };
+        while times.front().map_or(false, |t| now.saturating_duration_since(*t) >= limit.window) {
+            times.pop_front();
+        }
+        if times.len() >= limit.max_keys as usize {
+            return Err(Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR)).context(ks_err!(
+                "UID {} consumed {} remote provisioned keys within {:?}.",
+                uid,
+                times.len(),
+                limit.window
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records that `uid` consumed a key at `now`. Consumptions that left the window are
+    /// forgotten, so that at most `limit.max_keys` are kept per UID.
+    fn record(&self, limit: &RkpUidLimit, uid: u32, now: Instant) {
+        if limit.max_keys == 0 || Self::is_exempt(uid) {
+            return;
+        }
+        let mut consumed = self.consumed.lock().unwrap();
+        consumed.retain(|_, times| {
+            times.retain(|t| now.saturating_duration_since(*t) < limit.window);
+            !times.is_empty()
+        });
+        let times = consumed.entry(uid).or_default();
+        times.push_back(now);
+        while times.len() > limit.max_keys as usize {
+            times.pop_front();
+        }
+    }
+}
+
+/// A reservation of an attestation key from one of keystore's own key pools for the namespace of
+/// a key, see `RemProvState::reserve_rkp_key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RkpKeyReservation(u64);
+
+#[derive(Debug)]
+struct Reservation {
+    domain: Domain,
+    namespace: i64,
+    pool: Option<String>,
+    expires: Instant,
+}
+
+#[derive(Debug, Default)]
+struct ReservationState {
+    next_token: u64,
+    held: HashMap<u64, Reservation>,
+}
+
+impl ReservationState {
+    fn expire(&mut self, now: Instant) {
+        self.held.retain(|_, reservation| reservation.expires > now);
+    }
+
+    /// Returns the token of the reservation held for the namespace in `pool`, if any.
+    fn find(&self, domain: Domain, namespace: i64, pool: Option<&str>) -> Option<u64> {
+        self.held
+            .iter()
+            .find(|(_, r)| {
+                r.domain == domain && r.namespace == namespace && r.pool.as_deref() == pool
+            })
+            .map(|(token, _)| *token)
+    }
+
+    /// Returns the number of reservations held in `pool`.
+    fn count(&self, pool: Option<&str>) -> usize {
+        self.held.values().filter(|r| r.pool.as_deref() == pool).count()
+    }
+}
+
+/// The reservations of attestation keys from keystore's own key pool. Each reservation sets
+/// aside one of the available keys for a namespace, so that a multi-step flow can rely on an
+/// attestation key even if other namespaces attest concurrently. The reservation is claimed by
+/// the next assignment of a key to the namespace, or it expires unclaimed.
+#[derive(Debug, Default)]
+pub struct RkpKeyReservations {
+    state: Mutex<ReservationState>,
+}
+
+impl RkpKeyReservations {
+    /// Reserves a key of `pool` of `km_uuid` for the namespace until `now` plus the reservation
+    /// lifetime. Returns None if all available keys of the pool are reserved. A namespace holds
+    /// at most one reservation per pool; reserving again extends it.
+    fn reserve(
+        &self,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+        now: Instant,
+    ) -> Result<Option<RkpKeyReservation>> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(now);
+        let expires = now + RESERVATION_LIFETIME;
+        if let Some(token) = state.find(domain, namespace, pool) {
+            state.held.get_mut(&token).unwrap().expires = expires;
+            return Ok(Some(RkpKeyReservation(token)));
+        }
+        let available = db.count_available_attestation_keys(km_uuid, pool).context(ks_err!())?;
+        if available as usize <= state.count(pool) {
+            return Ok(None);
+        }
+        state.next_token += 1;
+        let token = state.next_token;
+        let pool = pool.map(str::to_string);
+        state.held.insert(token, Reservation { domain, namespace, pool, expires });
+        Ok(Some(RkpKeyReservation(token)))
+    }
+
+    /// Releases the reservation. Returns false if it was already claimed or expired.
+    fn release(&self, reservation: RkpKeyReservation) -> bool {
+        self.state.lock().unwrap().held.remove(&reservation.0).is_some()
+    }
+
+    /// Returns true if a key of `pool` may be assigned to the namespace, because it holds a
+    /// reservation in the pool or because the pool has more keys available than are reserved.
+    fn may_assign(
+        &self,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+        now: Instant,
+    ) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(now);
+        if state.find(domain, namespace, pool).is_some() {
+            return Ok(true);
+        }
+        let available = db.count_available_attestation_keys(km_uuid, pool).context(ks_err!())?;
+        Ok(available as usize > state.count(pool))
+    }
+
+    /// Assigns a key of `pool` of `km_uuid` to the namespace, claiming the reservation of the
+    /// namespace in the pool if it holds one. Keys reserved for other namespaces are not
+    /// assigned; if only those are left, OUT_OF_KEYS_TRANSIENT_ERROR is returned.
+    fn assign(
+        &self,
+        domain: Domain,
+        namespace: i64,
+        km_uuid: &Uuid,
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+        now: Instant,
+    ) -> Result<()> {
+        // The lock is held during the assignment, so that no other namespace can take the key
+        // between the check and the assignment.
+        let mut state = self.state.lock().unwrap();
+        state.expire(now);
+        match state.find(domain, namespace, pool) {
+            Some(token) => {
+                state.held.remove(&token);
+            }
+            None => {
+                let available =
+                    db.count_available_attestation_keys(km_uuid, pool).context(ks_err!())?;
+                if available as usize <= state.count(pool) {
+                    return Err(Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+                        .context(ks_err!("All available keys are reserved."));
+                }
+            }
+        }
+        db.assign_attestation_key(domain, namespace, km_uuid, pool).context(ks_err!())
+    }
+}
+
+/// Debounces the warm rotation of remote provisioned attestation keys, i.e., the replacement of
+/// keys that are about to be exhausted while they are still usable.
+#[derive(Debug, Default)]
+pub struct AttestationKeyRotation {
+    scheduled: Mutex<HashMap<i64, Instant>>,
+}
+
+impl AttestationKeyRotation {
+    /// Returns true if the replacement of the attestation key `key_id` is due, because fewer
+    /// than `threshold` uses remain, and it was not scheduled within the debounce interval
+    /// before `now`. The caller must schedule the replacement if this returns true.
+    pub fn is_due(&self, key_id: i64, remaining_uses: i64, threshold: i64, now: Instant) -> bool {
+        if remaining_uses >= threshold {
+            return false;
+        }
+        let mut scheduled = self.scheduled.lock().unwrap();
+        scheduled
+            .retain(|_, at| now.duration_since(*at) < ATTESTATION_KEY_ROTATION_DEBOUNCE_INTERVAL);
+        if scheduled.contains_key(&key_id) {
+            return false;
+        }
+        scheduled.insert(key_id, now);
+        true
+    }
+}
+
+/// Copies `e`, so that the outcome of a provisioning cycle can be reported to each caller that
+/// joined it. Errors that carry no code become `ResponseCode::SYSTEM_ERROR`.
+fn copy_error(e: &Error) -> Error {
+    match e {
+        Error::Rc(rc) => Error::Rc(*rc),
+        Error::Km(ec) => Error::Km(*ec),
+        Error::Rp(ec) => Error::Rp(*ec),
+        _ => Error::sys(),
+    }
+}
+
+#[derive(Debug, Default)]
+struct CycleState {
+    /// The instances whose cycle is running.
+    in_flight: HashSet<String>,
+    /// The number of cycles that finished, per KeyMint instance.
+    finished: HashMap<String, u64>,
+    /// The time at which the last cycle of each instance finished.
+    last_finished: HashMap<String, Instant>,
+    /// The root error and the description of the error of the last cycle of each instance, if
+    /// it failed.
+    last_error: HashMap<String, (Error, String)>,
+}
+
+/// Deduplicates forced provisioning cycles, see `RemProvState::force_provision`. At most one
+/// cycle runs per KeyMint instance. Callers that request a cycle while one is running wait for
+/// it and share its outcome instead of starting another one.
+#[derive(Debug, Default)]
+struct ProvisioningCycles {
+    state: Mutex<CycleState>,
+    cond_var: Condvar,
+}
+
+impl ProvisioningCycles {
+    /// Runs `provision` as the cycle of `instance`, or waits for the running cycle of `instance`
+    /// and returns its outcome.
+    fn run<F>(&self, instance: &str, provision: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight.contains(instance) {
+            let joined = state.finished.get(instance).copied().unwrap_or(0);
+            while state.finished.get(instance).copied().unwrap_or(0) == joined {
+                state = self.cond_var.wait(state).unwrap();
+            }
+            return match state.last_error.get(instance) {
+                None => Ok(()),
+                Some((e, description)) => Err(copy_error(e))
+                    .context(ks_err!("Joined provisioning cycle failed: {}", description)),
+            };
+        }
+        state.in_flight.insert(instance.to_string());
+        drop(state);
+
+        let result = provision();
+
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.remove(instance);
+        *state.finished.entry(instance.to_string()).or_default() += 1;
+        state.last_finished.insert(instance.to_string(), Instant::now());
+        match &result {
+            Ok(()) => state.last_error.remove(instance),
+            Err(e) => {
+                let root =
+                    e.root_cause().downcast_ref::<Error>().map_or_else(Error::sys, copy_error);
+                state.last_error.insert(instance.to_string(), (root, format!("{:?}", e)))
+            }
+        };
+        self.cond_var.notify_all();
+        result
+    }
+
+    /// Returns the cycle status of `instance` as of `now`.
+    fn status(&self, instance: &str, now: Instant) -> ProvisioningCycleStatus {
+        let state = self.state.lock().unwrap();
+        ProvisioningCycleStatus {
+            in_flight: state.in_flight.contains(instance),
+            finished: state.finished.get(instance).copied().unwrap_or(0),
+            since_last_finished: state
+                .last_finished
+                .get(instance)
+                .map(|finished| now.saturating_duration_since(*finished)),
+            last_error: state.last_error.get(instance).map(|(e, _)| copy_error(e)),
+        }
+    }
+}
+
+/// The status of the forced provisioning cycles of a KeyMint instance.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProvisioningCycleStatus {
+    /// True if a cycle is running.
+    pub in_flight: bool,
+    /// The number of cycles that finished.
+    pub finished: u64,
+    /// The time since the last cycle finished, if any did.
+    pub since_last_finished: Option<Duration>,
+    /// The root error of the last cycle, if it failed. The description of the error is left out,
+    /// because it may quote responses of RKPD.
+    pub last_error: Option<Error>,
+}
+
+/// The status of one of keystore's own key pools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPoolStatus {
+    /// The name of the pool, where None denotes the default pool.
+    pub pool: Option<String>,
+    /// The number of attestation keys that are available in the pool.
+    pub available: i32,
+    /// The number of available keys that are reserved for a namespace.
+    pub reserved: usize,
+}
+
+/// A redacted snapshot of the state of a `RemProvState`, see `RemProvState::dump`. It contains
+/// counts and flags only, and no key material, certificates, or key blobs.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RemProvStatus {
+    /// The security level of the KeyMint instance.
+    pub security_level: SecurityLevel,
+    /// The name of the KeyMint instance.
+    pub instance: String,
+    /// True if the device only accepts remote provisioned attestation keys.
+    pub rkp_only: bool,
+    /// True if RKPD hands out attestation keys for the instance.
+    pub rkpd_available: bool,
+    /// The status of the queried key pools.
+    pub pools: Vec<KeyPoolStatus>,
+    /// The status of the forced provisioning cycles.
+    pub provisioning: ProvisioningCycleStatus,
+}
+
+lazy_static! {
+    /// The forced provisioning cycles of all KeyMint instances.
+    static ref PROVISIONING_CYCLES: ProvisioningCycles = Default::default();
+}
 
 /// Contains helper functions to check if remote provisioning is enabled on the system and, if so,
 /// to assign and retrieve attestation keys and certificate chains.
 #[derive(Default)]
 pub struct RemProvState {
     security_level: SecurityLevel,
+    instance: String,
     km_uuid: Uuid,
+    rotation: AttestationKeyRotation,
+    reservations: RkpKeyReservations,
+    consumption: RkpKeyConsumption,
 }
 
 impl RemProvState {
-    /// Creates a RemProvState struct.
-    pub fn new(security_level: SecurityLevel, km_uuid: Uuid) -> Self {
-        Self { security_level, km_uuid }
+    /// Creates a RemProvState struct for the KeyMint device `instance` of the given security
+    /// level.
+    pub fn new(security_level: SecurityLevel, instance: &str, km_uuid: Uuid) -> Self {
+        Self {
+            security_level,
+            instance: instance.to_string(),
+            km_uuid,
+            rotation: Default::default(),
+            reservations: Default::default(),
+            consumption: Default::default(),
+        }
+    }
+
+    /// Fails with OUT_OF_KEYS_TRANSIENT_ERROR if the app `caller_uid` consumed as many remote
+    /// provisioned attestation keys of this KeyMint instance as the `RkpUidLimit` permits.
+    pub fn check_key_consumption(&self, caller_uid: u32) -> Result<()> {
+        self.consumption.check(&RkpUidLimit::from_property(), caller_uid, Instant::now())
+    }
+
+    /// Records that `caller_uid` consumed a remote provisioned attestation key of this KeyMint
+    /// instance.
+    pub fn record_key_consumption(&self, caller_uid: u32) {
+        self.consumption.record(&RkpUidLimit::from_property(), caller_uid, Instant::now())
     }
 
     /// Returns the uuid for the KM instance attached to this RemProvState struct.
@@ -55,6 +495,129 @@ impl RemProvState {
         self.km_uuid
     }
 
+    /// Returns the number of remote provisioned attestation keys left in keystore's own key pool
+    /// `pool` for this KeyMint instance, where None denotes the default pool. See
+    /// `KeystoreDB::count_available_attestation_keys`.
+    pub fn get_attestation_pool_size(
+        &self,
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+    ) -> Result<i32> {
+        db.count_available_attestation_keys(&self.km_uuid, pool).context(ks_err!())
+    }
+
+    /// Returns the number of attestations that the remote provisioned attestation keys in
+    /// keystore's own key pool `pool` for this KeyMint instance can still sign: the uses of the
+    /// available keys, see `get_attestation_pool_size`, plus the remaining uses of the assigned
+    /// keys, see `KeystoreDB::sum_remaining_attestation_key_uses`. Returns None if the uses of
+    /// attestation keys are not limited.
+    pub fn get_remaining_attestation_key_uses(
+        &self,
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+    ) -> Result<Option<i64>> {
+        self.get_remaining_attestation_key_uses_with(
+            pool,
+            db,
+            read_prop_u32(ATTESTATION_KEY_MAX_USES_PROPERTY, 0),
+        )
+    }
+
+    fn get_remaining_attestation_key_uses_with(
+        &self,
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+        max_uses: u32,
+    ) -> Result<Option<i64>> {
+        if max_uses == 0 {
+            return Ok(None);
+        }
+        let available = self.get_attestation_pool_size(pool, db).context(ks_err!())?;
+        let assigned = db
+            .sum_remaining_attestation_key_uses(&self.km_uuid, pool, max_uses as i64)
+            .context(ks_err!())?;
+        Ok(Some(available as i64 * max_uses as i64 + assigned))
+    }
+
+    /// Forces a provisioning cycle for this KeyMint instance instead of waiting for the automatic
+    /// trigger, e.g., for testing or to recover from an exhausted key pool. The cycle uses the
+    /// existing mechanism: an attestation key is requested from RKPD on behalf of keystore, upon
+    /// which RKPD provisions keys if it needs any. Returns once the cycle completed, or the error
+    /// with which it failed. Concurrent calls for the same instance share one cycle. The caller
+    /// must have checked that the client is privileged.
+    pub fn force_provision(&self) -> Result<()> {
+        self.force_provision_with(&PROVISIONING_CYCLES, || {
+            get_rkpd_attestation_key(&self.security_level, &self.instance, AID_KEYSTORE).map(|_| ())
+        })
+    }
+
+    fn force_provision_with<F>(&self, cycles: &ProvisioningCycles, provision: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let result = cycles.run(&self.instance, provision);
+        match &result {
+            Ok(()) => log::info!("Forced provisioning of {:?} completed.", self.security_level),
+            Err(e) => {
+                log::warn!("Forced provisioning of {:?} failed: {:?}", self.security_level, e)
+            }
+        }
+        result.context(ks_err!())
+    }
+
+    /// Returns a redacted snapshot of the provisioning state of this KeyMint instance, with the
+    /// status of each of keystore's own key pools in `pools`, where None denotes the default
+    /// pool. RKPD is probed with keystore's own UID.
+    pub fn dump(&self, pools: &[Option<&str>], db: &mut KeystoreDB) -> Result<RemProvStatus> {
+        self.dump_with(
+            pools,
+            db,
+            &PROVISIONING_CYCLES,
+            self.is_rkpd_available(AID_KEYSTORE),
+            Instant::now(),
+        )
+    }
+
+    fn dump_with(
+        &self,
+        pools: &[Option<&str>],
+        db: &mut KeystoreDB,
+        cycles: &ProvisioningCycles,
+        rkpd_available: bool,
+        now: Instant,
+    ) -> Result<RemProvStatus> {
+        let reserved = {
+            let mut state = self.reservations.state.lock().unwrap();
+            state.expire(now);
+            pools.iter().map(|pool| state.count(*pool)).collect::<Vec<_>>()
+        };
+        let pools = pools
+            .iter()
+            .zip(reserved)
+            .map(|(pool, reserved)| {
+                Ok(KeyPoolStatus {
+                    pool: pool.map(str::to_string),
+                    available: self.get_attestation_pool_size(*pool, db).context(ks_err!())?,
+                    reserved,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RemProvStatus {
+            security_level: self.security_level,
+            instance: self.instance.clone(),
+            rkp_only: self.is_rkp_only(),
+            rkpd_available,
+            pools,
+            provisioning: cycles.status(&self.instance, now),
+        })
+    }
+
+    /// Returns true if RKPD hands out attestation keys for this KeyMint instance. RKPD assigns
+    /// an attestation key to `uid` if it had none.
+    pub fn is_rkpd_available(&self, uid: u32) -> bool {
+        get_rkpd_attestation_key(&self.security_level, &self.instance, uid).is_ok()
+    }
+
     fn is_rkp_only(&self) -> bool {
         let default_value = false;
 
@@ -64,8 +627,7 @@ impl RemProvState {
             _ => return default_value,
         };
 
-        rustutils::system_properties::read_bool(property_name, default_value)
-            .unwrap_or(default_value)
+        read_prop_bool(property_name, default_value)
     }
 
     fn is_asymmetric_key(&self, params: &[KeyParameter]) -> bool {
@@ -83,6 +645,205 @@ impl RemProvState {
         })
     }
 
+    /// Returns true if `key` may be attested with a key from keystore's own key pool.
+    fn may_use_key_pool(&self, key: &KeyDescriptor, params: &[KeyParameter]) -> bool {
+        self.is_asymmetric_key(params) && matches!(key.domain, Domain::APP | Domain::SELINUX)
+    }
+
+    /// Returns true if `key` may be attested with a key from RKPD.
+    fn may_use_rkpd(&self, key: &KeyDescriptor, params: &[KeyParameter]) -> bool {
+        self.is_asymmetric_key(params) && key.domain == Domain::APP
+    }
+
+    /// Returns true if `get_remote_provisioned_key_and_certs` would find a key of `pool` for
+    /// `key`. Unlike the former, this neither assigns nor locks an attestation key.
+    pub fn has_remote_provisioned_key(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+    ) -> Result<bool> {
+        if !self.may_use_key_pool(key, params) {
+            return Ok(false);
+        }
+        Ok(db
+            .has_assigned_attestation_key(key.domain, key.nspace, &self.km_uuid, pool)
+            .context(ks_err!())?
+            || self
+                .reservations
+                .may_assign(key.domain, key.nspace, &self.km_uuid, pool, db, Instant::now())
+                .context(ks_err!())?)
+    }
+
+    /// Reserves an attestation key from keystore's own key pool `pool` for `key`'s namespace.
+    /// The reservation is claimed by the next call of `get_remote_provisioned_key_and_certs` for
+    /// the namespace and pool that needs to assign a key, and expires unclaimed after a short
+    /// while. Returns None if `key` cannot be attested with a key from the pool or if all
+    /// available keys of the pool are reserved.
+    pub fn reserve_rkp_key(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+    ) -> Result<Option<RkpKeyReservation>> {
+        if !self.may_use_key_pool(key, params) {
+            return Ok(None);
+        }
+        self.reservations
+            .reserve(key.domain, key.nspace, &self.km_uuid, pool, db, Instant::now())
+            .context(ks_err!())
+    }
+
+    /// Releases a reservation made by `reserve_rkp_key` that is no longer needed. Returns false
+    /// if it was already claimed or expired.
+    pub fn release_rkp_key(&self, reservation: RkpKeyReservation) -> bool {
+        self.reservations.release(reservation)
+    }
+
+    /// Returns true if `get_rkpd_attestation_key_and_certs` would find a key for `key`. RKPD is
+    /// probed with keystore's own UID, so that no attestation key is assigned to the caller. Like
+    /// the former, this fails if RKPD has no key and the device is RKP only.
+    pub fn has_rkpd_key(&self, key: &KeyDescriptor, params: &[KeyParameter]) -> Result<bool> {
+        if !self.may_use_rkpd(key, params) {
+            Ok(false)
+        } else if self.is_rkpd_available(AID_KEYSTORE) {
+            Ok(true)
+        } else if self.is_rkp_only() {
+            Err(Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED))
+                .context(ks_err!("RKPD has no attestation key, but the device is RKP only."))
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Fetches the remote provisioned attestation key assigned to the key's namespace from
+    /// keystore's own key pool `pool`, assigning one first if necessary. A KeyMint instance may
+    /// have several pools, e.g., one per use case; None selects the default pool. The assignment
+    /// claims the reservation of the namespace in the pool, if any. Returns None if the pool has
+    /// no keys for this KeyMint instance that are not reserved for other namespaces.
+    pub fn get_remote_provisioned_key_and_certs(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+        pool: Option<&str>,
+        db: &mut KeystoreDB,
+    ) -> Result<Option<(KeyIdGuard, AttestationKey, Certificate)>> {
+        if !self.may_use_key_pool(key, params) {
+            return Ok(None);
+        }
+        let mut cert_chain = db
+            .retrieve_attestation_key_and_cert_chain(key.domain, key.nspace, &self.km_uuid, pool)
+            .context(ks_err!("Failed to retrieve attestation key."))?;
+        if cert_chain.is_none() {
+            match self.reservations.assign(
+                key.domain,
+                key.nspace,
+                &self.km_uuid,
+                pool,
+                db,
+                Instant::now(),
+            ) {
+                Err(e)
+                    if e.root_cause().downcast_ref::<Error>()
+                        == Some(&Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR)) =>
+                {
+                    log_throttled(
+                        Level::Warn,
+                        &format!(
+                            "Key pool {:?} of {:?} is exhausted.",
+                            pool.unwrap_or("default"),
+                            self.security_level
+                        ),
+                    );
+                    return Ok(None);
+                }
+                r => r.context(ks_err!("Failed to assign attestation key."))?,
+            }
+            cert_chain = db
+                .retrieve_attestation_key_and_cert_chain(
+                    key.domain,
+                    key.nspace,
+                    &self.km_uuid,
+                    pool,
+                )
+                .context(ks_err!("Failed to retrieve newly assigned attestation key."))?;
+        }
+        match cert_chain {
+            None => Err(Error::sys()).context(ks_err!("Assigned attestation key not found.")),
+            Some((key_id_guard, cert_chain)) => {
+                self.record_attestation_key_use(key_id_guard.id(), key, db)
+                    .context(ks_err!("Failed to record attestation key use."))?;
+                Ok(Some((
+                    key_id_guard,
+                    AttestationKey {
+                        keyBlob: cert_chain.private_key.to_vec(),
+                        attestKeyParams: vec![],
+                        issuerSubjectName: parse_subject_from_certificate(&cert_chain.batch_cert)
+                            .context(ks_err!("Failed to parse subject."))?,
+                    },
+                    Certificate { encodedCertificate: cert_chain.cert_chain },
+                )))
+            }
+        }
+    }
+
+    /// Counts a use of the attestation key `key_id` that is assigned to `key`'s namespace, and
+    /// schedules the provisioning of a replacement as low priority job on the async task if the
+    /// key is about to be exhausted.
+    fn record_attestation_key_use(
+        &self,
+        key_id: i64,
+        key: &KeyDescriptor,
+        db: &mut KeystoreDB,
+    ) -> Result<()> {
+        let (domain, namespace, km_uuid) = (key.domain, key.nspace, self.km_uuid);
+        let security_level = self.security_level;
+        self.record_attestation_key_use_with(
+            key_id,
+            db,
+            read_prop_u32(ATTESTATION_KEY_MAX_USES_PROPERTY, 0),
+            read_prop_u32(
+                ATTESTATION_KEY_ROTATION_THRESHOLD_PROPERTY,
+                DEFAULT_ATTESTATION_KEY_ROTATION_THRESHOLD,
+            ),
+            move || {
+                ASYNC_TASK.queue_lo(move |_shelf| {
+                    let result = DB.with(|db| {
+                        db.borrow_mut().replace_attestation_key(key_id, domain, namespace, &km_uuid)
+                    });
+                    if let Err(e) = result {
+                        log::warn!(
+                            "Failed to replace attestation key of {:?}: {:?}",
+                            security_level,
+                            e
+                        );
+                    }
+                })
+            },
+        )
+    }
+
+    fn record_attestation_key_use_with<F: FnOnce()>(
+        &self,
+        key_id: i64,
+        db: &mut KeystoreDB,
+        max_uses: u32,
+        threshold: u32,
+        schedule_replacement: F,
+    ) -> Result<()> {
+        if max_uses == 0 {
+            return Ok(());
+        }
+        let use_count = db.record_attestation_key_use(key_id).context(ks_err!())?;
+        let remaining_uses = (max_uses as i64 - use_count).max(0);
+        if self.rotation.is_due(key_id, remaining_uses, threshold as i64, Instant::now()) {
+            schedule_replacement();
+        }
+        Ok(())
+    }
+
     /// Fetches attestation key and corresponding certificates from RKPD.
     pub fn get_rkpd_attestation_key_and_certs(
         &self,
@@ -90,16 +851,16 @@ impl RemProvState {
         caller_uid: u32,
         params: &[KeyParameter],
     ) -> Result<Option<(AttestationKey, Certificate)>> {
-        if !self.is_asymmetric_key(params) || key.domain != Domain::APP {
+        if !self.may_use_rkpd(key, params) {
             Ok(None)
         } else {
-            match get_rkpd_attestation_key(&self.security_level, caller_uid) {
+            match get_rkpd_attestation_key(&self.security_level, &self.instance, caller_uid) {
                 Err(e) => {
                     if self.is_rkp_only() {
-                        log::error!("Error occurred: {:?}", e);
+                        log_throttled(Level::Error, &format!("Error occurred: {:?}", e));
                         return Err(e);
                     }
-                    log::warn!("Error occurred: {:?}", e);
+                    log_throttled(Level::Warn, &format!("Error occurred: {:?}", e));
                     log_rkp_error_stats(
                         MetricsRkpError::FALL_BACK_DURING_HYBRID,
                         &self.security_level,
@@ -122,3 +883,444 @@ impl RemProvState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::new_test_db;
+    use crate::database::KEYSTORE_UUID;
+    use keystore2_test_utils::TempDir;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::SystemTime;
+
+    /// Adds a signed attestation key to the key pool `pool` of `db`.
+    fn add_attestation_key(db: &mut KeystoreDB, base_byte: u8, pool: Option<&str>) -> Result<()> {
+        let expiration_date = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis()
+            as i64
+            + 24 * 60 * 60 * 1000;
+        let raw_public_key = vec![base_byte, 0x02];
+        db.create_attestation_key_entry(
+            &[base_byte, 0x01],
+            &raw_public_key,
+            &[base_byte, 0x03],
+            &KEYSTORE_UUID,
+            pool,
+        )?;
+        db.store_signed_attestation_certificate_chain(
+            &raw_public_key,
+            &[base_byte, 0x04],
+            &[base_byte, 0x05],
+            expiration_date,
+            &KEYSTORE_UUID,
+        )
+    }
+
+    #[test]
+    fn test_forced_provisioning_is_deduplicated() {
+        const THREADS: usize = 4;
+        let cycles = Arc::new(ProvisioningCycles::default());
+        let provisioned = Arc::new(Mutex::new(0));
+        let barrier = Arc::new(std::sync::Barrier::new(THREADS));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (cycles, provisioned, barrier) =
+                    (cycles.clone(), provisioned.clone(), barrier.clone());
+                thread::spawn(move || {
+                    let rem_prov_state = RemProvState::new(
+                        SecurityLevel::TRUSTED_ENVIRONMENT,
+                        "default",
+                        KEYSTORE_UUID,
+                    );
+                    barrier.wait();
+                    rem_prov_state.force_provision_with(&cycles, || {
+                        *provisioned.lock().unwrap() += 1;
+                        // Keep the cycle in flight while the other threads request one.
+                        thread::sleep(Duration::from_millis(200));
+                        Ok(())
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap().is_ok());
+        }
+        assert_eq!(*provisioned.lock().unwrap(), 1);
+
+        // A later request runs a new cycle, and its failure is reported.
+        let rem_prov_state =
+            RemProvState::new(SecurityLevel::TRUSTED_ENVIRONMENT, "default", KEYSTORE_UUID);
+        let result = rem_prov_state.force_provision_with(&cycles, || {
+            *provisioned.lock().unwrap() += 1;
+            Err(Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR)).context("No network.")
+        });
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+        );
+        assert_eq!(*provisioned.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_joined_provisioning_cycle_shares_failure() {
+        let cycles = Arc::new(ProvisioningCycles::default());
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let leader = {
+            let cycles = cycles.clone();
+            thread::spawn(move || {
+                cycles.run("default", || {
+                    started_tx.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(200));
+                    Err(Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED)).context("Failed.")
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+        let joined = cycles.run("default", || panic!("A second cycle must not run."));
+        for result in [joined, leader.join().unwrap()] {
+            assert_eq!(
+                result.unwrap_err().root_cause().downcast_ref::<Error>(),
+                Some(&Error::Km(ErrorCode::ATTESTATION_KEYS_NOT_PROVISIONED))
+            );
+        }
+    }
+
+    #[test]
+    fn test_dump_reflects_state() -> Result<()> {
+        let mut db = new_test_db()?;
+        add_attestation_key(&mut db, 0x10, None)?;
+        add_attestation_key(&mut db, 0x11, None)?;
+        add_attestation_key(&mut db, 0x12, Some("system"))?;
+        let state = RemProvState::new(SecurityLevel::TRUSTED_ENVIRONMENT, "default", KEYSTORE_UUID);
+        let key = KeyDescriptor { domain: Domain::APP, nspace: 1, ..Default::default() };
+        let params = [KeyParameter {
+            tag: Tag::ALGORITHM,
+            value: KeyParameterValue::Algorithm(Algorithm::EC),
+        }];
+        assert!(state.reserve_rkp_key(&key, &params, None, &mut db)?.is_some());
+
+        let cycles = ProvisioningCycles::default();
+        let now = Instant::now();
+        let status = state.dump_with(&[None, Some("system")], &mut db, &cycles, true, now)?;
+        assert_eq!(status.security_level, SecurityLevel::TRUSTED_ENVIRONMENT);
+        assert_eq!(status.instance, "default");
+        assert!(status.rkpd_available);
+        assert_eq!(
+            status.pools,
+            vec![
+                KeyPoolStatus { pool: None, available: 2, reserved: 1 },
+                KeyPoolStatus { pool: Some("system".to_string()), available: 1, reserved: 0 },
+            ]
+        );
+        assert_eq!(
+            status.provisioning,
+            ProvisioningCycleStatus {
+                in_flight: false,
+                finished: 0,
+                since_last_finished: None,
+                last_error: None
+            }
+        );
+
+        // A failed cycle is reported with its root error only.
+        assert!(cycles
+            .run("default", || Err(Error::Rc(ResponseCode::OUT_OF_KEYS))
+                .context(ks_err!("Response from RKPD")))
+            .is_err());
+        let later = Instant::now() + Duration::from_secs(5);
+        let status = state.dump_with(&[None], &mut db, &cycles, false, later)?;
+        assert!(!status.rkpd_available);
+        assert_eq!(status.provisioning.finished, 1);
+        assert!(status.provisioning.since_last_finished.unwrap() >= Duration::from_secs(5));
+        assert_eq!(status.provisioning.last_error, Some(Error::Rc(ResponseCode::OUT_OF_KEYS)));
+        assert!(!format!("{:?}", status).contains("Response from RKPD"));
+
+        // Expired reservations are no longer counted.
+        let status =
+            state.dump_with(&[None], &mut db, &cycles, false, now + RESERVATION_LIFETIME)?;
+        assert_eq!(status.pools, vec![KeyPoolStatus { pool: None, available: 2, reserved: 0 }]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replacement_is_scheduled_once_before_exhaustion() -> Result<()> {
+        let mut db = new_test_db()?;
+        add_attestation_key(&mut db, 0x10, None)?;
+        add_attestation_key(&mut db, 0x20, None)?;
+        db.assign_attestation_key(Domain::APP, 30, &KEYSTORE_UUID, None)?;
+        let (key_id_guard, _) = db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, 30, &KEYSTORE_UUID, None)?
+            .unwrap();
+        let key_id = key_id_guard.id();
+        drop(key_id_guard);
+
+        let rem_prov_state =
+            RemProvState::new(SecurityLevel::TRUSTED_ENVIRONMENT, "default", KEYSTORE_UUID);
+        let mut scheduled = 0;
+        for use_count in 1..=10 {
+            rem_prov_state
+                .record_attestation_key_use_with(key_id, &mut db, 10, 3, || scheduled += 1)?;
+            // The replacement is scheduled as soon as fewer than 3 uses remain, and only once.
+            assert_eq!(scheduled, if use_count >= 8 { 1 } else { 0 });
+        }
+
+        // Running the scheduled job assigns the other key from the pool.
+        db.replace_attestation_key(key_id, Domain::APP, 30, &KEYSTORE_UUID)?;
+        let (key_id_guard, cert_chain) = db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, 30, &KEYSTORE_UUID, None)?
+            .unwrap();
+        assert_ne!(key_id_guard.id(), key_id);
+        assert_eq!(cert_chain.batch_cert, vec![0x20, 0x04]);
+        drop(key_id_guard);
+
+        // The pool is now empty, so replacing the new key fails and keeps the assignment.
+        let (key_id_guard, _) = db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, 30, &KEYSTORE_UUID, None)?
+            .unwrap();
+        assert_eq!(
+            db.replace_attestation_key(key_id_guard.id(), Domain::APP, 30, &KEYSTORE_UUID)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remaining_attestation_key_uses() -> Result<()> {
+        let mut db = new_test_db()?;
+        add_attestation_key(&mut db, 0x10, None)?;
+        add_attestation_key(&mut db, 0x11, None)?;
+        add_attestation_key(&mut db, 0x12, None)?;
+        add_attestation_key(&mut db, 0x20, Some("system"))?;
+        db.assign_attestation_key(Domain::APP, 30, &KEYSTORE_UUID, None)?;
+        let (key_id_guard, _) = db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, 30, &KEYSTORE_UUID, None)?
+            .unwrap();
+        let key_id = key_id_guard.id();
+        drop(key_id_guard);
+        for _ in 0..3 {
+            db.record_attestation_key_use(key_id)?;
+        }
+
+        let state = RemProvState::new(SecurityLevel::TRUSTED_ENVIRONMENT, "default", KEYSTORE_UUID);
+        // Two available keys with 10 uses each, and 7 uses left on the assigned key.
+        assert_eq!(state.get_remaining_attestation_key_uses_with(None, &mut db, 10)?, Some(27));
+        assert_eq!(
+            state.get_remaining_attestation_key_uses_with(Some("system"), &mut db, 10)?,
+            Some(10)
+        );
+        // Without a use limit, there is nothing to count.
+        assert_eq!(state.get_remaining_attestation_key_uses_with(None, &mut db, 0)?, None);
+
+        // Exhausted keys do not count negatively.
+        for _ in 0..10 {
+            db.record_attestation_key_use(key_id)?;
+        }
+        assert_eq!(state.get_remaining_attestation_key_uses_with(None, &mut db, 10)?, Some(20));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotation_is_debounced() {
+        let rotation = AttestationKeyRotation::default();
+        let now = Instant::now();
+        assert!(!rotation.is_due(1, 5, 5, now));
+        assert!(rotation.is_due(1, 4, 5, now));
+        assert!(!rotation.is_due(1, 3, 5, now));
+        // Other keys are debounced independently.
+        assert!(rotation.is_due(2, 0, 5, now));
+        // After the debounce interval, a failed replacement is scheduled again.
+        let later = now + ATTESTATION_KEY_ROTATION_DEBOUNCE_INTERVAL;
+        assert!(rotation.is_due(1, 2, 5, later));
+        // Without a limit on uses, no replacement is ever scheduled.
+        let rem_prov_state = RemProvState::default();
+        let mut db = new_test_db().unwrap();
+        rem_prov_state.record_attestation_key_use_with(1, &mut db, 0, 5, || panic!()).unwrap();
+    }
+
+    #[test]
+    fn test_only_one_caller_claims_single_key_pool() -> Result<()> {
+        let temp_dir = TempDir::new("test_only_one_caller_claims_single_key_pool_")?;
+        let mut db = KeystoreDB::new(temp_dir.path(), None)?;
+        add_attestation_key(&mut db, 0x10, None)?;
+
+        // Concurrent callers reserve and claim a key for their namespaces.
+        let reservations = Arc::new(RkpKeyReservations::default());
+        let now = Instant::now();
+        let handles: Vec<_> = (0..8)
+            .map(|namespace| {
+                let reservations = reservations.clone();
+                let path = temp_dir.path().to_owned();
+                thread::spawn(move || -> Result<Option<i64>> {
+                    let mut db = KeystoreDB::new(&path, None)?;
+                    match reservations.reserve(
+                        Domain::APP,
+                        namespace,
+                        &KEYSTORE_UUID,
+                        None,
+                        &mut db,
+                        now,
+                    )? {
+                        Some(_) => {
+                            reservations.assign(
+                                Domain::APP,
+                                namespace,
+                                &KEYSTORE_UUID,
+                                None,
+                                &mut db,
+                                now,
+                            )?;
+                            Ok(Some(namespace))
+                        }
+                        None => Ok(None),
+                    }
+                })
+            })
+            .collect();
+        let claimed: Vec<i64> =
+            handles.into_iter().filter_map(|handle| handle.join().unwrap().unwrap()).collect();
+        assert_eq!(claimed.len(), 1);
+        assert!(db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, claimed[0], &KEYSTORE_UUID, None)?
+            .is_some());
+
+        // The pool is exhausted for everybody else.
+        let other = (claimed[0] + 1) % 8;
+        assert_eq!(
+            reservations.reserve(Domain::APP, other, &KEYSTORE_UUID, None, &mut db, now)?,
+            None
+        );
+        assert_eq!(
+            reservations
+                .assign(Domain::APP, other, &KEYSTORE_UUID, None, &mut db, now)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unclaimed_reservations_expire() -> Result<()> {
+        let mut db = new_test_db()?;
+        add_attestation_key(&mut db, 0x10, None)?;
+        let reservations = RkpKeyReservations::default();
+        let now = Instant::now();
+
+        let reservation =
+            reservations.reserve(Domain::APP, 1, &KEYSTORE_UUID, None, &mut db, now)?.unwrap();
+        // Reserving again yields the same reservation.
+        assert_eq!(
+            reservations.reserve(Domain::APP, 1, &KEYSTORE_UUID, None, &mut db, now)?,
+            Some(reservation)
+        );
+        // The only key is reserved, so it can't be assigned to another namespace.
+        assert_eq!(reservations.reserve(Domain::APP, 2, &KEYSTORE_UUID, None, &mut db, now)?, None);
+        assert!(!reservations.may_assign(Domain::APP, 2, &KEYSTORE_UUID, None, &mut db, now)?);
+        assert!(reservations.may_assign(Domain::APP, 1, &KEYSTORE_UUID, None, &mut db, now)?);
+
+        // Once the reservation expired, the key is available to other namespaces again.
+        let later = now + RESERVATION_LIFETIME;
+        assert!(reservations.may_assign(Domain::APP, 2, &KEYSTORE_UUID, None, &mut db, later)?);
+        assert!(!reservations.release(reservation));
+
+        // Released reservations free the key, too.
+        let reservation =
+            reservations.reserve(Domain::APP, 2, &KEYSTORE_UUID, None, &mut db, later)?.unwrap();
+        assert!(!reservations.may_assign(Domain::APP, 1, &KEYSTORE_UUID, None, &mut db, later)?);
+        assert!(reservations.release(reservation));
+        assert!(reservations.may_assign(Domain::APP, 1, &KEYSTORE_UUID, None, &mut db, later)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_are_drawn_from_selected_pool() -> Result<()> {
+        let mut db = new_test_db()?;
+        add_attestation_key(&mut db, 0x10, None)?;
+        add_attestation_key(&mut db, 0x11, None)?;
+        add_attestation_key(&mut db, 0x20, Some("vpn"))?;
+        add_attestation_key(&mut db, 0x21, Some("vpn"))?;
+        let reservations = RkpKeyReservations::default();
+        let now = Instant::now();
+        let batch_cert = |db: &mut KeystoreDB, pool| -> Result<Option<Vec<u8>>> {
+            Ok(db
+                .retrieve_attestation_key_and_cert_chain(Domain::APP, 1, &KEYSTORE_UUID, pool)?
+                .map(|(_, cert_chain)| cert_chain.batch_cert))
+        };
+
+        reservations.assign(Domain::APP, 1, &KEYSTORE_UUID, Some("vpn"), &mut db, now)?;
+        assert_eq!(batch_cert(&mut db, Some("vpn"))?, Some(vec![0x20, 0x04]));
+        assert_eq!(batch_cert(&mut db, None)?, None);
+        assert!(!db.has_assigned_attestation_key(Domain::APP, 1, &KEYSTORE_UUID, None)?);
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, None)?, 2);
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, Some("vpn"))?, 1);
+
+        // Replacements are drawn from the pool of the replaced key.
+        let (key_id_guard, _) = db
+            .retrieve_attestation_key_and_cert_chain(Domain::APP, 1, &KEYSTORE_UUID, Some("vpn"))?
+            .unwrap();
+        let key_id = key_id_guard.id();
+        drop(key_id_guard);
+        db.replace_attestation_key(key_id, Domain::APP, 1, &KEYSTORE_UUID)?;
+        assert_eq!(batch_cert(&mut db, Some("vpn"))?, Some(vec![0x21, 0x04]));
+
+        // The namespace may hold a key of each pool, and the default pool is unaffected.
+        reservations.assign(Domain::APP, 1, &KEYSTORE_UUID, None, &mut db, now)?;
+        assert!(matches!(batch_cert(&mut db, None)?.as_deref(), Some([0x10 | 0x11, 0x04])));
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, None)?, 1);
+        assert_eq!(db.count_available_attestation_keys(&KEYSTORE_UUID, Some("vpn"))?, 0);
+        assert_eq!(
+            reservations
+                .assign(Domain::APP, 2, &KEYSTORE_UUID, Some("vpn"), &mut db, now)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_rkp_key_consumption_limit() {
+        const APP_UID: u32 = 10 * AID_USER_OFFSET + 10123;
+        const OTHER_APP_UID: u32 = 10 * AID_USER_OFFSET + 10124;
+        const SYSTEM_UID: u32 = 10 * AID_USER_OFFSET + 1000;
+        let limit = RkpUidLimit { max_keys: 2, window: Duration::from_secs(60) };
+        let consumption = RkpKeyConsumption::default();
+        let start = Instant::now();
+        let is_throttled = |result: Result<()>| {
+            result.unwrap_err().root_cause().downcast_ref::<Error>()
+                == Some(&Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+        };
+
+        // The app exhausts its allotment.
+        for second in 0..2 {
+            let now = start + Duration::from_secs(second);
+            assert!(consumption.check(&limit, APP_UID, now).is_ok());
+            consumption.record(&limit, APP_UID, now);
+        }
+        let now = start + Duration::from_secs(30);
+        assert!(is_throttled(consumption.check(&limit, APP_UID, now)));
+
+        // Other apps and system components proceed.
+        assert!(consumption.check(&limit, OTHER_APP_UID, now).is_ok());
+        consumption.record(&limit, OTHER_APP_UID, now);
+        for _ in 0..3 {
+            assert!(consumption.check(&limit, SYSTEM_UID, now).is_ok());
+            consumption.record(&limit, SYSTEM_UID, now);
+        }
+
+        // Once the first consumption leaves the window, the app may consume one more key.
+        let now = start + Duration::from_secs(60);
+        assert!(consumption.check(&limit, APP_UID, now).is_ok());
+        consumption.record(&limit, APP_UID, now);
+        assert!(is_throttled(consumption.check(&limit, APP_UID, now)));
+
+        // A limit of 0 disables the check.
+        let unlimited = RkpUidLimit { max_keys: 0, window: Duration::from_secs(60) };
+        assert!(consumption.check(&unlimited, APP_UID, now).is_ok());
+    }
+}
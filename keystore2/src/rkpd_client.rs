@@ -17,6 +17,7 @@
 use crate::error::{map_binder_status_code, Error, ResponseCode};
 use crate::globals::get_remotely_provisioned_component_name;
 use crate::ks_err;
+use crate::sysprop::read_prop_duration;
 use crate::utils::watchdog as wd;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
 use android_security_rkp_aidl::aidl::android::security::rkp::{
@@ -30,6 +31,7 @@ use android_security_rkp_aidl::aidl::android::security::rkp::{
 };
 use android_security_rkp_aidl::binder::{BinderFeatures, Interface, Strong};
 use anyhow::{Context, Result};
+use lazy_static::lazy_static;
 use std::sync::Mutex;
 use std::time::Duration;
 use tokio::sync::oneshot;
@@ -37,8 +39,22 @@ use tokio::time::timeout;
 
 // Normally, we block indefinitely when making calls outside of keystore and rely on watchdog to
 // report deadlocks. However, RKPD is mainline updatable. Also, calls to RKPD may wait on network
-// for certificates. So, we err on the side of caution and timeout instead.
-static RKPD_TIMEOUT: Duration = Duration::from_secs(10);
+// for certificates. So, we err on the side of caution and timeout instead. The timeout can be
+// configured with the system property `keystore.rkpd_timeout`. A timeout of 0 is ignored, because
+// it would fail every request.
+const RKPD_TIMEOUT_PROPERTY: &str = "keystore.rkpd_timeout";
+const DEFAULT_RKPD_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref RKPD_TIMEOUT: Duration = {
+        let rkpd_timeout = read_prop_duration(RKPD_TIMEOUT_PROPERTY, DEFAULT_RKPD_TIMEOUT);
+        if rkpd_timeout.is_zero() {
+            DEFAULT_RKPD_TIMEOUT
+        } else {
+            rkpd_timeout
+        }
+    };
+}
 
 fn tokio_rt() -> tokio::runtime::Runtime {
     tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()
@@ -111,12 +127,14 @@ impl IGetRegistrationCallback for GetRegistrationCallback {
 /// Make a new connection to a IRegistration service.
 async fn get_rkpd_registration(
     security_level: &SecurityLevel,
+    instance: &str,
+    rkpd_timeout: Duration,
 ) -> Result<binder::Strong<dyn IRegistration>> {
     let remote_provisioning: Strong<dyn IRemoteProvisioning> =
         map_binder_status_code(binder::get_interface("remote_provisioning"))
             .context(ks_err!("Trying to connect to IRemoteProvisioning service."))?;
 
-    let rpc_name = get_remotely_provisioned_component_name(security_level)
+    let rpc_name = get_remotely_provisioned_component_name(security_level, instance)
         .context(ks_err!("Trying to get IRPC name."))?;
 
     let (tx, rx) = oneshot::channel();
@@ -126,10 +144,18 @@ async fn get_rkpd_registration(
         .getRegistration(&rpc_name, &cb)
         .context(ks_err!("Trying to get registration."))?;
 
-    match timeout(RKPD_TIMEOUT, rx).await {
-        Err(e) => {
-            Err(Error::Rc(ResponseCode::SYSTEM_ERROR)).context(ks_err!("Waiting for RKPD: {:?}", e))
-        }
+    wait_for_registration(rx, rkpd_timeout).await
+}
+
+/// Waits for the registration requested from RKPD. Timing out is reported as transient error,
+/// so that the caller may retry later.
+async fn wait_for_registration(
+    rx: oneshot::Receiver<Result<binder::Strong<dyn IRegistration>>>,
+    rkpd_timeout: Duration,
+) -> Result<binder::Strong<dyn IRegistration>> {
+    match timeout(rkpd_timeout, rx).await {
+        Err(e) => Err(Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR))
+            .context(ks_err!("Waiting for RKPD registration timed out: {:?}", e)),
         Ok(v) => v.unwrap(),
     }
 }
@@ -196,6 +222,7 @@ impl IGetKeyCallback for GetKeyCallback {
 async fn get_rkpd_attestation_key_from_registration_async(
     registration: &Strong<dyn IRegistration>,
     caller_uid: u32,
+    rkpd_timeout: Duration,
 ) -> Result<RemotelyProvisionedKey> {
     let (tx, rx) = oneshot::channel();
     let cb = GetKeyCallback::new_native_binder(tx);
@@ -204,7 +231,7 @@ async fn get_rkpd_attestation_key_from_registration_async(
         .getKey(caller_uid.try_into().unwrap(), &cb)
         .context(ks_err!("Trying to get key."))?;
 
-    match timeout(RKPD_TIMEOUT, rx).await {
+    match timeout(rkpd_timeout, rx).await {
         Err(e) => {
             // Make a best effort attempt to cancel the timed out request.
             if let Err(e) = registration.cancelGetKey(&cb) {
@@ -219,12 +246,14 @@ async fn get_rkpd_attestation_key_from_registration_async(
 
 async fn get_rkpd_attestation_key_async(
     security_level: &SecurityLevel,
+    instance: &str,
     caller_uid: u32,
+    rkpd_timeout: Duration,
 ) -> Result<RemotelyProvisionedKey> {
-    let registration = get_rkpd_registration(security_level)
+    let registration = get_rkpd_registration(security_level, instance, rkpd_timeout)
         .await
         .context(ks_err!("Trying to get to IRegistration service."))?;
-    get_rkpd_attestation_key_from_registration_async(&registration, caller_uid).await
+    get_rkpd_attestation_key_from_registration_async(&registration, caller_uid, rkpd_timeout).await
 }
 
 struct StoreUpgradedKeyCallback {
@@ -264,6 +293,7 @@ async fn store_rkpd_attestation_key_with_registration_async(
     registration: &Strong<dyn IRegistration>,
     key_blob: &[u8],
     upgraded_blob: &[u8],
+    rkpd_timeout: Duration,
 ) -> Result<()> {
     let (tx, rx) = oneshot::channel();
     let cb = StoreUpgradedKeyCallback::new_native_binder(tx);
@@ -272,7 +302,7 @@ async fn store_rkpd_attestation_key_with_registration_async(
         .storeUpgradedKeyAsync(key_blob, upgraded_blob, &cb)
         .context(ks_err!("Failed to store upgraded blob with RKPD."))?;
 
-    match timeout(RKPD_TIMEOUT, rx).await {
+    match timeout(rkpd_timeout, rx).await {
         Err(e) => Err(Error::Rc(ResponseCode::SYSTEM_ERROR))
             .context(ks_err!("Waiting for RKPD to complete storing key: {:?}", e)),
         Ok(v) => v.unwrap(),
@@ -281,32 +311,53 @@ async fn store_rkpd_attestation_key_with_registration_async(
 
 async fn store_rkpd_attestation_key_async(
     security_level: &SecurityLevel,
+    instance: &str,
     key_blob: &[u8],
     upgraded_blob: &[u8],
+    rkpd_timeout: Duration,
 ) -> Result<()> {
-    let registration = get_rkpd_registration(security_level)
+    let registration = get_rkpd_registration(security_level, instance, rkpd_timeout)
         .await
         .context(ks_err!("Trying to get to IRegistration service."))?;
-    store_rkpd_attestation_key_with_registration_async(&registration, key_blob, upgraded_blob).await
+    store_rkpd_attestation_key_with_registration_async(
+        &registration,
+        key_blob,
+        upgraded_blob,
+        rkpd_timeout,
+    )
+    .await
 }
 
-/// Get attestation key from RKPD.
+/// Get attestation key from RKPD for the KeyMint device `instance` of the given security level.
 pub fn get_rkpd_attestation_key(
     security_level: &SecurityLevel,
+    instance: &str,
     caller_uid: u32,
 ) -> Result<RemotelyProvisionedKey> {
     let _wp = wd::watch_millis("Calling get_rkpd_attestation_key()", 500);
-    tokio_rt().block_on(get_rkpd_attestation_key_async(security_level, caller_uid))
+    tokio_rt().block_on(get_rkpd_attestation_key_async(
+        security_level,
+        instance,
+        caller_uid,
+        *RKPD_TIMEOUT,
+    ))
 }
 
-/// Store attestation key in RKPD.
+/// Store attestation key in RKPD for the KeyMint device `instance` of the given security level.
 pub fn store_rkpd_attestation_key(
     security_level: &SecurityLevel,
+    instance: &str,
     key_blob: &[u8],
     upgraded_blob: &[u8],
 ) -> Result<()> {
     let _wp = wd::watch_millis("Calling store_rkpd_attestation_key()", 500);
-    tokio_rt().block_on(store_rkpd_attestation_key_async(security_level, key_blob, upgraded_blob))
+    tokio_rt().block_on(store_rkpd_attestation_key_async(
+        security_level,
+        instance,
+        key_blob,
+        upgraded_blob,
+        *RKPD_TIMEOUT,
+    ))
 }
 
 #[cfg(test)]
@@ -325,6 +376,9 @@ mod tests {
     use std::sync::atomic::{AtomicU32, Ordering};
     use std::sync::{Arc, Mutex};
 
+    /// A short timeout, so that the timeout tests don't have to wait for the default timeout.
+    const TEST_RKPD_TIMEOUT: Duration = Duration::from_millis(100);
+
     struct MockRegistrationValues {
         key: RemotelyProvisionedKey,
         latency: Option<Duration>,
@@ -545,7 +599,11 @@ mod tests {
         let registration = get_mock_registration(&mock_key, /*latency=*/ None).unwrap();
 
         let key = tokio_rt()
-            .block_on(get_rkpd_attestation_key_from_registration_async(&registration, 0))
+            .block_on(get_rkpd_attestation_key_from_registration_async(
+                &registration,
+                0,
+                DEFAULT_RKPD_TIMEOUT,
+            ))
             .unwrap();
         assert_eq!(key, mock_key);
     }
@@ -554,11 +612,27 @@ mod tests {
     fn test_get_mock_key_timeout() {
         let mock_key =
             RemotelyProvisionedKey { keyBlob: vec![1, 2, 3], encodedCertChain: vec![4, 5, 6] };
-        let latency = RKPD_TIMEOUT + Duration::from_secs(1);
+        let latency = TEST_RKPD_TIMEOUT + Duration::from_secs(1);
         let registration = get_mock_registration(&mock_key, Some(latency)).unwrap();
 
-        let result =
-            tokio_rt().block_on(get_rkpd_attestation_key_from_registration_async(&registration, 0));
+        let result = tokio_rt().block_on(get_rkpd_attestation_key_from_registration_async(
+            &registration,
+            0,
+            TEST_RKPD_TIMEOUT,
+        ));
+        assert_eq!(
+            result.unwrap_err().downcast::<Error>().unwrap(),
+            Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR)
+        );
+    }
+
+    #[test]
+    fn test_get_registration_timeout() {
+        let (tx, rx) = oneshot::channel();
+        // RKPD never answers the registration request.
+        let _cb = GetRegistrationCallback::new_native_binder(tx);
+
+        let result = tokio_rt().block_on(wait_for_registration(rx, TEST_RKPD_TIMEOUT));
         assert_eq!(
             result.unwrap_err().downcast::<Error>().unwrap(),
             Error::Rc(ResponseCode::OUT_OF_KEYS_TRANSIENT_ERROR)
@@ -571,7 +645,12 @@ mod tests {
             RemotelyProvisionedKey { keyBlob: vec![1, 2, 3], encodedCertChain: vec![4, 5, 6] };
         let registration = get_mock_registration(&mock_key, /*latency=*/ None).unwrap();
         tokio_rt()
-            .block_on(store_rkpd_attestation_key_with_registration_async(&registration, &[], &[]))
+            .block_on(store_rkpd_attestation_key_with_registration_async(
+                &registration,
+                &[],
+                &[],
+                DEFAULT_RKPD_TIMEOUT,
+            ))
             .unwrap();
     }
 
@@ -579,13 +658,14 @@ mod tests {
     fn test_store_mock_key_timeout() {
         let mock_key =
             RemotelyProvisionedKey { keyBlob: vec![1, 2, 3], encodedCertChain: vec![4, 5, 6] };
-        let latency = RKPD_TIMEOUT + Duration::from_secs(1);
+        let latency = TEST_RKPD_TIMEOUT + Duration::from_secs(1);
         let registration = get_mock_registration(&mock_key, Some(latency)).unwrap();
 
         let result = tokio_rt().block_on(store_rkpd_attestation_key_with_registration_async(
             &registration,
             &[],
             &[],
+            TEST_RKPD_TIMEOUT,
         ));
         assert_eq!(
             result.unwrap_err().downcast::<Error>().unwrap(),
@@ -597,7 +677,8 @@ mod tests {
     fn test_get_rkpd_attestation_key() {
         binder::ProcessState::start_thread_pool();
         let key_id = get_next_key_id();
-        let key = get_rkpd_attestation_key(&SecurityLevel::TRUSTED_ENVIRONMENT, key_id).unwrap();
+        let key = get_rkpd_attestation_key(&SecurityLevel::TRUSTED_ENVIRONMENT, "default", key_id)
+            .unwrap();
         assert!(!key.keyBlob.is_empty());
         assert!(!key.encodedCertChain.is_empty());
     }
@@ -609,8 +690,8 @@ mod tests {
         let key_id = get_next_key_id();
 
         // Multiple calls should return the same key.
-        let first_key = get_rkpd_attestation_key(&sec_level, key_id).unwrap();
-        let second_key = get_rkpd_attestation_key(&sec_level, key_id).unwrap();
+        let first_key = get_rkpd_attestation_key(&sec_level, "default", key_id).unwrap();
+        let second_key = get_rkpd_attestation_key(&sec_level, "default", key_id).unwrap();
 
         assert_eq!(first_key.keyBlob, second_key.keyBlob);
         assert_eq!(first_key.encodedCertChain, second_key.encodedCertChain);
@@ -624,8 +705,8 @@ mod tests {
         let second_key_id = get_next_key_id();
 
         // Different callers should be getting different keys.
-        let first_key = get_rkpd_attestation_key(&sec_level, first_key_id).unwrap();
-        let second_key = get_rkpd_attestation_key(&sec_level, second_key_id).unwrap();
+        let first_key = get_rkpd_attestation_key(&sec_level, "default", first_key_id).unwrap();
+        let second_key = get_rkpd_attestation_key(&sec_level, "default", second_key_id).unwrap();
 
         assert_ne!(first_key.keyBlob, second_key.keyBlob);
         assert_ne!(first_key.encodedCertChain, second_key.encodedCertChain);
@@ -641,16 +722,18 @@ mod tests {
         binder::ProcessState::start_thread_pool();
         let sec_level = SecurityLevel::TRUSTED_ENVIRONMENT;
         let key_id = get_next_key_id();
-        let key = get_rkpd_attestation_key(&SecurityLevel::TRUSTED_ENVIRONMENT, key_id).unwrap();
+        let key = get_rkpd_attestation_key(&SecurityLevel::TRUSTED_ENVIRONMENT, "default", key_id)
+            .unwrap();
         let new_blob: [u8; 8] = rand::random();
 
-        assert!(store_rkpd_attestation_key(&sec_level, &key.keyBlob, &new_blob).is_ok());
+        assert!(store_rkpd_attestation_key(&sec_level, "default", &key.keyBlob, &new_blob).is_ok());
 
         let new_key =
-            get_rkpd_attestation_key(&SecurityLevel::TRUSTED_ENVIRONMENT, key_id).unwrap();
+            get_rkpd_attestation_key(&SecurityLevel::TRUSTED_ENVIRONMENT, "default", key_id)
+                .unwrap();
 
         // Restore original key so that we don't leave RKPD with invalid blobs.
-        assert!(store_rkpd_attestation_key(&sec_level, &new_blob, &key.keyBlob).is_ok());
+        assert!(store_rkpd_attestation_key(&sec_level, "default", &new_blob, &key.keyBlob).is_ok());
         assert_eq!(new_key.keyBlob, new_blob);
     }
 
@@ -670,7 +753,7 @@ mod tests {
         let key_id = get_next_key_id();
         let mut key_upgraded = false;
 
-        let key = get_rkpd_attestation_key(&security_level, key_id).unwrap();
+        let key = get_rkpd_attestation_key(&security_level, "default", key_id).unwrap();
         assert!(!key.keyBlob.is_empty());
         assert!(!key.encodedCertChain.is_empty());
 
@@ -700,7 +783,8 @@ mod tests {
             |new_blob| {
                 // This handler is only executed if a key upgrade was performed.
                 key_upgraded = true;
-                store_rkpd_attestation_key(&security_level, &key.keyBlob, new_blob).unwrap();
+                store_rkpd_attestation_key(&security_level, "default", &key.keyBlob, new_blob)
+                    .unwrap();
                 Ok(())
             },
         )
@@ -724,8 +808,12 @@ mod tests {
         for _ in 0..NTHREADS {
             threads.push(std::thread::spawn(move || {
                 for _ in 0..NCALLS {
-                    let key = get_rkpd_attestation_key(&SecurityLevel::TRUSTED_ENVIRONMENT, key_id)
-                        .unwrap();
+                    let key = get_rkpd_attestation_key(
+                        &SecurityLevel::TRUSTED_ENVIRONMENT,
+                        "default",
+                        key_id,
+                    )
+                    .unwrap();
                     assert!(!key.keyBlob.is_empty());
                     assert!(!key.encodedCertChain.is_empty());
                 }
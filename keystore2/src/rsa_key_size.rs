@@ -0,0 +1,153 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module enforces a configurable minimum RSA modulus size.
+//!
+//! To retire weak keys, the generation and import of RSA keys with a modulus smaller than the
+//! configured minimum fail with `ErrorCode::UNSUPPORTED_KEY_SIZE`. Existing smaller keys remain
+//! usable, unless the strict mode also blocks operations with them. No minimum is enforced by
+//! default.
+
+use crate::error::{Error, ErrorCode};
+use crate::key_parameter::KeyParameter as KsKeyParam;
+use crate::ks_err;
+use crate::sysprop::{read_prop_bool, read_prop_u32};
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    Algorithm::Algorithm, KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue,
+    Tag::Tag,
+};
+use anyhow::{Context, Result};
+
+/// The minimum RSA modulus size in bits. 0, the default, enforces no minimum.
+const MIN_KEY_SIZE_PROPERTY: &str = "keystore.rsa_min_key_size";
+
+/// If true, operations with existing RSA keys below the minimum fail as well.
+const STRICT_PROPERTY: &str = "keystore.rsa_min_key_size_strict";
+
+/// Decides whether the size of an RSA key is acceptable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RsaKeySizePolicy {
+    min_key_size: u32,
+    strict: bool,
+}
+
+impl RsaKeySizePolicy {
+    /// Reads the policy from the `keystore.rsa_min_key_size` and
+    /// `keystore.rsa_min_key_size_strict` system properties. The properties are read on every
+    /// call, so changes take effect with the next request.
+    pub fn from_property() -> Self {
+        Self {
+            min_key_size: read_prop_u32(MIN_KEY_SIZE_PROPERTY, 0),
+            strict: read_prop_bool(STRICT_PROPERTY, false),
+        }
+    }
+
+    /// Checks the parameters of a key generation or import, or the characteristics of an
+    /// imported key. Fails with `ErrorCode::UNSUPPORTED_KEY_SIZE` if they describe an RSA key
+    /// that is smaller than the minimum. Parameters without a key size pass, because the size
+    /// of an imported key is only known once KeyMint has parsed the key material.
+    pub fn check(&self, params: &[KeyParameter]) -> Result<()> {
+        if self.min_key_size == 0 {
+            return Ok(());
+        }
+        let is_rsa = params.iter().any(|kp| {
+            matches!(
+                (kp.tag, &kp.value),
+                (Tag::ALGORITHM, KeyParameterValue::Algorithm(Algorithm::RSA))
+            )
+        });
+        if !is_rsa {
+            return Ok(());
+        }
+        let key_size = params.iter().find_map(|kp| match (kp.tag, &kp.value) {
+            (Tag::KEY_SIZE, KeyParameterValue::Integer(size)) => Some(*size),
+            _ => None,
+        });
+        match key_size {
+            Some(size) if size < 0 || (size as u32) < self.min_key_size => {
+                Err(Error::Km(ErrorCode::UNSUPPORTED_KEY_SIZE)).context(ks_err!(
+                    "RSA key size {} is below the minimum of {}.",
+                    size,
+                    self.min_key_size
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the stored parameters of a key before an operation. Keys below the minimum are
+    /// only rejected in strict mode.
+    pub fn check_key_use(&self, key_params: &[KsKeyParam]) -> Result<()> {
+        if !self.strict || self.min_key_size == 0 {
+            return Ok(());
+        }
+        let params: Vec<KeyParameter> =
+            key_params.iter().map(|kp| kp.key_parameter_value().clone().into()).collect();
+        self.check(&params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::SecurityLevel::SecurityLevel;
+
+    const MIN_2048: RsaKeySizePolicy = RsaKeySizePolicy { min_key_size: 2048, strict: false };
+
+    fn key_params(algorithm: Algorithm, key_size: i32) -> Vec<KeyParameter> {
+        vec![
+            KeyParameter { tag: Tag::ALGORITHM, value: KeyParameterValue::Algorithm(algorithm) },
+            KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Integer(key_size) },
+        ]
+    }
+
+    fn is_unsupported_key_size(result: Result<()>) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>()
+            == Some(&Error::Km(ErrorCode::UNSUPPORTED_KEY_SIZE))
+    }
+
+    #[test]
+    fn test_rsa_key_size_at_below_and_above_minimum() {
+        assert!(MIN_2048.check(&key_params(Algorithm::RSA, 2048)).is_ok());
+        assert!(MIN_2048.check(&key_params(Algorithm::RSA, 4096)).is_ok());
+        assert!(is_unsupported_key_size(MIN_2048.check(&key_params(Algorithm::RSA, 1024))));
+        assert!(is_unsupported_key_size(MIN_2048.check(&key_params(Algorithm::RSA, -1))));
+    }
+
+    #[test]
+    fn test_other_requests_pass() {
+        // Other algorithms are not affected by the RSA minimum.
+        assert!(MIN_2048.check(&key_params(Algorithm::AES, 128)).is_ok());
+        // The size of imported keys may only be known after the import.
+        assert!(MIN_2048.check(&key_params(Algorithm::RSA, 2048)[..1]).is_ok());
+        // Without a minimum, everything passes.
+        let no_minimum = RsaKeySizePolicy { min_key_size: 0, strict: true };
+        assert!(no_minimum.check(&key_params(Algorithm::RSA, 512)).is_ok());
+    }
+
+    #[test]
+    fn test_use_of_existing_small_keys() {
+        let stored = [
+            KsKeyParam::new(
+                KsKeyParamValue::Algorithm(Algorithm::RSA),
+                SecurityLevel::TRUSTED_ENVIRONMENT,
+            ),
+            KsKeyParam::new(KsKeyParamValue::KeySize(1024), SecurityLevel::TRUSTED_ENVIRONMENT),
+        ];
+        assert!(MIN_2048.check_key_use(&stored).is_ok());
+        let strict = RsaKeySizePolicy { strict: true, ..MIN_2048 };
+        assert!(is_unsupported_key_size(strict.check_key_use(&stored)));
+    }
+}
@@ -14,24 +14,61 @@
 
 //! This crate implements the IKeystoreSecurityLevel interface.
 
-use crate::attestation_key_utils::{get_attest_key_info, AttestationKeyInfo};
+use crate::attestation_ids::check_attestation_ids_available;
+use crate::attestation_key_utils::{
+    check_provisioning_paths, explain_attest_key_selection, get_attest_key_info,
+    AttestKeyInfoOutcome, AttestationKeyInfo, AttestationKeyPolicy,
+};
+use crate::attestation_self_test::{
+    run_self_test, self_test_enabled, AttestationPipeline, SelfTestOutcome,
+};
+use crate::attestation_templates::ATTESTATION_TEMPLATES;
+use crate::attestation_verification::{expected_attestation_challenge, verify_attestation};
 use crate::audit_log::{
     log_key_deleted, log_key_generated, log_key_imported, log_key_integrity_violation,
 };
-use crate::database::{BlobInfo, CertificateInfo, KeyIdGuard};
-use crate::error::{self, map_km_error, map_or_log_err, Error, ErrorCode};
-use crate::globals::{DB, ENFORCEMENTS, LEGACY_IMPORTER, SUPER_KEY};
+use crate::boot_state_override::BootStateOverride;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::clock_rollback::{Clock, SystemClock, CLOCK_ROLLBACK_DETECTOR};
+use crate::database::{CertificateInfo, ExistingAlias, KeyIdGuard, StoredKey, WrapCertChain};
+use crate::ec_curve_strength::EcCurvePolicy;
+use crate::error::{self, map_km_error, map_ks_error, map_or_log_err, Error, ErrorCode};
+use crate::fips_mode::FipsPolicy;
+use crate::generation_defaults::GenerationDefaults;
+use crate::globals::{
+    ASYNC_TASK, BLOB_NONCE_GENERATOR, DB, ENFORCEMENTS, LEGACY_IMPORTER, SUPER_KEY,
+};
+use crate::key_flags::{
+    KEY_FLAG_DELETE_ON_EXPIRY, KEY_FLAG_FAIL_IF_EXISTS,
+    KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE, KEY_FLAG_TEST_CERTIFICATE_VALIDITY,
+    KEY_FLAG_TEST_CREATION_DATETIME, KEY_FLAG_TEST_VERIFIED_BOOT_STATE,
+    KEY_FLAG_VERIFY_ATTESTATION,
+};
+use crate::key_lifecycle::notify_key_used;
 use crate::key_parameter::KeyParameter as KsKeyParam;
 use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+use crate::key_tags::{is_keystore_tag, KeystoreParams};
+use crate::keymint_timeout::{KeyMintCallType, KeyMintCallWatch};
 use crate::ks_err;
-use crate::metrics_store::log_key_creation_event_stats;
+use crate::latency_budget::{GenerationStep, LatencyBudget};
+use crate::metrics_store::{
+    log_attestation_failure, log_attestation_self_test, log_imported_key_with_broad_purposes,
+    log_key_creation_event_stats,
+};
+use crate::operation_checkpoint;
+use crate::operation_token::OPERATION_TOKENS;
 use crate::remote_provisioning::RemProvState;
 use crate::rkpd_client::store_rkpd_attestation_key;
-use crate::super_key::{KeyBlob, SuperKeyManager};
+use crate::rsa_key_size::RsaKeySizePolicy;
+use crate::shared_uid_attestation::SharedUidAttestationPolicy;
+use crate::super_key::{BlobBinding, KeyBlob, SuperKeyManager};
+use crate::super_key_wait::SuperKeyWaitPolicy;
+use crate::sysprop::{read_prop_bool, read_prop_parsed, read_prop_u32};
+use crate::unknown_tags::UnknownTagPolicy;
 use crate::utils::{
-    check_device_attestation_permissions, check_key_permission,
-    check_unique_id_attestation_permissions, is_device_id_attestation_tag,
-    key_characteristics_to_internal, uid_to_android_user, watchdog as wd,
+    check_key_permission, check_unique_id_attestation_permissions, get_provisioned_attestation_ids,
+    is_device_id_attestation_tag, key_characteristics_to_internal, resolve_key_namespace,
+    uid_to_android_user, watchdog as wd, SystemPropertyAttestationIds, ATTESTATION_ID_TAGS,
 };
 use crate::{
     database::{
@@ -43,15 +80,16 @@ use crate::{
     operation::OperationDb,
     permission::KeyPerm,
 };
-use crate::{globals::get_keymint_device, id_rotation::IdRotationState};
+use crate::{globals::get_keymint_device_by_instance, id_rotation::IdRotationState};
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
-    Algorithm::Algorithm, AttestationKey::AttestationKey,
+    Algorithm::Algorithm, AttestationKey::AttestationKey, Digest::Digest, EcCurve::EcCurve,
     HardwareAuthenticatorType::HardwareAuthenticatorType, IKeyMintDevice::IKeyMintDevice,
     KeyCreationResult::KeyCreationResult, KeyFormat::KeyFormat,
     KeyMintHardwareInfo::KeyMintHardwareInfo, KeyParameter::KeyParameter,
-    KeyParameterValue::KeyParameterValue, SecurityLevel::SecurityLevel, Tag::Tag,
+    KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
+    SecurityLevel::SecurityLevel, Tag::Tag,
 };
-use android_hardware_security_keymint::binder::{BinderFeatures, Strong, ThreadState};
+use android_hardware_security_keymint::binder::{BinderFeatures, StatusCode, Strong, ThreadState};
 use android_system_keystore2::aidl::android::system::keystore2::{
     AuthenticatorSpec::AuthenticatorSpec, CreateOperationResponse::CreateOperationResponse,
     Domain::Domain, EphemeralStorageKeyResponse::EphemeralStorageKeyResponse,
@@ -60,18 +98,664 @@ use android_system_keystore2::aidl::android::system::keystore2::{
     KeyMetadata::KeyMetadata, KeyParameters::KeyParameters, ResponseCode::ResponseCode,
 };
 use anyhow::{anyhow, Context, Result};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
+lazy_static! {
+    /// The security levels by the UUID of their KeyMint instance, so that checkpointed
+    /// operations can be resumed on the instance they were created on.
+    static ref SECURITY_LEVELS: Mutex<HashMap<Uuid, Strong<dyn IKeystoreSecurityLevel>>> =
+        Default::default();
+}
+
+/// Returns the security level of the KeyMint instance `km_uuid`.
+pub fn get_security_level_by_uuid(km_uuid: &Uuid) -> Result<Strong<dyn IKeystoreSecurityLevel>> {
+    SECURITY_LEVELS
+        .lock()
+        .unwrap()
+        .get(km_uuid)
+        .cloned()
+        .ok_or_else(Error::sys)
+        .context(ks_err!("KeyMint instance {:?} not found.", km_uuid))
+}
+
+/// The EC curves that the TEE backend supports, as a comma separated list of `EcCurve` names,
+/// e.g., "P_256,P_384,CURVE_25519". If not set, they are inferred from the KeyMint version.
+const TEE_EC_CURVES_PROPERTY: &str = "keystore.ec_curves.tee";
+/// Like `TEE_EC_CURVES_PROPERTY`, for the StrongBox backends.
+const STRONGBOX_EC_CURVES_PROPERTY: &str = "keystore.ec_curves.strongbox";
+
+/// Parses a comma separated list of `EcCurve` names. Returns None if any of them is unknown.
+fn parse_ec_curves(value: &str) -> Option<Vec<EcCurve>> {
+    value
+        .split(',')
+        .map(|name| match name.trim() {
+            "P_224" => Some(EcCurve::P_224),
+            "P_256" => Some(EcCurve::P_256),
+            "P_384" => Some(EcCurve::P_384),
+            "P_521" => Some(EcCurve::P_521),
+            "CURVE_25519" => Some(EcCurve::CURVE_25519),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the EC curves that a backend is expected to support if the device does not configure
+/// them. P-521 is not required of StrongBox implementations, and Curve 25519 (Ed25519 and
+/// X25519) was introduced with KeyMint V2 and is only available in the TEE.
+fn default_ec_curves(hw_info: &KeyMintHardwareInfo) -> Vec<EcCurve> {
+    let mut curves = vec![EcCurve::P_224, EcCurve::P_256, EcCurve::P_384];
+    if hw_info.securityLevel != SecurityLevel::STRONGBOX {
+        curves.push(EcCurve::P_521);
+        if hw_info.versionNumber >= 200 {
+            curves.push(EcCurve::CURVE_25519);
+        }
+    }
+    curves
+}
+
+/// Returns the EC curves supported by the backend described by `hw_info`, as configured by
+/// `keystore.ec_curves.tee` or `keystore.ec_curves.strongbox`, or the default curves of the
+/// backend. The property is read on every call, so changes take effect with the next request.
+fn supported_ec_curves(hw_info: &KeyMintHardwareInfo) -> Vec<EcCurve> {
+    let property = match hw_info.securityLevel {
+        SecurityLevel::STRONGBOX => STRONGBOX_EC_CURVES_PROPERTY,
+        _ => TEE_EC_CURVES_PROPERTY,
+    };
+    read_prop_parsed(property, None, |v| parse_ec_curves(v).map(Some))
+        .unwrap_or_else(|| default_ec_curves(hw_info))
+}
+
+/// Checks that the EC curve requested in `params`, if any, is supported by the backend described
+/// by `hw_info`, see `supported_ec_curves`. Requests for curves the backend cannot handle are
+/// rejected with `ErrorCode::UNSUPPORTED_EC_CURVE` up front instead of relying on the backend to
+/// produce a meaningful error.
+fn check_ec_curve_supported(hw_info: &KeyMintHardwareInfo, params: &[KeyParameter]) -> Result<()> {
+    check_ec_curve_in(&supported_ec_curves(hw_info), hw_info, params)
+}
+
+fn check_ec_curve_in(
+    curves: &[EcCurve],
+    hw_info: &KeyMintHardwareInfo,
+    params: &[KeyParameter],
+) -> Result<()> {
+    let curve = match params.iter().find(|kp| kp.tag == Tag::EC_CURVE) {
+        Some(KeyParameter { value: KeyParameterValue::EcCurve(curve), .. }) => *curve,
+        _ => return Ok(()),
+    };
+    if curves.contains(&curve) {
+        Ok(())
+    } else {
+        Err(Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE)).context(ks_err!(
+            "EC curve {:?} is not supported by {:?} KeyMint version {}.",
+            curve,
+            hw_info.securityLevel,
+            hw_info.versionNumber
+        ))
+    }
+}
+
+/// If the caller opted in with `KEY_FLAG_VERIFY_ATTESTATION`, returns the attestation challenge
+/// in `params` that the attestation of the new key must carry. Returns None otherwise.
+fn attestation_challenge_to_verify(params: &[KeyParameter], flags: i32) -> Result<Option<Vec<u8>>> {
+    if (flags & KEY_FLAG_VERIFY_ATTESTATION) == 0 {
+        return Ok(None);
+    }
+    match params.iter().find(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
+        Some(KeyParameter { value: KeyParameterValue::Blob(challenge), .. }) => {
+            Ok(Some(challenge.clone()))
+        }
+        _ => Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("Attestation verification requires an attestation challenge.")),
+    }
+}
+
+/// If the caller named an attestation template with `KEY_TAG_ATTESTATION_TEMPLATE`, returns
+/// `params` merged with the template. Returns None otherwise.
+fn apply_attestation_template(
+    params: &[KeyParameter],
+    name: Option<&str>,
+) -> Result<Option<Vec<KeyParameter>>> {
+    match name {
+        Some(name) => ATTESTATION_TEMPLATES.apply(name, params).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// If the caller named a package with `KEY_TAG_ATTESTATION_PACKAGE`, checks that the key is
+/// attested and returns the package. Returns None otherwise.
+fn attestation_package<'a>(
+    params: &[KeyParameter],
+    package: Option<&'a [u8]>,
+) -> Result<Option<&'a [u8]>> {
+    if package.is_some() && !params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
+        return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("The attestation package requires an attestation challenge."));
+    }
+    Ok(package)
+}
+
+/// If the caller opted in with `KEY_FLAG_DELETE_ON_EXPIRY`, returns the expiry date given by
+/// `Tag::USAGE_EXPIRE_DATETIME` in `params`. Returns None otherwise.
+fn expiry_date_to_record(
+    params: &[KeyParameter],
+    flags: i32,
+    domain: Domain,
+) -> Result<Option<DateTime>> {
+    if (flags & KEY_FLAG_DELETE_ON_EXPIRY) == 0 {
+        return Ok(None);
+    }
+    if domain == Domain::BLOB {
+        return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("Keys of Domain::BLOB cannot be deleted on expiry."));
+    }
+    match params.iter().find(|kp| kp.tag == Tag::USAGE_EXPIRE_DATETIME) {
+        Some(KeyParameter { value: KeyParameterValue::DateTime(millis), .. }) => {
+            Ok(Some(DateTime::from_millis_epoch(*millis)))
+        }
+        _ => Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("Deletion on expiry requires Tag::USAGE_EXPIRE_DATETIME.")),
+    }
+}
+
+/// Fails with `ErrorCode::KEY_EXPIRED` if `key_entry` expired according to `clock`.
+fn check_key_not_expired(key_entry: &KeyEntry, clock: &dyn Clock) -> Result<()> {
+    if key_entry.is_expired(DateTime::from_millis_epoch(clock.wall_millis())) {
+        return Err(Error::Km(ErrorCode::KEY_EXPIRED))
+            .context(ks_err!("Key {} has expired.", key_entry.id()));
+    }
+    Ok(())
+}
+
+/// The UIDs that may use `KEY_FLAG_TEST_CREATION_DATETIME` and
+/// `KEY_FLAG_TEST_CERTIFICATE_VALIDITY`, i.e., root and shell.
+const TEST_FLAG_UIDS: [u32; 2] = [0, 2000];
+
+/// Returns true if the caller may use the test only key flags.
+fn may_use_test_flags(caller_uid: u32) -> bool {
+    read_prop_bool("ro.debuggable", false) && TEST_FLAG_UIDS.contains(&caller_uid)
+}
+
+/// If the caller opted in with `KEY_FLAG_TEST_CREATION_DATETIME`, checks that the caller may
+/// choose the creation date and returns it together with a copy of `params` without
+/// `Tag::CREATION_DATETIME`. Returns None otherwise.
+fn take_test_creation_datetime(
+    params: &[KeyParameter],
+    flags: i32,
+    caller_uid: u32,
+) -> Result<Option<(DateTime, Vec<KeyParameter>)>> {
+    if (flags & KEY_FLAG_TEST_CREATION_DATETIME) == 0 {
+        return Ok(None);
+    }
+    if !may_use_test_flags(caller_uid) {
+        return Err(Error::perm())
+            .context(ks_err!("Caller may not specify the creation date of a key."));
+    }
+    split_creation_datetime(params).map(Some)
+}
+
+/// Returns the creation date given by `Tag::CREATION_DATETIME` in `params` and a copy of
+/// `params` without it.
+fn split_creation_datetime(params: &[KeyParameter]) -> Result<(DateTime, Vec<KeyParameter>)> {
+    let creation_date = match params.iter().find(|kp| kp.tag == Tag::CREATION_DATETIME) {
+        Some(KeyParameter { value: KeyParameterValue::DateTime(millis), .. }) => {
+            DateTime::from_millis_epoch(*millis)
+        }
+        Some(_) => {
+            return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context(ks_err!("Malformed Tag::CREATION_DATETIME."))
+        }
+        None => {
+            return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context(ks_err!("Tag::CREATION_DATETIME is missing."))
+        }
+    };
+    let params = params.iter().filter(|kp| kp.tag != Tag::CREATION_DATETIME).cloned().collect();
+    Ok((creation_date, params))
+}
+
+/// If the caller opted in with `KEY_FLAG_TEST_CERTIFICATE_VALIDITY`, checks that the caller may
+/// choose the validity window of the certificate and that `params` specify it. Returns the
+/// window as milliseconds since the epoch, or None if the flag is not set.
+fn check_test_certificate_validity(
+    params: &[KeyParameter],
+    flags: i32,
+    caller_uid: u32,
+) -> Result<Option<(i64, i64)>> {
+    if (flags & KEY_FLAG_TEST_CERTIFICATE_VALIDITY) == 0 {
+        return Ok(None);
+    }
+    if !may_use_test_flags(caller_uid) {
+        return Err(Error::perm())
+            .context(ks_err!("Caller may not specify the certificate validity of a key."));
+    }
+    certificate_validity(params).map(Some)
+}
+
+/// Returns the validity window given by `Tag::CERTIFICATE_NOT_BEFORE` and
+/// `Tag::CERTIFICATE_NOT_AFTER` in `params`. Both must be present, and the window must not be
+/// empty.
+fn certificate_validity(params: &[KeyParameter]) -> Result<(i64, i64)> {
+    let find = |tag: Tag| match params.iter().find(|kp| kp.tag == tag) {
+        Some(KeyParameter { value: KeyParameterValue::DateTime(millis), .. }) => Ok(*millis),
+        Some(_) => {
+            Err(Error::Km(ErrorCode::INVALID_ARGUMENT)).context(ks_err!("Malformed {:?}.", tag))
+        }
+        None => {
+            Err(Error::Km(ErrorCode::INVALID_ARGUMENT)).context(ks_err!("{:?} is missing.", tag))
+        }
+    };
+    let not_before = find(Tag::CERTIFICATE_NOT_BEFORE)?;
+    let not_after = find(Tag::CERTIFICATE_NOT_AFTER)?;
+    if not_before > not_after {
+        return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("The certificate validity ends before it starts."));
+    }
+    Ok((not_before, not_after))
+}
+
+/// If the caller opted in with `KEY_FLAG_TEST_VERIFIED_BOOT_STATE`, checks that the caller may
+/// override the attested boot state and returns the configured override. Returns None
+/// otherwise.
+fn test_boot_state_override(
+    params: &[KeyParameter],
+    flags: i32,
+    caller_uid: u32,
+) -> Result<Option<BootStateOverride>> {
+    if (flags & KEY_FLAG_TEST_VERIFIED_BOOT_STATE) == 0 {
+        return Ok(None);
+    }
+    if !may_use_test_flags(caller_uid) {
+        return Err(Error::perm())
+            .context(ks_err!("Caller may not override the attested verified boot state."));
+    }
+    if !params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
+        return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("The verified boot state override requires an attestation."));
+    }
+    BootStateOverride::from_property()
+        .ok_or(Error::Km(ErrorCode::INVALID_ARGUMENT))
+        .context(ks_err!("No verified boot state override is configured."))
+        .map(Some)
+}
+
+/// Returns what happens to a key that already has the alias of the new key, as selected by
+/// `KEY_FLAG_FAIL_IF_EXISTS`. Otherwise, the key is replaced unless `idempotent_retry_after` is
+/// given and the key was created by an earlier request with the same idempotency key, see
+/// `KEY_TAG_IDEMPOTENCY_KEY`.
+fn existing_alias_policy(
+    flags: Option<i32>,
+    idempotent_retry_after: Option<DateTime>,
+) -> ExistingAlias {
+    match (flags, idempotent_retry_after) {
+        (Some(flags), _) if (flags & KEY_FLAG_FAIL_IF_EXISTS) != 0 => ExistingAlias::Fail,
+        (_, Some(created_after)) => ExistingAlias::KeepIdempotentRetry(created_after),
+        _ => ExistingAlias::Replace,
+    }
+}
+
+/// Adds the default validity window for the certificate of an asymmetric key to `result` for
+/// each bound that `params` do not specify.
+fn add_default_certificate_validity(params: &[KeyParameter], result: &mut Vec<KeyParameter>) {
+    match params.iter().find(|kp| kp.tag == Tag::ALGORITHM) {
+        Some(KeyParameter { tag: _, value: KeyParameterValue::Algorithm(Algorithm::RSA) })
+        | Some(KeyParameter { tag: _, value: KeyParameterValue::Algorithm(Algorithm::EC) }) => {
+            if !params.iter().any(|kp| kp.tag == Tag::CERTIFICATE_NOT_BEFORE) {
+                result.push(KeyParameter {
+                    tag: Tag::CERTIFICATE_NOT_BEFORE,
+                    value: KeyParameterValue::DateTime(0),
+                })
+            }
+            if !params.iter().any(|kp| kp.tag == Tag::CERTIFICATE_NOT_AFTER) {
+                result.push(KeyParameter {
+                    tag: Tag::CERTIFICATE_NOT_AFTER,
+                    value: KeyParameterValue::DateTime(UNDEFINED_NOT_AFTER),
+                })
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The time for which an idempotency key identifies the key that was generated with it.
+const IDEMPOTENCY_WINDOW_MILLIS: i64 = 10 * 60 * 1000;
+
+/// Returns the idempotency token and the parameters digest that identify a request with
+/// `idempotency_key` and `params`, see `KeyMetaEntry::IdempotencyToken`. The idempotency key
+/// itself is not stored.
+fn idempotency_digests(
+    idempotency_key: &[u8],
+    params: &[KeyParameter],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let values: Vec<KsKeyParamValue> = params.iter().map(KsKeyParamValue::from).collect();
+    let encoded =
+        serde_cbor::to_vec(&values).context(ks_err!("Failed to encode key parameters."))?;
+    Ok((
+        keystore2_crypto::sha256(idempotency_key).context(ks_err!())?,
+        keystore2_crypto::sha256(&encoded).context(ks_err!())?,
+    ))
+}
+
+/// Returns the earliest creation date of a key that a request at `now` can be a retry of, i.e.,
+/// keys created less than `IDEMPOTENCY_WINDOW_MILLIS` before `now`.
+fn idempotency_window_start(now: DateTime) -> DateTime {
+    DateTime::from_millis_epoch(now.to_millis_epoch() - IDEMPOTENCY_WINDOW_MILLIS + 1)
+}
+
+/// The largest attestation challenge that KeyMint implementations are required to accept.
+pub const MAX_ATTESTATION_CHALLENGE_LEN: usize = 128;
+
+/// If the caller opted in with `KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE` and the
+/// attestation challenge in `params` exceeds `MAX_ATTESTATION_CHALLENGE_LEN`, returns a copy of
+/// `params` with the challenge replaced by its SHA-256 digest. Returns None otherwise.
+fn hash_oversized_attestation_challenge(
+    params: &[KeyParameter],
+    flags: i32,
+) -> Result<Option<Vec<KeyParameter>>> {
+    if (flags & KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE) == 0 {
+        return Ok(None);
+    }
+    let oversized = params.iter().any(|kp| {
+        matches!((kp.tag, &kp.value), (Tag::ATTESTATION_CHALLENGE, KeyParameterValue::Blob(c))
+            if c.len() > MAX_ATTESTATION_CHALLENGE_LEN)
+    });
+    if !oversized {
+        return Ok(None);
+    }
+    params
+        .iter()
+        .map(|kp| match (kp.tag, &kp.value) {
+            (Tag::ATTESTATION_CHALLENGE, KeyParameterValue::Blob(challenge)) => Ok(KeyParameter {
+                tag: Tag::ATTESTATION_CHALLENGE,
+                value: KeyParameterValue::Blob(
+                    expected_attestation_challenge(challenge, flags).context(ks_err!())?,
+                ),
+            }),
+            _ => Ok(kp.clone()),
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Returns the output size of `digest` in bytes, or None for `Digest::NONE`.
+fn digest_len(digest: Digest) -> Option<i32> {
+    match digest {
+        Digest::MD5 => Some(16),
+        Digest::SHA1 => Some(20),
+        Digest::SHA_2_224 => Some(28),
+        Digest::SHA_2_256 => Some(32),
+        Digest::SHA_2_384 => Some(48),
+        Digest::SHA_2_512 => Some(64),
+        _ => None,
+    }
+}
+
+/// Checks that the RSA-PSS related parameters of a key to be generated or imported are
+/// consistent, so that the key can actually be used for PSS signing. Otherwise the mismatch would only surface at
+/// the first operation. KeyMint uses MGF1 with the signing digest and a salt as long as the
+/// digest, so the following is required if `PaddingMode::RSA_PSS` is requested:
+///  * The algorithm is RSA, else `ErrorCode::INCOMPATIBLE_PADDING_MODE`.
+///  * The key can sign or verify, else `ErrorCode::INCOMPATIBLE_PURPOSE`.
+///  * At least one digest other than `Digest::NONE` is authorized, and if the key size is given,
+///    one of them is short enough for the key size, i.e., 2 * digest length + 2 does not exceed
+///    the key size in bytes. Else `ErrorCode::INCOMPATIBLE_DIGEST`.
+///  * If the key is not also meant for RSA-OAEP, MGF1 digests only make sense as PSS digests,
+///    so each of them must be among the key's digests, else `ErrorCode::INCOMPATIBLE_MGF_DIGEST`.
+fn check_rsa_pss_params(params: &[KeyParameter]) -> Result<()> {
+    let paddings: Vec<PaddingMode> = params
+        .iter()
+        .filter_map(|kp| match kp.value {
+            KeyParameterValue::PaddingMode(p) => Some(p),
+            _ => None,
+        })
+        .collect();
+    if !paddings.contains(&PaddingMode::RSA_PSS) {
+        return Ok(());
+    }
+
+    let algorithm = params.iter().find_map(|kp| match kp.value {
+        KeyParameterValue::Algorithm(a) => Some(a),
+        _ => None,
+    });
+    if algorithm != Some(Algorithm::RSA) {
+        return Err(Error::Km(ErrorCode::INCOMPATIBLE_PADDING_MODE))
+            .context(ks_err!("RSA_PSS padding requested for algorithm {:?}.", algorithm));
+    }
+
+    if !params.iter().any(|kp| {
+        matches!(kp.value, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN | KeyPurpose::VERIFY))
+    }) {
+        return Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE))
+            .context(ks_err!("RSA_PSS padding requires the SIGN or VERIFY purpose."));
+    }
+
+    let digests: Vec<Digest> = params
+        .iter()
+        .filter_map(|kp| match kp.value {
+            KeyParameterValue::Digest(d) => Some(d),
+            _ => None,
+        })
+        .collect();
+    let key_size_bytes = params.iter().find_map(|kp| match kp.value {
+        KeyParameterValue::Integer(size) if kp.tag == Tag::KEY_SIZE => Some(size / 8),
+        _ => None,
+    });
+    let fits = |digest: &Digest| match (digest_len(*digest), key_size_bytes) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(len), Some(size)) => 2 * len + 2 <= size,
+    };
+    if !digests.iter().any(fits) {
+        return Err(Error::Km(ErrorCode::INCOMPATIBLE_DIGEST)).context(ks_err!(
+            "RSA_PSS padding requires a digest that fits the key size {:?} bytes. Digests: {:?}.",
+            key_size_bytes,
+            digests
+        ));
+    }
+
+    if !paddings.contains(&PaddingMode::RSA_OAEP) {
+        for kp in params {
+            if let KeyParameterValue::Digest(mgf_digest) = kp.value {
+                if kp.tag == Tag::RSA_OAEP_MGF_DIGEST && !digests.contains(&mgf_digest) {
+                    return Err(Error::Km(ErrorCode::INCOMPATIBLE_MGF_DIGEST)).context(ks_err!(
+                        "RSA_PSS uses MGF1 with the signing digest, but MGF1 digest {:?} \
+                         is not among the digests {:?}.",
+                        mgf_digest,
+                        digests
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the RSA-PSS related parameters of an operation with `check_rsa_pss_params`. The
+/// algorithm and the key size are taken from the key's parameters, the purpose, the digest, and
+/// the MGF1 digest from the operation. So a PSS operation fails with the same error codes as the
+/// generation of a PSS key whose only digest is the digest of the operation.
+fn check_rsa_pss_operation_params(
+    purpose: KeyPurpose,
+    operation_parameters: &[KeyParameter],
+    key_params: &[KsKeyParam],
+) -> Result<()> {
+    let mut params: Vec<KeyParameter> = key_params
+        .iter()
+        .filter(|kp| matches!(kp.get_tag(), Tag::ALGORITHM | Tag::KEY_SIZE))
+        .map(|kp| kp.key_parameter_value().clone().into())
+        .collect();
+    params.push(KeyParameter { tag: Tag::PURPOSE, value: KeyParameterValue::KeyPurpose(purpose) });
+    params.extend_from_slice(operation_parameters);
+    check_rsa_pss_params(&params)
+}
+
+/// Checks that `purpose` is among the purposes recorded in the key's parameters. This lets us
+/// reject operations with `ErrorCode::INCOMPATIBLE_PURPOSE` before loading the key into KeyMint,
+/// and with an error that names the offending purpose.
+fn check_key_purpose(purpose: KeyPurpose, key_params: &[KsKeyParam]) -> Result<()> {
+    let allowed: Vec<KeyPurpose> = key_params
+        .iter()
+        .filter_map(|kp| match kp.key_parameter_value() {
+            KsKeyParamValue::KeyPurpose(p) => Some(*p),
+            _ => None,
+        })
+        .collect();
+    if allowed.contains(&purpose) {
+        Ok(())
+    } else {
+        Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE)).context(ks_err!(
+            "Requested purpose {:?} is not among the key's purposes {:?}.",
+            purpose,
+            allowed
+        ))
+    }
+}
+
+/// Returns the purposes that KeyMint supports for keys of `algorithm`, or None if the algorithm
+/// is unknown.
+fn supported_purposes(algorithm: Algorithm) -> Option<&'static [KeyPurpose]> {
+    Some(match algorithm {
+        Algorithm::RSA => &[
+            KeyPurpose::ENCRYPT,
+            KeyPurpose::DECRYPT,
+            KeyPurpose::SIGN,
+            KeyPurpose::VERIFY,
+            KeyPurpose::WRAP_KEY,
+            KeyPurpose::ATTEST_KEY,
+        ],
+        Algorithm::EC => {
+            &[KeyPurpose::SIGN, KeyPurpose::VERIFY, KeyPurpose::AGREE_KEY, KeyPurpose::ATTEST_KEY]
+        }
+        Algorithm::AES => &[KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT, KeyPurpose::WRAP_KEY],
+        Algorithm::TRIPLE_DES => &[KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT],
+        Algorithm::HMAC => &[KeyPurpose::SIGN, KeyPurpose::VERIFY],
+        _ => return None,
+    })
+}
+
+fn declared_algorithm(params: &[KeyParameter]) -> Option<Algorithm> {
+    params.iter().find_map(|kp| match kp.value {
+        KeyParameterValue::Algorithm(a) => Some(a),
+        _ => None,
+    })
+}
+
+fn declared_purposes(params: &[KeyParameter]) -> Vec<KeyPurpose> {
+    params
+        .iter()
+        .filter_map(|kp| match kp.value {
+            KeyParameterValue::KeyPurpose(p) => Some(p),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Checks that the purposes declared for a key to be imported are consistent, so that a key
+/// is not imported for a use it can never serve. Otherwise the mismatch would only surface at
+/// the first operation. The following is required, else `ErrorCode::INCOMPATIBLE_PURPOSE`:
+///  * Each purpose is supported by the algorithm of the key, e.g., AES keys cannot sign.
+///  * `KeyPurpose::ATTEST_KEY` is not combined with any other purpose.
+fn check_import_purposes(params: &[KeyParameter]) -> Result<()> {
+    let (algorithm, supported) = match declared_algorithm(params)
+        .and_then(|algorithm| Some((algorithm, supported_purposes(algorithm)?)))
+    {
+        Some(v) => v,
+        // A missing or unknown algorithm is reported when the key format is determined.
+        None => return Ok(()),
+    };
+    let purposes = declared_purposes(params);
+    if let Some(purpose) = purposes.iter().find(|p| !supported.contains(p)) {
+        return Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE)).context(ks_err!(
+            "Purpose {:?} is not supported for {:?} keys.",
+            purpose,
+            algorithm
+        ));
+    }
+    if purposes.contains(&KeyPurpose::ATTEST_KEY) && purposes.len() > 1 {
+        return Err(Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE))
+            .context(ks_err!("ATTEST_KEY cannot be combined with other purposes {:?}.", purposes));
+    }
+    Ok(())
+}
+
+/// Rejects an attestation challenge in the generation parameters of a key that cannot be
+/// attested, i.e., of a symmetric key, with `ErrorCode::INVALID_ARGUMENT`. KeyMint would ignore
+/// the challenge, leaving the caller without the attestation it asked for.
+fn check_attestation_challenge_attestable(params: &[KeyParameter]) -> Result<()> {
+    if !params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
+        return Ok(());
+    }
+    match declared_algorithm(params) {
+        Some(algorithm @ (Algorithm::AES | Algorithm::HMAC | Algorithm::TRIPLE_DES)) => {
+            Err(Error::Km(ErrorCode::INVALID_ARGUMENT)).context(ks_err!(
+                "{:?} keys cannot be attested, but an attestation challenge was given.",
+                algorithm
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Overrides the maximum size in bytes of imported key material, see `check_import_size`.
+const MAX_IMPORT_SIZE_PROPERTY: &str = "keystore.max_import_size";
+
+/// Default maximum size in bytes of imported key material. A PKCS#8 encoded 4096 bit RSA key
+/// takes less than 2.5 kilobytes, which leaves ample room for wrapped keys and their metadata.
+const DEFAULT_MAX_IMPORT_SIZE: u32 = 16 * 1024;
+
+/// Rejects imported key material, i.e., the key data of an import or the wrapped key or masking
+/// key of a wrapped import, that exceeds the configured maximum size with
+/// `ResponseCode::TOO_MUCH_DATA`, before it is passed to KeyMint or stored.
+fn check_import_size(what: &str, data: &[u8]) -> Result<()> {
+    let max_size = read_prop_u32(MAX_IMPORT_SIZE_PROPERTY, DEFAULT_MAX_IMPORT_SIZE) as usize;
+    check_import_size_with(what, data, max_size)
+}
+
+fn check_import_size_with(what: &str, data: &[u8], max_size: usize) -> Result<()> {
+    if data.len() > max_size {
+        return Err(Error::Rc(ResponseCode::TOO_MUCH_DATA)).context(ks_err!(
+            "{} of {} bytes exceeds {} bytes.",
+            what,
+            data.len(),
+            max_size
+        ));
+    }
+    Ok(())
+}
+
+/// Returns true if `purposes` are unusually broad, i.e., they combine purposes of more than one
+/// kind out of encryption, signing, key agreement, and key wrapping. Such keys are legitimate
+/// but using one key for unrelated schemes weakens each of them, so their import is recorded.
+fn has_broad_purposes(purposes: &[KeyPurpose]) -> bool {
+    let kinds: HashSet<u8> = purposes
+        .iter()
+        .filter_map(|p| match *p {
+            KeyPurpose::ENCRYPT | KeyPurpose::DECRYPT => Some(0),
+            KeyPurpose::SIGN | KeyPurpose::VERIFY => Some(1),
+            KeyPurpose::AGREE_KEY => Some(2),
+            KeyPurpose::WRAP_KEY => Some(3),
+            _ => None,
+        })
+        .collect();
+    kinds.len() > 1
+}
+
 /// Implementation of the IKeystoreSecurityLevel Interface.
 pub struct KeystoreSecurityLevel {
     security_level: SecurityLevel,
+    instance: String,
     keymint: Strong<dyn IKeyMintDevice>,
     hw_info: KeyMintHardwareInfo,
     km_uuid: Uuid,
-    operation_db: OperationDb,
+    operation_db: Arc<OperationDb>,
     rem_prov_state: RemProvState,
     id_rotation_state: IdRotationState,
+    circuit_breaker: CircuitBreaker,
+    attestation_key_policy: AttestationKeyPolicy,
+    generation_defaults: GenerationDefaults,
 }
 
 // Blob of 32 zeroes used as empty masking key.
@@ -81,43 +765,142 @@ static ZERO_BLOB_32: &[u8] = &[0; 32];
 // 999912312359559, which is 253402300799000 ms from Jan 1, 1970.
 const UNDEFINED_NOT_AFTER: i64 = 253402300799000i64;
 
+/// Whether the certificate chains of new keys that are encrypted with an AES super key are
+/// wrapped with that super key as well, see `NewKeyEncryption::wrap_cert_chain`. False by
+/// default, because the chains of such keys can then only be read while the super key is in
+/// memory, and the attestation expiry checks skip them.
+const WRAP_CERT_CHAINS_PROPERTY: &str = "keystore.wrap_cert_chains";
+
 impl KeystoreSecurityLevel {
-    /// Creates a new security level instance wrapped in a
-    /// BnKeystoreSecurityLevel proxy object. It also enables
+    /// Creates a new security level instance for the KeyMint device `instance` of the given
+    /// security level.
+    fn new(
+        security_level: SecurityLevel,
+        instance: &str,
+        id_rotation_state: IdRotationState,
+    ) -> Result<Self> {
+        let (dev, hw_info, km_uuid) = get_keymint_device_by_instance(&security_level, instance)
+            .context(ks_err!("KeystoreSecurityLevel::new."))?;
+        Ok(Self {
+            security_level,
+            instance: instance.to_string(),
+            keymint: dev,
+            hw_info,
+            km_uuid,
+            operation_db: OperationDb::new_registered(),
+            rem_prov_state: RemProvState::new(security_level, instance, km_uuid),
+            id_rotation_state,
+            circuit_breaker: CircuitBreaker::new(security_level),
+            attestation_key_policy: AttestationKeyPolicy::for_security_level(security_level),
+            generation_defaults: GenerationDefaults::for_security_level(security_level),
+        })
+    }
+
+    /// Creates a new security level instance for the KeyMint device `instance` of the given
+    /// security level wrapped in a BnKeystoreSecurityLevel proxy object. It also enables
     /// `BinderFeatures::set_requesting_sid` on the new interface, because
     /// we need it for checking keystore permissions.
     pub fn new_native_binder(
         security_level: SecurityLevel,
+        instance: &str,
         id_rotation_state: IdRotationState,
     ) -> Result<(Strong<dyn IKeystoreSecurityLevel>, Uuid)> {
-        let (dev, hw_info, km_uuid) = get_keymint_device(&security_level)
-            .context(ks_err!("KeystoreSecurityLevel::new_native_binder."))?;
+        let security_level_instance =
+            Self::new(security_level, instance, id_rotation_state.clone())
+                .context(ks_err!("KeystoreSecurityLevel::new_native_binder."))?;
+        let km_uuid = security_level_instance.km_uuid;
         let result = BnKeystoreSecurityLevel::new_binder(
-            Self {
-                security_level,
-                keymint: dev,
-                hw_info,
-                km_uuid,
-                operation_db: OperationDb::new(),
-                rem_prov_state: RemProvState::new(security_level, km_uuid),
-                id_rotation_state,
-            },
+            security_level_instance,
             BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
         );
+        SECURITY_LEVELS.lock().unwrap().insert(km_uuid, result.clone());
+        Self::check_provisioning_paths_on_async_task(security_level, instance, km_uuid);
+        if self_test_enabled() {
+            Self::run_attestation_self_test_in_background(
+                security_level,
+                instance,
+                id_rotation_state,
+            );
+        }
         Ok((result, km_uuid))
     }
 
+    /// Runs the attestation self-test, see `crate::attestation_self_test`, on a separate
+    /// instance of the KeyMint device `instance` and logs its outcome. The self-test runs on a
+    /// thread of its own, because generating an attested key can take seconds.
+    fn run_attestation_self_test_in_background(
+        security_level: SecurityLevel,
+        instance: &str,
+        id_rotation_state: IdRotationState,
+    ) {
+        let instance = instance.to_string();
+        std::thread::spawn(move || {
+            let outcome = match Self::new(security_level, &instance, id_rotation_state) {
+                Ok(pipeline) => run_self_test(&pipeline),
+                Err(e) => {
+                    log::error!("Failed to set up the attestation self-test: {:?}", e);
+                    SelfTestOutcome::Failed
+                }
+            };
+            log::info!("Attestation self-test on {:?}: {:?}", security_level, outcome);
+            log_attestation_self_test(outcome, security_level);
+        });
+    }
+
+    /// Checks as low priority job on the async task whether keystore's own key pool and RKPD
+    /// agree on the availability of attestation keys for the preferred source of each caller
+    /// category, and logs every disagreement. This catches misconfigured devices before
+    /// attestations start failing.
+    fn check_provisioning_paths_on_async_task(
+        security_level: SecurityLevel,
+        instance: &str,
+        km_uuid: Uuid,
+    ) {
+        let rem_prov_state = RemProvState::new(security_level, instance, km_uuid);
+        ASYNC_TASK.queue_lo(move |_shelf| {
+            let result = DB.with(|db| {
+                check_provisioning_paths(
+                    &rem_prov_state,
+                    &AttestationKeyPolicy::for_security_level(security_level),
+                    &mut db.borrow_mut(),
+                )
+            });
+            match result {
+                Ok(disagreements) => {
+                    for (category, disagreement) in disagreements {
+                        log::warn!(
+                            "Attestation key sources of {:?} disagree for {:?}: {:?}",
+                            security_level,
+                            category,
+                            disagreement
+                        );
+                    }
+                }
+                Err(e) => log::error!("Failed to check attestation key sources: {:?}", e),
+            }
+        });
+    }
+
     fn watch_millis(&self, id: &'static str, millis: u64) -> Option<wd::WatchPoint> {
         let sec_level = self.security_level;
         wd::watch_millis_with(id, millis, move || format!("SecurityLevel {:?}", sec_level))
     }
 
+    fn watch_keymint(&self, call_type: KeyMintCallType, id: &'static str) -> KeyMintCallWatch {
+        KeyMintCallWatch::new(call_type, self.security_level, id)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn store_new_key(
         &self,
         key: KeyDescriptor,
         creation_result: KeyCreationResult,
         user_id: u32,
         flags: Option<i32>,
+        creation_date: Option<DateTime>,
+        extra_key_metadata: Vec<KeyMetaEntry>,
+        idempotent_retry_after: Option<DateTime>,
+        latency: &LatencyBudget,
     ) -> Result<KeyMetadata> {
         let KeyCreationResult {
             keyBlob: key_blob,
@@ -149,54 +932,92 @@ impl KeystoreSecurityLevel {
             SecurityLevel::SOFTWARE,
         ));
 
-        let creation_date = DateTime::now().context(ks_err!("Trying to make creation time."))?;
+        let creation_date = match creation_date {
+            Some(creation_date) => creation_date,
+            None => DateTime::now().context(ks_err!("Trying to make creation time."))?,
+        };
 
-        let key = match key.domain {
-            Domain::BLOB => KeyDescriptor {
+        let stored_key = match key.domain {
+            Domain::BLOB => None,
+            _ => latency
+                .measure(GenerationStep::Database, || {
+                    // The key blob is encrypted inside of the transaction that stores it.
+                    BLOB_NONCE_GENERATOR
+                        .reserve_for_transaction()
+                        .context(ks_err!("Failed to reserve blob nonces."))?;
+                    DB.with::<_, Result<StoredKey>>(|db| {
+                        let mut db = db.borrow_mut();
+
+                        let encryption = latency
+                            .measure(GenerationStep::Crypto, || {
+                                SUPER_KEY.read().unwrap().handle_super_encryption_on_key_init(
+                                    &mut db,
+                                    &LEGACY_IMPORTER,
+                                    &(key.domain),
+                                    &key_parameters,
+                                    flags,
+                                    user_id,
+                                )
+                            })
+                            .context(ks_err!("Failed to handle super encryption."))?;
+
+                        let mut key_metadata = KeyMetaData::new();
+                        key_metadata.add(KeyMetaEntry::CreationDate(creation_date));
+                        for entry in extra_key_metadata {
+                            key_metadata.add(entry);
+                        }
+
+                        let wrap_cert_chain = |key_id: i64, cert_chain: &[u8]| {
+                            encryption.wrap_cert_chain(cert_chain, &BlobBinding::new(key_id, &key))
+                        };
+                        let stored_key = db
+                            .store_new_key_with_blob(
+                                &key,
+                                KeyType::Client,
+                                &key_parameters,
+                                |key_id| {
+                                    let (key_blob, mut blob_metadata) = latency
+                                        .measure(GenerationStep::Crypto, || {
+                                            encryption
+                                                .encrypt(&key_blob, &BlobBinding::new(key_id, &key))
+                                        })
+                                        .context(ks_err!("Failed to super encrypt the key."))?;
+                                    blob_metadata.add(BlobMetaEntry::KmUuid(self.km_uuid));
+                                    Ok((key_blob, blob_metadata))
+                                },
+                                &cert_info,
+                                if read_prop_bool(WRAP_CERT_CHAINS_PROPERTY, false) {
+                                    Some(&wrap_cert_chain as &WrapCertChain)
+                                } else {
+                                    None
+                                },
+                                &key_metadata,
+                                &self.km_uuid,
+                                existing_alias_policy(flags, idempotent_retry_after),
+                            )
+                            .context(ks_err!())?;
+                        Ok(stored_key)
+                    })
+                })
+                .context(ks_err!())
+                .map(Some)?,
+        };
+
+        let key = match stored_key {
+            None => KeyDescriptor {
                 domain: Domain::BLOB,
                 blob: Some(key_blob.to_vec()),
                 ..Default::default()
             },
-            _ => DB
-                .with::<_, Result<KeyDescriptor>>(|db| {
-                    let mut db = db.borrow_mut();
-
-                    let (key_blob, mut blob_metadata) = SUPER_KEY
-                        .read()
-                        .unwrap()
-                        .handle_super_encryption_on_key_init(
-                            &mut db,
-                            &LEGACY_IMPORTER,
-                            &(key.domain),
-                            &key_parameters,
-                            flags,
-                            user_id,
-                            &key_blob,
-                        )
-                        .context(ks_err!("Failed to handle super encryption."))?;
-
-                    let mut key_metadata = KeyMetaData::new();
-                    key_metadata.add(KeyMetaEntry::CreationDate(creation_date));
-                    blob_metadata.add(BlobMetaEntry::KmUuid(self.km_uuid));
-
-                    let key_id = db
-                        .store_new_key(
-                            &key,
-                            KeyType::Client,
-                            &key_parameters,
-                            &BlobInfo::new(&key_blob, &blob_metadata),
-                            &cert_info,
-                            &key_metadata,
-                            &self.km_uuid,
-                        )
-                        .context(ks_err!())?;
-                    Ok(KeyDescriptor {
-                        domain: Domain::KEY_ID,
-                        nspace: key_id.id(),
-                        ..Default::default()
-                    })
-                })
-                .context(ks_err!())?,
+            Some(StoredKey::New(key_id)) => {
+                KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id.id(), ..Default::default() }
+            }
+            // An earlier request with the same idempotency key stored its key concurrently. The
+            // new key is discarded, and the key of the earlier request is returned instead.
+            Some(StoredKey::IdempotentRetry(key_id)) => {
+                self.delete_discarded_key(&key_blob, "the key of an idempotent retry");
+                return self.load_idempotent_key(key_id).context(ks_err!());
+            }
         };
 
         Ok(KeyMetadata {
@@ -209,6 +1030,21 @@ impl KeystoreSecurityLevel {
         })
     }
 
+    /// Returns the uuid of the KeyMint instance that owns the key blob of `key` if it is another
+    /// instance than this one. No permissions are checked, because the operation is then
+    /// created by the security level of the owning instance, which checks them. Keys that
+    /// cannot be found are left to the regular lookup, which reports the error.
+    fn owning_instance_if_other(&self, key: &KeyDescriptor, caller_uid: u32) -> Option<Uuid> {
+        if key.domain == Domain::BLOB {
+            return None;
+        }
+        DB.with(|db| {
+            db.borrow_mut().load_key_km_uuid(key, KeyType::Client, caller_uid, |_, _| Ok(()))
+        })
+        .ok()
+        .filter(|km_uuid| *km_uuid != self.km_uuid)
+    }
+
     fn create_operation(
         &self,
         key: &KeyDescriptor,
@@ -216,6 +1052,41 @@ impl KeystoreSecurityLevel {
         forced: bool,
     ) -> Result<CreateOperationResponse> {
         let caller_uid = ThreadState::get_calling_uid();
+        // A lost operation is resumed from its checkpoint, on the key and with the parameters of
+        // the checkpoint, see `operation_checkpoint`.
+        if let Some(operation_id) =
+            operation_checkpoint::resume_request(operation_parameters).context(ks_err!())?
+        {
+            if forced {
+                return Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                    .context(ks_err!("Resumed operations cannot be forced."));
+            }
+            return operation_checkpoint::resume(operation_id, caller_uid).context(ks_err!());
+        }
+        // Clients select the security level by its security level alone, which may be served by
+        // several KeyMint instances, e.g., several StrongBox instances. Operations on keys of
+        // another instance are routed to the security level of that instance.
+        if let Some(km_uuid) = self.owning_instance_if_other(key, caller_uid) {
+            let sec_level = get_security_level_by_uuid(&km_uuid).context(ks_err!())?;
+            return map_ks_error(sec_level.createOperation(key, operation_parameters, forced))
+                .context(ks_err!("Failed to create the operation on instance {:?}.", km_uuid));
+        }
+        // A delegate presents an operation token in the blob field of a `Domain::KEY_ID` key
+        // descriptor instead of holding the `Use` permission. The token is consumed before the
+        // key is loaded, so that it cannot be redeemed twice if the loading has to be retried.
+        let delegated = match key {
+            KeyDescriptor { domain: Domain::KEY_ID, nspace, blob: Some(token), .. } => {
+                if forced {
+                    return Err(Error::perm())
+                        .context(ks_err!("Operation tokens cannot authorize forced operations."));
+                }
+                OPERATION_TOKENS
+                    .redeem(&SystemClock, token, *nspace, operation_parameters, caller_uid)
+                    .context(ks_err!("Failed to redeem the operation token."))?;
+                true
+            }
+            _ => false,
+        };
         // We use `scoping_blob` to extend the life cycle of the blob loaded from the database,
         // so that we can use it by reference like the blob provided by the key descriptor.
         // Otherwise, we would have to clone the blob from the key descriptor.
@@ -257,6 +1128,11 @@ impl KeystoreSecurityLevel {
                                 KeyEntryLoadBits::KM,
                                 caller_uid,
                                 |k, av| {
+                                    // The token was minted by a caller with the `Use`
+                                    // permission for this key and this operation.
+                                    if delegated {
+                                        return Ok(());
+                                    }
                                     check_key_permission(KeyPerm::Use, k, &av)?;
                                     if forced {
                                         check_key_permission(KeyPerm::ReqForcedOp, k, &av)?;
@@ -268,6 +1144,16 @@ impl KeystoreSecurityLevel {
                     })
                     .context(ks_err!("Failed to load key blob."))?;
 
+                if *key_entry.km_uuid() != self.km_uuid {
+                    return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                        .context(ks_err!("Key belongs to a different KeyMint instance."));
+                }
+
+                if key_entry.is_frozen() {
+                    return Err(Error::perm()).context(ks_err!("Key is frozen."));
+                }
+                check_key_not_expired(&key_entry, &SystemClock).context(ks_err!())?;
+
                 let (blob, blob_metadata) =
                     key_entry.take_key_blob_info().ok_or_else(Error::sys).context(ks_err!(
                         "Successfully loaded key entry, \
@@ -300,6 +1186,17 @@ impl KeystoreSecurityLevel {
             operation_parameters.iter().filter(|p| p.tag != Tag::PURPOSE).cloned().collect();
         let operation_parameters = op_params.as_slice();
 
+        let fips_policy = FipsPolicy::from_property();
+        if let Some((_, key_params)) = &key_properties {
+            check_key_purpose(purpose, key_params).context(ks_err!())?;
+            check_rsa_pss_operation_params(purpose, operation_parameters, key_params)
+                .context(ks_err!())?;
+            fips_policy.check_key(key_params).context(ks_err!())?;
+            RsaKeySizePolicy::from_property().check_key_use(key_params).context(ks_err!())?;
+        }
+        fips_policy.check(operation_parameters).context(ks_err!())?;
+        UnknownTagPolicy::from_property().check(operation_parameters).context(ks_err!())?;
+
         let (immediate_hat, mut auth_info) = ENFORCEMENTS
             .authorize_create(
                 purpose,
@@ -309,10 +1206,29 @@ impl KeystoreSecurityLevel {
             )
             .context(ks_err!())?;
 
-        let km_blob = SUPER_KEY
-            .read()
-            .unwrap()
-            .unwrap_key_if_required(&blob_metadata, km_blob)
+        self.operation_db.enforce_operation_limit(caller_uid, forced).context(ks_err!())?;
+
+        let binding = match &key_id_guard {
+            Some(key_id_guard) => DB
+                .with(|db| {
+                    BlobBinding::load_if_bound(
+                        &mut db.borrow_mut(),
+                        key_id_guard.id(),
+                        &blob_metadata,
+                    )
+                })
+                .context(ks_err!())?,
+            None => None,
+        };
+        let km_blob = SuperKeyWaitPolicy::from_property()
+            .run(|| {
+                SUPER_KEY.read().unwrap().unwrap_key_for_operation(
+                    &blob_metadata,
+                    km_blob,
+                    binding.as_ref(),
+                    purpose,
+                )
+            })
             .context(ks_err!("Failed to handle super encryption."))?;
 
         let (begin_result, upgraded_blob) = self
@@ -324,9 +1240,9 @@ impl KeystoreSecurityLevel {
                 operation_parameters,
                 |blob| loop {
                     match map_km_error({
-                        let _wp = self.watch_millis(
+                        let _wp = self.watch_keymint(
+                            KeyMintCallType::Begin,
                             "In KeystoreSecurityLevel::create_operation: calling begin",
-                            500,
                         );
                         self.keymint.begin(
                             purpose,
@@ -339,6 +1255,10 @@ impl KeystoreSecurityLevel {
                             self.operation_db.prune(caller_uid, forced)?;
                             continue;
                         }
+                        Err(Error::BinderTransaction(StatusCode::DEAD_OBJECT)) => {
+                            self.operation_db.report_hardware_reset(self.security_level);
+                            return Err(Error::HardwareReset);
+                        }
                         v @ Err(Error::Km(ErrorCode::INVALID_KEY_BLOB)) => {
                             if let Some((key_id, _)) = key_properties {
                                 if let Ok(Some(key)) =
@@ -357,18 +1277,39 @@ impl KeystoreSecurityLevel {
             )
             .context(ks_err!("Failed to begin operation."))?;
 
+        if let Some((key_id, _)) = &key_properties {
+            notify_key_used(*key_id, caller_uid);
+        }
+
         let operation_challenge = auth_info.finalize_create_authorization(begin_result.challenge);
 
         let op_params: Vec<KeyParameter> = operation_parameters.to_vec();
 
+        let algorithm = key_properties.as_ref().and_then(|(_, key_params)| {
+            key_params.iter().find_map(|kp| match kp.key_parameter_value() {
+                KsKeyParamValue::Algorithm(a) => Some(*a),
+                _ => None,
+            })
+        });
+
         let operation = match begin_result.operation {
-            Some(km_op) => self.operation_db.create_operation(
-                km_op,
-                caller_uid,
-                auth_info,
-                forced,
-                LoggingInfo::new(self.security_level, purpose, op_params, upgraded_blob.is_some()),
-            ),
+            Some(km_op) => self
+                .operation_db
+                .create_operation(
+                    km_op,
+                    caller_uid,
+                    key_properties.as_ref().map(|(key_id, _)| *key_id),
+                    algorithm,
+                    auth_info,
+                    forced,
+                    LoggingInfo::new(
+                        self.security_level,
+                        purpose,
+                        op_params,
+                        upgraded_blob.is_some(),
+                    ),
+                )
+                .context(ks_err!("Failed to create operation."))?,
             None => {
                 return Err(Error::sys()).context(ks_err!(
                     "Begin operation returned successfully, \
@@ -377,6 +1318,20 @@ impl KeystoreSecurityLevel {
             }
         };
 
+        if let Some((key_id, key_params)) = &key_properties {
+            if operation_checkpoint::create_checkpoint(
+                operation.id(),
+                caller_uid,
+                *key_id,
+                &self.km_uuid,
+                purpose,
+                key_params,
+                operation_parameters,
+            ) {
+                operation.enable_checkpoint();
+            }
+        }
+
         let op_binder: binder::Strong<dyn IKeystoreOperation> =
             KeystoreOperation::new_native_binder(operation)
                 .as_binder()
@@ -397,41 +1352,96 @@ impl KeystoreSecurityLevel {
         })
     }
 
-    fn add_required_parameters(
+    /// Selects the attestation key for a new key, see `get_attest_key_info`. If the selection
+    /// fails, the decisions that lead to the failure are logged, and the failure is recorded in
+    /// the attestation failure metric. If no attestation key is selected, the reason is logged.
+    fn get_attest_key_info(
         &self,
-        uid: u32,
-        params: &[KeyParameter],
         key: &KeyDescriptor,
-    ) -> Result<Vec<KeyParameter>> {
-        let mut result = params.to_vec();
-
-        // Unconditionally add the CREATION_DATETIME tag and prevent callers from
-        // specifying it.
-        if params.iter().any(|kp| kp.tag == Tag::CREATION_DATETIME) {
-            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
-                "KeystoreSecurityLevel::add_required_parameters: \
-                Specifying Tag::CREATION_DATETIME is not allowed."
-            ));
-        }
-
+        caller_uid: u32,
+        attest_key_descriptor: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+    ) -> Result<Option<AttestationKeyInfo>> {
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            get_attest_key_info(
+                key,
+                caller_uid,
+                attest_key_descriptor,
+                params,
+                &self.rem_prov_state,
+                &self.attestation_key_policy,
+                &mut db,
+            )
+            .map_err(|e| {
+                log::warn!(
+                    "Attestation key selection failed: {:?}",
+                    explain_attest_key_selection(
+                        key,
+                        caller_uid,
+                        attest_key_descriptor,
+                        params,
+                        &self.rem_prov_state,
+                        &self.attestation_key_policy,
+                        &mut db,
+                    )
+                );
+                log_attestation_failure(self.security_level, &e);
+                e
+            })
+            .map(|outcome| match outcome {
+                AttestKeyInfoOutcome::Selected(info) => Some(info),
+                AttestKeyInfoOutcome::NotSelected(reason) => {
+                    log::debug!(
+                        "No attestation key selected on {:?}: {:?}",
+                        self.security_level,
+                        reason
+                    );
+                    None
+                }
+            })
+        })
+    }
+
+    fn add_required_parameters(
+        &self,
+        uid: u32,
+        params: &[KeyParameter],
+        key: &KeyDescriptor,
+        creation_date: Option<DateTime>,
+        attestation_package: Option<&[u8]>,
+    ) -> Result<Vec<KeyParameter>> {
+        let mut result = params.to_vec();
+
+        // Unconditionally add the CREATION_DATETIME tag and prevent callers from
+        // specifying it. Callers that may choose the creation date pass it in `creation_date`.
+        if params.iter().any(|kp| kp.tag == Tag::CREATION_DATETIME) {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                "KeystoreSecurityLevel::add_required_parameters: \
+                Specifying Tag::CREATION_DATETIME is not allowed."
+            ));
+        }
+
         // Add CREATION_DATETIME only if the backend version Keymint V1 (100) or newer.
         if self.hw_info.versionNumber >= 100 {
+            let creation_datetime = match creation_date {
+                Some(creation_date) => creation_date.to_millis_epoch(),
+                None => SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .context(ks_err!(
+                        "KeystoreSecurityLevel::add_required_parameters: \
+                            Failed to get epoch time."
+                    ))?
+                    .as_millis()
+                    .try_into()
+                    .context(ks_err!(
+                        "KeystoreSecurityLevel::add_required_parameters: \
+                            Failed to convert epoch time."
+                    ))?,
+            };
             result.push(KeyParameter {
                 tag: Tag::CREATION_DATETIME,
-                value: KeyParameterValue::DateTime(
-                    SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .context(ks_err!(
-                            "KeystoreSecurityLevel::add_required_parameters: \
-                                Failed to get epoch time."
-                        ))?
-                        .as_millis()
-                        .try_into()
-                        .context(ks_err!(
-                            "KeystoreSecurityLevel::add_required_parameters: \
-                                Failed to convert epoch time."
-                        ))?,
-                ),
+                value: KeyParameterValue::DateTime(creation_datetime),
             });
         }
 
@@ -445,6 +1455,11 @@ impl KeystoreSecurityLevel {
                 keystore2_aaid::get_aaid(uid)
                     .map_err(|e| anyhow!(ks_err!("get_aaid returned status {}.", e)))
             }?;
+            // The application id of a shared UID lists all of its packages, unless the caller
+            // named one of them or the policy requires it to.
+            let aaid = SharedUidAttestationPolicy::from_property()
+                .apply(aaid, attestation_package)
+                .context(ks_err!())?;
 
             result.push(KeyParameter {
                 tag: Tag::ATTESTATION_APPLICATION_ID,
@@ -473,36 +1488,115 @@ impl KeystoreSecurityLevel {
         }
 
         // If the caller requests any device identifier attestation tag, check that they hold the
-        // correct Android permission.
+        // correct Android permission, which also permits them to learn which IDs are provisioned.
         if params.iter().any(|kp| is_device_id_attestation_tag(kp.tag)) {
-            check_device_attestation_permissions().context(ks_err!(
-                "Caller does not have the permission to attest device identifiers."
-            ))?;
+            let provisioned = get_provisioned_attestation_ids(&SystemPropertyAttestationIds)
+                .context(ks_err!(
+                    "Caller does not have the permission to attest device identifiers."
+                ))?;
+            let unreported: Vec<Tag> = params
+                .iter()
+                .map(|kp| kp.tag)
+                .filter(|tag| ATTESTATION_ID_TAGS.contains(tag) && !provisioned.contains(tag))
+                .collect();
+            if !unreported.is_empty() {
+                log::info!(
+                    "Attestation of IDs {:?} that are not reported as provisioned.",
+                    unreported
+                );
+            }
         }
 
+        // Fail precisely if the requested IDs are known not to be provisioned, instead of
+        // letting KeyMint fail without saying which ID was missing.
+        check_attestation_ids_available(params).context(ks_err!())?;
+
+        check_ec_curve_supported(&self.hw_info, params).context(ks_err!())?;
+        FipsPolicy::from_property().check(params).context(ks_err!())?;
+        RsaKeySizePolicy::from_property().check(params).context(ks_err!())?;
+        EcCurvePolicy::from_property().check(params).context(ks_err!())?;
+        UnknownTagPolicy::from_property().check(params).context(ks_err!())?;
+
         // If we are generating/importing an asymmetric key, we need to make sure
         // that NOT_BEFORE and NOT_AFTER are present.
-        match params.iter().find(|kp| kp.tag == Tag::ALGORITHM) {
-            Some(KeyParameter { tag: _, value: KeyParameterValue::Algorithm(Algorithm::RSA) })
-            | Some(KeyParameter { tag: _, value: KeyParameterValue::Algorithm(Algorithm::EC) }) => {
-                if !params.iter().any(|kp| kp.tag == Tag::CERTIFICATE_NOT_BEFORE) {
-                    result.push(KeyParameter {
-                        tag: Tag::CERTIFICATE_NOT_BEFORE,
-                        value: KeyParameterValue::DateTime(0),
-                    })
-                }
-                if !params.iter().any(|kp| kp.tag == Tag::CERTIFICATE_NOT_AFTER) {
-                    result.push(KeyParameter {
-                        tag: Tag::CERTIFICATE_NOT_AFTER,
-                        value: KeyParameterValue::DateTime(UNDEFINED_NOT_AFTER),
-                    })
-                }
-            }
-            _ => {}
-        }
+        add_default_certificate_validity(params, &mut result);
         Ok(result)
     }
 
+    /// Returns the metadata of the key stored under `key` if it was generated on this backend at
+    /// or after `created_after` by an earlier request with the idempotency token of `request`,
+    /// see `KEY_TAG_IDEMPOTENCY_KEY`.
+    fn find_idempotent_key(
+        &self,
+        key: &KeyDescriptor,
+        caller_uid: u32,
+        request: &KeyMetaData,
+        created_after: DateTime,
+    ) -> Result<Option<KeyMetadata>> {
+        let result = DB.with(|db| {
+            db.borrow_mut().load_key_entry(
+                key,
+                KeyType::Client,
+                KeyEntryLoadBits::PUBLIC,
+                caller_uid,
+                |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
+            )
+        });
+        let (key_id_guard, key_entry) = match result {
+            Ok(r) => r,
+            Err(e) => match e.root_cause().downcast_ref::<Error>() {
+                Some(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => return Ok(None),
+                _ => return Err(e).context(ks_err!("Trying to load key.")),
+            },
+        };
+        if *key_entry.km_uuid() != self.km_uuid
+            || !key_entry.metadata().is_idempotent_retry(request, created_after)?
+        {
+            return Ok(None);
+        }
+        self.loaded_key_metadata(key_id_guard.id(), key_entry).map(Some)
+    }
+
+    /// Returns the metadata of the key with `key_id`, which an earlier request with the same
+    /// idempotency key stored while the current request generated its key. No permissions are
+    /// checked, because the store transaction found the key under the alias that the caller is
+    /// permitted to rebind.
+    fn load_idempotent_key(&self, key_id: i64) -> Result<KeyMetadata> {
+        let key = KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() };
+        let (_, key_entry) = DB
+            .with(|db| {
+                db.borrow_mut().load_key_entry(
+                    &key,
+                    KeyType::Client,
+                    KeyEntryLoadBits::PUBLIC,
+                    ThreadState::get_calling_uid(),
+                    |_, _| Ok(()),
+                )
+            })
+            .context(ks_err!("Trying to load key."))?;
+        self.loaded_key_metadata(key_id, key_entry)
+    }
+
+    fn loaded_key_metadata(&self, key_id: i64, mut key_entry: KeyEntry) -> Result<KeyMetadata> {
+        let modification_time_ms =
+            key_entry.metadata().creation_date().map_or(0, |d| d.to_millis_epoch());
+        let cert_chain = DB
+            .with(|db| {
+                SUPER_KEY.read().unwrap().take_cert_chain(&mut db.borrow_mut(), &mut key_entry)
+            })
+            .context(ks_err!("Trying to unwrap the certificate chain."))?;
+        Ok(KeyMetadata {
+            key: KeyDescriptor { domain: Domain::KEY_ID, nspace: key_id, ..Default::default() },
+            keySecurityLevel: self.security_level,
+            certificate: key_entry.take_cert(),
+            certificateChain: cert_chain,
+            modificationTimeMs: modification_time_ms,
+            authorizations: crate::utils::key_parameters_to_authorizations(
+                key_entry.into_key_parameters(),
+            ),
+        })
+    }
+
     fn generate_key(
         &self,
         key: &KeyDescriptor,
@@ -510,6 +1604,7 @@ impl KeystoreSecurityLevel {
         params: &[KeyParameter],
         flags: i32,
         _entropy: &[u8],
+        latency: &LatencyBudget,
     ) -> Result<KeyMetadata> {
         if key.domain != Domain::BLOB && key.alias.is_none() {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
@@ -517,118 +1612,202 @@ impl KeystoreSecurityLevel {
         }
         let caller_uid = ThreadState::get_calling_uid();
 
-        let key = match key.domain {
-            Domain::APP => KeyDescriptor {
-                domain: key.domain,
-                nspace: caller_uid as i64,
-                alias: key.alias.clone(),
-                blob: None,
-            },
-            _ => key.clone(),
-        };
+        let key = resolve_key_namespace(key, caller_uid);
 
         // generate_key requires the rebind permission.
         // Must return on error for security reasons.
         check_key_permission(KeyPerm::Rebind, &key, &None).context(ks_err!())?;
 
+        self.generate_key_as(caller_uid, key, attest_key_descriptor, params, flags, latency)
+    }
+
+    /// Generates the key `key` like `generate_key` on behalf of `caller_uid`. The namespace of
+    /// `key` must be resolved already, and the caller's permission to rebind it must be checked.
+    fn generate_key_as(
+        &self,
+        caller_uid: u32,
+        key: KeyDescriptor,
+        attest_key_descriptor: Option<&KeyDescriptor>,
+        params: &[KeyParameter],
+        flags: i32,
+        latency: &LatencyBudget,
+    ) -> Result<KeyMetadata> {
+        // The keystore specific parameters are never passed to KeyMint, see `crate::key_tags`.
+        let (keystore_params, remaining_params) =
+            KeystoreParams::take(params).context(ks_err!())?;
+        let params = remaining_params.as_deref().unwrap_or(params);
+
+        let attestation_package =
+            attestation_package(params, keystore_params.attestation_package.as_deref())
+                .context(ks_err!())?;
+
+        let templated_params =
+            apply_attestation_template(params, keystore_params.attestation_template.as_deref())
+                .context(ks_err!())?;
+        let params = templated_params.as_deref().unwrap_or(params);
+
+        // Fill in the parameters that the caller omitted from the defaults of the backend.
+        let defaulted_params = self.generation_defaults.apply(params);
+        let params = defaulted_params.as_deref().unwrap_or(params);
+
+        let mut extra_key_metadata = Vec::new();
+        let mut idempotent_retry_after = None;
+        if let Some(idempotency_key) =
+            keystore_params.idempotency_key.as_ref().filter(|_| key.domain != Domain::BLOB)
+        {
+            let (token, params_digest) =
+                idempotency_digests(idempotency_key, params).context(ks_err!())?;
+            // A rolled back clock would extend the idempotency window.
+            if CLOCK_ROLLBACK_DETECTOR.refuse_expiry_extension() {
+                log::warn!("Not honoring idempotent retry, because the clock is suspicious.");
+            } else {
+                let now = DateTime::now().context(ks_err!("Trying to get current time."))?;
+                let created_after = idempotency_window_start(now);
+                let mut request = KeyMetaData::new();
+                request.add(KeyMetaEntry::IdempotencyToken(token.clone()));
+                request.add(KeyMetaEntry::IdempotencyParamsDigest(params_digest.clone()));
+                if let Some(metadata) = latency
+                    .measure(GenerationStep::Database, || {
+                        self.find_idempotent_key(&key, caller_uid, &request, created_after)
+                    })
+                    .context(ks_err!("Trying to find key of previous request."))?
+                {
+                    return Ok(metadata);
+                }
+                // A concurrent request may store its key while this one generates its key. The
+                // store transaction checks for that again.
+                idempotent_retry_after = Some(created_after);
+            }
+            extra_key_metadata.push(KeyMetaEntry::IdempotencyToken(token));
+            extra_key_metadata.push(KeyMetaEntry::IdempotencyParamsDigest(params_digest));
+        }
+
+        if let Some(expiry_date) =
+            expiry_date_to_record(params, flags, key.domain).context(ks_err!())?
+        {
+            extra_key_metadata.push(KeyMetaEntry::ExpiryDate(expiry_date));
+        }
+
+        check_rsa_pss_params(params).context(ks_err!())?;
+        check_attestation_challenge_attestable(params).context(ks_err!())?;
+
+        let hashed_params = latency
+            .measure(GenerationStep::Crypto, || hash_oversized_attestation_challenge(params, flags))
+            .context(ks_err!())?;
+        if hashed_params.is_some() {
+            extra_key_metadata.push(KeyMetaEntry::AttestationChallengeHashed(true));
+        }
+        let params = hashed_params.as_deref().unwrap_or(params);
+
+        let test_creation =
+            take_test_creation_datetime(params, flags, caller_uid).context(ks_err!())?;
+        let creation_date = test_creation.as_ref().map(|(creation_date, _)| *creation_date);
+        let params = test_creation.as_ref().map_or(params, |(_, params)| params.as_slice());
+
+        check_test_certificate_validity(params, flags, caller_uid).context(ks_err!())?;
+
+        let boot_state_override =
+            test_boot_state_override(params, flags, caller_uid).context(ks_err!())?;
+
+        let challenge_to_verify =
+            attestation_challenge_to_verify(params, flags).context(ks_err!())?;
+
         let attestation_key_info = match (key.domain, attest_key_descriptor) {
             (Domain::BLOB, _) => None,
-            _ => DB
-                .with(|db| {
-                    get_attest_key_info(
-                        &key,
-                        caller_uid,
-                        attest_key_descriptor,
-                        params,
-                        &self.rem_prov_state,
-                        &mut db.borrow_mut(),
-                    )
+            _ => latency
+                .measure(GenerationStep::Attestation, || {
+                    self.get_attest_key_info(&key, caller_uid, attest_key_descriptor, params)
                 })
                 .context(ks_err!("Trying to get an attestation key"))?,
         };
         let params = self
-            .add_required_parameters(caller_uid, params, &key)
+            .add_required_parameters(caller_uid, params, &key, creation_date, attestation_package)
             .context(ks_err!("Trying to get aaid."))?;
+        let params = match &boot_state_override {
+            Some(boot_state) => boot_state.apply(&params),
+            None => params,
+        };
 
-        let creation_result = match attestation_key_info {
-            Some(AttestationKeyInfo::UserGenerated {
-                key_id_guard,
-                blob,
-                blob_metadata,
-                issuer_subject,
-            }) => self
-                .upgrade_keyblob_if_required_with(
-                    &*self.keymint,
-                    Some(key_id_guard),
-                    &KeyBlob::Ref(&blob),
-                    blob_metadata.km_uuid().copied(),
-                    &params,
-                    |blob| {
-                        let attest_key = Some(AttestationKey {
-                            keyBlob: blob.to_vec(),
-                            attestKeyParams: vec![],
-                            issuerSubjectName: issuer_subject.clone(),
-                        });
-                        map_km_error({
-                            let _wp = self.watch_millis(
-                                concat!(
-                                    "In KeystoreSecurityLevel::generate_key (UserGenerated): ",
-                                    "calling generate_key."
-                                ),
-                                5000, // Generate can take a little longer.
-                            );
-                            self.keymint.generateKey(&params, attest_key.as_ref())
-                        })
-                    },
-                )
-                .context(ks_err!("Using user generated attestation key."))
-                .map(|(result, _)| result),
-            Some(AttestationKeyInfo::RkpdProvisioned { attestation_key, attestation_certs }) => {
-                self.upgrade_rkpd_keyblob_if_required_with(&attestation_key.keyBlob, &[], |blob| {
+        let creation_result = latency
+            .measure(GenerationStep::KeyMint, || {
+                self.create_key_with_attestation(attestation_key_info, &params, |attest_key| {
                     map_km_error({
-                        let _wp = self.watch_millis(
-                            concat!(
-                                "In KeystoreSecurityLevel::generate_key (RkpdProvisioned): ",
-                                "calling generate_key.",
-                            ),
-                            5000, // Generate can take a little longer.
+                        let _wp = self.watch_keymint(
+                            KeyMintCallType::of_generation(&params),
+                            "In KeystoreSecurityLevel::generate_key: calling generate_key.",
                         );
-                        let dynamic_attest_key = Some(AttestationKey {
-                            keyBlob: blob.to_vec(),
-                            attestKeyParams: vec![],
-                            issuerSubjectName: attestation_key.issuerSubjectName.clone(),
-                        });
-                        self.keymint.generateKey(&params, dynamic_attest_key.as_ref())
+                        self.keymint.generateKey(&params, attest_key)
                     })
                 })
-                .context(ks_err!("While generating Key with remote provisioned attestation key."))
-                .map(|(mut result, _)| {
-                    result.certificateChain.push(attestation_certs);
-                    result
-                })
+            })
+            .map_err(|e| {
+                if params.iter().any(|kp| kp.tag == Tag::ATTESTATION_CHALLENGE) {
+                    log_attestation_failure(self.security_level, &e);
+                }
+                e
+            })
+            .context(ks_err!())?;
+
+        if let Some(challenge) = challenge_to_verify {
+            if let Err(e) = latency.measure(GenerationStep::Attestation, || {
+                verify_attestation(&creation_result.certificateChain, &challenge)
+            }) {
+                self.delete_discarded_key(
+                    &creation_result.keyBlob,
+                    "a key with unverified attestation",
+                );
+                let e = anyhow::Error::new(Error::Km(ErrorCode::VERIFICATION_FAILED))
+                    .context(ks_err!("Attestation verification failed: {}", e));
+                log_attestation_failure(self.security_level, &e);
+                return Err(e);
             }
-            None => map_km_error({
-                let _wp = self.watch_millis(
-                    concat!(
-                        "In KeystoreSecurityLevel::generate_key (No attestation): ",
-                        "calling generate_key.",
-                    ),
-                    5000, // Generate can take a little longer.
+        }
+
+        if let Some(boot_state) = boot_state_override {
+            let attested = match creation_result.certificateChain.first() {
+                Some(leaf) => boot_state.check_attestation(&leaf.encodedCertificate),
+                None => Err(Error::Km(ErrorCode::VERIFICATION_FAILED))
+                    .context(ks_err!("No attestation certificate was returned.")),
+            };
+            if let Err(e) = attested {
+                self.delete_discarded_key(
+                    &creation_result.keyBlob,
+                    "a key with unverified attestation",
                 );
-                self.keymint.generateKey(&params, None)
-            })
-            .context(ks_err!("While generating Key without explicit attestation key.")),
+                return Err(e).context(ks_err!("KeyMint did not attest the verified boot state."));
+            }
         }
-        .context(ks_err!())?;
 
         let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context(ks_err!())
+        self.store_new_key(
+            key,
+            creation_result,
+            user_id,
+            Some(flags),
+            creation_date,
+            extra_key_metadata,
+            idempotent_retry_after,
+            latency,
+        )
+        .context(ks_err!())
+    }
+
+    /// Deletes the new key `key_blob` from KeyMint if it is not stored after all, e.g., because
+    /// its attestation failed verification. `what` describes the key in the log.
+    fn delete_discarded_key(&self, key_blob: &[u8], what: &str) {
+        let _wp = self.watch_keymint(
+            KeyMintCallType::Other,
+            "In KeystoreSecurityLevel::generate_key: calling deleteKey",
+        );
+        if let Err(e) = map_km_error(self.keymint.deleteKey(key_blob)) {
+            log::warn!("Failed to delete {}: {:?}", what, e);
+        }
     }
 
     fn import_key(
         &self,
         key: &KeyDescriptor,
-        _attestation_key: Option<&KeyDescriptor>,
+        attest_key_descriptor: Option<&KeyDescriptor>,
         params: &[KeyParameter],
         flags: i32,
         key_data: &[u8],
@@ -637,23 +1816,31 @@ impl KeystoreSecurityLevel {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
                 .context(ks_err!("Alias must be specified"));
         }
+        check_import_size("Key data", key_data).context(ks_err!())?;
         let caller_uid = ThreadState::get_calling_uid();
 
-        let key = match key.domain {
-            Domain::APP => KeyDescriptor {
-                domain: key.domain,
-                nspace: caller_uid as i64,
-                alias: key.alias.clone(),
-                blob: None,
-            },
-            _ => key.clone(),
-        };
+        let key = resolve_key_namespace(key, caller_uid);
 
         // import_key requires the rebind permission.
         check_key_permission(KeyPerm::Rebind, &key, &None).context(ks_err!("In import_key."))?;
 
+        check_import_purposes(params).context(ks_err!())?;
+        if let Some(kp) = params.iter().find(|kp| is_keystore_tag(kp.tag)) {
+            return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context(ks_err!("Tag {:?} only applies to key generation.", kp.tag));
+        }
+
+        // Imported keys are attested like generated keys. KeyMint records the origin of the key
+        // as KeyOrigin::IMPORTED in the attestation, so that relying parties can tell imported
+        // keys apart from keys that never left the secure hardware.
+        let attestation_key_info = match (key.domain, attest_key_descriptor) {
+            (Domain::BLOB, _) => None,
+            _ => self
+                .get_attest_key_info(&key, caller_uid, attest_key_descriptor, params)
+                .context(ks_err!("Trying to get an attestation key"))?,
+        };
         let params = self
-            .add_required_parameters(caller_uid, params, &key)
+            .add_required_parameters(caller_uid, params, &key, None, None)
             .context(ks_err!("Trying to get aaid."))?;
 
         let format = params
@@ -672,16 +1859,48 @@ impl KeystoreSecurityLevel {
             })
             .context(ks_err!())?;
 
-        let km_dev = &self.keymint;
-        let creation_result = map_km_error({
-            let _wp =
-                self.watch_millis("In KeystoreSecurityLevel::import_key: calling importKey.", 500);
-            km_dev.importKey(&params, format, key_data, None /* attestKey */)
-        })
-        .context(ks_err!("Trying to call importKey"))?;
+        let creation_result = self
+            .create_key_with_attestation(attestation_key_info, &params, |attest_key| {
+                map_km_error({
+                    let _wp = self.watch_keymint(
+                        KeyMintCallType::Import,
+                        "In KeystoreSecurityLevel::import_key: calling importKey.",
+                    );
+                    self.keymint.importKey(&params, format, key_data, attest_key)
+                })
+            })
+            .context(ks_err!("Trying to call importKey"))?;
+
+        // The size and curve of the imported key are only known to KeyMint, if the caller did not
+        // specify them.
+        let authorizations: Vec<KeyParameter> = creation_result
+            .keyCharacteristics
+            .iter()
+            .flat_map(|c| c.authorizations.iter().cloned())
+            .collect();
+        RsaKeySizePolicy::from_property().check(&authorizations).context(ks_err!())?;
+        EcCurvePolicy::from_property().check(&authorizations).context(ks_err!())?;
+        check_rsa_pss_params(&authorizations).context(ks_err!())?;
+
+        let purposes = declared_purposes(&params);
+        if has_broad_purposes(&purposes) {
+            if let Some(algorithm) = declared_algorithm(&params) {
+                log_imported_key_with_broad_purposes(self.security_level, algorithm, &purposes);
+            }
+        }
 
         let user_id = uid_to_android_user(caller_uid);
-        self.store_new_key(key, creation_result, user_id, Some(flags)).context(ks_err!())
+        self.store_new_key(
+            key,
+            creation_result,
+            user_id,
+            Some(flags),
+            None,
+            vec![],
+            None,
+            &LatencyBudget::default(),
+        )
+        .context(ks_err!())
     }
 
     fn import_wrapped_key(
@@ -704,6 +1923,10 @@ impl KeystoreSecurityLevel {
                 ));
             }
         };
+        check_import_size("Wrapped key data", wrapped_data).context(ks_err!())?;
+        if let Some(masking_key) = masking_key {
+            check_import_size("Masking key", masking_key).context(ks_err!())?;
+        }
 
         if wrapping_key.domain == Domain::BLOB {
             return Err(error::Error::Km(ErrorCode::INVALID_ARGUMENT))
@@ -714,18 +1937,9 @@ impl KeystoreSecurityLevel {
         let user_id = uid_to_android_user(caller_uid);
 
         let key = match key.domain {
-            Domain::APP => KeyDescriptor {
-                domain: key.domain,
-                nspace: caller_uid as i64,
-                alias: key.alias.clone(),
-                blob: None,
-            },
-            Domain::SELINUX => KeyDescriptor {
-                domain: Domain::SELINUX,
-                nspace: key.nspace,
-                alias: key.alias.clone(),
-                blob: None,
-            },
+            Domain::APP | Domain::SELINUX => {
+                KeyDescriptor { blob: None, ..resolve_key_namespace(key, caller_uid) }
+            }
             _ => panic!("Unreachable."),
         };
 
@@ -748,15 +1962,33 @@ impl KeystoreSecurityLevel {
             })
             .context(ks_err!("Failed to load wrapping key."))?;
 
+        if wrapping_key_entry.is_frozen() {
+            return Err(error::Error::perm()).context(ks_err!("Wrapping key is frozen."));
+        }
+
         let (wrapping_key_blob, wrapping_blob_metadata) =
             wrapping_key_entry.take_key_blob_info().ok_or_else(error::Error::sys).context(
                 ks_err!("No km_blob after successfully loading key. This should never happen."),
             )?;
 
-        let wrapping_key_blob = SUPER_KEY
-            .read()
-            .unwrap()
-            .unwrap_key_if_required(&wrapping_blob_metadata, &wrapping_key_blob)
+        let wrapping_binding = DB
+            .with(|db| {
+                BlobBinding::load_if_bound(
+                    &mut db.borrow_mut(),
+                    wrapping_key_id_guard.id(),
+                    &wrapping_blob_metadata,
+                )
+            })
+            .context(ks_err!())?;
+        let wrapping_key_blob = SuperKeyWaitPolicy::from_property()
+            .run(|| {
+                SUPER_KEY.read().unwrap().unwrap_key_for_operation(
+                    &wrapping_blob_metadata,
+                    &wrapping_key_blob,
+                    wrapping_binding.as_ref(),
+                    KeyPurpose::WRAP_KEY,
+                )
+            })
             .context(ks_err!("Failed to handle super encryption for wrapping key."))?;
 
         // km_dev.importWrappedKey does not return a certificate chain.
@@ -789,9 +2021,9 @@ impl KeystoreSecurityLevel {
                 wrapping_blob_metadata.km_uuid().copied(),
                 &[],
                 |wrapping_blob| {
-                    let _wp = self.watch_millis(
+                    let _wp = self.watch_keymint(
+                        KeyMintCallType::Import,
                         "In KeystoreSecurityLevel::import_wrapped_key: calling importWrappedKey.",
-                        500,
                     );
                     let creation_result = map_km_error(self.keymint.importWrappedKey(
                         wrapped_data,
@@ -806,8 +2038,17 @@ impl KeystoreSecurityLevel {
             )
             .context(ks_err!())?;
 
-        self.store_new_key(key, creation_result, user_id, None)
-            .context(ks_err!("Trying to store the new key."))
+        self.store_new_key(
+            key,
+            creation_result,
+            user_id,
+            None,
+            None,
+            vec![],
+            None,
+            &LatencyBudget::default(),
+        )
+        .context(ks_err!("Trying to store the new key."))
     }
 
     fn store_upgraded_keyblob(
@@ -816,17 +2057,19 @@ impl KeystoreSecurityLevel {
         key_blob: &KeyBlob,
         upgraded_blob: &[u8],
     ) -> Result<()> {
-        let (upgraded_blob_to_be_stored, new_blob_metadata) =
-            SuperKeyManager::reencrypt_if_required(key_blob, upgraded_blob)
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let (upgraded_blob_to_be_stored, new_blob_metadata) =
+                SuperKeyManager::reencrypt_if_required(key_blob, upgraded_blob, || {
+                    BlobBinding::load(&mut db, key_id_guard.id())
+                })
                 .context(ks_err!("Failed to handle super encryption."))?;
 
-        let mut new_blob_metadata = new_blob_metadata.unwrap_or_default();
-        if let Some(uuid) = km_uuid {
-            new_blob_metadata.add(BlobMetaEntry::KmUuid(uuid));
-        }
+            let mut new_blob_metadata = new_blob_metadata.unwrap_or_default();
+            if let Some(uuid) = km_uuid {
+                new_blob_metadata.add(BlobMetaEntry::KmUuid(uuid));
+            }
 
-        DB.with(|db| {
-            let mut db = db.borrow_mut();
             db.set_blob(
                 &key_id_guard,
                 SubComponentType::KEY_BLOB,
@@ -837,6 +2080,87 @@ impl KeystoreSecurityLevel {
         .context(ks_err!("Failed to insert upgraded blob into the database."))
     }
 
+    /// Calls `create` with the attestation key described by `attestation_key_info`, upgrading
+    /// the attestation key first if required. Certificates of remote provisioned attestation keys
+    /// are appended to the certificate chain of the new key.
+    fn create_key_with_attestation<F>(
+        &self,
+        attestation_key_info: Option<AttestationKeyInfo>,
+        params: &[KeyParameter],
+        create: F,
+    ) -> Result<KeyCreationResult>
+    where
+        F: Fn(Option<&AttestationKey>) -> Result<KeyCreationResult, Error>,
+    {
+        if let Some(info) = &attestation_key_info {
+            log::debug!("Creating key with attestation key {}.", info.summary());
+        }
+        match attestation_key_info {
+            Some(AttestationKeyInfo::UserGenerated {
+                key_id_guard,
+                blob,
+                blob_metadata,
+                issuer_subject,
+            }) => self
+                .upgrade_keyblob_if_required_with(
+                    &*self.keymint,
+                    Some(key_id_guard),
+                    &KeyBlob::Ref(&blob),
+                    blob_metadata.km_uuid().copied(),
+                    params,
+                    |blob| {
+                        create(Some(&AttestationKey {
+                            keyBlob: blob.to_vec(),
+                            attestKeyParams: vec![],
+                            issuerSubjectName: issuer_subject.clone(),
+                        }))
+                    },
+                )
+                .context(ks_err!("Using user generated attestation key."))
+                .map(|(result, _)| result),
+            Some(AttestationKeyInfo::RemoteProvisioned {
+                key_id_guard,
+                attestation_key,
+                attestation_certs,
+            }) => self
+                .upgrade_keyblob_if_required_with(
+                    &*self.keymint,
+                    Some(key_id_guard),
+                    &KeyBlob::Ref(&attestation_key.keyBlob),
+                    Some(self.rem_prov_state.get_uuid()),
+                    &[],
+                    |blob| {
+                        create(Some(&AttestationKey {
+                            keyBlob: blob.to_vec(),
+                            attestKeyParams: vec![],
+                            issuerSubjectName: attestation_key.issuerSubjectName.clone(),
+                        }))
+                    },
+                )
+                .context(ks_err!("While creating key with remote provisioned attestation key."))
+                .map(|(mut result, _)| {
+                    result.certificateChain.push(attestation_certs);
+                    result
+                }),
+            Some(AttestationKeyInfo::RkpdProvisioned { attestation_key, attestation_certs }) => {
+                self.upgrade_rkpd_keyblob_if_required_with(&attestation_key.keyBlob, &[], |blob| {
+                    create(Some(&AttestationKey {
+                        keyBlob: blob.to_vec(),
+                        attestKeyParams: vec![],
+                        issuerSubjectName: attestation_key.issuerSubjectName.clone(),
+                    }))
+                })
+                .context(ks_err!("While creating key with remote provisioned attestation key."))
+                .map(|(mut result, _)| {
+                    result.certificateChain.push(attestation_certs);
+                    result
+                })
+            }
+            None => create(None)
+                .context(ks_err!("While creating key without explicit attestation key.")),
+        }
+    }
+
     fn upgrade_keyblob_if_required_with<T, F>(
         &self,
         km_dev: &dyn IKeyMintDevice,
@@ -894,8 +2218,13 @@ impl KeystoreSecurityLevel {
             params,
             f,
             |upgraded_blob| {
-                store_rkpd_attestation_key(&self.security_level, key_blob, upgraded_blob)
-                    .context(ks_err!("Failed store_rkpd_attestation_key()."))
+                store_rkpd_attestation_key(
+                    &self.security_level,
+                    &self.instance,
+                    key_blob,
+                    upgraded_blob,
+                )
+                .context(ks_err!("Failed store_rkpd_attestation_key()."))
             },
         )
         .context(ks_err!())
@@ -921,12 +2250,12 @@ impl KeystoreSecurityLevel {
 
         let km_dev = &self.keymint;
         match {
-            let _wp = self.watch_millis(
+            let _wp = self.watch_keymint(
+                KeyMintCallType::Other,
                 concat!(
                     "In IKeystoreSecurityLevel::convert_storage_key_to_ephemeral: ",
                     "calling convertStorageKeyToEphemeral (1)"
                 ),
-                500,
             );
             map_km_error(km_dev.convertStorageKeyToEphemeral(key_blob))
         } {
@@ -935,17 +2264,17 @@ impl KeystoreSecurityLevel {
             }
             Err(error::Error::Km(ErrorCode::KEY_REQUIRES_UPGRADE)) => {
                 let upgraded_blob = {
-                    let _wp = self.watch_millis(
+                    let _wp = self.watch_keymint(
+                        KeyMintCallType::Other,
                         "In convert_storage_key_to_ephemeral: calling upgradeKey",
-                        500,
                     );
                     map_km_error(km_dev.upgradeKey(key_blob, &[]))
                 }
                 .context(ks_err!("Failed to upgrade key blob."))?;
                 let ephemeral_key = {
-                    let _wp = self.watch_millis(
+                    let _wp = self.watch_keymint(
+                        KeyMintCallType::Other,
                         "In convert_storage_key_to_ephemeral: calling convertStorageKeyToEphemeral (2)",
-                        500,
                     );
                     map_km_error(km_dev.convertStorageKeyToEphemeral(&upgraded_blob))
                 }
@@ -978,13 +2307,39 @@ impl KeystoreSecurityLevel {
 
         let km_dev = &self.keymint;
         {
-            let _wp =
-                self.watch_millis("In KeystoreSecuritylevel::delete_key: calling deleteKey", 500);
+            let _wp = self.watch_keymint(
+                KeyMintCallType::Other,
+                "In KeystoreSecuritylevel::delete_key: calling deleteKey",
+            );
             map_km_error(km_dev.deleteKey(key_blob)).context(ks_err!("keymint device deleteKey"))
         }
     }
 }
 
+impl AttestationPipeline for KeystoreSecurityLevel {
+    fn generate_attested_key(
+        &self,
+        key: &KeyDescriptor,
+        params: &[KeyParameter],
+    ) -> Result<KeyMetadata> {
+        // Outside of a binder transaction, the calling UID is the UID of keystore itself.
+        let latency = LatencyBudget::start();
+        self.generate_key_as(ThreadState::get_calling_uid(), key.clone(), None, params, 0, &latency)
+    }
+
+    fn delete_attested_key(&self, key: &KeyDescriptor) -> Result<()> {
+        DB.with(|db| {
+            db.borrow_mut().unbind_key(
+                key,
+                KeyType::Client,
+                ThreadState::get_calling_uid(),
+                |_, _| Ok(()),
+            )
+        })
+        .context(ks_err!())
+    }
+}
+
 impl binder::Interface for KeystoreSecurityLevel {}
 
 impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
@@ -995,7 +2350,10 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         forced: bool,
     ) -> binder::Result<CreateOperationResponse> {
         let _wp = self.watch_millis("IKeystoreSecurityLevel::createOperation", 500);
-        map_or_log_err(self.create_operation(key, operation_parameters, forced), Ok)
+        map_or_log_err(
+            self.circuit_breaker.call(|| self.create_operation(key, operation_parameters, forced)),
+            Ok,
+        )
     }
     fn generateKey(
         &self,
@@ -1008,7 +2366,11 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         // Duration is set to 5 seconds, because generateKey - especially for RSA keys, takes more
         // time than other operations
         let _wp = self.watch_millis("IKeystoreSecurityLevel::generateKey", 5000);
-        let result = self.generate_key(key, attestation_key, params, flags, entropy);
+        let latency = LatencyBudget::start();
+        let result = self
+            .circuit_breaker
+            .call(|| self.generate_key(key, attestation_key, params, flags, entropy, &latency));
+        latency.finish(self.security_level);
         log_key_creation_event_stats(self.security_level, params, &result);
         log_key_generated(key, ThreadState::get_calling_uid(), result.is_ok());
         map_or_log_err(result, Ok)
@@ -1022,7 +2384,9 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         key_data: &[u8],
     ) -> binder::Result<KeyMetadata> {
         let _wp = self.watch_millis("IKeystoreSecurityLevel::importKey", 500);
-        let result = self.import_key(key, attestation_key, params, flags, key_data);
+        let result = self
+            .circuit_breaker
+            .call(|| self.import_key(key, attestation_key, params, flags, key_data));
         log_key_creation_event_stats(self.security_level, params, &result);
         log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
         map_or_log_err(result, Ok)
@@ -1036,8 +2400,9 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         authenticators: &[AuthenticatorSpec],
     ) -> binder::Result<KeyMetadata> {
         let _wp = self.watch_millis("IKeystoreSecurityLevel::importWrappedKey", 500);
-        let result =
-            self.import_wrapped_key(key, wrapping_key, masking_key, params, authenticators);
+        let result = self.circuit_breaker.call(|| {
+            self.import_wrapped_key(key, wrapping_key, masking_key, params, authenticators)
+        });
         log_key_creation_event_stats(self.security_level, params, &result);
         log_key_imported(key, ThreadState::get_calling_uid(), result.is_ok());
         map_or_log_err(result, Ok)
@@ -1056,3 +2421,833 @@ impl IKeystoreSecurityLevel for KeystoreSecurityLevel {
         map_or_log_err(result, Ok)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+    use crate::database::{tests::new_test_db, BlobInfo, KEYSTORE_UUID};
+
+    fn hw_info(security_level: SecurityLevel, version_number: i32) -> KeyMintHardwareInfo {
+        KeyMintHardwareInfo {
+            versionNumber: version_number,
+            securityLevel: security_level,
+            ..Default::default()
+        }
+    }
+
+    fn ec_params(curve: EcCurve) -> Vec<KeyParameter> {
+        vec![
+            KeyParameter {
+                tag: Tag::ALGORITHM,
+                value: KeyParameterValue::Algorithm(Algorithm::EC),
+            },
+            KeyParameter { tag: Tag::EC_CURVE, value: KeyParameterValue::EcCurve(curve) },
+        ]
+    }
+
+    fn assert_unsupported_curve(result: Result<()>) {
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::UNSUPPORTED_EC_CURVE))
+        );
+    }
+
+    #[test]
+    fn test_ec_curves_on_supporting_backend() {
+        let tee = hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, 200);
+        for curve in [EcCurve::P_224, EcCurve::P_256, EcCurve::P_384, EcCurve::P_521] {
+            assert!(check_ec_curve_supported(&tee, &ec_params(curve)).is_ok());
+        }
+        assert!(check_ec_curve_supported(&tee, &ec_params(EcCurve::CURVE_25519)).is_ok());
+    }
+
+    #[test]
+    fn test_ec_curves_on_limited_backend() {
+        let old_tee = hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, 100);
+        assert!(check_ec_curve_supported(&old_tee, &ec_params(EcCurve::P_521)).is_ok());
+        assert_unsupported_curve(check_ec_curve_supported(
+            &old_tee,
+            &ec_params(EcCurve::CURVE_25519),
+        ));
+
+        let strongbox = hw_info(SecurityLevel::STRONGBOX, 300);
+        assert!(check_ec_curve_supported(&strongbox, &ec_params(EcCurve::P_256)).is_ok());
+        assert_unsupported_curve(check_ec_curve_supported(&strongbox, &ec_params(EcCurve::P_521)));
+        assert_unsupported_curve(check_ec_curve_supported(
+            &strongbox,
+            &ec_params(EcCurve::CURVE_25519),
+        ));
+    }
+
+    #[test]
+    fn test_no_ec_curve() {
+        let strongbox = hw_info(SecurityLevel::STRONGBOX, 100);
+        let params = vec![KeyParameter {
+            tag: Tag::ALGORITHM,
+            value: KeyParameterValue::Algorithm(Algorithm::RSA),
+        }];
+        assert!(check_ec_curve_supported(&strongbox, &params).is_ok());
+    }
+
+    #[test]
+    fn test_configured_ec_curves() {
+        assert_eq!(
+            parse_ec_curves("P_256, P_521,CURVE_25519"),
+            Some(vec![EcCurve::P_256, EcCurve::P_521, EcCurve::CURVE_25519])
+        );
+        assert_eq!(parse_ec_curves("P_256,P_192"), None);
+        assert_eq!(parse_ec_curves(""), None);
+
+        // A StrongBox that supports P-521 can be configured to allow it, and a TEE can be
+        // restricted to fewer curves than its KeyMint version implies.
+        let strongbox = hw_info(SecurityLevel::STRONGBOX, 300);
+        let curves = [EcCurve::P_256, EcCurve::P_521];
+        assert!(check_ec_curve_in(&curves, &strongbox, &ec_params(EcCurve::P_521)).is_ok());
+        assert_unsupported_curve(check_ec_curve_in(
+            &curves,
+            &strongbox,
+            &ec_params(EcCurve::P_384),
+        ));
+        let tee = hw_info(SecurityLevel::TRUSTED_ENVIRONMENT, 300);
+        assert_unsupported_curve(check_ec_curve_in(
+            &[EcCurve::P_256],
+            &tee,
+            &ec_params(EcCurve::CURVE_25519),
+        ));
+    }
+
+    fn pss_params(key_size: i32, extra: &[(Tag, KeyParameterValue)]) -> Vec<KeyParameter> {
+        let mut params = vec![
+            KeyParameter {
+                tag: Tag::ALGORITHM,
+                value: KeyParameterValue::Algorithm(Algorithm::RSA),
+            },
+            KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Integer(key_size) },
+            KeyParameter {
+                tag: Tag::PADDING,
+                value: KeyParameterValue::PaddingMode(PaddingMode::RSA_PSS),
+            },
+        ];
+        params.extend(
+            extra.iter().map(|(tag, value)| KeyParameter { tag: *tag, value: value.clone() }),
+        );
+        params
+    }
+
+    fn sign() -> (Tag, KeyParameterValue) {
+        (Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::SIGN))
+    }
+
+    fn digest(digest: Digest) -> (Tag, KeyParameterValue) {
+        (Tag::DIGEST, KeyParameterValue::Digest(digest))
+    }
+
+    fn mgf_digest(digest: Digest) -> (Tag, KeyParameterValue) {
+        (Tag::RSA_OAEP_MGF_DIGEST, KeyParameterValue::Digest(digest))
+    }
+
+    fn assert_pss_error(params: &[KeyParameter], error_code: ErrorCode) {
+        assert_eq!(
+            check_rsa_pss_params(params).unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(error_code))
+        );
+    }
+
+    #[test]
+    fn test_valid_rsa_pss_params() {
+        assert!(
+            check_rsa_pss_params(&pss_params(2048, &[sign(), digest(Digest::SHA_2_256)])).is_ok()
+        );
+        // SHA-512 needs 130 bytes, which a 1040 bit key has.
+        assert!(
+            check_rsa_pss_params(&pss_params(1040, &[sign(), digest(Digest::SHA_2_512)])).is_ok()
+        );
+        // One usable digest is enough.
+        assert!(check_rsa_pss_params(&pss_params(
+            512,
+            &[sign(), digest(Digest::NONE), digest(Digest::SHA_2_512), digest(Digest::SHA1)]
+        ))
+        .is_ok());
+        assert!(check_rsa_pss_params(&pss_params(
+            2048,
+            &[sign(), digest(Digest::SHA_2_256), mgf_digest(Digest::SHA_2_256)]
+        ))
+        .is_ok());
+        // The MGF1 digest may differ if the key is also meant for OAEP.
+        let mut params = pss_params(
+            2048,
+            &[
+                sign(),
+                (Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::DECRYPT)),
+                digest(Digest::SHA_2_256),
+                mgf_digest(Digest::SHA1),
+            ],
+        );
+        params.push(KeyParameter {
+            tag: Tag::PADDING,
+            value: KeyParameterValue::PaddingMode(PaddingMode::RSA_OAEP),
+        });
+        assert!(check_rsa_pss_params(&params).is_ok());
+        // Keys without PSS padding are not checked.
+        assert!(check_rsa_pss_params(&ec_params(EcCurve::P_256)).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_rsa_pss_params() {
+        let mut params = ec_params(EcCurve::P_256);
+        params.extend(pss_params(256, &[sign(), digest(Digest::SHA_2_256)]).into_iter().skip(1));
+        assert_pss_error(&params, ErrorCode::INCOMPATIBLE_PADDING_MODE);
+
+        assert_pss_error(
+            &pss_params(
+                2048,
+                &[
+                    (Tag::PURPOSE, KeyParameterValue::KeyPurpose(KeyPurpose::ENCRYPT)),
+                    digest(Digest::SHA_2_256),
+                ],
+            ),
+            ErrorCode::INCOMPATIBLE_PURPOSE,
+        );
+
+        assert_pss_error(&pss_params(2048, &[sign()]), ErrorCode::INCOMPATIBLE_DIGEST);
+        assert_pss_error(
+            &pss_params(2048, &[sign(), digest(Digest::NONE)]),
+            ErrorCode::INCOMPATIBLE_DIGEST,
+        );
+        // SHA-512 needs 130 bytes, but a 1032 bit key has only 129.
+        assert_pss_error(
+            &pss_params(1032, &[sign(), digest(Digest::SHA_2_512)]),
+            ErrorCode::INCOMPATIBLE_DIGEST,
+        );
+
+        assert_pss_error(
+            &pss_params(2048, &[sign(), digest(Digest::SHA_2_256), mgf_digest(Digest::SHA1)]),
+            ErrorCode::INCOMPATIBLE_MGF_DIGEST,
+        );
+    }
+
+    #[test]
+    fn test_rsa_pss_operation_params() {
+        let key_params: Vec<KsKeyParam> = [
+            KsKeyParamValue::Algorithm(Algorithm::RSA),
+            KsKeyParamValue::KeySize(1032),
+            KsKeyParamValue::Digest(Digest::SHA_2_512),
+        ]
+        .into_iter()
+        .map(|v| KsKeyParam::new(v, SecurityLevel::TRUSTED_ENVIRONMENT))
+        .collect();
+        let check = |purpose, extra: &[(Tag, KeyParameterValue)]| {
+            let mut op_params = vec![KeyParameter {
+                tag: Tag::PADDING,
+                value: KeyParameterValue::PaddingMode(PaddingMode::RSA_PSS),
+            }];
+            op_params.extend(
+                extra.iter().map(|(tag, value)| KeyParameter { tag: *tag, value: value.clone() }),
+            );
+            check_rsa_pss_operation_params(purpose, &op_params, &key_params).map_err(|e| {
+                match e.root_cause().downcast_ref::<Error>() {
+                    Some(Error::Km(error_code)) => *error_code,
+                    _ => ErrorCode::UNKNOWN_ERROR,
+                }
+            })
+        };
+
+        assert!(check(KeyPurpose::SIGN, &[digest(Digest::SHA_2_256)]).is_ok());
+        // SHA-512 needs 130 bytes, but the 1032 bit key has only 129.
+        assert_eq!(
+            check(KeyPurpose::SIGN, &[digest(Digest::SHA_2_512)]),
+            Err(ErrorCode::INCOMPATIBLE_DIGEST)
+        );
+        assert_eq!(check(KeyPurpose::SIGN, &[]), Err(ErrorCode::INCOMPATIBLE_DIGEST));
+        assert_eq!(
+            check(KeyPurpose::VERIFY, &[digest(Digest::SHA_2_256), mgf_digest(Digest::SHA1)]),
+            Err(ErrorCode::INCOMPATIBLE_MGF_DIGEST)
+        );
+        assert_eq!(
+            check(KeyPurpose::ENCRYPT, &[digest(Digest::SHA_2_256)]),
+            Err(ErrorCode::INCOMPATIBLE_PURPOSE)
+        );
+        // Operations without PSS padding are not checked.
+        assert!(check_rsa_pss_operation_params(KeyPurpose::SIGN, &[], &key_params).is_ok());
+    }
+
+    fn key_params_with_purposes(purposes: &[KeyPurpose]) -> Vec<KsKeyParam> {
+        let mut params = vec![KsKeyParam::new(
+            KsKeyParamValue::Algorithm(Algorithm::EC),
+            SecurityLevel::TRUSTED_ENVIRONMENT,
+        )];
+        params.extend(purposes.iter().map(|p| {
+            KsKeyParam::new(KsKeyParamValue::KeyPurpose(*p), SecurityLevel::TRUSTED_ENVIRONMENT)
+        }));
+        params
+    }
+
+    fn assert_incompatible_purpose(result: Result<()>) {
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INCOMPATIBLE_PURPOSE))
+        );
+    }
+
+    #[test]
+    fn test_allowed_purposes() {
+        let params = key_params_with_purposes(&[KeyPurpose::SIGN, KeyPurpose::VERIFY]);
+        assert!(check_key_purpose(KeyPurpose::SIGN, &params).is_ok());
+        assert!(check_key_purpose(KeyPurpose::VERIFY, &params).is_ok());
+
+        let params = key_params_with_purposes(&[KeyPurpose::AGREE_KEY]);
+        assert!(check_key_purpose(KeyPurpose::AGREE_KEY, &params).is_ok());
+    }
+
+    #[test]
+    fn test_disallowed_purposes() {
+        let params = key_params_with_purposes(&[KeyPurpose::SIGN]);
+        assert_incompatible_purpose(check_key_purpose(KeyPurpose::DECRYPT, &params));
+        assert_incompatible_purpose(check_key_purpose(KeyPurpose::AGREE_KEY, &params));
+
+        let params = key_params_with_purposes(&[KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT]);
+        assert_incompatible_purpose(check_key_purpose(KeyPurpose::SIGN, &params));
+        assert_incompatible_purpose(check_key_purpose(KeyPurpose::WRAP_KEY, &params));
+
+        // A key without any recorded purpose cannot be used at all.
+        let params = key_params_with_purposes(&[]);
+        assert_incompatible_purpose(check_key_purpose(KeyPurpose::SIGN, &params));
+    }
+
+    fn import_params(algorithm: Algorithm, purposes: &[KeyPurpose]) -> Vec<KeyParameter> {
+        let mut params = vec![KeyParameter {
+            tag: Tag::ALGORITHM,
+            value: KeyParameterValue::Algorithm(algorithm),
+        }];
+        params.extend(
+            purposes.iter().map(|p| KeyParameter {
+                tag: Tag::PURPOSE,
+                value: KeyParameterValue::KeyPurpose(*p),
+            }),
+        );
+        params
+    }
+
+    #[test]
+    fn test_consistent_import_purposes() {
+        for (algorithm, purposes) in [
+            (Algorithm::RSA, &[KeyPurpose::SIGN, KeyPurpose::VERIFY][..]),
+            (Algorithm::RSA, &[KeyPurpose::DECRYPT, KeyPurpose::SIGN]),
+            (Algorithm::RSA, &[KeyPurpose::ATTEST_KEY]),
+            (Algorithm::EC, &[KeyPurpose::SIGN, KeyPurpose::AGREE_KEY]),
+            (Algorithm::AES, &[KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT, KeyPurpose::WRAP_KEY]),
+            (Algorithm::TRIPLE_DES, &[KeyPurpose::ENCRYPT]),
+            (Algorithm::HMAC, &[KeyPurpose::SIGN, KeyPurpose::VERIFY]),
+            (Algorithm::HMAC, &[]),
+        ] {
+            assert!(
+                check_import_purposes(&import_params(algorithm, purposes)).is_ok(),
+                "{:?} {:?}",
+                algorithm,
+                purposes
+            );
+        }
+        // A missing or unknown algorithm is not reported as a purpose mismatch.
+        assert!(check_import_purposes(&[KeyParameter {
+            tag: Tag::PURPOSE,
+            value: KeyParameterValue::KeyPurpose(KeyPurpose::SIGN),
+        }])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_conflicting_import_purposes() {
+        for (algorithm, purposes) in [
+            (Algorithm::AES, &[KeyPurpose::SIGN][..]),
+            (Algorithm::HMAC, &[KeyPurpose::SIGN, KeyPurpose::ENCRYPT]),
+            (Algorithm::EC, &[KeyPurpose::DECRYPT]),
+            (Algorithm::RSA, &[KeyPurpose::AGREE_KEY]),
+            (Algorithm::TRIPLE_DES, &[KeyPurpose::WRAP_KEY]),
+            (Algorithm::EC, &[KeyPurpose::ATTEST_KEY, KeyPurpose::SIGN]),
+            (Algorithm::AES, &[KeyPurpose::ATTEST_KEY]),
+        ] {
+            assert_incompatible_purpose(check_import_purposes(&import_params(algorithm, purposes)));
+        }
+    }
+
+    fn with_challenge(algorithm: Algorithm) -> Vec<KeyParameter> {
+        let mut params = import_params(algorithm, &[KeyPurpose::SIGN]);
+        params.push(KeyParameter {
+            tag: Tag::ATTESTATION_CHALLENGE,
+            value: KeyParameterValue::Blob(b"challenge".to_vec()),
+        });
+        params
+    }
+
+    #[test]
+    fn test_attestation_challenge_on_symmetric_key_is_rejected() {
+        for algorithm in [Algorithm::AES, Algorithm::HMAC, Algorithm::TRIPLE_DES] {
+            assert_eq!(
+                check_attestation_challenge_attestable(&with_challenge(algorithm))
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Km(ErrorCode::INVALID_ARGUMENT)),
+                "{:?}",
+                algorithm
+            );
+            // Without a challenge, symmetric keys are fine.
+            assert!(check_attestation_challenge_attestable(&import_params(algorithm, &[])).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_attestation_challenge_on_asymmetric_key_is_accepted() {
+        for algorithm in [Algorithm::RSA, Algorithm::EC] {
+            assert!(
+                check_attestation_challenge_attestable(&with_challenge(algorithm)).is_ok(),
+                "{:?}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn test_import_size_limit() {
+        let limit = 64;
+        assert!(check_import_size_with("Key data", &[0; 63], limit).is_ok());
+        assert!(check_import_size_with("Key data", &[0; 64], limit).is_ok());
+        assert_eq!(
+            check_import_size_with("Key data", &[0; 65], limit)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::TOO_MUCH_DATA))
+        );
+        // The default leaves room for the largest keys that can be imported.
+        assert!(check_import_size("Key data", &[0; 4096]).is_ok());
+    }
+
+    #[test]
+    fn test_broad_purposes() {
+        assert!(!has_broad_purposes(&[]));
+        assert!(!has_broad_purposes(&[KeyPurpose::SIGN, KeyPurpose::VERIFY]));
+        assert!(!has_broad_purposes(&[KeyPurpose::ENCRYPT, KeyPurpose::DECRYPT]));
+        assert!(!has_broad_purposes(&[KeyPurpose::ATTEST_KEY]));
+
+        assert!(has_broad_purposes(&[KeyPurpose::SIGN, KeyPurpose::DECRYPT]));
+        assert!(has_broad_purposes(&[KeyPurpose::SIGN, KeyPurpose::AGREE_KEY]));
+        assert!(has_broad_purposes(&[KeyPurpose::DECRYPT, KeyPurpose::WRAP_KEY]));
+    }
+
+    fn challenge_params(challenge: &[u8]) -> Vec<KeyParameter> {
+        vec![
+            KeyParameter {
+                tag: Tag::ALGORITHM,
+                value: KeyParameterValue::Algorithm(Algorithm::EC),
+            },
+            KeyParameter {
+                tag: Tag::ATTESTATION_CHALLENGE,
+                value: KeyParameterValue::Blob(challenge.to_vec()),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_oversized_attestation_challenge_hashed_on_opt_in() {
+        let challenge = vec![0xa5u8; MAX_ATTESTATION_CHALLENGE_LEN + 1];
+        let params = challenge_params(&challenge);
+        let hashed = hash_oversized_attestation_challenge(
+            &params,
+            KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE,
+        )
+        .unwrap()
+        .expect("Oversized challenge should have been hashed.");
+        assert_eq!(hashed.len(), params.len());
+        assert_eq!(hashed[0], params[0]);
+        assert_eq!(
+            hashed[1],
+            KeyParameter {
+                tag: Tag::ATTESTATION_CHALLENGE,
+                value: KeyParameterValue::Blob(keystore2_crypto::sha256(&challenge).unwrap()),
+            }
+        );
+
+        // Challenges within the limit are left alone even if the caller opted in.
+        let params = challenge_params(&[0xa5u8; MAX_ATTESTATION_CHALLENGE_LEN]);
+        assert_eq!(
+            hash_oversized_attestation_challenge(
+                &params,
+                KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_attestation_challenge_passed_through_by_default() {
+        let params = challenge_params(&[0xa5u8; MAX_ATTESTATION_CHALLENGE_LEN + 1]);
+        assert_eq!(hash_oversized_attestation_challenge(&params, 0).unwrap(), None);
+        assert_eq!(
+            hash_oversized_attestation_challenge(&ec_params(EcCurve::P_256), 0).unwrap(),
+            None
+        );
+    }
+
+    /// Returns the metadata of a request with `idempotency_key` for a P-256 key, and, if
+    /// `created` is given, of the key that the request stored.
+    fn idempotent_key_metadata(idempotency_key: &[u8], created: Option<DateTime>) -> KeyMetaData {
+        let (token, params_digest) =
+            idempotency_digests(idempotency_key, &ec_params(EcCurve::P_256)).unwrap();
+        let mut metadata = KeyMetaData::new();
+        if let Some(created) = created {
+            metadata.add(KeyMetaEntry::CreationDate(created));
+        }
+        metadata.add(KeyMetaEntry::IdempotencyToken(token));
+        metadata.add(KeyMetaEntry::IdempotencyParamsDigest(params_digest));
+        metadata
+    }
+
+    fn is_retry(metadata: &KeyMetaData, idempotency_key: &[u8], now: DateTime) -> bool {
+        let request = idempotent_key_metadata(idempotency_key, None);
+        metadata.is_idempotent_retry(&request, idempotency_window_start(now)).unwrap()
+    }
+
+    #[test]
+    fn test_attestation_template_is_applied_on_request() -> Result<()> {
+        const NAME: &str = "security_level_test_template";
+        ATTESTATION_TEMPLATES.register(NAME, &challenge_params(b"template")[1..])?;
+
+        // Without a template name, the parameters are used as they are.
+        assert_eq!(apply_attestation_template(&challenge_params(b"c")[..1], None)?, None);
+        assert_eq!(
+            apply_attestation_template(&challenge_params(b"c")[..1], Some(NAME))?,
+            Some(vec![challenge_params(b"template")[1].clone(), challenge_params(b"c")[0].clone()])
+        );
+        // The challenge of the request takes precedence.
+        assert_eq!(
+            apply_attestation_template(&challenge_params(b"request"), Some(NAME))?,
+            Some(challenge_params(b"request"))
+        );
+
+        assert_eq!(
+            apply_attestation_template(&challenge_params(b"c"), Some("unknown template"))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+        );
+        ATTESTATION_TEMPLATES.register(NAME, &[])
+    }
+
+    #[test]
+    fn test_attestation_package_is_checked_on_request() -> Result<()> {
+        const PACKAGE: &[u8] = b"com.example.a";
+        let params = challenge_params(b"c");
+
+        assert_eq!(attestation_package(&params, None)?, None);
+        assert_eq!(attestation_package(&params, Some(PACKAGE))?, Some(PACKAGE));
+        // Only attested keys name a package.
+        assert_eq!(
+            attestation_package(&params[..1], Some(PACKAGE))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_attestation_verification_requires_challenge() {
+        let params = challenge_params(b"challenge");
+        assert_eq!(attestation_challenge_to_verify(&params, 0).unwrap(), None);
+        assert_eq!(
+            attestation_challenge_to_verify(&params, KEY_FLAG_VERIFY_ATTESTATION).unwrap(),
+            Some(b"challenge".to_vec())
+        );
+
+        // Without a challenge there is nothing to verify.
+        let err =
+            attestation_challenge_to_verify(&params[..1], KEY_FLAG_VERIFY_ATTESTATION).unwrap_err();
+        assert_eq!(
+            err.root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+        );
+
+        // A hashed oversized challenge is expected in the attestation in its hashed form.
+        let challenge = vec![0xa5u8; MAX_ATTESTATION_CHALLENGE_LEN + 1];
+        let flags = KEY_FLAG_VERIFY_ATTESTATION | KEY_FLAG_HASH_OVERSIZED_ATTESTATION_CHALLENGE;
+        let hashed =
+            hash_oversized_attestation_challenge(&challenge_params(&challenge), flags).unwrap();
+        assert_eq!(
+            attestation_challenge_to_verify(&hashed.unwrap(), flags).unwrap(),
+            Some(keystore2_crypto::sha256(&challenge).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_idempotent_retry_returns_same_key() {
+        let created = DateTime::from_millis_epoch(1_000_000);
+        let metadata = idempotent_key_metadata(b"request-1", Some(created));
+        assert!(is_retry(&metadata, b"request-1", created));
+        assert!(is_retry(
+            &metadata,
+            b"request-1",
+            DateTime::from_millis_epoch(1_000_000 + IDEMPOTENCY_WINDOW_MILLIS - 1)
+        ));
+
+        // Once the window expired, a new key is generated.
+        assert!(!is_retry(
+            &metadata,
+            b"request-1",
+            DateTime::from_millis_epoch(1_000_000 + IDEMPOTENCY_WINDOW_MILLIS)
+        ));
+    }
+
+    #[test]
+    fn test_distinct_idempotency_keys_create_distinct_keys() {
+        let created = DateTime::from_millis_epoch(1_000_000);
+        let metadata = idempotent_key_metadata(b"request-1", Some(created));
+        assert!(!is_retry(&metadata, b"request-2", created));
+        assert!(!is_retry(&metadata, b"", created));
+
+        // Keys generated without an idempotency key are never returned for a retry.
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::CreationDate(created));
+        assert!(!is_retry(&metadata, b"request-1", created));
+    }
+
+    #[test]
+    fn test_idempotency_key_is_bound_to_params() {
+        let created = DateTime::from_millis_epoch(1_000_000);
+        let metadata = idempotent_key_metadata(b"request-1", Some(created));
+
+        // The idempotency key is not stored in the clear.
+        let (token, _) = idempotency_digests(b"request-1", &[]).unwrap();
+        assert_eq!(metadata.idempotency_token(), Some(&token));
+        assert_ne!(token, b"request-1".to_vec());
+
+        // Reusing the idempotency key for other parameters is an error.
+        let (token, params_digest) =
+            idempotency_digests(b"request-1", &ec_params(EcCurve::P_384)).unwrap();
+        let mut request = KeyMetaData::new();
+        request.add(KeyMetaEntry::IdempotencyToken(token));
+        request.add(KeyMetaEntry::IdempotencyParamsDigest(params_digest));
+        let result = metadata.is_idempotent_retry(&request, idempotency_window_start(created));
+        assert_eq!(
+            result.unwrap_err().root_cause().downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+        );
+    }
+
+    #[test]
+    fn test_key_with_past_creation_date_is_treated_as_old() {
+        let now = DateTime::now().unwrap();
+        let created =
+            DateTime::from_millis_epoch(now.to_millis_epoch() - 2 * IDEMPOTENCY_WINDOW_MILLIS);
+        let mut params = ec_params(EcCurve::P_256);
+        params.push(KeyParameter {
+            tag: Tag::CREATION_DATETIME,
+            value: KeyParameterValue::DateTime(created.to_millis_epoch()),
+        });
+
+        let (creation_date, remaining) = split_creation_datetime(&params).unwrap();
+        assert_eq!(creation_date, created);
+        assert_eq!(remaining, ec_params(EcCurve::P_256));
+
+        // A key recorded with the past creation date is too old for an idempotent retry,
+        // whereas the same key created now would be returned.
+        let metadata = idempotent_key_metadata(b"request-1", Some(creation_date));
+        assert!(!is_retry(&metadata, b"request-1", now));
+        let metadata = idempotent_key_metadata(b"request-1", Some(now));
+        assert!(is_retry(&metadata, b"request-1", now));
+    }
+
+    #[test]
+    fn test_creation_date_is_test_gated() {
+        let mut params = ec_params(EcCurve::P_256);
+        params.push(KeyParameter {
+            tag: Tag::CREATION_DATETIME,
+            value: KeyParameterValue::DateTime(1_000_000),
+        });
+
+        // Without the flag, the parameters are left alone and keystore uses the current time.
+        assert!(take_test_creation_datetime(&params, 0, 0).unwrap().is_none());
+
+        // Apps may never choose the creation date.
+        assert_eq!(
+            take_test_creation_datetime(&params, KEY_FLAG_TEST_CREATION_DATETIME, 10001)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::perm())
+        );
+
+        assert_eq!(
+            split_creation_datetime(&ec_params(EcCurve::P_256))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+        );
+    }
+
+    #[test]
+    fn test_boot_state_override_is_test_gated() {
+        let params = challenge_params(b"challenge");
+        assert!(test_boot_state_override(&params, 0, 0).unwrap().is_none());
+
+        // Apps may never override the attested boot state.
+        assert_eq!(
+            test_boot_state_override(&params, KEY_FLAG_TEST_VERIFIED_BOOT_STATE, 10001)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::perm())
+        );
+    }
+
+    fn with_validity(
+        mut params: Vec<KeyParameter>,
+        not_before: i64,
+        not_after: i64,
+    ) -> Vec<KeyParameter> {
+        params.push(KeyParameter {
+            tag: Tag::CERTIFICATE_NOT_BEFORE,
+            value: KeyParameterValue::DateTime(not_before),
+        });
+        params.push(KeyParameter {
+            tag: Tag::CERTIFICATE_NOT_AFTER,
+            value: KeyParameterValue::DateTime(not_after),
+        });
+        params
+    }
+
+    #[test]
+    fn test_supplied_certificate_validity_is_passed_to_keymint() {
+        const NOT_BEFORE: i64 = 1_600_000_000_000;
+        const NOT_AFTER: i64 = 1_700_000_000_000;
+        let params = with_validity(ec_params(EcCurve::P_256), NOT_BEFORE, NOT_AFTER);
+        assert_eq!(certificate_validity(&params).unwrap(), (NOT_BEFORE, NOT_AFTER));
+
+        // The supplied window is passed to KeyMint unchanged.
+        let mut result = params.clone();
+        add_default_certificate_validity(&params, &mut result);
+        assert_eq!(result, params);
+
+        // Normal callers get the default window.
+        let params = ec_params(EcCurve::P_256);
+        let mut result = params.clone();
+        add_default_certificate_validity(&params, &mut result);
+        assert_eq!(result, with_validity(params, 0, UNDEFINED_NOT_AFTER));
+    }
+
+    #[test]
+    fn test_certificate_validity_is_test_gated() {
+        let params = with_validity(ec_params(EcCurve::P_256), 2_000, 1_000);
+
+        // Without the flag, the parameters are not checked.
+        assert!(check_test_certificate_validity(&params, 0, 10001).unwrap().is_none());
+
+        // Apps may never choose the validity window this way.
+        assert_eq!(
+            check_test_certificate_validity(&params, KEY_FLAG_TEST_CERTIFICATE_VALIDITY, 10001)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::perm())
+        );
+
+        // Both bounds are required, and the window must not end before it starts.
+        for params in [params, ec_params(EcCurve::P_256)] {
+            assert_eq!(
+                certificate_validity(&params).unwrap_err().root_cause().downcast_ref::<Error>(),
+                Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+            );
+        }
+    }
+
+    #[test]
+    fn test_expired_keys_are_not_used() -> Result<()> {
+        let expiry = 1_700_000_000_000;
+        let params = vec![KeyParameter {
+            tag: Tag::USAGE_EXPIRE_DATETIME,
+            value: KeyParameterValue::DateTime(expiry),
+        }];
+        assert!(expiry_date_to_record(&params, 0, Domain::APP)?.is_none());
+        assert_eq!(
+            expiry_date_to_record(&params, KEY_FLAG_DELETE_ON_EXPIRY, Domain::APP)?,
+            Some(DateTime::from_millis_epoch(expiry))
+        );
+        // The flag requires the expiry date, and keys that keystore does not store cannot be
+        // deleted on expiry.
+        for (params, domain) in [(vec![], Domain::APP), (params, Domain::BLOB)] {
+            assert_eq!(
+                expiry_date_to_record(&params, KEY_FLAG_DELETE_ON_EXPIRY, domain)
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+            );
+        }
+
+        let mut db = new_test_db()?;
+        let key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 1,
+            alias: Some("expiring".to_string()),
+            blob: None,
+        };
+        let mut metadata = KeyMetaData::new();
+        metadata.add(KeyMetaEntry::ExpiryDate(DateTime::from_millis_epoch(expiry)));
+        db.store_new_key(
+            &key,
+            KeyType::Client,
+            &[],
+            &BlobInfo::new(b"key blob", &BlobMetaData::new()),
+            &CertificateInfo::new(None, None),
+            &metadata,
+            &KEYSTORE_UUID,
+        )?;
+        let (_, key_entry) =
+            db.load_key_entry(&key, KeyType::Client, KeyEntryLoadBits::KM, 1, |_, _| Ok(()))?;
+
+        let clock = Arc::new(FakeClock::default());
+        clock.move_wall_by(expiry - 1);
+        assert!(check_key_not_expired(&key_entry, &clock).is_ok());
+        clock.advance(1);
+        assert_eq!(
+            check_key_not_expired(&key_entry, &clock)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Km(ErrorCode::KEY_EXPIRED))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_alias_policy() {
+        // Replacing the existing key is the default, also for requests without flags.
+        assert_eq!(existing_alias_policy(None, None), ExistingAlias::Replace);
+        assert_eq!(existing_alias_policy(Some(0), None), ExistingAlias::Replace);
+        assert_eq!(
+            existing_alias_policy(Some(KEY_FLAG_DELETE_ON_EXPIRY), None),
+            ExistingAlias::Replace
+        );
+        assert_eq!(existing_alias_policy(Some(KEY_FLAG_FAIL_IF_EXISTS), None), ExistingAlias::Fail);
+        assert_eq!(
+            existing_alias_policy(Some(KEY_FLAG_FAIL_IF_EXISTS | KEY_FLAG_DELETE_ON_EXPIRY), None),
+            ExistingAlias::Fail
+        );
+
+        // Idempotent retries keep the existing key, unless the request must not replace it.
+        let created_after = DateTime::from_millis_epoch(1_000_000);
+        assert_eq!(
+            existing_alias_policy(Some(0), Some(created_after)),
+            ExistingAlias::KeepIdempotentRetry(created_after)
+        );
+        assert_eq!(
+            existing_alias_policy(Some(KEY_FLAG_FAIL_IF_EXISTS), Some(created_after)),
+            ExistingAlias::Fail
+        );
+    }
+}
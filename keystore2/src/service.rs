@@ -17,21 +17,27 @@
 
 use std::collections::HashMap;
 
+use crate::attestation_key_utils::check_attestation_key_cert;
 use crate::audit_log::log_key_deleted;
+use crate::cert_chain_order::CertChainOrder;
 use crate::ks_err;
 use crate::permission::{KeyPerm, KeystorePerm};
 use crate::security_level::KeystoreSecurityLevel;
 use crate::utils::{
     check_grant_permission, check_key_permission, check_keystore_permission, count_key_entries,
-    key_parameters_to_authorizations, list_key_entries, uid_to_android_user, watchdog as wd,
+    get_key_descriptor_for_lookup, key_parameters_to_authorizations, list_key_entries,
+    resolve_key_namespace, uid_to_android_user, watchdog as wd,
 };
 use crate::{
     database::Uuid,
-    globals::{create_thread_local_db, DB, LEGACY_BLOB_LOADER, LEGACY_IMPORTER, SUPER_KEY},
+    globals::{
+        create_thread_local_db, get_keymint_instances, primary_keymint_instance, DB,
+        LEGACY_BLOB_LOADER, LEGACY_IMPORTER, SUPER_KEY,
+    },
 };
 use crate::{database::KEYSTORE_UUID, permission};
 use crate::{
-    database::{KeyEntryLoadBits, KeyType, SubComponentType},
+    database::{KeyEntryLoadBits, KeyIdLockMode, KeyType, SubComponentType},
     error::ResponseCode,
 };
 use crate::{
@@ -47,13 +53,13 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 use anyhow::{Context, Result};
 use error::Error;
-use keystore2_selinux as selinux;
 
 /// Implementation of the IKeystoreService.
 #[derive(Default)]
 pub struct KeystoreService {
     i_sec_level_by_uuid: HashMap<Uuid, Strong<dyn IKeystoreSecurityLevel>>,
     uuid_by_sec_level: HashMap<SecurityLevel, Uuid>,
+    instances: HashMap<String, (SecurityLevel, Uuid)>,
 }
 
 impl KeystoreService {
@@ -62,20 +68,39 @@ impl KeystoreService {
         id_rotation_state: IdRotationState,
     ) -> Result<Strong<dyn IKeystoreService>> {
         let mut result: Self = Default::default();
+        let tee_instance = primary_keymint_instance(&SecurityLevel::TRUSTED_ENVIRONMENT)
+            .context(ks_err!("Trying to get the TEE instance name."))?;
         let (dev, uuid) = KeystoreSecurityLevel::new_native_binder(
             SecurityLevel::TRUSTED_ENVIRONMENT,
+            tee_instance,
             id_rotation_state.clone(),
         )
         .context(ks_err!("Trying to construct mandatory security level TEE."))?;
         result.i_sec_level_by_uuid.insert(uuid, dev);
         result.uuid_by_sec_level.insert(SecurityLevel::TRUSTED_ENVIRONMENT, uuid);
-
-        // Strongbox is optional, so we ignore errors and turn the result into an Option.
-        if let Ok((dev, uuid)) =
-            KeystoreSecurityLevel::new_native_binder(SecurityLevel::STRONGBOX, id_rotation_state)
-        {
-            result.i_sec_level_by_uuid.insert(uuid, dev);
-            result.uuid_by_sec_level.insert(SecurityLevel::STRONGBOX, uuid);
+        result
+            .instances
+            .insert(tee_instance.to_string(), (SecurityLevel::TRUSTED_ENVIRONMENT, uuid));
+
+        // Strongbox is optional, so we ignore errors and skip instances that cannot be reached.
+        // The primary instance comes first and is the one returned by getSecurityLevel.
+        let strongbox_instances =
+            get_keymint_instances(&SecurityLevel::STRONGBOX).context(ks_err!())?;
+        for instance in strongbox_instances {
+            match KeystoreSecurityLevel::new_native_binder(
+                SecurityLevel::STRONGBOX,
+                &instance,
+                id_rotation_state.clone(),
+            ) {
+                Ok((dev, uuid)) => {
+                    result.i_sec_level_by_uuid.insert(uuid, dev);
+                    if primary_keymint_instance(&SecurityLevel::STRONGBOX)? == instance {
+                        result.uuid_by_sec_level.insert(SecurityLevel::STRONGBOX, uuid);
+                    }
+                    result.instances.insert(instance, (SecurityLevel::STRONGBOX, uuid));
+                }
+                Err(e) => log::info!("StrongBox instance {} is not available: {:?}", instance, e),
+            }
         }
 
         let uuid_by_sec_level = result.uuid_by_sec_level.clone();
@@ -92,9 +117,9 @@ impl KeystoreService {
     }
 
     fn uuid_to_sec_level(&self, uuid: &Uuid) -> SecurityLevel {
-        self.uuid_by_sec_level
-            .iter()
-            .find(|(_, v)| **v == *uuid)
+        self.instances
+            .values()
+            .find(|(_, v)| *v == *uuid)
             .map(|(s, _)| *s)
             .unwrap_or(SecurityLevel::SOFTWARE)
     }
@@ -132,16 +157,22 @@ impl KeystoreService {
         let (key_id_guard, mut key_entry) = DB
             .with(|db| {
                 LEGACY_IMPORTER.with_try_import(key, caller_uid, super_key, || {
-                    db.borrow_mut().load_key_entry(
+                    db.borrow_mut().load_key_entry_with_mode(
                         key,
                         KeyType::Client,
                         KeyEntryLoadBits::PUBLIC,
                         caller_uid,
+                        KeyIdLockMode::Shared,
                         |k, av| check_key_permission(KeyPerm::GetInfo, k, &av),
                     )
                 })
             })
             .context(ks_err!("while trying to load key info."))?;
+        let cert_chain = DB
+            .with(|db| {
+                SUPER_KEY.read().unwrap().take_cert_chain(&mut db.borrow_mut(), &mut key_entry)
+            })
+            .context(ks_err!("Trying to unwrap the certificate chain."))?;
 
         let i_sec_level = if !key_entry.pure_cert() {
             Some(
@@ -162,7 +193,8 @@ impl KeystoreService {
                 },
                 keySecurityLevel: self.uuid_to_sec_level(key_entry.km_uuid()),
                 certificate: key_entry.take_cert(),
-                certificateChain: key_entry.take_cert_chain(),
+                certificateChain: cert_chain
+                    .map(|chain| CertChainOrder::from_property().apply(chain)),
                 modificationTimeMs: key_entry
                     .metadata()
                     .creation_date()
@@ -189,7 +221,7 @@ impl KeystoreService {
                 db.borrow_mut().load_key_entry(
                     key,
                     KeyType::Client,
-                    KeyEntryLoadBits::NONE,
+                    KeyEntryLoadBits::PUBLIC,
                     caller_uid,
                     |k, av| check_key_permission(KeyPerm::Update, k, &av).context(ks_err!()),
                 )
@@ -203,7 +235,15 @@ impl KeystoreService {
             .context(ks_err!("Failed to load key entry."))?;
 
             let mut db = db.borrow_mut();
-            if let Some((key_id_guard, _key_entry)) = entry {
+            if let Some((key_id_guard, key_entry)) = entry {
+                if let Some(public_cert) = public_cert {
+                    check_attestation_key_cert(
+                        key_entry.key_parameters(),
+                        key_entry.cert().as_deref(),
+                        public_cert,
+                    )
+                    .context(ks_err!("Refusing to update cert subcomponent."))?;
+                }
                 db.set_blob(&key_id_guard, SubComponentType::CERT, public_cert, None)
                     .context(ks_err!("Failed to update cert subcomponent."))?;
 
@@ -222,13 +262,9 @@ impl KeystoreService {
             // So we know that we have a certificate chain and no public cert.
             // Now check that we have everything we need to make a new certificate entry.
             let key = match (key.domain, &key.alias) {
-                (Domain::APP, Some(ref alias)) => KeyDescriptor {
-                    domain: Domain::APP,
-                    nspace: ThreadState::get_calling_uid() as i64,
-                    alias: Some(alias.clone()),
-                    blob: None,
-                },
-                (Domain::SELINUX, Some(_)) => key.clone(),
+                (Domain::APP, Some(_)) | (Domain::SELINUX, Some(_)) => {
+                    KeyDescriptor { blob: None, ..resolve_key_namespace(key, caller_uid) }
+                }
                 _ => {
                     return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
                         .context(ks_err!("Domain must be APP or SELINUX to insert a certificate."))
@@ -251,54 +287,14 @@ impl KeystoreService {
         .context(ks_err!())
     }
 
-    fn get_key_descriptor_for_lookup(
-        &self,
-        domain: Domain,
-        namespace: i64,
-    ) -> Result<KeyDescriptor> {
-        let mut k = match domain {
-            Domain::APP => KeyDescriptor {
-                domain,
-                nspace: ThreadState::get_calling_uid() as u64 as i64,
-                ..Default::default()
-            },
-            Domain::SELINUX => KeyDescriptor { domain, nspace: namespace, ..Default::default() },
-            _ => {
-                return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
-                    "List entries is only supported for Domain::APP and Domain::SELINUX."
-                ))
-            }
-        };
-
-        // First we check if the caller has the info permission for the selected domain/namespace.
-        // By default we use the calling uid as namespace if domain is Domain::APP.
-        // If the first check fails we check if the caller has the list permission allowing to list
-        // any namespace. In that case we also adjust the queried namespace if a specific uid was
-        // selected.
-        if let Err(e) = check_key_permission(KeyPerm::GetInfo, &k, &None) {
-            if let Some(selinux::Error::PermissionDenied) =
-                e.root_cause().downcast_ref::<selinux::Error>()
-            {
-                check_keystore_permission(KeystorePerm::List)
-                    .context(ks_err!("While checking keystore permission."))?;
-                if namespace != -1 {
-                    k.nspace = namespace;
-                }
-            } else {
-                return Err(e).context(ks_err!("While checking key permission."))?;
-            }
-        }
-        Ok(k)
-    }
-
     fn list_entries(&self, domain: Domain, namespace: i64) -> Result<Vec<KeyDescriptor>> {
-        let k = self.get_key_descriptor_for_lookup(domain, namespace)?;
+        let k = get_key_descriptor_for_lookup(domain, namespace)?;
 
         DB.with(|db| list_key_entries(&mut db.borrow_mut(), k.domain, k.nspace, None))
     }
 
     fn count_num_entries(&self, domain: Domain, namespace: i64) -> Result<i32> {
-        let k = self.get_key_descriptor_for_lookup(domain, namespace)?;
+        let k = get_key_descriptor_for_lookup(domain, namespace)?;
 
         DB.with(|db| count_key_entries(&mut db.borrow_mut(), k.domain, k.nspace))
     }
@@ -309,7 +305,7 @@ impl KeystoreService {
         namespace: i64,
         start_past_alias: Option<&str>,
     ) -> Result<Vec<KeyDescriptor>> {
-        let k = self.get_key_descriptor_for_lookup(domain, namespace)?;
+        let k = get_key_descriptor_for_lookup(domain, namespace)?;
         DB.with(|db| list_key_entries(&mut db.borrow_mut(), k.domain, k.nspace, start_past_alias))
     }
 
@@ -0,0 +1,268 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the attestation application id of callers with a shared UID.
+//!
+//! The attestation application id identifies the caller in the attestation of a key by the
+//! packages of its UID. If several packages share the UID, it is ambiguous which of them created
+//! the key. By default, the attestation application id lists all packages of the UID, which
+//! KeyMint permits. The `keystore.shared_uid_attestation_policy` system property can instead
+//! require shared UID callers to name the package on whose behalf they attest the key, see
+//! `KEY_TAG_ATTESTATION_PACKAGE`. The attestation application id then lists only that package.
+//! Callers can name a package under either policy, but only one of the packages of their UID.
+//!
+//! The attestation application id is DER encoded as follows:
+//!
+//! ```asn1
+//! KeyAttestationApplicationId ::= SEQUENCE {
+//!     packageInfos SET OF KeyAttestationPackageInfo,
+//!     signatureDigests SET OF OCTET STRING,
+//! }
+//!
+//! KeyAttestationPackageInfo ::= SEQUENCE {
+//!     packageName OCTET STRING,
+//!     version INTEGER,
+//! }
+//! ```
+
+use crate::error::{Error, ErrorCode};
+use crate::ks_err;
+use crate::sysprop::read_prop_parsed;
+use anyhow::{Context, Result};
+
+/// The policy for callers with a shared UID: "all_packages", the default, or "require_package".
+const SHARED_UID_ATTESTATION_POLICY_PROPERTY: &str = "keystore.shared_uid_attestation_policy";
+
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+
+/// How the attestation application id of a caller with a shared UID is derived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedUidAttestationPolicy {
+    /// The attestation application id lists all packages of the UID unless the caller names
+    /// one of them.
+    AllPackages,
+    /// Callers with a shared UID must name the package of the attestation application id.
+    RequirePackage,
+}
+
+impl SharedUidAttestationPolicy {
+    /// Reads the policy from the `keystore.shared_uid_attestation_policy` system property. The
+    /// property is read on every call, so changes take effect with the next attested key.
+    pub fn from_property() -> Self {
+        read_prop_parsed(SHARED_UID_ATTESTATION_POLICY_PROPERTY, Self::AllPackages, |value| {
+            match value {
+                "all_packages" => Some(Self::AllPackages),
+                "require_package" => Some(Self::RequirePackage),
+                _ => None,
+            }
+        })
+    }
+
+    /// Returns the attestation application id for the caller from `aaid`, the DER encoded
+    /// attestation application id with all packages of its UID, and `package`, the package
+    /// named by the caller, if any. Fails with `ErrorCode::INVALID_ARGUMENT` if `package` is not
+    /// one of the packages of the UID, or if the policy requires a package and the caller did
+    /// not name one although its UID is shared.
+    pub fn apply(&self, aaid: Vec<u8>, package: Option<&[u8]>) -> Result<Vec<u8>> {
+        if package.is_none() && *self == Self::AllPackages {
+            return Ok(aaid);
+        }
+        let decoded = DecodedAaid::decode(&aaid).context(ks_err!())?;
+        match package {
+            Some(package) => decoded.select_package(package).context(ks_err!()),
+            None if decoded.package_infos.len() > 1 => Err(Error::Km(ErrorCode::INVALID_ARGUMENT))
+                .context(ks_err!(
+                    "The UID is shared by {} packages, one of them must be named.",
+                    decoded.package_infos.len()
+                )),
+            None => Ok(aaid),
+        }
+    }
+}
+
+/// The parts of a DER encoded attestation application id.
+struct DecodedAaid<'a> {
+    /// The package names and the encoded `KeyAttestationPackageInfo`s that they appear in.
+    package_infos: Vec<(&'a [u8], &'a [u8])>,
+    /// The encoded `signatureDigests` SET.
+    signature_digests: &'a [u8],
+}
+
+impl<'a> DecodedAaid<'a> {
+    fn decode(aaid: &'a [u8]) -> Result<Self> {
+        let (content, rest) = read_element(aaid, TAG_SEQUENCE).context(ks_err!())?;
+        if !rest.is_empty() {
+            return Err(Error::sys()).context(ks_err!("Trailing data after the application id."));
+        }
+        let (mut infos, signature_digests) = read_element(content, TAG_SET).context(ks_err!())?;
+        let (_, rest) = read_element(signature_digests, TAG_SET).context(ks_err!())?;
+        if !rest.is_empty() {
+            return Err(Error::sys()).context(ks_err!("Trailing data after the digests."));
+        }
+        let mut package_infos = Vec::new();
+        while !infos.is_empty() {
+            let (info, rest) = read_element(infos, TAG_SEQUENCE).context(ks_err!())?;
+            let (name, _) = read_element(info, TAG_OCTET_STRING).context(ks_err!())?;
+            package_infos.push((name, &infos[..infos.len() - rest.len()]));
+            infos = rest;
+        }
+        Ok(Self { package_infos, signature_digests })
+    }
+
+    /// Returns the encoded attestation application id with only the package `package`. The
+    /// signature digests are kept, because all packages of a shared UID have the same signer.
+    fn select_package(&self, package: &[u8]) -> Result<Vec<u8>> {
+        let info = self
+            .package_infos
+            .iter()
+            .find(|(name, _)| *name == package)
+            .map(|(_, info)| *info)
+            .ok_or(Error::Km(ErrorCode::INVALID_ARGUMENT))
+            .context(ks_err!("The named package is not a package of the caller's UID."))?;
+        let mut content = Vec::new();
+        write_element(&mut content, TAG_SET, info);
+        content.extend_from_slice(self.signature_digests);
+        let mut encoded = Vec::new();
+        write_element(&mut encoded, TAG_SEQUENCE, &content);
+        Ok(encoded)
+    }
+}
+
+/// Reads the DER element with `tag` at the start of `data`. Returns its content and the data
+/// that follows it.
+fn read_element(data: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    match data.first() {
+        Some(t) if *t == tag => {}
+        _ => return Err(Error::sys()).context(ks_err!("Expected DER tag {:#x}.", tag)),
+    }
+    let (len, header_len) = match data.get(1) {
+        Some(len) if *len < 0x80 => (*len as usize, 2),
+        Some(len) if (0x81..=0x84).contains(len) => {
+            let n = (*len & 0x7f) as usize;
+            let bytes = data.get(2..2 + n).ok_or_else(Error::sys).context(ks_err!())?;
+            (bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize), 2 + n)
+        }
+        _ => return Err(Error::sys()).context(ks_err!("Unsupported DER length.")),
+    };
+    let end = header_len
+        .checked_add(len)
+        .filter(|end| *end <= data.len())
+        .ok_or_else(Error::sys)
+        .context(ks_err!("DER element exceeds its data."))?;
+    Ok((&data[header_len..end], &data[end..]))
+}
+
+/// Appends the DER element with `tag` and `content` to `out`.
+fn write_element(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+    out.push(tag);
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().copied().skip_while(|b| *b == 0).collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &[u8] = &[0xab; 32];
+
+    fn encode_aaid(packages: &[(&str, u8)]) -> Vec<u8> {
+        let mut infos = Vec::new();
+        for (name, version) in packages {
+            let mut info = Vec::new();
+            write_element(&mut info, TAG_OCTET_STRING, name.as_bytes());
+            info.extend_from_slice(&[0x02, 0x01, *version]);
+            write_element(&mut infos, TAG_SEQUENCE, &info);
+        }
+        let mut digests = Vec::new();
+        write_element(&mut digests, TAG_OCTET_STRING, DIGEST);
+        let mut content = Vec::new();
+        write_element(&mut content, TAG_SET, &infos);
+        write_element(&mut content, TAG_SET, &digests);
+        let mut aaid = Vec::new();
+        write_element(&mut aaid, TAG_SEQUENCE, &content);
+        aaid
+    }
+
+    fn is_invalid_argument(result: Result<Vec<u8>>) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>()
+            == Some(&Error::Km(ErrorCode::INVALID_ARGUMENT))
+    }
+
+    #[test]
+    fn test_all_packages_policy() -> Result<()> {
+        let policy = SharedUidAttestationPolicy::AllPackages;
+        let shared = encode_aaid(&[("com.example.a", 1), ("com.example.b", 2)]);
+
+        // Without a named package, all packages of the shared UID are attested.
+        assert_eq!(policy.apply(shared.clone(), None)?, shared);
+        let single = encode_aaid(&[("com.example.a", 1)]);
+        assert_eq!(policy.apply(single.clone(), None)?, single);
+
+        // A named package narrows the application id to that package.
+        assert_eq!(
+            policy.apply(shared.clone(), Some(b"com.example.b"))?,
+            encode_aaid(&[("com.example.b", 2)])
+        );
+        assert!(is_invalid_argument(policy.apply(shared, Some(b"com.example.c"))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_require_package_policy() -> Result<()> {
+        let policy = SharedUidAttestationPolicy::RequirePackage;
+        let shared = encode_aaid(&[("com.example.a", 1), ("com.example.b", 2)]);
+
+        // Callers with a shared UID must name the package.
+        assert!(is_invalid_argument(policy.apply(shared.clone(), None)));
+        assert_eq!(
+            policy.apply(shared.clone(), Some(b"com.example.a"))?,
+            encode_aaid(&[("com.example.a", 1)])
+        );
+        assert!(is_invalid_argument(policy.apply(shared, Some(b"com.example"))));
+
+        // Callers whose UID is not shared are unaffected.
+        let single = encode_aaid(&[("com.example.a", 1)]);
+        assert_eq!(policy.apply(single.clone(), None)?, single);
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_application_id() -> Result<()> {
+        // Enough packages to require long form lengths.
+        let names: Vec<String> = (0..20).map(|i| format!("com.example.package{}", i)).collect();
+        let packages: Vec<(&str, u8)> = names.iter().map(|name| (name.as_str(), 1)).collect();
+        let shared = encode_aaid(&packages);
+        assert!(shared.len() > 0xff);
+        assert_eq!(
+            SharedUidAttestationPolicy::RequirePackage
+                .apply(shared, Some(b"com.example.package17"))?,
+            encode_aaid(&[("com.example.package17", 1)])
+        );
+
+        // Malformed application ids are rejected.
+        assert!(SharedUidAttestationPolicy::RequirePackage
+            .apply(vec![0x30, 0x05, 0x31], None)
+            .is_err());
+        Ok(())
+    }
+}
@@ -19,17 +19,22 @@ use crate::{
     database::EncryptedBy,
     database::KeyEntry,
     database::KeyType,
-    database::{KeyEntryLoadBits, KeyIdGuard, KeyMetaData, KeyMetaEntry, KeystoreDB},
+    database::{
+        KeyEntryLoadBits, KeyIdGuard, KeyMetaData, KeyMetaEntry, KeystoreDB, SubComponentType,
+    },
     ec_crypto::ECDHPrivateKey,
     enforcements::Enforcements,
     error::Error,
     error::ResponseCode,
+    globals::BLOB_NONCE_GENERATOR,
     key_parameter::{KeyParameter, KeyParameterValue},
     ks_err,
     legacy_blob::LegacyBlobLoader,
     legacy_importer::LegacyImporter,
     raw_device::KeyMintDevice,
-    utils::{watchdog as wd, AesGcm, AID_KEYSTORE},
+    super_key_wait::notify_super_key_available,
+    sysprop::{read_prop_duration, read_prop_parsed},
+    utils::{uid_to_android_user, watchdog as wd, AesGcm, AID_KEYSTORE},
 };
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, BlockMode::BlockMode, HardwareAuthToken::HardwareAuthToken,
@@ -42,14 +47,17 @@ use android_system_keystore2::aidl::android::system::keystore2::{
 };
 use anyhow::{Context, Result};
 use keystore2_crypto::{
-    aes_gcm_decrypt, aes_gcm_encrypt, generate_aes256_key, generate_salt, Password, ZVec,
-    AES_256_KEY_LENGTH,
+    aead_decrypt_with_aad, aead_encrypt_with_nonce, aes_gcm_decrypt, aes_gcm_decrypt_with_aad,
+    aes_gcm_encrypt, aes_gcm_encrypt_with_iv, generate_aes256_key, generate_salt, hkdf_expand,
+    Aead, Password, ZVec, AEAD_NONCE_LENGTH, AES_256_KEY_LENGTH, GCM_IV_LENGTH, TAG_LENGTH,
 };
 use rustutils::system_properties::PropertyWatcher;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    sync::atomic::{AtomicU64, Ordering},
     sync::Arc,
-    sync::{Mutex, RwLock, Weak},
+    sync::{Condvar, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
 };
 use std::{convert::TryFrom, ops::Deref};
 
@@ -62,6 +70,27 @@ const BIOMETRIC_AUTH_TIMEOUT_S: i32 = 15; // seconds
 
 type UserId = u32;
 
+/// Delay enforced after the first failed attempt to unlock the super keys of a user with a
+/// password. The delay doubles with every further consecutive failure. A delay of 0 disables
+/// the backoff.
+const UNLOCK_BACKOFF_BASE_PROPERTY: &str = "keystore.unlock_backoff.base";
+const DEFAULT_UNLOCK_BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// Upper bound of the delay enforced between failed unlock attempts.
+const UNLOCK_BACKOFF_MAX_PROPERTY: &str = "keystore.unlock_backoff.max";
+const DEFAULT_UNLOCK_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Maximum number of users whose super keys are cached. The super keys of the least recently
+/// used locked user are evicted when the super keys of another user are loaded.
+const MAX_CACHED_USERS_PROPERTY: &str = "keystore.super_key_cache.max_users";
+/// Covers the users of all but the most unusual multi-user devices.
+const DEFAULT_MAX_CACHED_USERS: usize = 16;
+/// The system user, whose super keys are never evicted from the cache.
+const USER_SYSTEM: UserId = 0;
+
+/// Version of super keys, and of the blobs wrapped with them, that were written before
+/// versions were recorded.
+const INITIAL_SUPER_KEY_VERSION: i32 = 0;
+
 /// Encryption algorithm used by a particular type of superencryption key
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SuperEncryptionAlgorithm {
@@ -100,6 +129,23 @@ pub const USER_SCREEN_LOCK_BOUND_P521_KEY: SuperKeyType = SuperKeyType {
     algorithm: SuperEncryptionAlgorithm::EcdhP521,
 };
 
+/// Key used for SignOnlyWhileLocked keys; the corresponding superencryption key is loaded in
+/// memory each time the user enters their LSKF. Unlike the screen lock bound key, it stays in
+/// memory when the device is locked, but while the device is locked keys encrypted with it can
+/// only be used for signing. This allows, e.g., background sync to authenticate while locked.
+pub const USER_SIGN_ONLY_WHILE_LOCKED_KEY: SuperKeyType = SuperKeyType {
+    alias: "USER_SIGN_ONLY_WHILE_LOCKED_KEY",
+    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+};
+
+/// The super keys that are stored encrypted with a key derived from the user's password.
+const PASSWORD_ENCRYPTED_SUPER_KEYS: [&SuperKeyType; 4] = [
+    &USER_SUPER_KEY,
+    &USER_SCREEN_LOCK_BOUND_KEY,
+    &USER_SCREEN_LOCK_BOUND_P521_KEY,
+    &USER_SIGN_ONLY_WHILE_LOCKED_KEY,
+];
+
 /// Superencryption to apply to a new key.
 #[derive(Debug, Clone, Copy)]
 pub enum SuperEncryptionType {
@@ -109,6 +155,8 @@ pub enum SuperEncryptionType {
     LskfBound,
     /// Superencrypt with a key cleared from memory when the device is locked.
     ScreenLockBound,
+    /// Superencrypt with a key that only permits signing while the device is locked.
+    SignOnlyWhileLocked,
     /// Superencrypt with a key based on the desired boot level
     BootLevel(i32),
 }
@@ -130,6 +178,12 @@ impl SuperKeyIdentifier {
         }
     }
 
+    /// Returns the version of the super key that the blob described by `metadata` was
+    /// wrapped with.
+    fn version_from_metadata(metadata: &BlobMetaData) -> i32 {
+        metadata.super_key_version().copied().unwrap_or(INITIAL_SUPER_KEY_VERSION)
+    }
+
     fn add_to_metadata(&self, metadata: &mut BlobMetaData) {
         match self {
             SuperKeyIdentifier::DatabaseId(id) => {
@@ -142,16 +196,274 @@ impl SuperKeyIdentifier {
     }
 }
 
+/// Identifies the key entry that a super encrypted key blob belongs to. It is bound to the blob
+/// as AEAD additional data, so that the blob fails to decrypt if it is transplanted to a
+/// different key entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobBinding {
+    /// Id of the key entry.
+    pub key_id: i64,
+    /// Android user id of the owner of the key entry.
+    pub user_id: UserId,
+}
+
+impl BlobBinding {
+    const AAD_LABEL: &'static [u8] = b"keystore2 super encrypted key blob v1";
+
+    /// Creates the binding for the blob of the key entry `key_id` owned by `key`. Keys outside
+    /// of the APP domain are owned by the system user.
+    pub fn new(key_id: i64, key: &KeyDescriptor) -> Self {
+        let user_id = match key.domain {
+            Domain::APP => uid_to_android_user(key.nspace as u32),
+            _ => 0,
+        };
+        Self { key_id, user_id }
+    }
+
+    /// Returns the binding of the blob of the key entry `key_id` with `metadata` if the blob is
+    /// bound, i.e., if `metadata` has a `BlobMetaEntry::BoundUserId`. The user id is derived from
+    /// the owner of the key entry in `db`, not from the metadata, which is stored next to the
+    /// blob and therefore no more trustworthy than the blob itself.
+    pub fn load_if_bound(
+        db: &mut KeystoreDB,
+        key_id: i64,
+        metadata: &BlobMetaData,
+    ) -> Result<Option<Self>> {
+        if metadata.bound_user_id().is_none() {
+            return Ok(None);
+        }
+        Self::load(db, key_id).map(Some)
+    }
+
+    /// Returns the binding of the key entry `key_id`, derived from its owner in `db`.
+    pub fn load(db: &mut KeystoreDB, key_id: i64) -> Result<Self> {
+        let owner = db
+            .load_key_descriptor(key_id)
+            .context(ks_err!("Failed to load the key descriptor."))?
+            .ok_or(Error::Rc(ResponseCode::KEY_NOT_FOUND))
+            .context(ks_err!("Key entry is gone."))?;
+        Ok(Self::new(key_id, &owner))
+    }
+
+    /// The AAD is the label followed by the key id and the user id, both in big endian.
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = Self::AAD_LABEL.to_vec();
+        aad.extend_from_slice(&self.key_id.to_be_bytes());
+        aad.extend_from_slice(&self.user_id.to_be_bytes());
+        aad
+    }
+}
+
+/// The contexts for which wrapping keys are derived from an AES super key. Each context has its
+/// own wrapping key, so that material wrapped in one context cannot be unwrapped in another, even
+/// though both are protected by the same super key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrappingContext {
+    /// KeyMint key blobs.
+    KeyBlob,
+    /// Certificate chains of key entries, see `NewKeyEncryption::wrap_cert_chain`.
+    Certificate,
+}
+
+impl WrappingContext {
+    /// Tag of the context in the blob metadata. The values must not change.
+    fn to_metadata(self) -> i32 {
+        match self {
+            Self::KeyBlob => 1,
+            Self::Certificate => 2,
+        }
+    }
+
+    /// HKDF info label used to derive the wrapping key of the context.
+    fn info(self) -> &'static [u8] {
+        match self {
+            Self::KeyBlob => b"keystore2 super key wrapping key: key blob",
+            Self::Certificate => b"keystore2 super key wrapping key: certificate",
+        }
+    }
+}
+
+/// Selects the scheme that new blobs are encrypted with under an AES super key, one of
+/// "aes_gcm", "aes_gcm_siv", and "chacha20_poly1305". Existing blobs keep their scheme until
+/// they are written again, e.g., when the key is upgraded.
+const ENCRYPTION_SCHEME_PROPERTY: &str = "keystore.blob_encryption_scheme";
+
+/// The authenticated encryption schemes that blobs can be encrypted with under an AES super key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionScheme {
+    /// AES-256-GCM.
+    AesGcm,
+    /// AES-256-GCM-SIV.
+    AesGcmSiv,
+    /// ChaCha20-Poly1305.
+    ChaCha20Poly1305,
+}
+
+impl EncryptionScheme {
+    const ALL: [Self; 3] = [Self::AesGcm, Self::AesGcmSiv, Self::ChaCha20Poly1305];
+
+    /// Tag of the scheme in the blob metadata. The values must not change.
+    fn to_metadata(self) -> i32 {
+        match self {
+            Self::AesGcm => 0,
+            Self::AesGcmSiv => 1,
+            Self::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    /// Returns the scheme recorded in `metadata`. Blobs without a recorded scheme are
+    /// encrypted with AES-GCM.
+    fn from_metadata(metadata: &BlobMetaData) -> Result<Self> {
+        match metadata.encryption_scheme() {
+            None => Ok(Self::AesGcm),
+            Some(tag) => Self::ALL
+                .into_iter()
+                .find(|scheme| scheme.to_metadata() == *tag)
+                .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context(ks_err!("Unknown encryption scheme {}.", tag)),
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "aes_gcm" => Some(Self::AesGcm),
+            "aes_gcm_siv" => Some(Self::AesGcmSiv),
+            "chacha20_poly1305" => Some(Self::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Returns the scheme that new blobs are encrypted with, see `ENCRYPTION_SCHEME_PROPERTY`.
+    fn configured() -> Self {
+        read_prop_parsed(ENCRYPTION_SCHEME_PROPERTY, Self::AesGcm, Self::parse)
+    }
+
+    /// Encrypts `plaintext` with `key` and authenticates `aad`. Returns the ciphertext, the IV,
+    /// and the AEAD tag. The IV is generated by `BLOB_NONCE_GENERATOR`.
+    fn encrypt(
+        self,
+        plaintext: &[u8],
+        key: &[u8],
+        aad: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+        let nonce_length = match self {
+            Self::AesGcm => GCM_IV_LENGTH,
+            Self::AesGcmSiv | Self::ChaCha20Poly1305 => AEAD_NONCE_LENGTH,
+        };
+        let nonce = BLOB_NONCE_GENERATOR.next_nonce(nonce_length).context(ks_err!())?;
+        match self {
+            Self::AesGcm => aes_gcm_encrypt_with_iv(plaintext, key, aad, nonce),
+            Self::AesGcmSiv => {
+                aead_encrypt_with_nonce(Aead::Aes256GcmSiv, plaintext, key, aad, nonce)
+            }
+            Self::ChaCha20Poly1305 => {
+                aead_encrypt_with_nonce(Aead::ChaCha20Poly1305, plaintext, key, aad, nonce)
+            }
+        }
+        .context(ks_err!("Failed to encrypt with {:?}.", self))
+    }
+
+    fn decrypt(self, data: &[u8], iv: &[u8], tag: &[u8], key: &[u8], aad: &[u8]) -> Result<ZVec> {
+        match self {
+            Self::AesGcm => aes_gcm_decrypt_with_aad(data, iv, tag, key, aad),
+            Self::AesGcmSiv => aead_decrypt_with_aad(Aead::Aes256GcmSiv, data, iv, tag, key, aad),
+            Self::ChaCha20Poly1305 => {
+                aead_decrypt_with_aad(Aead::ChaCha20Poly1305, data, iv, tag, key, aad)
+            }
+        }
+        .context(ks_err!("Failed to decrypt with {:?}.", self))
+    }
+}
+
+/// The known test vector that is encrypted into the verification token of a super key.
+const VERIFICATION_VECTOR: &[u8] = b"keystore2 super key verification vector";
+
+/// HKDF info label used to derive the key that encrypts the verification token from a super key.
+const VERIFICATION_KEY_INFO: &[u8] = b"keystore2 super key verification key";
+
+/// The result of checking a super key against the verification token that was stored with it
+/// when it was encrypted with the password. If a blob fails to unwrap, this tells a wrong super
+/// key apart from a corrupted blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperKeyVerification {
+    /// The super key decrypts the token to the test vector.
+    Verified,
+    /// The super key does not decrypt the token, or the token is malformed.
+    WrongKey,
+    /// No token was stored with the super key, because it was stored before tokens were.
+    NoToken,
+}
+
+impl SuperKeyVerification {
+    /// Creates the verification token of `super_key`, i.e., the IV, the AEAD tag, and the test
+    /// vector encrypted with a key derived from the super key.
+    fn create_token(super_key: &[u8]) -> Result<Vec<u8>> {
+        let key = hkdf_expand(AES_256_KEY_LENGTH, super_key, VERIFICATION_KEY_INFO)
+            .context(ks_err!("Failed to derive the verification key."))?;
+        let (ciphertext, iv, tag) = aes_gcm_encrypt(VERIFICATION_VECTOR, &key)
+            .context(ks_err!("Failed to encrypt the verification vector."))?;
+        Ok([iv, tag, ciphertext].concat())
+    }
+
+    /// Checks `super_key` against the verification token created by `create_token`.
+    fn check(super_key: &[u8], token: Option<&[u8]>) -> Self {
+        let token = match token {
+            Some(token) if token.len() >= GCM_IV_LENGTH + TAG_LENGTH => token,
+            Some(_) => return Self::WrongKey,
+            None => return Self::NoToken,
+        };
+        let (iv, rest) = token.split_at(GCM_IV_LENGTH);
+        let (tag, ciphertext) = rest.split_at(TAG_LENGTH);
+        let plaintext = hkdf_expand(AES_256_KEY_LENGTH, super_key, VERIFICATION_KEY_INFO)
+            .ok()
+            .and_then(|key| aes_gcm_decrypt(ciphertext, iv, tag, &key).ok());
+        match plaintext {
+            Some(plaintext) if &*plaintext == VERIFICATION_VECTOR => Self::Verified,
+            _ => Self::WrongKey,
+        }
+    }
+
+    /// Explains the failure to unwrap a blob with a super key of this verification result.
+    fn explain_unwrap_failure(self) -> &'static str {
+        match self {
+            Self::Verified => "The super key is correct, so the blob is corrupted.",
+            Self::WrongKey => "The super key is wrong.",
+            Self::NoToken => "The super key cannot be verified.",
+        }
+    }
+}
+
 pub struct SuperKey {
     algorithm: SuperEncryptionAlgorithm,
     key: ZVec,
     /// Identifier of the encrypting key, used to write an encrypted blob
     /// back to the database after re-encryption eg on a key update.
     id: SuperKeyIdentifier,
+    /// Version of the key. While a super key is rotated, multiple versions of the key with
+    /// the same identifier may be in memory. Blobs record the version they were wrapped with.
+    version: i32,
     /// ECDH is more expensive than AES. So on ECDH private keys we set the
     /// reencrypt_with field to point at the corresponding AES key, and the
     /// keys will be re-encrypted with AES on first use.
     reencrypt_with: Option<Arc<SuperKey>>,
+    /// The verification token stored with the key, see `SuperKeyVerification`.
+    verification_token: Option<Vec<u8>>,
+}
+
+impl SuperKey {
+    /// Checks the key against its verification token.
+    pub fn verify(&self) -> SuperKeyVerification {
+        SuperKeyVerification::check(&self.key, self.verification_token.as_deref())
+    }
+
+    /// Derives the key that wraps material of the given context from this AES super key.
+    fn wrapping_key(&self, context: WrappingContext) -> Result<ZVec> {
+        if self.algorithm != SuperEncryptionAlgorithm::Aes256Gcm {
+            return Err(Error::sys()).context(ks_err!("Key is not an AES key."));
+        }
+        hkdf_expand(AES_256_KEY_LENGTH, &self.key, context.info())
+            .context(ks_err!("Failed to derive the wrapping key."))
+    }
 }
 
 impl AesGcm for SuperKey {
@@ -177,15 +489,24 @@ impl AesGcm for SuperKey {
 struct LockedKey {
     algorithm: SuperEncryptionAlgorithm,
     id: SuperKeyIdentifier,
+    version: i32,
     nonce: Vec<u8>,
     ciphertext: Vec<u8>, // with tag appended
+    verification_token: Option<Vec<u8>>,
 }
 
 impl LockedKey {
     fn new(key: &[u8], to_encrypt: &Arc<SuperKey>) -> Result<Self> {
         let (mut ciphertext, nonce, mut tag) = aes_gcm_encrypt(&to_encrypt.key, key)?;
         ciphertext.append(&mut tag);
-        Ok(LockedKey { algorithm: to_encrypt.algorithm, id: to_encrypt.id, nonce, ciphertext })
+        Ok(LockedKey {
+            algorithm: to_encrypt.algorithm,
+            id: to_encrypt.id,
+            version: to_encrypt.version,
+            nonce,
+            ciphertext,
+            verification_token: to_encrypt.verification_token.clone(),
+        })
     }
 
     fn decrypt(
@@ -221,7 +542,14 @@ impl LockedKey {
             Some(auth_token),
             &self.ciphertext,
         )?)?;
-        Ok(Arc::new(SuperKey { algorithm: self.algorithm, key, id: self.id, reencrypt_with }))
+        Ok(Arc::new(SuperKey {
+            algorithm: self.algorithm,
+            key,
+            id: self.id,
+            version: self.version,
+            reencrypt_with,
+            verification_token: self.verification_token.clone(),
+        }))
     }
 }
 
@@ -254,29 +582,228 @@ struct UserSuperKeys {
     screen_lock_bound_private: Option<Arc<SuperKey>>,
     /// Versions of the above two keys, locked behind a biometric.
     biometric_unlock: Option<BiometricUnlock>,
+    /// The sign only key is loaded together with the screen lock bound keys, but it is not
+    /// cleared from memory when the screen lock is engaged. Instead, keys encrypted with it
+    /// can only be unwrapped for signing until the screen lock bound keys are unlocked again.
+    sign_only: Option<Arc<SuperKey>>,
+    /// The value of `SkmState::use_counter` when the keys of the user were last used.
+    last_used: AtomicU64,
 }
 
-#[derive(Default)]
 struct SkmState {
     user_keys: HashMap<UserId, UserSuperKeys>,
-    key_index: HashMap<i64, Weak<SuperKey>>,
-    boot_level_key_cache: Option<Mutex<BootLevelKeyCache>>,
+    /// Super keys by database id and version, with the user they belong to.
+    key_index: HashMap<(i64, i32), (UserId, Weak<SuperKey>)>,
+    boot_level_key_cache: Option<BootLevelKeyCache>,
+    /// The maximum number of entries of `user_keys`.
+    max_cached_users: usize,
+    /// The user that most recently unlocked the device, whose keys are never evicted.
+    foreground_user: Option<UserId>,
+    /// Incremented whenever the super keys of a user are used, to order the users by recency.
+    use_counter: AtomicU64,
+}
+
+impl Default for SkmState {
+    fn default() -> Self {
+        Self::new(read_prop_parsed(MAX_CACHED_USERS_PROPERTY, DEFAULT_MAX_CACHED_USERS, |v| {
+            v.parse::<usize>().ok().filter(|max| *max > 0)
+        }))
+    }
 }
 
 impl SkmState {
-    fn add_key_to_key_index(&mut self, super_key: &Arc<SuperKey>) -> Result<()> {
+    fn new(max_cached_users: usize) -> Self {
+        Self {
+            user_keys: Default::default(),
+            key_index: Default::default(),
+            boot_level_key_cache: None,
+            max_cached_users,
+            foreground_user: None,
+            use_counter: Default::default(),
+        }
+    }
+
+    fn add_key_to_key_index(&mut self, user_id: UserId, super_key: &Arc<SuperKey>) -> Result<()> {
         if let SuperKeyIdentifier::DatabaseId(id) = super_key.id {
-            self.key_index.insert(id, Arc::downgrade(super_key));
+            self.key_index.insert((id, super_key.version), (user_id, Arc::downgrade(super_key)));
+            notify_super_key_available();
             Ok(())
         } else {
             Err(Error::sys()).context(ks_err!("Cannot add key with ID {:?}", super_key.id))
         }
     }
+
+    /// Marks the super keys of `user_id` as most recently used.
+    fn mark_used(&self, user_id: UserId) {
+        if let Some(entry) = self.user_keys.get(&user_id) {
+            let now = self.use_counter.fetch_add(1, Ordering::Relaxed) + 1;
+            entry.last_used.store(now, Ordering::Relaxed);
+        }
+    }
+
+    /// Makes room for the super keys of `user_id` and marks them as most recently used. If the
+    /// super keys of more than `max_cached_users` users are cached, those of the least recently
+    /// used locked users are evicted, i.e., of users whose screen lock bound keys are not in
+    /// memory. The system user and the foreground user are never evicted, so the cache may
+    /// exceed its size if there is no other user to evict. The key material of evicted keys is
+    /// zeroized when the last reference to them is dropped. They are derived again when the user
+    /// unlocks next, and are not available until then.
+    fn cache_user(&mut self, user_id: UserId) {
+        self.user_keys.entry(user_id).or_default();
+        self.mark_used(user_id);
+        while self.user_keys.len() > self.max_cached_users {
+            let least_recently_used = self
+                .user_keys
+                .iter()
+                .filter(|(id, entry)| {
+                    **id != user_id
+                        && **id != USER_SYSTEM
+                        && Some(**id) != self.foreground_user
+                        && entry.screen_lock_bound.is_none()
+                })
+                .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(id, _)| *id);
+            match least_recently_used {
+                Some(evicted) => {
+                    log::info!("Evicting the super keys of user {} from the cache.", evicted);
+                    self.user_keys.remove(&evicted);
+                    self.key_index.retain(|_, (owner, _)| *owner != evicted);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Tracks consecutive failed password unlocks per user and enforces an exponentially growing
+/// delay before the next attempt. Attempts within the delay are rejected with
+/// `ResponseCode::BACKEND_BUSY` without trying the password, even if it is correct. The state is
+/// kept in memory only and is lost on reboot.
+#[derive(Debug)]
+struct UnlockBackoff {
+    base: Duration,
+    max: Duration,
+    /// Number of consecutive failures and the time of the last failure.
+    failures: HashMap<UserId, (u32, Instant)>,
+}
+
+impl Default for UnlockBackoff {
+    fn default() -> Self {
+        Self::new(
+            read_prop_duration(UNLOCK_BACKOFF_BASE_PROPERTY, DEFAULT_UNLOCK_BACKOFF_BASE),
+            read_prop_duration(UNLOCK_BACKOFF_MAX_PROPERTY, DEFAULT_UNLOCK_BACKOFF_MAX),
+        )
+    }
 }
 
+impl UnlockBackoff {
+    fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, failures: Default::default() }
+    }
+
+    /// Returns the delay enforced after `failures` consecutive failures.
+    fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+
+    /// Returns the time that must still elapse at `now` before the user may attempt to
+    /// unlock again.
+    fn remaining(&self, user_id: UserId, now: Instant) -> Duration {
+        match self.failures.get(&user_id) {
+            Some((failures, last_failure)) => {
+                self.delay(*failures).saturating_sub(now.saturating_duration_since(*last_failure))
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Fails with `ResponseCode::BACKEND_BUSY` if the delay since the last failure of the user
+    /// has not yet elapsed.
+    fn check(&self, user_id: UserId, now: Instant) -> Result<()> {
+        let remaining = self.remaining(user_id, now);
+        if remaining.is_zero() {
+            Ok(())
+        } else {
+            Err(Error::Rc(ResponseCode::BACKEND_BUSY)).context(ks_err!(
+                "Unlock of user {} is backed off for {:?}.",
+                user_id,
+                remaining
+            ))
+        }
+    }
+
+    fn record_failure(&mut self, user_id: UserId, now: Instant) {
+        let entry = self.failures.entry(user_id).or_insert((0, now));
+        *entry = (entry.0.saturating_add(1), now);
+    }
+
+    fn record_success(&mut self, user_id: UserId) {
+        self.failures.remove(&user_id);
+    }
+}
+
+/// The users whose per-boot super key is being unlocked, see
+/// `SuperKeyManager::unlock_user_single_flight`.
 #[derive(Default)]
+struct UnlocksInFlight {
+    users: Mutex<HashSet<UserId>>,
+    cond_var: Condvar,
+}
+
+impl UnlocksInFlight {
+    /// Returns true if the caller shall unlock `user_id`, in which case it must call `finish`
+    /// afterwards. If another thread is unlocking the user, this blocks until that thread is
+    /// finished and returns false.
+    fn join(&self, user_id: UserId) -> bool {
+        let mut users = self.users.lock().unwrap();
+        if users.insert(user_id) {
+            return true;
+        }
+        while users.contains(&user_id) {
+            users = self.cond_var.wait(users).unwrap();
+        }
+        false
+    }
+
+    fn finish(&self, user_id: UserId) {
+        self.users.lock().unwrap().remove(&user_id);
+        self.cond_var.notify_all();
+    }
+}
+
+#[derive(Default)]
+/// Returns the items for which `predicate` holds, in their order. The items are split into up
+/// to `workers` chunks, each of which is checked on a thread of its own, so that a slow
+/// predicate, e.g., one that decrypts a blob, can use multiple cores. With a single worker, the
+/// items are checked on the calling thread.
+fn filter_in_parallel<T, F>(items: &[T], workers: usize, predicate: F) -> Vec<&T>
+where
+    T: Sync,
+    F: Fn(&T) -> bool + Sync,
+{
+    if workers <= 1 || items.len() <= 1 {
+        return items.iter().filter(|item| predicate(item)).collect();
+    }
+    let predicate = &predicate;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(items.len().div_ceil(workers))
+            .map(|chunk| {
+                scope.spawn(move || chunk.iter().filter(|item| predicate(item)).collect::<Vec<_>>())
+            })
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
 pub struct SuperKeyManager {
     data: SkmState,
+    unlock_backoff: UnlockBackoff,
+    unlocks_in_flight: Arc<UnlocksInFlight>,
 }
 
 impl SuperKeyManager {
@@ -288,8 +815,8 @@ impl SuperKeyManager {
         }
         let level_zero_key =
             get_level_zero_key(db).context(ks_err!("get_level_zero_key failed"))?;
-        skm_guard.data.boot_level_key_cache =
-            Some(Mutex::new(BootLevelKeyCache::new(level_zero_key)));
+        skm_guard.data.boot_level_key_cache = Some(BootLevelKeyCache::new(level_zero_key));
+        notify_super_key_available();
         log::info!("Starting boot level watcher.");
         let clone = skm.clone();
         std::thread::spawn(move || {
@@ -312,15 +839,13 @@ impl SuperKeyManager {
             // This scope limits the skm_guard life, so we don't hold the skm_guard while
             // waiting.
             {
-                let mut skm_guard = skm.write().unwrap();
+                let skm_guard = skm.read().unwrap();
                 let boot_level_key_cache = skm_guard
                     .data
                     .boot_level_key_cache
-                    .as_mut()
+                    .as_ref()
                     .ok_or_else(Error::sys)
-                    .context(ks_err!("Boot level cache not initialized"))?
-                    .get_mut()
-                    .unwrap();
+                    .context(ks_err!("Boot level cache not initialized"))?;
                 if level < MAX_MAX_BOOT_LEVEL {
                     log::info!("Read keystore.boot_level value {}", level);
                     boot_level_key_cache
@@ -345,35 +870,54 @@ impl SuperKeyManager {
         self.data
             .boot_level_key_cache
             .as_ref()
-            .map_or(false, |c| c.lock().unwrap().level_accessible(boot_level as usize))
+            .map_or(false, |c| c.level_accessible(boot_level as usize))
     }
 
     pub fn forget_all_keys_for_user(&mut self, user: UserId) {
         self.data.user_keys.remove(&user);
     }
 
+    /// Returns true if the per boot key of the given user is in the cache, i.e., if the user
+    /// unlocked since boot and the key was not evicted since. Unlike `get_user_state`, this
+    /// does not hand out the key, does not touch the database, and does not count as a use of
+    /// the key for the eviction order.
+    pub fn is_user_unlocked(&self, user_id: UserId) -> bool {
+        self.data.user_keys.get(&user_id).map_or(false, |e| e.per_boot.is_some())
+    }
+
     fn install_per_boot_key_for_user(
         &mut self,
         user: UserId,
         super_key: Arc<SuperKey>,
     ) -> Result<()> {
         self.data
-            .add_key_to_key_index(&super_key)
+            .add_key_to_key_index(user, &super_key)
             .context(ks_err!("add_key_to_key_index failed"))?;
+        self.data.cache_user(user);
         self.data.user_keys.entry(user).or_default().per_boot = Some(super_key);
         Ok(())
     }
 
-    fn lookup_key(&self, key_id: &SuperKeyIdentifier) -> Result<Option<Arc<SuperKey>>> {
+    /// Looks up the super key with the given identifier. Keys from the database are also
+    /// selected by their `version`. Boot level keys have no versions.
+    fn lookup_key(
+        &self,
+        key_id: &SuperKeyIdentifier,
+        version: i32,
+    ) -> Result<Option<Arc<SuperKey>>> {
         Ok(match key_id {
             SuperKeyIdentifier::DatabaseId(id) => {
-                self.data.key_index.get(id).and_then(|k| k.upgrade())
+                self.data.key_index.get(&(*id, version)).and_then(|(user_id, k)| {
+                    let super_key = k.upgrade()?;
+                    self.data.mark_used(*user_id);
+                    Some(super_key)
+                })
             }
             SuperKeyIdentifier::BootLevel(level) => self
                 .data
                 .boot_level_key_cache
                 .as_ref()
-                .map(|b| b.lock().unwrap().aes_key(*level as usize))
+                .map(|b| b.aes_key(*level as usize))
                 .transpose()
                 .context(ks_err!("aes_key failed"))?
                 .flatten()
@@ -382,12 +926,29 @@ impl SuperKeyManager {
                         algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
                         key,
                         id: *key_id,
+                        version: INITIAL_SUPER_KEY_VERSION,
                         reencrypt_with: None,
+                        verification_token: None,
                     })
                 }),
         })
     }
 
+    /// Returns the error for a super key that `lookup_key` did not find. Super keys that were
+    /// never in memory since boot, or that were evicted from the cache, are not available
+    /// until the user unlocks. All others were dropped because the device was locked.
+    fn missing_key_error(&self, key_id: &SuperKeyIdentifier, version: i32) -> Error {
+        let was_available = match key_id {
+            SuperKeyIdentifier::DatabaseId(id) => self.data.key_index.contains_key(&(*id, version)),
+            SuperKeyIdentifier::BootLevel(_) => self.data.boot_level_key_cache.is_some(),
+        };
+        if was_available {
+            Error::Rc(ResponseCode::LOCKED)
+        } else {
+            Error::SuperKeyUnavailable
+        }
+    }
+
     pub fn get_per_boot_key_by_user_id(
         &self,
         user_id: UserId,
@@ -397,6 +958,7 @@ impl SuperKeyManager {
     }
 
     fn get_per_boot_key_by_user_id_internal(&self, user_id: UserId) -> Option<Arc<SuperKey>> {
+        self.data.mark_used(user_id);
         self.data.user_keys.get(&user_id).and_then(|e| e.per_boot.as_ref().cloned())
     }
 
@@ -445,22 +1007,91 @@ impl SuperKeyManager {
         Ok(())
     }
 
+    /// Returns true if the super key identified by `key_id` is the sign only key of a locked
+    /// user. Keys encrypted with it may only be used for signing right now. The screen lock
+    /// bound key is present exactly while the user is unlocked.
+    fn is_sign_only_while_locked(&self, key_id: &SuperKeyIdentifier) -> bool {
+        let id = match key_id {
+            SuperKeyIdentifier::DatabaseId(id) => *id,
+            SuperKeyIdentifier::BootLevel(_) => return false,
+        };
+        self.data.user_keys.values().any(|entry| {
+            entry.screen_lock_bound.is_none()
+                && matches!(&entry.sign_only, Some(super_key)
+                    if matches!(super_key.id, SuperKeyIdentifier::DatabaseId(i) if i == id))
+        })
+    }
+
+    /// Like `unwrap_key_if_required`, but for a key that is about to be used in an operation
+    /// with the given purpose. Keys encrypted with the sign only super key of a locked user
+    /// can only be unwrapped for signing.
+    pub fn unwrap_key_for_operation<'a>(
+        &self,
+        metadata: &BlobMetaData,
+        blob: &'a [u8],
+        binding: Option<&BlobBinding>,
+        purpose: KeyPurpose,
+    ) -> Result<KeyBlob<'a>> {
+        if let Some(super_key_id) = SuperKeyIdentifier::from_metadata(metadata) {
+            if purpose != KeyPurpose::SIGN && self.is_sign_only_while_locked(&super_key_id) {
+                return Err(Error::Rc(ResponseCode::LOCKED)).context(ks_err!(
+                    "Only signing is permitted while the device is locked, requested {:?}.",
+                    purpose
+                ));
+            }
+        }
+        self.unwrap_key_if_required(metadata, blob, binding)
+    }
+
+    /// Like `unwrap_key_if_required`, but for a key whose material is about to leave keystore,
+    /// e.g., to be wrapped for export. Keys encrypted with the sign only super key of a locked
+    /// user cannot be unwrapped for export.
+    pub fn unwrap_key_for_export<'a>(
+        &self,
+        metadata: &BlobMetaData,
+        blob: &'a [u8],
+        binding: Option<&BlobBinding>,
+    ) -> Result<KeyBlob<'a>> {
+        if let Some(super_key_id) = SuperKeyIdentifier::from_metadata(metadata) {
+            if self.is_sign_only_while_locked(&super_key_id) {
+                return Err(Error::Rc(ResponseCode::LOCKED))
+                    .context(ks_err!("Keys cannot be exported while the device is locked."));
+            }
+        }
+        self.unwrap_key_if_required(metadata, blob, binding)
+    }
+
     /// Check if a given key is super-encrypted, from its metadata. If so, unwrap the key using
-    /// the relevant super key.
+    /// the relevant super key. `binding` identifies the key entry that the blob was loaded
+    /// from, see `BlobBinding::load_if_bound`. It is required to unwrap blobs that are bound to
+    /// their key entry.
     pub fn unwrap_key_if_required<'a>(
         &self,
         metadata: &BlobMetaData,
         blob: &'a [u8],
+        binding: Option<&BlobBinding>,
     ) -> Result<KeyBlob<'a>> {
-        Ok(if let Some(key_id) = SuperKeyIdentifier::from_metadata(metadata) {
+        Ok(if let Some(super_key_id) = SuperKeyIdentifier::from_metadata(metadata) {
+            let version = SuperKeyIdentifier::version_from_metadata(metadata);
             let super_key = self
-                .lookup_key(&key_id)
+                .lookup_key(&super_key_id, version)
                 .context(ks_err!("lookup_key failed"))?
-                .ok_or(Error::Rc(ResponseCode::LOCKED))
+                .ok_or_else(|| self.missing_key_error(&super_key_id, version))
                 .context(ks_err!("Required super decryption key is not in memory."))?;
             KeyBlob::Sensitive {
-                key: Self::unwrap_key_with_key(blob, metadata, &super_key)
-                    .context(ks_err!("unwrap_key_with_key failed"))?,
+                key: Self::unwrap_key_with_key(
+                    blob,
+                    metadata,
+                    binding,
+                    &super_key,
+                    WrappingContext::KeyBlob,
+                )
+                .with_context(|| {
+                    ks_err!(
+                        "unwrap_key_with_key failed. {}",
+                        super_key.verify().explain_unwrap_failure()
+                    )
+                })?,
                 reencrypt_with: super_key.reencrypt_with.as_ref().unwrap_or(&super_key).clone(),
                 force_reencrypt: super_key.reencrypt_with.is_some(),
             }
@@ -469,12 +1100,365 @@ impl SuperKeyManager {
         })
     }
 
-    /// Unwraps an encrypted key blob given an encryption key.
-    fn unwrap_key_with_key(blob: &[u8], metadata: &BlobMetaData, key: &SuperKey) -> Result<ZVec> {
+    /// Unwraps the certificate chain `cert_chain` with the metadata `metadata` of the key entry
+    /// given by `binding` if it was wrapped with a super key, see
+    /// `NewKeyEncryption::wrap_cert_chain`, and returns it as is otherwise. Fails with
+    /// `ResponseCode::LOCKED` if the super key is not in memory.
+    pub fn unwrap_cert_chain_if_required(
+        &self,
+        cert_chain: Vec<u8>,
+        metadata: &BlobMetaData,
+        binding: &BlobBinding,
+    ) -> Result<Vec<u8>> {
+        let super_key_id = match SuperKeyIdentifier::from_metadata(metadata) {
+            Some(super_key_id) => super_key_id,
+            None => return Ok(cert_chain),
+        };
+        // Certificate chains were never encrypted with the super key itself.
+        if metadata.wrapping_context() != Some(&WrappingContext::Certificate.to_metadata()) {
+            return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                .context(ks_err!("Certificate chain of another wrapping context."));
+        }
+        let version = SuperKeyIdentifier::version_from_metadata(metadata);
+        let super_key = self
+            .lookup_key(&super_key_id, version)
+            .context(ks_err!("lookup_key failed"))?
+            .ok_or_else(|| self.missing_key_error(&super_key_id, version))
+            .context(ks_err!("Required super decryption key is not in memory."))?;
+        Self::unwrap_key_with_key(
+            &cert_chain,
+            metadata,
+            Some(binding),
+            &super_key,
+            WrappingContext::Certificate,
+        )
+        .map(|cert_chain| cert_chain.to_vec())
+        .context(ks_err!("Failed to unwrap the certificate chain."))
+    }
+
+    /// Takes the certificate chain of `key_entry` from `db`, see `KeyEntry::take_cert_chain`, and
+    /// unwraps it if required, see `unwrap_cert_chain_if_required`.
+    pub fn take_cert_chain(
+        &self,
+        db: &mut KeystoreDB,
+        key_entry: &mut KeyEntry,
+    ) -> Result<Option<Vec<u8>>> {
+        match key_entry.take_cert_chain() {
+            Some(cert_chain)
+                if SuperKeyIdentifier::from_metadata(key_entry.cert_chain_metadata()).is_some() =>
+            {
+                let binding = BlobBinding::load(db, key_entry.id()).context(ks_err!())?;
+                self.unwrap_cert_chain_if_required(
+                    cert_chain,
+                    key_entry.cert_chain_metadata(),
+                    &binding,
+                )
+                .map(Some)
+            }
+            cert_chain => Ok(cert_chain),
+        }
+    }
+
+    /// Tries to unwrap the key blobs of up to `limit` keys, starting after the key id
+    /// `after_key_id`, see `KeystoreDB::load_key_blobs_after`. Returns the ids of the keys whose
+    /// blob failed to unwrap, and the key id to continue after if there may be more keys to
+    /// verify. Blobs whose super key is not in memory, e.g., because the user is locked or has
+    /// not unlocked since boot, are skipped. The unwrapped key material is discarded immediately.
+    /// The blobs are unwrapped by up to `workers` threads in parallel, see `filter_in_parallel`.
+    pub fn verify_key_blobs(
+        &self,
+        db: &mut KeystoreDB,
+        after_key_id: Option<i64>,
+        limit: usize,
+        workers: usize,
+    ) -> Result<(Vec<i64>, Option<i64>)> {
+        let blobs = db
+            .load_key_blobs_after(after_key_id, limit)
+            .context(ks_err!("Failed to load key blobs."))?;
+        let next =
+            if blobs.len() == limit { blobs.last().map(|(key_id, _, _, _)| *key_id) } else { None };
+        let failed = filter_in_parallel(&blobs, workers, |(key_id, owner, blob, metadata)| {
+            let binding = metadata.bound_user_id().map(|_| BlobBinding::new(*key_id, owner));
+            match self.unwrap_key_if_required(metadata, blob, binding.as_ref()) {
+                Ok(_) => false,
+                Err(e) => !matches!(
+                    e.root_cause().downcast_ref::<Error>(),
+                    Some(Error::Rc(ResponseCode::LOCKED)) | Some(Error::SuperKeyUnavailable)
+                ),
+            }
+        })
+        .into_iter()
+        .map(|(key_id, _, _, _)| *key_id)
+        .collect();
+        Ok((failed, next))
+    }
+
+    /// Returns the current version of the database super key `super_key_id`, i.e., the greatest
+    /// version that is still in memory, or None if no version of the key is in memory.
+    fn current_key_version(&self, super_key_id: i64) -> Option<Arc<SuperKey>> {
+        self.data
+            .key_index
+            .iter()
+            .filter(|((id, _), _)| *id == super_key_id)
+            .filter_map(|(_, (_, k))| k.upgrade())
+            .max_by_key(|k| k.version)
+    }
+
+    /// Returns true if the blob described by `metadata` is wrapped with a version of a database
+    /// super key that is no longer in memory, although another version of the same super key
+    /// is. This happens if a rotation of the super key was torn, i.e., the old version was
+    /// retired before all blobs were rewrapped with the new one. Blobs of users whose super key
+    /// is not in memory at all cannot be told apart from locked ones and are not skewed.
+    fn has_version_skew(&self, metadata: &BlobMetaData) -> bool {
+        match SuperKeyIdentifier::from_metadata(metadata) {
+            Some(SuperKeyIdentifier::DatabaseId(id)) => {
+                let version = SuperKeyIdentifier::version_from_metadata(metadata);
+                let present = self
+                    .data
+                    .key_index
+                    .get(&(id, version))
+                    .map_or(false, |(_, k)| k.strong_count() > 0);
+                !present && self.current_key_version(id).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks the key blobs of up to `limit` keys, starting after the key id `after_key_id`,
+    /// for version skew, see `has_version_skew`. Returns the ids of the keys whose blob is
+    /// wrapped with a super key version that is no longer present, and the key id to continue
+    /// after if there may be more keys to check, like `verify_key_blobs`. The blobs are not
+    /// unwrapped. Skewed blobs can be rewrapped with `repair_version_skew`.
+    pub fn find_version_skew(
+        &self,
+        db: &mut KeystoreDB,
+        after_key_id: Option<i64>,
+        limit: usize,
+    ) -> Result<(Vec<i64>, Option<i64>)> {
+        let blobs = db
+            .load_key_blobs_after(after_key_id, limit)
+            .context(ks_err!("Failed to load key blobs."))?;
+        let next =
+            if blobs.len() == limit { blobs.last().map(|(key_id, _, _, _)| *key_id) } else { None };
+        let skewed: Vec<i64> = blobs
+            .iter()
+            .filter(|(_, _, _, metadata)| self.has_version_skew(metadata))
+            .map(|(key_id, _, _, _)| *key_id)
+            .collect();
+        if !skewed.is_empty() {
+            log::warn!("Key blobs wrapped with a retired super key version: {:?}", skewed);
+        }
+        Ok((skewed, next))
+    }
+
+    /// Re-runs the rotation from the super key version `retired` to the current version of the
+    /// same super key, see `current_key_version`, for the blobs of the keys `key_ids` only,
+    /// e.g., those reported by `find_version_skew`. Each blob that is still wrapped with
+    /// `retired` is unwrapped with it and rewrapped with the current version. Blobs that were
+    /// rewrapped or replaced in the meantime, and keys that were deleted, are skipped. Returns
+    /// the ids of the keys whose blob was rewrapped. Fails with `ResponseCode::LOCKED` if no
+    /// newer version of the super key is in memory.
+    pub fn repair_version_skew(
+        &self,
+        db: &mut KeystoreDB,
+        retired: &SuperKey,
+        key_ids: &[i64],
+    ) -> Result<Vec<i64>> {
+        let super_key_id = match retired.id {
+            SuperKeyIdentifier::DatabaseId(id) => id,
+            SuperKeyIdentifier::BootLevel(_) => {
+                return Err(Error::sys()).context(ks_err!("Boot level keys have no versions."))
+            }
+        };
+        let current = self
+            .current_key_version(super_key_id)
+            .filter(|k| k.version != retired.version)
+            .ok_or(Error::Rc(ResponseCode::LOCKED))
+            .context(ks_err!("No newer version of super key {} is in memory.", super_key_id))?;
+        let mut repaired = Vec::new();
+        for key_id in key_ids {
+            let key =
+                KeyDescriptor { domain: Domain::KEY_ID, nspace: *key_id, alias: None, blob: None };
+            // The blobs are repaired by keystore itself, not on behalf of a caller.
+            let (key_id_guard, key_entry) = match db.load_key_entry(
+                &key,
+                KeyType::Client,
+                KeyEntryLoadBits::KM,
+                AID_KEYSTORE,
+                |_, _| Ok(()),
+            ) {
+                Ok(loaded) => loaded,
+                Err(e) => match e.root_cause().downcast_ref::<Error>() {
+                    Some(Error::Rc(ResponseCode::KEY_NOT_FOUND)) => continue,
+                    _ => return Err(e).context(ks_err!("Failed to load key {}.", key_id)),
+                },
+            };
+            let (blob, metadata) = match key_entry.key_blob_info() {
+                Some((blob, metadata)) => (blob, metadata),
+                None => continue,
+            };
+            let wrapped_with_retired = matches!(
+                SuperKeyIdentifier::from_metadata(metadata),
+                Some(SuperKeyIdentifier::DatabaseId(id)) if id == super_key_id
+            ) && SuperKeyIdentifier::version_from_metadata(metadata)
+                == retired.version;
+            if !wrapped_with_retired {
+                continue;
+            }
+            let binding = BlobBinding::load(db, *key_id).context(ks_err!())?;
+            let unwrapped = Self::unwrap_key_with_key(
+                blob,
+                metadata,
+                metadata.bound_user_id().map(|_| &binding),
+                retired,
+                WrappingContext::KeyBlob,
+            )
+            .context(ks_err!("Failed to unwrap the blob of key {}.", key_id))?;
+            let (new_blob, mut new_metadata) = Self::encrypt_with_aes_super_key(
+                &unwrapped,
+                &current,
+                &binding,
+                WrappingContext::KeyBlob,
+            )
+            .context(ks_err!("Failed to rewrap the blob of key {}.", key_id))?;
+            if let Some(km_uuid) = metadata.km_uuid() {
+                new_metadata.add(BlobMetaEntry::KmUuid(*km_uuid));
+            }
+            db.set_blob(
+                &key_id_guard,
+                SubComponentType::KEY_BLOB,
+                Some(&new_blob),
+                Some(&new_metadata),
+            )
+            .context(ks_err!("Failed to store the blob of key {}.", key_id))?;
+            repaired.push(*key_id);
+        }
+        Ok(repaired)
+    }
+
+    /// Rewraps the super encrypted `blob` with `metadata`, which is bound to the key entry given
+    /// by `from`, for the key entry given by `to`. The blob stays encrypted with the same super
+    /// key, wrapping context, and encryption scheme. Returns None if the blob is not super
+    /// encrypted or not bound, if the super key is not in memory, or if the blob cannot be
+    /// unwrapped with it, e.g., because the super key of the same id belongs to another device.
+    pub fn rewrap_bound_blob(
+        &self,
+        blob: &[u8],
+        metadata: &BlobMetaData,
+        context: WrappingContext,
+        from: &BlobBinding,
+        to: &BlobBinding,
+    ) -> Result<Option<(Vec<u8>, BlobMetaData)>> {
+        let super_key_id = match SuperKeyIdentifier::from_metadata(metadata) {
+            Some(super_key_id) if metadata.bound_user_id().is_some() => super_key_id,
+            _ => return Ok(None),
+        };
+        let version = SuperKeyIdentifier::version_from_metadata(metadata);
+        let super_key = match self.lookup_key(&super_key_id, version).context(ks_err!())? {
+            Some(super_key) => super_key,
+            None => {
+                log::warn!("Super key {:?} is not in memory, cannot rewrap blob.", super_key_id);
+                return Ok(None);
+            }
+        };
+        let unwrapped =
+            match Self::unwrap_key_with_key(blob, metadata, Some(from), &super_key, context) {
+                Ok(unwrapped) => unwrapped,
+                Err(e) => {
+                    log::warn!("Failed to unwrap blob of key entry {}: {:?}", from.key_id, e);
+                    return Ok(None);
+                }
+            };
+        let scheme = EncryptionScheme::from_metadata(metadata).context(ks_err!())?;
+        let (new_blob, mut new_metadata) = Self::encrypt_with_aes_super_key_and_scheme(
+            &unwrapped, &super_key, to, context, scheme,
+        )
+        .context(ks_err!("Failed to rewrap the blob of key entry {}.", from.key_id))?;
+        if let Some(km_uuid) = metadata.km_uuid() {
+            new_metadata.add(BlobMetaEntry::KmUuid(*km_uuid));
+        }
+        if let Some(max_boot_level) = metadata.max_boot_level() {
+            new_metadata.add(BlobMetaEntry::MaxBootLevel(*max_boot_level));
+        }
+        Ok(Some((new_blob, new_metadata)))
+    }
+
+    /// Rewraps the blob of type `sc_type` of the key entry `old_key_id` of a backup archive,
+    /// which is owned by `owner`, for the new key id `new_key_id` of the imported entry, see
+    /// `rewrap_bound_blob`. This is the `RewrapBlob` of `KeystoreDB::import_backup`.
+    pub fn rewrap_imported_blob(
+        &self,
+        owner: &KeyDescriptor,
+        old_key_id: i64,
+        new_key_id: i64,
+        sc_type: SubComponentType,
+        blob: &[u8],
+        metadata: &BlobMetaData,
+    ) -> Result<Option<(Vec<u8>, BlobMetaData)>> {
+        let context = match sc_type {
+            SubComponentType::KEY_BLOB => WrappingContext::KeyBlob,
+            SubComponentType::CERT_CHAIN => WrappingContext::Certificate,
+            _ => return Ok(None),
+        };
+        self.rewrap_bound_blob(
+            blob,
+            metadata,
+            context,
+            &BlobBinding::new(old_key_id, owner),
+            &BlobBinding::new(new_key_id, owner),
+        )
+        .context(ks_err!())
+    }
+
+    /// Unwraps an encrypted key blob given an encryption key. If the blob is bound to its key
+    /// entry, `binding` must identify the key entry that the blob was loaded from. Blobs that
+    /// were wrapped with a derived wrapping key can only be unwrapped in the `context` that they
+    /// were wrapped in.
+    fn unwrap_key_with_key(
+        blob: &[u8],
+        metadata: &BlobMetaData,
+        binding: Option<&BlobBinding>,
+        key: &SuperKey,
+        context: WrappingContext,
+    ) -> Result<ZVec> {
         match key.algorithm {
             SuperEncryptionAlgorithm::Aes256Gcm => match (metadata.iv(), metadata.aead_tag()) {
                 (Some(iv), Some(tag)) => {
-                    key.decrypt(blob, iv, tag).context(ks_err!("Failed to decrypt the key blob."))
+                    // Blobs written before the introduction of the wrapping contexts are
+                    // encrypted with the super key itself.
+                    let wrapping_key = match metadata.wrapping_context() {
+                        None => None,
+                        Some(c) if *c == context.to_metadata() => Some(
+                            key.wrapping_key(context)
+                                .context(ks_err!("Failed to get the wrapping key."))?,
+                        ),
+                        Some(c) => {
+                            return Err(Error::Rc(ResponseCode::VALUE_CORRUPTED)).context(ks_err!(
+                                "Blob of wrapping context {} used as {:?}.",
+                                c,
+                                context
+                            ))
+                        }
+                    };
+                    let wrapping_key = wrapping_key.as_deref().unwrap_or(&key.key);
+                    let scheme = EncryptionScheme::from_metadata(metadata).context(ks_err!())?;
+                    match metadata.bound_user_id() {
+                        // Blobs written before the introduction of the binding have no AAD.
+                        None => scheme
+                            .decrypt(blob, iv, tag, wrapping_key, &[])
+                            .context(ks_err!("Failed to decrypt the key blob.")),
+                        // The recorded user id only marks the blob as bound. The AAD is derived
+                        // from the key entry that the blob was loaded from.
+                        Some(_) => {
+                            let binding =
+                                binding
+                                    .ok_or(Error::Rc(ResponseCode::VALUE_CORRUPTED))
+                                    .context(ks_err!("Bound key blob without a key entry."))?;
+                            scheme
+                                .decrypt(blob, iv, tag, wrapping_key, &binding.aad())
+                                .context(ks_err!("Failed to decrypt the bound key blob."))
+                        }
+                    }
                 }
                 (iv, tag) => Err(Error::Rc(ResponseCode::VALUE_CORRUPTED)).context(ks_err!(
                     "Key has incomplete metadata. Present: iv: {}, aead_tag: {}.",
@@ -536,22 +1520,118 @@ impl SuperKeyManager {
         user_id: UserId,
         pw: &Password,
     ) -> Result<UserState> {
-        let alias = &USER_SUPER_KEY;
-        let result = legacy_importer
-            .with_try_import_super_key(user_id, pw, || db.load_super_key(alias, user_id))
-            .context(ks_err!("Failed to load super key"))?;
+        self.with_unlock_backoff(user_id, |skm| {
+            let alias = &USER_SUPER_KEY;
+            let result = legacy_importer
+                .with_try_import_super_key(user_id, pw, || db.load_super_key(alias, user_id))
+                .context(ks_err!("Failed to load super key"))?;
+
+            match result {
+                Some((_, entry)) => {
+                    let super_key = skm
+                        .populate_cache_from_super_key_blob(user_id, alias.algorithm, entry, pw)
+                        .context(ks_err!())?;
+                    Ok(UserState::LskfUnlocked(super_key))
+                }
+                None => Ok(UserState::Uninitialized),
+            }
+        })
+    }
 
+    /// Runs `unlock`, which derives super keys of the user from a password, subject to the
+    /// unlock backoff. Attempts are rejected while the backoff of the user is in effect, before
+    /// the password is tried. See `record_unlock_attempt` for how the outcome is recorded.
+    fn with_unlock_backoff<T, F>(&mut self, user_id: UserId, unlock: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        self.unlock_backoff.check(user_id, Instant::now()).context(ks_err!())?;
+        let result = unlock(self);
+        self.record_unlock_attempt(user_id, &result);
+        result
+    }
+
+    /// Records the outcome of an unlock attempt of the user in the unlock backoff. A successful
+    /// unlock resets the backoff. Only a failure to decrypt, i.e., a wrong password, extends it,
+    /// other failures leave it as it is.
+    fn record_unlock_attempt<T>(&mut self, user_id: UserId, result: &Result<T>) {
         match result {
-            Some((_, entry)) => {
-                let super_key = self
-                    .populate_cache_from_super_key_blob(user_id, alias.algorithm, entry, pw)
-                    .context(ks_err!())?;
-                Ok(UserState::LskfUnlocked(super_key))
+            Ok(_) => self.unlock_backoff.record_success(user_id),
+            Err(e) => {
+                if let Some(keystore2_crypto::Error::DecryptionFailed) =
+                    e.root_cause().downcast_ref::<keystore2_crypto::Error>()
+                {
+                    self.unlock_backoff.record_failure(user_id, Instant::now());
+                }
             }
-            None => Ok(UserState::Uninitialized),
         }
     }
 
+    /// Like `unlock_and_get_user_state`, but the lock of `skm` is not held while the super key
+    /// is derived from the password, and concurrent unlocks of the same user share a single
+    /// derivation: The first thread derives the key, and the others wait for it and then take
+    /// the key from the cache. If the first thread fails, e.g., because its password was
+    /// wrong, the next waiting thread tries its own password.
+    pub fn unlock_user_single_flight(
+        skm: &RwLock<Self>,
+        db: &mut KeystoreDB,
+        legacy_importer: &LegacyImporter,
+        user_id: UserId,
+        password: &Password,
+    ) -> Result<UserState> {
+        Self::unlock_single_flight_with(skm, user_id, || {
+            let alias = &USER_SUPER_KEY;
+            legacy_importer
+                .with_try_import_super_key(user_id, password, || db.load_super_key(alias, user_id))
+                .context(ks_err!("Failed to load super key"))?
+                .map(|(_, entry)| {
+                    Self::extract_super_key_from_key_entry(alias.algorithm, entry, password, None)
+                })
+                .transpose()
+        })
+    }
+
+    /// Implements `unlock_user_single_flight`. `derive` returns the decrypted super key of the
+    /// user, or None if the user has none.
+    fn unlock_single_flight_with<F>(
+        skm: &RwLock<Self>,
+        user_id: UserId,
+        derive: F,
+    ) -> Result<UserState>
+    where
+        F: FnOnce() -> Result<Option<Arc<SuperKey>>>,
+    {
+        let in_flight = skm.read().unwrap().unlocks_in_flight.clone();
+        loop {
+            if let Some(super_key) =
+                skm.read().unwrap().get_per_boot_key_by_user_id_internal(user_id)
+            {
+                return Ok(UserState::LskfUnlocked(super_key));
+            }
+            if in_flight.join(user_id) {
+                break;
+            }
+            // Another thread finished unlocking the user. Its key is in the cache, unless it
+            // failed.
+        }
+
+        let check = skm.read().unwrap().unlock_backoff.check(user_id, Instant::now());
+        let result = check.context(ks_err!()).and_then(|_| derive());
+        let result = {
+            let mut skm_guard = skm.write().unwrap();
+            skm_guard.record_unlock_attempt(user_id, &result);
+            match result {
+                Ok(Some(super_key)) => skm_guard
+                    .install_per_boot_key_for_user(user_id, super_key.clone())
+                    .map(|_| UserState::LskfUnlocked(super_key)),
+                Ok(None) => Ok(UserState::Uninitialized),
+                Err(e) => Err(e),
+            }
+        };
+        in_flight.finish(user_id);
+        result.context(ks_err!("Failed to unlock super key."))
+    }
+
     /// Checks if user has already setup LSKF (i.e. a super key is persisted in the database or the
     /// legacy database). If so, return LskfLocked state.
     /// If the password is provided, generate a new super key, encrypt with the password,
@@ -625,6 +1705,7 @@ impl SuperKeyManager {
         reencrypt_with: Option<Arc<SuperKey>>,
     ) -> Result<Arc<SuperKey>> {
         if let Some((blob, metadata)) = entry.key_blob_info() {
+            let version = SuperKeyIdentifier::version_from_metadata(metadata);
             let key = match (
                 metadata.encrypted_by(),
                 metadata.salt(),
@@ -653,12 +1734,19 @@ impl SuperKeyManager {
                     ));
                 }
             };
-            Ok(Arc::new(SuperKey {
+            let super_key = SuperKey {
                 algorithm,
                 key,
                 id: SuperKeyIdentifier::DatabaseId(entry.id()),
+                version,
                 reencrypt_with,
-            }))
+                verification_token: metadata.super_key_verification_token().cloned(),
+            };
+            if super_key.verify() == SuperKeyVerification::WrongKey {
+                return Err(Error::sys())
+                    .context(ks_err!("Super key {} failed verification.", entry.id()));
+            }
+            Ok(Arc::new(super_key))
         } else {
             Err(Error::Rc(ResponseCode::VALUE_CORRUPTED)).context(ks_err!("No key blob info."))
         }
@@ -680,10 +1768,49 @@ impl SuperKeyManager {
             .context(ks_err!("Failed to encrypt new super key."))?;
         metadata.add(BlobMetaEntry::Iv(iv));
         metadata.add(BlobMetaEntry::AeadTag(tag));
+        metadata.add(BlobMetaEntry::SuperKeyVerificationToken(
+            SuperKeyVerification::create_token(super_key).context(ks_err!())?,
+        ));
         Ok((encrypted_key, metadata))
     }
 
-    // Encrypt the given key blob with the user's super key, if the super key exists and the device
+    /// Rotates the salts of the keys that are derived from the password of `user_id` to encrypt
+    /// the user's super keys. Each super key stored in the database is decrypted with the key
+    /// derived from `pw` and its current salt, and re-encrypted with a key derived from a newly
+    /// generated salt. The super keys themselves do not change, so the blobs that they wrap
+    /// remain valid. All super keys are replaced in a single transaction; if any of them fails to
+    /// decrypt or to be stored, the database keeps the old salts.
+    /// The reference to self is unused but it is required to prevent calling this function
+    /// concurrently with skm state database changes.
+    pub fn rotate_password_salt(
+        &mut self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        pw: &Password,
+    ) -> Result<()> {
+        let mut reencrypted = Vec::new();
+        for key_type in PASSWORD_ENCRYPTED_SUPER_KEYS {
+            let entry = match db.load_super_key(key_type, user_id).context(ks_err!())? {
+                Some((_, entry)) => entry,
+                None => continue,
+            };
+            let version =
+                entry.key_blob_info().as_ref().and_then(|(_, m)| m.super_key_version().copied());
+            let super_key =
+                Self::extract_super_key_from_key_entry(key_type.algorithm, entry, pw, None)
+                    .context(ks_err!("Failed to decrypt super key {}.", key_type.alias))?;
+            let (encrypted_super_key, mut blob_metadata) =
+                Self::encrypt_with_password(&super_key.key, pw).context(ks_err!())?;
+            if let Some(version) = version {
+                blob_metadata.add(BlobMetaEntry::SuperKeyVersion(version));
+            }
+            reencrypted.push((key_type, encrypted_super_key, blob_metadata));
+        }
+        db.replace_super_key_blobs(user_id, &reencrypted)
+            .context(ks_err!("Failed to store re-encrypted super keys."))
+    }
+
+    // Select the user's super key to encrypt a new key blob, if the super key exists and the device
     // is unlocked. If the super key exists and the device is locked, or LSKF is not setup,
     // return error. Note that it is out of the scope of this function to check if super encryption
     // is required. Such check should be performed before calling this function.
@@ -692,16 +1819,12 @@ impl SuperKeyManager {
         db: &mut KeystoreDB,
         legacy_importer: &LegacyImporter,
         user_id: UserId,
-        key_blob: &[u8],
-    ) -> Result<(Vec<u8>, BlobMetaData)> {
+    ) -> Result<NewKeyEncryption> {
         match self
             .get_user_state(db, legacy_importer, user_id)
             .context(ks_err!("Failed to get user state."))?
         {
-            UserState::LskfUnlocked(super_key) => {
-                Self::encrypt_with_aes_super_key(key_blob, &super_key)
-                    .context(ks_err!("Failed to encrypt the key."))
-            }
+            UserState::LskfUnlocked(super_key) => Ok(NewKeyEncryption::Aes(super_key)),
             UserState::LskfLocked => {
                 Err(Error::Rc(ResponseCode::LOCKED)).context(ks_err!("Device is locked."))
             }
@@ -712,26 +1835,61 @@ impl SuperKeyManager {
 
     // Helper function to encrypt a key with the given super key. Callers should select which super
     // key to be used. This is called when a key is super encrypted at its creation as well as at
-    // its upgrade.
+    // its upgrade. The encrypted blob is bound to the key entry given by `binding`, and it is
+    // encrypted with the wrapping key that is derived from the super key for `context`.
     fn encrypt_with_aes_super_key(
         key_blob: &[u8],
         super_key: &SuperKey,
+        binding: &BlobBinding,
+        context: WrappingContext,
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        Self::encrypt_with_aes_super_key_and_scheme(
+            key_blob,
+            super_key,
+            binding,
+            context,
+            EncryptionScheme::configured(),
+        )
+    }
+
+    // Like `encrypt_with_aes_super_key`, but encrypts with the given scheme instead of the
+    // configured one.
+    fn encrypt_with_aes_super_key_and_scheme(
+        key_blob: &[u8],
+        super_key: &SuperKey,
+        binding: &BlobBinding,
+        context: WrappingContext,
+        scheme: EncryptionScheme,
     ) -> Result<(Vec<u8>, BlobMetaData)> {
         if super_key.algorithm != SuperEncryptionAlgorithm::Aes256Gcm {
             return Err(Error::sys()).context(ks_err!("unexpected algorithm"));
         }
+        let wrapping_key =
+            super_key.wrapping_key(context).context(ks_err!("Failed to get the wrapping key."))?;
         let mut metadata = BlobMetaData::new();
-        let (encrypted_key, iv, tag) = aes_gcm_encrypt(key_blob, &(super_key.key))
+        let (encrypted_key, iv, tag) = scheme
+            .encrypt(key_blob, &wrapping_key, &binding.aad())
             .context(ks_err!("Failed to encrypt new super key."))?;
         metadata.add(BlobMetaEntry::Iv(iv));
         metadata.add(BlobMetaEntry::AeadTag(tag));
+        metadata.add(BlobMetaEntry::BoundUserId(binding.user_id as i32));
+        metadata.add(BlobMetaEntry::WrappingContext(context.to_metadata()));
+        // Blobs that predate the encryption scheme metadata are AES-GCM, so AES-GCM is recorded
+        // by the absence of a scheme. This does not make the blob readable by older versions of
+        // keystore, which do not know about the binding and the wrapping context.
+        if scheme != EncryptionScheme::AesGcm {
+            metadata.add(BlobMetaEntry::EncryptionScheme(scheme.to_metadata()));
+        }
         super_key.id.add_to_metadata(&mut metadata);
+        if let SuperKeyIdentifier::DatabaseId(_) = super_key.id {
+            metadata.add(BlobMetaEntry::SuperKeyVersion(super_key.version));
+        }
         Ok((encrypted_key, metadata))
     }
 
-    /// Check if super encryption is required and if so, super-encrypt the key to be stored in
-    /// the database.
-    #[allow(clippy::too_many_arguments)]
+    /// Check if super encryption is required and if so, select the super key that the key to be
+    /// stored in the database gets encrypted with. The encryption itself happens once the key
+    /// entry was created, see `NewKeyEncryption::encrypt`.
     pub fn handle_super_encryption_on_key_init(
         &self,
         db: &mut KeystoreDB,
@@ -740,19 +1898,17 @@ impl SuperKeyManager {
         key_parameters: &[KeyParameter],
         flags: Option<i32>,
         user_id: UserId,
-        key_blob: &[u8],
-    ) -> Result<(Vec<u8>, BlobMetaData)> {
+    ) -> Result<NewKeyEncryption> {
         match Enforcements::super_encryption_required(domain, key_parameters, flags) {
-            SuperEncryptionType::None => Ok((key_blob.to_vec(), BlobMetaData::new())),
+            SuperEncryptionType::None => Ok(NewKeyEncryption::None),
             SuperEncryptionType::LskfBound => self
-                .super_encrypt_on_key_init(db, legacy_importer, user_id, key_blob)
+                .super_encrypt_on_key_init(db, legacy_importer, user_id)
                 .context(ks_err!("Failed to super encrypt with LskfBound key.")),
             SuperEncryptionType::ScreenLockBound => {
                 let entry =
                     self.data.user_keys.get(&user_id).and_then(|e| e.screen_lock_bound.as_ref());
                 if let Some(super_key) = entry {
-                    Self::encrypt_with_aes_super_key(key_blob, super_key)
-                        .context(ks_err!("Failed to encrypt with ScreenLockBound key."))
+                    Ok(NewKeyEncryption::Aes(super_key.clone()))
                 } else {
                     // Symmetric key is not available, use public key encryption
                     let loaded = db
@@ -765,44 +1921,62 @@ impl SuperKeyManager {
                         .sec1_public_key()
                         .ok_or_else(Error::sys)
                         .context(ks_err!("sec1_public_key missing."))?;
-                    let mut metadata = BlobMetaData::new();
-                    let (ephem_key, salt, iv, encrypted_key, aead_tag) =
-                        ECDHPrivateKey::encrypt_message(public_key, key_blob)
-                            .context(ks_err!("ECDHPrivateKey::encrypt_message failed."))?;
-                    metadata.add(BlobMetaEntry::PublicKey(ephem_key));
-                    metadata.add(BlobMetaEntry::Salt(salt));
-                    metadata.add(BlobMetaEntry::Iv(iv));
-                    metadata.add(BlobMetaEntry::AeadTag(aead_tag));
-                    SuperKeyIdentifier::DatabaseId(key_id_guard.id())
-                        .add_to_metadata(&mut metadata);
-                    Ok((encrypted_key, metadata))
+                    let super_key_version = key_entry
+                        .key_blob_info()
+                        .as_ref()
+                        .map(|(_, metadata)| SuperKeyIdentifier::version_from_metadata(metadata))
+                        .unwrap_or(INITIAL_SUPER_KEY_VERSION);
+                    Ok(NewKeyEncryption::Ecdh {
+                        public_key: public_key.to_vec(),
+                        super_key_id: key_id_guard.id(),
+                        super_key_version,
+                    })
                 }
             }
+            SuperEncryptionType::SignOnlyWhileLocked => {
+                let super_key = self
+                    .data
+                    .user_keys
+                    .get(&user_id)
+                    .and_then(|e| e.sign_only.as_ref())
+                    .ok_or(Error::Rc(ResponseCode::LOCKED))
+                    .context(ks_err!("Sign only key absent."))?;
+                Ok(NewKeyEncryption::Aes(super_key.clone()))
+            }
             SuperEncryptionType::BootLevel(level) => {
                 let key_id = SuperKeyIdentifier::BootLevel(level);
                 let super_key = self
-                    .lookup_key(&key_id)
+                    .lookup_key(&key_id, INITIAL_SUPER_KEY_VERSION)
                     .context(ks_err!("lookup_key failed"))?
                     .ok_or(Error::Rc(ResponseCode::LOCKED))
                     .context(ks_err!("Boot stage key absent"))?;
-                Self::encrypt_with_aes_super_key(key_blob, &super_key)
-                    .context(ks_err!("Failed to encrypt with BootLevel key."))
+                Ok(NewKeyEncryption::Aes(super_key))
             }
         }
     }
 
     /// Check if a given key needs re-super-encryption, from its KeyBlob type.
     /// If so, re-super-encrypt the key and return a new set of metadata,
-    /// containing the new super encryption information.
-    pub fn reencrypt_if_required<'a>(
+    /// containing the new super encryption information. The re-encrypted key is bound to
+    /// the key entry returned by `binding`, which is only called if re-encryption is required.
+    pub fn reencrypt_if_required<'a, F>(
         key_blob_before_upgrade: &KeyBlob,
         key_after_upgrade: &'a [u8],
-    ) -> Result<(KeyBlob<'a>, Option<BlobMetaData>)> {
+        binding: F,
+    ) -> Result<(KeyBlob<'a>, Option<BlobMetaData>)>
+    where
+        F: FnOnce() -> Result<BlobBinding>,
+    {
         match key_blob_before_upgrade {
             KeyBlob::Sensitive { reencrypt_with: super_key, .. } => {
-                let (key, metadata) =
-                    Self::encrypt_with_aes_super_key(key_after_upgrade, super_key)
-                        .context(ks_err!("Failed to re-super-encrypt key."))?;
+                let binding = binding().context(ks_err!("Failed to get the blob binding."))?;
+                let (key, metadata) = Self::encrypt_with_aes_super_key(
+                    key_after_upgrade,
+                    super_key,
+                    &binding,
+                    WrappingContext::KeyBlob,
+                )
+                .context(ks_err!("Failed to re-super-encrypt key."))?;
                 Ok((KeyBlob::NonSensitive(key), Some(metadata)))
             }
             _ => Ok((KeyBlob::Ref(key_after_upgrade), None)),
@@ -864,7 +2038,9 @@ impl SuperKeyManager {
                 algorithm: key_type.algorithm,
                 key: super_key,
                 id: SuperKeyIdentifier::DatabaseId(key_entry.id()),
+                version: INITIAL_SUPER_KEY_VERSION,
                 reencrypt_with,
+                verification_token: blob_metadata.super_key_verification_token().cloned(),
             }))
         }
     }
@@ -876,6 +2052,33 @@ impl SuperKeyManager {
         user_id: UserId,
         password: &Password,
     ) -> Result<()> {
+        self.with_unlock_backoff(user_id, |skm| {
+            skm.unlock_screen_lock_bound_key_with_password(db, user_id, password)
+        })
+    }
+
+    fn unlock_screen_lock_bound_key_with_password(
+        &mut self,
+        db: &mut KeystoreDB,
+        user_id: UserId,
+        password: &Password,
+    ) -> Result<()> {
+        self.data.foreground_user = Some(user_id);
+        if self.data.user_keys.get(&user_id).and_then(|e| e.sign_only.as_ref()).is_none() {
+            let sign_only = self
+                .get_or_create_super_key(
+                    db,
+                    user_id,
+                    &USER_SIGN_ONLY_WHILE_LOCKED_KEY,
+                    password,
+                    None,
+                )
+                .context(ks_err!("Trying to get or create sign only key."))?;
+            self.data.add_key_to_key_index(user_id, &sign_only)?;
+            self.data.cache_user(user_id);
+            self.data.user_keys.entry(user_id).or_default().sign_only = Some(sign_only);
+        }
+
         let (screen_lock_bound, screen_lock_bound_private) = self
             .data
             .user_keys
@@ -912,8 +2115,9 @@ impl SuperKeyManager {
             .context(ks_err!("Trying to get or create asymmetric key."))?
         };
 
-        self.data.add_key_to_key_index(&aes)?;
-        self.data.add_key_to_key_index(&ecdh)?;
+        self.data.add_key_to_key_index(user_id, &aes)?;
+        self.data.add_key_to_key_index(user_id, &ecdh)?;
+        self.data.cache_user(user_id);
         let entry = self.data.user_keys.entry(user_id).or_default();
         entry.screen_lock_bound = Some(aes);
         entry.screen_lock_bound_private = Some(ecdh);
@@ -928,6 +2132,7 @@ impl SuperKeyManager {
         unlocking_sids: &[i64],
     ) {
         log::info!("Locking screen bound for user {} sids {:?}", user_id, unlocking_sids);
+        self.data.cache_user(user_id);
         let mut entry = self.data.user_keys.entry(user_id).or_default();
         if !unlocking_sids.is_empty() {
             if let (Some(aes), Some(ecdh)) = (
@@ -1004,6 +2209,8 @@ impl SuperKeyManager {
         db: &mut KeystoreDB,
         user_id: UserId,
     ) -> Result<()> {
+        self.data.foreground_user = Some(user_id);
+        self.data.cache_user(user_id);
         let mut entry = self.data.user_keys.entry(user_id).or_default();
         if let Some(biometric) = entry.biometric_unlock.as_ref() {
             let (key_id_guard, key_entry) = db
@@ -1044,8 +2251,8 @@ impl SuperKeyManager {
                         Ok((slb, slbp)) => {
                             entry.screen_lock_bound = Some(slb.clone());
                             entry.screen_lock_bound_private = Some(slbp.clone());
-                            self.data.add_key_to_key_index(&slb)?;
-                            self.data.add_key_to_key_index(&slbp)?;
+                            self.data.add_key_to_key_index(user_id, &slb)?;
+                            self.data.add_key_to_key_index(user_id, &slbp)?;
                             log::info!("Successfully unlocked with biometric");
                             return Ok(());
                         }
@@ -1113,7 +2320,18 @@ impl SuperKeyManager {
             }
             Some(super_key) => {
                 // Keystore won't be notified when changing to a new password when LSKF is
-                // already setup. Therefore, ideally this path wouldn't be reached.
+                // already setup. Therefore, ideally this path wouldn't be reached. If it is, the
+                // password still encrypts the super keys, so their salts are rotated. The old
+                // salts remain valid, so a failed rotation is only logged.
+                if let Some(pw) = password {
+                    if let Err(e) = self.rotate_password_salt(db, user_id, pw) {
+                        log::warn!(
+                            "Failed to rotate the password salts of user {}: {:?}",
+                            user_id,
+                            e
+                        );
+                    }
+                }
                 Ok(UserState::LskfUnlocked(super_key))
             }
             None => {
@@ -1190,6 +2408,76 @@ pub enum UserState {
     Uninitialized,
 }
 
+/// The super encryption of a new key, as selected by
+/// `SuperKeyManager::handle_super_encryption_on_key_init`. The key id of a new key is only known
+/// once its key entry was created, so the encryption is applied in a second step.
+pub enum NewKeyEncryption {
+    /// The key is not super encrypted.
+    None,
+    /// The key is encrypted with the given AES super key and bound to its key entry.
+    Aes(Arc<SuperKey>),
+    /// The key is encrypted with the given ECDH public key of the super key with the database
+    /// id `super_key_id` and the version `super_key_version`. Such keys are re-encrypted with AES on first use, which binds them to
+    /// their key entry.
+    Ecdh { public_key: Vec<u8>, super_key_id: i64, super_key_version: i32 },
+}
+
+impl NewKeyEncryption {
+    /// Encrypts `key_blob` for the key entry given by `binding`. Returns the blob to be stored
+    /// in the database along with its metadata.
+    pub fn encrypt(
+        &self,
+        key_blob: &[u8],
+        binding: &BlobBinding,
+    ) -> Result<(Vec<u8>, BlobMetaData)> {
+        match self {
+            Self::None => Ok((key_blob.to_vec(), BlobMetaData::new())),
+            Self::Aes(super_key) => SuperKeyManager::encrypt_with_aes_super_key(
+                key_blob,
+                super_key,
+                binding,
+                WrappingContext::KeyBlob,
+            )
+            .context(ks_err!("Failed to encrypt the key.")),
+            Self::Ecdh { public_key, super_key_id, super_key_version } => {
+                let mut metadata = BlobMetaData::new();
+                let (ephem_key, salt, iv, encrypted_key, aead_tag) =
+                    ECDHPrivateKey::encrypt_message(public_key, key_blob)
+                        .context(ks_err!("ECDHPrivateKey::encrypt_message failed."))?;
+                metadata.add(BlobMetaEntry::PublicKey(ephem_key));
+                metadata.add(BlobMetaEntry::Salt(salt));
+                metadata.add(BlobMetaEntry::Iv(iv));
+                metadata.add(BlobMetaEntry::AeadTag(aead_tag));
+                SuperKeyIdentifier::DatabaseId(*super_key_id).add_to_metadata(&mut metadata);
+                metadata.add(BlobMetaEntry::SuperKeyVersion(*super_key_version));
+                Ok((encrypted_key, metadata))
+            }
+        }
+    }
+
+    /// Wraps the certificate chain `cert_chain` of the key entry given by `binding` in the
+    /// certificate context of the AES super key that the key blob is encrypted with, so that the
+    /// chain can only be read while the key can be used. Returns None if the key is not
+    /// encrypted with an AES super key; the chain is then stored as is.
+    pub fn wrap_cert_chain(
+        &self,
+        cert_chain: &[u8],
+        binding: &BlobBinding,
+    ) -> Result<Option<(Vec<u8>, BlobMetaData)>> {
+        match self {
+            Self::Aes(super_key) => SuperKeyManager::encrypt_with_aes_super_key(
+                cert_chain,
+                super_key,
+                binding,
+                WrappingContext::Certificate,
+            )
+            .map(Some)
+            .context(ks_err!("Failed to wrap the certificate chain.")),
+            Self::None | Self::Ecdh { .. } => Ok(None),
+        }
+    }
+}
+
 /// This enum represents three states a KeyMint Blob can be in, w.r.t super encryption.
 /// `Sensitive` holds the non encrypted key and a reference to its super key.
 /// `NonSensitive` holds a non encrypted key that is never supposed to be encrypted.
@@ -1233,3 +2521,1072 @@ impl<'a> Deref for KeyBlob<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{CertificateInfo, ExistingAlias, KEYSTORE_UUID};
+    use crate::zeroize_check::{is_zeroized_by, is_zeroized_on_drop};
+    use keystore2_test_utils::TempDir;
+
+    const USER_ID: UserId = 10;
+    const KEY_ID: i64 = 42;
+    const BINDING: BlobBinding = BlobBinding { key_id: KEY_ID, user_id: USER_ID };
+    const KEY_BLOB: &[u8] = b"sign only key blob";
+    const CERT_CHAIN: &[u8] = b"certificate chain";
+
+    fn sign_only_key(skm: &SuperKeyManager) -> &Arc<SuperKey> {
+        skm.data.user_keys.get(&USER_ID).and_then(|e| e.sign_only.as_ref()).unwrap()
+    }
+
+    fn encrypt_sign_only_key(skm: &SuperKeyManager) -> (Vec<u8>, BlobMetaData) {
+        SuperKeyManager::encrypt_with_aes_super_key(
+            KEY_BLOB,
+            sign_only_key(skm),
+            &BINDING,
+            WrappingContext::KeyBlob,
+        )
+        .unwrap()
+    }
+
+    fn is_decryption_failure<T>(result: Result<T>) -> bool {
+        matches!(
+            result.map(|_| ()).unwrap_err().root_cause().downcast_ref::<keystore2_crypto::Error>(),
+            Some(keystore2_crypto::Error::DecryptionFailed)
+        )
+    }
+
+    fn can_use(
+        skm: &SuperKeyManager,
+        blob: &[u8],
+        metadata: &BlobMetaData,
+        purpose: KeyPurpose,
+    ) -> bool {
+        match skm.unwrap_key_for_operation(metadata, blob, Some(&BINDING), purpose) {
+            Ok(key) => {
+                assert_eq!(&*key, KEY_BLOB);
+                true
+            }
+            Err(e) => {
+                assert_eq!(
+                    e.root_cause().downcast_ref::<Error>(),
+                    Some(&Error::Rc(ResponseCode::LOCKED))
+                );
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign_only_while_locked() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        let password: Password = (&b"the password"[..]).into();
+
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        let (blob, metadata) = encrypt_sign_only_key(&skm);
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::SIGN));
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::DECRYPT));
+
+        // While locked, only signing is permitted.
+        skm.lock_screen_lock_bound_key(&mut db, USER_ID, &[]);
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::SIGN));
+        assert!(!can_use(&skm, &blob, &metadata, KeyPurpose::DECRYPT));
+        assert!(!can_use(&skm, &blob, &metadata, KeyPurpose::ENCRYPT));
+        assert!(!can_use(&skm, &blob, &metadata, KeyPurpose::WRAP_KEY));
+        // Nor can the key be exported.
+        assert_eq!(
+            skm.unwrap_key_for_export(&metadata, &blob, Some(&BINDING))
+                .map(|_| ())
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::LOCKED))
+        );
+        // Unwrapping without an operation, e.g., for deletion, is not restricted.
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))?, KEY_BLOB);
+
+        // Unlocking restores full access, and the same sign only key is loaded.
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::SIGN));
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::DECRYPT));
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::WRAP_KEY));
+        assert_eq!(&*skm.unwrap_key_for_export(&metadata, &blob, Some(&BINDING))?, KEY_BLOB);
+
+        // After a reboot, the sign only key is loaded from the database.
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::DECRYPT));
+        Ok(())
+    }
+    #[test]
+    fn test_super_key_verification() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        let password: Password = (&b"the password"[..]).into();
+
+        // The token is stored when the super key is created, and the key is verified when it is
+        // derived again.
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        assert_eq!(sign_only_key(&skm).verify(), SuperKeyVerification::Verified);
+        let (mut blob, metadata) = encrypt_sign_only_key(&skm);
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        let super_key = sign_only_key(&skm);
+        assert_eq!(super_key.verify(), SuperKeyVerification::Verified);
+
+        // A wrongly derived super key fails the verification cleanly.
+        let wrong_key = SuperKey {
+            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+            key: generate_aes256_key()?,
+            id: super_key.id,
+            version: super_key.version,
+            reencrypt_with: None,
+            verification_token: super_key.verification_token.clone(),
+        };
+        assert_eq!(wrong_key.verify(), SuperKeyVerification::WrongKey);
+        assert_eq!(
+            SuperKeyVerification::check(&super_key.key, Some(&[0u8; 4])),
+            SuperKeyVerification::WrongKey
+        );
+        assert_eq!(
+            SuperKeyVerification::check(&super_key.key, None),
+            SuperKeyVerification::NoToken
+        );
+
+        // Since the super key verifies, a blob that fails to unwrap is reported as corrupted.
+        blob[0] ^= 0x01;
+        let error = skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING)).unwrap_err();
+        assert!(format!("{:?}", error).contains("the blob is corrupted"));
+        assert!(is_decryption_failure(Err::<(), _>(error)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_super_keys_are_zeroized() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        let password: Password = (&b"the password"[..]).into();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+
+        let super_key = SuperKey {
+            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+            key: generate_aes256_key()?,
+            id: sign_only_key(&skm).id,
+            version: 0,
+            reencrypt_with: None,
+            verification_token: None,
+        };
+        let buffer = super_key.key.as_ptr();
+        assert!(is_zeroized_on_drop(Arc::new(super_key), buffer));
+
+        // Forgetting the keys of a user, e.g., when the device is locked, zeroes them.
+        let buffer = sign_only_key(&skm).key.as_ptr();
+        assert!(is_zeroized_by(buffer, || skm.forget_all_keys_for_user(USER_ID)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_least_recently_used_super_keys_are_evicted() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm = SuperKeyManager { data: SkmState::new(2), ..Default::default() };
+        let password: Password = (&b"the password"[..]).into();
+        let cached_users = |skm: &SuperKeyManager| {
+            let mut users: Vec<UserId> = skm.data.user_keys.keys().copied().collect();
+            users.sort();
+            users
+        };
+        let encrypt_for_user = |skm: &SuperKeyManager, user_id: UserId| {
+            let super_key =
+                skm.data.user_keys.get(&user_id).and_then(|e| e.sign_only.as_ref()).unwrap();
+            SuperKeyManager::encrypt_with_aes_super_key(
+                KEY_BLOB,
+                super_key,
+                &BINDING,
+                WrappingContext::KeyBlob,
+            )
+            .unwrap()
+        };
+
+        skm.unlock_screen_lock_bound_key(&mut db, 1, &password)?;
+        skm.unlock_screen_lock_bound_key(&mut db, 2, &password)?;
+        let (blob_1, metadata_1) = encrypt_for_user(&skm, 1);
+        let (blob_2, metadata_2) = encrypt_for_user(&skm, 2);
+        let evicted_key = skm
+            .data
+            .user_keys
+            .get(&2)
+            .and_then(|e| e.sign_only.as_ref())
+            .map(Arc::downgrade)
+            .unwrap();
+        skm.lock_screen_lock_bound_key(&mut db, 1, &[]);
+        skm.lock_screen_lock_bound_key(&mut db, 2, &[]);
+
+        // Using a key of user 1 makes user 2 the least recently used one.
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata_1, &blob_1, Some(&BINDING))?, KEY_BLOB);
+        skm.unlock_screen_lock_bound_key(&mut db, 3, &password)?;
+        assert_eq!(cached_users(&skm), vec![1, 3]);
+        // No reference to the evicted key remains, so its key material was zeroized, and the
+        // key is no longer known until the user unlocks again.
+        assert!(evicted_key.upgrade().is_none());
+        assert!(skm.data.key_index.values().all(|(user_id, _)| *user_id != 2));
+        assert_eq!(
+            skm.unwrap_key_if_required(&metadata_2, &blob_2, Some(&BINDING))
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::SuperKeyUnavailable)
+        );
+
+        // The super keys of user 2 are derived again when the user unlocks, which evicts the
+        // locked user 1 rather than the unlocked user 3.
+        skm.unlock_screen_lock_bound_key(&mut db, 2, &password)?;
+        assert_eq!(cached_users(&skm), vec![2, 3]);
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata_2, &blob_2, Some(&BINDING))?, KEY_BLOB);
+
+        // Users that are unlocked or in the foreground are not evicted, even if that exceeds the
+        // size of the cache.
+        skm.unlock_screen_lock_bound_key(&mut db, 4, &password)?;
+        assert_eq!(cached_users(&skm), vec![2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_user_super_keys_are_not_evicted() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm = SuperKeyManager { data: SkmState::new(1), ..Default::default() };
+        let password: Password = (&b"the password"[..]).into();
+
+        skm.unlock_screen_lock_bound_key(&mut db, USER_SYSTEM, &password)?;
+        skm.lock_screen_lock_bound_key(&mut db, USER_SYSTEM, &[]);
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        skm.lock_screen_lock_bound_key(&mut db, USER_ID, &[]);
+        let mut users: Vec<UserId> = skm.data.user_keys.keys().copied().collect();
+        users.sort();
+        assert_eq!(users, vec![USER_SYSTEM, USER_ID]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_user_unlocked() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let temp_dir = TempDir::new("test_is_user_unlocked")?;
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+        let mut skm = SuperKeyManager { data: SkmState::new(1), ..Default::default() };
+        let password: Password = (&b"the password"[..]).into();
+        assert!(!skm.is_user_unlocked(USER_ID));
+
+        skm.unlock_user_key(&mut db, USER_ID, &password, &legacy_blob_loader)?;
+        assert!(skm.is_user_unlocked(USER_ID));
+        assert!(!skm.is_user_unlocked(USER_ID + 1));
+
+        // Unlocking another user evicts the super keys of the first one from the cache.
+        skm.unlock_user_key(&mut db, USER_ID + 1, &password, &legacy_blob_loader)?;
+        assert!(!skm.is_user_unlocked(USER_ID));
+        assert!(skm.is_user_unlocked(USER_ID + 1));
+
+        // The state follows the cache across unlocks and forgetting.
+        skm.unlock_user_key(&mut db, USER_ID, &password, &legacy_blob_loader)?;
+        assert!(skm.is_user_unlocked(USER_ID));
+        assert!(!skm.is_user_unlocked(USER_ID + 1));
+        skm.forget_all_keys_for_user(USER_ID);
+        assert!(!skm.is_user_unlocked(USER_ID));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_password_salt() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        let password: Password = (&b"the password"[..]).into();
+
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        let (blob, metadata) = encrypt_sign_only_key(&skm);
+        let load_salts = |db: &mut KeystoreDB| -> Result<Vec<Vec<u8>>> {
+            let mut salts = Vec::new();
+            for key_type in PASSWORD_ENCRYPTED_SUPER_KEYS {
+                if let Some((_, entry)) = db.load_super_key(key_type, USER_ID)? {
+                    let (_, metadata) = entry.key_blob_info().as_ref().unwrap();
+                    salts.push(metadata.salt().unwrap().clone());
+                }
+            }
+            Ok(salts)
+        };
+        let old_salts = load_salts(&mut db)?;
+        assert_eq!(old_salts.len(), 3);
+
+        // A wrong password fails the rotation without changing any salt.
+        let wrong_password: Password = (&b"wrong password"[..]).into();
+        assert!(skm.rotate_password_salt(&mut db, USER_ID, &wrong_password).is_err());
+        assert_eq!(load_salts(&mut db)?, old_salts);
+
+        skm.rotate_password_salt(&mut db, USER_ID, &password)?;
+        let new_salts = load_salts(&mut db)?;
+        assert_eq!(new_salts.len(), 3);
+        for (old_salt, new_salt) in old_salts.iter().zip(&new_salts) {
+            assert_ne!(old_salt, new_salt);
+        }
+
+        // After a reboot, the same super keys are derived with the new salts, so existing key
+        // blobs remain usable.
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::DECRYPT));
+        assert_eq!(load_salts(&mut db)?, new_salts);
+
+        // A password change notification for an unlocked user rotates the salts, including the
+        // salt of the user's super key.
+        let temp_dir = TempDir::new("test_rotate_password_salt")?;
+        let legacy_blob_loader = LegacyBlobLoader::new(temp_dir.path());
+        skm.unlock_user_key(&mut db, USER_ID, &password, &legacy_blob_loader)?;
+        let old_salts = load_salts(&mut db)?;
+        assert_eq!(old_salts.len(), 4);
+        let legacy_importer = LegacyImporter::new(Arc::new(Default::default()));
+        assert!(matches!(
+            skm.reset_or_init_user_and_get_user_state(
+                &mut db,
+                &legacy_importer,
+                USER_ID,
+                Some(&password)
+            )?,
+            UserState::LskfUnlocked(_)
+        ));
+        let new_salts = load_salts(&mut db)?;
+        assert_eq!(new_salts.len(), 4);
+        for (old_salt, new_salt) in old_salts.iter().zip(&new_salts) {
+            assert_ne!(old_salt, new_salt);
+        }
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_user_key(&mut db, USER_ID, &password, &legacy_blob_loader)?;
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        assert!(can_use(&skm, &blob, &metadata, KeyPurpose::DECRYPT));
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_unlocks_share_one_derivation() -> Result<()> {
+        const THREADS: usize = 4;
+        let skm: Arc<RwLock<SuperKeyManager>> = Default::default();
+        let derivations = Arc::new(AtomicU64::new(0));
+        let barrier = Arc::new(std::sync::Barrier::new(THREADS));
+        let key = Arc::new(SuperKey {
+            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+            key: generate_aes256_key()?,
+            id: SuperKeyIdentifier::DatabaseId(KEY_ID),
+            version: INITIAL_SUPER_KEY_VERSION,
+            reencrypt_with: None,
+            verification_token: None,
+        });
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (skm, derivations, barrier, key) =
+                    (skm.clone(), derivations.clone(), barrier.clone(), key.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    SuperKeyManager::unlock_single_flight_with(&skm, USER_ID, || {
+                        derivations.fetch_add(1, Ordering::SeqCst);
+                        // Keep the derivation in flight while the other threads try to unlock.
+                        std::thread::sleep(Duration::from_millis(200));
+                        Ok(Some(key))
+                    })
+                })
+            })
+            .collect();
+        for thread in threads {
+            let state = thread.join().unwrap()?;
+            assert!(matches!(state, UserState::LskfUnlocked(k) if Arc::ptr_eq(&k, &key)));
+        }
+        assert_eq!(derivations.load(Ordering::SeqCst), 1);
+
+        // Once unlocked, the key is taken from the cache.
+        let state = SuperKeyManager::unlock_single_flight_with(&skm, USER_ID, || {
+            panic!("The super key must not be derived again.")
+        })?;
+        assert!(matches!(state, UserState::LskfUnlocked(k) if Arc::ptr_eq(&k, &key)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unlock_backoff_grows_and_resets() -> Result<()> {
+        let base = Duration::from_secs(1);
+        let mut backoff = UnlockBackoff::new(base, Duration::from_secs(5));
+        let start = Instant::now();
+        backoff.check(USER_ID, start)?;
+
+        // Every consecutive failure doubles the delay up to the maximum.
+        let mut now = start;
+        for expected in [1, 2, 4, 5, 5] {
+            backoff.record_failure(USER_ID, now);
+            let delay = Duration::from_secs(expected);
+            assert_eq!(backoff.remaining(USER_ID, now), delay);
+            assert_eq!(
+                backoff
+                    .check(USER_ID, now + delay - Duration::from_millis(1))
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Rc(ResponseCode::BACKEND_BUSY))
+            );
+            now += delay;
+            backoff.check(USER_ID, now)?;
+        }
+        // Other users are not affected.
+        backoff.check(USER_ID + 1, now)?;
+
+        // A success resets the backoff.
+        backoff.record_success(USER_ID);
+        backoff.record_failure(USER_ID, now);
+        assert_eq!(backoff.remaining(USER_ID, now), base);
+
+        // A base of 0 disables the backoff.
+        let mut backoff = UnlockBackoff::new(Duration::ZERO, Duration::from_secs(5));
+        backoff.record_failure(USER_ID, start);
+        backoff.record_failure(USER_ID, start);
+        backoff.check(USER_ID, start)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_unlocks_are_backed_off() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_backoff = UnlockBackoff::new(Duration::from_millis(50), Duration::from_secs(1));
+        let password: Password = (&b"the password"[..]).into();
+        let wrong_password: Password = (&b"wrong password"[..]).into();
+
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        skm.lock_screen_lock_bound_key(&mut db, USER_ID, &[]);
+
+        assert!(is_decryption_failure(skm.unlock_screen_lock_bound_key(
+            &mut db,
+            USER_ID,
+            &wrong_password
+        )));
+        // Even the correct password is rejected until the delay elapsed, and the rejected
+        // attempt does not extend the backoff.
+        assert_eq!(
+            skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::BACKEND_BUSY))
+        );
+        assert_eq!(skm.unlock_backoff.failures[&USER_ID].0, 1);
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(is_decryption_failure(skm.unlock_screen_lock_bound_key(
+            &mut db,
+            USER_ID,
+            &wrong_password
+        )));
+        assert_eq!(skm.unlock_backoff.failures[&USER_ID].0, 2);
+
+        // Once the delay elapsed, the correct password unlocks the user and resets the backoff.
+        std::thread::sleep(Duration::from_millis(100));
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &password)?;
+        assert!(!skm.unlock_backoff.failures.contains_key(&USER_ID));
+        Ok(())
+    }
+
+    #[test]
+    fn test_blobs_unwrap_with_recorded_encryption_scheme() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &(&b"the password"[..]).into())?;
+
+        for scheme in EncryptionScheme::ALL {
+            let (blob, mut metadata) = SuperKeyManager::encrypt_with_aes_super_key_and_scheme(
+                KEY_BLOB,
+                sign_only_key(&skm),
+                &BINDING,
+                WrappingContext::KeyBlob,
+                scheme,
+            )?;
+            // Only schemes other than the default AES-GCM are recorded.
+            assert_eq!(
+                metadata.encryption_scheme().copied(),
+                Some(scheme.to_metadata()).filter(|_| scheme != EncryptionScheme::AesGcm)
+            );
+            assert_eq!(EncryptionScheme::from_metadata(&metadata)?, scheme);
+            assert_eq!(&*skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))?, KEY_BLOB);
+
+            // A blob does not decrypt under a different scheme.
+            let other = EncryptionScheme::ALL.into_iter().find(|s| *s != scheme).unwrap();
+            metadata.add(BlobMetaEntry::EncryptionScheme(other.to_metadata()));
+            assert!(is_decryption_failure(skm.unwrap_key_if_required(
+                &metadata,
+                &blob,
+                Some(&BINDING)
+            )));
+
+            metadata.add(BlobMetaEntry::EncryptionScheme(99));
+            assert_eq!(
+                skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))
+                    .map(|_| ())
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Rc(ResponseCode::VALUE_CORRUPTED))
+            );
+        }
+
+        assert_eq!(EncryptionScheme::parse("aes_gcm"), Some(EncryptionScheme::AesGcm));
+        assert_eq!(EncryptionScheme::parse("aes_gcm_siv"), Some(EncryptionScheme::AesGcmSiv));
+        assert_eq!(
+            EncryptionScheme::parse("chacha20_poly1305"),
+            Some(EncryptionScheme::ChaCha20Poly1305)
+        );
+        assert_eq!(EncryptionScheme::parse("aes_cbc"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transplanted_blob_fails_to_decrypt() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &(&b"the password"[..]).into())?;
+
+        let (blob, mut metadata) = encrypt_sign_only_key(&skm);
+        assert_eq!(metadata.bound_user_id(), Some(&(USER_ID as i32)));
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))?, KEY_BLOB);
+
+        // The blob cannot be moved to another key entry.
+        let other_entry = BlobBinding { key_id: KEY_ID + 1, ..BINDING };
+        assert!(is_decryption_failure(skm.unwrap_key_if_required(
+            &metadata,
+            &blob,
+            Some(&other_entry)
+        )));
+        assert_eq!(
+            skm.unwrap_key_if_required(&metadata, &blob, None)
+                .map(|_| ())
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::VALUE_CORRUPTED))
+        );
+
+        // Nor to a key entry of another user, even if its metadata is rewritten to match.
+        let other_user = BlobBinding { user_id: USER_ID + 1, ..BINDING };
+        metadata.add(BlobMetaEntry::BoundUserId(USER_ID as i32 + 1));
+        assert!(is_decryption_failure(skm.unwrap_key_if_required(
+            &metadata,
+            &blob,
+            Some(&other_user)
+        )));
+
+        // The recorded user id does not override the owner of the key entry.
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))?, KEY_BLOB);
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_without_binding_is_readable_and_migrated() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &(&b"the password"[..]).into())?;
+
+        // Blobs written before the binding was introduced are encrypted without AAD.
+        let super_key = sign_only_key(&skm);
+        let (blob, iv, tag) = aes_gcm_encrypt(KEY_BLOB, &super_key.key)?;
+        let mut metadata = BlobMetaData::new();
+        metadata.add(BlobMetaEntry::Iv(iv));
+        metadata.add(BlobMetaEntry::AeadTag(tag));
+        super_key.id.add_to_metadata(&mut metadata);
+        // Nor did they record the super key version or a wrapping context.
+        assert_eq!(metadata.super_key_version(), None);
+        assert_eq!(metadata.wrapping_context(), None);
+
+        let key = skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))?;
+        assert_eq!(&*key, KEY_BLOB);
+
+        // When the key is re-encrypted, e.g., on upgrade, it gets bound to its key entry.
+        let (reencrypted, new_metadata) =
+            SuperKeyManager::reencrypt_if_required(&key, KEY_BLOB, || Ok(BINDING))?;
+        let new_metadata = new_metadata.unwrap();
+        assert_eq!(new_metadata.bound_user_id(), Some(&(USER_ID as i32)));
+        assert_eq!(new_metadata.super_key_version(), Some(&INITIAL_SUPER_KEY_VERSION));
+        assert_eq!(new_metadata.wrapping_context(), Some(&WrappingContext::KeyBlob.to_metadata()));
+        assert_eq!(
+            &*skm.unwrap_key_if_required(&new_metadata, &reencrypted, Some(&BINDING))?,
+            KEY_BLOB
+        );
+        assert!(is_decryption_failure(skm.unwrap_key_if_required(
+            &new_metadata,
+            &reencrypted,
+            Some(&BlobBinding { key_id: KEY_ID + 1, ..BINDING })
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrapping_contexts_are_separated() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &(&b"the password"[..]).into())?;
+        let super_key = sign_only_key(&skm);
+
+        // Certificate chains are wrapped with the certificate wrapping key.
+        let (cert_chain, mut cert_chain_metadata) = NewKeyEncryption::Aes(super_key.clone())
+            .wrap_cert_chain(CERT_CHAIN, &BINDING)?
+            .unwrap();
+        assert_eq!(
+            cert_chain_metadata.wrapping_context(),
+            Some(&WrappingContext::Certificate.to_metadata())
+        );
+        assert_eq!(
+            skm.unwrap_cert_chain_if_required(cert_chain.clone(), &cert_chain_metadata, &BINDING)?,
+            CERT_CHAIN
+        );
+
+        // A chain wrapped with the certificate wrapping key is not accepted as key blob.
+        assert_eq!(
+            skm.unwrap_key_if_required(&cert_chain_metadata, &cert_chain, Some(&BINDING))
+                .map(|_| ())
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::VALUE_CORRUPTED))
+        );
+
+        // Nor can it be unwrapped with the key blob wrapping key by rewriting its metadata.
+        cert_chain_metadata
+            .add(BlobMetaEntry::WrappingContext(WrappingContext::KeyBlob.to_metadata()));
+        assert!(is_decryption_failure(skm.unwrap_key_if_required(
+            &cert_chain_metadata,
+            &cert_chain,
+            Some(&BINDING)
+        )));
+
+        // New key blobs are wrapped with the key blob wrapping key.
+        let (blob, mut metadata) = encrypt_sign_only_key(&skm);
+        assert_eq!(metadata.wrapping_context(), Some(&WrappingContext::KeyBlob.to_metadata()));
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))?, KEY_BLOB);
+
+        // And a key blob is not accepted as certificate chain.
+        assert_eq!(
+            skm.unwrap_cert_chain_if_required(blob.clone(), &metadata, &BINDING)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::VALUE_CORRUPTED))
+        );
+
+        // Certificate chains of keys that are not encrypted with an AES super key are not
+        // wrapped, and chains without wrapping metadata are returned as is.
+        assert!(NewKeyEncryption::None.wrap_cert_chain(CERT_CHAIN, &BINDING)?.is_none());
+        assert_eq!(
+            skm.unwrap_cert_chain_if_required(CERT_CHAIN.to_vec(), &BlobMetaData::new(), &BINDING)?,
+            CERT_CHAIN
+        );
+
+        // A key blob cannot be unwrapped by claiming that it was wrapped with the super key
+        // itself.
+        let mut legacy_metadata = BlobMetaData::new();
+        legacy_metadata.add(BlobMetaEntry::Iv(metadata.iv().unwrap().clone()));
+        legacy_metadata.add(BlobMetaEntry::AeadTag(metadata.aead_tag().unwrap().clone()));
+        legacy_metadata.add(BlobMetaEntry::BoundUserId(USER_ID as i32));
+        super_key.id.add_to_metadata(&mut legacy_metadata);
+        assert!(is_decryption_failure(skm.unwrap_key_if_required(
+            &legacy_metadata,
+            &blob,
+            Some(&BINDING)
+        )));
+
+        // Nor is a blob of another wrapping context accepted as key blob.
+        metadata.add(BlobMetaEntry::WrappingContext(2));
+        assert_eq!(
+            skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))
+                .map(|_| ())
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::VALUE_CORRUPTED))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_key_blobs_reports_corrupted_blob() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        skm.unlock_screen_lock_bound_key(&mut db, USER_ID, &(&b"the password"[..]).into())?;
+        let super_key = sign_only_key(&skm).clone();
+
+        let mut store_key = |alias: &str, corrupt: bool, super_encrypt: bool| -> Result<i64> {
+            let key = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 10001,
+                alias: Some(alias.to_string()),
+                blob: None,
+            };
+            db.store_new_key_with_blob(
+                &key,
+                KeyType::Client,
+                &[],
+                |key_id| {
+                    if !super_encrypt {
+                        return Ok((KEY_BLOB.to_vec(), BlobMetaData::new()));
+                    }
+                    let binding = BlobBinding::new(key_id, &key);
+                    let (mut blob, metadata) = SuperKeyManager::encrypt_with_aes_super_key(
+                        KEY_BLOB,
+                        &super_key,
+                        &binding,
+                        WrappingContext::KeyBlob,
+                    )?;
+                    if corrupt {
+                        blob[0] ^= 1;
+                    }
+                    Ok((blob, metadata))
+                },
+                &CertificateInfo::new(None, None),
+                None,
+                &KeyMetaData::new(),
+                &KEYSTORE_UUID,
+                ExistingAlias::Replace,
+            )
+            .map(|guard| guard.id())
+        };
+        store_key("a", false, true)?;
+        let corrupted = store_key("b", true, true)?;
+        store_key("c", false, false)?;
+        store_key("d", false, true)?;
+
+        // Verify in batches of two to cover the continuation.
+        let mut failed = vec![];
+        let mut after_key_id = None;
+        let mut batches = 0;
+        loop {
+            let (mut batch_failed, next) = skm.verify_key_blobs(&mut db, after_key_id, 2, 1)?;
+            failed.append(&mut batch_failed);
+            batches += 1;
+            match next {
+                Some(next) => after_key_id = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(failed, vec![corrupted]);
+        assert_eq!(batches, 3);
+
+        // Parallel workers find the same blobs.
+        for workers in [2, 3, 8] {
+            assert_eq!(skm.verify_key_blobs(&mut db, None, 10, workers)?, (vec![corrupted], None));
+        }
+
+        // Blobs whose super key is not in memory can't be verified, but are not reported.
+        let locked: SuperKeyManager = Default::default();
+        assert_eq!(locked.verify_key_blobs(&mut db, None, 10, 1)?, (vec![], None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_in_parallel() {
+        let items: Vec<u32> = (0..10).collect();
+        for workers in [0, 1, 2, 3, 4, 10, 16] {
+            let threads = Mutex::new(HashSet::new());
+            let even = filter_in_parallel(&items, workers, |item| {
+                threads.lock().unwrap().insert(std::thread::current().id());
+                item % 2 == 0
+            });
+            // Every item is checked, and the order is preserved.
+            assert_eq!(even, [&0, &2, &4, &6, &8], "{} workers", workers);
+            let threads = threads.into_inner().unwrap();
+            if workers <= 1 {
+                assert_eq!(threads, HashSet::from([std::thread::current().id()]));
+            } else {
+                // Each chunk has a thread of its own, and there are no more chunks than workers.
+                assert_eq!(threads.len(), items.len().div_ceil(items.len().div_ceil(workers)));
+                assert!(threads.len() <= workers);
+                assert!(!threads.contains(&std::thread::current().id()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_unwrap_selects_recorded_super_key_version() -> Result<()> {
+        let mut skm: SuperKeyManager = Default::default();
+        const SUPER_KEY_ID: i64 = 7;
+        let mut versions: Vec<Arc<SuperKey>> = (1..=2)
+            .map(|version| {
+                Arc::new(SuperKey {
+                    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+                    key: generate_aes256_key().unwrap(),
+                    id: SuperKeyIdentifier::DatabaseId(SUPER_KEY_ID),
+                    version,
+                    reencrypt_with: None,
+                    verification_token: None,
+                })
+            })
+            .collect();
+        for super_key in &versions {
+            skm.data.add_key_to_key_index(USER_ID, super_key)?;
+        }
+
+        // While the super key is rotated, both versions are in memory, and each blob is
+        // unwrapped with the version that it was wrapped with.
+        let (blob_v1, metadata_v1) = SuperKeyManager::encrypt_with_aes_super_key(
+            KEY_BLOB,
+            &versions[0],
+            &BINDING,
+            WrappingContext::KeyBlob,
+        )?;
+        let (blob_v2, metadata_v2) = SuperKeyManager::encrypt_with_aes_super_key(
+            KEY_BLOB,
+            &versions[1],
+            &BINDING,
+            WrappingContext::KeyBlob,
+        )?;
+        assert_eq!(metadata_v1.super_key_version(), Some(&1));
+        assert_eq!(metadata_v2.super_key_version(), Some(&2));
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata_v1, &blob_v1, Some(&BINDING))?, KEY_BLOB);
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata_v2, &blob_v2, Some(&BINDING))?, KEY_BLOB);
+
+        // Re-encryption, e.g., on upgrade, keeps the version of the unwrapping key.
+        let key = skm.unwrap_key_if_required(&metadata_v1, &blob_v1, Some(&BINDING))?;
+        let (_, new_metadata) =
+            SuperKeyManager::reencrypt_if_required(&key, KEY_BLOB, || Ok(BINDING))?;
+        assert_eq!(new_metadata.unwrap().super_key_version(), Some(&1));
+
+        // Once the old version is gone, its blobs can no longer be unwrapped.
+        drop(key);
+        drop(versions.remove(0));
+        assert_eq!(
+            skm.unwrap_key_if_required(&metadata_v1, &blob_v1, Some(&BINDING))
+                .map(|_| ())
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::LOCKED))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_version_skew_after_torn_rotation() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        const SUPER_KEY_ID: i64 = 7;
+        let mut versions: Vec<Arc<SuperKey>> = (1..=2)
+            .map(|version| {
+                Arc::new(SuperKey {
+                    algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+                    key: generate_aes256_key().unwrap(),
+                    id: SuperKeyIdentifier::DatabaseId(SUPER_KEY_ID),
+                    version,
+                    reencrypt_with: None,
+                    verification_token: None,
+                })
+            })
+            .collect();
+        for super_key in &versions {
+            skm.data.add_key_to_key_index(USER_ID, super_key)?;
+        }
+
+        let mut store_key = |alias: &str, super_key: Option<&SuperKey>| -> Result<i64> {
+            let key = KeyDescriptor {
+                domain: Domain::APP,
+                nspace: 10001,
+                alias: Some(alias.to_string()),
+                blob: None,
+            };
+            db.store_new_key_with_blob(
+                &key,
+                KeyType::Client,
+                &[],
+                |key_id| match super_key {
+                    Some(super_key) => SuperKeyManager::encrypt_with_aes_super_key(
+                        KEY_BLOB,
+                        super_key,
+                        &BlobBinding::new(key_id, &key),
+                        WrappingContext::KeyBlob,
+                    ),
+                    None => Ok((KEY_BLOB.to_vec(), BlobMetaData::new())),
+                },
+                &CertificateInfo::new(None, None),
+                None,
+                &KeyMetaData::new(),
+                &KEYSTORE_UUID,
+                ExistingAlias::Replace,
+            )
+            .map(|key| key.id())
+        };
+        // The rotation from version 1 to version 2 rewrapped the first key, but was torn
+        // before it rewrapped the second one.
+        let rewrapped = store_key("a", Some(&versions[1]))?;
+        let torn = store_key("b", Some(&versions[0]))?;
+        store_key("c", None)?;
+
+        // While both versions are present, nothing is skewed.
+        assert_eq!(skm.find_version_skew(&mut db, None, 10)?, (vec![], None));
+
+        // Retiring version 1 leaves the second key behind.
+        let retired = SuperKey {
+            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+            key: ZVec::try_from(versions[0].key.to_vec())?,
+            id: SuperKeyIdentifier::DatabaseId(SUPER_KEY_ID),
+            version: 1,
+            reencrypt_with: None,
+            verification_token: None,
+        };
+        drop(versions.remove(0));
+        assert_eq!(skm.find_version_skew(&mut db, None, 10)?, (vec![torn], None));
+
+        // The repair rewraps only the skewed blob with the current version.
+        assert_eq!(skm.repair_version_skew(&mut db, &retired, &[rewrapped, torn])?, vec![torn]);
+        assert_eq!(skm.find_version_skew(&mut db, None, 10)?, (vec![], None));
+        for (key_id, _, blob, metadata) in db.load_key_blobs_after(None, 10)? {
+            if key_id == torn {
+                assert_eq!(metadata.super_key_version(), Some(&2));
+                assert_eq!(
+                    &*skm.unwrap_key_if_required(
+                        &metadata,
+                        &blob,
+                        Some(&BlobBinding::load(&mut db, key_id)?)
+                    )?,
+                    KEY_BLOB
+                );
+            }
+        }
+        // Repairing again has nothing left to do.
+        assert_eq!(skm.repair_version_skew(&mut db, &retired, &[torn])?, Vec::<i64>::new());
+
+        // Without a newer version in memory, there is nothing to rewrap with.
+        drop(versions);
+        assert_eq!(
+            skm.repair_version_skew(&mut db, &retired, &[torn])
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::LOCKED))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_round_trip_rewraps_bound_blobs() -> Result<()> {
+        let mut db = KeystoreDB::new_in_memory()?;
+        let mut skm: SuperKeyManager = Default::default();
+        let make_super_key = |id| -> Result<Arc<SuperKey>> {
+            Ok(Arc::new(SuperKey {
+                algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+                key: generate_aes256_key()?,
+                id: SuperKeyIdentifier::DatabaseId(id),
+                version: INITIAL_SUPER_KEY_VERSION,
+                reencrypt_with: None,
+                verification_token: None,
+            }))
+        };
+        let super_key = make_super_key(7)?;
+        skm.data.add_key_to_key_index(USER_ID, &super_key)?;
+        // The super key of the second key is not in memory, e.g., it is from another device.
+        let other_super_key = make_super_key(8)?;
+
+        let key = |alias: &str| KeyDescriptor {
+            domain: Domain::APP,
+            nspace: 10001,
+            alias: Some(alias.to_string()),
+            blob: None,
+        };
+        let mut key_ids = vec![];
+        for (alias, super_key) in [("a", &super_key), ("b", &other_super_key)] {
+            let key_id = db
+                .store_new_key_with_blob(
+                    &key(alias),
+                    KeyType::Client,
+                    &[],
+                    |key_id| {
+                        SuperKeyManager::encrypt_with_aes_super_key(
+                            KEY_BLOB,
+                            super_key,
+                            &BlobBinding::new(key_id, &key(alias)),
+                            WrappingContext::KeyBlob,
+                        )
+                    },
+                    &CertificateInfo::new(None, None),
+                    None,
+                    &KeyMetaData::new(),
+                    &KEYSTORE_UUID,
+                    ExistingAlias::Replace,
+                )?
+                .id();
+            key_ids.push(key_id);
+        }
+
+        let pw: Password = (&b"backup passphrase"[..]).into();
+        let (archive, _) = db.export_backup(&pw)?;
+        let mut new_db = KeystoreDB::new_in_memory()?;
+        new_db.import_backup(&archive, &pw, &|owner, old_key_id, new_key_id, sc_type, b, m| {
+            skm.rewrap_imported_blob(owner, old_key_id, new_key_id, sc_type, b, m)
+        })?;
+
+        let load = |db: &mut KeystoreDB, alias: &str| -> Result<(i64, Vec<u8>, BlobMetaData)> {
+            let (key_id_guard, mut key_entry) = db.load_key_entry(
+                &key(alias),
+                KeyType::Client,
+                KeyEntryLoadBits::KM,
+                10001,
+                |_, _| Ok(()),
+            )?;
+            let (blob, metadata) = key_entry.take_key_blob_info().unwrap();
+            Ok((key_id_guard.id(), blob, metadata))
+        };
+        // The bound blob was rewrapped for the new key id of its entry.
+        let (new_key_id, blob, metadata) = load(&mut new_db, "a")?;
+        assert_ne!(new_key_id, key_ids[0]);
+        assert_eq!(
+            &*skm.unwrap_key_if_required(
+                &metadata,
+                &blob,
+                Some(&BlobBinding::load(&mut new_db, new_key_id)?)
+            )?,
+            KEY_BLOB
+        );
+        // The blob that could not be rewrapped was imported as is.
+        assert_eq!(load(&mut new_db, "b")?.1, load(&mut db, "b")?.1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_super_key_not_yet_available() -> Result<()> {
+        let mut skm: SuperKeyManager = Default::default();
+        let super_key = Arc::new(SuperKey {
+            algorithm: SuperEncryptionAlgorithm::Aes256Gcm,
+            key: generate_aes256_key()?,
+            id: SuperKeyIdentifier::DatabaseId(7),
+            version: INITIAL_SUPER_KEY_VERSION,
+            reencrypt_with: None,
+            verification_token: None,
+        });
+        let (blob, metadata) = SuperKeyManager::encrypt_with_aes_super_key(
+            KEY_BLOB,
+            &super_key,
+            &BINDING,
+            WrappingContext::KeyBlob,
+        )?;
+        let fails_with = |skm: &SuperKeyManager, metadata: &BlobMetaData, error: Error| {
+            skm.unwrap_key_if_required(metadata, &blob, Some(&BINDING))
+                .map(|_| ())
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>()
+                == Some(&error)
+        };
+
+        // Before the super key was in memory, it is not available yet.
+        assert!(fails_with(&skm, &metadata, Error::SuperKeyUnavailable));
+        skm.data.add_key_to_key_index(USER_ID, &super_key)?;
+        assert_eq!(&*skm.unwrap_key_if_required(&metadata, &blob, Some(&BINDING))?, KEY_BLOB);
+
+        // Once it was dropped, e.g., because the device was locked, it is locked.
+        drop(super_key);
+        assert!(fails_with(&skm, &metadata, Error::Rc(ResponseCode::LOCKED)));
+
+        // Boot level keys are not available before the boot level cache is set up.
+        let mut boot_level_metadata = BlobMetaData::new();
+        boot_level_metadata.add(BlobMetaEntry::MaxBootLevel(0));
+        assert!(fails_with(&skm, &boot_level_metadata, Error::SuperKeyUnavailable));
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the optional waiting for super keys that are not available yet.
+//!
+//! Early during boot, keys may be requested before the super key that protects them has been
+//! unlocked for the first time, e.g., before the boot level keys are set up or before the user
+//! has unlocked the device. Such requests fail with `Error::SuperKeyUnavailable`, which is
+//! distinct from `ResponseCode::LOCKED` for super keys that were available before. By default
+//! they fail immediately. If a timeout is configured, the requests instead wait for up to the
+//! timeout for a super key to become available, and are retried whenever one does. Waiting
+//! blocks the binder thread of the request, so the timeout should be short.
+
+use crate::clock_rollback::{Clock, SystemClock};
+use crate::error::Error;
+use crate::ks_err;
+use crate::sysprop::read_prop_u32;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// How long requests wait for an unavailable super key, in milliseconds. 0, the default, fails
+/// such requests immediately.
+const WAIT_TIMEOUT_PROPERTY: &str = "keystore.super_key_wait_timeout_ms";
+
+/// Waiting requests are retried at least this often, in case a notification was missed.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref SUPER_KEY_AVAILABILITY: Availability = Default::default();
+}
+
+/// Counts the super keys that became available, so that waiting requests can tell whether they
+/// need to retry.
+#[derive(Default)]
+struct Availability {
+    generation: Mutex<u64>,
+    cond_var: Condvar,
+}
+
+impl Availability {
+    fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Blocks for up to `timeout` unless a super key became available since `seen`.
+    fn wait(&self, seen: u64, timeout: Duration) {
+        let generation = self.generation.lock().unwrap();
+        if *generation == seen {
+            let _ = self.cond_var.wait_timeout(generation, timeout).unwrap();
+        }
+    }
+
+    fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.cond_var.notify_all();
+    }
+}
+
+/// Wakes up the requests that wait for a super key. Must be called whenever a super key becomes
+/// available.
+pub fn notify_super_key_available() {
+    SUPER_KEY_AVAILABILITY.notify();
+}
+
+/// Decides what happens to requests whose super key is not available yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperKeyWaitPolicy {
+    /// The request fails with `Error::SuperKeyUnavailable`.
+    FailImmediately,
+    /// The request waits for up to the given time for the super key.
+    Wait(Duration),
+}
+
+impl SuperKeyWaitPolicy {
+    /// Reads the policy from the `keystore.super_key_wait_timeout_ms` system property. The
+    /// property is read on every call, so changes take effect with the next request.
+    pub fn from_property() -> Self {
+        match read_prop_u32(WAIT_TIMEOUT_PROPERTY, 0) {
+            0 => Self::FailImmediately,
+            millis => Self::Wait(Duration::from_millis(millis.into())),
+        }
+    }
+
+    /// Calls `attempt`, which typically unwraps a key blob, until it no longer fails with
+    /// `Error::SuperKeyUnavailable` or until the timeout of the policy has passed according to
+    /// the monotonic time of `clock`. Returns the result of the last attempt.
+    pub fn run_with_clock<T>(
+        &self,
+        clock: &dyn Clock,
+        mut attempt: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        let timeout = match self {
+            Self::FailImmediately => return attempt(),
+            Self::Wait(timeout) => i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX),
+        };
+        let deadline = clock.monotonic_millis().saturating_add(timeout);
+        loop {
+            let seen = SUPER_KEY_AVAILABILITY.generation();
+            match attempt() {
+                Err(e) if is_super_key_unavailable(&e) => {
+                    let remaining = deadline - clock.monotonic_millis();
+                    if remaining <= 0 {
+                        return Err(e).context(ks_err!(
+                            "Gave up waiting for the super key after {} ms.",
+                            timeout
+                        ));
+                    }
+                    SUPER_KEY_AVAILABILITY
+                        .wait(seen, POLL_INTERVAL.min(Duration::from_millis(remaining as u64)));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Like `run_with_clock`, but with the clocks of the system.
+    pub fn run<T>(&self, attempt: impl FnMut() -> Result<T>) -> Result<T> {
+        self.run_with_clock(&SystemClock, attempt)
+    }
+}
+
+/// Returns true if the root cause of `e` is `Error::SuperKeyUnavailable`.
+pub fn is_super_key_unavailable(e: &anyhow::Error) -> bool {
+    matches!(e.root_cause().downcast_ref::<Error>(), Some(Error::SuperKeyUnavailable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_rollback::tests::FakeClock;
+    use crate::error::ResponseCode;
+    use std::sync::Arc;
+
+    fn unavailable() -> Result<i32> {
+        Err(Error::SuperKeyUnavailable).context("Super key is not unlocked.")
+    }
+
+    #[test]
+    fn test_fail_immediately() {
+        let clock = Arc::new(FakeClock::default());
+        let mut attempts = 0;
+        let result = SuperKeyWaitPolicy::FailImmediately.run_with_clock(&clock, || {
+            attempts += 1;
+            unavailable()
+        });
+        assert!(is_super_key_unavailable(&result.unwrap_err()));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_wait_then_succeed() {
+        let clock = Arc::new(FakeClock::default());
+        let policy = SuperKeyWaitPolicy::Wait(Duration::from_secs(10));
+        let mut attempts = 0;
+        let result = policy.run_with_clock(&clock, || {
+            attempts += 1;
+            clock.advance(1000);
+            if attempts < 3 {
+                // The super key becomes available while waiting.
+                notify_super_key_available();
+                unavailable()
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_wait_times_out() {
+        let clock = Arc::new(FakeClock::default());
+        let policy = SuperKeyWaitPolicy::Wait(Duration::from_secs(10));
+        let mut attempts = 0;
+        let result = policy.run_with_clock(&clock, || {
+            attempts += 1;
+            clock.advance(4000);
+            notify_super_key_available();
+            unavailable()
+        });
+        assert!(is_super_key_unavailable(&result.unwrap_err()));
+        assert_eq!(attempts, 3);
+
+        // Other errors are not retried.
+        let mut attempts = 0;
+        let result: Result<i32> = policy.run_with_clock(&clock, || {
+            attempts += 1;
+            Err(Error::Rc(ResponseCode::LOCKED).into())
+        });
+        assert!(!is_super_key_unavailable(&result.unwrap_err()));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module provides typed accessors for system properties. All accessors take a default
+//! value which is returned if the property is not set, cannot be read, or cannot be parsed.
+//! Read and parse failures are logged, a missing property is not.
+
+use std::time::Duration;
+
+/// Reads the raw value of the given property. Returns None if the property is not set or if
+/// reading it failed.
+fn read_prop(name: &str) -> Option<String> {
+    match rustutils::system_properties::read(name) {
+        Ok(value) => value,
+        Err(e) => {
+            log::warn!("Failed to read property {}: {:?}", name, e);
+            None
+        }
+    }
+}
+
+/// Parses `value` with `parse`, falling back to `default` if `value` is None or not parsable.
+fn parse_prop<T, F>(name: &str, value: Option<String>, default: T, parse: F) -> T
+where
+    T: std::fmt::Debug,
+    F: FnOnce(&str) -> Option<T>,
+{
+    match value {
+        None => default,
+        Some(value) => match parse(value.trim()) {
+            Some(v) => v,
+            None => {
+                log::warn!(
+                    "Failed to parse property {} value {:?}. Using default {:?}.",
+                    name,
+                    value,
+                    default
+                );
+                default
+            }
+        },
+    }
+}
+
+/// Parses a boolean using the same conventions as libcutils.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "y" | "yes" | "on" | "true" => Some(true),
+        "0" | "n" | "no" | "off" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a duration. A plain integer is interpreted as milliseconds. The suffixes "ms", "s",
+/// "m", and "h" are accepted.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit_ms) = if let Some(n) = value.strip_suffix("ms") {
+        (n, 1)
+    } else if let Some(n) = value.strip_suffix('s') {
+        (n, 1000)
+    } else if let Some(n) = value.strip_suffix('m') {
+        (n, 60 * 1000)
+    } else if let Some(n) = value.strip_suffix('h') {
+        (n, 60 * 60 * 1000)
+    } else {
+        (value, 1)
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(unit_ms).map(Duration::from_millis)
+}
+
+/// Reads a boolean property. Returns `default` if the property is not set or not a boolean.
+pub fn read_prop_bool(name: &str, default: bool) -> bool {
+    parse_prop(name, read_prop(name), default, parse_bool)
+}
+
+/// Reads an unsigned 32 bit integer property. Returns `default` if the property is not set
+/// or not a valid u32.
+pub fn read_prop_u32(name: &str, default: u32) -> u32 {
+    parse_prop(name, read_prop(name), default, |v| v.parse::<u32>().ok())
+}
+
+/// Reads a duration property. See `parse_duration` for the accepted formats. Returns `default`
+/// if the property is not set or not a valid duration.
+pub fn read_prop_duration(name: &str, default: Duration) -> Duration {
+    parse_prop(name, read_prop(name), default, parse_duration)
+}
+
+/// Reads a property and parses it with `parse`. Returns `default` if the property is not set
+/// or `parse` returns None.
+pub fn read_prop_parsed<T, F>(name: &str, default: T, parse: F) -> T
+where
+    T: std::fmt::Debug,
+    F: FnOnce(&str) -> Option<T>,
+{
+    parse_prop(name, read_prop(name), default, parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prop(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        assert!(parse_prop("p", prop("true"), false, parse_bool));
+        assert!(parse_prop("p", prop("1"), false, parse_bool));
+        assert!(parse_prop("p", prop(" on "), false, parse_bool));
+        assert!(!parse_prop("p", prop("false"), true, parse_bool));
+        assert!(!parse_prop("p", prop("0"), true, parse_bool));
+        // Parse failure falls back to the default.
+        assert!(parse_prop("p", prop("maybe"), true, parse_bool));
+        assert!(!parse_prop("p", prop(""), false, parse_bool));
+        // Missing property.
+        assert!(parse_prop("p", None, true, parse_bool));
+    }
+
+    #[test]
+    fn test_parse_u32() {
+        let parse = |v: &str| v.parse::<u32>().ok();
+        assert_eq!(parse_prop("p", prop("42"), 7, parse), 42);
+        assert_eq!(parse_prop("p", prop("-1"), 7, parse), 7);
+        assert_eq!(parse_prop("p", prop("4294967296"), 7, parse), 7);
+        assert_eq!(parse_prop("p", prop("abc"), 7, parse), 7);
+        assert_eq!(parse_prop("p", None, 7, parse), 7);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        let default = Duration::from_secs(1);
+        assert_eq!(
+            parse_prop("p", prop("250"), default, parse_duration),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            parse_prop("p", prop("250ms"), default, parse_duration),
+            Duration::from_millis(250)
+        );
+        assert_eq!(parse_prop("p", prop("3s"), default, parse_duration), Duration::from_secs(3));
+        assert_eq!(parse_prop("p", prop("2m"), default, parse_duration), Duration::from_secs(120));
+        assert_eq!(parse_prop("p", prop("1h"), default, parse_duration), Duration::from_secs(3600));
+        assert_eq!(parse_prop("p", prop("1d"), default, parse_duration), default);
+        assert_eq!(parse_prop("p", prop("s"), default, parse_duration), default);
+        assert_eq!(parse_prop("p", None, default, parse_duration), default);
+    }
+
+    #[test]
+    fn test_read_missing_prop() {
+        let name = "keystore2.test.this_property_does_not_exist";
+        assert!(read_prop_bool(name, true));
+        assert_eq!(read_prop_u32(name, 11), 11);
+        assert_eq!(read_prop_duration(name, Duration::from_secs(5)), Duration::from_secs(5));
+    }
+}
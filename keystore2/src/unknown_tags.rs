@@ -0,0 +1,131 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements the handling of key parameters with tags that keystore does not know.
+//!
+//! Clients built against a newer KeyMint HAL may send tags that this keystore does not
+//! recognize, i.e., that `KeyParameterValue` has no variant for, or known tags with a value of
+//! the wrong type. In lenient mode, the default, such parameters are passed through to KeyMint
+//! unchanged. This is safe because KeyMint validates every parameter and rejects those it does
+//! not support with `ErrorCode::UNSUPPORTED_TAG` or `ErrorCode::INVALID_TAG`, and because
+//! keystore cannot have software enforcement for tags it does not know. In strict mode, keystore
+//! rejects them itself with `ErrorCode::INVALID_TAG` before KeyMint is called, e.g., on devices
+//! that must only use audited tags.
+
+use crate::error::{Error, ErrorCode};
+use crate::key_parameter::KeyParameterValue as KsKeyParamValue;
+use crate::ks_err;
+use crate::sysprop::read_prop_parsed;
+use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+    KeyParameter::KeyParameter, Tag::Tag,
+};
+use anyhow::{Context, Result};
+
+/// The policy for unknown tags: "strict" or "lenient", the default.
+const UNKNOWN_TAG_POLICY_PROPERTY: &str = "keystore.unknown_tag_policy";
+
+/// Decides what happens to key parameters with tags that keystore does not know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownTagPolicy {
+    /// Requests with unknown tags fail with `ErrorCode::INVALID_TAG`.
+    Strict,
+    /// Unknown tags are passed through to KeyMint.
+    Lenient,
+}
+
+impl UnknownTagPolicy {
+    /// Reads the policy from the `keystore.unknown_tag_policy` system property. The property is
+    /// read on every call, so changes take effect with the next request.
+    pub fn from_property() -> Self {
+        read_prop_parsed(UNKNOWN_TAG_POLICY_PROPERTY, Self::Lenient, |value| match value {
+            "strict" => Some(Self::Strict),
+            "lenient" => Some(Self::Lenient),
+            _ => None,
+        })
+    }
+
+    /// Checks the parameters of a key generation, key import, or operation. Fails with
+    /// `ErrorCode::INVALID_TAG` in strict mode if any of them has an unknown tag.
+    pub fn check(&self, params: &[KeyParameter]) -> Result<()> {
+        if *self == Self::Lenient {
+            return Ok(());
+        }
+        match params.iter().find(|kp| !is_known(kp)) {
+            Some(kp) => Err(Error::Km(ErrorCode::INVALID_TAG)).context(ks_err!(
+                "Unknown tag {:?} with value {:?}.",
+                kp.tag,
+                kp.value
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns true if keystore knows the tag of `kp` with the type of its value.
+fn is_known(kp: &KeyParameter) -> bool {
+    // Unknown tags and values of the wrong type are converted to `KeyParameterValue::Invalid`.
+    kp.tag != Tag::INVALID && KsKeyParamValue::from(kp.clone()).get_tag() == kp.tag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
+        KeyParameterValue::KeyParameterValue, TagType::TagType,
+    };
+
+    fn params_with(unknown: KeyParameter) -> Vec<KeyParameter> {
+        vec![
+            KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Integer(256) },
+            unknown,
+            KeyParameter { tag: Tag::NO_AUTH_REQUIRED, value: KeyParameterValue::BoolValue(true) },
+        ]
+    }
+
+    fn is_invalid_tag(result: Result<()>) -> bool {
+        result.unwrap_err().root_cause().downcast_ref::<Error>()
+            == Some(&Error::Km(ErrorCode::INVALID_TAG))
+    }
+
+    #[test]
+    fn test_unknown_tag() {
+        // A tag of a newer HAL.
+        let unknown =
+            KeyParameter { tag: Tag(TagType::UINT.0 | 9999), value: KeyParameterValue::Integer(1) };
+        let params = params_with(unknown);
+        assert!(is_invalid_tag(UnknownTagPolicy::Strict.check(&params)));
+        assert!(UnknownTagPolicy::Lenient.check(&params).is_ok());
+    }
+
+    #[test]
+    fn test_known_tag_with_wrong_type() {
+        let wrong_type =
+            KeyParameter { tag: Tag::KEY_SIZE, value: KeyParameterValue::Blob(vec![1]) };
+        let params = params_with(wrong_type);
+        assert!(is_invalid_tag(UnknownTagPolicy::Strict.check(&params)));
+        assert!(UnknownTagPolicy::Lenient.check(&params).is_ok());
+    }
+
+    #[test]
+    fn test_known_tags_pass() {
+        let known = KeyParameter {
+            tag: Tag::ATTESTATION_CHALLENGE,
+            value: KeyParameterValue::Blob(vec![1, 2, 3]),
+        };
+        let params = params_with(known);
+        assert!(UnknownTagPolicy::Strict.check(&params).is_ok());
+        assert!(UnknownTagPolicy::Strict.check(&[]).is_ok());
+        assert!(is_invalid_tag(UnknownTagPolicy::Strict.check(&[KeyParameter::default()])));
+    }
+}
@@ -15,11 +15,13 @@
 //! This module implements utility functions used by the Keystore 2.0 service
 //! implementation.
 
+use crate::attestation_ids::AttestationIdStatus;
 use crate::error::{map_binder_status, map_km_error, Error, ErrorCode};
 use crate::key_parameter::KeyParameter;
 use crate::ks_err;
 use crate::permission;
 use crate::permission::{KeyPerm, KeyPermSet, KeystorePerm};
+use crate::sysprop::read_prop_bool;
 use crate::{
     database::{KeyType, KeystoreDB},
     globals::LEGACY_IMPORTER,
@@ -35,6 +37,7 @@ use android_security_apc::aidl::android::security::apc::{
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     Authorization::Authorization, Domain::Domain, KeyDescriptor::KeyDescriptor,
+    ResponseCode::ResponseCode,
 };
 use anyhow::{Context, Result};
 use binder::{Strong, ThreadState};
@@ -44,6 +47,7 @@ use keystore2_apc_compat::{
     APC_COMPAT_ERROR_SYSTEM_ERROR,
 };
 use keystore2_crypto::{aes_gcm_decrypt, aes_gcm_encrypt, ZVec};
+use keystore2_selinux as selinux;
 use std::iter::IntoIterator;
 
 /// This function uses its namesake in the permission module and in
@@ -78,11 +82,19 @@ pub fn check_grant_permission(access_vec: KeyPermSet, key: &KeyDescriptor) -> an
 /// This function uses its namesake in the permission module and in
 /// combination with with_calling_sid from the binder crate to check
 /// if the caller has the given key permission.
+///
+/// In debug builds, `key` and `access_vector`, as resolved by the database, are also asserted
+/// to respect the user partition, see `check_user_partition`. This catches keys that leak from
+/// another user at the load boundary, even if the permission check would let them pass.
 pub fn check_key_permission(
     perm: KeyPerm,
     key: &KeyDescriptor,
     access_vector: &Option<KeyPermSet>,
 ) -> anyhow::Result<()> {
+    if cfg!(debug_assertions) {
+        check_user_partition(key, *access_vector, ThreadState::get_calling_uid())
+            .context(ks_err!("Resolved key violates the user partition."))?;
+    }
     ThreadState::with_calling_sid(|calling_sid| {
         permission::check_key_permission(
             ThreadState::get_calling_uid(),
@@ -122,6 +134,84 @@ pub fn check_unique_id_attestation_permissions() -> anyhow::Result<()> {
     check_android_permission("android.permission.REQUEST_UNIQUE_ID_ATTESTATION")
 }
 
+/// The attestation ID tags whose provisioning status can be queried with
+/// `get_provisioned_attestation_ids`.
+pub const ATTESTATION_ID_TAGS: &[Tag] = &[
+    Tag::ATTESTATION_ID_BRAND,
+    Tag::ATTESTATION_ID_DEVICE,
+    Tag::ATTESTATION_ID_PRODUCT,
+    Tag::ATTESTATION_ID_SERIAL,
+    Tag::ATTESTATION_ID_IMEI,
+    Tag::ATTESTATION_ID_SECOND_IMEI,
+    Tag::ATTESTATION_ID_MEID,
+    Tag::ATTESTATION_ID_MANUFACTURER,
+    Tag::ATTESTATION_ID_MODEL,
+];
+
+/// A source of truth for which attestation ID fields have been provisioned on the device.
+pub trait AttestationIdSource {
+    /// Returns true if the identifier corresponding to the given attestation ID tag has been
+    /// provisioned.
+    fn is_provisioned(&self, tag: Tag) -> bool;
+}
+
+/// Determines provisioning status of the attestation IDs from system properties. If the device
+/// declares the provisioned IDs, see `AttestationIdStatus`, the declaration is authoritative.
+/// Otherwise, the product identifiers are looked up in the `*_for_attestation` properties first,
+/// falling back to the vendor partition values, and the serial number in `ro.serialno`. The
+/// telephony identifiers are not exposed as system properties, so they are only reported if the
+/// device declares them.
+pub struct SystemPropertyAttestationIds;
+
+impl SystemPropertyAttestationIds {
+    fn property_names(tag: Tag) -> &'static [&'static str] {
+        match tag {
+            Tag::ATTESTATION_ID_BRAND => {
+                &["ro.product.brand_for_attestation", "ro.product.vendor.brand"]
+            }
+            Tag::ATTESTATION_ID_DEVICE => {
+                &["ro.product.device_for_attestation", "ro.product.vendor.device"]
+            }
+            Tag::ATTESTATION_ID_PRODUCT => {
+                &["ro.product.name_for_attestation", "ro.product.vendor.name"]
+            }
+            Tag::ATTESTATION_ID_MANUFACTURER => {
+                &["ro.product.manufacturer_for_attestation", "ro.product.vendor.manufacturer"]
+            }
+            Tag::ATTESTATION_ID_MODEL => {
+                &["ro.product.model_for_attestation", "ro.product.vendor.model"]
+            }
+            Tag::ATTESTATION_ID_SERIAL => &["ro.serialno"],
+            _ => &[],
+        }
+    }
+}
+
+impl AttestationIdSource for SystemPropertyAttestationIds {
+    fn is_provisioned(&self, tag: Tag) -> bool {
+        if let AttestationIdStatus::Provisioned(tags) = AttestationIdStatus::query() {
+            return tags.contains(&tag);
+        }
+        Self::property_names(tag).iter().any(|name| {
+            matches!(rustutils::system_properties::read(name), Ok(Some(value)) if !value.is_empty())
+        })
+    }
+}
+
+/// Returns the subset of `ATTESTATION_ID_TAGS` that `source` reports as provisioned.
+fn provisioned_attestation_ids(source: &dyn AttestationIdSource) -> Vec<Tag> {
+    ATTESTATION_ID_TAGS.iter().copied().filter(|tag| source.is_provisioned(*tag)).collect()
+}
+
+/// Reports which attestation ID fields are provisioned on the device, so that callers can avoid
+/// requesting the attestation of identifiers that are not available. Knowing which identifiers
+/// exist is sensitive in itself, so the caller needs the same permissions as for device ID
+/// attestation.
+pub fn get_provisioned_attestation_ids(source: &dyn AttestationIdSource) -> Result<Vec<Tag>> {
+    check_device_attestation_permissions().context(ks_err!())?;
+    Ok(provisioned_attestation_ids(source))
+}
+
 fn check_android_permission(permission: &str) -> anyhow::Result<()> {
     let permission_controller: Strong<dyn IPermissionController::IPermissionController> =
         binder::get_interface("permission")?;
@@ -249,6 +339,9 @@ pub fn ui_opts_2_compat(opt: i32) -> ApcCompatUiOptions {
 /// AID offset for uid space partitioning.
 pub const AID_USER_OFFSET: u32 = rustutils::users::AID_USER_OFFSET;
 
+/// First AID assigned to apps.
+pub const AID_APP_START: u32 = rustutils::users::AID_APP_START;
+
 /// AID of the keystore process itself, used for keys that
 /// keystore generates for its own use.
 pub const AID_KEYSTORE: u32 = rustutils::users::AID_KEYSTORE;
@@ -258,6 +351,144 @@ pub fn uid_to_android_user(uid: u32) -> u32 {
     rustutils::users::multiuser_get_user_id(uid)
 }
 
+/// Returns a copy of `key` with the namespace that all key lookups must derive from its domain.
+/// For `Domain::APP` the namespace is always the uid of the caller, no matter which namespace
+/// the caller supplied, so that an app can never address the namespace of another app this way.
+/// For `Domain::SELINUX` the supplied namespace is kept. It is validated against the SELinux
+/// policy by `check_key_permission`, which must be called before the key is accessed. Keys of
+/// all other domains are returned unchanged.
+pub fn resolve_key_namespace(key: &KeyDescriptor, caller_uid: u32) -> KeyDescriptor {
+    match key.domain {
+        Domain::APP => KeyDescriptor { nspace: caller_uid as i64, ..key.clone() },
+        _ => key.clone(),
+    }
+}
+
+/// Checks that a key that was resolved on behalf of `caller_uid` does not leak from another
+/// android user. `access_key` and `access_vector` are the access descriptor and the grant that
+/// the resolution found. A key of `Domain::APP` belongs to the user of its namespace, and a
+/// caller of another user may only access it through a grant of the owner. Keys of all other
+/// domains are not partitioned by user. Returns `ResponseCode::PERMISSION_DENIED` if the key
+/// belongs to another user.
+pub fn check_user_partition(
+    access_key: &KeyDescriptor,
+    access_vector: Option<KeyPermSet>,
+    caller_uid: u32,
+) -> Result<()> {
+    if access_key.domain != Domain::APP || access_vector.is_some() {
+        return Ok(());
+    }
+    match u32::try_from(access_key.nspace) {
+        Ok(owner_uid) if uid_to_android_user(owner_uid) == uid_to_android_user(caller_uid) => {
+            Ok(())
+        }
+        _ => Err(Error::perm()).context(ks_err!(
+            "Key of namespace {} resolved for uid {} of another user.",
+            access_key.nspace,
+            caller_uid
+        )),
+    }
+}
+
+/// The namespace that clients pass with `Domain::APP` if they leave it to keystore.
+pub const NAMESPACE_UNSPECIFIED: i64 = -1;
+
+/// Checks that `key` is structurally valid for `caller_uid` without accessing the database, so
+/// that clients can validate key descriptors before they submit an operation.
+///  * `Domain::APP` and `Domain::SELINUX` require a valid alias and no blob. With `Domain::APP`,
+///    the namespace is implied by the caller, so the only namespaces allowed are
+///    `NAMESPACE_UNSPECIFIED` and the uid of the caller.
+///  * `Domain::GRANT` and `Domain::KEY_ID` address the key by the grant or key id in the
+///    namespace, and allow neither an alias nor a blob.
+///  * `Domain::BLOB` requires a non-empty blob and no alias.
+///
+/// Passing this check does not imply that the key exists or that the caller may access it.
+/// Returns `ResponseCode::INVALID_ARGUMENT` otherwise.
+pub fn validate_key_descriptor(key: &KeyDescriptor, caller_uid: u32) -> Result<()> {
+    let invalid = |reason: &str| -> Result<()> {
+        Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+            "{} for {:?}.",
+            reason,
+            key.domain
+        ))
+    };
+    match key.domain {
+        Domain::APP | Domain::SELINUX => {
+            if key.domain == Domain::APP
+                && key.nspace != NAMESPACE_UNSPECIFIED
+                && key.nspace != caller_uid as i64
+            {
+                return invalid("An explicit namespace is not allowed");
+            }
+            if key.blob.is_some() {
+                return invalid("A blob is not allowed");
+            }
+            match &key.alias {
+                Some(alias) => check_alias(alias).context(ks_err!()),
+                None => invalid("An alias is required"),
+            }
+        }
+        Domain::GRANT | Domain::KEY_ID => {
+            if key.alias.is_some() {
+                return invalid("An alias is not allowed");
+            }
+            if key.blob.is_some() {
+                return invalid("A blob is not allowed");
+            }
+            Ok(())
+        }
+        Domain::BLOB => {
+            if key.alias.is_some() {
+                return invalid("An alias is not allowed");
+            }
+            match &key.blob {
+                Some(blob) if !blob.is_empty() => Ok(()),
+                _ => invalid("A non-empty blob is required"),
+            }
+        }
+        _ => invalid("Unknown domain"),
+    }
+}
+
+/// The maximal length of a key alias in bytes.
+pub const MAX_ALIAS_LENGTH: usize = 8192;
+
+/// If true, aliases are restricted to ASCII letters, digits, '.', '_', and '-'.
+const STRICT_ALIAS_CHARSET_PROPERTY: &str = "keystore.strict_alias_charset";
+
+/// Checks that `alias` may be bound to a key. Aliases must not be empty or longer than
+/// `MAX_ALIAS_LENGTH` bytes, and must not contain control characters or U+FFFD, which stands
+/// in for malformed input that was decoded lossily. If the strict alias charset property is
+/// set, the alias must consist of ASCII letters, digits, '.', '_', and '-' only. Returns
+/// `ResponseCode::INVALID_ARGUMENT` otherwise.
+pub fn check_alias(alias: &str) -> Result<()> {
+    check_alias_with(alias, read_prop_bool(STRICT_ALIAS_CHARSET_PROPERTY, false))
+}
+
+fn check_alias_with(alias: &str, strict_charset: bool) -> Result<()> {
+    if alias.is_empty() || alias.len() > MAX_ALIAS_LENGTH {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Alias length {} is out of range.", alias.len()));
+    }
+    if let Some(c) = alias.chars().find(|c| c.is_control() || *c == char::REPLACEMENT_CHARACTER) {
+        return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+            .context(ks_err!("Alias contains invalid character {:?}.", c));
+    }
+    if strict_charset {
+        if let Some(c) =
+            alias.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+        {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT))
+                .context(ks_err!("Alias contains disallowed character {:?}.", c));
+        }
+    }
+    Ok(())
+}
+
+/// The estimated size in bytes up to which a list of keys is returned in one binder transaction.
+/// The binder transaction size limit is 1M, and the binder overhead is estimated at 60%.
+pub const RESPONSE_SIZE_LIMIT: usize = 358400;
+
 /// Merges and filters two lists of key descriptors. The first input list, legacy_descriptors,
 /// is assumed to not be sorted or filtered. As such, all key descriptors in that list whose
 /// alias is less than, or equal to, start_past_alias (if provided) will be removed.
@@ -330,6 +561,44 @@ fn estimate_safe_amount_to_return(
     items_to_return
 }
 
+/// Returns the key descriptor of the namespace `namespace` of `domain` that the caller may list
+/// the keys of. The caller needs the `GetInfo` permission for the namespace, which by default is
+/// the caller's own namespace for `Domain::APP`, or the `List` keystore permission, which allows
+/// listing any namespace.
+pub fn get_key_descriptor_for_lookup(domain: Domain, namespace: i64) -> Result<KeyDescriptor> {
+    let mut k = match domain {
+        Domain::APP | Domain::SELINUX => resolve_key_namespace(
+            &KeyDescriptor { domain, nspace: namespace, ..Default::default() },
+            ThreadState::get_calling_uid(),
+        ),
+        _ => {
+            return Err(Error::Rc(ResponseCode::INVALID_ARGUMENT)).context(ks_err!(
+                "List entries is only supported for Domain::APP and Domain::SELINUX."
+            ))
+        }
+    };
+
+    // First we check if the caller has the info permission for the selected domain/namespace.
+    // By default we use the calling uid as namespace if domain is Domain::APP.
+    // If the first check fails we check if the caller has the list permission allowing to list
+    // any namespace. In that case we also adjust the queried namespace if a specific uid was
+    // selected.
+    if let Err(e) = check_key_permission(KeyPerm::GetInfo, &k, &None) {
+        if let Some(selinux::Error::PermissionDenied) =
+            e.root_cause().downcast_ref::<selinux::Error>()
+        {
+            check_keystore_permission(KeystorePerm::List)
+                .context(ks_err!("While checking keystore permission."))?;
+            if namespace != -1 {
+                k.nspace = namespace;
+            }
+        } else {
+            return Err(e).context(ks_err!("While checking key permission."))?;
+        }
+    }
+    Ok(k)
+}
+
 /// List all key aliases for a given domain + namespace. whose alias is greater
 /// than start_past_alias (if provided).
 pub fn list_key_entries(
@@ -353,7 +622,6 @@ pub fn list_key_entries(
         start_past_alias,
     );
 
-    const RESPONSE_SIZE_LIMIT: usize = 358400;
     let safe_amount_to_return =
         estimate_safe_amount_to_return(&merged_key_entries, RESPONSE_SIZE_LIMIT);
     Ok(merged_key_entries[..safe_amount_to_return].to_vec())
@@ -465,6 +733,245 @@ mod tests {
         })
     }
 
+    fn assert_invalid_alias(alias: &str, strict_charset: bool) {
+        assert_eq!(
+            check_alias_with(alias, strict_charset)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT)),
+            "{:?}",
+            alias
+        );
+    }
+
+    #[test]
+    fn test_valid_aliases() -> Result<()> {
+        for alias in ["a", "my_key-1.2", "Schl\u{fc}ssel", "\u{1f511}"] {
+            check_alias_with(alias, false)?;
+        }
+        check_alias_with(&"a".repeat(MAX_ALIAS_LENGTH), false)?;
+        check_alias_with("my_key-1.2", true)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_alias() {
+        assert_invalid_alias(&"a".repeat(MAX_ALIAS_LENGTH + 1), false);
+        // The limit counts bytes, not characters.
+        assert_invalid_alias(&"\u{fc}".repeat(MAX_ALIAS_LENGTH / 2 + 1), false);
+        assert_invalid_alias("", false);
+    }
+
+    #[test]
+    fn test_invalid_alias_characters() {
+        // Invalid byte sequences are replaced by U+FFFD when decoded lossily.
+        let lossy = String::from_utf8_lossy(b"key\xff\xfe");
+        assert_invalid_alias(&lossy, false);
+        for alias in ["key\0", "key\n", "\u{7f}key", "key\u{9b}"] {
+            assert_invalid_alias(alias, false);
+        }
+        for alias in ["Schl\u{fc}ssel", "my key", "key/1"] {
+            assert_invalid_alias(alias, true);
+        }
+    }
+
+    #[test]
+    fn test_resolve_key_namespace() {
+        const CALLER_UID: u32 = 10001;
+        const OTHER_UID: i64 = 10002;
+        let key = |domain, nspace| KeyDescriptor {
+            domain,
+            nspace,
+            alias: Some("alias".to_string()),
+            blob: None,
+        };
+
+        // Domain::APP always resolves to the namespace of the caller, even if the caller tries
+        // to address the namespace of another app.
+        for nspace in [CALLER_UID as i64, OTHER_UID, 0, -1] {
+            assert_eq!(
+                resolve_key_namespace(&key(Domain::APP, nspace), CALLER_UID),
+                key(Domain::APP, CALLER_UID as i64)
+            );
+        }
+
+        // The namespaces of all other domains are left to the permission check and the database.
+        for domain in [Domain::SELINUX, Domain::GRANT, Domain::KEY_ID, Domain::BLOB] {
+            assert_eq!(
+                resolve_key_namespace(&key(domain, OTHER_UID), CALLER_UID),
+                key(domain, OTHER_UID)
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_user_partition() {
+        const CALLER_UID: u32 = 10001;
+        const SAME_USER_UID: i64 = 10002;
+        const OTHER_USER_UID: i64 = (AID_USER_OFFSET + 10001) as i64;
+        let key = |domain, nspace| KeyDescriptor {
+            domain,
+            nspace,
+            alias: Some("alias".to_string()),
+            blob: None,
+        };
+        let is_denied = |result: Result<()>| {
+            result.unwrap_err().root_cause().downcast_ref::<Error>()
+                == Some(&Error::Rc(ResponseCode::PERMISSION_DENIED))
+        };
+
+        assert!(
+            check_user_partition(&key(Domain::APP, CALLER_UID as i64), None, CALLER_UID).is_ok()
+        );
+        // Other apps of the same user are left to the permission check.
+        assert!(check_user_partition(&key(Domain::APP, SAME_USER_UID), None, CALLER_UID).is_ok());
+
+        // Keys of other users are denied, unless the owner granted them.
+        assert!(is_denied(check_user_partition(
+            &key(Domain::APP, OTHER_USER_UID),
+            None,
+            CALLER_UID
+        )));
+        assert!(is_denied(check_user_partition(&key(Domain::APP, -1), None, CALLER_UID)));
+        assert!(check_user_partition(
+            &key(Domain::APP, OTHER_USER_UID),
+            Some(KeyPermSet::from(KeyPerm::Use)),
+            CALLER_UID
+        )
+        .is_ok());
+        assert!(check_user_partition(&key(Domain::GRANT, 0x1234), None, CALLER_UID).is_ok());
+
+        // SELinux namespaces are shared by all users.
+        assert!(check_user_partition(&key(Domain::SELINUX, 102), None, CALLER_UID).is_ok());
+        assert!(check_user_partition(
+            &key(Domain::SELINUX, 102),
+            None,
+            AID_USER_OFFSET + CALLER_UID
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check_key_permission_asserts_user_partition() {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let other_user_key = KeyDescriptor {
+            domain: Domain::APP,
+            nspace: (ThreadState::get_calling_uid() + AID_USER_OFFSET) as i64,
+            alias: Some("alias".to_string()),
+            blob: None,
+        };
+        assert_eq!(
+            check_key_permission(KeyPerm::Use, &other_user_key, &None)
+                .unwrap_err()
+                .root_cause()
+                .downcast_ref::<Error>(),
+            Some(&Error::perm())
+        );
+    }
+
+    #[test]
+    fn test_validate_key_descriptor() {
+        const CALLER_UID: u32 = 10001;
+        let key = |domain, nspace, alias: Option<&str>, blob: Option<&[u8]>| KeyDescriptor {
+            domain,
+            nspace,
+            alias: alias.map(str::to_string),
+            blob: blob.map(<[u8]>::to_vec),
+        };
+
+        let valid = [
+            key(Domain::APP, NAMESPACE_UNSPECIFIED, Some("alias"), None),
+            key(Domain::APP, CALLER_UID as i64, Some("alias"), None),
+            key(Domain::SELINUX, 102, Some("alias"), None),
+            key(Domain::GRANT, 0x1234, None, None),
+            key(Domain::KEY_ID, 42, None, None),
+            key(Domain::BLOB, 0, None, Some(b"blob")),
+        ];
+        for key in valid {
+            assert!(validate_key_descriptor(&key, CALLER_UID).is_ok(), "{:?}", key);
+        }
+
+        let invalid = [
+            // An explicit namespace with Domain::APP.
+            key(Domain::APP, 10002, Some("alias"), None),
+            key(Domain::APP, 0, Some("alias"), None),
+            // Missing or invalid aliases.
+            key(Domain::APP, NAMESPACE_UNSPECIFIED, None, None),
+            key(Domain::SELINUX, 102, None, None),
+            key(Domain::SELINUX, 102, Some(""), None),
+            key(Domain::APP, NAMESPACE_UNSPECIFIED, Some("ali\nas"), None),
+            // Blobs with domains other than Domain::BLOB.
+            key(Domain::APP, NAMESPACE_UNSPECIFIED, Some("alias"), Some(b"blob")),
+            key(Domain::SELINUX, 102, Some("alias"), Some(b"blob")),
+            key(Domain::GRANT, 0x1234, None, Some(b"blob")),
+            key(Domain::KEY_ID, 42, None, Some(b"blob")),
+            // Aliases with domains that address keys by id or blob.
+            key(Domain::GRANT, 0x1234, Some("alias"), None),
+            key(Domain::KEY_ID, 42, Some("alias"), None),
+            key(Domain::BLOB, 0, Some("alias"), Some(b"blob")),
+            // Domain::BLOB without a blob.
+            key(Domain::BLOB, 0, None, None),
+            key(Domain::BLOB, 0, None, Some(b"")),
+            // Unknown domains.
+            key(Domain(42), 0, Some("alias"), None),
+        ];
+        for key in invalid {
+            assert_eq!(
+                validate_key_descriptor(&key, CALLER_UID)
+                    .unwrap_err()
+                    .root_cause()
+                    .downcast_ref::<Error>(),
+                Some(&Error::Rc(ResponseCode::INVALID_ARGUMENT)),
+                "{:?}",
+                key
+            );
+        }
+    }
+
+    struct MockAttestationIds(Vec<Tag>);
+
+    impl AttestationIdSource for MockAttestationIds {
+        fn is_provisioned(&self, tag: Tag) -> bool {
+            self.0.contains(&tag)
+        }
+    }
+
+    #[test]
+    fn test_provisioned_attestation_ids() {
+        let source = MockAttestationIds(vec![
+            Tag::ATTESTATION_ID_SERIAL,
+            Tag::ATTESTATION_ID_BRAND,
+            Tag::ATTESTATION_ID_IMEI,
+        ]);
+        assert_eq!(
+            provisioned_attestation_ids(&source),
+            vec![Tag::ATTESTATION_ID_BRAND, Tag::ATTESTATION_ID_SERIAL, Tag::ATTESTATION_ID_IMEI]
+        );
+
+        // Tags that are not attestation IDs are never reported.
+        let source = MockAttestationIds(vec![Tag::ALGORITHM, Tag::ATTESTATION_ID_MEID]);
+        assert_eq!(provisioned_attestation_ids(&source), vec![Tag::ATTESTATION_ID_MEID]);
+
+        assert!(provisioned_attestation_ids(&MockAttestationIds(vec![])).is_empty());
+    }
+
+    #[test]
+    fn test_get_provisioned_attestation_ids_requires_permission() -> Result<()> {
+        let source = MockAttestationIds(ATTESTATION_ID_TAGS.to_vec());
+        match get_provisioned_attestation_ids(&source) {
+            Ok(tags) => assert_eq!(tags, ATTESTATION_ID_TAGS.to_vec()),
+            Err(error) => match error.root_cause().downcast_ref::<Error>() {
+                // Expected: the context for this test might not be allowed to attest device IDs.
+                Some(Error::Km(ErrorCode::CANNOT_ATTEST_IDS)) => {}
+                _ => return Err(error),
+            },
+        }
+        Ok(())
+    }
+
     fn create_key_descriptors_from_aliases(key_aliases: &[&str]) -> Vec<KeyDescriptor> {
         key_aliases
             .iter()
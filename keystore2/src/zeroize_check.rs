@@ -0,0 +1,126 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This module implements a test only check that sensitive buffers are zeroed when dropped.
+//!
+//! Key material is kept in `ZVec`s, which zero their buffer when dropped. After the drop, the
+//! buffer is freed, so tests cannot safely inspect it anymore. Instead, the unit tests of
+//! keystore2 run with an allocator that can watch one allocation. When the watched allocation is
+//! freed, the allocator checks whether all of its bytes are zero before passing it on to the
+//! system allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicPtr, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+/// The watched allocation has not been freed yet.
+const NOT_FREED: u8 = 0;
+/// The watched allocation was all zeros when it was freed.
+const FREED_ZEROED: u8 = 1;
+/// The watched allocation was freed with some bytes that were not zero.
+const FREED_NOT_ZEROED: u8 = 2;
+
+/// The system allocator, which additionally checks the watched allocation when it is freed.
+struct ZeroizeCheckAllocator {
+    watched: AtomicPtr<u8>,
+    outcome: AtomicU8,
+}
+
+// SAFETY: All allocations are served by the system allocator. `dealloc` only reads the memory
+// of the allocation that is being freed before the system allocator frees it.
+unsafe impl GlobalAlloc for ZeroizeCheckAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !ptr.is_null() && ptr == self.watched.load(Ordering::SeqCst) {
+            let contents = std::slice::from_raw_parts(ptr, layout.size());
+            let outcome =
+                if contents.iter().all(|b| *b == 0) { FREED_ZEROED } else { FREED_NOT_ZEROED };
+            self.outcome.store(outcome, Ordering::SeqCst);
+            self.watched.store(std::ptr::null_mut(), Ordering::SeqCst);
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: ZeroizeCheckAllocator = ZeroizeCheckAllocator {
+    watched: AtomicPtr::new(std::ptr::null_mut()),
+    outcome: AtomicU8::new(NOT_FREED),
+};
+
+/// Only one allocation can be watched at a time.
+static WATCH_LOCK: Mutex<()> = Mutex::new(());
+
+/// Calls `f` and returns true if the heap allocation that starts at `buffer` was freed by `f`
+/// and was all zeros when it was freed. Returns false if it was not zeroed, or if `f` did not
+/// free it.
+pub fn is_zeroized_by(buffer: *const u8, f: impl FnOnce()) -> bool {
+    let _lock = WATCH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    ALLOCATOR.outcome.store(NOT_FREED, Ordering::SeqCst);
+    ALLOCATOR.watched.store(buffer as *mut u8, Ordering::SeqCst);
+    f();
+    ALLOCATOR.watched.store(std::ptr::null_mut(), Ordering::SeqCst);
+    ALLOCATOR.outcome.load(Ordering::SeqCst) == FREED_ZEROED
+}
+
+/// Drops `value` and returns true if the heap allocation that starts at `buffer`, which must be
+/// owned by `value`, was all zeros when it was freed, see `is_zeroized_by`.
+pub fn is_zeroized_on_drop<T>(value: T, buffer: *const u8) -> bool {
+    is_zeroized_by(buffer, || drop(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keystore2_crypto::{generate_aes256_key, Password, ZVec};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_zvec_is_zeroized() {
+        let key = generate_aes256_key().unwrap();
+        assert!(key.iter().any(|b| *b != 0));
+        let buffer = key.as_ptr();
+        assert!(is_zeroized_on_drop(key, buffer));
+
+        // Shrinking a ZVec does not exempt the rest of its buffer.
+        let mut key = ZVec::try_from(vec![0xaa; 64]).unwrap();
+        key.reduce_len(16);
+        let buffer = key.as_ptr();
+        assert!(is_zeroized_on_drop(key, buffer));
+
+        let password: Password = (&b"correct horse"[..]).into();
+        let password = password.try_clone().unwrap();
+        let buffer = match &password {
+            Password::Owned(key) => key.as_ptr(),
+            Password::Ref(_) => panic!("A cloned password must be owned."),
+        };
+        assert!(is_zeroized_on_drop(password, buffer));
+    }
+
+    #[test]
+    fn test_plain_vec_is_not_zeroized() {
+        // The check must be able to fail, or it would not guard against regressions.
+        let plain = vec![0xaau8; 32];
+        let buffer = plain.as_ptr();
+        assert!(!is_zeroized_on_drop(plain, buffer));
+
+        // A buffer that is not freed by the drop is not reported as zeroized.
+        let kept = ZVec::try_from(vec![0xaa; 32]).unwrap();
+        let reference = &kept;
+        assert!(!is_zeroized_on_drop(reference, kept.as_ptr()));
+    }
+}
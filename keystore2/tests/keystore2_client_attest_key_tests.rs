@@ -14,6 +14,9 @@
 
 use nix::unistd::getuid;
 
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, BlockMode::BlockMode, Digest::Digest, EcCurve::EcCurve,
     ErrorCode::ErrorCode, KeyPurpose::KeyPurpose, PaddingMode::PaddingMode,
@@ -480,3 +483,95 @@ fn keystore2_attest_symmetric_key_fail_sys_error() {
     // Should not have an attestation record.
     assert!(aes_key_metadata.certificateChain.is_none());
 }
+
+/// Generate an EC attestation key and use it to attest an imported EC key. Test should be able to
+/// import the key with an attestation, and the attested certificate should hold the public key of
+/// the imported key.
+#[test]
+fn keystore2_attest_imported_ec_key_success() {
+    skip_test_if_no_app_attest_key_feature!();
+
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let att_challenge: &[u8] = b"foo";
+
+    // Create attestation key.
+    let attestation_key_metadata =
+        key_generations::generate_attestation_key(&sec_level, Algorithm::EC, att_challenge)
+            .unwrap();
+
+    // Import EC key and use attestation key to sign its certificate.
+    let import_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .ec_curve(EcCurve::P_256)
+        .digest(Digest::SHA_2_256)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .attestation_challenge(att_challenge.to_vec())
+        .cert_not_before(0)
+        .cert_not_after(253402300799000);
+
+    let alias = format!("ks_attest_imported_ec_key_{}", getuid());
+    let key_metadata = sec_level
+        .importKey(
+            &KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(alias), blob: None },
+            Some(&attestation_key_metadata.key),
+            &import_params,
+            0,
+            key_generations::EC_P_256_KEY,
+        )
+        .unwrap();
+
+    let mut cert_chain: Vec<u8> = Vec::new();
+    cert_chain.extend(key_metadata.certificate.as_ref().unwrap());
+    cert_chain.extend(attestation_key_metadata.certificate.as_ref().unwrap());
+    cert_chain.extend(attestation_key_metadata.certificateChain.as_ref().unwrap());
+    validate_certchain(&cert_chain).expect("Error while validating cert chain.");
+
+    // The attested certificate must hold the public key of the imported key.
+    let cert = X509::from_der(key_metadata.certificate.as_ref().unwrap()).unwrap();
+    let imported_key = PKey::private_key_from_pkcs8(key_generations::EC_P_256_KEY).unwrap();
+    assert!(cert.public_key().unwrap().public_eq(&imported_key));
+}
+
+/// Import an EC key with an attestation challenge but without an attestation key. Test should be
+/// able to import the key, and the key should be attested by the factory or remotely provisioned
+/// attestation key with a certificate that holds the public key of the imported key.
+#[test]
+fn keystore2_attest_imported_ec_key_without_attest_key_success() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let att_challenge: &[u8] = b"foo";
+
+    let import_params = authorizations::AuthSetBuilder::new()
+        .no_auth_required()
+        .algorithm(Algorithm::EC)
+        .ec_curve(EcCurve::P_256)
+        .digest(Digest::SHA_2_256)
+        .purpose(KeyPurpose::SIGN)
+        .purpose(KeyPurpose::VERIFY)
+        .attestation_challenge(att_challenge.to_vec())
+        .cert_not_before(0)
+        .cert_not_after(253402300799000);
+
+    let alias = format!("ks_attest_imported_ec_key_no_attest_key_{}", getuid());
+    let key_metadata = sec_level
+        .importKey(
+            &KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(alias), blob: None },
+            None,
+            &import_params,
+            0,
+            key_generations::EC_P_256_KEY,
+        )
+        .unwrap();
+
+    let mut cert_chain: Vec<u8> = Vec::new();
+    cert_chain.extend(key_metadata.certificate.as_ref().unwrap());
+    cert_chain.extend(key_metadata.certificateChain.as_ref().unwrap());
+    validate_certchain(&cert_chain).expect("Error while validating cert chain.");
+
+    let cert = X509::from_der(key_metadata.certificate.as_ref().unwrap()).unwrap();
+    let imported_key = PKey::private_key_from_pkcs8(key_generations::EC_P_256_KEY).unwrap();
+    assert!(cert.public_key().unwrap().public_eq(&imported_key));
+}
@@ -17,7 +17,8 @@ use rustutils::users::AID_USER_OFFSET;
 
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Algorithm::Algorithm, Digest::Digest, EcCurve::EcCurve, ErrorCode::ErrorCode,
-    KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
+    KeyParameter::KeyParameter, KeyParameterValue::KeyParameterValue, KeyPurpose::KeyPurpose,
+    SecurityLevel::SecurityLevel, Tag::Tag, TagType::TagType,
 };
 use android_system_keystore2::aidl::android::system::keystore2::{
     CreateOperationResponse::CreateOperationResponse, Domain::Domain,
@@ -515,3 +516,59 @@ fn keystore2_generate_key_with_blob_domain() {
     // Delete the generated key blob.
     sec_level.deleteKey(&key_metadata.key).unwrap();
 }
+
+/// The keystore specific key parameter of `generateKey` that carries an idempotency key, see
+/// `keystore2::key_tags`.
+const KEY_TAG_IDEMPOTENCY_KEY: Tag = Tag(TagType::BYTES.0 | 0x7000);
+
+/// Generate an EC key twice with the same idempotency key. The retry must return the key of the
+/// first request instead of generating another one. Reusing the idempotency key with other key
+/// parameters must fail with `INVALID_ARGUMENT`.
+#[test]
+fn keystore2_generate_ec_key_idempotent_retry() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let alias = format!("ks_ec_idempotent_test_key_{}", getuid());
+    let key =
+        KeyDescriptor { domain: Domain::APP, nspace: -1, alias: Some(alias.clone()), blob: None };
+    let idempotency_key = format!("ks_ec_idempotent_request_{}", getuid());
+
+    let gen_params = |digest: Digest| {
+        let mut params = authorizations::AuthSetBuilder::new()
+            .no_auth_required()
+            .algorithm(Algorithm::EC)
+            .purpose(KeyPurpose::SIGN)
+            .purpose(KeyPurpose::VERIFY)
+            .digest(digest)
+            .ec_curve(EcCurve::P_256)
+            .to_vec();
+        params.push(KeyParameter {
+            tag: KEY_TAG_IDEMPOTENCY_KEY,
+            value: KeyParameterValue::Blob(idempotency_key.as_bytes().to_vec()),
+        });
+        params
+    };
+
+    let first =
+        sec_level.generateKey(&key, None, &gen_params(Digest::SHA_2_256), 0, b"entropy").unwrap();
+    let retry =
+        sec_level.generateKey(&key, None, &gen_params(Digest::SHA_2_256), 0, b"entropy").unwrap();
+    assert_eq!(first.key, retry.key);
+    assert_eq!(first.certificate, retry.certificate);
+    assert_eq!(first.modificationTimeMs, retry.modificationTimeMs);
+
+    let result = key_generations::map_ks_error(sec_level.generateKey(
+        &key,
+        None,
+        &gen_params(Digest::SHA_2_512),
+        0,
+        b"entropy",
+    ));
+    assert_eq!(Error::Km(ErrorCode::INVALID_ARGUMENT), result.unwrap_err());
+
+    // The failed request leaves the key of the first request in place.
+    let entry = keystore2.getKeyEntry(&key).unwrap();
+    assert_eq!(entry.metadata.key, first.key);
+
+    delete_app_key(&keystore2, &alias).unwrap();
+}
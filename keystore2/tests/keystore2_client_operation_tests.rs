@@ -20,6 +20,7 @@ use std::thread::JoinHandle;
 use android_hardware_security_keymint::aidl::android::hardware::security::keymint::{
     Digest::Digest, ErrorCode::ErrorCode, KeyPurpose::KeyPurpose, SecurityLevel::SecurityLevel,
 };
+use android_security_maintenance::aidl::android::security::maintenance::IKeystoreMaintenance::IKeystoreMaintenance;
 use android_system_keystore2::aidl::android::system::keystore2::{
     CreateOperationResponse::CreateOperationResponse, Domain::Domain,
     IKeystoreOperation::IKeystoreOperation, ResponseCode::ResponseCode,
@@ -34,6 +35,8 @@ use crate::keystore2_client_test_utils::{
     BarrierReached, ForcedOp, TestOutcome,
 };
 
+static MAINTENANCE_SERVICE_NAME: &str = "android.security.maintenance";
+
 /// Create `max_ops` number child processes with the given context and perform an operation under each
 /// child process.
 pub fn create_operations(
@@ -447,3 +450,45 @@ fn keystore2_op_fails_operation_busy() {
 
     assert!(result1 || result2);
 }
+
+/// Generate a key and freeze it. Any attempt to create an operation with the frozen key should
+/// fail with `PERMISSION_DENIED`, while its metadata can still be loaded. After unfreezing the
+/// key, operations should succeed again. A frozen key can also be deleted.
+#[test]
+fn keystore2_frozen_key_rejects_operations() {
+    let keystore2 = get_keystore_service();
+    let sec_level = keystore2.getSecurityLevel(SecurityLevel::TRUSTED_ENVIRONMENT).unwrap();
+    let maintenance: binder::Strong<dyn IKeystoreMaintenance> =
+        binder::get_interface(MAINTENANCE_SERVICE_NAME).unwrap();
+    let alias = "ks_frozen_key_test".to_string();
+    let key_metadata = key_generations::generate_ec_p256_signing_key(
+        &sec_level,
+        Domain::APP,
+        -1,
+        Some(alias),
+        None,
+    )
+    .unwrap();
+    let create_op = || {
+        sec_level.createOperation(
+            &key_metadata.key,
+            &authorizations::AuthSetBuilder::new()
+                .purpose(KeyPurpose::SIGN)
+                .digest(Digest::SHA_2_256),
+            false,
+        )
+    };
+
+    maintenance.setKeyFrozen(&key_metadata.key, true).unwrap();
+    let result = key_generations::map_ks_error(create_op());
+    assert!(result.is_err());
+    assert_eq!(Error::Rc(ResponseCode::PERMISSION_DENIED), result.unwrap_err());
+    keystore2.getKeyEntry(&key_metadata.key).unwrap();
+
+    maintenance.setKeyFrozen(&key_metadata.key, false).unwrap();
+    let op_response = create_op().unwrap();
+    perform_sample_sign_operation(&op_response.iOperation.unwrap()).unwrap();
+
+    maintenance.setKeyFrozen(&key_metadata.key, true).unwrap();
+    keystore2.deleteKey(&key_metadata.key).unwrap();
+}